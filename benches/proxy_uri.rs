@@ -0,0 +1,105 @@
+//! Compares building the URIs of the requests proxied to the Market
+//! from the pre-parsed [`UpstreamUri`] parts with formatting and re-parsing the whole URI.
+//!
+//! The number of allocations of each is printed before the benchmarks.
+//!
+//! `cargo bench --bench proxy_uri`
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use http::Uri;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use supermarket::market::{MarketUrl, UpstreamUri};
+
+/// Counts the allocations of the [`System`] allocator
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let result = f();
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+    drop(result);
+
+    allocations
+}
+
+const MARKET_URLS: [&str; 2] = [
+    "https://market.adex.network/",
+    "https://market.adex.network/market/",
+];
+
+const URI: &str = "/slots/QmQ696XttGrC456X3uBDMViPAJi24VJmbXAzayNw5maoLe?limit=10&skip=20";
+
+/// Formats the Market URL with the path & query and parses the resulting `String`
+fn formatted(market_url: &MarketUrl, uri: &Uri) -> Uri {
+    let path_and_query = uri
+        .path_and_query()
+        .map(|p_q| {
+            let string = p_q.to_string();
+            string
+                .strip_prefix('/')
+                .map(ToString::to_string)
+                .unwrap_or(string)
+        })
+        .unwrap_or_default();
+
+    format!("{}{}", market_url, path_and_query)
+        .parse()
+        .expect("Should parse the formatted URI")
+}
+
+fn proxy_uri(c: &mut Criterion) {
+    let uri: Uri = URI.parse().expect("Valid URI");
+    let mut group = c.benchmark_group("proxy_uri");
+
+    for market_url in MARKET_URLS.iter() {
+        let market_url: MarketUrl = market_url.parse().expect("Valid Market URL");
+        let upstream_uri = UpstreamUri::new(&market_url);
+
+        println!(
+            "{}: formatted - {} allocations, upstream_uri - {} allocations",
+            market_url,
+            count_allocations(|| formatted(&market_url, &uri)),
+            count_allocations(|| upstream_uri.uri(&uri)),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("formatted", &market_url),
+            &uri,
+            |b, uri| b.iter(|| formatted(black_box(&market_url), black_box(uri))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("upstream_uri", &market_url),
+            &uri,
+            |b, uri| {
+                b.iter(|| {
+                    upstream_uri
+                        .uri(black_box(uri))
+                        .expect("Should build the URI")
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, proxy_uri);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+use std::process::Command;
+
+/// Embeds the build information used by the `/version` route
+fn main() {
+    let git_commit = Command::new("git")
+        .args(&["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=SUPERMARKET_GIT_COMMIT={}", git_commit);
+    println!(
+        "cargo:rustc-env=SUPERMARKET_BUILD_TIMESTAMP={}",
+        chrono::Utc::now().to_rfc3339()
+    );
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
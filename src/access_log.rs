@@ -0,0 +1,233 @@
+//! Access log of the requests in the Combined Log Format (the Common Log Format with the `Referer` & `User-Agent`),
+//! see [`AccessLog`](crate::config::AccessLog).
+//!
+//! The lines are written to the file by a dedicated thread fed by a bounded channel,
+//! when it's full (e.g. the disk is slow) the lines are dropped instead of blocking the requests.
+use crate::{bot::CidrSet, metrics::ACCESS_LOG_DROPPED};
+use chrono::{DateTime, Utc};
+use http::header::{HeaderName, CONTENT_LENGTH, REFERER, USER_AGENT};
+use hyper::{body::HttpBody, Body, Request, Response};
+use slog::{error, info, Logger};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+};
+
+/// Sends the lines to the writer thread, which stops when all of its clones are dropped
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    sender: SyncSender<String>,
+}
+
+impl AccessLog {
+    /// Opens (or creates) the file for appending and spawns the writer thread,
+    /// which reopens the file on `SIGHUP` (e.g. after it's rotated).
+    pub fn open(logger: Logger, path: &Path, buffer: usize) -> io::Result<Self> {
+        let file = append(path)?;
+        let (access_log, receiver) = Self::channel(buffer);
+
+        let reopen = Arc::new(AtomicBool::new(false));
+        spawn_reopen_on_hangup(reopen.clone())?;
+
+        let path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(logger, path, file, receiver, reopen))?;
+
+        Ok(access_log)
+    }
+
+    fn channel(buffer: usize) -> (Self, Receiver<String>) {
+        let (sender, receiver) = sync_channel(buffer);
+
+        (Self { sender }, receiver)
+    }
+
+    /// Never blocks, if the channel is full the line is dropped and counted in the [`ACCESS_LOG_DROPPED`]
+    pub fn log(&self, line: String) {
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                ACCESS_LOG_DROPPED.inc()
+            }
+        }
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn spawn_reopen_on_hangup(reopen: Arc<AtomicBool>) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            reopen.store(true, Ordering::SeqCst);
+        }
+    });
+
+    Ok(())
+}
+
+/// Runs on the writer thread until all the [`AccessLog`]s are dropped,
+/// the file is reopened before the next line after a `SIGHUP`.
+fn write_lines(
+    logger: Logger,
+    path: PathBuf,
+    mut file: File,
+    receiver: Receiver<String>,
+    reopen: Arc<AtomicBool>,
+) {
+    for line in receiver {
+        if reopen.swap(false, Ordering::SeqCst) {
+            match append(&path) {
+                Ok(reopened) => {
+                    file = reopened;
+                    info!(&logger, "Access log reopened"; "path" => %path.display());
+                }
+                Err(err) => {
+                    error!(&logger, "Failed to reopen the access log"; "path" => %path.display(), "error" => %err)
+                }
+            }
+        }
+
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            error!(&logger, "Failed to write to the access log"; "path" => %path.display(), "error" => %err);
+        }
+    }
+}
+
+/// The request part of an access log line, taken before the request is handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLine {
+    remote_ip: Option<IpAddr>,
+    time: DateTime<Utc>,
+    /// `METHOD /path?query HTTP/1.1`
+    request: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl RequestLine {
+    /// The remote IP is the client IP (see [`Config.trusted_proxies`](crate::Config::trusted_proxies))
+    pub fn new(req: &Request<Body>, trusted_proxies: &CidrSet, time: DateTime<Utc>) -> Self {
+        let header = |name: HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(ToString::to_string)
+        };
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or_else(|| req.uri().path());
+
+        Self {
+            remote_ip: crate::admin::client_ip(req, trusted_proxies),
+            time,
+            request: format!("{} {} {:?}", req.method(), path, req.version()),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+
+    /// The Combined Log Format line (incl. the new line) for the `response`
+    pub fn format(&self, response: &Response<Body>) -> String {
+        let bytes = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+            .or_else(|| response.body().size_hint().exact());
+
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"\n",
+            self.remote_ip
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.request),
+            response.status().as_u16(),
+            bytes
+                .filter(|bytes| *bytes > 0)
+                .map(|bytes| bytes.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.referer.as_deref().map(escape).unwrap_or_default(),
+            self.user_agent.as_deref().map(escape).unwrap_or_default(),
+        )
+    }
+}
+
+/// The quoted fields can't break out of their quotes or the line
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::admin::RemoteAddr;
+    use chrono::TimeZone;
+    use http::StatusCode;
+
+    #[test]
+    fn lines_are_in_the_combined_log_format() {
+        let mut req = Request::get("/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C?depositAsset=0x6B175474E89094C44Da98b954EedeAC495271d0F")
+            .header(REFERER, "https://example.com/page")
+            .header(USER_AGENT, "Mozilla/5.0 \"quoted\"")
+            .body(Body::empty())
+            .expect("Valid request");
+        req.extensions_mut()
+            .insert(RemoteAddr("10.0.0.1:51000".parse().expect("Valid address")));
+        let time = Utc.ymd(2020, 10, 10).and_hms(13, 55, 36);
+
+        let request_line = RequestLine::new(&req, &CidrSet::default(), time);
+
+        let response = Response::new(Body::from("{\"campaigns\":[]}"));
+        assert_eq!(
+            "10.0.0.1 - - [10/Oct/2020:13:55:36 +0000] \"GET /units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C?depositAsset=0x6B175474E89094C44Da98b954EedeAC495271d0F HTTP/1.1\" 200 16 \"https://example.com/page\" \"Mozilla/5.0 \\\"quoted\\\"\"\n",
+            request_line.format(&response)
+        );
+
+        // without a size, a remote address and the headers
+        let req = Request::post("/validators/refresh")
+            .body(Body::empty())
+            .expect("Valid request");
+        let response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("Valid response");
+        assert_eq!(
+            "- - - [10/Oct/2020:13:55:36 +0000] \"POST /validators/refresh HTTP/1.1\" 404 - \"\" \"\"\n",
+            RequestLine::new(&req, &CidrSet::default(), time).format(&response)
+        );
+    }
+
+    #[test]
+    fn lines_are_dropped_when_the_channel_is_full() {
+        let (access_log, receiver) = AccessLog::channel(2);
+        let dropped = ACCESS_LOG_DROPPED.get();
+
+        for i in 0..5 {
+            access_log.log(format!("line {}\n", i));
+        }
+
+        assert_eq!(3, ACCESS_LOG_DROPPED.get() - dropped);
+        assert_eq!(
+            vec!["line 0\n", "line 1\n"],
+            receiver.try_iter().collect::<Vec<_>>()
+        );
+    }
+}
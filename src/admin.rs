@@ -0,0 +1,1185 @@
+//! Admin routes which require the `Authorization: Bearer <token>` header
+//! with the configured [`Config.admin_token`](crate::Config::admin_token).
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    StatusCode,
+};
+use hyper::{Body, Request, Response};
+use primitives::{util::ApiUrl, ChannelId, ValidatorId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::{
+    bad_request,
+    bot::{CidrSet, X_FORWARDED_FOR_HEADER},
+    cache::{snapshot::Snapshot, Cache, Client},
+    config::UnreachableMediaPolicy,
+    ids::Canonical,
+    not_found,
+    status::Status,
+    units_for_slot::pipeline::{self, failed_campaign_steps, is_unit_matchable},
+    util::constant_time_eq,
+    Config, Error,
+};
+
+pub mod idempotency;
+
+/// The remote address of the connection, set on every request by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Returns the response that should be served, if the request is not authorized:
+/// - `404 Not Found` - if there's no admin token set in the [`Config`], i.e. admin routes are disabled
+/// - `403 Forbidden` - if the client IP is not in the [`Config.admin_allowed_ips`](crate::Config::admin_allowed_ips) (when set),
+///   regardless of the token
+/// - `401 Unauthorized` - if the `Authorization` header is missing or the token is wrong
+pub fn authorize(req: &Request<Body>, config: &Config) -> Result<(), Response<Body>> {
+    let admin_token = match config.admin_token.as_ref() {
+        Some(admin_token) => admin_token,
+        None => return Err(not_found()),
+    };
+
+    if !config.admin_allowed_ips.is_empty() {
+        let allowed = client_ip(req, &config.trusted_proxies)
+            .map_or(false, |ip| config.admin_allowed_ips.contains(ip));
+
+        if !allowed {
+            return Err(forbidden());
+        }
+    }
+
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match bearer {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.expose().as_bytes()) => {
+            Ok(())
+        }
+        _ => Err(unauthorized()),
+    }
+}
+
+/// The IP of the connection (see [`RemoteAddr`]), or if it's one of the `trusted_proxies`,
+/// the last `X-Forwarded-For` address which is not one of them.
+/// `None` if there's no [`RemoteAddr`] or a malformed address is reached.
+pub(crate) fn client_ip(req: &Request<Body>, trusted_proxies: &CidrSet) -> Option<IpAddr> {
+    let RemoteAddr(remote_addr) = req.extensions().get::<RemoteAddr>()?;
+    let mut client_ip = remote_addr.ip();
+
+    let forwarded_for = req
+        .headers()
+        .get_all(&*X_FORWARDED_FOR_HEADER)
+        .iter()
+        .map(|value| value.to_str().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let mut forwarded = forwarded_for.iter().flat_map(|list| list.split(',')).rev();
+
+    // every proxy appends the address it received the request from
+    while trusted_proxies.contains(client_ip) {
+        match forwarded.next() {
+            Some(address) => client_ip = address.trim().parse().ok()?,
+            None => break,
+        }
+    }
+
+    Some(client_ip)
+}
+
+/// `GET /config` - the currently active [`Config`] with the secrets redacted.
+///
+/// The values changed at runtime are taken from the Cache: the `slot_overrides` reloaded on `SIGHUP`
+/// and the `validators` changed with `PUT /validators` & `DELETE /validators/:host`.
+pub async fn get_config<C: Client>(
+    config: &Config,
+    cache: &Cache<C>,
+) -> Result<Response<Body>, Error> {
+    let mut active = config.clone();
+    active.slot_overrides = cache.slot_overrides.read().await.clone();
+    active.validators = cache.validators().await;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&active)?))?)
+}
+
+/// The body of the [`refresh_validator`] route
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshValidator {
+    pub url: String,
+    /// Fetch the Campaigns even if the Validator is neither configured nor known from the Active Campaigns
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshedValidator {
+    pub url: ApiUrl,
+    /// The number of Active Campaigns fetched from the Validator
+    pub active: usize,
+    /// The number of Finalized Campaigns fetched from the Validator
+    pub finalized: usize,
+}
+
+/// `POST /validators/refresh` - fetches the Campaigns of a single Validator (see [`Cache::fetch_new_campaigns_from`])
+/// with a [`RefreshValidator`] JSON body:
+/// - `400 Bad Request` - if the body or the Validator URL is malformed
+/// - `404 Not Found` - if the Validator is not in the current [`Cache::validators`]
+///   nor a Validator of the Active Campaigns, unless `force` is set
+pub async fn refresh_validator<C: Client>(
+    req: Request<Body>,
+    cache: &Cache<C>,
+) -> Result<Response<Body>, Error> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let refresh: RefreshValidator = match serde_json::from_slice(&body) {
+        Ok(refresh) => refresh,
+        Err(error) => return Ok(bad_request(format!("Malformed body: {}", error))),
+    };
+
+    let url: ApiUrl = match refresh.url.parse() {
+        Ok(url) => url,
+        Err(error) => return Ok(bad_request(format!("Malformed Validator URL: {}", error))),
+    };
+
+    let is_known =
+        cache.validators().await.contains(&url) || cache.known_validators().await.contains(&url);
+    if !is_known && !refresh.force {
+        return Ok(not_found());
+    }
+
+    let (active, finalized) = cache.fetch_new_campaigns_from(&url).await;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&RefreshedValidator {
+            url,
+            active,
+            finalized,
+        })?))?)
+}
+
+/// The response of the `/validators` routes
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Validators {
+    /// The current Validators, sorted
+    pub validators: Vec<ApiUrl>,
+    /// The added Validators by `PUT /validators`, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<ApiUrl>,
+    /// The removed Validator by `DELETE /validators/:host`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed: Option<ApiUrl>,
+    /// Whether the Validators were persisted to the config file, see [`persist_requested`]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub persisted: bool,
+}
+
+impl Validators {
+    async fn new<C: Client>(cache: &Cache<C>) -> Self {
+        Self {
+            validators: sorted(cache.validators().await),
+            added: vec![],
+            removed: None,
+            persisted: false,
+        }
+    }
+
+    fn into_response(self) -> Result<Response<Body>, Error> {
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(&self)?))?)
+    }
+}
+
+fn sorted(validators: impl IntoIterator<Item = ApiUrl>) -> Vec<ApiUrl> {
+    let mut validators = validators.into_iter().collect::<Vec<_>>();
+    validators.sort_by_key(ToString::to_string);
+
+    validators
+}
+
+/// `GET /validators` - the current Validators from which the new Campaigns are collected.
+/// Unlike the `validators` of `GET /config` it includes the changes made at runtime.
+pub async fn get_validators<C: Client>(cache: &Cache<C>) -> Result<Response<Body>, Error> {
+    Validators::new(cache).await.into_response()
+}
+
+/// Whether the Validators changed by the request (its `?persist=true`) should be persisted to the config file as well,
+/// so they're kept after a restart, see [`Config::persist_validators`]
+pub fn persist_requested(query: Option<&str>) -> bool {
+    url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .any(|(key, value)| key == "persist" && value == "true")
+}
+
+/// The response if the Validators can't be persisted, checked before they're changed
+fn persist_conflict(persist: bool, config: &Config) -> Option<Response<Body>> {
+    let error = config.validators_file().err().filter(|_| persist)?;
+
+    Some(
+        Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(Body::from(error.to_string()))
+            .expect("Conflict response should be valid"),
+    )
+}
+
+/// Persists the current Validators to the config file, if it's `persist`ed
+async fn persist_validators<C: Client>(
+    persist: bool,
+    cache: &Cache<C>,
+    config: &Config,
+) -> Result<bool, Error> {
+    if persist {
+        config
+            .persist_validators(&cache.validators().await)
+            .map_err(Error::PersistValidators)?;
+    }
+
+    Ok(persist)
+}
+
+/// `PUT /validators` - replaces the Validators with the JSON array of URLs of the body,
+/// the added ones are immediately backfilled (see [`Cache::replace_validators`]).
+/// With `?persist=true` they're persisted to the config file as well, see [`persist_requested`].
+/// - `400 Bad Request` - if the body or any of the URLs is malformed
+/// - `409 Conflict` - if they should be persisted, but the config has no file or its `validators` are overridden
+///   by an environment variable, see [`Config::validators_file`]. The Validators are not changed.
+pub async fn put_validators<C: Client>(
+    req: Request<Body>,
+    cache: &Cache<C>,
+    config: &Config,
+) -> Result<Response<Body>, Error> {
+    let persist = persist_requested(req.uri().query());
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let urls: Vec<String> = match serde_json::from_slice(&body) {
+        Ok(urls) => urls,
+        Err(error) => return Ok(bad_request(format!("Malformed body: {}", error))),
+    };
+
+    let mut validators = HashSet::new();
+    for url in urls {
+        match url.parse::<ApiUrl>() {
+            Ok(validator) => validators.insert(validator),
+            Err(error) => {
+                return Ok(bad_request(format!(
+                    "Malformed Validator URL `{}`: {}",
+                    url, error
+                )))
+            }
+        };
+    }
+
+    if let Some(conflict) = persist_conflict(persist, config) {
+        return Ok(conflict);
+    }
+
+    let added = cache.replace_validators(validators).await;
+    let persisted = persist_validators(persist, cache, config).await?;
+
+    Validators {
+        added: sorted(added),
+        persisted,
+        ..Validators::new(cache).await
+    }
+    .into_response()
+}
+
+/// `DELETE /validators/:host` - stops collecting the new Campaigns from the Validator
+/// with this host (incl. the port if it's set, e.g. `localhost:8005`), see [`Cache::remove_validator`].
+/// With `persist` (the `?persist=true` of the request) the rest are persisted to the config file as well.
+/// - `404 Not Found` - if there's no such Validator
+/// - `409 Conflict` - same as [`put_validators`]
+pub async fn delete_validator<C: Client>(
+    host: &str,
+    persist: bool,
+    cache: &Cache<C>,
+    config: &Config,
+) -> Result<Response<Body>, Error> {
+    if let Some(conflict) = persist_conflict(persist, config) {
+        return Ok(conflict);
+    }
+
+    match cache.remove_validator(host).await {
+        Some(removed) => Validators {
+            removed: Some(removed),
+            persisted: persist_validators(persist, cache, config).await?,
+            ..Validators::new(cache).await
+        }
+        .into_response(),
+        None => Ok(not_found()),
+    }
+}
+
+/// `GET /internal/cache-snapshot` - the [`Snapshot`] of the Cache for warming up another replica,
+/// see [`Config.warm_from`](crate::Config::warm_from)
+pub async fn get_cache_snapshot<C: Client>(cache: &Cache<C>) -> Result<Response<Body>, Error> {
+    let snapshot = Snapshot::take(cache).await;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&snapshot)?))?)
+}
+
+/// `GET /stats/publishers/:address` - the units-for-slot requests of the publisher's AdSlots in hourly buckets,
+/// see [`PublisherStats`](crate::cache::Cache::publisher_stats).
+/// A publisher which is not tracked (no requests within the retention) has no requests.
+/// - `400 Bad Request` - if the address is malformed
+pub async fn get_publisher_stats<C: Client>(
+    address: &str,
+    cache: &Cache<C>,
+) -> Result<Response<Body>, Error> {
+    let publisher = match address.parse::<Canonical<ValidatorId>>() {
+        Ok(Canonical(publisher)) => publisher,
+        Err(malformed) => return Ok(bad_request(malformed.to_string())),
+    };
+
+    let requests = cache
+        .publisher_stats
+        .read()
+        .await
+        .get(publisher, cache.clock().now_utc());
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&requests)?))?)
+}
+
+/// `GET /campaigns/:id/serve-stats` - how many units-for-slot responses served the units of the Campaign
+/// (anywhere and at the top) in hourly buckets, see [`ServeHistory`](crate::cache::Cache::serve_history).
+/// A Campaign which is not tracked (no serves within the retention) has no serves,
+/// the serves since the last flush (see [`ServeStats`](crate::config::ServeStats)) are not counted yet.
+/// - `400 Bad Request` - if the ChannelId is malformed
+pub async fn get_campaign_serve_stats<C: Client>(
+    channel_id: &str,
+    cache: &Cache<C>,
+    config: &Config,
+) -> Result<Response<Body>, Error> {
+    let channel_id = match channel_id.parse::<Canonical<ChannelId>>() {
+        Ok(Canonical(channel_id)) => channel_id,
+        Err(malformed) => return Ok(bad_request(malformed.to_string())),
+    };
+
+    let serves = cache.serve_history.read().await.get(
+        channel_id,
+        cache.clock().now_utc(),
+        &config.serve_stats,
+    );
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&serves)?))?)
+}
+
+/// The response of [`get_campaign_units`]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CampaignUnits {
+    pub channel_id: ChannelId,
+    pub status: Status,
+    /// The [`CAMPAIGN_STEPS`](pipeline::CAMPAIGN_STEPS) the Campaign doesn't pass, none of its units are served if any
+    pub failed_checks: Vec<&'static str>,
+    /// In the order of the Campaign's spec
+    pub units: Vec<CampaignUnit>,
+}
+
+/// An AdUnit of the Campaign's spec
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CampaignUnit {
+    pub ipfs: String,
+    #[serde(rename = "type")]
+    pub ad_type: String,
+    pub media_url: String,
+    pub archived: bool,
+    /// Whether its media was found unreachable, `None` if the media isn't checked, see [`MediaCheck`](crate::config::MediaCheck)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_unreachable: Option<bool>,
+    /// It's matched for the AdSlots of its type (subject to the targeting), demoted if its media is unreachable
+    pub servable: bool,
+}
+
+/// `GET /campaigns/:id/units` - the AdUnits of an Active Campaign and whether each of them is currently served,
+/// by the checks of the units-for-slot which don't depend on the AdSlot or the publisher:
+/// the Campaign's [`CAMPAIGN_STEPS`](pipeline::CAMPAIGN_STEPS), the unit's type & archived state and its media reachability.
+/// - `400 Bad Request` - if the ChannelId is malformed
+/// - `404 Not Found` - if there is no such Active Campaign in the [`Cache`]
+pub async fn get_campaign_units<C: Client>(
+    channel_id: &str,
+    cache: &Cache<C>,
+    config: &Config,
+) -> Result<Response<Body>, Error> {
+    let channel_id = match channel_id.parse::<Canonical<ChannelId>>() {
+        Ok(Canonical(channel_id)) => channel_id,
+        Err(malformed) => return Ok(bad_request(malformed.to_string())),
+    };
+
+    let active = cache.active.read().await;
+    let campaign = match active.get(&channel_id) {
+        Some(campaign) => campaign,
+        None => return Ok(not_found()),
+    };
+
+    let now = cache.clock().now_instant();
+    let failed_checks = {
+        let refreshed = cache.refreshed.read().await;
+        let follower_balances = cache.follower_balances.read().await;
+        let restricted = cache.restricted.read().await;
+        let context = pipeline::Context {
+            config,
+            refreshed: &refreshed,
+            follower_balances: &follower_balances,
+            now,
+            now_utc: cache.clock().now_utc(),
+            // not used by the Campaign steps
+            publisher_id: ValidatorId::from(&[0_u8; 20]),
+            deposit_assets: &[],
+            serve_stale: false,
+            restricted: &restricted,
+        };
+
+        failed_campaign_steps(&context, campaign)
+    };
+
+    let media_check = &config.media_check;
+    let media_checks = cache.media_checks.read().await;
+    let units = campaign
+        .channel
+        .spec
+        .ad_units
+        .iter()
+        .map(|ad_unit| {
+            let media_unreachable = Some(media_check)
+                .filter(|media_check| media_check.is_enabled())
+                .map(|media_check| {
+                    media_checks.is_unreachable(&ad_unit.ipfs, now, media_check.ttl)
+                });
+            let excluded = media_unreachable == Some(true)
+                && media_check.policy == UnreachableMediaPolicy::Exclude;
+
+            CampaignUnit {
+                ipfs: ad_unit.ipfs.clone(),
+                ad_type: ad_unit.ad_type.clone(),
+                media_url: ad_unit.media_url.clone(),
+                archived: ad_unit.archived,
+                media_unreachable,
+                servable: failed_checks.is_empty() && is_unit_matchable(ad_unit) && !excluded,
+            }
+        })
+        .collect();
+
+    let response = CampaignUnits {
+        channel_id,
+        status: campaign.status.clone(),
+        failed_checks,
+        units,
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+pub(crate) fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::empty())
+        .expect("Unauthorized response should be valid")
+}
+
+pub(crate) fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::empty())
+        .expect("Forbidden response should be valid")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cache::MockClient,
+        config::{Secret, DEVELOPMENT},
+        status::Status,
+    };
+    use primitives::{supermarket::Campaign, util::tests::prep_db::DUMMY_CHANNEL, ChannelId};
+    use std::collections::HashMap;
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::get(crate::ROUTE_CONFIG);
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization);
+        }
+
+        builder.body(Body::empty()).expect("Should build Request")
+    }
+
+    #[test]
+    fn admin_routes_are_disabled_without_a_token() {
+        let config = DEVELOPMENT.clone();
+
+        let response = authorize(&request(Some("Bearer token")), &config)
+            .expect_err("Should not be authorized");
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+
+    #[test]
+    fn admin_routes_require_the_token() {
+        let mut config = DEVELOPMENT.clone();
+        config.admin_token = Some(Secret::from("token".to_string()));
+
+        for authorization in &[
+            None,
+            Some("token"),
+            Some("Bearer wrong"),
+            Some("Bearer token2"),
+        ] {
+            let response =
+                authorize(&request(*authorization), &config).expect_err("Should not be authorized");
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        }
+
+        assert!(authorize(&request(Some("Bearer token")), &config).is_ok());
+    }
+
+    fn request_from(remote_addr: &str, forwarded_for: Option<&str>, token: &str) -> Request<Body> {
+        let mut builder =
+            Request::get(crate::ROUTE_CONFIG).header(AUTHORIZATION, format!("Bearer {}", token));
+        if let Some(forwarded_for) = forwarded_for {
+            builder = builder.header("x-forwarded-for", forwarded_for);
+        }
+
+        let mut request = builder.body(Body::empty()).expect("Should build Request");
+        request.extensions_mut().insert(RemoteAddr(
+            remote_addr.parse().expect("Valid remote address"),
+        ));
+
+        request
+    }
+
+    fn cidr_set(cidrs: &[&str]) -> CidrSet {
+        serde_json::from_value(serde_json::json!(cidrs)).expect("Valid CIDRs")
+    }
+
+    #[test]
+    fn admin_routes_are_restricted_to_the_allowed_ips() {
+        let mut config = DEVELOPMENT.clone();
+        config.admin_token = Some(Secret::from("token".to_string()));
+        config.admin_allowed_ips = cidr_set(&["10.0.0.0/8", "2001:db8::/32"]);
+
+        let status = |request: &Request<Body>| match authorize(request, &config) {
+            Ok(()) => StatusCode::OK,
+            Err(response) => response.status(),
+        };
+
+        // IPv4
+        assert_eq!(
+            StatusCode::OK,
+            status(&request_from("10.1.2.3:5000", None, "token"))
+        );
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(&request_from("10.1.2.3:5000", None, "wrong"))
+        );
+        // the token is not checked for the other IPs
+        for token in &["token", "wrong"] {
+            assert_eq!(
+                StatusCode::FORBIDDEN,
+                status(&request_from("11.0.0.1:5000", None, token))
+            );
+        }
+
+        // IPv6
+        assert_eq!(
+            StatusCode::OK,
+            status(&request_from("[2001:db8:1::1]:5000", None, "token"))
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(&request_from("[2001:db9::1]:5000", None, "token"))
+        );
+
+        // without a remote address
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(&request(Some("Bearer token")))
+        );
+    }
+
+    #[test]
+    fn forwarded_for_is_used_only_from_the_trusted_proxies() {
+        let mut config = DEVELOPMENT.clone();
+        config.admin_token = Some(Secret::from("token".to_string()));
+        config.admin_allowed_ips = cidr_set(&["10.0.0.0/8", "2001:db8::/32"]);
+        config.trusted_proxies = cidr_set(&["192.168.0.0/16", "fd00::/8"]);
+
+        let status = |remote_addr: &str, forwarded_for: &str| match authorize(
+            &request_from(remote_addr, Some(forwarded_for), "token"),
+            &config,
+        ) {
+            Ok(()) => StatusCode::OK,
+            Err(response) => response.status(),
+        };
+
+        assert_eq!(StatusCode::OK, status("192.168.0.1:5000", "10.1.2.3"));
+        assert_eq!(StatusCode::OK, status("[fd00::1]:5000", "2001:db8::1"));
+        // through multiple trusted proxies
+        assert_eq!(
+            StatusCode::OK,
+            status("192.168.0.1:5000", "10.1.2.3, 192.168.0.2")
+        );
+        // spoofed by the client in front of the appended address
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status("192.168.0.1:5000", "10.1.2.3, 11.0.0.1")
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status("192.168.0.1:5000", "not-an-ip")
+        );
+        // not from a trusted proxy
+        assert_eq!(StatusCode::FORBIDDEN, status("11.0.0.1:5000", "10.1.2.3"));
+    }
+
+    #[tokio::test]
+    async fn config_route_redacts_the_admin_token() {
+        let mut config = DEVELOPMENT.clone();
+        config.admin_token = Some(Secret::from("super-secret-admin-token".to_string()));
+
+        let cache = crate::cache::Cache::initialize(
+            MockClient::init(vec![HashMap::new()], vec![], None).await,
+        )
+        .await;
+
+        let response = get_config(&config, &cache)
+            .await
+            .expect("Should serve the Config");
+        let body = hyper::body::to_bytes(response).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).expect("Should be UTF-8");
+
+        assert!(!body.contains("super-secret-admin-token"));
+        assert!(body.contains(crate::config::REDACTED));
+    }
+
+    #[tokio::test]
+    async fn config_route_serves_the_values_changed_at_runtime() {
+        const SLOT: &str = "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C";
+        let tom: ApiUrl = "https://tom.adex.network/".parse().expect("Valid URL");
+        let jerry: ApiUrl = "https://jerry.adex.network/".parse().expect("Valid URL");
+
+        let path = std::env::temp_dir().join("supermarket-config-route-test.toml");
+        let write = |toml: &str| std::fs::write(&path, toml).expect("Should write config file");
+
+        write("[slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C]\nblocked = true");
+        let config = Config::new(path.to_str(), crate::config::Environment::Development)
+            .expect("Should load config");
+        let client = MockClient::init(vec![HashMap::new()], vec![], None)
+            .await
+            .with_validators(std::iter::once(tom).collect());
+        let cache = crate::cache::Cache::builder(client)
+            .slot_overrides(config.slot_overrides.clone())
+            .initialize()
+            .await;
+
+        write("[slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C]\nforce_fallback = true");
+        crate::units_for_slot::overrides::reload(
+            &crate::util::test::discard_logger(),
+            &cache,
+            &config,
+        )
+        .await;
+        std::fs::remove_file(&path).expect("Should remove config file");
+        cache
+            .replace_validators(std::iter::once(jerry.clone()).collect())
+            .await;
+
+        let response = get_config(&config, &cache)
+            .await
+            .expect("Should serve the Config");
+        let active: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(response).await.unwrap())
+                .expect("Should deserialize");
+
+        assert_eq!(
+            serde_json::json!(false),
+            active["slot_overrides"][SLOT]["blocked"]
+        );
+        assert_eq!(
+            serde_json::json!(true),
+            active["slot_overrides"][SLOT]["force_fallback"]
+        );
+        assert_eq!(serde_json::json!([jerry]), active["validators"]);
+        // the startup Config is left as it is
+        assert!(config.slot_overrides[SLOT].blocked);
+    }
+
+    #[tokio::test]
+    async fn refreshing_a_validator() {
+        let configured: ApiUrl = "https://tom.adex.network/".parse().expect("Valid URL");
+        let unknown: ApiUrl = "https://new.adex.network/".parse().expect("Valid URL");
+
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.id = ChannelId::from([7; 32]);
+        let campaign = Campaign::new(channel, Status::Active, Default::default());
+        let new_campaigns: HashMap<_, _> =
+            std::iter::once((campaign.channel.id, campaign)).collect();
+
+        let client = MockClient::init(vec![HashMap::new()], vec![], None)
+            .await
+            .with_validators(std::iter::once(configured.clone()).collect())
+            .with_validator_campaigns(configured.clone(), new_campaigns.clone())
+            .with_validator_campaigns(unknown, new_campaigns);
+        let cache = crate::cache::Cache::initialize(client).await;
+
+        let request = |body: &str| {
+            Request::post(crate::ROUTE_VALIDATORS_REFRESH)
+                .body(Body::from(body.to_string()))
+                .expect("Should build Request")
+        };
+
+        // (body, expected status)
+        let failing = vec![
+            (r#"{ "url": "not a url" }"#, StatusCode::BAD_REQUEST),
+            (
+                r#"{ "uri": "https://tom.adex.network/" }"#,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                r#"{ "url": "https://new.adex.network/" }"#,
+                StatusCode::NOT_FOUND,
+            ),
+        ];
+        for (body, expected_status) in failing {
+            let response = refresh_validator(request(body), &cache)
+                .await
+                .expect("Should handle the request");
+
+            assert_eq!(expected_status, response.status(), "{}", body);
+        }
+        assert!(cache.active.read().await.is_empty());
+
+        for body in &[
+            r#"{ "url": "https://tom.adex.network/" }"#,
+            r#"{ "url": "https://new.adex.network/", "force": true }"#,
+        ] {
+            let response = refresh_validator(request(body), &cache)
+                .await
+                .expect("Should handle the request");
+            assert_eq!(StatusCode::OK, response.status(), "{}", body);
+
+            let refreshed: RefreshedValidator =
+                serde_json::from_slice(&hyper::body::to_bytes(response).await.unwrap())
+                    .expect("Should deserialize");
+            assert_eq!(1, refreshed.active);
+            assert_eq!(0, refreshed.finalized);
+        }
+
+        assert!(cache
+            .active
+            .read()
+            .await
+            .contains_key(&ChannelId::from([7; 32])));
+    }
+
+    #[tokio::test]
+    async fn managing_the_validators() {
+        let tom: ApiUrl = "https://tom.adex.network/".parse().expect("Valid URL");
+        let jerry: ApiUrl = "http://localhost:8005/".parse().expect("Valid URL");
+
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.id = ChannelId::from([9; 32]);
+        let campaign = Campaign::new(channel, Status::Active, Default::default());
+        let jerry_campaigns: HashMap<_, _> =
+            std::iter::once((campaign.channel.id, campaign)).collect();
+
+        let client = MockClient::init(vec![HashMap::new()], vec![], None)
+            .await
+            .with_validators(std::iter::once(tom.clone()).collect())
+            .with_validator_campaigns(jerry.clone(), jerry_campaigns);
+        let cache = crate::cache::Cache::initialize(client).await;
+        let config = DEVELOPMENT.clone();
+
+        let put = |body: &str| {
+            Request::put(crate::ROUTE_VALIDATORS)
+                .body(Body::from(body.to_string()))
+                .expect("Should build Request")
+        };
+        let validators = |response: Response<Body>| async move {
+            assert_eq!(StatusCode::OK, response.status());
+
+            serde_json::from_slice::<Validators>(&hyper::body::to_bytes(response).await.unwrap())
+                .expect("Should deserialize")
+        };
+
+        let malformed = put_validators(
+            put(r#"["https://tom.adex.network/", "not a url"]"#),
+            &cache,
+            &config,
+        )
+        .await
+        .expect("Should handle the request");
+        assert_eq!(StatusCode::BAD_REQUEST, malformed.status());
+        assert_eq!(
+            vec![tom.clone()],
+            cache.validators().await.into_iter().collect::<Vec<_>>()
+        );
+
+        // add
+        let body = r#"["https://tom.adex.network/", "http://localhost:8005/"]"#;
+        let added = validators(put_validators(put(body), &cache, &config).await.unwrap()).await;
+        assert_eq!(vec![jerry.clone(), tom.clone()], added.validators);
+        assert_eq!(vec![jerry.clone()], added.added);
+        // the added Validator is backfilled
+        assert!(cache
+            .active
+            .read()
+            .await
+            .contains_key(&ChannelId::from([9; 32])));
+
+        // idempotent re-add
+        let re_added = validators(put_validators(put(body), &cache, &config).await.unwrap()).await;
+        assert_eq!(added.validators, re_added.validators);
+        assert!(re_added.added.is_empty());
+
+        // remove
+        let removed = validators(
+            delete_validator("localhost:8005", false, &cache, &config)
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(Some(jerry.clone()), removed.removed);
+        assert_eq!(vec![tom.clone()], removed.validators);
+        assert!(!removed.persisted);
+        // the Campaigns collected from it are kept
+        assert!(cache
+            .active
+            .read()
+            .await
+            .contains_key(&ChannelId::from([9; 32])));
+
+        let not_found = delete_validator("localhost:8005", false, &cache, &config)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::NOT_FOUND, not_found.status());
+
+        let listed = validators(get_validators(&cache).await.unwrap()).await;
+        assert_eq!(vec![tom.clone()], listed.validators);
+
+        // the changes compared to the configured ones
+        let stats = cache.stats().await.validators;
+        assert!(stats.changed_at.is_some());
+        assert!(stats.added.is_empty() && stats.removed.is_empty());
+
+        let body = r#"["http://localhost:8005/"]"#;
+        validators(put_validators(put(body), &cache, &config).await.unwrap()).await;
+        let stats = cache.stats().await.validators;
+        assert_eq!(vec![jerry], stats.added);
+        assert_eq!(vec![tom], stats.removed);
+    }
+
+    #[tokio::test]
+    async fn the_validators_are_persisted_only_to_a_config_file() {
+        let tom: ApiUrl = "https://tom.adex.network/".parse().expect("Valid URL");
+        let client = MockClient::init(vec![HashMap::new()], vec![], None)
+            .await
+            .with_validators(std::iter::once(tom.clone()).collect());
+        let cache = crate::cache::Cache::initialize(client).await;
+
+        let put = |body: &str| {
+            Request::put(format!("{}?persist=true", crate::ROUTE_VALIDATORS))
+                .body(Body::from(body.to_string()))
+                .expect("Should build Request")
+        };
+        let body = r#"["https://tom.adex.network/", "http://localhost:8005/"]"#;
+
+        // loaded from the defaults
+        let conflict = put_validators(put(body), &cache, &DEVELOPMENT)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::CONFLICT, conflict.status());
+        assert_eq!(
+            vec![tom.clone()],
+            cache.validators().await.into_iter().collect::<Vec<_>>(),
+            "The Validators shouldn't change if they can't be persisted"
+        );
+
+        let path = std::env::temp_dir().join("supermarket-admin-persist-validators-test.toml");
+        std::fs::write(&path, r#"validators = ["https://tom.adex.network/"]"#)
+            .expect("Should write config file");
+        let config = Config::new(path.to_str(), crate::config::Environment::Development)
+            .expect("Should load config");
+
+        let added = put_validators(put(body), &cache, &config)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::OK, added.status());
+        let added: Validators =
+            serde_json::from_slice(&hyper::body::to_bytes(added).await.unwrap())
+                .expect("Should deserialize");
+        assert!(added.persisted);
+        assert_eq!(2, config.reload().expect("Should reload").validators.len());
+
+        let removed = delete_validator("tom.adex.network", true, &cache, &config)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::OK, removed.status());
+        let reloaded = config.reload().expect("Should reload");
+        std::fs::remove_file(&path).expect("Should remove config file");
+        assert_eq!(
+            vec!["http://localhost:8005/"
+                .parse::<ApiUrl>()
+                .expect("Valid URL")],
+            reloaded.validators.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn persisting_is_requested_by_the_query() {
+        assert!(persist_requested(Some("persist=true")));
+        assert!(persist_requested(Some("a=b&persist=true")));
+        assert!(!persist_requested(Some("persist=false")));
+        assert!(!persist_requested(Some("persist")));
+        assert!(!persist_requested(None));
+    }
+
+    #[tokio::test]
+    async fn publisher_stats_roll_over_hourly() {
+        use crate::{
+            units_for_slot::publisher_stats::Served,
+            util::{test::MockClock, Clock},
+        };
+        use chrono::{TimeZone, Utc};
+        use primitives::util::tests::prep_db::IDS;
+        use std::{sync::Arc, time::Duration};
+
+        let clock = MockClock::starting_at(Utc.ymd(2021, 3, 1).and_hms(10, 50, 0));
+        let client = MockClient::init(vec![HashMap::new()], vec![], None).await;
+        let cache = crate::cache::Cache::builder(client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        let publisher = IDS["publisher"];
+        let record = |served: Served| {
+            let (cache, clock) = (&cache, &clock);
+
+            async move {
+                cache
+                    .publisher_stats
+                    .write()
+                    .await
+                    .record(publisher, served, clock.now_utc());
+            }
+        };
+        let stats = |address: String| {
+            let cache = &cache;
+
+            async move {
+                let response = get_publisher_stats(&address, cache)
+                    .await
+                    .expect("Should handle the request");
+                assert_eq!(StatusCode::OK, response.status());
+
+                serde_json::from_slice::<serde_json::Value>(
+                    &hyper::body::to_bytes(response).await.unwrap(),
+                )
+                .expect("Should deserialize")
+            }
+        };
+
+        record(Served::Matched).await;
+        record(Served::Fallback).await;
+        clock.advance(Duration::from_secs(20 * 60));
+        record(Served::Matched).await;
+
+        let json = stats(publisher.to_string()).await;
+        assert_eq!(
+            serde_json::json!({ "requests": 3, "matched": 2, "fallbacks": 1 }),
+            json["total"]
+        );
+        assert_eq!(2, json["hourly"].as_array().expect("Array").len());
+        assert_eq!(serde_json::json!(2), json["hourly"][0]["requests"]);
+        assert_eq!(serde_json::json!(1), json["hourly"][1]["requests"]);
+
+        // the first hour is out of the retention
+        clock.advance(Duration::from_secs(23 * 60 * 60));
+        let json = stats(publisher.to_string()).await;
+        assert_eq!(serde_json::json!(1), json["total"]["requests"]);
+
+        let untracked = stats(IDS["publisher2"].to_string()).await;
+        assert_eq!(serde_json::json!(0), untracked["total"]["requests"]);
+
+        let malformed = get_publisher_stats("0xnot-an-address", &cache)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::BAD_REQUEST, malformed.status());
+    }
+
+    #[tokio::test]
+    async fn campaign_serve_stats_are_served_after_the_flush() {
+        use crate::util::{test::MockClock, Clock};
+        use chrono::{TimeZone, Utc};
+        use std::sync::Arc;
+
+        let clock = MockClock::starting_at(Utc.ymd(2021, 3, 1).and_hms(10, 50, 0));
+        let client = MockClient::init(vec![HashMap::new()], vec![], None).await;
+        let cache = crate::cache::Cache::builder(client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        let config = DEVELOPMENT.clone();
+        let serve_stats = || async {
+            let response = get_campaign_serve_stats(&DUMMY_CHANNEL.id.to_string(), &cache, &config)
+                .await
+                .expect("Should handle the request");
+            assert_eq!(StatusCode::OK, response.status());
+
+            serde_json::from_slice::<serde_json::Value>(
+                &hyper::body::to_bytes(response).await.unwrap(),
+            )
+            .expect("Should deserialize")
+        };
+
+        cache.serve_counters.record(DUMMY_CHANNEL.id, true);
+        cache.serve_counters.record(DUMMY_CHANNEL.id, false);
+        // not flushed yet
+        assert_eq!(serde_json::json!([]), serve_stats().await["hourly"]);
+
+        cache.serve_history.write().await.flush(
+            cache.serve_counters.take(),
+            clock.now_utc(),
+            &config.serve_stats,
+        );
+        let json = serve_stats().await;
+        assert_eq!(serde_json::json!({ "served": 2, "top": 1 }), json["total"]);
+        assert_eq!(
+            serde_json::json!([{ "hour": "2021-03-01T10:00:00Z", "served": 2, "top": 1 }]),
+            json["hourly"]
+        );
+
+        let malformed = get_campaign_serve_stats("0xnot-a-channel", &cache, &config)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::BAD_REQUEST, malformed.status());
+    }
+
+    #[tokio::test]
+    async fn lists_the_servable_units_of_the_campaign() {
+        use crate::units_for_slot::media_check::Reachability;
+        use primitives::util::tests::prep_db::DUMMY_AD_UNITS;
+
+        let unit = |ipfs: &str, archived: bool| {
+            let mut unit = DUMMY_AD_UNITS[0].clone();
+            unit.ipfs = ipfs.to_string();
+            unit.archived = archived;
+
+            unit
+        };
+        let mut active = DUMMY_CHANNEL.clone();
+        active.spec.ad_units = vec![
+            unit("QmServed", false),
+            unit("QmArchived", true),
+            unit("QmUnpinned", false),
+        ];
+        let mut waiting = active.clone();
+        waiting.id = ChannelId::from([2; 32]);
+        let campaigns = vec![
+            Campaign::new(active.clone(), Status::Active, Default::default()),
+            Campaign::new(waiting.clone(), Status::Waiting, Default::default()),
+        ]
+        .into_iter()
+        .map(|campaign| (campaign.channel.id, campaign))
+        .collect();
+        let cache =
+            crate::cache::Cache::initialize(MockClient::init(vec![campaigns], vec![], None).await)
+                .await;
+
+        let mut config = DEVELOPMENT.clone();
+        config.media_check.enabled = true;
+        let now = cache.clock().now_instant();
+        cache.media_checks.write().await.record(
+            "QmUnpinned".to_string(),
+            Some(Reachability::Unreachable),
+            now,
+            config.media_check.ttl,
+        );
+
+        let units = |channel_id: String, config: Config| {
+            let cache = cache.clone();
+
+            async move {
+                let response = get_campaign_units(&channel_id, &cache, &config)
+                    .await
+                    .expect("Should handle the request");
+                assert_eq!(StatusCode::OK, response.status());
+
+                serde_json::from_slice::<serde_json::Value>(
+                    &hyper::body::to_bytes(response).await.unwrap(),
+                )
+                .expect("Should deserialize")
+            }
+        };
+        let servable = |json: &serde_json::Value| {
+            json["units"]
+                .as_array()
+                .expect("Array")
+                .iter()
+                .map(|unit| (unit["ipfs"].clone(), unit["servable"].clone()))
+                .collect::<Vec<_>>()
+        };
+        let expected = |served: [bool; 3]| {
+            vec!["QmServed", "QmArchived", "QmUnpinned"]
+                .into_iter()
+                .zip(served.iter())
+                .map(|(ipfs, served)| (serde_json::json!(ipfs), serde_json::json!(served)))
+                .collect::<Vec<_>>()
+        };
+
+        // the unreachable media is demoted
+        let json = units(active.id.to_string(), config.clone()).await;
+        assert_eq!(serde_json::json!([]), json["failedChecks"]);
+        assert_eq!(expected([true, false, true]), servable(&json));
+        assert_eq!(
+            serde_json::json!(true),
+            json["units"][2]["mediaUnreachable"]
+        );
+        assert_eq!(serde_json::json!(true), json["units"][1]["archived"]);
+
+        config.media_check.policy = UnreachableMediaPolicy::Exclude;
+        let json = units(active.id.to_string(), config.clone()).await;
+        assert_eq!(expected([true, false, false]), servable(&json));
+
+        // without checking the media
+        let json = units(active.id.to_string(), DEVELOPMENT.clone()).await;
+        assert_eq!(expected([true, false, true]), servable(&json));
+        assert!(json["units"][2].get("mediaUnreachable").is_none());
+
+        let json = units(waiting.id.to_string(), config.clone()).await;
+        assert_eq!(serde_json::json!(["status"]), json["failedChecks"]);
+        assert_eq!(expected([false, false, false]), servable(&json));
+
+        let unknown = get_campaign_units(&ChannelId::from([3; 32]).to_string(), &cache, &config)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::NOT_FOUND, unknown.status());
+
+        let malformed = get_campaign_units("not-a-channel", &cache, &config)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::BAD_REQUEST, malformed.status());
+    }
+}
@@ -0,0 +1,734 @@
+//! Replay protection of the mutating admin routes (`POST /validators/refresh`, `PUT /validators`
+//! & `DELETE /validators/:host`), see [`Idempotency`](crate::config::Idempotency).
+//!
+//! A request with an `Idempotency-Key` header is executed once and its response is stored with the key,
+//! so a retry (e.g. after a timeout of the operator's script) gets the same response without executing it again.
+//! The requests without the header are executed as usual.
+use http::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Method, StatusCode,
+};
+use hyper::{
+    body::{Bytes, HttpBody},
+    Body, Request, Response,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+};
+use tokio::time::Instant;
+
+use crate::{
+    bad_request,
+    cache::{Cache, Cached, Client},
+    config::Idempotency,
+    metrics::ADMIN_IDEMPOTENCY_KEYS,
+    Config, Error,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set to `true` on the stored responses served to the retries
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+pub const MAX_KEY_LENGTH: usize = 255;
+/// The request body is buffered for the [`Fingerprint`], the admin bodies (e.g. the Validators list) are small
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// The method, path (incl. the query, e.g. `?persist=true`) & body of a request, a key can't be reused for another request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub fn new(method: &Method, path: &str, body: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        (method.as_str(), path, body).hash(&mut hasher);
+
+        Self(hasher.finish())
+    }
+}
+
+/// The response of the first request with a key, served to its retries
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredResponse {
+    pub status: StatusCode,
+    pub content_type: Option<HeaderValue>,
+    pub body: Bytes,
+}
+
+impl StoredResponse {
+    fn replay(&self) -> Result<Response<Body>, Error> {
+        let mut response = Response::builder()
+            .status(self.status)
+            .header(IDEMPOTENT_REPLAYED_HEADER, "true");
+        if let Some(content_type) = &self.content_type {
+            response = response.header(CONTENT_TYPE, content_type);
+        }
+
+        Ok(response.body(Body::from(self.body.clone()))?)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    /// The first request with the key is still executing
+    InFlight,
+    Completed(StoredResponse),
+}
+
+#[derive(Debug, Clone)]
+struct Key {
+    fingerprint: Fingerprint,
+    entry: Entry,
+    stored_at: Instant,
+}
+
+/// What to do with a request with an `Idempotency-Key`, see [`IdempotencyKeys::begin`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Begin {
+    /// The key is new (or expired), the request is executed
+    Execute,
+    /// A retry of a completed request
+    Replay(StoredResponse),
+    /// A retry while the first request is still executing
+    InProgress,
+    /// The key was used for another request
+    Mismatch,
+    /// All of the `max_keys` are in-flight, so none of them can make room for the new key
+    Full,
+}
+
+/// The recent `Idempotency-Key`s with the responses of their requests
+#[derive(Debug, Default)]
+pub struct IdempotencyKeys {
+    keys: HashMap<String, Key>,
+}
+
+impl IdempotencyKeys {
+    /// Marks a new (or expired) key as in-flight, the request should then be executed and [`complete`](Self::complete)d.
+    /// When the `max_keys` are remembered the expired ones are dropped and if none of them expired,
+    /// the oldest completed key makes room for the new one. The in-flight keys are never evicted,
+    /// otherwise a retry would execute the request a second time.
+    pub fn begin(
+        &mut self,
+        key: &str,
+        fingerprint: Fingerprint,
+        now: Instant,
+        config: &Idempotency,
+    ) -> Begin {
+        // the window of a key starts once its request completes
+        let expired = |stored: &Key| {
+            matches!(stored.entry, Entry::Completed(_))
+                && now.saturating_duration_since(stored.stored_at) >= config.window
+        };
+
+        match self.keys.get(key) {
+            Some(stored) if !expired(stored) => {
+                return match (&stored.entry, stored.fingerprint == fingerprint) {
+                    (_, false) => Begin::Mismatch,
+                    (Entry::InFlight, true) => Begin::InProgress,
+                    (Entry::Completed(response), true) => Begin::Replay(response.clone()),
+                }
+            }
+            Some(_) => {}
+            None if self.keys.len() >= config.max_keys => {
+                self.keys.retain(|_, stored| !expired(stored));
+
+                if self.keys.len() >= config.max_keys {
+                    let oldest = self
+                        .keys
+                        .iter()
+                        .filter(|(_, stored)| matches!(stored.entry, Entry::Completed(_)))
+                        .min_by_key(|(_, stored)| stored.stored_at)
+                        .map(|(key, _)| key.clone());
+                    match oldest {
+                        Some(oldest) => {
+                            self.keys.remove(&oldest);
+                        }
+                        None => return Begin::Full,
+                    }
+                }
+            }
+            None => {}
+        }
+
+        self.keys.insert(
+            key.to_string(),
+            Key {
+                fingerprint,
+                entry: Entry::InFlight,
+                stored_at: now,
+            },
+        );
+
+        Begin::Execute
+    }
+
+    /// Stores the response of the in-flight request, its window starts now
+    pub fn complete(
+        &mut self,
+        key: &str,
+        fingerprint: Fingerprint,
+        response: StoredResponse,
+        now: Instant,
+    ) {
+        if let Some(stored) = self.in_flight(key, fingerprint) {
+            stored.entry = Entry::Completed(response);
+            stored.stored_at = now;
+        }
+    }
+
+    /// Forgets the in-flight request (e.g. it failed), so its retry is executed again
+    pub fn forget(&mut self, key: &str, fingerprint: Fingerprint) {
+        if self.in_flight(key, fingerprint).is_some() {
+            self.keys.remove(key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn in_flight(&mut self, key: &str, fingerprint: Fingerprint) -> Option<&mut Key> {
+        self.keys.get_mut(key).filter(|stored| {
+            stored.fingerprint == fingerprint && matches!(stored.entry, Entry::InFlight)
+        })
+    }
+}
+
+/// Forgets the in-flight key if the request is dropped (e.g. the client disconnected) before it's completed
+struct InFlight {
+    keys: Cached<IdempotencyKeys>,
+    key: Option<String>,
+    fingerprint: Fingerprint,
+}
+
+impl InFlight {
+    async fn complete(mut self, response: StoredResponse, now: Instant) {
+        if let Some(key) = self.key.take() {
+            self.keys
+                .write()
+                .await
+                .complete(&key, self.fingerprint, response, now);
+        }
+    }
+
+    async fn forget(mut self) {
+        if let Some(key) = self.key.take() {
+            self.keys.write().await.forget(&key, self.fingerprint);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let (keys, fingerprint) = (self.keys.clone(), self.fingerprint);
+
+            tokio::spawn(async move { keys.write().await.forget(&key, fingerprint) });
+        }
+    }
+}
+
+/// Executes the mutating admin `handler` once per `Idempotency-Key` within the [`Idempotency.window`](Idempotency::window):
+/// - without the header, it's executed as usual
+/// - `400 Bad Request` - if the key is empty, longer than [`MAX_KEY_LENGTH`] or not visible ASCII
+/// - `413 Payload Too Large` - if the body is larger than [`MAX_BODY_SIZE`]
+/// - the stored response (with the `Idempotent-Replayed: true` header) - for a retry of a completed request
+/// - `409 Conflict` - for a retry while the first request is still executing
+/// - `422 Unprocessable Entity` - if the key was used for another method, path or body
+/// - `503 Service Unavailable` - if all of the [`Idempotency.max_keys`](Idempotency::max_keys) are still executing
+///
+/// The `5xx` responses (and the errors) are not stored, so their retries are executed again.
+pub async fn idempotent<C, H, F>(
+    req: Request<Body>,
+    cache: &Cache<C>,
+    config: &Config,
+    handler: H,
+) -> Result<Response<Body>, Error>
+where
+    C: Client,
+    H: FnOnce(Request<Body>) -> F,
+    F: Future<Output = Result<Response<Body>, Error>>,
+{
+    let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        None => return handler(req).await,
+        Some(key) => match parse_key(key) {
+            Some(key) => key,
+            None => {
+                return Ok(bad_request(format!(
+                    "The Idempotency-Key should be 1 to {} visible ASCII characters",
+                    MAX_KEY_LENGTH
+                )))
+            }
+        },
+    };
+
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(error) => return Ok(bad_request(format!("Reading body: {}", error))),
+        };
+
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            ADMIN_IDEMPOTENCY_KEYS
+                .with_label_values(&["too_large"])
+                .inc();
+            return text_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!("The body should be at most {} bytes", MAX_BODY_SIZE),
+            );
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    let body = Bytes::from(bytes);
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map_or(parts.uri.path(), |path_and_query| path_and_query.as_str());
+    let fingerprint = Fingerprint::new(&parts.method, path_and_query, &body);
+    let req = Request::from_parts(parts, Body::from(body));
+
+    let begin = cache.idempotency_keys.write().await.begin(
+        &key,
+        fingerprint,
+        cache.clock().now_instant(),
+        &config.idempotency,
+    );
+    match begin {
+        Begin::Execute => {}
+        Begin::Replay(stored) => {
+            ADMIN_IDEMPOTENCY_KEYS
+                .with_label_values(&["replayed"])
+                .inc();
+            return stored.replay();
+        }
+        Begin::InProgress => {
+            ADMIN_IDEMPOTENCY_KEYS
+                .with_label_values(&["in_progress"])
+                .inc();
+            return text_response(
+                StatusCode::CONFLICT,
+                "A request with the same Idempotency-Key is still executing",
+            );
+        }
+        Begin::Mismatch => {
+            ADMIN_IDEMPOTENCY_KEYS
+                .with_label_values(&["mismatch"])
+                .inc();
+            return text_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The Idempotency-Key was used for another request",
+            );
+        }
+        Begin::Full => {
+            ADMIN_IDEMPOTENCY_KEYS.with_label_values(&["full"]).inc();
+            return text_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many requests with an Idempotency-Key are executing",
+            );
+        }
+    }
+
+    let in_flight = InFlight {
+        keys: cache.idempotency_keys.clone(),
+        key: Some(key),
+        fingerprint,
+    };
+    ADMIN_IDEMPOTENCY_KEYS
+        .with_label_values(&["executed"])
+        .inc();
+
+    let response = match handler(req).await {
+        Ok(response) if !response.status().is_server_error() => response,
+        result => {
+            in_flight.forget().await;
+            return result;
+        }
+    };
+
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let stored = StoredResponse {
+        status: parts.status,
+        content_type: parts.headers.get(CONTENT_TYPE).cloned(),
+        body: body.clone(),
+    };
+    in_flight
+        .complete(stored, cache.clock().now_instant())
+        .await;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+fn parse_key(key: &HeaderValue) -> Option<String> {
+    let key = key.to_str().ok()?;
+    let valid = !key.is_empty()
+        && key.len() <= MAX_KEY_LENGTH
+        && key.bytes().all(|byte| byte.is_ascii_graphic());
+
+    if valid {
+        Some(key.to_string())
+    } else {
+        None
+    }
+}
+
+fn text_response(status: StatusCode, message: &str) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        admin::{delete_validator, put_validators},
+        cache::MockClient,
+        config::DEVELOPMENT,
+        util::test::MockClock,
+    };
+    use primitives::util::ApiUrl;
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    fn request(method: Method, uri: &str, key: Option<&str>, body: &str) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(key) = key {
+            builder = builder.header(IDEMPOTENCY_KEY_HEADER, key);
+        }
+
+        builder
+            .body(Body::from(body.to_string()))
+            .expect("Should build Request")
+    }
+
+    async fn body(response: Response<Body>) -> Bytes {
+        hyper::body::to_bytes(response)
+            .await
+            .expect("Should read the body")
+    }
+
+    fn is_replayed(response: &Response<Body>) -> bool {
+        response.headers().get(IDEMPOTENT_REPLAYED_HEADER)
+            == Some(&HeaderValue::from_static("true"))
+    }
+
+    #[tokio::test]
+    async fn a_replayed_key_is_executed_once() {
+        let tom: ApiUrl = "https://tom.adex.network/".parse().expect("Valid URL");
+        let clock = MockClock::new();
+        let client = MockClient::init(vec![HashMap::new()], vec![], None)
+            .await
+            .with_validators(std::iter::once(tom.clone()).collect());
+        let cache = Cache::builder(client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        let config = DEVELOPMENT.clone();
+
+        let executions = AtomicUsize::new(0);
+        let put = |key: Option<&str>, body: &str| {
+            let req = request(Method::PUT, crate::ROUTE_VALIDATORS, key, body);
+
+            idempotent(req, &cache, &config, |req| {
+                executions.fetch_add(1, Ordering::SeqCst);
+                put_validators(req, &cache, &config)
+            })
+        };
+
+        let both = r#"["https://tom.adex.network/", "http://localhost:8005/"]"#;
+        let first = put(Some("add-jerry"), both).await.expect("Should execute");
+        assert_eq!(StatusCode::OK, first.status());
+        assert!(!is_replayed(&first));
+        let first = body(first).await;
+        assert_eq!(2, cache.validators().await.len());
+
+        // the Validators change in the meantime
+        assert_eq!(
+            StatusCode::OK,
+            delete_validator("localhost:8005", false, &cache, &config)
+                .await
+                .expect("Should execute")
+                .status()
+        );
+
+        let replayed = put(Some("add-jerry"), both).await.expect("Should replay");
+        assert_eq!(StatusCode::OK, replayed.status());
+        assert!(is_replayed(&replayed));
+        assert_eq!(
+            Some(&HeaderValue::from_static("application/json")),
+            replayed.headers().get(CONTENT_TYPE)
+        );
+        assert_eq!(first, body(replayed).await);
+        assert_eq!(1, executions.load(Ordering::SeqCst));
+        // it's not executed again, so the removed Validator isn't re-added
+        assert_eq!(
+            vec![tom.clone()],
+            cache.validators().await.into_iter().collect::<Vec<_>>()
+        );
+
+        // the key can't be reused for another body
+        let mismatch = put(Some("add-jerry"), r#"["https://tom.adex.network/"]"#)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, mismatch.status());
+        assert_eq!(1, executions.load(Ordering::SeqCst));
+
+        // without a key every request is executed
+        for _ in 0..2 {
+            let response = put(None, both).await.expect("Should execute");
+            assert_eq!(StatusCode::OK, response.status());
+            assert!(!is_replayed(&response));
+        }
+        assert_eq!(3, executions.load(Ordering::SeqCst));
+
+        // after the window the key is executed again
+        clock.advance(config.idempotency.window);
+        let expired = put(Some("add-jerry"), both).await.expect("Should execute");
+        assert!(!is_replayed(&expired));
+        assert_eq!(4, executions.load(Ordering::SeqCst));
+
+        let too_long = "k".repeat(MAX_KEY_LENGTH + 1);
+        for invalid in &["", too_long.as_str(), "a key"] {
+            let response = put(Some(invalid), both)
+                .await
+                .expect("Should handle the request");
+            assert_eq!(StatusCode::BAD_REQUEST, response.status(), "{:?}", invalid);
+        }
+        assert_eq!(4, executions.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn the_client_errors_are_replayed_and_the_server_errors_are_retried() {
+        let client = MockClient::init(vec![HashMap::new()], vec![], None).await;
+        let cache = Cache::initialize(client).await;
+        let config = DEVELOPMENT.clone();
+
+        // `404` for a missing Validator, the same response is replayed
+        let executions = AtomicUsize::new(0);
+        for _ in 0..2 {
+            let req = request(
+                Method::DELETE,
+                "/validators/localhost:8005",
+                Some("delete"),
+                "",
+            );
+            let response = idempotent(req, &cache, &config, |_| {
+                executions.fetch_add(1, Ordering::SeqCst);
+                delete_validator("localhost:8005", false, &cache, &config)
+            })
+            .await
+            .expect("Should handle the request");
+            assert_eq!(StatusCode::NOT_FOUND, response.status());
+        }
+        assert_eq!(1, executions.load(Ordering::SeqCst));
+
+        let executions = AtomicUsize::new(0);
+        for _ in 0..2 {
+            let req = request(
+                Method::POST,
+                crate::ROUTE_VALIDATORS_REFRESH,
+                Some("refresh"),
+                "{}",
+            );
+            let response = idempotent(req, &cache, &config, |_| async {
+                executions.fetch_add(1, Ordering::SeqCst);
+                text_response(StatusCode::BAD_GATEWAY, "The Validator is unreachable")
+            })
+            .await
+            .expect("Should handle the request");
+            assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+            assert!(!is_replayed(&response));
+        }
+        assert_eq!(2, executions.load(Ordering::SeqCst));
+        // only the `404` is remembered
+        assert_eq!(1, cache.idempotency_keys.read().await.len());
+    }
+
+    #[tokio::test]
+    async fn a_retry_during_the_execution_is_refused() {
+        let client = MockClient::init(vec![HashMap::new()], vec![], None).await;
+        let cache = Cache::initialize(client).await;
+        let config = DEVELOPMENT.clone();
+
+        let executions = AtomicUsize::new(0);
+        let slow_refresh = || {
+            let req = request(
+                Method::POST,
+                crate::ROUTE_VALIDATORS_REFRESH,
+                Some("refresh"),
+                "{}",
+            );
+
+            idempotent(req, &cache, &config, |_| async {
+                executions.fetch_add(1, Ordering::SeqCst);
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+                text_response(StatusCode::OK, "refreshed")
+            })
+        };
+
+        let (first, retry) = futures::join!(slow_refresh(), slow_refresh());
+        let mut statuses = vec![
+            first.expect("Should execute").status(),
+            retry.expect("Should handle the request").status(),
+        ];
+        statuses.sort();
+        assert_eq!(vec![StatusCode::OK, StatusCode::CONFLICT], statuses);
+        assert_eq!(1, executions.load(Ordering::SeqCst));
+
+        let replayed = slow_refresh().await.expect("Should replay");
+        assert!(is_replayed(&replayed));
+        assert_eq!("refreshed", body(replayed).await);
+        assert_eq!(1, executions.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn the_keys_are_bounded() {
+        let config = Idempotency {
+            window: Duration::from_secs(60),
+            max_keys: 2,
+        };
+        let fingerprint = Fingerprint::new(&Method::PUT, crate::ROUTE_VALIDATORS, b"[]");
+        let other = Fingerprint::new(&Method::PUT, crate::ROUTE_VALIDATORS, b"[\"other\"]");
+        let response = StoredResponse {
+            status: StatusCode::OK,
+            content_type: None,
+            body: Bytes::from_static(b"[]"),
+        };
+        let now = Instant::now();
+        let mut keys = IdempotencyKeys::default();
+
+        for (key, seconds) in &[("first", 0), ("second", 1)] {
+            let at = now + Duration::from_secs(*seconds);
+            assert_eq!(Begin::Execute, keys.begin(key, fingerprint, at, &config));
+            keys.complete(key, fingerprint, response.clone(), at);
+        }
+
+        // none of them expired, so the oldest one makes room
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            Begin::Execute,
+            keys.begin("third", fingerprint, later, &config)
+        );
+        assert_eq!(2, keys.len());
+        assert_eq!(
+            Begin::Replay(response),
+            keys.begin("second", fingerprint, later, &config)
+        );
+        assert_eq!(
+            Begin::InProgress,
+            keys.begin("third", fingerprint, later, &config)
+        );
+        assert_eq!(Begin::Mismatch, keys.begin("third", other, later, &config));
+        assert_eq!(
+            Begin::Execute,
+            keys.begin("first", fingerprint, later, &config)
+        );
+        assert_eq!(2, keys.len());
+        keys.complete("first", fingerprint, response.clone(), later);
+
+        // the expired ones are dropped first
+        let expired = now + Duration::from_secs(62);
+        assert_eq!(
+            Begin::Execute,
+            keys.begin("fourth", fingerprint, expired, &config)
+        );
+        assert_eq!(2, keys.len());
+        assert_eq!(
+            Begin::InProgress,
+            keys.begin("third", fingerprint, expired, &config)
+        );
+    }
+
+    #[test]
+    fn the_in_flight_keys_are_never_evicted() {
+        let config = Idempotency {
+            window: Duration::from_secs(60),
+            max_keys: 2,
+        };
+        let fingerprint = Fingerprint::new(&Method::POST, crate::ROUTE_VALIDATORS_REFRESH, b"{}");
+        let response = StoredResponse {
+            status: StatusCode::OK,
+            content_type: None,
+            body: Bytes::from_static(b"refreshed"),
+        };
+        let now = Instant::now();
+        let mut keys = IdempotencyKeys::default();
+
+        for key in &["first", "second"] {
+            assert_eq!(Begin::Execute, keys.begin(key, fingerprint, now, &config));
+        }
+
+        // even after the window, the requests are still executing
+        let later = now + Duration::from_secs(120);
+        assert_eq!(
+            Begin::Full,
+            keys.begin("third", fingerprint, later, &config)
+        );
+        assert_eq!(2, keys.len());
+        for key in &["first", "second"] {
+            assert_eq!(
+                Begin::InProgress,
+                keys.begin(key, fingerprint, later, &config)
+            );
+        }
+
+        // a completed key makes room
+        keys.complete("first", fingerprint, response, later);
+        assert_eq!(
+            Begin::Execute,
+            keys.begin("third", fingerprint, later, &config)
+        );
+        assert_eq!(
+            Begin::InProgress,
+            keys.begin("second", fingerprint, later, &config)
+        );
+        assert_eq!(
+            Begin::Full,
+            keys.begin("first", fingerprint, later, &config)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_body_larger_than_the_max_is_refused() {
+        let client = MockClient::init(vec![HashMap::new()], vec![], None).await;
+        let cache = Cache::initialize(client).await;
+        let config = DEVELOPMENT.clone();
+
+        let executions = AtomicUsize::new(0);
+        let put = |body: String| {
+            let req = request(Method::PUT, crate::ROUTE_VALIDATORS, Some("large"), &body);
+
+            idempotent(req, &cache, &config, |_| async {
+                executions.fetch_add(1, Ordering::SeqCst);
+                text_response(StatusCode::OK, "updated")
+            })
+        };
+
+        let too_large = put("a".repeat(MAX_BODY_SIZE + 1))
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, too_large.status());
+        assert_eq!(0, executions.load(Ordering::SeqCst));
+        assert!(cache.idempotency_keys.read().await.is_empty());
+
+        let at_most = put("a".repeat(MAX_BODY_SIZE))
+            .await
+            .expect("Should execute");
+        assert_eq!(StatusCode::OK, at_most.status());
+        assert_eq!(1, executions.load(Ordering::SeqCst));
+    }
+}
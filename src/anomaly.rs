@@ -0,0 +1,377 @@
+//! Temporarily blocking the client IPs and the AdSlots with too many `4xx` responses, e.g. a client requesting
+//! random AdSlots or an AdSlot which keeps failing, see [`AnomalyBlocking`].
+//!
+//! The `4xx` responses are counted per key within a fixed `window`, a key reaching its threshold gets
+//! `429 Too Many Requests` for the `block_duration` without being routed. The counters are compacted
+//! every `window` by [`spawn_compaction`], which also logs the expired blocks.
+use crate::{
+    admin,
+    cache::{Cache, Client},
+    config::{AnomalyBlocking, Config},
+    metrics::{ANOMALY_BLOCKED, ANOMALY_BLOCKS},
+    units_for_slot::AdSlotPath,
+    ROUTE_HEALTHZ, ROUTE_METRICS, ROUTE_READYZ, ROUTE_VERSION,
+};
+use http::{header::RETRY_AFTER, Request, Response, StatusCode};
+use hyper::Body;
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use std::{collections::HashMap, hash::Hash, net::IpAddr, time::Duration};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+struct Counter {
+    window_start: Instant,
+    responses: u32,
+    blocked_until: Option<Instant>,
+}
+
+impl Counter {
+    fn is_blocked(&self, now: Instant) -> bool {
+        self.blocked_until.map_or(false, |until| until > now)
+    }
+}
+
+/// The `4xx` responses of the keys within the current window and their blocks
+#[derive(Debug)]
+pub struct Counters<K> {
+    counters: HashMap<K, Counter>,
+}
+
+impl<K> Default for Counters<K> {
+    fn default() -> Self {
+        Self {
+            counters: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Counters<K> {
+    /// The remaining time of the key's block, `None` if it's not blocked
+    pub fn blocked_for(&self, key: &K, now: Instant) -> Option<Duration> {
+        let blocked_until = self.counters.get(key)?.blocked_until?;
+
+        Some(blocked_until.saturating_duration_since(now))
+            .filter(|remaining| *remaining > Duration::from_secs(0))
+    }
+
+    /// Counts a `4xx` response of the key and blocks it once it reaches the `threshold` (`0` never blocks).
+    /// Returns whether the key was blocked by it.
+    ///
+    /// Once there are `max_keys` counters the expired ones are dropped first, if none of them have expired
+    /// the new keys are not counted until the next [`Counters::compact`].
+    pub fn record(
+        &mut self,
+        key: K,
+        threshold: u32,
+        settings: &AnomalyBlocking,
+        now: Instant,
+    ) -> bool {
+        if !self.counters.contains_key(&key) && self.counters.len() >= settings.max_keys {
+            self.compact(settings.window, now);
+
+            if self.counters.len() >= settings.max_keys {
+                return false;
+            }
+        }
+
+        let counter = self.counters.entry(key).or_insert(Counter {
+            window_start: now,
+            responses: 0,
+            blocked_until: None,
+        });
+        if counter.is_blocked(now) {
+            return false;
+        }
+        if now.saturating_duration_since(counter.window_start) >= settings.window {
+            counter.window_start = now;
+            counter.responses = 0;
+        }
+
+        counter.responses += 1;
+        if threshold == 0 || counter.responses < threshold {
+            return false;
+        }
+
+        // the count starts over once the block expires
+        counter.window_start = now;
+        counter.responses = 0;
+        counter.blocked_until = Some(now + settings.block_duration);
+
+        true
+    }
+
+    /// Drops the counters whose window has expired and which are not blocked,
+    /// returns the keys whose block has expired since the last compaction
+    pub fn compact(&mut self, window: Duration, now: Instant) -> Vec<K> {
+        let mut unblocked = vec![];
+
+        self.counters.retain(|key, counter| {
+            if counter.blocked_until.map_or(false, |until| until <= now) {
+                counter.blocked_until = None;
+                unblocked.push(key.clone());
+            }
+
+            counter.is_blocked(now) || now.saturating_duration_since(counter.window_start) < window
+        });
+
+        unblocked
+    }
+
+    /// The number of the currently blocked keys
+    pub fn blocked(&self, now: Instant) -> usize {
+        self.counters
+            .values()
+            .filter(|counter| counter.is_blocked(now))
+            .count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+}
+
+/// The counters of the client IPs and of the AdSlots of the units-for-slot requests
+#[derive(Debug, Default)]
+pub struct Anomalies {
+    pub ips: Counters<IpAddr>,
+    pub slots: Counters<String>,
+}
+
+impl Anomalies {
+    pub fn blocked(&self, now: Instant) -> BlockedStats {
+        BlockedStats {
+            ips: self.ips.blocked(now),
+            slots: self.slots.blocked(now),
+        }
+    }
+}
+
+/// The number of the currently blocked keys, part of the `/stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockedStats {
+    pub ips: usize,
+    pub slots: usize,
+}
+
+/// The keys of a request which are counted and blocked, see [`Tracker::of`]
+#[derive(Debug)]
+pub struct Tracker<C: Client> {
+    ip: Option<IpAddr>,
+    slot: Option<String>,
+    settings: AnomalyBlocking,
+    cache: Cache<C>,
+    logger: Logger,
+}
+
+impl<C: Client> Tracker<C> {
+    /// `None` if the blocking is disabled or it's the health, readiness, metrics or version route,
+    /// which are never blocked
+    pub fn of(
+        req: &Request<Body>,
+        config: &Config,
+        cache: &Cache<C>,
+        logger: &Logger,
+    ) -> Option<Self> {
+        let path = req.uri().path();
+        if !config.anomaly_blocking.enabled
+            || [ROUTE_HEALTHZ, ROUTE_READYZ, ROUTE_METRICS, ROUTE_VERSION].contains(&path)
+        {
+            return None;
+        }
+
+        let slot = match AdSlotPath::parse(path) {
+            AdSlotPath::Ipfs(ipfs) => Some(ipfs.to_string()),
+            _ => None,
+        };
+
+        Some(Self {
+            ip: admin::client_ip(req, &config.trusted_proxies),
+            slot,
+            settings: config.anomaly_blocking,
+            cache: cache.clone(),
+            logger: logger.clone(),
+        })
+    }
+
+    /// `429 Too Many Requests` with a `Retry-After` (in seconds) if the client IP or the AdSlot is blocked
+    pub async fn blocked_response(&self) -> Option<Response<Body>> {
+        let now = self.cache.clock().now_instant();
+        let anomalies = self.cache.anomalies.read().await;
+
+        let ip_block = self.ip.and_then(|ip| anomalies.ips.blocked_for(&ip, now));
+        let slot_block = self
+            .slot
+            .as_ref()
+            .and_then(|slot| anomalies.slots.blocked_for(slot, now));
+        let remaining = ip_block.into_iter().chain(slot_block).max()?;
+        // rounded up, so it's not retried before the block expires
+        let retry_after = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+
+        Some(
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, retry_after)
+                .body(Body::empty())
+                .expect("Too Many Requests response should be valid"),
+        )
+    }
+
+    /// Counts the response if it's a `4xx` and logs the keys which were blocked by it
+    pub async fn record(&self, status: StatusCode) {
+        if !status.is_client_error() {
+            return;
+        }
+
+        let now = self.cache.clock().now_instant();
+        let mut anomalies = self.cache.anomalies.write().await;
+
+        if let Some(ip) = self.ip {
+            if anomalies
+                .ips
+                .record(ip, self.settings.ip_threshold, &self.settings, now)
+            {
+                ANOMALY_BLOCKS.with_label_values(&["ip"]).inc();
+                warn!(&self.logger, "Blocked a client IP with too many 4xx responses"; "ip" => %ip, "duration" => ?self.settings.block_duration);
+            }
+        }
+
+        if let Some(slot) = &self.slot {
+            if anomalies.slots.record(
+                slot.clone(),
+                self.settings.slot_threshold,
+                &self.settings,
+                now,
+            ) {
+                ANOMALY_BLOCKS.with_label_values(&["slot"]).inc();
+                warn!(&self.logger, "Blocked an AdSlot with too many 4xx responses"; "AdSlot" => slot, "duration" => ?self.settings.block_duration);
+            }
+        }
+    }
+}
+
+/// Every `window` drops the expired counters, logs the expired blocks and sets the [`ANOMALY_BLOCKED`] gauges
+pub fn spawn_compaction<C: Client + Send + Sync + 'static>(
+    logger: Logger,
+    cache: Cache<C>,
+    config: Config,
+) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(config.anomaly_blocking.window);
+
+        loop {
+            ticks.tick().await;
+
+            let now = cache.clock().now_instant();
+            let mut anomalies = cache.anomalies.write().await;
+
+            for ip in anomalies.ips.compact(config.anomaly_blocking.window, now) {
+                info!(&logger, "Unblocked a client IP"; "ip" => %ip);
+            }
+            for slot in anomalies.slots.compact(config.anomaly_blocking.window, now) {
+                info!(&logger, "Unblocked an AdSlot"; "AdSlot" => slot);
+            }
+
+            let blocked = anomalies.blocked(now);
+            ANOMALY_BLOCKED
+                .with_label_values(&[cache.network(), "ip"])
+                .set(blocked.ips as i64);
+            ANOMALY_BLOCKED
+                .with_label_values(&[cache.network(), "slot"])
+                .set(blocked.slots as i64);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings() -> AnomalyBlocking {
+        AnomalyBlocking {
+            enabled: true,
+            window: Duration::from_secs(60),
+            ip_threshold: 3,
+            slot_threshold: 3,
+            block_duration: Duration::from_secs(300),
+            max_keys: 2,
+        }
+    }
+
+    #[test]
+    fn a_key_is_blocked_once_it_reaches_the_threshold_until_the_block_expires() {
+        let settings = settings();
+        let now = Instant::now();
+        let mut counters = Counters::default();
+
+        assert!(!counters.record("QmSlot", 3, &settings, now));
+        assert!(!counters.record("QmSlot", 3, &settings, now + Duration::from_secs(1)));
+        assert_eq!(None, counters.blocked_for(&"QmSlot", now));
+
+        let blocked_at = now + Duration::from_secs(2);
+        assert!(counters.record("QmSlot", 3, &settings, blocked_at));
+        assert_eq!(
+            Some(settings.block_duration),
+            counters.blocked_for(&"QmSlot", blocked_at)
+        );
+        assert_eq!(1, counters.blocked(blocked_at));
+        // it's not blocked again while blocked
+        assert!(!counters.record("QmSlot", 3, &settings, blocked_at));
+
+        // the block outlives the window
+        let later = blocked_at + settings.window * 2;
+        assert!(counters.compact(settings.window, later).is_empty());
+        assert_eq!(1, counters.len());
+        assert!(counters.blocked_for(&"QmSlot", later).is_some());
+
+        let expired = blocked_at + settings.block_duration;
+        assert_eq!(None, counters.blocked_for(&"QmSlot", expired));
+        assert_eq!(0, counters.blocked(expired));
+        assert_eq!(vec!["QmSlot"], counters.compact(settings.window, expired));
+        assert!(counters.is_empty());
+
+        // the count starts over
+        assert!(!counters.record("QmSlot", 3, &settings, expired));
+        assert!(!counters.record("QmSlot", 3, &settings, expired));
+    }
+
+    #[test]
+    fn the_count_starts_over_with_every_window() {
+        let settings = settings();
+        let now = Instant::now();
+        let mut counters = Counters::default();
+
+        assert!(!counters.record("QmSlot", 3, &settings, now));
+        assert!(!counters.record("QmSlot", 3, &settings, now));
+        assert!(!counters.record("QmSlot", 3, &settings, now + settings.window));
+        assert!(!counters.record("QmSlot", 3, &settings, now + settings.window));
+        // a threshold of `0` never blocks
+        for _ in 0..10 {
+            assert!(!counters.record("QmOther", 0, &settings, now));
+        }
+    }
+
+    #[test]
+    fn at_most_max_keys_are_counted() {
+        let settings = settings();
+        let now = Instant::now();
+        let mut counters = Counters::default();
+
+        counters.record("QmFirst", 3, &settings, now);
+        counters.record("QmSecond", 3, &settings, now);
+        counters.record("QmThird", 3, &settings, now);
+        assert_eq!(2, counters.len());
+        assert!(!counters.counters.contains_key("QmThird"));
+
+        // the expired counters make room for the new keys
+        let later = now + settings.window;
+        counters.record("QmThird", 3, &settings, later);
+        assert_eq!(1, counters.len());
+        assert!(counters.counters.contains_key("QmThird"));
+    }
+}
@@ -0,0 +1,451 @@
+//! Lightweight bot and scraper classification for the units-for-slot route.
+use http::{HeaderMap, HeaderName};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    sync::Arc,
+};
+use thiserror::Error;
+
+lazy_static::lazy_static! {
+    pub(crate) static ref CLOUDFLARE_CONNECTING_IP_HEADER: HeaderName = HeaderName::from_static("cf-connecting-ip");
+    pub(crate) static ref X_FORWARDED_FOR_HEADER: HeaderName = HeaderName::from_static("x-forwarded-for");
+}
+
+/// The `User-Agent` tokens (see [`user_agent_tokens`]) of known crawlers, scrapers and automation tools.
+///
+/// Whole tokens are matched, so a device or a browser which only contains one of them (e.g. the `Cubot` phones)
+/// isn't a bot.
+const BOT_USER_AGENT_TOKENS: [&str; 35] = [
+    "bot",
+    "robot",
+    "crawler",
+    "spider",
+    "googlebot",
+    "adsbot-google",
+    "mediapartners-google",
+    "bingbot",
+    "yandexbot",
+    "duckduckbot",
+    "applebot",
+    "ahrefsbot",
+    "semrushbot",
+    "mj12bot",
+    "dotbot",
+    "petalbot",
+    "twitterbot",
+    "linkedinbot",
+    "slackbot",
+    "discordbot",
+    "telegrambot",
+    "pinterestbot",
+    "slurp",
+    "scrapy",
+    "headlesschrome",
+    "phantomjs",
+    "lighthouse",
+    "chrome-lighthouse",
+    "facebookexternalhit",
+    "curl",
+    "wget",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "java",
+];
+
+/// The token suffixes of the crawler families with many names, e.g. `baiduspider` or `bytespider`
+const BOT_USER_AGENT_TOKEN_SUFFIXES: [&str; 2] = ["crawler", "spider"];
+
+/// What to do with requests from suspected bots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BotPolicy {
+    /// Serve the request normally
+    Serve,
+    /// Serve the request, but add `"suspectBot": true` to the response
+    Flag,
+    /// Respond with `204 No Content`
+    Block,
+}
+
+/// Is the request from a suspected bot, based on the `User-Agent` and the client IP
+/// (from the `CF-Connecting-IP` or the first `X-Forwarded-For` address)
+pub fn is_suspect_bot(headers: &HeaderMap, deny_list: &CidrSet) -> bool {
+    let user_agent = headers
+        .get(http::header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+
+    let bot_user_agent = user_agent_tokens(&user_agent).any(|token| {
+        BOT_USER_AGENT_TOKENS.contains(&token)
+            || BOT_USER_AGENT_TOKEN_SUFFIXES
+                .iter()
+                .any(|suffix| token.ends_with(suffix))
+    });
+
+    bot_user_agent || client_ip(headers).map_or(false, |ip| deny_list.contains(ip))
+}
+
+/// The words of the `User-Agent`, e.g. `googlebot`, `2`, `1`, `http`, `www`, `google`, `com`, `bot` & `html`
+/// of `Googlebot/2.1; +http://www.google.com/bot.html`, the `-` and `_` are part of the words (e.g. `python-requests`)
+fn user_agent_tokens(user_agent: &str) -> impl Iterator<Item = &str> {
+    user_agent
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+        .filter(|token| !token.is_empty())
+}
+
+fn client_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let header = |name: &HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+
+    header(&CLOUDFLARE_CONNECTING_IP_HEADER)
+        .or_else(|| header(&X_FORWARDED_FOR_HEADER).and_then(|list| list.split(',').next()))
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Invalid CIDR `{0}`, expected e.g. `66.249.64.0/19` or `2001:db8::/32`")]
+pub struct InvalidCidr(String);
+
+/// An IPv4 or IPv6 network, e.g. `66.249.64.0/19`. An address without a prefix is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(cidr.to_string());
+
+        let (address, prefix) = match cidr.trim().find('/') {
+            Some(slash) => (&cidr.trim()[..slash], Some(&cidr.trim()[slash + 1..])),
+            None => (cidr.trim(), None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let width = address_bits(address).1;
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| u32::from(*prefix) <= width)
+                .ok_or_else(invalid)?,
+            None => width as u8,
+        };
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// A set of [`Cidr`]s stored in binary prefix tries (one for IPv4 and one for IPv6),
+/// so a lookup takes at most as many steps as the address bits, regardless of the number of networks.
+#[derive(Debug, Clone, Default)]
+pub struct CidrSet {
+    cidrs: Vec<Cidr>,
+    tries: Arc<(Trie, Trie)>,
+}
+
+impl CidrSet {
+    pub fn new(cidrs: Vec<Cidr>) -> Self {
+        let mut ipv4 = Trie::default();
+        let mut ipv6 = Trie::default();
+
+        for cidr in cidrs.iter() {
+            let (bits, width) = address_bits(cidr.address);
+            let trie = match cidr.address {
+                IpAddr::V4(_) => &mut ipv4,
+                IpAddr::V6(_) => &mut ipv6,
+            };
+
+            trie.insert(prefix_bits(bits, width, cidr.prefix.into()));
+        }
+
+        Self {
+            cidrs,
+            tries: Arc::new((ipv4, ipv6)),
+        }
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4-mapped IPv6 addresses, e.g. `::ffff:66.249.64.1`
+        let address = match address {
+            IpAddr::V6(ipv6) => match ipv6.segments() {
+                [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                    IpAddr::V4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+                }
+                _ => address,
+            },
+            ipv4 => ipv4,
+        };
+
+        let (bits, width) = address_bits(address);
+        let trie = match address {
+            IpAddr::V4(_) => &self.tries.0,
+            IpAddr::V6(_) => &self.tries.1,
+        };
+
+        trie.contains_prefix_of(prefix_bits(bits, width, width))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cidrs.is_empty()
+    }
+}
+
+impl<'de> Deserialize<'de> for CidrSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cidrs = Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|cidr| cidr.parse())
+            .collect::<Result<Vec<Cidr>, _>>()
+            .map_err(serde::de::Error::custom)?;
+
+        Ok(Self::new(cidrs))
+    }
+}
+
+impl Serialize for CidrSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.cidrs.iter().map(ToString::to_string))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Trie {
+    /// The root is the first node
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: [Option<usize>; 2],
+    /// A network ends at this node
+    terminal: bool,
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+impl Trie {
+    fn insert(&mut self, bits: impl Iterator<Item = bool>) {
+        let mut current = 0;
+
+        for bit in bits {
+            current = match self.nodes[current].children[bit as usize] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[current].children[bit as usize] = Some(child);
+
+                    child
+                }
+            };
+        }
+
+        self.nodes[current].terminal = true;
+    }
+
+    /// Whether any of the inserted networks is a prefix of the bits
+    fn contains_prefix_of(&self, bits: impl Iterator<Item = bool>) -> bool {
+        let mut current = 0;
+
+        for bit in bits {
+            if self.nodes[current].terminal {
+                return true;
+            }
+
+            match self.nodes[current].children[bit as usize] {
+                Some(child) => current = child,
+                None => return false,
+            }
+        }
+
+        self.nodes[current].terminal
+    }
+}
+
+/// The address bits (right-aligned) and the width of the address
+fn address_bits(address: IpAddr) -> (u128, u32) {
+    match address {
+        IpAddr::V4(ipv4) => (u32::from(ipv4).into(), 32),
+        IpAddr::V6(ipv6) => (u128::from(ipv6), 128),
+    }
+}
+
+/// The first `length` bits, starting from the most significant bit of the address
+fn prefix_bits(bits: u128, width: u32, length: u32) -> impl Iterator<Item = bool> {
+    (0..length).map(move |index| (bits >> (width - 1 - index)) & 1 == 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn cidr_set(cidrs: &[&str]) -> CidrSet {
+        CidrSet::new(
+            cidrs
+                .iter()
+                .map(|cidr| cidr.parse().expect("Valid CIDR"))
+                .collect(),
+        )
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().expect("Valid IP")
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(
+            Ok(Cidr {
+                address: ip("66.249.64.0"),
+                prefix: 19
+            }),
+            "66.249.64.0/19".parse()
+        );
+        assert_eq!(Ok(32), "10.0.0.1".parse::<Cidr>().map(|cidr| cidr.prefix));
+        assert_eq!(Ok(128), "::1".parse::<Cidr>().map(|cidr| cidr.prefix));
+
+        for invalid in &[
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "bots",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cidr_set_matches_the_networks() {
+        let set = cidr_set(&["66.249.64.0/19", "10.1.2.3", "2001:db8::/32"]);
+
+        assert!(set.contains(ip("66.249.64.1")));
+        assert!(set.contains(ip("66.249.95.255")));
+        assert!(!set.contains(ip("66.249.96.0")));
+        assert!(set.contains(ip("10.1.2.3")));
+        assert!(!set.contains(ip("10.1.2.4")));
+        assert!(set.contains(ip("2001:db8:1234::1")));
+        assert!(!set.contains(ip("2001:db9::1")));
+        // IPv4-mapped IPv6
+        assert!(set.contains(ip("::ffff:66.249.64.1")));
+
+        // overlapping networks
+        let overlapping = cidr_set(&["10.0.0.0/8", "10.1.0.0/16"]);
+        assert!(overlapping.contains(ip("10.200.0.1")));
+        assert!(overlapping.contains(ip("10.1.0.1")));
+
+        let everything = cidr_set(&["0.0.0.0/0"]);
+        assert!(everything.contains(ip("1.2.3.4")));
+        assert!(!everything.contains(ip("::2")));
+
+        assert!(!CidrSet::default().contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn cidr_set_serialization() {
+        let set: CidrSet =
+            serde_json::from_str(r#"["66.249.64.0/19", "::1"]"#).expect("Should deserialize");
+
+        assert_eq!(
+            r#"["66.249.64.0/19","::1/128"]"#,
+            serde_json::to_string(&set).expect("Should serialize")
+        );
+        assert!(serde_json::from_str::<CidrSet>(r#"["66.249.64.0/40"]"#).is_err());
+    }
+
+    #[test]
+    fn detects_suspect_bots() {
+        let deny_list = cidr_set(&["66.249.64.0/19"]);
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+        let firefox =
+            "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:83.0) Gecko/20100101 Firefox/83.0";
+
+        assert!(!is_suspect_bot(
+            &headers(&[("user-agent", firefox)]),
+            &deny_list
+        ));
+        assert!(is_suspect_bot(
+            &headers(&[(
+                "user-agent",
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"
+            )]),
+            &deny_list
+        ));
+        assert!(is_suspect_bot(
+            &headers(&[("user-agent", "curl/7.68.0")]),
+            &deny_list
+        ));
+        for bot in &[
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+            "Mozilla/5.0 (compatible; Baiduspider/2.0; +http://www.baidu.com/search/spider.html)",
+            "Mozilla/5.0 (compatible; AhrefsBot/7.0; +http://ahrefs.com/robot/)",
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/87.0.4280.88 Safari/537.36",
+            "python-requests/2.25.1",
+            "Go-http-client/1.1",
+        ] {
+            assert!(is_suspect_bot(&headers(&[("user-agent", *bot)]), &deny_list), "{}", bot);
+        }
+        // the bot words are matched only as whole tokens
+        for browser in &[
+            "Mozilla/5.0 (Linux; Android 9; CUBOT P30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/87.0.4280.101 Mobile Safari/537.36",
+            "Mozilla/5.0 (Linux; Android 10; Cubot_X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/86.0.4240.198 Mobile Safari/537.36",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/87.0.4280.88 Safari/537.36 Botanica/1.2",
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 14_2 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/14.0.1 Mobile/15E148 Safari/604.1 JavaScriptEngine",
+        ] {
+            assert!(
+                !is_suspect_bot(&headers(&[("user-agent", *browser)]), &deny_list),
+                "{}",
+                browser
+            );
+        }
+        assert!(is_suspect_bot(
+            &headers(&[
+                ("user-agent", firefox),
+                ("cf-connecting-ip", "66.249.64.10")
+            ]),
+            &deny_list
+        ));
+        assert!(is_suspect_bot(
+            &headers(&[
+                ("user-agent", firefox),
+                ("x-forwarded-for", "66.249.64.10, 10.0.0.1")
+            ]),
+            &deny_list
+        ));
+        // only the client (first) address is checked
+        assert!(!is_suspect_bot(
+            &headers(&[
+                ("user-agent", firefox),
+                ("x-forwarded-for", "1.2.3.4, 66.249.64.10")
+            ]),
+            &deny_list
+        ));
+    }
+}
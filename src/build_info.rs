@@ -0,0 +1,40 @@
+//! Build information embedded at compile time by the `build.rs` script.
+use serde::Serialize;
+
+use crate::market::{market_host, MarketUrl};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("SUPERMARKET_GIT_COMMIT");
+/// RFC 3339 timestamp of the build
+pub const BUILD_TIMESTAMP: &str = env!("SUPERMARKET_BUILD_TIMESTAMP");
+
+/// Served on the [`ROUTE_VERSION`](crate::ROUTE_VERSION) route,
+/// logged on startup and used as constant labels for the metrics.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_timestamp: &'static str,
+    pub market_host: String,
+}
+
+impl BuildInfo {
+    pub fn new(market_url: &MarketUrl) -> Self {
+        Self {
+            version: VERSION,
+            git_commit: GIT_COMMIT,
+            build_timestamp: BUILD_TIMESTAMP,
+            market_host: market_host(market_url),
+        }
+    }
+
+    /// The labels added to every metric
+    pub fn labels(&self) -> Vec<(&'static str, &str)> {
+        vec![
+            ("version", self.version),
+            ("git_commit", self.git_commit),
+            ("market_host", &self.market_host),
+        ]
+    }
+}
@@ -1,14 +1,64 @@
-use crate::status::Status;
+use crate::{
+    admin::idempotency::IdempotencyKeys,
+    anomaly::Anomalies,
+    config::{self, SlotOverrides, ValidatorAllowlistPolicy},
+    metrics::{
+        CAMPAIGNS_EVICTED, CAMPAIGNS_STALE, CAMPAIGN_SPEC_CHANGES, INVALID_CAMPAIGNS,
+        RESTRICTED_CAMPAIGNS,
+    },
+    network::DEFAULT_NETWORK,
+    status::{self, LastNewState, Status},
+    units_for_slot::{
+        media_check::MediaChecks,
+        negative_slots::NegativeSlotCache,
+        prewarm::{SlotCache, SlotPopularity},
+        publisher_stats::PublisherStats,
+        selection::RoundRobinPositions,
+        serve_stats::{ServeCounters, ServeHistory},
+        CoalescedRequests, MatchedUnitsCache, TargetingMemo,
+    },
+    util::{CircuitBreaker, Clock, SystemClock},
+    Config,
+};
+use allowlist::ValidatorAllowlist;
 use async_trait::async_trait;
-use primitives::{BalancesMap, ChannelId};
-use slog::{info, Logger};
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
-use tokio::sync::RwLock;
-
+use chrono::{DateTime, Utc};
+use diff::{CampaignDiff, DIFFS_CAPACITY};
+use filter::CampaignFilter;
+use futures::future::{self, Future};
+use generation::Generation;
+use init::{CollectedPage, InitProgress};
+use lock::Lock;
+use primitives::{util::ApiUrl, BalancesMap, BigNum, Channel, ChannelId};
+use reqwest::Url;
+use slog::{info, warn, Logger};
+use snapshot::Snapshot;
+use stats::{CacheStats, ValidatorChanges};
+use std::borrow::Cow;
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
+
+pub mod allowlist;
 mod api_client;
+pub mod diff;
+pub mod filter;
+pub mod generation;
+pub mod init;
+pub mod lock;
 #[cfg(test)]
 pub mod mock_client;
+pub mod snapshot;
+pub mod stats;
+pub mod validation;
 
 pub use api_client::ApiClient;
 #[cfg(test)]
@@ -17,58 +67,541 @@ pub use mock_client::MockClient;
 // Re-export the Campaign
 pub use primitives::supermarket::Campaign;
 
-pub(crate) type Cached<T> = Arc<RwLock<T>>;
+pub(crate) type Cached<T> = Arc<Lock<T>>;
 
-pub type ActiveCache = HashMap<ChannelId, Campaign>;
+/// The Active Campaigns, shared with the published [`Generation`]s (see [`Cache::current`])
+/// and copied on write only when they change, see [`Cache::update`]
+pub type ActiveCache = HashMap<ChannelId, Arc<Campaign>>;
 pub type FinalizedCache = HashSet<ChannelId>;
+/// The balances of the NewState last approved by the Follower of each Active Campaign,
+/// next to the Leader's ones which are the Campaign's `balances`, see [`effective_balances`]
+pub type FollowerBalances = HashMap<ChannelId, BalancesMap>;
+/// When was each of the Active Campaigns last added or updated in the Cache
+pub type RefreshedCache = HashMap<ChannelId, Refreshed>;
+
+/// The moment a Campaign was last added or updated (its status & balances) in the Cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Refreshed {
+    pub at: DateTime<Utc>,
+    /// For measuring the staleness, see [`Cache::check_staleness`]
+    pub instant: Instant,
+}
+
+impl Refreshed {
+    pub fn now(clock: &dyn Clock) -> Self {
+        Self {
+            at: clock.now_utc(),
+            instant: clock.now_instant(),
+        }
+    }
+
+    /// Whether the Campaign wasn't refreshed within the `max_staleness` until `now`
+    pub fn is_stale(&self, max_staleness: Duration, now: Instant) -> bool {
+        now.saturating_duration_since(self.instant) > max_staleness
+    }
+}
+
+/// How many of the most recently updated Campaigns to include in the diagnostics dump
+const DIAGNOSTICS_RECENT_CAMPAIGNS: usize = 10;
+
+/// The updated Status & balances of the Active Campaigns and the newly Finalized ones,
+/// see [`Cache::collect_campaign_updates`]
+pub type CampaignUpdates = (HashMap<ChannelId, (Status, BalancesMap)>, FinalizedCache);
 
 #[derive(Debug)]
 pub enum ActiveAction {
     /// Update exiting Campaigns in the Cache
     Update(HashMap<ChannelId, (Status, BalancesMap)>),
     /// Add new and/or replace (if Campaign exist already) Campaigns to the Cache
-    New(ActiveCache),
+    New(HashMap<ChannelId, Campaign>),
 }
 #[async_trait]
 pub trait Client: core::fmt::Debug + Clone {
     fn logger(&self) -> Logger;
     /// Collects all the Campaigns
     async fn collect_campaigns(&self) -> HashMap<ChannelId, Campaign>;
+    /// Collects all the Campaigns like [`Client::collect_campaigns`], but sends them page by page
+    /// as soon as each page of a Validator's Channels arrives and the statuses of its Campaigns are computed
+    async fn collect_campaign_pages(&self, pages: mpsc::UnboundedSender<CollectedPage>);
+    /// Collects the Campaigns only from the passed Validator
+    async fn collect_campaigns_from(&self, validator: &ApiUrl) -> HashMap<ChannelId, Campaign>;
     /// Collects updates on the active Campaigns passed to it
     async fn fetch_campaign_updates(
         &self,
         active: &ActiveCache,
     ) -> (HashMap<ChannelId, (Status, BalancesMap)>, FinalizedCache);
+    /// The current Channels (incl. their spec & targeting rules) of the active Campaigns passed to it,
+    /// the ones which couldn't be fetched are left out
+    async fn fetch_channels(&self, active: &ActiveCache) -> HashMap<ChannelId, Channel>;
+    /// The number of failed requests per Validator since the start
+    async fn validator_failures(&self) -> HashMap<String, u64>;
+    /// The number of skipped malformed entries per Validator host since the start
+    async fn validator_malformed_entries(&self) -> HashMap<String, u64>;
+    /// The Validators from which the Campaigns are collected
+    async fn validators(&self) -> HashSet<ApiUrl>;
+    /// Replaces the Validators from which the Campaigns are collected
+    async fn set_validators(&self, validators: HashSet<ApiUrl>);
+    /// The Leader's NewState of the last collected or updated status of an Active Campaign
+    async fn last_new_state(&self, channel_id: &ChannelId) -> Option<LastNewState>;
+    /// The balances approved by the Follower of the last collected or updated status of an Active Campaign
+    async fn follower_balances(&self, channel_id: &ChannelId) -> Option<BalancesMap>;
+}
+
+/// The moments at which the last runs of updating the Cache have completed
+#[derive(Debug, Clone, Copy)]
+pub struct LastRuns {
+    /// Last completed [`Cache::fetch_new_campaigns`]
+    pub new_campaigns: Instant,
+    /// Last completed [`Cache::fetch_campaign_updates`]
+    pub campaign_updates: Instant,
+}
+
+impl LastRuns {
+    /// Both runs are set to the current instant, so a Cache which never completes
+    /// a run will still become stale after the allowed time.
+    fn now(clock: &dyn Clock) -> Self {
+        let now = clock.now_instant();
+
+        Self {
+            new_campaigns: now,
+            campaign_updates: now,
+        }
+    }
+}
+
+/// Bounds the Active Campaigns in the Cache, see [`campaigns_to_evict`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheLimits {
+    pub max_campaigns: Option<usize>,
+    /// The maximum of the approximate size in bytes, see [`estimated_size`]
+    pub max_bytes: Option<usize>,
+}
+
+impl From<&config::Limits> for CacheLimits {
+    fn from(limits: &config::Limits) -> Self {
+        Self {
+            max_campaigns: limits.max_campaigns,
+            max_bytes: limits.max_cache_bytes,
+        }
+    }
+}
+
+/// The hash of the Channel's spec and targeting rules (i.e. everything but its id),
+/// for detecting when they are amended, see [`Cache::refresh_changed_specs`]
+pub fn spec_hash(channel: &Channel) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(&channel.spec)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    serde_json::to_vec(&channel.targeting_rules)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// The approximate size of the Campaign in memory, i.e. the length of its JSON serialization
+/// and its entries in the published [`Generation`], which shares the Campaign itself with the Cache
+pub fn estimated_size(campaign: &Campaign) -> usize {
+    serde_json::to_vec(campaign)
+        .map(|serialized| serialized.len())
+        .unwrap_or_default()
+        + generation::ENTRY_SIZE
+}
+
+/// The Leader's & Follower's balances of the Campaign reconciled to the most spent view,
+/// see [`status::effective_balances`](crate::status::effective_balances).
+/// Without the Follower's balances they are the Leader's ones (i.e. the Campaign's `balances`).
+pub fn effective_balances<'a>(
+    campaign: &'a Campaign,
+    follower_balances: &FollowerBalances,
+) -> Cow<'a, BalancesMap> {
+    match follower_balances.get(&campaign.channel.id) {
+        Some(follower) => Cow::Owned(status::effective_balances(&campaign.balances, follower)),
+        None => Cow::Borrowed(&campaign.balances),
+    }
+}
+
+/// The deposit of the Campaign which hasn't been paid out yet
+fn remaining_budget(campaign: &Campaign) -> BigNum {
+    remaining_budget_by(campaign, &campaign.balances)
+}
+
+/// The deposit of the Campaign which isn't paid out by the `balances`, e.g. its [`effective_balances`]
+pub(crate) fn remaining_budget_by(campaign: &Campaign, balances: &BalancesMap) -> BigNum {
+    let deposit = &campaign.channel.deposit_amount;
+    let spent = balances.values().sum::<BigNum>();
+
+    if &spent >= deposit {
+        BigNum::from(0)
+    } else {
+        deposit - &spent
+    }
+}
+
+/// A Campaign competing for a place in the Cache, see [`evict_candidates`]
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    pub channel_id: ChannelId,
+    /// By its [`effective_balances`]
+    pub remaining: BigNum,
+    pub refreshed: Option<Refreshed>,
+    /// See [`estimated_size`]
+    pub size: usize,
+}
+
+impl EvictionCandidate {
+    pub fn of(
+        campaign: &Campaign,
+        refreshed: Option<Refreshed>,
+        follower_balances: &FollowerBalances,
+    ) -> Self {
+        Self {
+            channel_id: campaign.channel.id,
+            remaining: remaining_budget_by(
+                campaign,
+                &effective_balances(campaign, follower_balances),
+            ),
+            refreshed,
+            size: estimated_size(campaign),
+        }
+    }
+}
+
+/// Decides which of the Active Campaigns should be evicted in order to fit in the `limits`, see [`evict_candidates`]
+pub fn campaigns_to_evict(
+    active: &ActiveCache,
+    refreshed: &RefreshedCache,
+    follower_balances: &FollowerBalances,
+    limits: &CacheLimits,
+) -> Vec<ChannelId> {
+    if *limits == CacheLimits::default() {
+        return vec![];
+    }
+
+    let candidates = active
+        .iter()
+        .map(|(channel_id, campaign)| {
+            EvictionCandidate::of(
+                campaign,
+                refreshed.get(channel_id).copied(),
+                follower_balances,
+            )
+        })
+        .collect();
+
+    evict_candidates(candidates, limits)
+}
+
+/// Decides which of the `candidates` should be evicted in order to fit in the `limits`.
+///
+/// The Campaigns with the largest remaining budget are kept first and then the most recently refreshed ones.
+/// A Campaign which doesn't fit in the `max_bytes` is evicted, but the lower priority ones might still fit.
+pub fn evict_candidates(
+    mut candidates: Vec<EvictionCandidate>,
+    limits: &CacheLimits,
+) -> Vec<ChannelId> {
+    // the highest priority first, the ChannelId keeps the order deterministic
+    candidates.sort_by(|a, b| {
+        b.remaining
+            .cmp(&a.remaining)
+            .then(b.refreshed.cmp(&a.refreshed))
+            .then_with(|| a.channel_id.to_string().cmp(&b.channel_id.to_string()))
+    });
+
+    let max_campaigns = limits.max_campaigns.unwrap_or(usize::MAX);
+    let max_bytes = limits.max_bytes.unwrap_or(usize::MAX);
+    let (mut kept, mut bytes) = (0, 0_usize);
+
+    candidates
+        .into_iter()
+        .filter_map(
+            |EvictionCandidate {
+                 channel_id, size, ..
+             }| {
+                if kept < max_campaigns && bytes.saturating_add(size) <= max_bytes {
+                    kept += 1;
+                    bytes += size;
+
+                    None
+                } else {
+                    Some(channel_id)
+                }
+            },
+        )
+        .collect()
 }
 
 #[derive(Debug, Clone)]
 pub struct Cache<C: Client> {
     pub active: Cached<ActiveCache>,
     pub finalized: Cached<FinalizedCache>,
+    pub last_runs: Cached<LastRuns>,
+    pub refreshed: Cached<RefreshedCache>,
+    /// The Follower's balances of the Active Campaigns, synced from the Client when the Campaigns change,
+    /// replaced as a whole so the published [`Generation`] shares them
+    pub follower_balances: Cached<Arc<FollowerBalances>>,
+    /// The Active Campaigns which weren't refreshed within the `max_campaign_staleness`, see [`Cache::check_staleness`]
+    stale: Cached<HashSet<ChannelId>>,
+    /// The discovered Campaigns which were skipped because of an invalid spec, see [`validation::validate`]
+    invalid: Cached<HashSet<ChannelId>>,
+    /// The discovered Campaigns with a Validator which isn't on the allowlist, see [`Cache::apply_allowlist`]
+    pub restricted: Cached<HashSet<ChannelId>>,
+    /// The Campaigns evicted by the [`CacheLimits`], they aren't added back until an Active Campaign is Finalized,
+    /// see [`Cache::skip_evicted`]
+    pub evicted: Cached<HashSet<ChannelId>>,
+    /// See [`Cache::fetch_initial_campaigns`]
+    init_progress: Cached<InitProgress>,
+    /// Briefly cached units-for-slot results for stable pagination
+    pub matched_units: Cached<MatchedUnitsCache>,
+    /// The in-flight units-for-slot requests, shared with the identical concurrent ones
+    pub coalesced_requests: Cached<CoalescedRequests>,
+    /// The memoized targeting results of the Campaigns, see [`Cache::generation`]
+    pub targeting_memo: Cached<TargetingMemo>,
+    /// The AdSlots (with their AdUnits) fetched from the Market, see [`Prewarm`](crate::config::Prewarm)
+    pub slots: Cached<SlotCache>,
+    /// How often each AdSlot is requested, for refreshing the most requested ones
+    pub slot_popularity: Arc<SlotPopularity>,
+    /// The AdSlots which the Market responded with `404`, see [`NegativeSlots`](crate::config::NegativeSlots)
+    pub negative_slots: Cached<NegativeSlotCache>,
+    /// Whether the media of the served AdUnits is reachable, see [`MediaCheck`](crate::config::MediaCheck)
+    pub media_checks: Cached<MediaChecks>,
+    /// The units-for-slot requests per publisher, see [`PublisherStats`]
+    pub publisher_stats: Cached<PublisherStats>,
+    /// The serves of each Campaign since the last flush into the [`Cache::serve_history`],
+    /// see [`ServeStats`](crate::config::ServeStats)
+    pub serve_counters: Arc<ServeCounters>,
+    /// The flushed serves of each Campaign in hourly buckets
+    pub serve_history: Cached<ServeHistory>,
+    /// The positions of the AdSlots served with the `round_robin`, see [`Selection`](crate::config::Selection)
+    pub round_robin: Cached<RoundRobinPositions>,
+    /// The `4xx` responses per client IP & AdSlot and their blocks, see [`AnomalyBlocking`](crate::config::AnomalyBlocking)
+    pub anomalies: Cached<Anomalies>,
+    /// The changes of the Validators at runtime, see [`Cache::replace_validators`]
+    pub validator_changes: Cached<ValidatorChanges>,
+    /// The recent `Idempotency-Key`s of the mutating admin routes with their responses,
+    /// see [`Idempotency`](crate::config::Idempotency)
+    pub idempotency_keys: Cached<IdempotencyKeys>,
+    /// The operator's overrides of the AdSlots, replaced when the config is reloaded,
+    /// see [`Config::slot_overrides`](crate::Config::slot_overrides)
+    pub slot_overrides: Cached<SlotOverrides>,
+    /// Increased every time the Active Campaigns change
+    generation: Arc<AtomicU64>,
+    /// The snapshot of the Active Campaigns of the current generation, see [`Cache::current`]
+    current: Cached<Arc<Generation>>,
+    /// Publishes what changed with every update of the Active Campaigns, see [`Cache::subscribe_diffs`]
+    diffs: broadcast::Sender<CampaignDiff>,
+    /// The aggregates of the Active Campaigns, see [`Cache::stats`]
+    stats: Cached<CacheStats>,
+    /// The deposit assets labeled in the stats metrics, see [`CacheStats::set_gauges`]
+    stats_assets: Arc<HashSet<String>>,
+    /// The network of the Cache, the `network` label of its metrics
+    network: Arc<str>,
+    allowlist: Arc<ValidatorAllowlist>,
+    limits: CacheLimits,
+    clock: Arc<dyn Clock>,
     client: C,
     logger: Logger,
 }
 
-impl<C> Cache<C>
+/// Builds a [`Cache`], see [`Cache::builder`]
+#[derive(Debug)]
+pub struct CacheBuilder<C: Client> {
+    client: C,
+    limits: CacheLimits,
+    clock: Arc<dyn Clock>,
+    stats_assets: HashSet<String>,
+    network: String,
+    slot_overrides: SlotOverrides,
+    allowlist: ValidatorAllowlist,
+}
+
+impl<C> CacheBuilder<C>
 where
     C: Client,
 {
-    /// Fetches the new campaigns on initialization.
-    pub async fn initialize(client: C) -> Self {
+    /// Bounds the Active Campaigns by the `limits`, by default there are no limits
+    pub fn limits(mut self, limits: CacheLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The deposit assets labeled in the stats metrics, the rest are summed together,
+    /// by default all of them are
+    pub fn stats_assets(mut self, assets: HashSet<String>) -> Self {
+        self.stats_assets = assets;
+        self
+    }
+
+    /// The network of the Cache, its metrics are labeled by it, by default the [`DEFAULT_NETWORK`]
+    pub fn network(mut self, network: &str) -> Self {
+        self.network = network.to_string();
+        self
+    }
+
+    /// The initial overrides of the AdSlots, by default there are none
+    pub fn slot_overrides(mut self, slot_overrides: SlotOverrides) -> Self {
+        self.slot_overrides = slot_overrides;
+        self
+    }
+
+    /// The Validators whose Campaigns are served, by default any of them
+    pub fn validator_allowlist(mut self, allowlist: ValidatorAllowlist) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Replaces the [`SystemClock`] used for the staleness of the Cache & the Campaigns
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Fetches the new campaigns on initialization, see [`Cache::fetch_initial_campaigns`].
+    pub async fn initialize(self) -> Cache<C> {
+        let cache = self.build();
+
+        // collect and initialize the active campaigns
+        cache.fetch_initial_campaigns(future::pending()).await;
+
+        cache
+    }
+
+    /// Loads the Campaigns of the `snapshot` instead of fetching them,
+    /// they keep the time since they were last refreshed in the replica.
+    pub async fn initialize_from(self, snapshot: Snapshot) -> Cache<C> {
+        let cache = self.build();
+        cache.load_snapshot(snapshot).await;
+        *cache.init_progress.write().await = InitProgress::completed();
+
+        cache
+    }
+
+    /// Fetches the [`Snapshot`] of the running `replica` and loads it (see [`CacheBuilder::initialize_from`]).
+    /// If it fails, e.g. the replica is unreachable, it has another snapshot version or the snapshot is corrupt,
+    /// it falls back to [`CacheBuilder::initialize`].
+    pub async fn initialize_warm_from(
+        self,
+        replica: &ApiUrl,
+        admin_token: Option<&str>,
+        timeout: Duration,
+    ) -> Cache<C> {
+        let logger = self.client.logger();
+
+        match Snapshot::fetch(replica, admin_token, timeout).await {
+            Ok(snapshot) => {
+                info!(
+                    &logger,
+                    "Warming up the Cache from a replica";
+                    "replica" => %replica,
+                    "campaigns" => snapshot.active.len(),
+                    "taken at" => %snapshot.taken_at,
+                );
+
+                self.initialize_from(snapshot).await
+            }
+            Err(error) => {
+                warn!(
+                    &logger,
+                    "Warming up the Cache failed, fetching the Campaigns from the Validators";
+                    "replica" => %replica,
+                    "error" => %error,
+                );
+
+                self.initialize().await
+            }
+        }
+    }
+
+    /// The Cache without any Campaigns, which isn't ready (see [`Cache::is_ready`])
+    /// until they are fetched with [`Cache::fetch_initial_campaigns`]
+    pub fn build(self) -> Cache<C> {
+        let Self {
+            client,
+            limits,
+            clock,
+            stats_assets,
+            network,
+            slot_overrides,
+            allowlist,
+        } = self;
         let logger = client.logger().clone();
         info!(&logger, "Initialize Cache with Client"; "client" => ?&client);
 
-        let cache = Self {
+        Cache {
             active: Default::default(),
             finalized: Default::default(),
+            last_runs: Arc::new(Lock::new(LastRuns::now(&*clock))),
+            refreshed: Default::default(),
+            follower_balances: Default::default(),
+            stale: Default::default(),
+            invalid: Default::default(),
+            evicted: Default::default(),
+            restricted: Default::default(),
+            init_progress: Default::default(),
+            matched_units: Default::default(),
+            coalesced_requests: Default::default(),
+            targeting_memo: Default::default(),
+            slots: Default::default(),
+            slot_popularity: Default::default(),
+            negative_slots: Default::default(),
+            idempotency_keys: Default::default(),
+            media_checks: Default::default(),
+            publisher_stats: Default::default(),
+            serve_counters: Default::default(),
+            serve_history: Default::default(),
+            round_robin: Default::default(),
+            anomalies: Default::default(),
+            validator_changes: Default::default(),
+            slot_overrides: Arc::new(Lock::new(slot_overrides)),
+            generation: Default::default(),
+            current: Default::default(),
+            diffs: broadcast::channel(DIFFS_CAPACITY).0,
+            stats: Default::default(),
+            stats_assets: Arc::new(stats_assets),
+            network: network.into(),
+            allowlist: Arc::new(allowlist),
+            limits,
+            clock,
             logger,
             client,
-        };
+        }
+    }
+}
 
-        // collect and initialize the active campaigns
-        cache.fetch_new_campaigns().await;
+impl<C> Cache<C>
+where
+    C: Client,
+{
+    pub fn builder(client: C) -> CacheBuilder<C> {
+        CacheBuilder {
+            client,
+            limits: CacheLimits::default(),
+            clock: Arc::new(SystemClock),
+            stats_assets: HashSet::new(),
+            network: DEFAULT_NETWORK.to_string(),
+            slot_overrides: SlotOverrides::new(),
+            allowlist: ValidatorAllowlist::default(),
+        }
+    }
 
-        cache
+    /// Fetches the new campaigns on initialization, see [`Cache::builder`] for setting the limits & the clock.
+    pub async fn initialize(client: C) -> Self {
+        Self::builder(client).initialize().await
+    }
+
+    /// The clock of the Cache, for everything that depends on the current time
+    pub fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    /// The network of the Cache, see [`CacheBuilder::network`]
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    pub fn client(&self) -> &C {
+        &self.client
     }
 
     /// Updates the full Cache with the new values:
@@ -80,12 +613,23 @@ where
     ///
     /// 2. Updates Finalized cache
     /// - Extend the Finalized `ChannelId`s with the new ones
-    async fn update(&self, new_active: ActiveAction, new_finalized: FinalizedCache) {
+    ///
+    /// Returns what changed in the Active Campaigns (incl. the Follower's balances), only then
+    /// the generation (see [`Cache::generation`]) and the stats are refreshed.
+    async fn update(
+        &self,
+        new_active: ActiveAction,
+        new_finalized: FinalizedCache,
+    ) -> CampaignDiff {
+        let mut diff = CampaignDiff::default();
+
         // Updates Active cache
         // - Extend the Active Campaigns with the new ones
         // - Remove the Finalized `ChannelId`s from the Active Campaigns
         {
             let mut active = self.active.write().await;
+            let mut refreshed = self.refreshed.write().await;
+            let now = Refreshed::now(self.clock());
             // Log and extend active cache
             // only log messages if there are actions to take on campaigns
             match new_active {
@@ -97,8 +641,31 @@ where
                         new_active.len()
                     );
 
+                    refreshed.extend(new_active.keys().map(|channel_id| (*channel_id, now)));
+                    for (channel_id, campaign) in new_active.iter() {
+                        match active.get(channel_id) {
+                            None => {
+                                diff.added.insert(*channel_id);
+                            }
+                            Some(current) => {
+                                if current.status != campaign.status {
+                                    diff.status_changed.insert(*channel_id);
+                                }
+                                if current.balances != campaign.balances {
+                                    diff.balances_changed.insert(*channel_id);
+                                }
+                                if spec_hash(&current.channel) != spec_hash(&campaign.channel) {
+                                    diff.spec_changed.insert(*channel_id);
+                                }
+                            }
+                        }
+                    }
                     // extend the Active Cache with new active campaigns
-                    active.extend(new_active)
+                    active.extend(
+                        new_active
+                            .into_iter()
+                            .map(|(channel_id, campaign)| (channel_id, Arc::new(campaign))),
+                    );
                 }
 
                 ActiveAction::Update(update_active) if !update_active.is_empty() => {
@@ -111,9 +678,23 @@ where
                     for (channel_id, (new_status, new_balances)) in update_active {
                         active
                             .entry(channel_id)
-                            .and_modify(|campaign: &mut Campaign| {
-                                campaign.status = new_status;
-                                campaign.balances = new_balances;
+                            .and_modify(|campaign: &mut Arc<Campaign>| {
+                                let status_changed = campaign.status != new_status;
+                                let balances_changed = campaign.balances != new_balances;
+                                // the unchanged Campaigns stay shared with the current generation
+                                if status_changed || balances_changed {
+                                    let campaign = Arc::make_mut(campaign);
+                                    campaign.status = new_status;
+                                    campaign.balances = new_balances;
+                                }
+                                if status_changed {
+                                    diff.status_changed.insert(channel_id);
+                                }
+                                if balances_changed {
+                                    diff.balances_changed.insert(channel_id);
+                                }
+
+                                refreshed.insert(channel_id, now);
                             });
                     }
                 }
@@ -127,13 +708,20 @@ where
                     new_finalized.len()
                 );
                 for id in new_finalized.iter() {
-                    // remove from active campaigns
-                    // in practice we don't care if this value was in the active cache
-                    // this is why we don't handle the `Option` returned from `remove()`
-                    active.remove(id);
+                    // remove from active campaigns,
+                    // only the ones which were in the active cache have changed
+                    if active.remove(id).is_some() {
+                        diff.removed.insert(*id);
+                    }
+                    refreshed.remove(id);
                 }
             }
-        } // Active cache - release of RwLockWriteGuard
+        } // Active & Refreshed cache - release of RwLockWriteGuards
+
+        // the Finalized Campaigns make room for the evicted ones, they compete again with the next fetched ones
+        if !diff.removed.is_empty() {
+            self.evicted.write().await.clear();
+        }
 
         // Updates Finalized cache
         // - Extend the Finalized `ChannelId`s with the new ones
@@ -146,14 +734,145 @@ where
 
             self.finalized.write().await.extend(new_finalized);
         } // Finalized cache - release of RwLockWriteGuard
+
+        diff.balances_changed
+            .extend(self.sync_follower_balances().await);
+        if !diff.is_empty() {
+            self.next_generation().await;
+            self.refresh_stats().await;
+        }
+
+        diff
+    }
+
+    /// Keeps the Follower's balances of the Active Campaigns next to their (Leader's) `balances`.
+    ///
+    /// Returns the Campaigns whose Follower's balances changed.
+    async fn sync_follower_balances(&self) -> Vec<ChannelId> {
+        let channel_ids = self.active.read().await.keys().copied().collect::<Vec<_>>();
+
+        let mut follower_balances = FollowerBalances::with_capacity(channel_ids.len());
+        for channel_id in channel_ids {
+            if let Some(balances) = self.client.follower_balances(&channel_id).await {
+                follower_balances.insert(channel_id, balances);
+            }
+        }
+
+        let mut current = self.follower_balances.write().await;
+        let changed = follower_balances
+            .iter()
+            .filter(|(channel_id, balances)| current.get(channel_id) != Some(balances))
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        *current = Arc::new(follower_balances);
+
+        changed
+    }
+
+    /// Publishes the `diff` to the subscribers (see [`Cache::subscribe_diffs`]) unless it's empty
+    fn publish(&self, diff: &CampaignDiff) {
+        if !diff.is_empty() {
+            // there might be no subscribers
+            let _ = self.diffs.send(diff.clone());
+        }
+    }
+
+    /// Subscribes to the non-empty [`CampaignDiff`]s of the Cache updates,
+    /// for rebuilding what depends on the Active Campaigns only once they change
+    pub fn subscribe_diffs(&self) -> broadcast::Receiver<CampaignDiff> {
+        self.diffs.subscribe()
+    }
+
+    /// Fetches the Campaigns on initialization, adding each page of the Validators' Channels to the Cache
+    /// as soon as it arrives, with the statuses of its Campaigns (see [`Client::collect_campaign_pages`]).
+    /// The progress is logged as the Validators are done.
+    ///
+    /// On the `shutdown` the fetching is aborted, the pages which already arrived are still added.
+    /// Returns whether it has completed.
+    pub async fn fetch_initial_campaigns(&self, shutdown: impl Future<Output = ()>) -> bool {
+        let validators = self.client.validators().await;
+        *self.init_progress.write().await = InitProgress::new(validators);
+
+        let (sender, mut pages) = mpsc::unbounded_channel();
+        let collect = async {
+            tokio::select! {
+                _ = self.client.collect_campaign_pages(sender) => true,
+                _ = shutdown => false,
+            }
+        };
+        let add = async {
+            while let Some(page) = pages.recv().await {
+                let page = match page {
+                    CollectedPage::Campaigns(validator, campaigns) => {
+                        self.add_new_campaigns(campaigns).await;
+                        // the Campaigns are added before the progress counts them
+                        CollectedPage::Campaigns(validator, HashMap::new())
+                    }
+                    done => done,
+                };
+
+                let mut progress = self.init_progress.write().await;
+                if progress.record(&page) {
+                    info!(
+                        &self.logger,
+                        "Initializing the Cache";
+                        "validators done" => format!("{}%", progress.percent_done()),
+                        "validators responded" => format!("{:.0}%", progress.responded() * 100.0),
+                    );
+                }
+            }
+        };
+        let (completed, _) = future::join(collect, add).await;
+
+        if completed {
+            self.init_progress.write().await.complete();
+            self.last_runs.write().await.new_campaigns = self.clock.now_instant();
+            info!(&self.logger, "Initialized the Cache"; "active" => self.active.read().await.len());
+        }
+
+        completed
     }
 
     /// # Update the Campaigns in the Cache
     /// - New Campaigns
     /// - New Finalized Campaigns
     pub async fn fetch_new_campaigns(&self) {
-        let campaigns = self.client.collect_campaigns().await;
+        let campaigns = self.collect_new_campaigns().await;
+
+        self.apply_new_campaigns(campaigns).await;
+    }
+
+    /// Collects the Campaigns from the Validators without changing the Cache,
+    /// the network phase of [`Cache::fetch_new_campaigns`]
+    pub async fn collect_new_campaigns(&self) -> HashMap<ChannelId, Campaign> {
+        self.client.collect_campaigns().await
+    }
+
+    /// Adds the collected Campaigns, see [`Cache::collect_new_campaigns`]
+    pub async fn apply_new_campaigns(&self, campaigns: HashMap<ChannelId, Campaign>) {
+        self.add_new_campaigns(campaigns).await;
+
+        self.last_runs.write().await.new_campaigns = self.clock.now_instant();
+    }
+
+    /// Same as [`Cache::fetch_new_campaigns`] but only from the passed Validator,
+    /// the Campaigns are merged into the Cache.
+    /// It doesn't count as a run of fetching the new campaigns (see [`LastRuns`]).
+    ///
+    /// Returns the number of the new Active & Finalized campaigns.
+    pub async fn fetch_new_campaigns_from(&self, validator: &ApiUrl) -> (usize, usize) {
+        let campaigns = self.client.collect_campaigns_from(validator).await;
+
+        self.add_new_campaigns(campaigns).await
+    }
 
+    /// Returns the number of the new Active & Finalized campaigns
+    async fn add_new_campaigns(&self, campaigns: HashMap<ChannelId, Campaign>) -> (usize, usize) {
+        let skipped_before = self.invalid.read().await.len() + self.restricted.read().await.len();
+        let campaigns = self.skip_invalid(campaigns).await;
+        let campaigns = self.apply_allowlist(campaigns).await;
+        let newly_skipped =
+            self.invalid.read().await.len() + self.restricted.read().await.len() != skipped_before;
         let (active, finalized) = campaigns.into_iter().fold(
             (HashMap::new(), HashSet::new()),
             |(mut active, mut finalized), (id, campaign)| {
@@ -170,26 +889,703 @@ where
                 (active, finalized)
             },
         );
+        let active = self.skip_evicted(active).await;
+        let (active, evicted) = self.fit_in_limits(active).await;
+        let counts = (active.len(), finalized.len());
+
+        let mut diff = self.update(ActiveAction::New(active), finalized).await;
+        diff.removed.extend(self.remove_evicted(evicted).await);
+        // the skipped Campaigns are in the stats as well
+        if diff.is_empty() && newly_skipped {
+            self.refresh_stats().await;
+        }
+        self.publish(&diff);
+
+        counts
+    }
+
+    /// Skips the Campaigns (except the Finalized ones) with an invalid spec, see [`validation::validate`].
+    /// Each of them is logged and counted (by the failing rule) only the first time it's skipped.
+    async fn skip_invalid(
+        &self,
+        campaigns: HashMap<ChannelId, Campaign>,
+    ) -> HashMap<ChannelId, Campaign> {
+        let now = self.clock.now_utc();
+        let mut invalid = self.invalid.write().await;
+
+        campaigns
+            .into_iter()
+            .filter(|(channel_id, campaign)| {
+                if let Status::Finalized(_) = campaign.status {
+                    return true;
+                }
+
+                match validation::validate(&campaign.channel, now) {
+                    Ok(()) => true,
+                    Err(error) => {
+                        if invalid.insert(*channel_id) {
+                            warn!(
+                                &self.logger,
+                                "Skipped a Campaign with an invalid spec: {}",
+                                error;
+                                "channel_id" => %channel_id,
+                                "rule" => error.rule(),
+                            );
+                            INVALID_CAMPAIGNS.with_label_values(&[error.rule()]).inc();
+                        }
+
+                        false
+                    }
+                }
+            })
+            .collect()
+    }
 
-        self.update(ActiveAction::New(active), finalized).await
+    /// Applies the [`ValidatorAllowlist`] to the discovered Campaigns (except the Finalized ones),
+    /// the ones with a Leader or a Follower which isn't allowed are [`Cache::restricted`]
+    /// and either skipped or kept (but not served) by the [`ValidatorAllowlistPolicy`].
+    /// Each of them is logged and counted only the first time.
+    async fn apply_allowlist(
+        &self,
+        campaigns: HashMap<ChannelId, Campaign>,
+    ) -> HashMap<ChannelId, Campaign> {
+        if self.allowlist.is_empty() {
+            return campaigns;
+        }
+        let mut restricted = self.restricted.write().await;
+
+        campaigns
+            .into_iter()
+            .filter(|(channel_id, campaign)| {
+                if let Status::Finalized(_) = campaign.status {
+                    return true;
+                }
+
+                let validator = match self.allowlist.disallowed(&campaign.channel) {
+                    Some(validator) => validator,
+                    None => return true,
+                };
+                if restricted.insert(*channel_id) {
+                    warn!(
+                        &self.logger,
+                        "Restricted a Campaign with a Validator which is not on the allowlist";
+                        "channel_id" => %channel_id,
+                        "validator" => &validator.url,
+                        "policy" => ?self.allowlist.policy,
+                    );
+                    RESTRICTED_CAMPAIGNS.inc();
+                }
+
+                self.allowlist.policy == ValidatorAllowlistPolicy::Restrict
+            })
+            .collect()
+    }
+
+    async fn load_snapshot(&self, mut snapshot: Snapshot) {
+        let merged = snapshot.merge_duplicates();
+        if merged > 0 {
+            warn!(&self.logger, "Merged the duplicate Campaigns of the snapshot, keeping the most recently refreshed ones"; "merged" => merged);
+        }
+
+        let (active, refreshed_at, finalized) = snapshot.into_parts();
+        // the replica might not have the same allowlist
+        let active = self.apply_allowlist(active).await;
+
+        let mut diff = self.update(ActiveAction::New(active), finalized).await;
+        {
+            let now = Refreshed::now(self.clock());
+            let mut refreshed = self.refreshed.write().await;
+            let mut refreshed_later = 0;
+            for (channel_id, at) in refreshed_at {
+                if let Some(refreshed) = refreshed.get_mut(&channel_id) {
+                    // the monotonic time of the refresh by its age, the replica's clock may be ahead of this one
+                    let age = match (now.at - at).to_std() {
+                        Ok(age) => age,
+                        Err(_) => {
+                            refreshed_later += 1;
+
+                            Default::default()
+                        }
+                    };
+                    *refreshed = Refreshed {
+                        at,
+                        instant: now.instant.checked_sub(age).unwrap_or(now.instant),
+                    };
+                }
+            }
+
+            if refreshed_later > 0 {
+                warn!(
+                    &self.logger,
+                    "Campaigns of the snapshot were refreshed after the current time, their age is clamped to zero";
+                    "campaigns" => refreshed_later,
+                    "now" => %now.at,
+                );
+            }
+        }
+        // the restored refreshes are published as well
+        self.next_generation().await;
+        diff.removed.extend(self.evict().await);
+        self.publish(&diff);
+    }
+
+    /// Evicts the Active Campaigns which don't fit in the [`CacheLimits`],
+    /// they're decided (which serializes every Campaign for its size) without holding the write locks.
+    ///
+    /// Returns the evicted Campaigns.
+    async fn evict(&self) -> Vec<ChannelId> {
+        if self.limits == CacheLimits::default() {
+            return vec![];
+        }
+
+        let evicted = {
+            let active = self.active.read().await;
+            let refreshed = self.refreshed.read().await;
+            let follower_balances = self.follower_balances.read().await;
+
+            campaigns_to_evict(&active, &refreshed, &follower_balances, &self.limits)
+        };
+
+        self.remove_evicted(evicted).await
+    }
+
+    /// Decides which of the `new` Campaigns and the Active ones don't fit in the [`CacheLimits`] together,
+    /// before the new ones are added, so they are never added only to be evicted right after.
+    ///
+    /// Returns the new Campaigns which fit and the Campaigns to evict (incl. the Active ones).
+    async fn fit_in_limits(
+        &self,
+        mut new: HashMap<ChannelId, Campaign>,
+    ) -> (HashMap<ChannelId, Campaign>, Vec<ChannelId>) {
+        if self.limits == CacheLimits::default() || new.is_empty() {
+            return (new, vec![]);
+        }
+
+        let now = Refreshed::now(self.clock());
+        let candidates =
+            {
+                let active = self.active.read().await;
+                let refreshed = self.refreshed.read().await;
+                let follower_balances = self.follower_balances.read().await;
+
+                active
+                    .iter()
+                    // the new ones replace them
+                    .filter(|(channel_id, _)| !new.contains_key(channel_id))
+                    .map(|(channel_id, campaign)| {
+                        EvictionCandidate::of(
+                            campaign,
+                            refreshed.get(channel_id).copied(),
+                            &follower_balances,
+                        )
+                    })
+                    .chain(new.values().map(|campaign| {
+                        EvictionCandidate::of(campaign, Some(now), &follower_balances)
+                    }))
+                    .collect()
+            };
+
+        let evicted = evict_candidates(candidates, &self.limits);
+        for channel_id in evicted.iter() {
+            new.remove(channel_id);
+        }
+
+        (new, evicted)
+    }
+
+    /// Removes the `evicted` Campaigns from the Active ones and remembers them, see [`Cache::skip_evicted`]
+    ///
+    /// Returns the evicted Campaigns which were Active.
+    async fn remove_evicted(&self, evicted: Vec<ChannelId>) -> Vec<ChannelId> {
+        if evicted.is_empty() {
+            return evicted;
+        }
+
+        let mut removed = vec![];
+        {
+            let mut active = self.active.write().await;
+            let mut refreshed = self.refreshed.write().await;
+            let mut follower_balances = self.follower_balances.write().await;
+            let follower_balances = Arc::make_mut(&mut *follower_balances);
+
+            for channel_id in evicted.iter() {
+                // the new ones which don't fit were never added
+                if active.remove(channel_id).is_some() {
+                    removed.push(*channel_id);
+                }
+                refreshed.remove(channel_id);
+                follower_balances.remove(channel_id);
+            }
+        }
+        self.evicted.write().await.extend(evicted.iter().copied());
+
+        CAMPAIGNS_EVICTED.inc_by(evicted.len() as u64);
+        info!(
+            &self.logger,
+            "Evicted {} Campaigns exceeding the Cache limits",
+            evicted.len();
+            "limits" => ?self.limits,
+        );
+
+        if !removed.is_empty() {
+            self.next_generation().await;
+            self.refresh_stats().await;
+        }
+
+        removed
+    }
+
+    /// Skips the evicted Campaigns (see [`Cache::remove_evicted`]), so they aren't fetched, added and evicted again
+    /// on every fetch. They're forgotten once an Active Campaign is Finalized, making room for them.
+    async fn skip_evicted(
+        &self,
+        mut campaigns: HashMap<ChannelId, Campaign>,
+    ) -> HashMap<ChannelId, Campaign> {
+        let evicted = self.evicted.read().await;
+        campaigns.retain(|channel_id, _| !evicted.contains(channel_id));
+
+        campaigns
+    }
+
+    /// Logs and counts (see [`CAMPAIGNS_STALE`]) the Active Campaigns which have become stale,
+    /// i.e. weren't refreshed within the `max_staleness`, since the last check.
+    ///
+    /// Returns the number of the newly stale Campaigns.
+    pub async fn check_staleness(&self, max_staleness: Duration) -> usize {
+        let now = self.clock.now_instant();
+        let now_stale = self
+            .refreshed
+            .read()
+            .await
+            .iter()
+            .filter(|(_, refreshed)| refreshed.is_stale(max_staleness, now))
+            .map(|(channel_id, refreshed)| (*channel_id, *refreshed))
+            .collect::<Vec<_>>();
+
+        let mut stale = self.stale.write().await;
+        let newly_stale = now_stale
+            .iter()
+            .filter(|(channel_id, _)| !stale.contains(channel_id))
+            .collect::<Vec<_>>();
+
+        for (channel_id, refreshed) in newly_stale.iter() {
+            warn!(
+                &self.logger,
+                "Campaign is stale and excluded from serving until it's refreshed";
+                "channel" => %channel_id,
+                "last refreshed" => %refreshed.at,
+                "max staleness" => ?max_staleness,
+            );
+        }
+        CAMPAIGNS_STALE.inc_by(newly_stale.len() as u64);
+        let newly_stale = newly_stale.len();
+
+        *stale = now_stale
+            .into_iter()
+            .map(|(channel_id, _)| channel_id)
+            .collect();
+
+        newly_stale
+    }
+
+    /// The Active Campaigns matching the `filter`, only the matching ones are cloned
+    pub async fn filter_campaigns(&self, filter: &CampaignFilter) -> Vec<Campaign> {
+        filter
+            .apply(self.active.read().await.values().map(Arc::as_ref))
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// The aggregates of the Active Campaigns, as of the last time they changed
+    pub async fn stats(&self) -> CacheStats {
+        let mut stats = self.stats.read().await.clone();
+        stats.blocked = self
+            .anomalies
+            .read()
+            .await
+            .blocked(self.clock.now_instant());
+        stats.validators = self.validator_changes.read().await.clone();
+
+        stats
+    }
+
+    /// Computes the [`CacheStats`] and sets their metrics,
+    /// called once the Active Campaigns change instead of on every request
+    async fn refresh_stats(&self) {
+        let finalized = self.finalized.read().await.len();
+        let mut stats =
+            CacheStats::compute(&*self.active.read().await, finalized, self.clock.now_utc());
+        stats.skipped_invalid = self.invalid.read().await.len();
+        stats.restricted = self.restricted.read().await.len();
+        stats.set_gauges(&self.network, &self.stats_assets);
+
+        *self.stats.write().await = stats;
+    }
+
+    /// The generation of the Active Campaigns, the targeting results
+    /// memoized in [`Cache::targeting_memo`] are valid only for the same generation.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// The snapshot of the Active Campaigns of the current generation,
+    /// it's never modified (the next generation replaces it) so it can be held for a whole request.
+    pub async fn current(&self) -> Arc<Generation> {
+        self.current.read().await.clone()
+    }
+
+    /// Publishes the next generation with a snapshot of the Active Campaigns.
+    /// None of their locks may be held for writing by the caller.
+    ///
+    /// The snapshot shares the Campaigns and the Follower's balances with the Cache (see [`ActiveCache`]),
+    /// only the maps of the ChannelIds are copied.
+    async fn next_generation(&self) {
+        // held throughout, so the concurrent updates publish their generations in order
+        let mut current = self.current.write().await;
+        let number = current.number + 1;
+
+        *current = Arc::new(Generation {
+            number,
+            active: self.active.read().await.clone(),
+            refreshed: self.refreshed.read().await.clone(),
+            follower_balances: Arc::clone(&*self.follower_balances.read().await),
+            restricted: self.restricted.read().await.clone(),
+        });
+        self.generation.store(number, Ordering::SeqCst);
+    }
+
+    /// The Leader's NewState from which the cached balances of the Active Campaign are
+    pub async fn last_new_state(&self, channel_id: &ChannelId) -> Option<LastNewState> {
+        self.client.last_new_state(channel_id).await
+    }
+
+    /// The Validators from which the new Campaigns are collected
+    pub async fn validators(&self) -> HashSet<ApiUrl> {
+        self.client.validators().await
+    }
+
+    /// Replaces the Validators from which the new Campaigns are collected,
+    /// the added ones are immediately backfilled (see [`Cache::fetch_new_campaigns_from`]).
+    /// The Campaigns of the removed Validators are kept until they are Finalized.
+    /// The changes are part of the [`Cache::stats`].
+    ///
+    /// Returns the added Validators.
+    pub async fn replace_validators(&self, validators: HashSet<ApiUrl>) -> HashSet<ApiUrl> {
+        let current = self.client.validators().await;
+        let added = validators
+            .difference(&current)
+            .cloned()
+            .collect::<HashSet<_>>();
+        let removed = current
+            .difference(&validators)
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        self.client.set_validators(validators).await;
+        self.validator_changes.write().await.record(
+            added.iter().cloned(),
+            removed.iter().cloned(),
+            self.clock.now_utc(),
+        );
+        info!(&self.logger, "Replaced the Validators"; "added" => ?added, "removed" => ?removed);
+
+        for validator in added.iter() {
+            self.fetch_new_campaigns_from(validator).await;
+        }
+
+        added
+    }
+
+    /// Stops collecting new Campaigns from the Validator with the `host` (incl. the port, if it's set),
+    /// the Campaigns collected from it are kept until they are Finalized.
+    /// The change is part of the [`Cache::stats`].
+    ///
+    /// Returns the removed Validator, if there was one with this host.
+    pub async fn remove_validator(&self, host: &str) -> Option<ApiUrl> {
+        let mut validators = self.client.validators().await;
+        let removed = validators
+            .iter()
+            .find(|validator| validator_host(validator).as_deref() == Some(host))
+            .cloned()?;
+
+        validators.remove(&removed);
+        self.client.set_validators(validators).await;
+        self.validator_changes.write().await.record(
+            std::iter::empty(),
+            std::iter::once(removed.clone()),
+            self.clock.now_utc(),
+        );
+        info!(&self.logger, "Removed Validator"; "validator" => %removed);
+
+        Some(removed)
+    }
+
+    /// The Validators (leaders & followers) of the Active Campaigns
+    pub async fn known_validators(&self) -> HashSet<ApiUrl> {
+        self.active
+            .read()
+            .await
+            .values()
+            .flat_map(|campaign| {
+                let validators = &campaign.channel.spec.validators;
+
+                vec![
+                    validators.leader().url.clone(),
+                    validators.follower().url.clone(),
+                ]
+            })
+            .filter_map(|url| url.parse().ok())
+            .collect()
     }
 
     /// Reads the active campaigns and schedules a list of non-finalized campaigns for update
-    pub async fn fetch_campaign_updates(&self) {
-        let (active, finalized) = self
-            .client
+    ///
+    /// Returns what changed, see [`Cache::apply_campaign_updates`]
+    pub async fn fetch_campaign_updates(&self) -> CampaignDiff {
+        let updates = self.collect_campaign_updates().await;
+
+        self.apply_campaign_updates(updates).await
+    }
+
+    /// Fetches the updates of the Active Campaigns without changing the Cache,
+    /// the network phase of [`Cache::fetch_campaign_updates`]
+    pub async fn collect_campaign_updates(&self) -> CampaignUpdates {
+        self.client
             .fetch_campaign_updates(&*self.active.read().await)
-            .await;
+            .await
+    }
+
+    /// Applies the collected updates (see [`Cache::collect_campaign_updates`])
+    /// and replaces the Campaigns whose spec was amended.
+    ///
+    /// Returns what changed in the Active Campaigns, which is published to the subscribers unless it's empty,
+    /// see [`Cache::subscribe_diffs`]. Without any changes the generation (and the memoized targeting results) and the stats are kept.
+    pub async fn apply_campaign_updates(
+        &self,
+        (active, finalized): CampaignUpdates,
+    ) -> CampaignDiff {
+        let mut diff = self.update(ActiveAction::Update(active), finalized).await;
+        diff.extend(self.refresh_changed_specs().await);
+
+        self.last_runs.write().await.campaign_updates = self.clock.now_instant();
+        self.publish(&diff);
 
-        self.update(ActiveAction::Update(active), finalized).await
+        diff
     }
+
+    /// Replaces the Channels of the Active Campaigns whose spec or targeting rules were amended,
+    /// dropping the targeting results of the previous ones (memoized and the matched units).
+    /// Campaigns amended with an invalid spec (e.g. too complex targeting rules, see [`validation::validate`])
+    /// are removed and skipped from then on, like the newly discovered ones.
+    ///
+    /// Returns the Campaigns whose spec changed and the removed ones.
+    async fn refresh_changed_specs(&self) -> CampaignDiff {
+        let channels = self.client.fetch_channels(&*self.active.read().await).await;
+        let now = self.clock.now_utc();
+
+        let mut diff = CampaignDiff::default();
+        {
+            let mut active = self.active.write().await;
+            for (channel_id, channel) in channels {
+                let changed = match active.get(&channel_id) {
+                    Some(campaign) => spec_hash(&campaign.channel) != spec_hash(&channel),
+                    None => false,
+                };
+                if !changed {
+                    continue;
+                }
+
+                if let Err(error) = validation::validate(&channel, now) {
+                    warn!(
+                        &self.logger,
+                        "Removed a Campaign amended with an invalid spec: {}",
+                        error;
+                        "channel_id" => %channel_id,
+                        "rule" => error.rule(),
+                    );
+                    INVALID_CAMPAIGNS.with_label_values(&[error.rule()]).inc();
+
+                    active.remove(&channel_id);
+                    self.invalid.write().await.insert(channel_id);
+                    diff.removed.insert(channel_id);
+                    continue;
+                }
+
+                info!(&self.logger, "Campaign spec changed, replacing it"; "channel_id" => %channel_id);
+                if let Some(campaign) = active.get_mut(&channel_id) {
+                    Arc::make_mut(campaign).channel = channel;
+                }
+                diff.spec_changed.insert(channel_id);
+            }
+        }
+        if !diff.removed.is_empty() {
+            let mut refreshed = self.refreshed.write().await;
+            let mut follower_balances = self.follower_balances.write().await;
+            let follower_balances = Arc::make_mut(&mut *follower_balances);
+            for channel_id in diff.removed.iter() {
+                refreshed.remove(channel_id);
+                follower_balances.remove(channel_id);
+            }
+        }
+
+        if !diff.is_empty() {
+            CAMPAIGN_SPEC_CHANGES.inc_by(diff.spec_changed.len() as u64);
+            // the memoized targeting results are dropped with the new generation
+            self.next_generation().await;
+            self.matched_units.write().await.clear();
+            self.refresh_stats().await;
+        }
+
+        diff
+    }
+
+    /// The Cache is stale if either the last fetching of new campaigns or
+    /// the last updating of campaigns has completed more than
+    /// [`Config.watchdog_multiplier`](crate::Config::watchdog_multiplier) times their interval ago.
+    pub async fn is_stale(&self, config: &Config) -> bool {
+        let last_runs = *self.last_runs.read().await;
+        let multiplier = config.watchdog_multiplier;
+        let now = self.clock.now_instant();
+
+        now.saturating_duration_since(last_runs.new_campaigns)
+            > config.fetch_campaigns_every * multiplier
+            || now.saturating_duration_since(last_runs.campaign_updates)
+                > config.update_campaigns_every * multiplier
+    }
+
+    /// Whether the initial Campaigns were fetched (see [`Cache::fetch_initial_campaigns`]) or loaded from a snapshot
+    pub async fn is_initialized(&self) -> bool {
+        self.init_progress.read().await.is_completed()
+    }
+
+    /// While it's initialized (see [`Cache::fetch_initial_campaigns`]), the Cache is ready once at least
+    /// the [`Initialization::ready_fraction`](crate::config::Initialization::ready_fraction) of the Validators
+    /// have returned a page, afterwards it's ready unless it's stale (see [`Cache::is_stale`]).
+    pub async fn is_ready(&self, config: &Config) -> bool {
+        {
+            let progress = self.init_progress.read().await;
+            if !progress.is_completed() {
+                return progress.is_ready(config.initialization.ready_fraction);
+            }
+        }
+
+        !self.is_stale(config).await
+    }
+
+    /// Whether the Active Campaigns can't be served as usual, i.e. the Cache is stale (see [`Cache::is_stale`]),
+    /// there are no Active Campaigns or all of them weren't refreshed within the `max_campaign_staleness`.
+    pub async fn is_degraded(&self, config: &Config) -> bool {
+        if self.is_stale(config).await {
+            return true;
+        }
+
+        let active = self.active.read().await;
+        if active.is_empty() {
+            return true;
+        }
+
+        match config.max_campaign_staleness {
+            Some(max_staleness) => {
+                let refreshed = self.refreshed.read().await;
+                let now = self.clock.now_instant();
+
+                active.keys().all(|channel_id| {
+                    refreshed
+                        .get(channel_id)
+                        .map_or(false, |refreshed| refreshed.is_stale(max_staleness, now))
+                })
+            }
+            None => false,
+        }
+    }
+
+    /// Logs diagnostics for incident response:
+    /// - Cache stats
+    /// - The state & the consecutive failures of the Market's `circuit_breaker`, see [`MarketApi::circuit_breaker`](crate::MarketApi::circuit_breaker)
+    /// - Failed requests and skipped malformed entries per Validator
+    /// - The most recently updated Campaigns with their statuses
+    ///
+    /// While holding the locks it only makes copies of the values it needs,
+    /// the sorting and logging happens after the locks are released.
+    pub async fn dump_diagnostics(&self, logger: &Logger, circuit_breaker: &CircuitBreaker) {
+        let (active_count, mut recent) = {
+            let active = self.active.read().await;
+            let refreshed = self.refreshed.read().await;
+
+            let recent = refreshed
+                .iter()
+                .filter_map(|(channel_id, refreshed_at)| {
+                    active
+                        .get(channel_id)
+                        .map(|campaign| (*channel_id, *refreshed_at, campaign.status.clone()))
+                })
+                .collect::<Vec<_>>();
+
+            (active.len(), recent)
+        };
+        let finalized_count = self.finalized.read().await.len();
+        let last_runs = *self.last_runs.read().await;
+        let validator_failures = self.client.validator_failures().await;
+        let malformed_entries = self.client.validator_malformed_entries().await;
+        let now = self.clock.now_instant();
+
+        info!(
+            logger,
+            "Cache diagnostics";
+            "active" => active_count,
+            "finalized" => finalized_count,
+            "last new campaigns run" => ?now.saturating_duration_since(last_runs.new_campaigns),
+            "last campaign updates run" => ?now.saturating_duration_since(last_runs.campaign_updates),
+        );
+
+        info!(
+            logger,
+            "Market circuit breaker";
+            "state" => if circuit_breaker.is_open(now) { "open" } else { "closed" },
+            "failures" => circuit_breaker.failures(),
+        );
+
+        for (validator, failures) in validator_failures {
+            info!(logger, "Validator failures"; "validator" => validator, "failures" => failures);
+        }
+
+        for (validator, malformed) in malformed_entries {
+            info!(logger, "Validator malformed entries"; "validator" => validator, "malformed" => malformed);
+        }
+
+        // most recently updated first
+        recent.sort_by(|(_, a, _), (_, b, _)| b.cmp(a));
+        for (channel_id, refreshed_at, status) in
+            recent.into_iter().take(DIAGNOSTICS_RECENT_CAMPAIGNS)
+        {
+            info!(
+                logger,
+                "Recently updated Campaign";
+                "channel" => %channel_id,
+                "updated" => %refreshed_at.at,
+                "status" => ?status,
+            );
+        }
+    }
+}
+
+/// The host (and port) of the Validator URL, see [`sentry_api::validator_host`](crate::sentry_api::validator_host)
+fn validator_host(validator: &ApiUrl) -> Option<String> {
+    Url::parse(&validator.to_string())
+        .ok()
+        .map(|url| crate::sentry_api::validator_host(&url))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    use crate::{config::DEVELOPMENT, util::test::discard_logger};
+    use crate::{
+        cache::MockClient,
+        config::DEVELOPMENT,
+        util::test::{discard_logger, MemoryDrain, MockClock},
+    };
     use crate::{
         status::test::{get_approve_state_msg, get_heartbeat_msg, get_new_state_msg},
         SentryApi,
@@ -202,7 +1598,9 @@ mod test {
         },
         util::{
             api::ApiUrl,
-            tests::prep_db::{DUMMY_CHANNEL, DUMMY_VALIDATOR_FOLLOWER, DUMMY_VALIDATOR_LEADER},
+            tests::prep_db::{
+                DUMMY_CHANNEL, DUMMY_VALIDATOR_FOLLOWER, DUMMY_VALIDATOR_LEADER, IDS,
+            },
         },
         validator::{MessageTypes, NewState},
         Channel,
@@ -223,12 +1621,54 @@ mod test {
         let client = ApiClient {
             logger,
             sentry,
-            validators,
+            validators: Arc::new(Lock::new(validators)),
+            failures: Default::default(),
+            new_states: Default::default(),
+            follower_balances: Default::default(),
+            clock: Arc::new(SystemClock),
+            heartbeat_recency: Default::default(),
         };
 
         Ok(Cache {
-            active: Arc::new(RwLock::new(active)),
-            finalized: Arc::new(RwLock::new(finalized)),
+            active: Arc::new(Lock::new(
+                active
+                    .into_iter()
+                    .map(|(channel_id, campaign)| (channel_id, Arc::new(campaign)))
+                    .collect(),
+            )),
+            finalized: Arc::new(Lock::new(finalized)),
+            last_runs: Arc::new(Lock::new(LastRuns::now(&SystemClock))),
+            refreshed: Default::default(),
+            follower_balances: Default::default(),
+            stale: Default::default(),
+            invalid: Default::default(),
+            evicted: Default::default(),
+            restricted: Default::default(),
+            init_progress: Arc::new(Lock::new(InitProgress::completed())),
+            matched_units: Default::default(),
+            coalesced_requests: Default::default(),
+            targeting_memo: Default::default(),
+            slots: Default::default(),
+            slot_popularity: Default::default(),
+            negative_slots: Default::default(),
+            idempotency_keys: Default::default(),
+            media_checks: Default::default(),
+            publisher_stats: Default::default(),
+            serve_counters: Default::default(),
+            serve_history: Default::default(),
+            round_robin: Default::default(),
+            anomalies: Default::default(),
+            validator_changes: Default::default(),
+            slot_overrides: Default::default(),
+            generation: Default::default(),
+            current: Default::default(),
+            diffs: broadcast::channel(DIFFS_CAPACITY).0,
+            stats: Default::default(),
+            stats_assets: Default::default(),
+            network: DEFAULT_NETWORK.into(),
+            allowlist: Default::default(),
+            limits: Default::default(),
+            clock: Arc::new(SystemClock),
             logger: client.logger().clone(),
             client,
         })
@@ -786,4 +2226,922 @@ mod test {
         assert_eq!(1, finalized.len());
         assert_eq!(Some(&channel_id), finalized.get(&channel_id));
     }
+
+    #[tokio::test]
+    async fn cache_becomes_stale_when_it_is_not_updated() {
+        let clock = MockClock::new();
+
+        let config = DEVELOPMENT.clone();
+        let client = MockClient::init(vec![HashMap::new()], vec![Default::default()], None).await;
+        let cache = Cache::builder(client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+
+        assert!(!cache.is_stale(&config).await);
+
+        // just before the allowed time for updating the campaigns has passed
+        let allowed_update = config.update_campaigns_every * config.watchdog_multiplier;
+        clock.advance(allowed_update - std::time::Duration::from_secs(1));
+        assert!(!cache.is_stale(&config).await);
+
+        // the update completes and keeps the Cache fresh
+        cache.fetch_campaign_updates().await;
+        clock.advance(std::time::Duration::from_secs(2));
+        assert!(!cache.is_stale(&config).await);
+
+        // no more updates complete and the allowed time passes
+        clock.advance(allowed_update);
+        assert!(cache.is_stale(&config).await);
+    }
+
+    #[tokio::test]
+    async fn cache_dumps_diagnostics() {
+        let mut channel = DUMMY_CHANNEL.clone();
+        let campaigns = (0..12_u8)
+            .map(|i| {
+                channel.id = ChannelId::from([i; 32]);
+
+                let campaign = Campaign {
+                    channel: channel.clone(),
+                    status: Status::Active,
+                    balances: Default::default(),
+                };
+
+                (channel.id, campaign)
+            })
+            .collect();
+
+        let client = MockClient::init(vec![campaigns], vec![], None).await;
+        let cache = Cache::initialize(client).await;
+
+        let circuit_breaker = CircuitBreaker::new(std::time::Duration::from_secs(5));
+        circuit_breaker.record(false, cache.clock().now_instant());
+        circuit_breaker.record(false, cache.clock().now_instant());
+
+        let drain = MemoryDrain::default();
+        cache
+            .dump_diagnostics(&drain.logger(), &circuit_breaker)
+            .await;
+
+        let records = drain.records();
+        // Stats + the circuit breaker + the limited number of recently updated campaigns
+        assert_eq!(2 + DIAGNOSTICS_RECENT_CAMPAIGNS, records.len());
+
+        let (message, stats) = &records[0];
+        assert_eq!("Cache diagnostics", message);
+        assert_eq!(Some("12"), stats.get("active").map(String::as_str));
+        assert_eq!(Some("0"), stats.get("finalized").map(String::as_str));
+        assert!(stats.contains_key("last new campaigns run"));
+        assert!(stats.contains_key("last campaign updates run"));
+
+        let (message, circuit_breaker) = &records[1];
+        assert_eq!("Market circuit breaker", message);
+        assert_eq!(
+            Some("open"),
+            circuit_breaker.get("state").map(String::as_str)
+        );
+        assert_eq!(
+            Some("2"),
+            circuit_breaker.get("failures").map(String::as_str)
+        );
+
+        for (message, campaign) in &records[2..] {
+            assert_eq!("Recently updated Campaign", message);
+            assert_eq!(Some("Active"), campaign.get("status").map(String::as_str));
+            assert!(campaign.contains_key("channel"));
+            assert!(campaign.contains_key("updated"));
+        }
+    }
+
+    fn budget_campaign(id: u8, deposit: u64, spent: u64) -> Campaign {
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.id = ChannelId::from([id; 32]);
+        channel.deposit_amount = deposit.into();
+
+        let mut balances = BalancesMap::default();
+        balances.insert(DUMMY_VALIDATOR_LEADER.id, spent.into());
+
+        Campaign::new(channel, Status::Active, balances)
+    }
+
+    fn campaigns_by_id(campaigns: Vec<Campaign>) -> HashMap<ChannelId, Campaign> {
+        campaigns
+            .into_iter()
+            .map(|campaign| (campaign.channel.id, campaign))
+            .collect()
+    }
+
+    fn active_cache(campaigns: Vec<Campaign>) -> ActiveCache {
+        campaigns
+            .into_iter()
+            .map(|campaign| (campaign.channel.id, Arc::new(campaign)))
+            .collect()
+    }
+
+    #[test]
+    fn estimated_size_is_the_serialized_length_and_the_generation_entries() {
+        let campaign = budget_campaign(1, 1_000, 10);
+        assert_eq!(
+            serde_json::to_vec(&campaign)
+                .expect("Should serialize")
+                .len()
+                + generation::ENTRY_SIZE,
+            estimated_size(&campaign)
+        );
+
+        let mut with_more_balances = campaign.clone();
+        with_more_balances
+            .balances
+            .insert(DUMMY_VALIDATOR_FOLLOWER.id, 20.into());
+        assert!(estimated_size(&with_more_balances) > estimated_size(&campaign));
+    }
+
+    #[test]
+    fn evicts_the_campaigns_with_the_smallest_remaining_budget() {
+        // remaining budgets: 1 => 900, 2 => 500, 3 => 0 (overspent), 4 => 990
+        let active = active_cache(vec![
+            budget_campaign(1, 1_000, 100),
+            budget_campaign(2, 1_000, 500),
+            budget_campaign(3, 1_000, 2_000),
+            budget_campaign(4, 1_000, 10),
+        ]);
+        let refreshed = RefreshedCache::new();
+
+        assert!(campaigns_to_evict(
+            &active,
+            &refreshed,
+            &FollowerBalances::new(),
+            &CacheLimits::default()
+        )
+        .is_empty());
+
+        let by_count = CacheLimits {
+            max_campaigns: Some(2),
+            max_bytes: None,
+        };
+        let mut evicted =
+            campaigns_to_evict(&active, &refreshed, &FollowerBalances::new(), &by_count);
+        evicted.sort_by_key(ToString::to_string);
+        assert_eq!(
+            vec![ChannelId::from([2; 32]), ChannelId::from([3; 32])],
+            evicted
+        );
+
+        // every campaign has (almost) the same size, so only 3 fit
+        let size = estimated_size(&active[&ChannelId::from([1; 32])]);
+        let by_bytes = CacheLimits {
+            max_campaigns: None,
+            max_bytes: Some(size * 3 + size / 2),
+        };
+        assert_eq!(
+            vec![ChannelId::from([3; 32])],
+            campaigns_to_evict(&active, &refreshed, &FollowerBalances::new(), &by_bytes)
+        );
+    }
+
+    #[test]
+    fn the_remaining_budget_is_by_the_most_spent_of_the_leader_and_follower_balances() {
+        // remaining budgets by the Leader: 1 => 900, 2 => 500, 3 => 990
+        let active = active_cache(vec![
+            budget_campaign(1, 1_000, 100),
+            budget_campaign(2, 1_000, 500),
+            budget_campaign(3, 1_000, 10),
+        ]);
+        let refreshed = RefreshedCache::new();
+        let limits = CacheLimits {
+            max_campaigns: Some(2),
+            max_bytes: None,
+        };
+        assert_eq!(
+            vec![ChannelId::from([2; 32])],
+            campaigns_to_evict(&active, &refreshed, &FollowerBalances::new(), &limits)
+        );
+
+        // the Follower approved less for the Leader's address, but also an address the Leader's NewState is missing
+        let follower: BalancesMap = vec![
+            (DUMMY_VALIDATOR_LEADER.id, BigNum::from(5)),
+            (IDS["publisher"], BigNum::from(600)),
+        ]
+        .into_iter()
+        .collect();
+        let follower_balances: FollowerBalances = vec![(ChannelId::from([3; 32]), follower)]
+            .into_iter()
+            .collect();
+
+        let campaign = &active[&ChannelId::from([3; 32])];
+        assert_eq!(
+            BigNum::from(390),
+            remaining_budget_by(campaign, &effective_balances(campaign, &follower_balances))
+        );
+        assert_eq!(
+            vec![ChannelId::from([3; 32])],
+            campaigns_to_evict(&active, &refreshed, &follower_balances, &limits)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_follower_balances_of_the_active_campaigns_are_synced_from_the_client() {
+        let campaigns = campaigns_by_id(vec![budget_campaign(1, 1_000, 100)]);
+        let follower: BalancesMap = vec![(IDS["publisher"], BigNum::from(50))]
+            .into_iter()
+            .collect();
+        // only the ones of the Active Campaigns are kept
+        let client = MockClient::init(vec![campaigns], vec![], None)
+            .await
+            .with_follower_balances(
+                vec![
+                    (ChannelId::from([1; 32]), follower.clone()),
+                    (ChannelId::from([2; 32]), follower.clone()),
+                ]
+                .into_iter()
+                .collect(),
+            );
+
+        let cache = Cache::builder(client).initialize().await;
+        assert_eq!(
+            vec![(ChannelId::from([1; 32]), follower)]
+                .into_iter()
+                .collect::<FollowerBalances>(),
+            **cache.follower_balances.read().await
+        );
+    }
+
+    #[tokio::test]
+    async fn the_generations_share_the_unchanged_campaigns_with_the_cache() {
+        let campaigns = campaigns_by_id(vec![
+            budget_campaign(1, 1_000, 100),
+            budget_campaign(2, 1_000, 500),
+        ]);
+        let (unchanged, changed) = (ChannelId::from([1; 32]), ChannelId::from([2; 32]));
+        let updates = vec![
+            (unchanged, campaigns[&unchanged].balances.clone()),
+            (
+                changed,
+                vec![(IDS["publisher"], BigNum::from(600))]
+                    .into_iter()
+                    .collect(),
+            ),
+        ]
+        .into_iter()
+        .map(|(channel_id, balances)| (channel_id, (Status::Active, balances)))
+        .collect();
+        let client = MockClient::init(
+            vec![campaigns],
+            vec![(updates, FinalizedCache::new())],
+            None,
+        )
+        .await;
+        let cache = Cache::initialize(client).await;
+
+        let first = cache.current().await;
+        {
+            let active = cache.active.read().await;
+            assert!(Arc::ptr_eq(&active[&unchanged], &first.active[&unchanged]));
+            assert!(Arc::ptr_eq(&active[&changed], &first.active[&changed]));
+        }
+
+        cache.fetch_campaign_updates().await;
+
+        let second = cache.current().await;
+        assert_eq!(first.number + 1, second.number);
+        // only the changed Campaign is copied, the published generation keeps its own
+        assert!(Arc::ptr_eq(
+            &first.active[&unchanged],
+            &second.active[&unchanged]
+        ));
+        assert!(!Arc::ptr_eq(
+            &first.active[&changed],
+            &second.active[&changed]
+        ));
+        assert_eq!(
+            BigNum::from(500),
+            first.active[&changed].balances.values().sum::<BigNum>()
+        );
+        assert_eq!(
+            BigNum::from(600),
+            second.active[&changed].balances.values().sum::<BigNum>()
+        );
+        assert!(Arc::ptr_eq(
+            &cache.active.read().await[&changed],
+            &second.active[&changed]
+        ));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_refreshed_campaigns_with_the_same_budget() {
+        let active = active_cache(vec![
+            budget_campaign(1, 1_000, 100),
+            budget_campaign(2, 1_000, 100),
+            budget_campaign(3, 1_000, 100),
+        ]);
+        let now = Refreshed::now(&MockClock::new());
+        let ago = |minutes| Refreshed {
+            at: now.at - Duration::minutes(minutes),
+            ..now
+        };
+        let refreshed: RefreshedCache = vec![
+            (ChannelId::from([1; 32]), ago(10)),
+            (ChannelId::from([2; 32]), now),
+            (ChannelId::from([3; 32]), ago(5)),
+        ]
+        .into_iter()
+        .collect();
+
+        let limits = CacheLimits {
+            max_campaigns: Some(1),
+            max_bytes: None,
+        };
+        let mut evicted =
+            campaigns_to_evict(&active, &refreshed, &FollowerBalances::new(), &limits);
+        evicted.sort_by_key(ToString::to_string);
+
+        assert_eq!(
+            vec![ChannelId::from([1; 32]), ChannelId::from([3; 32])],
+            evicted
+        );
+    }
+
+    #[tokio::test]
+    async fn fetching_new_campaigns_applies_the_cache_limits() {
+        let campaigns = campaigns_by_id(vec![
+            budget_campaign(1, 1_000, 100),
+            budget_campaign(2, 1_000, 500),
+            budget_campaign(3, 1_000, 10),
+        ]);
+        let client = MockClient::init(vec![campaigns], vec![], None).await;
+
+        let evicted_before = CAMPAIGNS_EVICTED.get();
+        let cache = Cache::builder(client)
+            .limits(CacheLimits {
+                max_campaigns: Some(2),
+                max_bytes: None,
+            })
+            .initialize()
+            .await;
+
+        let active = cache.active.read().await;
+        assert_eq!(2, active.len());
+        assert!(!active.contains_key(&ChannelId::from([2; 32])));
+        assert!(!cache
+            .refreshed
+            .read()
+            .await
+            .contains_key(&ChannelId::from([2; 32])));
+        assert!(CAMPAIGNS_EVICTED.get() >= evicted_before + 1);
+    }
+
+    #[tokio::test]
+    async fn the_evicted_campaigns_are_not_added_back_until_one_is_finalized() {
+        // remaining budgets: 1 => 900, 2 => 500, 3 => 990
+        let campaigns = campaigns_by_id(vec![
+            budget_campaign(1, 1_000, 100),
+            budget_campaign(2, 1_000, 500),
+            budget_campaign(3, 1_000, 10),
+        ]);
+        let finalized = std::iter::once(ChannelId::from([1; 32])).collect::<FinalizedCache>();
+        let updated = std::iter::once((
+            ChannelId::from([3; 32]),
+            (
+                Status::Active,
+                campaigns[&ChannelId::from([3; 32])].balances.clone(),
+            ),
+        ))
+        .collect();
+        let client = MockClient::init(vec![campaigns], vec![(updated, finalized)], None).await;
+
+        let cache = Cache::builder(client)
+            .limits(CacheLimits {
+                max_campaigns: Some(2),
+                max_bytes: None,
+            })
+            .initialize()
+            .await;
+        assert_eq!(
+            vec![ChannelId::from([2; 32])],
+            cache
+                .evicted
+                .read()
+                .await
+                .iter()
+                .copied()
+                .collect::<Vec<_>>()
+        );
+
+        // the same Campaigns are fetched again, the evicted one isn't added & evicted again
+        let mut diffs = cache.subscribe_diffs();
+        cache.fetch_new_campaigns().await;
+        assert!(diffs.try_recv().is_err(), "Nothing should change");
+        assert!(!cache
+            .active
+            .read()
+            .await
+            .contains_key(&ChannelId::from([2; 32])));
+
+        // a Finalized one makes room for it
+        cache.fetch_campaign_updates().await;
+        assert!(cache.evicted.read().await.is_empty());
+        cache
+            .add_new_campaigns(campaigns_by_id(vec![budget_campaign(2, 1_000, 500)]))
+            .await;
+        let active = cache.active.read().await;
+        assert_eq!(2, active.len());
+        assert!(active.contains_key(&ChannelId::from([2; 32])));
+    }
+
+    #[tokio::test]
+    async fn the_fetched_campaigns_which_do_not_fit_are_never_added() {
+        let client = MockClient::init(
+            vec![campaigns_by_id(vec![
+                budget_campaign(1, 1_000, 100),
+                budget_campaign(2, 1_000, 500),
+            ])],
+            vec![],
+            None,
+        )
+        .await;
+        let cache = Cache::builder(client)
+            .limits(CacheLimits {
+                max_campaigns: Some(2),
+                max_bytes: None,
+            })
+            .initialize()
+            .await;
+
+        let mut diffs = cache.subscribe_diffs();
+        let generation = cache.generation();
+        // remaining budget of 0, it has the lowest priority
+        cache
+            .add_new_campaigns(campaigns_by_id(vec![budget_campaign(3, 1_000, 1_000)]))
+            .await;
+
+        assert!(diffs.try_recv().is_err(), "Nothing should change");
+        assert_eq!(generation, cache.generation());
+        assert!(!cache
+            .current()
+            .await
+            .active
+            .contains_key(&ChannelId::from([3; 32])));
+        assert!(cache
+            .evicted
+            .read()
+            .await
+            .contains(&ChannelId::from([3; 32])));
+    }
+
+    #[tokio::test]
+    async fn campaigns_are_stale_until_they_are_refreshed() {
+        let clock = MockClock::new();
+
+        let max_staleness = std::time::Duration::from_secs(300);
+        let campaigns = campaigns_by_id(vec![
+            budget_campaign(1, 1_000, 100),
+            budget_campaign(2, 1_000, 500),
+        ]);
+        let client = MockClient::init(vec![campaigns], vec![], None).await;
+        let cache = Cache::builder(client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+
+        let stale_before = CAMPAIGNS_STALE.get();
+        assert_eq!(0, cache.check_staleness(max_staleness).await);
+
+        clock.advance(max_staleness + std::time::Duration::from_secs(1));
+        assert!(cache
+            .refreshed
+            .read()
+            .await
+            .values()
+            .all(|refreshed| refreshed.is_stale(max_staleness, clock.now_instant())));
+        assert_eq!(2, cache.check_staleness(max_staleness).await);
+        // already logged & counted
+        assert_eq!(0, cache.check_staleness(max_staleness).await);
+        assert!(CAMPAIGNS_STALE.get() >= stale_before + 2);
+
+        // refreshes the Campaigns
+        cache.fetch_new_campaigns().await;
+        assert_eq!(0, cache.check_staleness(max_staleness).await);
+        assert!(cache.stale.read().await.is_empty());
+
+        clock.advance(max_staleness + std::time::Duration::from_secs(1));
+        assert_eq!(2, cache.check_staleness(max_staleness).await);
+    }
+
+    #[tokio::test]
+    async fn the_staleness_is_not_affected_by_the_clock_stepping_back() {
+        let clock = MockClock::new();
+
+        let config = DEVELOPMENT.clone();
+        let max_staleness = std::time::Duration::from_secs(300);
+        let client = MockClient::init(
+            vec![campaigns_by_id(vec![budget_campaign(1, 1_000, 100)])],
+            vec![Default::default()],
+            None,
+        )
+        .await;
+        let cache = Cache::builder(client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+
+        // NTP steps the system clock back by more than the allowed times
+        clock.step_back(config.update_campaigns_every * (config.watchdog_multiplier + 1));
+        clock.step_back(max_staleness * 2);
+        assert!(!cache.is_stale(&config).await);
+        assert_eq!(0, cache.check_staleness(max_staleness).await);
+
+        // the elapsed time is measured by the monotonic time
+        clock.advance(max_staleness + std::time::Duration::from_secs(1));
+        assert_eq!(1, cache.check_staleness(max_staleness).await);
+        clock.advance(config.update_campaigns_every * config.watchdog_multiplier);
+        assert!(cache.is_stale(&config).await);
+    }
+
+    #[tokio::test]
+    async fn stats_are_computed_on_every_update() {
+        let first = budget_campaign(1, 1_000, 100);
+        let second = budget_campaign(2, 500, 200);
+        let asset = first.channel.deposit_asset.clone();
+
+        let mut balances = BalancesMap::default();
+        balances.insert(DUMMY_VALIDATOR_LEADER.id, 400.into());
+        let update = (
+            vec![(first.channel.id, (Status::Waiting, balances))]
+                .into_iter()
+                .collect(),
+            vec![second.channel.id].into_iter().collect(),
+        );
+        let client = MockClient::init(
+            vec![campaigns_by_id(vec![first.clone(), second.clone()])],
+            vec![update],
+            None,
+        )
+        .await;
+        let cache = Cache::initialize(client).await;
+
+        let stats = cache.stats().await;
+        assert!(stats.computed_at.is_some());
+        assert_eq!((2, 0), (stats.active, stats.finalized));
+        assert_eq!(Some(&2), stats.by_status.get("active"));
+        let expected = stats::AssetStats {
+            campaigns: 2,
+            deposited: 1_500.into(),
+            distributed: 300.into(),
+            remaining: 1_200.into(),
+        };
+        assert_eq!(Some(&expected), stats.by_asset.get(&asset));
+
+        cache.fetch_campaign_updates().await;
+
+        let stats = cache.stats().await;
+        assert_eq!((1, 1), (stats.active, stats.finalized));
+        assert_eq!(None, stats.by_status.get("active"));
+        assert_eq!(Some(&1), stats.by_status.get("waiting"));
+        let expected = stats::AssetStats {
+            campaigns: 1,
+            deposited: 1_000.into(),
+            distributed: 400.into(),
+            remaining: 600.into(),
+        };
+        assert_eq!(Some(&expected), stats.by_asset.get(&asset));
+    }
+
+    #[tokio::test]
+    async fn campaigns_with_an_invalid_spec_are_skipped() {
+        let valid = budget_campaign(1, 1_000, 100);
+        let invalid = budget_campaign(2, 0, 0);
+        let skipped = || INVALID_CAMPAIGNS.with_label_values(&["zero_deposit"]).get();
+        let skipped_before = skipped();
+
+        let discovered = campaigns_by_id(vec![valid.clone(), invalid.clone()]);
+        let client = MockClient::init(vec![discovered.clone(), discovered], vec![], None).await;
+        let cache = Cache::initialize(client).await;
+
+        let active = cache
+            .active
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(vec![valid.channel.id], active);
+        assert_eq!(1, cache.stats().await.skipped_invalid);
+
+        // discovered again, but counted only once
+        cache.fetch_new_campaigns().await;
+        assert!(!cache.active.read().await.contains_key(&invalid.channel.id));
+        assert_eq!(1, cache.stats().await.skipped_invalid);
+        assert_eq!(skipped_before + 1, skipped());
+    }
+
+    #[tokio::test]
+    async fn campaigns_with_validators_off_the_allowlist_are_skipped_or_restricted() {
+        let mut allowed = budget_campaign(1, 1_000, 100);
+        allowed.channel.spec.validators = setup_channel(
+            &"https://tom.adex.network/".parse().expect("Valid URL"),
+            &"https://jerry.adex.network:8443/"
+                .parse()
+                .expect("Valid URL"),
+        )
+        .spec
+        .validators;
+        // on `localhost`
+        let off_the_allowlist = budget_campaign(2, 1_000, 100);
+        let allowlist = |policy| ValidatorAllowlist::new(&["Adex.Network".to_string()], policy);
+
+        for policy in &[
+            ValidatorAllowlistPolicy::Skip,
+            ValidatorAllowlistPolicy::Restrict,
+        ] {
+            let restricted_before = RESTRICTED_CAMPAIGNS.get();
+            let discovered = campaigns_by_id(vec![allowed.clone(), off_the_allowlist.clone()]);
+            let client = MockClient::init(vec![discovered.clone(), discovered], vec![], None).await;
+            let cache = Cache::builder(client)
+                .validator_allowlist(allowlist(*policy))
+                .initialize()
+                .await;
+            // discovered again, but counted only once
+            cache.fetch_new_campaigns().await;
+
+            let restricted = vec![off_the_allowlist.channel.id]
+                .into_iter()
+                .collect::<HashSet<_>>();
+            assert_eq!(restricted, *cache.restricted.read().await, "{:?}", policy);
+            assert_eq!(1, cache.stats().await.restricted);
+            assert!(RESTRICTED_CAMPAIGNS.get() >= restricted_before + 1);
+
+            let active = cache.active.read().await;
+            assert!(active.contains_key(&allowed.channel.id));
+            assert_eq!(
+                *policy == ValidatorAllowlistPolicy::Restrict,
+                active.contains_key(&off_the_allowlist.channel.id),
+                "{:?}",
+                policy
+            );
+        }
+
+        // any Validator without an allowlist
+        let discovered = campaigns_by_id(vec![allowed, off_the_allowlist]);
+        let cache = Cache::initialize(MockClient::init(vec![discovered], vec![], None).await).await;
+        assert!(cache.restricted.read().await.is_empty());
+        assert_eq!(2, cache.active.read().await.len());
+    }
+
+    #[tokio::test]
+    async fn duplicate_campaigns_of_a_snapshot_are_merged_on_load() {
+        use crate::ids::Canonical;
+        use snapshot::{SnapshotCampaign, SNAPSHOT_VERSION};
+
+        let id = "0x061d5e2a67d0a9a10f1c732bca12a676d83f79663a396f7d87b3e30b9b411088";
+        let forms = vec![
+            id.to_string(),
+            id.to_ascii_uppercase().replacen("0X", "0x", 1),
+            id.trim_start_matches("0x").to_string(),
+        ];
+        let now = Utc::now();
+        let refreshed_at = vec![Some(now - Duration::minutes(5)), Some(now), None];
+
+        let active = forms
+            .iter()
+            .zip(refreshed_at)
+            .enumerate()
+            .map(|(deposit, (form, refreshed_at))| {
+                let Canonical(channel_id) = form
+                    .parse::<Canonical<ChannelId>>()
+                    .expect("Should parse the ChannelId");
+                let mut campaign = budget_campaign(1, 1_000 + deposit as u64, 0);
+                campaign.channel.id = channel_id;
+
+                SnapshotCampaign {
+                    campaign,
+                    refreshed_at,
+                }
+            })
+            .collect();
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: now,
+            active,
+            finalized: Default::default(),
+        };
+
+        let client = MockClient::init(vec![], vec![], None).await;
+        let cache = Cache::builder(client).initialize_from(snapshot).await;
+
+        let active = cache.active.read().await;
+        assert_eq!(1, active.len());
+        let (channel_id, campaign) = active.iter().next().expect("Should have the Campaign");
+        assert_eq!(id, channel_id.to_string());
+        // the most recently refreshed one is kept
+        assert_eq!(BigNum::from(1_001), campaign.channel.deposit_amount);
+        assert_eq!(now, cache.refreshed.read().await[channel_id].at);
+    }
+
+    #[tokio::test]
+    async fn amended_campaign_specs_replace_the_cached_ones() {
+        let campaign = budget_campaign(1, 1_000, 100);
+        let channel_id = campaign.channel.id;
+
+        let mut amended = campaign.channel.clone();
+        amended.targeting_rules = serde_json::from_value(serde_json::json!([{
+            "if": [
+                { "intersects": [{ "get": "adSlot.categories" }, ["IAB3"]] },
+                { "set": ["boost", { "mul": [{ "get": "boost" }, 2.0] }] }
+            ]
+        }]))
+        .expect("Should deserialize the rules");
+        assert_ne!(spec_hash(&campaign.channel), spec_hash(&amended));
+
+        let update = || {
+            let statuses = vec![(channel_id, (Status::Active, campaign.balances.clone()))]
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+
+            (statuses, FinalizedCache::default())
+        };
+        let client = MockClient::init(
+            vec![campaigns_by_id(vec![campaign.clone()])],
+            vec![update()],
+            None,
+        )
+        .await
+        .with_channel_updates(vec![
+            vec![(channel_id, amended.clone())].into_iter().collect(),
+            // unchanged since the last update
+            vec![(channel_id, amended.clone())].into_iter().collect(),
+        ]);
+        let cache = Cache::initialize(client).await;
+
+        let changes_before = CAMPAIGN_SPEC_CHANGES.get();
+        let generation = cache.generation();
+
+        cache.fetch_campaign_updates().await;
+        assert_eq!(
+            spec_hash(&amended),
+            spec_hash(&cache.active.read().await[&channel_id].channel)
+        );
+        assert!(CAMPAIGN_SPEC_CHANGES.get() >= changes_before + 1);
+        assert!(cache.generation() > generation);
+
+        assert!(cache.refresh_changed_specs().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn campaigns_amended_with_too_complex_rules_are_removed() {
+        let campaign = budget_campaign(1, 1_000, 100);
+        let channel_id = campaign.channel.id;
+
+        let mut boost = serde_json::json!({ "get": "boost" });
+        for _ in 0..validation::MAX_RULES_DEPTH {
+            boost = serde_json::json!({ "mul": [boost, 1.1] });
+        }
+        let mut amended = campaign.channel.clone();
+        amended.targeting_rules =
+            serde_json::from_value(serde_json::json!([{ "set": ["boost", boost] }]))
+                .expect("Should deserialize the rules");
+
+        let statuses = vec![(channel_id, (Status::Active, campaign.balances.clone()))]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let client = MockClient::init(
+            vec![campaigns_by_id(vec![campaign.clone()])],
+            vec![(statuses, FinalizedCache::default())],
+            None,
+        )
+        .await
+        .with_channel_updates(vec![vec![(channel_id, amended)].into_iter().collect()]);
+        let cache = Cache::initialize(client).await;
+
+        let skipped = || {
+            INVALID_CAMPAIGNS
+                .with_label_values(&["targeting_rules"])
+                .get()
+        };
+        let skipped_before = skipped();
+
+        let diff = cache.fetch_campaign_updates().await;
+        assert!(diff.removed.contains(&channel_id));
+        assert!(diff.spec_changed.is_empty());
+        assert!(!cache.active.read().await.contains_key(&channel_id));
+        assert_eq!(1, cache.stats().await.skipped_invalid);
+        assert!(skipped() >= skipped_before + 1);
+    }
+
+    #[tokio::test]
+    async fn unchanged_campaign_updates_are_not_published_and_keep_the_generation_and_stats() {
+        let campaign = budget_campaign(1, 1_000, 100);
+        let channel_id = campaign.channel.id;
+        let clock = MockClock::new();
+
+        let unchanged = (
+            vec![(channel_id, (Status::Active, campaign.balances.clone()))]
+                .into_iter()
+                .collect(),
+            FinalizedCache::default(),
+        );
+        let client = MockClient::init(
+            vec![campaigns_by_id(vec![campaign.clone()])],
+            vec![unchanged],
+            None,
+        )
+        .await;
+        let cache = Cache::builder(client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        let mut diffs = cache.subscribe_diffs();
+        let generation = cache.generation();
+        let computed_at = cache.stats().await.computed_at;
+
+        clock.advance(std::time::Duration::from_secs(60));
+        assert!(cache.fetch_campaign_updates().await.is_empty());
+        cache.fetch_new_campaigns().await;
+
+        assert_eq!(generation, cache.generation());
+        assert_eq!(computed_at, cache.stats().await.computed_at);
+        assert!(diffs.try_recv().is_err(), "Nothing should be published");
+
+        let mut balances = campaign.balances.clone();
+        balances.insert(DUMMY_VALIDATOR_LEADER.id, 400.into());
+        let changed = (
+            vec![(channel_id, (Status::Waiting, balances))]
+                .into_iter()
+                .collect(),
+            vec![ChannelId::from([9; 32])].into_iter().collect(),
+        );
+        let diff = cache.apply_campaign_updates(changed).await;
+
+        let expected = diff::CampaignDiff {
+            status_changed: vec![channel_id].into_iter().collect(),
+            balances_changed: vec![channel_id].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(expected, diff);
+        assert_eq!(expected, diffs.try_recv().expect("Should publish the diff"));
+        assert!(cache.generation() > generation);
+        assert_ne!(computed_at, cache.stats().await.computed_at);
+    }
+
+    #[tokio::test]
+    async fn campaign_updates_are_fetched_from_the_validators_of_each_campaign() {
+        let first_server = MockServer::start().await;
+        let second_server = MockServer::start().await;
+
+        let campaign_on = |server: &MockServer, id: u8| {
+            let leader_url = format!("{}/leader", server.uri())
+                .parse()
+                .expect("Valid URL");
+            let follower_url = format!("{}/follower", server.uri())
+                .parse()
+                .expect("Valid URL");
+            let mut channel = setup_channel(&leader_url, &follower_url);
+            channel.id = ChannelId::from([id; 32]);
+
+            Campaign::new(channel, Status::Active, Default::default())
+        };
+
+        let first_campaigns = vec![campaign_on(&first_server, 1), campaign_on(&first_server, 2)];
+        let second_campaigns = vec![campaign_on(&second_server, 3)];
+
+        // the Leaders respond with an error, the rest of the status is not fetched
+        for (server, campaigns, others) in vec![
+            (&first_server, &first_campaigns, &second_campaigns),
+            (&second_server, &second_campaigns, &first_campaigns),
+        ] {
+            for (campaign, expected) in campaigns
+                .iter()
+                .map(|campaign| (campaign, 1_u64))
+                .chain(others.iter().map(|campaign| (campaign, 0_u64)))
+            {
+                Mock::given(method("GET"))
+                    .and(path(format!(
+                        "/leader/channel/{}/last-approved",
+                        campaign.channel.id
+                    )))
+                    .respond_with(ResponseTemplate::new(500))
+                    .expect(expected)
+                    .mount(server)
+                    .await;
+            }
+        }
+
+        let active = first_campaigns
+            .iter()
+            .chain(second_campaigns.iter())
+            .map(|campaign| (campaign.channel.id, campaign.clone()))
+            .collect::<HashMap<_, _>>();
+
+        // none of the Campaigns is on the Validators of the config
+        let cache =
+            setup_cache(active, HashSet::new(), HashSet::new()).expect("Should setup the Cache");
+
+        let (update, finalize) = cache
+            .client
+            .fetch_campaign_updates(&*cache.active.read().await)
+            .await;
+
+        assert!(update.is_empty());
+        assert!(finalize.is_empty());
+    }
 }
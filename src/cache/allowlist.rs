@@ -0,0 +1,155 @@
+//! The Validators whose Campaigns are served, see [`Config.validator_allowlist`](crate::Config::validator_allowlist)
+use crate::config::{Config, ValidatorAllowlistPolicy};
+use primitives::{Channel, ValidatorDesc};
+use reqwest::Url;
+
+/// The hosts of the allowed Validators, a Validator is allowed if the host of its URL (without the port)
+/// is one of them or their subdomain (of any depth). Any Validator is allowed if there are none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidatorAllowlist {
+    /// Lowercase, without a trailing `.` and a port
+    hosts: Vec<String>,
+    pub policy: ValidatorAllowlistPolicy,
+}
+
+impl ValidatorAllowlist {
+    /// The `hosts` are compared case-insensitively, the ones with a port (e.g. `tom.adex.network:443`) regardless of it
+    pub fn new(hosts: &[String], policy: ValidatorAllowlistPolicy) -> Self {
+        let hosts = hosts
+            .iter()
+            .filter_map(|host| normalized_host(host.trim()))
+            .collect();
+
+        Self { hosts, policy }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// Whether the host of the Validator's `url` is allowed, a malformed URL is not
+    pub fn allows(&self, url: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let host = match Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().and_then(normalized_host))
+        {
+            Some(host) => host,
+            None => return false,
+        };
+
+        self.hosts.iter().any(|allowed| {
+            host == *allowed
+                || host
+                    .strip_suffix(allowed.as_str())
+                    .map_or(false, |subdomain| subdomain.ends_with('.'))
+        })
+    }
+
+    /// The first of the Leader & the Follower of the Campaign which isn't allowed
+    pub fn disallowed<'a>(&self, channel: &'a Channel) -> Option<&'a ValidatorDesc> {
+        let validators = &channel.spec.validators;
+
+        vec![validators.leader(), validators.follower()]
+            .into_iter()
+            .find(|validator| !self.allows(&validator.url))
+    }
+}
+
+impl From<&Config> for ValidatorAllowlist {
+    fn from(config: &Config) -> Self {
+        Self::new(
+            &config.validator_allowlist,
+            config.validator_allowlist_policy,
+        )
+    }
+}
+
+/// The lowercase host without a trailing `.` and a port, IPv6 hosts keep their brackets
+fn normalized_host(host: &str) -> Option<String> {
+    let host = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+    Some(host).filter(|host| !host.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use primitives::util::tests::prep_db::DUMMY_CHANNEL;
+
+    fn allowlist(hosts: &[&str]) -> ValidatorAllowlist {
+        let hosts = hosts.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        ValidatorAllowlist::new(&hosts, Default::default())
+    }
+
+    #[test]
+    fn the_hosts_and_their_subdomains_are_allowed_regardless_of_the_port() {
+        let allowlist = allowlist(&["adex.network", "Jerry.Example.com.", "localhost:8005", " "]);
+
+        for url in &[
+            "https://adex.network/",
+            "https://tom.adex.network/",
+            "https://eu.tom.adex.network:8443/",
+            "https://TOM.ADEX.NETWORK./",
+            "https://jerry.example.com/",
+            "http://localhost:8006/",
+        ] {
+            assert!(allowlist.allows(url), "{} should be allowed", url);
+        }
+
+        for url in &[
+            "https://eviladex.network/",
+            "https://adex.network.evil.com/",
+            "https://example.com/",
+            "https://tom.example.com/",
+            "not a url",
+            "http://127.0.0.1:8005/",
+        ] {
+            assert!(!allowlist.allows(url), "{} should not be allowed", url);
+        }
+    }
+
+    #[test]
+    fn any_validator_is_allowed_without_hosts() {
+        let allowlist = allowlist(&[]);
+
+        assert!(allowlist.allows("https://tom.adex.network/"));
+        assert!(allowlist.allows("not a url"));
+        assert!(allowlist.disallowed(&DUMMY_CHANNEL).is_none());
+    }
+
+    #[test]
+    fn the_first_disallowed_validator_of_the_campaign() {
+        let mut channel = DUMMY_CHANNEL.clone();
+        let (mut leader, mut follower) = (
+            channel.spec.validators.leader().clone(),
+            channel.spec.validators.follower().clone(),
+        );
+        leader.url = "https://tom.adex.network/".to_string();
+        follower.url = "https://jerry.example.com/".to_string();
+        channel.spec.validators = (leader, follower).into();
+
+        let disallowed = |hosts: &[&str]| {
+            allowlist(hosts)
+                .disallowed(&channel)
+                .map(|validator| validator.url.clone())
+        };
+        assert_eq!(None, disallowed(&["adex.network", "example.com"]));
+        assert_eq!(
+            Some("https://jerry.example.com/".to_string()),
+            disallowed(&["adex.network"])
+        );
+        assert_eq!(
+            Some("https://tom.adex.network/".to_string()),
+            disallowed(&["example.com"])
+        );
+    }
+}
@@ -1,19 +1,40 @@
 use super::*;
 use crate::{
-    status::{get_status, Status},
+    error_reporting,
+    sentry_api::validator_host,
+    status::{get_status, HeartbeatRecency, LastNewState, Status},
+    util::{Clock, NonDecreasingClock, SystemClock, ERROR_SAMPLER},
     Config, Error, SentryApi,
 };
 use async_trait::async_trait;
-use futures::future::{join_all, FutureExt};
-use primitives::{util::ApiUrl, Channel, ChannelId};
+use futures::{
+    future::{join_all, FutureExt},
+    stream::StreamExt,
+};
+use primitives::{util::ApiUrl, Channel, ChannelId, ValidatorDesc};
 use slog::{error, info, Logger};
 use std::collections::{HashMap, HashSet};
+use url::Url;
+
+/// Every time a Validator fails this many times to return the Channels, the failures are reported
+const REPEATED_VALIDATOR_FAILURES: u64 = 3;
 
 #[derive(Debug, Clone)]
 pub struct ApiClient {
-    pub(crate) validators: HashSet<ApiUrl>,
+    /// The Validators to collect the Campaigns from, can be changed at runtime
+    pub(crate) validators: Cached<HashSet<ApiUrl>>,
     pub(crate) logger: Logger,
     pub(crate) sentry: SentryApi,
+    /// Failed requests for fetching the Channels per Validator
+    pub(crate) failures: Cached<HashMap<ApiUrl, u64>>,
+    /// The Leader's NewState of the last computed status per Campaign, see [`Client::last_new_state`]
+    pub(crate) new_states: Cached<HashMap<ChannelId, LastNewState>>,
+    /// The balances approved by the Follower of the last computed status per Campaign, see [`Client::follower_balances`]
+    pub(crate) follower_balances: Cached<FollowerBalances>,
+    /// For the Campaign statuses, it doesn't go backwards (see [`NonDecreasingClock`]) & [`ApiClient::with_clock`]
+    pub(crate) clock: Arc<dyn Clock>,
+    /// See [`Config.clock_skew_tolerance`](crate::Config::clock_skew_tolerance)
+    pub(crate) heartbeat_recency: HeartbeatRecency,
 }
 
 impl ApiClient {
@@ -22,14 +43,38 @@ impl ApiClient {
             &logger,
             "Initialize Cache ApiClient"; "validators" => format_args!("{:?}", &config.validators)
         );
-        let sentry = SentryApi::new(config.timeouts.validator_request)?;
+        let clock: Arc<dyn Clock> = Arc::new(NonDecreasingClock::new(
+            Arc::new(SystemClock),
+            logger.clone(),
+        ));
+        let sentry = SentryApi::with_timeouts(&config.timeouts)?
+            .with_channel_list(config.channel_list.clone())
+            .with_last_approved(config.last_approved.clone())
+            .with_validators(&config.validators)
+            .with_logger(logger.clone())
+            .with_clock(clock.clone());
+        let heartbeat_recency = (&config).into();
 
         Ok(Self {
-            validators: config.validators,
+            clock,
+            validators: Arc::new(Lock::new(config.validators)),
             logger,
             sentry,
+            failures: Default::default(),
+            new_states: Default::default(),
+            follower_balances: Default::default(),
+            heartbeat_recency,
         })
     }
+
+    /// Replaces the system clock used for computing the Campaign statuses and filtering the expired Channels,
+    /// its wall-clock time is kept from going backwards as well
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(NonDecreasingClock::new(clock, self.logger.clone()));
+        self.sentry = self.sentry.with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
 }
 
 #[async_trait]
@@ -40,32 +85,62 @@ impl Client for ApiClient {
     /// If there is an invalid Channel / ChannelId the status will reflect the current state of the Channel,
     /// since it's computed based on all validators.
     async fn collect_campaigns(&self) -> HashMap<ChannelId, Campaign> {
-        let mut campaigns = HashMap::new();
+        let validators = self.validators.read().await.clone();
 
-        for channel in get_all_channels(&self.logger, &self.sentry, &self.validators).await {
-            match get_status(&self.sentry, &channel).await {
-                Ok((status, balances)) => {
-                    let channel_id = channel.id;
-                    campaigns
-                        .entry(channel_id)
-                        .and_modify(|campaign: &mut Campaign| {
-                            campaign.status = status.clone();
-                            campaign.balances = balances.clone();
-                        })
-                        .or_insert_with(|| Campaign::new(channel, status, balances));
+        self.collect_campaigns_of(&validators).await
+    }
+
+    /// Same as [`ApiClient::collect_campaigns`], but the Validators are requested concurrently
+    /// and the Campaigns of each page are sent as soon as their statuses are computed.
+    /// A Channel moving between the pages of a Validator while they are fetched is sent only once.
+    async fn collect_campaign_pages(&self, pages: mpsc::UnboundedSender<CollectedPage>) {
+        let validators = self.validators.read().await.clone();
+
+        let futures = validators.iter().map(|validator| {
+            let pages = pages.clone();
+
+            async move {
+                let mut seen = HashSet::new();
+                let mut channel_pages = Box::pin(self.sentry.validator_channel_pages(validator));
+
+                while let Some(result) = channel_pages.next().await {
+                    match result {
+                        Ok(channels) => {
+                            let channels = channels
+                                .into_iter()
+                                .filter(|channel| seen.insert(channel.id));
+                            let campaigns = self.campaigns_of(channels).await;
+
+                            // the receiver is dropped only if the Cache stops waiting for the pages
+                            let _ =
+                                pages.send(CollectedPage::Campaigns(validator.clone(), campaigns));
+                        }
+                        Err(err) => {
+                            record_failure(&self.logger, validator, &self.failures, &err).await;
+                            break;
+                        }
+                    }
                 }
-                Err(err) => error!(
-                    self.logger,
-                    "Failed to fetch Campaign ({:?}) status from Validator", channel.id; "error" => ?err
-                ),
+
+                let _ = pages.send(CollectedPage::Done(validator.clone()));
             }
-        }
+        });
 
-        campaigns
+        join_all(futures).await;
+    }
+
+    /// Same as [`ApiClient::collect_campaigns`] but only from the passed Validator
+    async fn collect_campaigns_from(&self, validator: &ApiUrl) -> HashMap<ChannelId, Campaign> {
+        self.collect_campaigns_of(&std::iter::once(validator.clone()).collect())
+            .await
     }
 
     /// Uses the active campaigns to schedule a list of non-finalized campaigns
-    /// for update from the Validators
+    /// for update from the Validators of each Campaign's spec (the Validators of the config
+    /// are only used for collecting new Campaigns).
+    /// The Campaigns are grouped by the host of their Leader, the groups are updated concurrently
+    /// and the Campaigns of a group one after the other, reusing the connections to the host.
+    /// The `last-approved` of a group are prefetched in batches, see [`SentryApi::prefetch_last_approved`].
     ///
     /// Checks the Campaign status:
     /// If Finalized:
@@ -76,36 +151,228 @@ impl Client for ApiClient {
         &self,
         active: &ActiveCache,
     ) -> (HashMap<ChannelId, (Status, BalancesMap)>, FinalizedCache) {
+        let mut by_leader_host: HashMap<String, Vec<&Campaign>> = HashMap::new();
+        for campaign in active.values() {
+            let leader_host = Url::parse(&campaign.channel.spec.validators.leader().url)
+                .map(|url| validator_host(&url))
+                .unwrap_or_default();
+
+            by_leader_host
+                .entry(leader_host)
+                .or_default()
+                .push(campaign);
+        }
+
+        let groups = by_leader_host.into_iter().map(|(_, campaigns)| async move {
+            self.prefetch_last_approved(&campaigns).await;
+
+            let mut statuses = Vec::with_capacity(campaigns.len());
+            for campaign in &campaigns {
+                let status = get_status(
+                    &self.sentry,
+                    &campaign.channel,
+                    &*self.clock,
+                    self.heartbeat_recency,
+                )
+                .await;
+                statuses.push((campaign.channel.id, status));
+            }
+
+            let channel_ids = campaigns
+                .iter()
+                .map(|campaign| campaign.channel.id)
+                .collect();
+            self.sentry.discard_prefetched(&channel_ids).await;
+
+            statuses
+        });
+
         let mut update = HashMap::new();
         let mut finalize = HashSet::new();
-        for (id, campaign) in active.iter() {
-            match get_status(&self.sentry, &campaign.channel).await {
-                Ok((Status::Finalized(_), _balances)) => {
-                    finalize.insert(*id);
+        for (id, status) in join_all(groups).await.into_iter().flatten() {
+            match status {
+                Ok((Status::Finalized(_), _balances, _)) => {
+                    self.new_states.write().await.remove(&id);
+                    self.follower_balances.write().await.remove(&id);
+                    finalize.insert(id);
                 }
-                Ok((new_status, new_balances)) => {
-                    update.insert(*id, (new_status, new_balances));
+                Ok((new_status, new_balances, new_state)) => {
+                    self.set_new_state(id, new_state).await;
+                    self.follower_balances
+                        .write()
+                        .await
+                        .insert(id, new_balances.follower);
+                    update.insert(id, (new_status, new_balances.leader));
+                }
+                Err(err) => {
+                    let message = "Error getting Campaign status";
+                    if let Some(suppressed) =
+                        ERROR_SAMPLER.sample(message, &["channel_id", "error"])
+                    {
+                        error!(
+                            &self.logger,
+                            "{}", message;
+                            "channel_id" => %id,
+                            "error" => ?err,
+                            "suppressed" => suppressed
+                        );
+                    }
+
+                    // reported on every update while it keeps failing, so it's sampled
+                    error_reporting::report_sampled_error(
+                        message,
+                        &err.to_string(),
+                        &[("channel_id", &id.to_string())],
+                    );
                 }
-                Err(err) => error!(
-                    &self.logger,
-                    "Error getting Campaign ({:?}) status", id; "error" => ?err
-                ),
             };
         }
 
         (update, finalize)
     }
 
+    /// Fetches the Channels from the Leaders of the active Campaigns
+    async fn fetch_channels(&self, active: &ActiveCache) -> HashMap<ChannelId, Channel> {
+        let leaders = active
+            .values()
+            .filter_map(|campaign| campaign.channel.spec.validators.leader().url.parse().ok())
+            .collect::<HashSet<ApiUrl>>();
+
+        get_all_channels(&self.logger, &self.sentry, &leaders, &self.failures)
+            .await
+            .into_iter()
+            .filter(|channel| active.contains_key(&channel.id))
+            .map(|channel| (channel.id, channel))
+            .collect()
+    }
+
+    async fn validator_failures(&self) -> HashMap<String, u64> {
+        self.failures
+            .read()
+            .await
+            .iter()
+            .map(|(validator, failures)| (validator.to_string(), *failures))
+            .collect()
+    }
+
+    async fn validator_malformed_entries(&self) -> HashMap<String, u64> {
+        self.sentry.malformed_entries().await
+    }
+
+    async fn validators(&self) -> HashSet<ApiUrl> {
+        self.validators.read().await.clone()
+    }
+
+    async fn set_validators(&self, validators: HashSet<ApiUrl>) {
+        self.sentry.set_validators(&validators).await;
+        *self.validators.write().await = validators;
+    }
+
+    async fn last_new_state(&self, channel_id: &ChannelId) -> Option<LastNewState> {
+        self.new_states.read().await.get(channel_id).cloned()
+    }
+
+    async fn follower_balances(&self, channel_id: &ChannelId) -> Option<BalancesMap> {
+        self.follower_balances.read().await.get(channel_id).cloned()
+    }
+
     fn logger(&self) -> Logger {
         self.logger.clone()
     }
 }
 
+impl ApiClient {
+    /// Collects the Campaigns of the Channels of the `validators` and computes their Statuses
+    async fn collect_campaigns_of(
+        &self,
+        validators: &HashSet<ApiUrl>,
+    ) -> HashMap<ChannelId, Campaign> {
+        let all_channels =
+            get_all_channels(&self.logger, &self.sentry, validators, &self.failures).await;
+
+        self.campaigns_of(all_channels).await
+    }
+
+    /// Computes the Statuses of the Campaigns of the `channels`, the ones which fail are logged & left out
+    async fn campaigns_of(
+        &self,
+        channels: impl IntoIterator<Item = Channel>,
+    ) -> HashMap<ChannelId, Campaign> {
+        let mut campaigns = HashMap::new();
+
+        for channel in channels {
+            match get_status(&self.sentry, &channel, &*self.clock, self.heartbeat_recency).await {
+                Ok((status, balances, new_state)) => {
+                    let channel_id = channel.id;
+                    self.set_new_state(channel_id, new_state).await;
+                    self.follower_balances
+                        .write()
+                        .await
+                        .insert(channel_id, balances.follower);
+                    campaigns
+                        .entry(channel_id)
+                        .and_modify(|campaign: &mut Campaign| {
+                            campaign.status = status.clone();
+                            campaign.balances = balances.leader.clone();
+                        })
+                        .or_insert_with(|| Campaign::new(channel, status, balances.leader));
+                }
+                Err(err) => error!(
+                    self.logger,
+                    "Failed to fetch Campaign ({:?}) status from Validator", channel.id; "error" => ?err
+                ),
+            }
+        }
+
+        campaigns
+    }
+
+    /// Prefetches the `last-approved` of the Campaigns from their Leaders & Followers,
+    /// except for the expired ones & the ones in their withdraw period, which don't need them
+    async fn prefetch_last_approved(&self, campaigns: &[&Campaign]) {
+        let now = self.clock.now_utc();
+
+        let mut by_validator: HashMap<&str, (&ValidatorDesc, Vec<ChannelId>)> = HashMap::new();
+        for campaign in campaigns {
+            let channel = &campaign.channel;
+            if now > channel.valid_until || now > channel.spec.withdraw_period_start {
+                continue;
+            }
+
+            for validator in vec![
+                channel.spec.validators.leader(),
+                channel.spec.validators.follower(),
+            ] {
+                by_validator
+                    .entry(validator.url.as_str())
+                    .or_insert_with(|| (validator, vec![]))
+                    .1
+                    .push(channel.id);
+            }
+        }
+
+        for (validator, channel_ids) in by_validator.values() {
+            self.sentry
+                .prefetch_last_approved(validator, channel_ids)
+                .await;
+        }
+    }
+
+    async fn set_new_state(&self, channel_id: ChannelId, new_state: Option<LastNewState>) {
+        let mut new_states = self.new_states.write().await;
+        match new_state {
+            Some(new_state) => new_states.insert(channel_id, new_state),
+            None => new_states.remove(&channel_id),
+        };
+    }
+}
+
 /// Retrieves all channels from all Validator URLs
 async fn get_all_channels<'a>(
     logger: &Logger,
     sentry: &SentryApi,
     validators: &HashSet<ApiUrl>,
+    failures: &Cached<HashMap<ApiUrl, u64>>,
 ) -> Vec<Channel> {
     let futures = validators.iter().map(|validator| {
         sentry
@@ -113,21 +380,46 @@ async fn get_all_channels<'a>(
             .map(move |result| (validator, result))
     });
 
-    join_all(futures)
-    .await
-    .into_iter()
-    .filter_map(|(validator, result)| match result {
+    let mut all_channels = vec![];
+    for (validator, result) in join_all(futures).await {
+        match result {
             Ok(channels) => {
-                info!(logger, "Fetched {} active Channels from Validator ({})", channels.len(), validator);
+                info!(
+                    logger,
+                    "Fetched {} active Channels from Validator ({})",
+                    channels.len(),
+                    validator
+                );
 
-                Some(channels)
-            },
-            Err(err) => {
-                error!(logger, "Failed to fetch Channels from Validator ({})", validator; "error" => ?err);
-
-                None
+                all_channels.extend(channels);
             }
-        })
-    .flatten()
-    .collect()
+            Err(err) => record_failure(logger, validator, failures, &err).await,
+        }
+    }
+
+    all_channels
+}
+
+/// Logs & counts the failure of fetching the Channels from the `validator`, reporting the repeated ones
+async fn record_failure(
+    logger: &Logger,
+    validator: &ApiUrl,
+    failures: &Cached<HashMap<ApiUrl, u64>>,
+    err: &crate::sentry_api::Error,
+) {
+    error!(logger, "Failed to fetch Channels from Validator ({})", validator; "error" => ?err);
+
+    let mut failures = failures.write().await;
+    let validator_failures = failures.entry(validator.clone()).or_insert(0);
+    *validator_failures += 1;
+
+    if *validator_failures % REPEATED_VALIDATOR_FAILURES == 0 {
+        error_reporting::report_error(
+            &format!(
+                "Fetching Channels from Validator failed {} times: {}",
+                validator_failures, err
+            ),
+            &[("validator", &validator.to_string())],
+        );
+    }
 }
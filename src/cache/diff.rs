@@ -0,0 +1,40 @@
+//! What changed in the Active Campaigns with an update of the Cache, see [`Cache::subscribe_diffs`](super::Cache::subscribe_diffs)
+use primitives::ChannelId;
+use std::collections::HashSet;
+
+/// How many diffs are kept for a lagging subscriber, the older ones are skipped
+pub const DIFFS_CAPACITY: usize = 16;
+
+/// The Campaigns which changed in an update of the Cache, by what changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CampaignDiff {
+    /// The newly Active Campaigns
+    pub added: HashSet<ChannelId>,
+    /// The Finalized and the evicted Campaigns
+    pub removed: HashSet<ChannelId>,
+    pub status_changed: HashSet<ChannelId>,
+    /// Either the Leader's or the Follower's balances
+    pub balances_changed: HashSet<ChannelId>,
+    /// The Campaigns whose spec or targeting rules were amended
+    pub spec_changed: HashSet<ChannelId>,
+}
+
+impl CampaignDiff {
+    /// Nothing changed, the Cache update doesn't affect the results built from the Campaigns
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.status_changed.is_empty()
+            && self.balances_changed.is_empty()
+            && self.spec_changed.is_empty()
+    }
+
+    /// Merges the `other` diff of the same update
+    pub fn extend(&mut self, other: CampaignDiff) {
+        self.added.extend(other.added);
+        self.removed.extend(other.removed);
+        self.status_changed.extend(other.status_changed);
+        self.balances_changed.extend(other.balances_changed);
+        self.spec_changed.extend(other.spec_changed);
+    }
+}
@@ -0,0 +1,338 @@
+//! Filtering and sorting the Active Campaigns of the [`Cache`](super::Cache) by the query parameters,
+//! see [`CampaignFilter::parse`] and [`Cache::filter_campaigns`](super::Cache::filter_campaigns).
+use chrono::{DateTime, TimeZone, Utc};
+use primitives::{supermarket::Status, ValidatorId};
+use std::cmp::Ordering;
+use thiserror::Error;
+use url::form_urlencoded;
+
+use super::{remaining_budget, stats::status_label, Campaign};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Malformed query parameter `{parameter}`")]
+pub struct MalformedFilter {
+    pub parameter: &'static str,
+}
+
+/// What the Campaigns are sorted by, `?sort=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// The creation of the Campaign's spec
+    Created,
+    ValidUntil,
+    /// The deposit which hasn't been paid out yet
+    Remaining,
+}
+
+/// The direction of the sort, `?order=asc` (default) or `?order=desc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        Self::Ascending
+    }
+}
+
+/// The predicates (all of which should match) & the sort of the Campaigns.
+/// Unknown parameters are ignored.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CampaignFilter {
+    /// `?status=active,unsound` (the labels of the `supermarket_campaigns` metric, case-insensitive),
+    /// can be repeated, any status if empty
+    pub statuses: Vec<&'static str>,
+    /// `?validUntil_gte=` - RFC 3339 or seconds since the epoch
+    pub valid_until_gte: Option<DateTime<Utc>>,
+    /// `?validUntil_lte=` - RFC 3339 or seconds since the epoch
+    pub valid_until_lte: Option<DateTime<Utc>>,
+    /// `?creator=`
+    pub creator: Option<ValidatorId>,
+    /// `?depositAsset=` (case-insensitive) can be repeated, any deposit asset if empty
+    pub deposit_assets: Vec<String>,
+    /// `?idPrefix=` - the beginning of the ChannelId (case-insensitive, with or without the `0x`)
+    pub id_prefix: Option<String>,
+    /// `?sort=created|validUntil|remaining`, ordered by the ChannelId if not set
+    pub sort: Option<SortBy>,
+    pub order: SortOrder,
+}
+
+impl CampaignFilter {
+    /// For parameters which are not repeatable, the last value is used.
+    pub fn parse(query: &str) -> Result<Self, MalformedFilter> {
+        let mut parsed = Self::default();
+
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "status" => {
+                    for status in value.split(',').filter(|status| !status.is_empty()) {
+                        let label = parse_status(status).ok_or_else(|| malformed("status"))?;
+                        if !parsed.statuses.contains(&label) {
+                            parsed.statuses.push(label);
+                        }
+                    }
+                }
+                "validUntil_gte" => {
+                    parsed.valid_until_gte =
+                        Some(parse_date(&value).ok_or_else(|| malformed("validUntil_gte"))?)
+                }
+                "validUntil_lte" => {
+                    parsed.valid_until_lte =
+                        Some(parse_date(&value).ok_or_else(|| malformed("validUntil_lte"))?)
+                }
+                "creator" => {
+                    parsed.creator = Some(value.parse().map_err(|_| malformed("creator"))?)
+                }
+                "depositAsset" if !value.is_empty() => {
+                    parsed.deposit_assets.push(value.to_lowercase())
+                }
+                "idPrefix" => {
+                    let prefix = value.to_lowercase();
+                    let hex = prefix.strip_prefix("0x").unwrap_or(&prefix);
+                    if !hex.chars().all(|char| char.is_ascii_hexdigit()) {
+                        return Err(malformed("idPrefix"));
+                    }
+
+                    parsed.id_prefix = Some(hex.to_string()).filter(|hex| !hex.is_empty());
+                }
+                "sort" => {
+                    parsed.sort = Some(match value.as_ref() {
+                        "created" => SortBy::Created,
+                        "validUntil" => SortBy::ValidUntil,
+                        "remaining" => SortBy::Remaining,
+                        _ => return Err(malformed("sort")),
+                    })
+                }
+                "order" => {
+                    parsed.order = match value.as_ref() {
+                        "asc" => SortOrder::Ascending,
+                        "desc" => SortOrder::Descending,
+                        _ => return Err(malformed("order")),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Whether the Campaign matches all of the predicates
+    pub fn matches(&self, campaign: &Campaign) -> bool {
+        let channel = &campaign.channel;
+
+        (self.statuses.is_empty() || self.statuses.contains(&status_label(&campaign.status)))
+            && self
+                .valid_until_gte
+                .map_or(true, |gte| channel.valid_until >= gte)
+            && self
+                .valid_until_lte
+                .map_or(true, |lte| channel.valid_until <= lte)
+            && self
+                .creator
+                .map_or(true, |creator| channel.creator == creator)
+            && (self.deposit_assets.is_empty()
+                || self
+                    .deposit_assets
+                    .contains(&channel.deposit_asset.to_lowercase()))
+            && self.id_prefix.as_ref().map_or(true, |prefix| {
+                let id = channel.id.to_string().to_lowercase();
+
+                id.strip_prefix("0x").unwrap_or(&id).starts_with(prefix)
+            })
+    }
+
+    /// The matching Campaigns, sorted without cloning them
+    pub fn apply<'a>(&self, campaigns: impl Iterator<Item = &'a Campaign>) -> Vec<&'a Campaign> {
+        let mut matching = campaigns
+            .filter(|campaign| self.matches(campaign))
+            .collect::<Vec<_>>();
+
+        // the ChannelId breaks the ties, so the order is stable across the requests
+        matching.sort_by(|a, b| {
+            let ordering = match self.sort {
+                Some(SortBy::Created) => a.channel.spec.created.cmp(&b.channel.spec.created),
+                Some(SortBy::ValidUntil) => a.channel.valid_until.cmp(&b.channel.valid_until),
+                Some(SortBy::Remaining) => remaining_budget(a).cmp(&remaining_budget(b)),
+                None => Ordering::Equal,
+            }
+            .then_with(|| a.channel.id.to_string().cmp(&b.channel.id.to_string()));
+
+            match self.order {
+                SortOrder::Ascending => ordering,
+                SortOrder::Descending => ordering.reverse(),
+            }
+        });
+
+        matching
+    }
+}
+
+fn malformed(parameter: &'static str) -> MalformedFilter {
+    MalformedFilter { parameter }
+}
+
+fn parse_status(status: &str) -> Option<&'static str> {
+    let status = status.to_lowercase();
+
+    super::stats::STATUSES
+        .iter()
+        .copied()
+        .find(|label| *label == status)
+}
+
+fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    match value.parse::<i64>() {
+        Ok(seconds) => Utc.timestamp_opt(seconds, 0).single(),
+        Err(_) => DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|date| date.with_timezone(&Utc)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Duration;
+    use primitives::{
+        util::tests::prep_db::{DUMMY_CHANNEL, IDS},
+        BigNum, ChannelId,
+    };
+
+    const DAI: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+
+    fn campaign(id: u8, status: Status, valid_for_days: i64, paid: u64) -> Campaign {
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.id = ChannelId::from([id; 32]);
+        channel.deposit_asset = DAI.to_string();
+        channel.deposit_amount = 1_000.into();
+        channel.spec.created = Utc.ymd(2021, 1, 1).and_hms(0, 0, 0) + Duration::days(id.into());
+        channel.valid_until = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0) + Duration::days(valid_for_days);
+        let balances = vec![(IDS["publisher"], BigNum::from(paid))]
+            .into_iter()
+            .collect();
+
+        Campaign::new(channel, status, balances)
+    }
+
+    fn ids(campaigns: &[&Campaign]) -> Vec<ChannelId> {
+        campaigns
+            .iter()
+            .map(|campaign| campaign.channel.id)
+            .collect()
+    }
+
+    #[test]
+    fn parses_the_filter() {
+        let filter = CampaignFilter::parse(&format!(
+            "status=Active,Unsound&status=active&validUntil_gte=2021-03-01T00:00:00Z&validUntil_lte=1617235200&creator={}&depositAsset={}&idPrefix=0xAB&sort=remaining&order=desc&unknown=1",
+            IDS["creator"], DAI
+        ))
+        .expect("Should parse");
+
+        let expected = CampaignFilter {
+            statuses: vec!["active", "unsound"],
+            valid_until_gte: Some(Utc.ymd(2021, 3, 1).and_hms(0, 0, 0)),
+            valid_until_lte: Some(Utc.ymd(2021, 4, 1).and_hms(0, 0, 0)),
+            creator: Some(IDS["creator"]),
+            deposit_assets: vec![DAI.to_lowercase()],
+            id_prefix: Some("ab".to_string()),
+            sort: Some(SortBy::Remaining),
+            order: SortOrder::Descending,
+        };
+        assert_eq!(expected, filter);
+        assert_eq!(Ok(CampaignFilter::default()), CampaignFilter::parse(""));
+    }
+
+    #[test]
+    fn malformed_values_name_the_parameter() {
+        let table = [
+            ("status=Active,Unknown", "status"),
+            ("validUntil_gte=yesterday", "validUntil_gte"),
+            ("validUntil_lte=2021-13-01", "validUntil_lte"),
+            ("creator=0x1", "creator"),
+            ("idPrefix=0xZZ", "idPrefix"),
+            ("sort=deposit", "sort"),
+            ("order=up", "order"),
+        ];
+
+        for (query, parameter) in table.iter() {
+            assert_eq!(
+                Err(MalformedFilter {
+                    parameter: *parameter
+                }),
+                CampaignFilter::parse(query),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn all_the_predicates_should_match() {
+        let mut by_other = campaign(3, Status::Active, 10, 0);
+        by_other.channel.creator = IDS["publisher2"];
+        by_other.channel.deposit_asset = "0xOTHER".to_string();
+        let campaigns = vec![
+            campaign(1, Status::Active, 0, 0),
+            campaign(2, Status::Waiting, 20, 0),
+            by_other,
+        ];
+        let filtered = |query: &str| {
+            let filter = CampaignFilter::parse(query).expect("Should parse");
+
+            ids(&filter.apply(campaigns.iter()))
+        };
+        let id = |id: u8| ChannelId::from([id; 32]);
+
+        assert_eq!(vec![id(1), id(2), id(3)], filtered(""));
+        assert_eq!(vec![id(1), id(3)], filtered("status=ACTIVE"));
+        assert_eq!(
+            vec![id(2), id(3)],
+            filtered("validUntil_gte=2021-03-05T00:00:00Z")
+        );
+        assert_eq!(
+            vec![id(3)],
+            filtered("validUntil_gte=2021-03-05T00:00:00Z&validUntil_lte=2021-03-15T00:00:00Z")
+        );
+        assert_eq!(
+            vec![id(1), id(2)],
+            filtered(&format!("depositAsset={}", DAI.to_uppercase()))
+        );
+        assert_eq!(
+            vec![id(3)],
+            filtered(&format!("creator={}&status=active", IDS["publisher2"]))
+        );
+        assert_eq!(vec![id(2)], filtered("idPrefix=0202"));
+        assert_eq!(vec![id(2)], filtered("idPrefix=0x0202&status=waiting"));
+        assert!(filtered("idPrefix=0202&status=active").is_empty());
+    }
+
+    #[test]
+    fn the_campaigns_are_sorted_by_the_channel_id_by_default() {
+        let campaigns = vec![
+            campaign(2, Status::Active, 0, 900),
+            campaign(3, Status::Active, 10, 100),
+            campaign(1, Status::Active, 20, 500),
+        ];
+        let sorted = |query: &str| {
+            let filter = CampaignFilter::parse(query).expect("Should parse");
+
+            ids(&filter.apply(campaigns.iter()))
+        };
+        let ids = |ids: [u8; 3]| {
+            ids.iter()
+                .map(|id| ChannelId::from([*id; 32]))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids([1, 2, 3]), sorted(""));
+        assert_eq!(ids([3, 2, 1]), sorted("order=desc"));
+        assert_eq!(ids([1, 2, 3]), sorted("sort=created"));
+        assert_eq!(ids([2, 3, 1]), sorted("sort=validUntil"));
+        // the remaining budgets of 1, 2 & 3 are 500, 100 & 900
+        assert_eq!(ids([3, 1, 2]), sorted("sort=remaining&order=desc"));
+    }
+}
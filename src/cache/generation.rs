@@ -0,0 +1,26 @@
+//! An immutable snapshot of the Active Campaigns (with everything the units-for-slot pipeline reads about them),
+//! published every time they change, see [`Cache::current`](super::Cache::current).
+//!
+//! A units-for-slot request captures the current one at its start and evaluates every step against it,
+//! so the Campaigns, their refreshes & Follower's balances never come from different updates.
+//!
+//! The Campaigns & the Follower's balances are shared with the Cache (and the other generations) behind an `Arc`,
+//! so publishing a generation copies only the maps and it is counted in the [`estimated_size`](super::estimated_size).
+use primitives::ChannelId;
+use std::{collections::HashSet, mem::size_of, sync::Arc};
+
+use super::{ActiveCache, Campaign, FollowerBalances, Refreshed, RefreshedCache};
+
+/// The memory taken by the entries of a Campaign in the maps of a generation, besides the shared Campaign
+pub const ENTRY_SIZE: usize =
+    size_of::<(ChannelId, Arc<Campaign>)>() + size_of::<(ChannelId, Refreshed)>();
+
+#[derive(Debug, Clone, Default)]
+pub struct Generation {
+    /// Increased with every change of the Active Campaigns, see [`Cache::generation`](super::Cache::generation)
+    pub number: u64,
+    pub active: ActiveCache,
+    pub refreshed: RefreshedCache,
+    pub follower_balances: Arc<FollowerBalances>,
+    pub restricted: HashSet<ChannelId>,
+}
@@ -0,0 +1,141 @@
+//! The Campaigns collected page by page when the Cache is initialized,
+//! see [`Cache::fetch_initial_campaigns`](super::Cache::fetch_initial_campaigns)
+use super::Campaign;
+use primitives::{util::ApiUrl, ChannelId};
+use std::collections::{HashMap, HashSet};
+
+/// What is sent by [`Client::collect_campaign_pages`](super::Client::collect_campaign_pages) for each Validator
+#[derive(Debug, Clone)]
+pub enum CollectedPage {
+    /// The Campaigns of a page of the Validator's Channels, with their statuses
+    Campaigns(ApiUrl, HashMap<ChannelId, Campaign>),
+    /// The Validator has no more pages, or fetching the rest of them failed
+    Done(ApiUrl),
+}
+
+/// How far the initialization of the Cache is, by the Validators the Campaigns are collected from
+#[derive(Debug, Clone, Default)]
+pub struct InitProgress {
+    validators: HashSet<ApiUrl>,
+    /// The Validators which have returned at least one page
+    responded: HashSet<ApiUrl>,
+    done: HashSet<ApiUrl>,
+    completed: bool,
+}
+
+impl InitProgress {
+    pub fn new(validators: HashSet<ApiUrl>) -> Self {
+        Self {
+            validators,
+            ..Default::default()
+        }
+    }
+
+    /// The Cache wasn't initialized page by page, e.g. it was loaded from a snapshot
+    pub fn completed() -> Self {
+        Self {
+            completed: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether the `page` is the first one of its Validator or the Validator is done,
+    /// the pages of Validators which aren't collected from are ignored
+    pub fn record(&mut self, page: &CollectedPage) -> bool {
+        match page {
+            CollectedPage::Campaigns(validator, _) if self.validators.contains(validator) => {
+                self.responded.insert(validator.clone())
+            }
+            CollectedPage::Done(validator) if self.validators.contains(validator) => {
+                self.done.insert(validator.clone())
+            }
+            _ => false,
+        }
+    }
+
+    pub fn complete(&mut self) {
+        self.completed = true;
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.completed
+    }
+
+    /// The share of the Validators which have returned at least one page, from `0` to `1`
+    pub fn responded(&self) -> f64 {
+        if self.validators.is_empty() {
+            return 0.0;
+        }
+
+        self.responded.len() as f64 / self.validators.len() as f64
+    }
+
+    /// The percentage of the Validators which are done
+    pub fn percent_done(&self) -> u64 {
+        if self.completed || self.validators.is_empty() {
+            return 100;
+        }
+
+        (self.done.len() * 100 / self.validators.len()) as u64
+    }
+
+    /// Whether the Campaigns can be served, once at least the `ready_fraction` of the Validators have returned a page
+    /// (see [`Initialization::ready_fraction`](crate::config::Initialization::ready_fraction)) or it's completed
+    pub fn is_ready(&self, ready_fraction: f64) -> bool {
+        self.completed || (!self.validators.is_empty() && self.responded() >= ready_fraction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validator(port: u16) -> ApiUrl {
+        format!("http://localhost:{}/", port)
+            .parse()
+            .expect("Valid Validator URL")
+    }
+
+    #[test]
+    fn it_is_ready_once_enough_validators_have_responded() {
+        let validators = vec![
+            validator(8005),
+            validator(8006),
+            validator(8007),
+            validator(8008),
+        ];
+        let mut progress = InitProgress::new(validators.iter().cloned().collect());
+        assert!(!progress.is_ready(0.5));
+
+        let page = |validator: &ApiUrl| CollectedPage::Campaigns(validator.clone(), HashMap::new());
+        assert!(progress.record(&page(&validators[0])));
+        // only the first page of a Validator counts
+        assert!(!progress.record(&page(&validators[0])));
+        assert!(!progress.record(&page(&validator(9000))));
+        assert!(!progress.is_ready(0.5));
+
+        assert!(progress.record(&CollectedPage::Done(validators[0].clone())));
+        assert_eq!(25, progress.percent_done());
+        // a failed Validator is done, but without a page
+        assert!(progress.record(&CollectedPage::Done(validators[1].clone())));
+        assert!(!progress.is_ready(0.5));
+
+        assert!(progress.record(&page(&validators[2])));
+        assert!(progress.is_ready(0.5));
+        assert!(!progress.is_ready(1.0));
+
+        progress.complete();
+        assert!(progress.is_ready(1.0));
+        assert_eq!(100, progress.percent_done());
+    }
+
+    #[test]
+    fn it_is_ready_without_validators_only_once_completed() {
+        let mut progress = InitProgress::new(HashSet::new());
+        assert!(!progress.is_ready(0.0));
+
+        progress.complete();
+        assert!(progress.is_ready(0.0));
+        assert!(InitProgress::completed().is_ready(1.0));
+    }
+}
@@ -0,0 +1,138 @@
+//! The lock of the values of the [`Cache`](super::Cache), see [`Cached`](super::Cached).
+//!
+//! With the `cache-metrics` feature the time spent waiting for every read & write lock is observed
+//! in the `supermarket_cache_lock_wait_seconds` histogram by the call site (`file:line`) and the mode,
+//! and the waits longer than the [`Config::cache_lock_wait_warning`](crate::Config::cache_lock_wait_warning) are logged.
+//! Without it [`Lock`] is the `tokio` `RwLock` itself, so the call sites are the same either way.
+
+#[cfg(not(feature = "cache-metrics"))]
+pub use tokio::sync::RwLock as Lock;
+
+#[cfg(feature = "cache-metrics")]
+pub use instrumented::{warn_on_waits_over, Lock};
+
+#[cfg(feature = "cache-metrics")]
+mod instrumented {
+    use crate::metrics::CACHE_LOCK_WAIT;
+    use slog::{warn, Logger};
+    use std::{future::Future, panic::Location, sync::RwLock as StdRwLock, time::Duration};
+    use tokio::{
+        sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+        time::Instant,
+    };
+
+    lazy_static::lazy_static! {
+        /// The logger & the threshold of the waits which are logged, see [`warn_on_waits_over`]
+        static ref WAIT_WARNING: StdRwLock<Option<(Logger, Duration)>> = StdRwLock::new(None);
+    }
+
+    /// Logs the lock acquisitions which wait longer than the `threshold`, with their call site
+    pub fn warn_on_waits_over(logger: Logger, threshold: Duration) {
+        *WAIT_WARNING.write().expect("Should not be poisoned") = Some((logger, threshold));
+    }
+
+    /// A `tokio` `RwLock` observing how long its `read()`s & `write()`s wait, by their call site
+    #[derive(Debug, Default)]
+    pub struct Lock<T> {
+        inner: RwLock<T>,
+    }
+
+    impl<T> Lock<T> {
+        pub fn new(value: T) -> Self {
+            Self {
+                inner: RwLock::new(value),
+            }
+        }
+
+        #[track_caller]
+        pub fn read(&self) -> impl Future<Output = RwLockReadGuard<'_, T>> + '_ {
+            let site = Location::caller();
+
+            async move {
+                let started = Instant::now();
+                let guard = self.inner.read().await;
+                observe(site, "read", started.elapsed());
+
+                guard
+            }
+        }
+
+        #[track_caller]
+        pub fn write(&self) -> impl Future<Output = RwLockWriteGuard<'_, T>> + '_ {
+            let site = Location::caller();
+
+            async move {
+                let started = Instant::now();
+                let guard = self.inner.write().await;
+                observe(site, "write", started.elapsed());
+
+                guard
+            }
+        }
+    }
+
+    fn observe(site: &Location<'_>, mode: &'static str, waited: Duration) {
+        let site = format!("{}:{}", site.file(), site.line());
+        CACHE_LOCK_WAIT
+            .with_label_values(&[&site, mode])
+            .observe(waited.as_secs_f64());
+
+        let wait_warning = WAIT_WARNING.read().expect("Should not be poisoned");
+        if let Some((logger, threshold)) = &*wait_warning {
+            if waited > *threshold {
+                warn!(
+                    logger,
+                    "Waited long for a Cache lock";
+                    "site" => &site,
+                    "mode" => mode,
+                    "waited_ms" => waited.as_millis() as u64,
+                );
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::util::test::MemoryDrain;
+        use std::sync::Arc;
+
+        #[tokio::test]
+        async fn waits_under_contention_are_recorded_by_the_call_site() {
+            let drain = MemoryDrain::default();
+            warn_on_waits_over(drain.logger(), Duration::from_millis(10));
+
+            let lock = Arc::new(Lock::new(0));
+            let guard = lock.write().await;
+
+            let waiting_lock = lock.clone();
+            let site = format!("{}:{}", file!(), line!() + 2);
+            let waiting = tokio::spawn(async move {
+                *waiting_lock.write().await += 1;
+            });
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            drop(guard);
+            waiting.await.expect("Should not panic");
+
+            let waits = CACHE_LOCK_WAIT.with_label_values(&[&site, "write"]);
+            assert_eq!(1, waits.get_sample_count());
+            assert!(waits.get_sample_sum() >= 0.05);
+
+            // the uncontended read barely waits
+            let read_site = format!("{}:{}", file!(), line!() + 1);
+            assert_eq!(1, *lock.read().await);
+            let reads = CACHE_LOCK_WAIT.with_label_values(&[&read_site, "read"]);
+            assert_eq!(1, reads.get_sample_count());
+            assert!(reads.get_sample_sum() < 0.05);
+
+            let warned = drain
+                .records()
+                .into_iter()
+                .filter(|(_, values)| values.get("site") == Some(&site))
+                .collect::<Vec<_>>();
+            assert_eq!(1, warned.len());
+            assert_eq!("Waited long for a Cache lock", warned[0].0);
+            assert_eq!(Some(&"write".to_string()), warned[0].1.get("mode"));
+        }
+    }
+}
@@ -1,18 +1,21 @@
 use crate::{
-    cache::{ActiveCache, Client, FinalizedCache},
+    cache::{init::CollectedPage, ActiveCache, Client, FinalizedCache, FollowerBalances},
+    status::LastNewState,
     util::test::discard_logger,
 };
 use async_trait::async_trait;
 use primitives::{
     supermarket::{Campaign, Status},
-    BalancesMap, ChannelId,
+    util::ApiUrl,
+    BalancesMap, Channel, ChannelId,
 };
 use slog::Logger;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
 
-use super::Cached;
+use super::{lock::Lock, Cached};
 
 // Consists of the index of the call and the results
 type MockedCall<T> = (usize, Vec<T>);
@@ -22,6 +25,19 @@ pub struct MockClient {
     collect_campaigns: Cached<MockedCall<HashMap<ChannelId, Campaign>>>,
     campaign_updates:
         Cached<MockedCall<(HashMap<ChannelId, (Status, BalancesMap)>, FinalizedCache)>>,
+    /// See [`MockClient::with_channel_updates`]
+    channel_updates: Cached<MockedCall<HashMap<ChannelId, Channel>>>,
+    /// The Campaigns collected from a single Validator, see [`MockClient::with_validator_campaigns`]
+    validator_campaigns: HashMap<ApiUrl, HashMap<ChannelId, Campaign>>,
+    validators: Cached<HashSet<ApiUrl>>,
+    /// See [`MockClient::with_campaign_pages`]
+    campaign_pages: Option<(Vec<CollectedPage>, Arc<Semaphore>)>,
+    /// See [`MockClient::with_new_states`]
+    new_states: HashMap<ChannelId, LastNewState>,
+    /// See [`MockClient::with_follower_balances`]
+    follower_balances: FollowerBalances,
+    /// See [`MockClient::with_update_delay`]
+    update_delay: Option<Duration>,
     logger: Logger,
 }
 
@@ -33,11 +49,78 @@ impl MockClient {
         logger: impl Into<Option<Logger>>,
     ) -> Self {
         Self {
-            collect_campaigns: Arc::new(RwLock::new((0, collect_calls))),
-            campaign_updates: Arc::new(RwLock::new((0, update_calls))),
+            collect_campaigns: Arc::new(Lock::new((0, collect_calls))),
+            campaign_updates: Arc::new(Lock::new((0, update_calls))),
+            channel_updates: Default::default(),
+            validator_campaigns: HashMap::new(),
+            validators: Default::default(),
+            campaign_pages: None,
+            new_states: HashMap::new(),
+            follower_balances: HashMap::new(),
+            update_delay: None,
             logger: logger.into().unwrap_or_else(discard_logger),
         }
     }
+
+    /// Sets the initial Validators of the client
+    pub fn with_validators(self, validators: HashSet<ApiUrl>) -> Self {
+        Self {
+            validators: Arc::new(Lock::new(validators)),
+            ..self
+        }
+    }
+
+    /// The `campaigns` will be collected when collecting only from the `validator`,
+    /// for any other Validator no Campaigns are collected.
+    pub fn with_validator_campaigns(
+        mut self,
+        validator: ApiUrl,
+        campaigns: HashMap<ChannelId, Campaign>,
+    ) -> Self {
+        self.validator_campaigns.insert(validator, campaigns);
+
+        self
+    }
+
+    /// The `pages` are sent by [`Client::collect_campaign_pages`] one by one,
+    /// each of them once a permit of the `released` semaphore is acquired, like slow Validators.
+    /// If not set, the next mocked `collect_campaigns` call is sent as a single page.
+    pub fn with_campaign_pages(self, pages: Vec<CollectedPage>, released: Arc<Semaphore>) -> Self {
+        Self {
+            campaign_pages: Some((pages, released)),
+            ..self
+        }
+    }
+
+    /// The Channels fetched on each call of [`Client::fetch_channels`],
+    /// once the calls are exhausted (or if not set) no Channels are fetched.
+    pub fn with_channel_updates(self, channel_calls: Vec<HashMap<ChannelId, Channel>>) -> Self {
+        Self {
+            channel_updates: Arc::new(Lock::new((0, channel_calls))),
+            ..self
+        }
+    }
+
+    /// Sets the Leader's NewStates of the Campaigns' statuses
+    pub fn with_new_states(self, new_states: HashMap<ChannelId, LastNewState>) -> Self {
+        Self { new_states, ..self }
+    }
+
+    /// Sets the balances approved by the Followers of the Campaigns
+    pub fn with_follower_balances(self, follower_balances: FollowerBalances) -> Self {
+        Self {
+            follower_balances,
+            ..self
+        }
+    }
+
+    /// Every [`Client::fetch_campaign_updates`] takes this long, like a slow Validator
+    pub fn with_update_delay(self, delay: Duration) -> Self {
+        Self {
+            update_delay: Some(delay),
+            ..self
+        }
+    }
 }
 
 #[async_trait]
@@ -64,10 +147,41 @@ impl Client for MockClient {
         call_data
     }
 
+    async fn collect_campaign_pages(&self, pages: mpsc::UnboundedSender<CollectedPage>) {
+        let (mocked_pages, released) = match &self.campaign_pages {
+            Some(mocked) => mocked,
+            None => {
+                let validator: ApiUrl = "http://localhost:8005/".parse().expect("Valid URL");
+                let campaigns = self.collect_campaigns().await;
+
+                let _ = pages.send(CollectedPage::Campaigns(validator.clone(), campaigns));
+                let _ = pages.send(CollectedPage::Done(validator));
+                return;
+            }
+        };
+
+        for page in mocked_pages {
+            released.acquire().await.forget();
+
+            let _ = pages.send(page.clone());
+        }
+    }
+
+    async fn collect_campaigns_from(&self, validator: &ApiUrl) -> HashMap<ChannelId, Campaign> {
+        self.validator_campaigns
+            .get(validator)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     async fn fetch_campaign_updates(
         &self,
         active: &ActiveCache,
     ) -> (HashMap<ChannelId, (Status, BalancesMap)>, FinalizedCache) {
+        if let Some(delay) = self.update_delay {
+            tokio::time::delay_for(delay).await;
+        }
+
         let calls = self.campaign_updates.write().await;
 
         let (ref mut index, data) = (calls.0, &calls.1);
@@ -86,4 +200,38 @@ impl Client for MockClient {
 
         call_data
     }
+
+    async fn fetch_channels(&self, _active: &ActiveCache) -> HashMap<ChannelId, Channel> {
+        let mut calls = self.channel_updates.write().await;
+        let (index, data) = &mut *calls;
+
+        let call_data = data.get(*index).cloned().unwrap_or_default();
+        *index += 1;
+
+        call_data
+    }
+
+    async fn validator_failures(&self) -> HashMap<String, u64> {
+        HashMap::new()
+    }
+
+    async fn validator_malformed_entries(&self) -> HashMap<String, u64> {
+        HashMap::new()
+    }
+
+    async fn validators(&self) -> HashSet<ApiUrl> {
+        self.validators.read().await.clone()
+    }
+
+    async fn set_validators(&self, validators: HashSet<ApiUrl>) {
+        *self.validators.write().await = validators;
+    }
+
+    async fn last_new_state(&self, channel_id: &ChannelId) -> Option<LastNewState> {
+        self.new_states.get(channel_id).cloned()
+    }
+
+    async fn follower_balances(&self, channel_id: &ChannelId) -> Option<BalancesMap> {
+        self.follower_balances.get(channel_id).cloned()
+    }
 }
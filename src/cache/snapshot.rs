@@ -0,0 +1,198 @@
+//! Serialized dumps of the [`Cache`] for warming up a new replica from a running one,
+//! see [`CacheBuilder::initialize_warm_from`](super::CacheBuilder::initialize_warm_from).
+use super::{Cache, Campaign, Client, FinalizedCache};
+use chrono::{DateTime, Utc};
+use primitives::{util::ApiUrl, ChannelId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
+use thiserror::Error;
+
+/// Increased on every change of the [`Snapshot`] format,
+/// snapshots of other versions are rejected.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The admin route of the running replica serving the [`Snapshot`]
+pub const SNAPSHOT_PATH: &str = "internal/cache-snapshot";
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Fetching the snapshot failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("The snapshot version `{found}` is not the supported `{expected}`")]
+    Version { expected: u32, found: u32 },
+    #[error("The snapshot is corrupt: {0}")]
+    Corrupt(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub version: u32,
+    pub taken_at: DateTime<Utc>,
+    pub active: Vec<SnapshotCampaign>,
+    pub finalized: FinalizedCache,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotCampaign {
+    pub campaign: Campaign,
+    /// When the Campaign was last refreshed in the Cache of the replica, see [`Refreshed`](super::Refreshed)
+    pub refreshed_at: Option<DateTime<Utc>>,
+}
+
+/// Only the version, for rejecting the snapshots of other versions before parsing them
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+impl Snapshot {
+    pub async fn take<C: Client>(cache: &Cache<C>) -> Self {
+        let active = cache.active.read().await;
+        let refreshed = cache.refreshed.read().await;
+
+        Self {
+            version: SNAPSHOT_VERSION,
+            taken_at: cache.clock().now_utc(),
+            active: active
+                .iter()
+                .map(|(channel_id, campaign)| SnapshotCampaign {
+                    campaign: Campaign::clone(campaign),
+                    refreshed_at: refreshed.get(channel_id).map(|refreshed| refreshed.at),
+                })
+                .collect(),
+            finalized: cache.finalized.read().await.clone(),
+        }
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let Versioned { version } = serde_json::from_slice(bytes)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::Version {
+                expected: SNAPSHOT_VERSION,
+                found: version,
+            });
+        }
+
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    /// Fetches the snapshot from the [`SNAPSHOT_PATH`] of the `replica`
+    pub async fn fetch(
+        replica: &ApiUrl,
+        admin_token: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Self, SnapshotError> {
+        let url = replica
+            .join(SNAPSHOT_PATH)
+            .expect("The snapshot path should be a valid URL path");
+
+        let request = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()?
+            .get(url.to_url());
+        let request = match admin_token {
+            Some(admin_token) => request.bearer_auth(admin_token),
+            None => request,
+        };
+        let bytes = request.send().await?.error_for_status()?.bytes().await?;
+
+        Self::from_slice(&bytes)
+    }
+
+    /// Merges the Campaigns with the same ChannelId, e.g. listed by Validators with a differently cased id,
+    /// keeping the most recently refreshed one. Returns how many were dropped.
+    pub fn merge_duplicates(&mut self) -> usize {
+        let listed = self.active.len();
+        let mut by_id: HashMap<ChannelId, SnapshotCampaign> = HashMap::with_capacity(listed);
+
+        for snapshot in self.active.drain(..) {
+            match by_id.entry(snapshot.campaign.channel.id) {
+                Entry::Occupied(mut kept) => {
+                    // a Campaign which was never refreshed is the oldest
+                    if snapshot.refreshed_at > kept.get().refreshed_at {
+                        kept.insert(snapshot);
+                    }
+                }
+                Entry::Vacant(vacant) => {
+                    vacant.insert(snapshot);
+                }
+            }
+        }
+        self.active = by_id.into_iter().map(|(_, snapshot)| snapshot).collect();
+
+        listed - self.active.len()
+    }
+
+    /// Splits the Campaigns into the Active ones and when each of them was refreshed
+    pub(super) fn into_parts(
+        self,
+    ) -> (
+        HashMap<ChannelId, Campaign>,
+        Vec<(ChannelId, DateTime<Utc>)>,
+        FinalizedCache,
+    ) {
+        let refreshed = self
+            .active
+            .iter()
+            .filter_map(|snapshot| {
+                snapshot
+                    .refreshed_at
+                    .map(|refreshed_at| (snapshot.campaign.channel.id, refreshed_at))
+            })
+            .collect();
+        let active = self
+            .active
+            .into_iter()
+            .map(|snapshot| (snapshot.campaign.channel.id, snapshot.campaign))
+            .collect();
+
+        (active, refreshed, self.finalized)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_other_versions_and_corrupt_snapshots() {
+        let snapshot = serde_json::json!({
+            "version": SNAPSHOT_VERSION + 1,
+            "takenAt": Utc::now(),
+            "active": [],
+            "finalized": [],
+        });
+        match Snapshot::from_slice(snapshot.to_string().as_bytes()) {
+            Err(SnapshotError::Version { found, .. }) => assert_eq!(SNAPSHOT_VERSION + 1, found),
+            result => panic!("Expected a Version error, got: {:?}", result),
+        }
+
+        let snapshot = serde_json::json!({
+            "version": SNAPSHOT_VERSION,
+            "takenAt": Utc::now(),
+            "active": [{ "campaign": "not a campaign" }],
+            "finalized": [],
+        });
+        assert!(matches!(
+            Snapshot::from_slice(snapshot.to_string().as_bytes()),
+            Err(SnapshotError::Corrupt(_))
+        ));
+        assert!(matches!(
+            Snapshot::from_slice(b"{ truncated"),
+            Err(SnapshotError::Corrupt(_))
+        ));
+
+        let snapshot = serde_json::json!({
+            "version": SNAPSHOT_VERSION,
+            "takenAt": Utc::now(),
+            "active": [],
+            "finalized": [],
+        });
+        assert!(Snapshot::from_slice(snapshot.to_string().as_bytes()).is_ok());
+    }
+}
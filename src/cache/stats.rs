@@ -0,0 +1,386 @@
+//! The aggregates of the Active Campaigns, computed every time they change, see [`Cache::stats`](super::Cache::stats)
+use chrono::{DateTime, Utc};
+use primitives::{supermarket::Status, util::ApiUrl, BigNum};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+use super::{remaining_budget, ActiveCache, Campaign};
+use crate::anomaly::BlockedStats;
+use crate::metrics::{
+    ASSET_CAMPAIGNS, ASSET_DEPOSITED, ASSET_DISTRIBUTED, ASSET_REMAINING, CAMPAIGNS_BY_STATUS,
+};
+
+/// The `asset` label of the deposit assets which are not in the `stats.assets` of the config
+pub const OTHER_ASSET: &str = "other";
+
+/// The labels of the [`Status`]es, every one of them is always set in the metrics
+pub const STATUSES: [&str; 6] = [
+    "active",
+    "pending",
+    "initializing",
+    "waiting",
+    "unsound",
+    "finalized",
+];
+
+/// The totals of the Active Campaigns with the same deposit asset
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssetStats {
+    pub campaigns: usize,
+    /// The sum of the deposits
+    pub deposited: BigNum,
+    /// The sum of the balances, i.e. what has already been paid out
+    pub distributed: BigNum,
+    /// The sum of the deposits which haven't been paid out yet
+    pub remaining: BigNum,
+}
+
+impl AssetStats {
+    fn of<'a>(campaigns: impl Iterator<Item = &'a Campaign> + Clone) -> Self {
+        let remaining = campaigns.clone().map(remaining_budget).collect::<Vec<_>>();
+
+        Self {
+            campaigns: campaigns.clone().count(),
+            deposited: campaigns
+                .clone()
+                .map(|campaign| &campaign.channel.deposit_amount)
+                .sum(),
+            distributed: campaigns
+                .flat_map(|campaign| campaign.balances.values())
+                .sum(),
+            remaining: remaining.iter().sum(),
+        }
+    }
+}
+
+/// The changes of the Validators made at runtime (by the `/validators` admin routes) compared to the configured ones,
+/// see [`Cache::replace_validators`](super::Cache::replace_validators)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidatorChanges {
+    /// When the Validators were last changed, `None` until they are
+    pub changed_at: Option<DateTime<Utc>>,
+    /// The Validators which aren't configured, sorted
+    pub added: Vec<ApiUrl>,
+    /// The configured Validators which were removed, sorted
+    pub removed: Vec<ApiUrl>,
+}
+
+impl ValidatorChanges {
+    /// Adding a removed configured Validator back (or removing an added one) undoes the change
+    pub fn record(
+        &mut self,
+        added: impl IntoIterator<Item = ApiUrl>,
+        removed: impl IntoIterator<Item = ApiUrl>,
+        changed_at: DateTime<Utc>,
+    ) {
+        for validator in added {
+            toggle(&mut self.removed, &mut self.added, validator);
+        }
+        for validator in removed {
+            toggle(&mut self.added, &mut self.removed, validator);
+        }
+
+        self.changed_at = Some(changed_at);
+    }
+}
+
+/// Drops the `validator` from the `undone` changes or adds it to the `changes` if it wasn't there
+fn toggle(undone: &mut Vec<ApiUrl>, changes: &mut Vec<ApiUrl>, validator: ApiUrl) {
+    if let Some(index) = undone.iter().position(|change| change == &validator) {
+        undone.remove(index);
+    } else if !changes.contains(&validator) {
+        changes.push(validator);
+        changes.sort_by_key(ToString::to_string);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    /// When the Active Campaigns last changed, `None` until the first Campaigns are added
+    pub computed_at: Option<DateTime<Utc>>,
+    pub active: usize,
+    pub finalized: usize,
+    /// The discovered Campaigns which were skipped because of an invalid spec,
+    /// see [`validation`](super::validation)
+    pub skipped_invalid: usize,
+    /// The discovered Campaigns with a Validator which isn't on the allowlist, either skipped or Active but not served
+    /// by the [`ValidatorAllowlistPolicy`](crate::config::ValidatorAllowlistPolicy)
+    pub restricted: usize,
+    /// The currently blocked client IPs & AdSlots, see [`anomaly`](crate::anomaly).
+    /// Unlike the rest of the stats it's not computed with the Active Campaigns but on every request.
+    pub blocked: BlockedStats,
+    /// The Validators changed at runtime, like the `blocked` ones they're not computed with the Active Campaigns
+    pub validators: ValidatorChanges,
+    /// The number of Active Campaigns per status
+    pub by_status: BTreeMap<&'static str, usize>,
+    /// The totals of the Active Campaigns per deposit asset
+    pub by_asset: BTreeMap<String, AssetStats>,
+}
+
+impl CacheStats {
+    pub fn compute(active: &ActiveCache, finalized: usize, computed_at: DateTime<Utc>) -> Self {
+        let mut by_status = BTreeMap::new();
+        let mut assets = BTreeMap::new();
+        for campaign in active.values() {
+            *by_status.entry(status_label(&campaign.status)).or_insert(0) += 1;
+            assets
+                .entry(campaign.channel.deposit_asset.clone())
+                .or_insert_with(Vec::new)
+                .push(&**campaign);
+        }
+
+        Self {
+            computed_at: Some(computed_at),
+            active: active.len(),
+            finalized,
+            skipped_invalid: 0,
+            restricted: 0,
+            blocked: BlockedStats::default(),
+            validators: ValidatorChanges::default(),
+            by_status,
+            by_asset: assets
+                .into_iter()
+                .map(|(asset, campaigns)| (asset, AssetStats::of(campaigns.into_iter())))
+                .collect(),
+        }
+    }
+
+    /// Sets the Campaigns & assets gauges of the `network`, the deposit assets which are not in the `assets` (case-insensitive)
+    /// are summed under the [`OTHER_ASSET`] label, so the labels are bounded.
+    pub fn set_gauges(&self, network: &str, assets: &HashSet<String>) {
+        for status in STATUSES.iter() {
+            let campaigns = self.by_status.get(status).copied().unwrap_or_default();
+            CAMPAIGNS_BY_STATUS
+                .with_label_values(&[network, status])
+                .set(campaigns as i64);
+        }
+
+        let mut by_label: BTreeMap<String, Vec<&AssetStats>> = BTreeMap::new();
+        for (asset, stats) in self.by_asset.iter() {
+            by_label
+                .entry(asset_label(asset, assets))
+                .or_default()
+                .push(stats);
+        }
+
+        // only the assets of this network which no longer have Campaigns are removed, the other networks' are kept
+        let removed = assets
+            .iter()
+            .map(|asset| asset.to_lowercase())
+            .chain(std::iter::once(OTHER_ASSET.to_string()))
+            .filter(|label| !by_label.contains_key(label));
+        for label in removed {
+            let labels = [network, label.as_str()];
+            for gauge in &[&*ASSET_DEPOSITED, &*ASSET_DISTRIBUTED, &*ASSET_REMAINING] {
+                // `Err` if it wasn't set
+                let _ = gauge.remove_label_values(&labels);
+            }
+            let _ = ASSET_CAMPAIGNS.remove_label_values(&labels);
+        }
+        for (label, stats) in by_label {
+            let labels = [network, label.as_str()];
+            let sum = |amount: fn(&AssetStats) -> &BigNum| {
+                to_f64(&stats.iter().map(|stats| amount(stats)).sum::<BigNum>())
+            };
+
+            ASSET_CAMPAIGNS
+                .with_label_values(&labels)
+                .set(stats.iter().map(|stats| stats.campaigns).sum::<usize>() as i64);
+            ASSET_DEPOSITED
+                .with_label_values(&labels)
+                .set(sum(|stats| &stats.deposited));
+            ASSET_DISTRIBUTED
+                .with_label_values(&labels)
+                .set(sum(|stats| &stats.distributed));
+            ASSET_REMAINING
+                .with_label_values(&labels)
+                .set(sum(|stats| &stats.remaining));
+        }
+    }
+}
+
+/// The `status` label of the Campaigns
+pub fn status_label(status: &Status) -> &'static str {
+    match status {
+        Status::Active => "active",
+        Status::Pending => "pending",
+        Status::Initializing => "initializing",
+        Status::Waiting => "waiting",
+        Status::Unsound { .. } => "unsound",
+        Status::Finalized(_) => "finalized",
+    }
+}
+
+/// The `asset` label of the deposit asset, the lowercased address if it's one of the `assets`
+pub fn asset_label(asset: &str, assets: &HashSet<String>) -> String {
+    let asset = asset.to_lowercase();
+
+    if assets.iter().any(|allowed| allowed.to_lowercase() == asset) {
+        asset
+    } else {
+        OTHER_ASSET.to_string()
+    }
+}
+
+/// The gauges are only approximate for amounts beyond the `f64` precision
+fn to_f64(amount: &BigNum) -> f64 {
+    amount.to_string().parse().unwrap_or(f64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use primitives::util::tests::prep_db::{DUMMY_CHANNEL, IDS};
+    use primitives::ChannelId;
+    use std::sync::Arc;
+
+    const DAI: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+    const OTHER: &str = "0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359";
+
+    fn campaign(id: u8, asset: &str, deposit: u64, paid: &[u64], status: Status) -> Campaign {
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.id = ChannelId::from([id; 32]);
+        channel.deposit_asset = asset.to_string();
+        channel.deposit_amount = deposit.into();
+        let balances = paid
+            .iter()
+            .zip(&["publisher", "publisher2", "leader"])
+            .map(|(paid, earner)| (IDS[*earner], BigNum::from(*paid)))
+            .collect();
+
+        Campaign::new(channel, status, balances)
+    }
+
+    #[test]
+    fn aggregates_the_active_campaigns() {
+        let active = vec![
+            campaign(1, DAI, 1_000, &[100, 50], Status::Active),
+            campaign(2, DAI, 500, &[200], Status::Waiting),
+            // overspent, nothing remains
+            campaign(3, DAI, 100, &[150], Status::Active),
+            campaign(4, OTHER, 300, &[], Status::Initializing),
+        ]
+        .into_iter()
+        .map(|campaign| (campaign.channel.id, Arc::new(campaign)))
+        .collect::<ActiveCache>();
+        let computed_at = Utc::now();
+
+        let stats = CacheStats::compute(&active, 7, computed_at);
+
+        assert_eq!(Some(computed_at), stats.computed_at);
+        assert_eq!(4, stats.active);
+        assert_eq!(7, stats.finalized);
+        let by_status = vec![("active", 2), ("initializing", 1), ("waiting", 1)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(by_status, stats.by_status);
+
+        let expected_dai = AssetStats {
+            campaigns: 3,
+            deposited: 1_600.into(),
+            distributed: 500.into(),
+            remaining: 1_150.into(),
+        };
+        assert_eq!(Some(&expected_dai), stats.by_asset.get(DAI));
+        let expected_other = AssetStats {
+            campaigns: 1,
+            deposited: 300.into(),
+            distributed: 0.into(),
+            remaining: 300.into(),
+        };
+        assert_eq!(Some(&expected_other), stats.by_asset.get(OTHER));
+
+        let json = serde_json::to_value(&stats).expect("Should serialize");
+        assert_eq!(serde_json::json!("1600"), json["byAsset"][DAI]["deposited"]);
+        assert_eq!(serde_json::json!(2), json["byStatus"]["active"]);
+    }
+
+    #[test]
+    fn only_the_allowed_assets_are_labeled() {
+        let assets = vec![DAI.to_lowercase()].into_iter().collect();
+
+        assert_eq!(DAI.to_lowercase(), asset_label(DAI, &assets));
+        assert_eq!(OTHER_ASSET, asset_label(OTHER, &assets));
+        assert_eq!(OTHER_ASSET, asset_label(DAI, &HashSet::new()));
+    }
+
+    #[test]
+    fn the_gauges_of_the_networks_are_kept_apart() {
+        let assets: HashSet<String> = vec![DAI.to_lowercase()].into_iter().collect();
+        let active = |campaigns: Vec<Campaign>| {
+            campaigns
+                .into_iter()
+                .map(|campaign| (campaign.channel.id, Arc::new(campaign)))
+                .collect::<ActiveCache>()
+        };
+        let dai = DAI.to_lowercase();
+        let campaigns =
+            |network: &str, asset: &str| ASSET_CAMPAIGNS.with_label_values(&[network, asset]).get();
+
+        CacheStats::compute(
+            &active(vec![
+                campaign(1, DAI, 1_000, &[], Status::Active),
+                campaign(2, DAI, 500, &[], Status::Active),
+            ]),
+            0,
+            Utc::now(),
+        )
+        .set_gauges("stats-mainnet", &assets);
+        CacheStats::compute(
+            &active(vec![campaign(3, OTHER, 300, &[], Status::Waiting)]),
+            0,
+            Utc::now(),
+        )
+        .set_gauges("stats-testnet", &assets);
+
+        assert_eq!(2, campaigns("stats-mainnet", &dai));
+        assert_eq!(1, campaigns("stats-testnet", OTHER_ASSET));
+        assert_eq!(
+            2,
+            CAMPAIGNS_BY_STATUS
+                .with_label_values(&["stats-mainnet", "active"])
+                .get()
+        );
+        assert_eq!(
+            0,
+            CAMPAIGNS_BY_STATUS
+                .with_label_values(&["stats-testnet", "active"])
+                .get()
+        );
+
+        // the Campaigns of the testnet end, the gauges of the mainnet stay
+        CacheStats::compute(&active(vec![]), 1, Utc::now()).set_gauges("stats-testnet", &assets);
+
+        assert_eq!(2, campaigns("stats-mainnet", &dai));
+        assert!(ASSET_CAMPAIGNS
+            .remove_label_values(&["stats-testnet", OTHER_ASSET])
+            .is_err());
+    }
+
+    #[test]
+    fn the_validator_changes_are_against_the_configured_ones() {
+        let url = |url: &str| url.parse::<ApiUrl>().expect("Valid URL");
+        let (tom, jerry, spike) = (
+            url("https://tom.adex.network"),
+            url("https://jerry.adex.network"),
+            url("http://localhost:8005"),
+        );
+        let mut changes = ValidatorChanges::default();
+
+        let first = Utc::now();
+        changes.record(vec![spike.clone(), jerry.clone()], vec![tom.clone()], first);
+        assert_eq!(Some(first), changes.changed_at);
+        assert_eq!(vec![spike.clone(), jerry.clone()], changes.added);
+        assert_eq!(vec![tom.clone()], changes.removed);
+
+        // the configured Validator is back and the added one is removed again
+        let second = first + chrono::Duration::seconds(1);
+        changes.record(vec![tom], vec![spike], second);
+        assert_eq!(Some(second), changes.changed_at);
+        assert_eq!(vec![jerry], changes.added);
+        assert!(changes.removed.is_empty());
+    }
+}
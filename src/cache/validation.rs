@@ -0,0 +1,380 @@
+//! Validating the spec of the newly discovered Campaigns, the invalid ones are skipped,
+//! see [`Cache::fetch_new_campaigns`](super::Cache::fetch_new_campaigns)
+use chrono::{DateTime, Duration, Utc};
+use primitives::{
+    targeting::{get_pricing_bounds, Rule},
+    BigNum, Channel,
+};
+use reqwest::Url;
+use serde_json::Value;
+use thiserror::Error;
+
+/// Campaigns which expired longer ago than a year are considered malformed
+pub const MAX_EXPIRED_AGE_DAYS: i64 = 365;
+/// The deepest nesting of the targeting rules (functions, their arguments & arrays) which is evaluated
+pub const MAX_RULES_DEPTH: usize = 32;
+/// The most functions & values in all of the targeting rules of a Campaign or an AdSlot
+pub const MAX_RULES_NODES: usize = 2_000;
+
+/// The rule which the Campaign breaks
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum InvalidCampaign {
+    #[error("The deposit is zero")]
+    ZeroDeposit,
+    #[error("The IMPRESSION pricing bounds are missing or invalid (min: {min}, max: {max})")]
+    PricingBounds { min: BigNum, max: BigNum },
+    #[error("validUntil ({valid_until}) is not after the creation ({created})")]
+    ValidUntilBeforeCreated {
+        valid_until: DateTime<Utc>,
+        created: DateTime<Utc>,
+    },
+    #[error("validUntil ({0}) is more than a year in the past")]
+    LongExpired(DateTime<Utc>),
+    #[error("The Leader and the Follower are the same Validator")]
+    SameValidators,
+    #[error("Malformed Validator URL `{0}`")]
+    ValidatorUrl(String),
+    #[error("The targeting rules are too complex (depth: {}, nodes: {})", .0.depth, .0.nodes)]
+    TargetingRules(RulesComplexity),
+}
+
+impl InvalidCampaign {
+    /// The `rule` label of the metrics
+    pub fn rule(&self) -> &'static str {
+        match self {
+            Self::ZeroDeposit => "zero_deposit",
+            Self::PricingBounds { .. } => "pricing_bounds",
+            Self::ValidUntilBeforeCreated { .. } => "valid_until_before_created",
+            Self::LongExpired(_) => "long_expired",
+            Self::SameValidators => "same_validators",
+            Self::ValidatorUrl(_) => "validator_url",
+            Self::TargetingRules(_) => "targeting_rules",
+        }
+    }
+}
+
+/// The size of the targeting rules, bounded by [`MAX_RULES_DEPTH`] & [`MAX_RULES_NODES`]
+/// since evaluating them recurses through every node for every unit of every request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RulesComplexity {
+    pub depth: usize,
+    pub nodes: usize,
+}
+
+impl RulesComplexity {
+    /// Walks the serialized `rules` with an explicit stack instead of recursing into them.
+    /// Each rule is at a depth of `1`, the arguments of a function & the elements of an array are one deeper.
+    pub fn of(rules: &[Rule]) -> Self {
+        Self::of_each(rules)
+            .into_iter()
+            .fold(Self::default(), |total, rule| Self {
+                depth: total.depth.max(rule.depth),
+                nodes: total.nodes + rule.nodes,
+            })
+    }
+
+    /// The complexity of each one of the `rules`, in the same order, see [`RulesComplexity::of`]
+    pub fn of_each(rules: &[Rule]) -> Vec<Self> {
+        match serde_json::to_value(rules) {
+            Ok(Value::Array(serialized)) => serialized.iter().map(Self::of_value).collect(),
+            _ => vec![Self::default(); rules.len()],
+        }
+    }
+
+    fn of_value(rule: &Value) -> Self {
+        let mut complexity = Self::default();
+
+        let mut stack = vec![(rule, 1)];
+        while let Some((node, depth)) = stack.pop() {
+            complexity.nodes += 1;
+            complexity.depth = complexity.depth.max(depth);
+
+            match node {
+                Value::Array(values) => stack.extend(values.iter().map(|value| (value, depth + 1))),
+                Value::Object(map) => stack.extend(map.values().map(|value| (value, depth + 1))),
+                _ => {}
+            }
+        }
+
+        complexity
+    }
+
+    pub fn is_within_limits(&self) -> bool {
+        self.depth <= MAX_RULES_DEPTH && self.nodes <= MAX_RULES_NODES
+    }
+}
+
+/// Checks the complexity of the `rules`, see [`RulesComplexity`]
+pub fn validate_rules(rules: &[Rule]) -> Result<(), InvalidCampaign> {
+    let complexity = RulesComplexity::of(rules);
+
+    if complexity.is_within_limits() {
+        Ok(())
+    } else {
+        Err(InvalidCampaign::TargetingRules(complexity))
+    }
+}
+
+/// Checks the spec of the Campaign, returning the first rule it breaks.
+///
+/// The spec always has exactly two Validators (the Leader & the Follower), so only their ids & URLs are checked.
+pub fn validate(channel: &Channel, now: DateTime<Utc>) -> Result<(), InvalidCampaign> {
+    let zero = BigNum::from(0);
+    if channel.deposit_amount == zero {
+        return Err(InvalidCampaign::ZeroDeposit);
+    }
+
+    let pricing_bounds = get_pricing_bounds(channel, "IMPRESSION");
+    if pricing_bounds.max == zero || pricing_bounds.min > pricing_bounds.max {
+        return Err(InvalidCampaign::PricingBounds {
+            min: pricing_bounds.min,
+            max: pricing_bounds.max,
+        });
+    }
+
+    let (valid_until, spec) = (channel.valid_until, &channel.spec);
+    if valid_until <= spec.created {
+        return Err(InvalidCampaign::ValidUntilBeforeCreated {
+            valid_until,
+            created: spec.created,
+        });
+    }
+    if valid_until < now - Duration::days(MAX_EXPIRED_AGE_DAYS) {
+        return Err(InvalidCampaign::LongExpired(valid_until));
+    }
+
+    let (leader, follower) = (spec.validators.leader(), spec.validators.follower());
+    if leader.id == follower.id {
+        return Err(InvalidCampaign::SameValidators);
+    }
+    for validator in &[leader, follower] {
+        if Url::parse(&validator.url).is_err() {
+            return Err(InvalidCampaign::ValidatorUrl(validator.url.clone()));
+        }
+    }
+
+    validate_rules(&channel.targeting_rules.0)?;
+    validate_rules(&spec.targeting_rules.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use primitives::{
+        channel::{Pricing, PricingBounds, SpecValidators},
+        targeting::Rules,
+        util::tests::prep_db::DUMMY_CHANNEL,
+    };
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 3, 1).and_hms(12, 0, 0)
+    }
+
+    fn channel() -> Channel {
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.spec.created = now() - Duration::days(30);
+        channel.valid_until = now() + Duration::days(30);
+
+        channel
+    }
+
+    #[test]
+    fn valid_campaigns_pass() {
+        assert_eq!(Ok(()), validate(&channel(), now()));
+
+        // recently expired Campaigns are still valid, they're just not Active
+        let mut expired = channel();
+        expired.valid_until = now() - Duration::days(7);
+        assert_eq!(Ok(()), validate(&expired, now()));
+    }
+
+    #[test]
+    fn zero_deposit() {
+        let mut channel = channel();
+        channel.deposit_amount = 0.into();
+
+        assert_eq!(Err(InvalidCampaign::ZeroDeposit), validate(&channel, now()));
+    }
+
+    #[test]
+    fn pricing_bounds() {
+        let with_impression = |min: u64, max: u64| {
+            let mut channel = channel();
+            channel.spec.pricing_bounds = Some(PricingBounds {
+                impression: Some(Pricing {
+                    min: min.into(),
+                    max: max.into(),
+                }),
+                click: None,
+            });
+
+            validate(&channel, now())
+        };
+
+        assert_eq!(Ok(()), with_impression(1, 10));
+        assert_eq!(
+            Err(InvalidCampaign::PricingBounds {
+                min: 0.into(),
+                max: 0.into()
+            }),
+            with_impression(0, 0)
+        );
+        assert_eq!(
+            Err(InvalidCampaign::PricingBounds {
+                min: 10.into(),
+                max: 1.into()
+            }),
+            with_impression(10, 1)
+        );
+    }
+
+    #[test]
+    fn valid_until_before_created() {
+        let mut channel = channel();
+        channel.spec.created = channel.valid_until;
+
+        assert_eq!(
+            "valid_until_before_created",
+            validate(&channel, now()).unwrap_err().rule()
+        );
+    }
+
+    #[test]
+    fn long_expired() {
+        let mut channel = channel();
+        channel.spec.created = now() - Duration::days(3 * 365);
+        channel.valid_until = now() - Duration::days(2 * 365);
+
+        assert_eq!(
+            Err(InvalidCampaign::LongExpired(channel.valid_until)),
+            validate(&channel, now())
+        );
+    }
+
+    #[test]
+    fn validators() {
+        let mut same = channel();
+        let leader = same.spec.validators.leader().clone();
+        same.spec.validators = SpecValidators::new(leader.clone(), leader.clone());
+        assert_eq!(Err(InvalidCampaign::SameValidators), validate(&same, now()));
+
+        let mut malformed = channel();
+        let mut follower = malformed.spec.validators.follower().clone();
+        follower.url = "not a url".to_string();
+        malformed.spec.validators = SpecValidators::new(leader, follower);
+        assert_eq!(
+            Err(InvalidCampaign::ValidatorUrl("not a url".to_string())),
+            validate(&malformed, now())
+        );
+    }
+
+    /// `depth` nested multiplications of the boost, each of them is 2 levels deep (the function & its arguments)
+    fn deep_rules(depth: usize) -> Rules {
+        let mut boost = serde_json::json!({ "get": "boost" });
+        for _ in 0..depth {
+            boost = serde_json::json!({ "mul": [boost, 1.1] });
+        }
+
+        serde_json::from_value(serde_json::json!([{ "set": ["boost", boost] }]))
+            .expect("Should deserialize the rules")
+    }
+
+    /// A boost for an intersection with `width` categories
+    fn wide_rules(width: usize) -> Rules {
+        let categories = (0..width)
+            .map(|category| format!("IAB{}", category))
+            .collect::<Vec<_>>();
+
+        serde_json::from_value(serde_json::json!([{
+            "if": [
+                { "intersects": [{ "get": "adSlot.categories" }, categories] },
+                { "set": ["boost", 2.0] }
+            ]
+        }]))
+        .expect("Should deserialize the rules")
+    }
+
+    #[test]
+    fn rules_complexity() {
+        assert_eq!(
+            RulesComplexity { depth: 0, nodes: 0 },
+            RulesComplexity::of(&[])
+        );
+        // set -> [boost, get] -> get -> "boost"
+        assert_eq!(
+            RulesComplexity { depth: 4, nodes: 5 },
+            RulesComplexity::of(&deep_rules(0).0)
+        );
+        assert_eq!(
+            RulesComplexity {
+                depth: 4 + 2 * 100,
+                nodes: 5 + 3 * 100
+            },
+            RulesComplexity::of(&deep_rules(100).0)
+        );
+        assert_eq!(
+            RulesComplexity {
+                depth: 6,
+                nodes: 11 + 3_000
+            },
+            RulesComplexity::of(&wide_rules(3_000).0)
+        );
+    }
+
+    #[test]
+    fn rules_complexity_of_each_rule() {
+        let rules = deep_rules(2)
+            .0
+            .into_iter()
+            .chain(wide_rules(3).0)
+            .collect::<Vec<_>>();
+
+        let each = RulesComplexity::of_each(&rules);
+        assert_eq!(
+            vec![
+                RulesComplexity::of(&deep_rules(2).0),
+                RulesComplexity::of(&wide_rules(3).0)
+            ],
+            each
+        );
+        assert_eq!(
+            RulesComplexity {
+                depth: 8,
+                nodes: each[0].nodes + each[1].nodes
+            },
+            RulesComplexity::of(&rules)
+        );
+    }
+
+    #[test]
+    fn too_complex_targeting_rules() {
+        let with_rules = |rules: Rules, in_spec: bool| {
+            let mut channel = channel();
+            if in_spec {
+                channel.spec.targeting_rules = rules;
+            } else {
+                channel.targeting_rules = rules;
+            }
+
+            validate(&channel, now())
+        };
+
+        for &in_spec in &[true, false] {
+            assert_eq!(Ok(()), with_rules(deep_rules(10), in_spec));
+            assert_eq!(Ok(()), with_rules(wide_rules(100), in_spec));
+            assert_eq!(
+                "targeting_rules",
+                with_rules(deep_rules(MAX_RULES_DEPTH), in_spec)
+                    .unwrap_err()
+                    .rule()
+            );
+            assert_eq!(
+                "targeting_rules",
+                with_rules(wide_rules(MAX_RULES_NODES), in_spec)
+                    .unwrap_err()
+                    .rule()
+            );
+        }
+    }
+}
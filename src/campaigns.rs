@@ -0,0 +1,315 @@
+//! Public routes for the Campaigns in the [`Cache`]
+use chrono::{DateTime, Utc};
+use http::{header::CONTENT_TYPE, StatusCode};
+use hyper::{Body, Response};
+use primitives::{BalancesMap, BigNum, ChannelId, ValidatorDesc, ValidatorId};
+use serde::Serialize;
+
+use crate::{
+    bad_request,
+    cache::{filter::CampaignFilter, Cache, Client},
+    ids::Canonical,
+    not_found,
+    status::Status,
+    Error, ROUTE_CAMPAIGNS,
+};
+
+/// The ChannelId of the `/campaigns/:id/balances` route
+pub(crate) fn balances_route(path: &str) -> Option<&str> {
+    path.strip_prefix(ROUTE_CAMPAIGNS)
+        .and_then(|rest| rest.strip_suffix("/balances"))
+        .filter(|channel_id| !channel_id.is_empty() && !channel_id.contains('/'))
+}
+
+/// The ChannelId of the admin `/campaigns/:id/units` route, see [`get_campaign_units`](crate::admin::get_campaign_units)
+pub(crate) fn units_route(path: &str) -> Option<&str> {
+    path.strip_prefix(ROUTE_CAMPAIGNS)
+        .and_then(|rest| rest.strip_suffix("/units"))
+        .filter(|channel_id| !channel_id.is_empty() && !channel_id.contains('/'))
+}
+
+/// The ChannelId of the admin `/campaigns/:id/serve-stats` route, see [`get_campaign_serve_stats`](crate::admin::get_campaign_serve_stats)
+pub(crate) fn serve_stats_route(path: &str) -> Option<&str> {
+    path.strip_prefix(ROUTE_CAMPAIGNS)
+        .and_then(|rest| rest.strip_suffix("/serve-stats"))
+        .filter(|channel_id| !channel_id.is_empty() && !channel_id.contains('/'))
+}
+
+/// The response of [`get_balances`]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CampaignBalances {
+    pub channel_id: ChannelId,
+    pub status: Status,
+    /// Empty if the Leader has no NewState yet
+    pub balances: BalancesMap,
+    /// The state root of the Leader's NewState of the balances
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_root: Option<String>,
+    /// When the Leader's NewState of the balances was received
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received: Option<DateTime<Utc>>,
+    /// The Leader & Follower of the Campaign's spec, from which its status & balances are updated
+    pub validators: Vec<CampaignValidator>,
+}
+
+/// A Validator of the Campaign's spec
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CampaignValidator {
+    pub id: ValidatorId,
+    pub url: String,
+    pub fee: BigNum,
+}
+
+impl From<&ValidatorDesc> for CampaignValidator {
+    fn from(validator: &ValidatorDesc) -> Self {
+        Self {
+            id: validator.id,
+            url: validator.url.clone(),
+            fee: validator.fee.clone(),
+        }
+    }
+}
+
+/// `GET /campaigns/:id/balances` - the cached balances of an Active Campaign:
+/// - `400 Bad Request` - if the ChannelId is malformed
+/// - `404 Not Found` - if there is no such Active Campaign in the [`Cache`]
+pub async fn get_balances<C: Client>(
+    channel_id: &str,
+    cache: &Cache<C>,
+) -> Result<Response<Body>, Error> {
+    let channel_id = match channel_id.parse::<Canonical<ChannelId>>() {
+        Ok(Canonical(channel_id)) => channel_id,
+        Err(malformed) => return Ok(bad_request(malformed.to_string())),
+    };
+
+    let (status, balances, validators) = match cache.active.read().await.get(&channel_id) {
+        Some(campaign) => {
+            let validators = &campaign.channel.spec.validators;
+
+            (
+                campaign.status.clone(),
+                campaign.balances.clone(),
+                vec![validators.leader().into(), validators.follower().into()],
+            )
+        }
+        None => return Ok(not_found()),
+    };
+    let new_state = cache.last_new_state(&channel_id).await;
+
+    let response = CampaignBalances {
+        channel_id,
+        status,
+        balances,
+        state_root: new_state
+            .as_ref()
+            .map(|new_state| new_state.state_root.clone()),
+        received: new_state.map(|new_state| new_state.received),
+        validators,
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&response)?))?)
+}
+
+/// The Active Campaigns in the [`Cache`] filtered & sorted by the `query`, see [`CampaignFilter::parse`]:
+/// - `400 Bad Request` - naming the query parameter with a malformed value
+pub async fn filter_campaigns<C: Client>(
+    query: &str,
+    cache: &Cache<C>,
+) -> Result<Response<Body>, Error> {
+    let filter = match CampaignFilter::parse(query) {
+        Ok(filter) => filter,
+        Err(malformed) => return Ok(bad_request(malformed.to_string())),
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(
+            &cache.filter_campaigns(&filter).await,
+        )?))?)
+}
+
+/// `GET /stats` - the aggregates of the Active Campaigns in the [`Cache`] (per status and deposit asset),
+/// as of the last time they changed
+pub async fn get_stats<C: Client>(cache: &Cache<C>) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&cache.stats().await)?))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cache::{Campaign, MockClient},
+        status::LastNewState,
+    };
+    use chrono::TimeZone;
+    use primitives::util::tests::prep_db::{DUMMY_CHANNEL, IDS};
+    use std::collections::HashMap;
+
+    async fn balances(path: &str, cache: &Cache<MockClient>) -> Response<Body> {
+        let channel_id = balances_route(path).expect("Should be the balances route");
+
+        get_balances(channel_id, cache)
+            .await
+            .expect("Should handle the request")
+    }
+
+    #[test]
+    fn matches_the_balances_route() {
+        let channel_id = DUMMY_CHANNEL.id.to_string();
+
+        assert_eq!(
+            Some(channel_id.as_str()),
+            balances_route(&format!("/campaigns/{}/balances", channel_id))
+        );
+        assert_eq!(None, balances_route("/campaigns//balances"));
+        assert_eq!(None, balances_route("/campaigns/a/b/balances"));
+        assert_eq!(
+            None,
+            balances_route(&format!("/campaigns/{}/balances/", channel_id))
+        );
+        assert_eq!(None, balances_route(&format!("/campaigns/{}", channel_id)));
+        assert_eq!(
+            Some(channel_id.as_str()),
+            units_route(&format!("/campaigns/{}/units", channel_id))
+        );
+        assert_eq!(
+            None,
+            units_route(&format!("/campaigns/{}/balances", channel_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_the_cached_balances_of_the_campaign() {
+        let with_new_state = DUMMY_CHANNEL.clone();
+        let mut without_new_state = DUMMY_CHANNEL.clone();
+        without_new_state.id = ChannelId::from([2; 32]);
+
+        let balances_map: BalancesMap = vec![(IDS["publisher"], 100.into())].into_iter().collect();
+        let received = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+        let campaigns = vec![
+            Campaign::new(with_new_state.clone(), Status::Active, balances_map.clone()),
+            Campaign::new(
+                without_new_state.clone(),
+                Status::Initializing,
+                Default::default(),
+            ),
+        ]
+        .into_iter()
+        .map(|campaign| (campaign.channel.id, campaign))
+        .collect();
+        let new_states = vec![(
+            with_new_state.id,
+            LastNewState {
+                state_root: "0xroot".to_string(),
+                received,
+            },
+        )]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+        let client = MockClient::init(vec![campaigns], vec![], None)
+            .await
+            .with_new_states(new_states);
+        let cache = Cache::initialize(client).await;
+
+        let response = balances(
+            &format!("/campaigns/{}/balances", with_new_state.id),
+            &cache,
+        )
+        .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read body");
+        let json: serde_json::Value = serde_json::from_slice(&body).expect("Should deserialize");
+        let expected = CampaignBalances {
+            channel_id: with_new_state.id,
+            status: Status::Active,
+            balances: balances_map,
+            state_root: Some("0xroot".to_string()),
+            received: Some(received),
+            validators: vec![
+                with_new_state.spec.validators.leader().into(),
+                with_new_state.spec.validators.follower().into(),
+            ],
+        };
+        assert_eq!(
+            serde_json::to_value(&expected).expect("Should serialize"),
+            json
+        );
+        assert_eq!(serde_json::json!("0xroot"), json["stateRoot"]);
+        assert_eq!(
+            serde_json::json!(with_new_state.spec.validators.leader().url),
+            json["validators"][0]["url"]
+        );
+
+        let response = balances(
+            &format!("/campaigns/{}/balances", without_new_state.id),
+            &cache,
+        )
+        .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read body");
+        let json: serde_json::Value = serde_json::from_slice(&body).expect("Should deserialize");
+        assert_eq!(serde_json::json!({}), json["balances"]);
+        assert!(json.get("status").is_some());
+        assert!(json.get("stateRoot").is_none());
+
+        let unknown = ChannelId::from([3; 32]);
+        let response = balances(&format!("/campaigns/{}/balances", unknown), &cache).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+        let response = balances("/campaigns/not-a-channel/balances", &cache).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+
+    #[tokio::test]
+    async fn filters_the_cached_campaigns() {
+        let mut waiting = DUMMY_CHANNEL.clone();
+        waiting.id = ChannelId::from([2; 32]);
+        let campaigns = vec![
+            Campaign::new(DUMMY_CHANNEL.clone(), Status::Active, Default::default()),
+            Campaign::new(waiting, Status::Waiting, Default::default()),
+        ]
+        .into_iter()
+        .map(|campaign| (campaign.channel.id, campaign))
+        .collect();
+        let cache = Cache::initialize(MockClient::init(vec![campaigns], vec![], None).await).await;
+
+        let response = filter_campaigns("status=waiting", &cache)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read body");
+        let campaigns: Vec<Campaign> = serde_json::from_slice(&body).expect("Should deserialize");
+        assert_eq!(
+            vec![ChannelId::from([2; 32])],
+            campaigns
+                .iter()
+                .map(|campaign| campaign.channel.id)
+                .collect::<Vec<_>>()
+        );
+
+        let response = filter_campaigns("status=active&sort=deposit", &cache)
+            .await
+            .expect("Should handle the request");
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read body");
+        assert_eq!(&b"Malformed query parameter `sort`"[..], &body[..]);
+    }
+}
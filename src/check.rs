@@ -0,0 +1,203 @@
+//! The `check` subcommand - probes everything the Supermarket depends on without serving,
+//! see [`check`].
+use std::fmt;
+
+use futures::future::join_all;
+use slog::Logger;
+
+use crate::{cache::snapshot::Snapshot, market::MarketUrl, Config, MarketApi, SentryApi};
+
+/// The result of a single check, `Err` with the reason it failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub result: Result<String, String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<String, String>) -> Self {
+        Self {
+            name: name.into(),
+            result,
+        }
+    }
+}
+
+/// The human-readable report of the [`check`]s, one line per check
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub checks: Vec<Check>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in self.checks.iter() {
+            match &check.result {
+                Ok(details) => writeln!(f, "[ OK ] {}: {}", check.name, details)?,
+                Err(reason) => writeln!(f, "[FAIL] {}: {}", check.name, reason)?,
+            }
+        }
+
+        let failed = self
+            .checks
+            .iter()
+            .filter(|check| check.result.is_err())
+            .count();
+        match failed {
+            0 => write!(f, "All {} checks passed", self.checks.len()),
+            failed => write!(f, "{} of {} checks failed", failed, self.checks.len()),
+        }
+    }
+}
+
+/// Validates the `config` and probes with its timeouts:
+/// - the Market, the same way as on startup (see [`MarketApi::probe`])
+/// - each of the Validators, which should respond to a `HEAD` request (see [`SentryApi::warm_up`])
+/// - the Cache snapshot of the `warm_from` replica (if set), which should be fetched and parsed
+pub async fn check(logger: &Logger, config: &Config, market_url: MarketUrl) -> CheckReport {
+    let mut checks = vec![Check::new(
+        "config",
+        config
+            .validate()
+            .map(|_| "valid".to_string())
+            .map_err(|error| error.to_string()),
+    )];
+
+    let market = match MarketApi::new(market_url.clone(), config, logger.clone()) {
+        Ok(market) => market.probe().await.map_err(|error| error.to_string()),
+        Err(error) => Err(error.to_string()),
+    };
+    checks.push(Check::new(
+        format!("market {}", market_url),
+        market.map(|_| "reachable".to_string()),
+    ));
+
+    match SentryApi::with_timeouts(&config.timeouts) {
+        Ok(sentry) => {
+            let mut validators = config.validators.iter().collect::<Vec<_>>();
+            validators.sort_by_key(|validator| validator.to_string());
+
+            let probes = validators.iter().map(|validator| sentry.warm_up(validator));
+            for (validator, result) in validators.iter().zip(join_all(probes).await) {
+                checks.push(Check::new(
+                    format!("validator {}", validator),
+                    result
+                        .map(|_| "reachable".to_string())
+                        .map_err(|error| error.to_string()),
+                ));
+            }
+        }
+        Err(error) => checks.push(Check::new("validators", Err(error.to_string()))),
+    }
+
+    if let Some(replica) = config.warm_from.as_ref() {
+        let admin_token = config.admin_token.as_ref().map(|token| token.expose());
+        let snapshot = Snapshot::fetch(replica, admin_token, config.timeouts.global_request)
+            .await
+            .map(|snapshot| format!("{} Active Campaigns", snapshot.active.len()))
+            .map_err(|error| error.to_string());
+
+        checks.push(Check::new(format!("snapshot {}", replica), snapshot));
+    }
+
+    CheckReport { checks }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::DEVELOPMENT, util::test::discard_logger};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn upstream(status: u16) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(status))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(status))
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    fn api_url(server: &MockServer) -> MarketUrl {
+        format!("{}/", server.uri()).parse().expect("Valid URL")
+    }
+
+    #[tokio::test]
+    async fn reports_each_of_the_checks() {
+        let (market, validator) = (upstream(200).await, upstream(200).await);
+        let mut config = DEVELOPMENT.clone();
+        config.validators = vec![api_url(&validator)].into_iter().collect();
+
+        let report = check(&discard_logger(), &config, api_url(&market)).await;
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(
+            vec![
+                "config".to_string(),
+                format!("market {}", api_url(&market)),
+                format!("validator {}", api_url(&validator)),
+            ],
+            report
+                .checks
+                .iter()
+                .map(|check| check.name.clone())
+                .collect::<Vec<_>>()
+        );
+        assert!(report.to_string().ends_with("All 3 checks passed"));
+    }
+
+    #[tokio::test]
+    async fn fails_on_any_unreachable_upstream() {
+        let (market, validator) = (upstream(503).await, upstream(200).await);
+        // nothing listens on the port once the listener is dropped
+        let unreachable: MarketUrl = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Should bind");
+            let addr = listener.local_addr().expect("Should have an address");
+
+            format!("http://{}/", addr).parse().expect("Valid URL")
+        };
+        let replica = MockServer::start().await;
+        Mock::given(path("/internal/cache-snapshot"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("not a snapshot"))
+            .mount(&replica)
+            .await;
+
+        let mut config = DEVELOPMENT.clone();
+        config.validators = vec![api_url(&validator), unreachable.clone()]
+            .into_iter()
+            .collect();
+        config.warm_from = Some(api_url(&replica));
+
+        let report = check(&discard_logger(), &config, api_url(&market)).await;
+        assert!(!report.is_ok());
+
+        let failed = report
+            .checks
+            .iter()
+            .filter(|check| check.result.is_err())
+            .map(|check| check.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                format!("market {}", api_url(&market)),
+                format!("validator {}", unreachable),
+                format!("snapshot {}", api_url(&replica)),
+            ],
+            failed
+        );
+        assert!(report.to_string().ends_with("3 of 5 checks failed"));
+    }
+}
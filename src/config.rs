@@ -1,20 +1,65 @@
+use crate::{
+    bot::{BotPolicy, CidrSet},
+    units_for_slot::{selection::Strategy, EmptyReason},
+};
+use http::header::{HeaderName, HeaderValue, USER_AGENT};
 use lazy_static::lazy_static;
-use primitives::{util::ApiUrl, BigNum};
-use serde::{Deserialize, Deserializer};
-use std::{collections::HashSet, fmt, str::FromStr, time::Duration};
+use primitives::{util::ApiUrl, BigNum, ValidatorId};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use url::Url;
+
+/// The prefix of the environment variables which override the [`Config`] fields.
+/// Nested fields are separated by `__`, e.g. `SUPERMARKET_TIMEOUTS__VALIDATOR_REQUEST`
+pub const ENV_PREFIX: &str = "SUPERMARKET_";
+const ENV_NESTED_SEPARATOR: &str = "__";
+/// The maps keyed by the operator's values (e.g. the AdSlot ipfs), `*` is any key.
+/// The segment of the environment variable after them is the key and it's kept as given,
+/// the rest of the segments are lowercased to the field names.
+const ENV_MAP_FIELDS: &[&[&str]] = &[
+    &["slot_overrides"],
+    &["slot_overrides", "*", "min_price"],
+    &["networks"],
+    &["timeouts", "validators"],
+    &["proxy", "extra_headers"],
+];
+
+static DEVELOPMENT_TOML: &str = include_str!("../config/dev.toml");
+static PRODUCTION_TOML: &str = include_str!("../config/prod.toml");
 
 lazy_static! {
     pub static ref DEVELOPMENT: Config = {
-        toml::from_str(include_str!("../config/dev.toml"))
-            .expect("Failed to parse dev.toml config file")
+        let mut config: Config =
+            toml::from_str(DEVELOPMENT_TOML).expect("Failed to parse dev.toml config file");
+        config.source = ConfigSource::Defaults {
+            environment: Environment::Development,
+        };
+
+        config
     };
     pub static ref PRODUCTION: Config = {
-        toml::from_str(include_str!("../config/prod.toml"))
-            .expect("Failed to parse prod.toml config file")
+        let mut config: Config =
+            toml::from_str(PRODUCTION_TOML).expect("Failed to parse prod.toml config file");
+        config.source = ConfigSource::Defaults {
+            environment: Environment::Production,
+        };
+
+        config
     };
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// The value of secrets when they are serialized or debug formatted
+pub const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum Environment {
     Development,
     Production,
@@ -43,95 +88,2654 @@ impl FromStr for Environment {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Where was the [`Config`] loaded from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum ConfigSource {
+    /// The built-in `config/dev.toml` or `config/prod.toml` profile of the environment
+    Defaults { environment: Environment },
+    /// A custom config file layered on top of the environment's profile
+    File {
+        path: String,
+        environment: Environment,
+    },
+}
+
+impl Default for ConfigSource {
+    fn default() -> Self {
+        Self::Defaults {
+            environment: Environment::Development,
+        }
+    }
+}
+
+/// A secret which is redacted when it's serialized or debug formatted,
+/// e.g. when the [`Config`] is logged or served.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
+    #[serde(skip_deserializing)]
+    pub source: ConfigSource,
+    /// The names of the environment variables which have overridden the values of the `source`
+    #[serde(skip_deserializing)]
+    pub env_overrides: Vec<String>,
     pub validators: HashSet<ApiUrl>,
-    #[serde(deserialize_with = "seconds_to_std_duration")]
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
     /// (Now - Recency) determines if a DateTime is recent or not
     pub recency: Duration,
-    #[serde(deserialize_with = "seconds_to_std_duration")]
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    /// The difference between the clocks of the Validators and ours which is tolerated when checking their Heartbeats:
+    /// the ones dated up to this much in the future count as sent now
+    /// and the ones up to `recency` + `clock_skew_tolerance` old are still recent
+    pub clock_skew_tolerance: Duration,
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
     pub fetch_campaigns_every: Duration,
-    #[serde(deserialize_with = "seconds_to_std_duration")]
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
     pub update_campaigns_every: Duration,
+    /// The Cache is considered stale when the last completed run of fetching new campaigns
+    /// or updating the campaigns is older than `watchdog_multiplier` times their interval.
+    pub watchdog_multiplier: u32,
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    /// For how long the sorted units-for-slot results of an AdSlot and query are cached,
+    /// in order for the `?skip=` & `?limit=` pages to be stable.
+    pub units_for_slot_cache_ttl: Duration,
+    /// The maximum number of the cached units-for-slot results (per AdSlot, type & targeting inputs),
+    /// the least recently cached ones are evicted first, `0` disables the caching.
+    pub units_for_slot_cache_size: usize,
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    /// A warning with the time spent in each phase is logged for units-for-slot requests
+    /// which take longer than this (in milliseconds) to handle.
+    pub slow_request_threshold: Duration,
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    /// With the `cache-metrics` feature, a warning with the call site is logged for the Cache locks
+    /// which wait longer than this (in milliseconds), see [`Lock`](crate::cache::lock::Lock)
+    pub cache_lock_wait_warning: Duration,
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    /// Identical concurrent units-for-slot requests are coalesced into one
+    /// and its successful response is shared for this long (in milliseconds) after it completes, `0` disables it.
+    pub units_for_slot_coalesce_window: Duration,
+    /// The maximum number of memoized targeting results (per AdSlot & Campaign), `0` disables the memoization.
+    pub targeting_memo_size: usize,
+    /// Refuse (`204 No Content`) the units-for-slot requests whose `Referer` doesn't match the AdSlot's website,
+    /// otherwise they are served with `"referrerMismatch": true`
+    #[serde(default)]
+    pub strict_referrer_check: bool,
+    /// Treat the units-for-slot requests without a `Referer` as mismatching, see `strict_referrer_check`
+    #[serde(default)]
+    pub require_referrer: bool,
+    /// Whether the requests which aren't handled by the Supermarket are proxied to the Market.
+    /// If not, they are `404 Not Found` and the proxy isn't built, see [`Upstream::proxy`](crate::network::Upstream::proxy)
+    #[serde(default = "default_proxy_enabled")]
+    pub proxy_enabled: bool,
+    /// Campaigns which weren't refreshed (their status & balances) for longer than this are not served
+    /// until they are refreshed again. If not set, the Campaigns are served regardless.
+    #[serde(
+        default,
+        deserialize_with = "option_seconds_to_std_duration",
+        serialize_with = "option_std_duration_to_seconds"
+    )]
+    pub max_campaign_staleness: Option<Duration>,
+    /// How the units-for-slot requests are served while the Cache is degraded, see [`DegradationPolicy`]
+    #[serde(default)]
+    pub degradation_policy: DegradationPolicy,
+    /// The hosts of the Validators whose Campaigns are served (incl. their subdomains, regardless of the port),
+    /// any Validator if empty, see [`ValidatorAllowlist`](crate::cache::allowlist::ValidatorAllowlist)
+    #[serde(default)]
+    pub validator_allowlist: Vec<String>,
+    /// What happens to the discovered Campaigns whose Leader or Follower isn't on the `validator_allowlist`
+    #[serde(default)]
+    pub validator_allowlist_policy: ValidatorAllowlistPolicy,
+    /// The IPFS gateway to which the `ipfs://` media URLs of the units-for-slot responses are rewritten,
+    /// e.g. `https://ipfs.adex.network/` for `https://ipfs.adex.network/ipfs/<hash>`
+    pub ipfs_gateway: ApiUrl,
+    /// The `Authorization: Bearer <token>` required for the admin routes.
+    /// If not set, the admin routes are disabled.
+    #[serde(default)]
+    pub admin_token: Option<Secret>,
+    /// A separate address (e.g. reachable only from the internal network) for the admin routes,
+    /// if set they are served only on it and the other routes only on the public one.
+    #[serde(default)]
+    pub admin_listen: Option<SocketAddr>,
+    /// If not empty, the admin routes are served (`403 Forbidden` otherwise) only to client IPs in these CIDRs,
+    /// e.g. `10.0.0.0/8` or `2001:db8::/32`
+    #[serde(default)]
+    pub admin_allowed_ips: CidrSet,
+    /// Requests from these CIDRs (e.g. a load balancer) are trusted to append the client IP to the `X-Forwarded-For`
+    #[serde(default)]
+    pub trusted_proxies: CidrSet,
+    /// A running replica (the URL of its API) whose Cache snapshot is loaded on startup,
+    /// fetched with the same `admin_token`. If it fails or it's not set, the Campaigns are fetched from the Validators.
+    #[serde(default)]
+    pub warm_from: Option<ApiUrl>,
+    /// The DSN of the Sentry.io project for reporting errors & panics,
+    /// used only with the `sentry-reporting` feature.
+    #[serde(default)]
+    pub sentry_dsn: Option<Secret>,
+    #[serde(default)]
+    pub keep_warm: KeepWarm,
+    #[serde(default)]
+    pub prewarm: Prewarm,
+    #[serde(default)]
+    pub negative_slots: NegativeSlots,
+    #[serde(default)]
+    pub media_check: MediaCheck,
+    #[serde(default)]
+    pub channel_list: ChannelList,
+    #[serde(default)]
+    pub initialization: Initialization,
+    #[serde(default)]
+    pub anomaly_blocking: AnomalyBlocking,
+    #[serde(default)]
+    pub serve_stats: ServeStats,
+    #[serde(default)]
+    pub selection: Selection,
+    #[serde(default)]
+    pub tags: Tags,
+    #[serde(default)]
+    pub idempotency: Idempotency,
+    #[serde(default)]
+    pub last_approved: LastApprovedBatch,
+    #[serde(default)]
+    pub stats: Stats,
+    #[serde(default)]
+    pub proxy: ProxyHeaders,
+    #[serde(default)]
+    pub cache_control: CacheControl,
+    #[serde(default)]
+    pub access_log: AccessLog,
+    #[serde(default)]
+    pub sampling: Sampling,
+    /// The operator's overrides of the units-for-slot requests by the AdSlot ipfs,
+    /// reloaded from the config on `SIGHUP`, see [`SlotOverride`]
+    #[serde(default)]
+    pub slot_overrides: SlotOverrides,
+    /// Other AdEx networks (e.g. a staging one) served next to the default one by their name, see [`Network`]
+    #[serde(default)]
+    pub networks: BTreeMap<String, Network>,
     pub limits: Limits,
     pub market: Market,
     pub timeouts: Timeouts,
+    pub bots: Bots,
+    #[serde(default)]
+    pub server: Server,
 }
 
 impl Config {
+    /// Loads the environment's profile (`config/dev.toml` or `config/prod.toml`),
+    /// layers the config file (if set) on top of it and then
+    /// applies the [`ENV_PREFIX`] environment variables on top of both.
     pub fn new(config_path: Option<&str>, environment: Environment) -> Result<Config, Error> {
-        match (config_path, environment) {
-            (Some(path), _) => {
+        Self::with_vars(config_path, environment, std::env::vars())
+    }
+
+    /// Same as [`Config::new`] but the environment variables are passed instead.
+    pub fn with_vars(
+        config_path: Option<&str>,
+        environment: Environment,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, Error> {
+        let profile = match environment {
+            Environment::Development => DEVELOPMENT_TOML,
+            Environment::Production => PRODUCTION_TOML,
+        };
+        let mut value: toml::Value = toml::from_str(profile).map_err(Error::Toml)?;
+
+        let source = match config_path {
+            Some(path) => {
                 let content = std::fs::read_to_string(path).map_err(Error::Io)?;
+                merge_toml(&mut value, toml::from_str(&content).map_err(Error::Toml)?);
+
+                ConfigSource::File {
+                    path: path.to_string(),
+                    environment,
+                }
+            }
+            None => ConfigSource::Defaults { environment },
+        };
+
+        let mut overrides = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect::<Vec<_>>();
+        // apply them in a deterministic order
+        overrides.sort();
+
+        let mut env_overrides = vec![];
+        for (name, raw) in overrides {
+            let path = env_path(&name[ENV_PREFIX.len()..]);
+
+            // set after each variable, in order to know which one is wrong
+            if let Err(source) = set_env_value(&mut value, &path, &raw) {
+                return Err(Error::EnvVar { name, source });
+            }
+            if is_unknown_field(&value, &path) {
+                return Err(Error::UnknownEnvVar(name));
+            }
+
+            env_overrides.push(name);
+        }
+
+        let mut config: Config = value.try_into().map_err(Error::Toml)?;
+        config.source = source;
+        config.env_overrides = env_overrides;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Loads the config again from its [`ConfigSource`] and the current environment variables,
+    /// e.g. after the config file has changed
+    pub fn reload(&self) -> Result<Config, Error> {
+        match &self.source {
+            ConfigSource::Defaults { environment } => Self::new(None, *environment),
+            ConfigSource::File { path, environment } => Self::new(Some(path), *environment),
+        }
+    }
+
+    /// The config file to which the Validators are persisted, see [`Config::persist_validators`].
+    /// - [`Error::NoConfigFile`] - if the config is loaded only from the environment's profile
+    /// - [`Error::ValidatorsOverridden`] - if the `validators` are overridden by an environment variable,
+    ///   i.e. the ones in the file wouldn't be used
+    pub fn validators_file(&self) -> Result<&str, Error> {
+        let validators_var = format!("{}VALIDATORS", ENV_PREFIX);
+        if self.env_overrides.contains(&validators_var) {
+            return Err(Error::ValidatorsOverridden(validators_var));
+        }
+
+        match &self.source {
+            ConfigSource::File { path, .. } => Ok(path),
+            ConfigSource::Defaults { .. } => Err(Error::NoConfigFile),
+        }
+    }
+
+    /// Replaces the `validators` of the config file (see [`Config::validators_file`]), e.g. with the ones changed at runtime,
+    /// so they are used after a restart. The rest of the values in the file are kept, but not its comments & formatting.
+    ///
+    /// The file is replaced with a temporary one written next to it, so it's never left half-written.
+    pub fn persist_validators(&self, validators: &HashSet<ApiUrl>) -> Result<(), Error> {
+        let path = self.validators_file()?;
+
+        let content = std::fs::read_to_string(path).map_err(Error::Io)?;
+        let mut file: toml::value::Table = toml::from_str(&content).map_err(Error::Toml)?;
+
+        let mut urls = validators
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        urls.sort();
+        file.insert(
+            "validators".to_string(),
+            toml::Value::Array(urls.into_iter().map(toml::Value::String).collect()),
+        );
+        // the `Value` serializes the tables after the rest of the values, as TOML requires
+        let content = toml::to_string(&toml::Value::Table(file))?;
+
+        let temporary = format!("{}.tmp", path);
+        std::fs::write(&temporary, content).map_err(Error::Io)?;
+        std::fs::rename(&temporary, path).map_err(Error::Io)
+    }
+
+    /// Validates the values which depend on each other:
+    ///
+    /// - every per-validator timeout override should be shorter than the Cache operation timeouts
+    /// - the [`Server`] settings, see [`Server::validate`]
+    /// - the `keep_warm` interval should not be `0`
+    /// - the [`Prewarm`] settings, see [`Prewarm::validate`]
+    /// - the [`NegativeSlots`] settings, see [`NegativeSlots::validate`]
+    /// - the proxy headers should be valid and not protected, see [`ProxyHeaders::headers`]
+    /// - the [`ProxyPaths`] prefixes should start with `/`
+    /// - the [`Sampling`] settings, see [`Sampling::validate`]
+    /// - the [`ClientDeadline`] bounds, see [`ClientDeadline::validate`]
+    /// - the [`Network`]s, see [`Config::validate_networks`]
+    /// - when the [`AnomalyBlocking`] is enabled, its `window`, `block_duration` & `max_keys` should not be `0`
+    /// - the [`ServeStats`] `flush_interval`, `retention_hours` & `max_campaigns` should not be `0`
+    /// - the [`Selection`] `round_robin_ttl` & `round_robin_max_slots` should not be `0`
+    /// - the [`Idempotency`] `window` & `max_keys` should not be `0`
+    pub fn validate(&self) -> Result<(), Error> {
+        self.server.validate()?;
+
+        if self.keep_warm.enabled && self.keep_warm.interval == Duration::from_secs(0) {
+            return Err(Error::KeepWarm);
+        }
+        self.prewarm.validate()?;
+        self.negative_slots.validate()?;
+        self.proxy.headers()?;
+        let paths = &self.proxy.paths;
+        if let Some(prefix) = paths
+            .allowed
+            .iter()
+            .chain(paths.denied.iter())
+            .find(|prefix| !prefix.starts_with('/'))
+        {
+            return Err(Error::ProxyPath(prefix.clone()));
+        }
+
+        if self.access_log.path.is_some() && self.access_log.buffer == 0 {
+            return Err(Error::AccessLog);
+        }
+        self.sampling.validate()?;
+        self.timeouts.client_deadline.validate()?;
+        self.validate_networks()?;
+
+        if self.channel_list.page_concurrency == 0 || self.channel_list.max_pages == 0 {
+            return Err(Error::ChannelList);
+        }
+        if !(0.0..=1.0).contains(&self.initialization.ready_fraction) {
+            return Err(Error::Initialization);
+        }
+        let anomaly_blocking = &self.anomaly_blocking;
+        if anomaly_blocking.enabled
+            && (anomaly_blocking.window == Duration::from_secs(0)
+                || anomaly_blocking.block_duration == Duration::from_secs(0)
+                || anomaly_blocking.max_keys == 0)
+        {
+            return Err(Error::AnomalyBlocking);
+        }
+        let serve_stats = &self.serve_stats;
+        if serve_stats.flush_interval == Duration::from_secs(0)
+            || serve_stats.retention_hours == 0
+            || serve_stats.max_campaigns == 0
+        {
+            return Err(Error::ServeStats);
+        }
+        if self.selection.round_robin_ttl == Duration::from_secs(0)
+            || self.selection.round_robin_max_slots == 0
+        {
+            return Err(Error::Selection);
+        }
+        if self.idempotency.window == Duration::from_secs(0) || self.idempotency.max_keys == 0 {
+            return Err(Error::Idempotency);
+        }
+
+        let cache_timeout = std::cmp::min(
+            self.timeouts.cache_update_campaign_statuses,
+            self.timeouts.cache_fetch_campaigns_from_market,
+        );
+
+        match self
+            .timeouts
+            .validators
+            .iter()
+            .find(|(_, timeout)| **timeout >= cache_timeout)
+        {
+            Some((validator, timeout)) => Err(Error::ValidatorTimeout {
+                validator: validator.clone(),
+                timeout: *timeout,
+                cache_timeout,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The names of the [`Network`]s should be lowercase alphanumeric (incl. `-` & `_`) and should not be
+    /// the first segment of a Supermarket route (e.g. `units-for-slot` or `stats`), their `hosts` should be unique.
+    fn validate_networks(&self) -> Result<(), Error> {
+        let invalid = |name: &str, reason: &str| Error::Network {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        };
+        let reserved = [
+            crate::ROUTE_UNITS_FOR_SLOT,
+            crate::ROUTE_HEALTHZ,
+            crate::ROUTE_READYZ,
+            crate::ROUTE_METRICS,
+            crate::ROUTE_VERSION,
+            crate::ROUTE_CAMPAIGNS,
+            crate::ROUTE_SLOTS,
+            crate::ROUTE_STATS,
+            crate::ROUTE_CONFIG,
+            crate::ROUTE_VALIDATORS,
+            crate::ROUTE_INTERNAL,
+        ];
+
+        let mut hosts = HashSet::new();
+        for (name, network) in self.networks.iter() {
+            let is_valid = !name.is_empty()
+                && name.bytes().all(|byte| {
+                    byte.is_ascii_lowercase()
+                        || byte.is_ascii_digit()
+                        || byte == b'-'
+                        || byte == b'_'
+                });
+            if !is_valid {
+                return Err(invalid(
+                    name,
+                    "the name should be lowercase alphanumeric, `-` or `_`",
+                ));
+            }
+            if name == crate::network::DEFAULT_NETWORK
+                || reserved
+                    .iter()
+                    .any(|route| route.split('/').nth(1) == Some(name.as_str()))
+            {
+                return Err(invalid(name, "the name is reserved"));
+            }
+
+            for host in network.hosts.iter() {
+                if !hosts.insert(host.to_ascii_lowercase()) {
+                    return Err(invalid(
+                        name,
+                        &format!("the host `{}` is already used", host),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The Config of the `name`d [`Network`]: its Market, Validators and intervals replace the default ones
+    /// and it has no other networks. `None` if there's no such network.
+    pub fn network(&self, name: &str) -> Option<Config> {
+        let network = self.networks.get(name)?;
+
+        let mut config = self.clone();
+        config.market.url = network.market_url.clone();
+        config.validators = network.validators.clone();
+        if let Some(fetch_campaigns_every) = network.fetch_campaigns_every {
+            config.fetch_campaigns_every = fetch_campaigns_every;
+        }
+        if let Some(update_campaigns_every) = network.update_campaigns_every {
+            config.update_campaigns_every = update_campaigns_every;
+        }
+        config.networks.clear();
+
+        Some(config)
+    }
+}
+
+/// Deeply merges the `overlay` Tables into the `base` ones, any other values (incl. Arrays) are replaced.
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// The path of the field of the environment variable (without the [`ENV_PREFIX`]),
+/// e.g. `SLOT_OVERRIDES__QmVw...__BLOCKED` is `["slot_overrides", "QmVw...", "blocked"]`, see [`ENV_MAP_FIELDS`]
+fn env_path(name: &str) -> Vec<String> {
+    let mut path: Vec<String> = vec![];
+    for segment in name.split(ENV_NESTED_SEPARATOR) {
+        let is_map_key = ENV_MAP_FIELDS.iter().any(|map| {
+            map.len() == path.len()
+                && map
+                    .iter()
+                    .zip(&path)
+                    .all(|(field, segment)| *field == "*" || field == segment)
+        });
+
+        if is_map_key {
+            path.push(segment.to_string());
+        } else {
+            path.push(segment.to_lowercase());
+        }
+    }
+
+    path
+}
+
+/// Sets the raw value of the environment variable on the `path` of the `value`,
+/// as the first of the following which the [`Config`] field can be deserialized from:
+///
+/// - an Integer, Float or Boolean, if the raw value can be parsed to it
+/// - a String, e.g. durations like `5m`
+/// - an Array of the `,` separated Strings, e.g. `https://a,https://b`
+///
+/// So the type comes from the field, even when it's not set in the toml.
+/// If none of them can be deserialized, the String is set and its error is returned.
+fn set_env_value(
+    value: &mut toml::Value,
+    path: &[String],
+    raw: &str,
+) -> Result<(), toml::de::Error> {
+    use toml::Value;
+
+    let mut candidates = vec![];
+    candidates.extend(raw.parse().map(Value::Integer).ok());
+    candidates.extend(raw.parse().map(Value::Float).ok());
+    candidates.extend(raw.parse().map(Value::Boolean).ok());
+    candidates.push(Value::String(raw.to_string()));
+    candidates.push(Value::Array(
+        raw.split(',')
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .map(|element| Value::String(element.to_string()))
+            .collect(),
+    ));
+
+    let mut string_error = None;
+    for candidate in candidates {
+        let is_string = candidate.is_str();
+        let mut overridden = value.clone();
+        if let Value::Table(table) = &mut overridden {
+            insert_env_value(table, path, candidate);
+        }
+
+        match overridden.clone().try_into::<Config>() {
+            Ok(_) => {
+                *value = overridden;
+                return Ok(());
+            }
+            Err(error) if is_string => string_error = Some((overridden, error)),
+            Err(_) => {}
+        }
+    }
+
+    let (overridden, error) = string_error.expect("The String is always a candidate");
+    *value = overridden;
+
+    Err(error)
+}
+
+fn insert_env_value(table: &mut toml::value::Table, path: &[String], value: toml::Value) {
+    let (field, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
 
-                toml::from_str(&content).map_err(Error::Toml)
+    if rest.is_empty() {
+        table.insert(field.clone(), value);
+    } else {
+        match table.get_mut(field) {
+            Some(toml::Value::Table(nested)) => insert_env_value(nested, rest, value),
+            _ => {
+                let mut nested = toml::value::Table::new();
+                insert_env_value(&mut nested, rest, value);
+
+                table.insert(field.clone(), toml::Value::Table(nested));
             }
-            (None, Environment::Development) => Ok(DEVELOPMENT.clone()),
-            (None, Environment::Production) => Ok(PRODUCTION.clone()),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Whether the `path` (or one of its parents) is not a field of the [`Config`], e.g. a misspelled environment variable
+fn is_unknown_field(value: &toml::Value, path: &[String]) -> bool {
+    let mut unknown = vec![];
+    let _ = serde_ignored::deserialize::<_, _, Config>(value.clone(), |ignored| {
+        unknown.push(field_path(&ignored))
+    });
+
+    unknown.iter().any(|ignored| path.starts_with(ignored))
+}
+
+fn field_path(path: &serde_ignored::Path<'_>) -> Vec<String> {
+    use serde_ignored::Path;
+
+    match path {
+        Path::Root => vec![],
+        Path::Seq { parent, index } => {
+            let mut fields = field_path(parent);
+            fields.push(index.to_string());
+            fields
+        }
+        Path::Map { parent, key } => {
+            let mut fields = field_path(parent);
+            fields.push(key.clone());
+            fields
+        }
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => field_path(parent),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Market {
+    /// The default Market URL, the `--marketUrl` CLI option takes precedence over it
+    pub url: ApiUrl,
     /// Duration specified will be the time to remain idle before sending a TCP keepalive probe.
     /// Applied to:
     /// - [`market::Proxy`](crate::market::Proxy) on the [`hyper::Client`](hyper::Client) (see [`hyper::client::Builder::http2_keep_alive_interval`](hyper::client::Builder::http2_keep_alive_interval))
     /// - The [`MarketApi`](crate::MarketApi) and it's [`reqwest::Client`] (see [`reqwest::ClientBuilder::tcp_keepalive`](reqwest::ClientBuilder::tcp_keepalive))
-    #[serde(deserialize_with = "seconds_to_std_duration")]
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
     pub keep_alive_interval: Duration,
+    /// The `Retry-After` of the units-for-slot responses while the AdSlot can't be fetched because the Market is unavailable,
+    /// see [`SlotFetchError`](crate::units_for_slot::SlotFetchError)
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub retry_after: Duration,
+    #[serde(default)]
+    pub verify_market_on_start: VerifyMarketOnStart,
+    /// In bytes - the bodies of the requests to the Market (see [`MarketApi::post_json`](crate::MarketApi::post_json))
+    /// larger than this are sent with `Content-Encoding: gzip`, if not set they are never compressed
+    #[serde(default)]
+    pub gzip_requests_over: Option<usize>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct Limits {
+/// Probing the Market on startup, see [`MarketApi::verify_on_start`](crate::MarketApi::verify_on_start)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyMarketOnStart {
+    pub enabled: bool,
+    /// Abort the startup if the Market is unreachable, otherwise only log it
     #[serde(default)]
-    pub limited_identity_earnings_limit: Option<BigNum>,
-    pub max_channels_earning_from: u16,
-    pub global_min_impression_price: BigNum,
+    pub strict: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct Timeouts {
-    #[serde(deserialize_with = "seconds_to_std_duration")]
-    /// Timeout Duration for the Cache updating all campaigns statuses
-    /// by querying the validators and etc.
-    pub cache_update_campaign_statuses: Duration,
-    #[serde(deserialize_with = "seconds_to_std_duration")]
-    /// Timeout Duration for the Cache fetching new campaigns from the Market
-    pub cache_fetch_campaigns_from_market: Duration,
-    #[serde(deserialize_with = "seconds_to_std_duration")]
-    /// Timeout for querying a single Validator endpoint
-    pub validator_request: Duration,
+/// Keeping the pooled connections to the Market and the Validators alive,
+/// see [`keep_warm`](crate::keep_warm::keep_warm)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepWarm {
+    pub enabled: bool,
+    /// How often each of them is requested, it should be shorter than their idle connection timeouts
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub interval: Duration,
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    #[error("File reading: {0}")]
-    Io(#[from] std::io::Error),
-    #[error("Toml parsing: {0}")]
-    Toml(#[from] toml::de::Error),
-    #[error(
-        "Environment can only be {} or {}, actual: {actual}",
-        Environment::Development,
-        Environment::Production
+impl Default for KeepWarm {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Checking in the background whether the `ipfs://` media of the served AdUnits can be fetched from the `ipfs_gateway`,
+/// see [`media_check`](crate::units_for_slot::media_check)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct MediaCheck {
+    pub enabled: bool,
+    /// For how long the reachability of a media URL is known before it's checked again
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
     )]
-    Environment { actual: String },
+    pub ttl: Duration,
+    /// At most this many media URLs are checked per second, one at a time
+    pub max_checks_per_second: u32,
+    /// The timeout of a single `HEAD` request (in milliseconds)
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    pub timeout: Duration,
+    pub policy: UnreachableMediaPolicy,
 }
 
-fn seconds_to_std_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    use serde::de::Error;
-    use std::convert::TryFrom;
-    use toml::Value;
+impl MediaCheck {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled && self.max_checks_per_second > 0
+    }
 
-    let toml_value: Value = Value::deserialize(deserializer)?;
+    /// The interval between the checks by the `max_checks_per_second`
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.max_checks_per_second.max(1)
+    }
+}
 
-    let seconds = match toml_value {
-        Value::Integer(secs) => u64::try_from(secs).map_err(Error::custom),
-        _ => Err(Error::custom("Only integers allowed for this value")),
-    }?;
+impl Default for MediaCheck {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl: Duration::from_secs(10 * 60),
+            max_checks_per_second: 5,
+            timeout: Duration::from_millis(2_000),
+            policy: UnreachableMediaPolicy::default(),
+        }
+    }
+}
 
-    Ok(Duration::from_secs(seconds))
+/// What happens to the AdUnits whose media is known to be unreachable, see [`MediaCheck`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnreachableMediaPolicy {
+    /// They are ranked below all of the reachable ones
+    Demote,
+    /// They are not served
+    Exclude,
+}
+
+impl Default for UnreachableMediaPolicy {
+    fn default() -> Self {
+        Self::Demote
+    }
+}
+
+/// Caching the AdSlots (with their AdUnits) fetched from the Market and refreshing the most requested ones
+/// in the background before they expire, see [`prewarm`](crate::units_for_slot::prewarm)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct Prewarm {
+    /// For how long a fetched AdSlot and its AdUnits are reused, `0` disables the caching
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub slot_cache_ttl: Duration,
+    /// How many of the most requested AdSlots are refreshed in the background, `0` disables it
+    pub top_slots: usize,
+    /// How long before the `slot_cache_ttl` expires they are refreshed, also the interval of the refreshing
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub refresh_margin: Duration,
+}
+
+impl Prewarm {
+    /// Whether the most requested AdSlots are refreshed in the background
+    pub fn is_enabled(&self) -> bool {
+        self.top_slots > 0 && self.slot_cache_ttl > Duration::from_secs(0)
+    }
+
+    /// When enabled, the `refresh_margin` should be longer than `0` and shorter than the `slot_cache_ttl`
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.is_enabled()
+            && (self.refresh_margin == Duration::from_secs(0)
+                || self.refresh_margin >= self.slot_cache_ttl)
+        {
+            Err(Error::Prewarm {
+                refresh_margin: self.refresh_margin,
+                slot_cache_ttl: self.slot_cache_ttl,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for Prewarm {
+    fn default() -> Self {
+        Self {
+            slot_cache_ttl: Duration::from_secs(0),
+            top_slots: 0,
+            refresh_margin: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Caching the `404 Not Found` of the AdSlots which are not in the Market and revalidating the recent ones
+/// in the background, see [`negative_slots`](crate::units_for_slot::negative_slots)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct NegativeSlots {
+    /// For how long a `404` of the Market is served without requesting the AdSlot again, `0` disables the caching
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub ttl: Duration,
+    /// At most this many of the most recently missed AdSlots are revalidated, `0` disables the revalidation
+    pub queue_size: usize,
+    /// The first revalidation is this long after the first miss and the interval doubles after each one,
+    /// also the interval of the revalidator
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub first_interval: Duration,
+    /// For how long after the first miss the AdSlot is revalidated
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub revalidate_for: Duration,
+}
+
+impl NegativeSlots {
+    /// Whether the negative cached AdSlots are revalidated in the background
+    pub fn is_revalidated(&self) -> bool {
+        self.queue_size > 0 && self.ttl > Duration::from_secs(0)
+    }
+
+    /// When revalidated, the `first_interval` should be longer than `0` and not longer than the `revalidate_for`
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.is_revalidated()
+            && (self.first_interval == Duration::from_secs(0)
+                || self.first_interval > self.revalidate_for)
+        {
+            Err(Error::NegativeSlots {
+                first_interval: self.first_interval,
+                revalidate_for: self.revalidate_for,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for NegativeSlots {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(0),
+            queue_size: 1_000,
+            first_interval: Duration::from_secs(5),
+            revalidate_for: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Fetching the pages of the Validators' `/channel/list`,
+/// see [`SentryApi::get_validator_channels`](crate::SentryApi::get_validator_channels)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChannelList {
+    /// How many pages (after the first one) of a Validator are requested at the same time
+    pub page_concurrency: usize,
+    /// The pages after this many are not fetched (and a warning is logged)
+    pub max_pages: u64,
+    /// Only the Channels of this creator are fetched
+    pub creator: Option<String>,
+    /// Only the Channels with this Validator are fetched
+    pub validator: Option<ValidatorId>,
+}
+
+impl Default for ChannelList {
+    fn default() -> Self {
+        Self {
+            page_concurrency: 4,
+            max_pages: 100,
+            creator: None,
+            validator: None,
+        }
+    }
+}
+
+/// Serving the Campaigns while the Cache is initialized,
+/// see [`Cache::fetch_initial_campaigns`](crate::Cache::fetch_initial_campaigns)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Initialization {
+    /// The Cache is ready (`/readyz`) once this share of the Validators (from `0` to `1`)
+    /// have returned at least one page of Channels, `1` waits for all of them
+    pub ready_fraction: f64,
+}
+
+impl Default for Initialization {
+    fn default() -> Self {
+        Self {
+            ready_fraction: 0.5,
+        }
+    }
+}
+
+/// Temporarily blocking the client IPs & the AdSlots (of the units-for-slot) with too many `4xx` responses,
+/// see [`anomaly`](crate::anomaly)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct AnomalyBlocking {
+    pub enabled: bool,
+    /// The `4xx` responses are counted within a window of this long, then the count starts over.
+    /// Also the interval of the compaction of the counters
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub window: Duration,
+    /// A client IP is blocked once it has this many `4xx` responses within a window, `0` never blocks
+    pub ip_threshold: u32,
+    /// An AdSlot is blocked once it has this many `4xx` responses within a window, `0` never blocks
+    pub slot_threshold: u32,
+    /// For how long the blocked client IPs & AdSlots get `429 Too Many Requests`
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub block_duration: Duration,
+    /// At most this many client IPs and this many AdSlots are counted
+    pub max_keys: usize,
+}
+
+impl Default for AnomalyBlocking {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window: Duration::from_secs(60),
+            ip_threshold: 100,
+            slot_threshold: 500,
+            block_duration: Duration::from_secs(5 * 60),
+            max_keys: 100_000,
+        }
+    }
+}
+
+/// Counting how many times the units of each Campaign were served by the units-for-slot,
+/// see [`serve_stats`](crate::units_for_slot::serve_stats)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct ServeStats {
+    /// How often the counts of the requests are flushed into the hourly buckets and the metrics
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub flush_interval: Duration,
+    /// How many hourly buckets are kept (incl. the current one)
+    pub retention_hours: u32,
+    /// At most this many Campaigns are tracked, the least recently served one is dropped for a new one
+    pub max_campaigns: usize,
+}
+
+impl Default for ServeStats {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_secs(10),
+            retention_hours: 24,
+            max_campaigns: 10_000,
+        }
+    }
+}
+
+/// The order in which the matched units are served,
+/// see [`selection`](crate::units_for_slot::selection)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Selection {
+    /// The strategy of the requests which don't select one with `?strategy=`
+    pub default: Strategy,
+    /// The strategies the requests can select with `?strategy=` besides the `default`, the rest are refused
+    pub allowed: Vec<Strategy>,
+    /// The `round_robin` position of an AdSlot starts over once it isn't requested for this long
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub round_robin_ttl: Duration,
+    /// At most this many AdSlots (per AdUnit type) have a `round_robin` position,
+    /// the rest start from the first unit until the positions of others expire
+    pub round_robin_max_slots: usize,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            default: Strategy::HighestPrice,
+            allowed: vec![],
+            round_robin_ttl: Duration::from_secs(600),
+            round_robin_max_slots: 10_000,
+        }
+    }
+}
+
+/// Normalizing the tags of the AdSlots and of the targeting rules of the Campaigns before they're matched,
+/// see [`tags`](crate::units_for_slot::tags)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Tags {
+    /// Applied to the tags after the NFC normalization (which is always applied)
+    pub case_folding: CaseFolding,
+}
+
+/// How the case of the tags is normalized, see [`Tags`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseFolding {
+    /// The tags keep their case, e.g. the `IAB3` category
+    None,
+    /// The tags are lowercased (in any script), so `Кино` & `КИНО` are the same tag
+    Lowercase,
+}
+
+impl Default for CaseFolding {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// Replaying the responses of the mutating admin routes to the retries with the same `Idempotency-Key`,
+/// see [`idempotency`](crate::admin::idempotency)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Idempotency {
+    /// For how long the response of a key is replayed
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    pub window: Duration,
+    /// At most this many keys are remembered, the oldest completed one makes room for a new one
+    pub max_keys: usize,
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(3600),
+            max_keys: 10_000,
+        }
+    }
+}
+
+/// Fetching the `last-approved` of many Channels of a Validator in a single request,
+/// see [`SentryApi::prefetch_last_approved`](crate::SentryApi::prefetch_last_approved)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LastApprovedBatch {
+    /// At most this many Channels are requested at once, `0` disables the batching
+    pub batch_size: usize,
+}
+
+impl Default for LastApprovedBatch {
+    fn default() -> Self {
+        Self { batch_size: 50 }
+    }
+}
+
+/// The aggregates of the Active Campaigns on the `/stats` route and in the metrics,
+/// see [`CacheStats`](crate::cache::stats::CacheStats)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Stats {
+    /// The deposit assets (addresses) labeled in the metrics, the rest are summed under `other`
+    pub assets: HashSet<String>,
+}
+
+/// The headers of the requests proxied to the Market and the size of their responses, see [`Proxy`](crate::market::Proxy)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ProxyHeaders {
+    /// Set on every proxied request, replacing the incoming ones with the same name.
+    /// `{version}` in the values is replaced with the version of the Supermarket, e.g. `adex-supermarket/{version}`
+    pub extra_headers: BTreeMap<String, String>,
+    /// Replaces the `User-Agent` of the proxied requests, if not set the incoming one is preserved
+    pub user_agent: Option<String>,
+    /// The `Host` of the proxied requests, see [`HostHeader`]
+    pub host: HostHeader,
+    /// The max size (in bytes) of a proxied response body, the larger ones are truncated.
+    /// If not set the responses are not limited
+    pub max_response_bytes: Option<u64>,
+    /// Validating the submitted events before they are proxied, see [`EventValidation`]
+    pub events: EventValidation,
+    /// Which paths are proxied, see [`ProxyPaths`]
+    pub paths: ProxyPaths,
+}
+
+/// The path prefixes which are proxied to the Market (the rest are `403 Forbidden`),
+/// matched against the decoded & normalized path, see [`paths`](crate::market::paths)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ProxyPaths {
+    /// Every path which isn't `denied` is proxied, regardless of the `allowed`
+    pub open_proxy: bool,
+    /// The prefixes of the proxied paths, e.g. `/units` covers `/units` & `/units/:ipfs` but not `/unitsadmin`
+    pub allowed: Vec<String>,
+    /// The prefixes of the paths which are never proxied, even if they are `allowed`
+    pub denied: Vec<String>,
+}
+
+impl Default for ProxyPaths {
+    /// The routes of the Market used by the SDK & the Platform, incl. the event submissions (see [`EventValidation`])
+    fn default() -> Self {
+        Self {
+            open_proxy: false,
+            allowed: ["/units", "/slots", "/campaigns", "/tags", "/channel"]
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            denied: vec![],
+        }
+    }
+}
+
+/// Validating the body of the events submitted through the proxy (`POST /channel/:id/events`),
+/// see [`events`](crate::market::events)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct EventValidation {
+    /// The malformed submissions are refused with `400 Bad Request` instead of being proxied,
+    /// if disabled (the default) they are proxied as they are
+    pub enabled: bool,
+    /// The larger bodies are refused with `413 Payload Too Large`, only the bodies of the submissions are buffered
+    pub max_body_bytes: u64,
+}
+
+impl Default for EventValidation {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_body_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// The `Host` header of the requests proxied to the Market
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostHeader {
+    /// Replaced with the authority of the `market_url` (incl. a non-default port),
+    /// e.g. for a Market behind an ingress routing on the `Host`
+    Rewrite,
+    /// The incoming `Host` is kept, without one the authority of the `market_url` is used
+    Preserve,
+}
+
+impl Default for HostHeader {
+    fn default() -> Self {
+        Self::Rewrite
+    }
+}
+
+impl ProxyHeaders {
+    /// The hop-by-hop headers, the `Host` and the framing headers, which are set only by the [`Proxy`](crate::market::Proxy).
+    /// The `User-Agent` is set by `user_agent` instead.
+    pub const PROTECTED: [&'static str; 12] = [
+        "connection",
+        "keep-alive",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "proxy-connection",
+        "te",
+        "trailer",
+        "transfer-encoding",
+        "upgrade",
+        "host",
+        "content-length",
+        "user-agent",
+    ];
+
+    /// The `extra_headers` and the `User-Agent` (if set) with the `{version}` replaced
+    pub fn headers(&self) -> Result<Vec<(HeaderName, HeaderValue)>, Error> {
+        let mut headers = vec![];
+        for (name, value) in self.extra_headers.iter() {
+            let header_name = HeaderName::from_str(name).map_err(|_| Error::ProxyHeader {
+                name: name.clone(),
+                reason: "invalid header name".to_string(),
+            })?;
+            if Self::PROTECTED.contains(&header_name.as_str()) {
+                return Err(Error::ProxyHeader {
+                    name: name.clone(),
+                    reason: "hop-by-hop, `Host`, framing and `User-Agent` headers can't be set"
+                        .to_string(),
+                });
+            }
+
+            headers.push((header_name, header_value(name, value)?));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            headers.push((USER_AGENT, header_value(USER_AGENT.as_str(), user_agent)?));
+        }
+
+        Ok(headers)
+    }
+}
+
+/// Only visible ASCII characters are allowed in the values
+fn header_value(name: &str, value: &str) -> Result<HeaderValue, Error> {
+    let value = value.replace("{version}", crate::build_info::VERSION);
+
+    match HeaderValue::from_str(&value) {
+        Ok(header_value) if value.is_ascii() => Ok(header_value),
+        _ => Err(Error::ProxyHeader {
+            name: name.to_string(),
+            reason: format!("invalid header value `{}`", value),
+        }),
+    }
+}
+
+/// The `Cache-Control: max-age` of the units-for-slot responses
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct CacheControl {
+    /// In seconds, if not set the responses have no `Cache-Control` (unless overridden by the `empty_max_age`)
+    pub max_age: Option<u64>,
+    /// In seconds, overrides the `max_age` of the responses without matched units by their `reason`,
+    /// e.g. a short one for `no_active_campaigns`, so they aren't cached for long by the edge
+    pub empty_max_age: BTreeMap<EmptyReason, u64>,
+}
+
+impl CacheControl {
+    /// The shortest `max-age` of the responses (one per requested type) by their [`EmptyReason`],
+    /// `None` if there's neither a `max_age` nor an `empty_max_age` of their reasons.
+    pub fn max_age(&self, reasons: impl IntoIterator<Item = Option<EmptyReason>>) -> Option<u64> {
+        reasons
+            .into_iter()
+            .filter_map(|reason| {
+                reason
+                    .and_then(|reason| self.empty_max_age.get(&reason).copied())
+                    .or(self.max_age)
+            })
+            .min()
+    }
+}
+
+/// Writing the access log of the requests in the Combined Log Format,
+/// see [`access_log`](crate::access_log)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AccessLog {
+    /// The file the lines are appended to, if not set there's no access log
+    pub path: Option<PathBuf>,
+    /// How many lines can wait to be written, the rest are dropped
+    pub buffer: usize,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self {
+            path: None,
+            buffer: 4096,
+        }
+    }
+}
+
+/// Sampling the units-for-slot requests & responses for offline analysis,
+/// see [`sampling`](crate::units_for_slot::sampling)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Sampling {
+    /// The share of the requests which are sampled, from `0` (none, the default) to `1` (all)
+    pub rate: f64,
+    /// The JSONL file the samples are appended to
+    pub path: Option<PathBuf>,
+    /// The HTTP collector each sample is `POST`ed to (as a JSONL line), instead of the `path`
+    pub collector: Option<Url>,
+    /// How many samples can wait to be written, the rest are dropped
+    pub buffer: usize,
+    /// Keep the publisher address in the sampled targeting input, by default it's redacted
+    pub include_publisher: bool,
+    /// Seeds the sampling for reproducible samples, if not set it's seeded randomly
+    pub seed: Option<u64>,
+}
+
+impl Sampling {
+    /// Whether any of the requests are sampled
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// The `rate` should be from `0` to `1` and, when enabled,
+    /// there should be either a `path` or a `collector` and a `buffer` larger than `0`
+    pub fn validate(&self) -> Result<(), Error> {
+        let destinations = self.path.iter().count() + self.collector.iter().count();
+
+        if !(0.0..=1.0).contains(&self.rate)
+            || (self.is_enabled() && (destinations != 1 || self.buffer == 0))
+        {
+            Err(Error::Sampling)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            rate: 0.0,
+            path: None,
+            collector: None,
+            buffer: 1024,
+            include_publisher: false,
+            seed: None,
+        }
+    }
+}
+
+/// The [`SlotOverride`]s by the AdSlot ipfs
+pub type SlotOverrides = HashMap<String, SlotOverride>;
+
+/// The operator's override of a single AdSlot, consulted before anything else of its units-for-slot requests
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SlotOverride {
+    /// The requests are refused with `403 Forbidden`, the AdSlot isn't even fetched from the Market
+    pub blocked: bool,
+    /// The min IMPRESSION price by the deposit asset of the Campaigns,
+    /// it only tightens the `global_min_impression_price` (the higher of the two applies)
+    pub min_price: BTreeMap<String, BigNum>,
+    /// Only the fallback AdUnit of the AdSlot is served, the Campaigns are not matched at all
+    pub force_fallback: bool,
+}
+
+impl SlotOverride {
+    /// The min IMPRESSION price of the Campaigns with the `deposit_asset`
+    pub fn min_impression_price<'a>(
+        &'a self,
+        global: &'a BigNum,
+        deposit_asset: &str,
+    ) -> &'a BigNum {
+        match self.min_price.get(deposit_asset) {
+            Some(min_price) if min_price > global => min_price,
+            _ => global,
+        }
+    }
+}
+
+/// Another AdEx network served by the same Supermarket with its own Market, Validators and Cache.
+///
+/// Its requests are prefixed with `/<name>` (e.g. `/staging/units-for-slot/:ipfs`) or sent to one of its `hosts`,
+/// the rest of the config is shared with the default network, see [`Config::network`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    pub market_url: ApiUrl,
+    pub validators: HashSet<ApiUrl>,
+    /// Defaults to the `fetch_campaigns_every` of the default network
+    #[serde(
+        default,
+        deserialize_with = "option_seconds_to_std_duration",
+        serialize_with = "option_std_duration_to_seconds"
+    )]
+    pub fetch_campaigns_every: Option<Duration>,
+    /// Defaults to the `update_campaigns_every` of the default network
+    #[serde(
+        default,
+        deserialize_with = "option_seconds_to_std_duration",
+        serialize_with = "option_std_duration_to_seconds"
+    )]
+    pub update_campaigns_every: Option<Duration>,
+    /// The requests with one of these `Host`s (the port is ignored) are served by the network without the `/<name>` prefix
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Limits {
+    #[serde(default)]
+    pub limited_identity_earnings_limit: Option<BigNum>,
+    pub max_channels_earning_from: u16,
+    pub global_min_impression_price: BigNum,
+    /// Matched units with a lower targeting score are dropped,
+    /// can be overridden per request with `?minScore=`
+    pub min_targeting_score: f64,
+    /// The maximum number of Active Campaigns in the Cache,
+    /// the ones with the smallest remaining budget and least recent activity are evicted
+    #[serde(default)]
+    pub max_campaigns: Option<usize>,
+    /// The maximum approximate size (serialized JSON) of the Active Campaigns in the Cache
+    #[serde(default)]
+    pub max_cache_bytes: Option<usize>,
+    /// The maximum number of AdUnits fetched from the Market for an AdSlot,
+    /// the units-for-slot responses of the truncated ones have `truncated: true`
+    #[serde(default)]
+    pub max_units_per_slot: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bots {
+    /// What to do with the units-for-slot requests from suspected bots,
+    /// detected by their `User-Agent` or the `deny_list`
+    pub policy: BotPolicy,
+    /// The client IPs in these CIDRs are treated as bots, e.g. `66.249.64.0/19`
+    #[serde(default)]
+    pub deny_list: CidrSet,
+}
+
+/// How the units-for-slot requests are served while the Cache is degraded,
+/// i.e. it's stale, empty or all of its Active Campaigns are stale (see [`Cache::is_degraded`](crate::cache::Cache::is_degraded)),
+/// for example when all of the Validators are unreachable.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DegradationPolicy {
+    /// Serve only the Campaigns which aren't stale, as usual
+    Strict,
+    /// Serve the last known Campaigns regardless of the `max_campaign_staleness`,
+    /// with the `X-Degraded: stale-cache` header
+    ServeStale,
+    /// Serve only the fallback AdUnit of the AdSlot, with the `X-Degraded: fallback-only` header
+    FallbackOnly,
+}
+
+/// What happens to the discovered Campaigns whose Leader or Follower isn't on the [`Config::validator_allowlist`]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ValidatorAllowlistPolicy {
+    /// They are not added to the Cache
+    Skip,
+    /// They are kept in the Cache (e.g. for the `/campaigns` & the stats), but they are not served
+    Restrict,
+}
+
+impl Default for ValidatorAllowlistPolicy {
+    fn default() -> Self {
+        Self::Skip
+    }
+}
+
+impl Default for DegradationPolicy {
+    fn default() -> Self {
+        Self::Strict
+    }
+}
+
+impl DegradationPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Strict => "strict",
+            Self::ServeStale => "serve-stale",
+            Self::FallbackOnly => "fallback-only",
+        }
+    }
+
+    /// The value of the `X-Degraded` header of the responses served while the Cache is degraded
+    pub fn degraded_header(&self) -> Option<&'static str> {
+        match self {
+            Self::Strict => None,
+            Self::ServeStale => Some("stale-cache"),
+            Self::FallbackOnly => Some("fallback-only"),
+        }
+    }
+}
+
+/// The HTTP server settings, the defaults are the ones of [`hyper::Server`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Server {
+    /// Sets `TCP_NODELAY` on the accepted connections
+    pub tcp_nodelay: bool,
+    /// HTTP/1 keep-alive connections
+    pub http1_keepalive: bool,
+    /// Aggregates the flushes of pipelined HTTP/1 responses, requires `http1_keepalive`
+    pub http1_pipeline_flush: bool,
+    /// The maximum buffer size of a HTTP/1 connection, at least [`Server::MIN_BUF_SIZE`]
+    pub max_buf_size: usize,
+    /// Sleep for a second after connection accept errors (e.g. too many open files) instead of retrying immediately
+    pub sleep_on_errors: bool,
+    /// For how long to wait for the in-flight requests on shutdown,
+    /// if not set it waits until all of them are done.
+    #[serde(
+        deserialize_with = "option_seconds_to_std_duration",
+        serialize_with = "option_std_duration_to_seconds"
+    )]
+    pub shutdown_timeout: Option<Duration>,
+}
+
+impl Server {
+    /// The minimum `max_buf_size` allowed by [`hyper`]
+    pub const MIN_BUF_SIZE: usize = 8192;
+
+    /// - `max_buf_size` should be at least [`Server::MIN_BUF_SIZE`]
+    /// - `http1_pipeline_flush` requires `http1_keepalive`, otherwise there are no pipelined requests
+    /// - `shutdown_timeout` should not be `0`
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |reason: &str| {
+            Err(Error::Server {
+                reason: reason.to_string(),
+            })
+        };
+
+        if self.max_buf_size < Self::MIN_BUF_SIZE {
+            invalid("`max_buf_size` should be at least 8192 bytes")
+        } else if self.http1_pipeline_flush && !self.http1_keepalive {
+            invalid("`http1_pipeline_flush` requires `http1_keepalive`")
+        } else if self.shutdown_timeout == Some(Duration::from_secs(0)) {
+            invalid("`shutdown_timeout` should be longer than 0 seconds")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            tcp_nodelay: false,
+            http1_keepalive: true,
+            http1_pipeline_flush: false,
+            // the default of `hyper`
+            max_buf_size: 8192 + 4096 * 100,
+            sleep_on_errors: true,
+            shutdown_timeout: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Timeouts {
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    /// Timeout Duration for the Cache updating all campaigns statuses
+    /// by querying the validators and etc.
+    pub cache_update_campaign_statuses: Duration,
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    /// Timeout Duration for the Cache fetching new campaigns from the Market
+    pub cache_fetch_campaigns_from_market: Duration,
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    /// Timeout for querying a single Validator endpoint
+    pub validator_request: Duration,
+    #[serde(
+        deserialize_with = "seconds_to_std_duration",
+        serialize_with = "std_duration_to_seconds"
+    )]
+    /// Timeout for every outgoing request to the Market or the Validators
+    pub global_request: Duration,
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    /// Timeout (in milliseconds) for fetching the AdSlot from the Market for a units-for-slot request,
+    /// the request fails with `504 Gateway Timeout`
+    pub market_fetch_slot: Duration,
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    /// Timeout (in milliseconds) for fetching the AdUnits of the AdSlot from the Market for a units-for-slot request,
+    /// only the fallback AdUnit is served
+    pub market_fetch_units: Duration,
+    #[serde(
+        default,
+        deserialize_with = "validators_seconds_to_std_duration",
+        serialize_with = "validators_std_duration_to_seconds"
+    )]
+    /// Per-validator overrides of the `validator_request` timeout, keyed by the Validator URL.
+    /// They should be shorter than the Cache operation timeouts and are capped by the `global_request`.
+    pub validators: HashMap<ApiUrl, Duration>,
+    #[serde(default)]
+    pub client_deadline: ClientDeadline,
+}
+
+/// The bounds of the deadline the clients set for their units-for-slot requests with the `X-Request-Timeout-Ms` header,
+/// see [`deadline`](crate::units_for_slot::deadline)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ClientDeadline {
+    /// In milliseconds, the shorter client timeouts are raised to it
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    pub min: Duration,
+    /// In milliseconds, the longer client timeouts are lowered to it
+    #[serde(
+        deserialize_with = "milliseconds_to_std_duration",
+        serialize_with = "std_duration_to_milliseconds"
+    )]
+    pub max: Duration,
+}
+
+impl ClientDeadline {
+    /// The `min` should be longer than `0` and not longer than the `max`
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.min == Duration::from_secs(0) || self.min > self.max {
+            Err(Error::ClientDeadline {
+                min: self.min,
+                max: self.max,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for ClientDeadline {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_millis(50),
+            max: Duration::from_millis(5000),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("File reading: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Toml parsing: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Toml serializing: {0}")]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[error("The config is loaded from the defaults, there's no config file to persist the Validators to")]
+    NoConfigFile,
+    #[error("The Validators are overridden by the environment variable `{0}`, the ones in the config file aren't used")]
+    ValidatorsOverridden(String),
+    #[error("Environment variable `{name}`: {source}")]
+    EnvVar {
+        name: String,
+        source: toml::de::Error,
+    },
+    #[error("Environment variable `{0}` is not a config field")]
+    UnknownEnvVar(String),
+    #[error(
+        "Environment can only be {} or {}, actual: {actual}",
+        Environment::Development,
+        Environment::Production
+    )]
+    Environment { actual: String },
+    #[error("Timeout override of Validator `{validator}` ({timeout:?}) should be shorter than the Cache timeouts ({cache_timeout:?})")]
+    ValidatorTimeout {
+        validator: ApiUrl,
+        timeout: Duration,
+        cache_timeout: Duration,
+    },
+    #[error("Invalid server config: {reason}")]
+    Server { reason: String },
+    #[error("The `keep_warm` interval should be longer than 0 seconds")]
+    KeepWarm,
+    #[error("The `access_log` buffer should be larger than 0")]
+    AccessLog,
+    #[error("The `sampling` rate should be from 0 to 1 and, when it's larger than 0, there should be either a `path` or a `collector` and a buffer larger than 0")]
+    Sampling,
+    #[error("The `client_deadline` min ({min:?}) should be longer than 0 and not longer than its max ({max:?})")]
+    ClientDeadline { min: Duration, max: Duration },
+    #[error("The `channel_list` page_concurrency and max_pages should be larger than 0")]
+    ChannelList,
+    #[error("The `initialization` ready_fraction should be from 0 to 1")]
+    Initialization,
+    #[error("The `anomaly_blocking` window, block_duration and max_keys should be larger than 0")]
+    AnomalyBlocking,
+    #[error("The `serve_stats` flush_interval, retention_hours and max_campaigns should be larger than 0")]
+    ServeStats,
+    #[error("The `selection` round_robin_ttl and round_robin_max_slots should be larger than 0")]
+    Selection,
+    #[error("The `idempotency` window and max_keys should be larger than 0")]
+    Idempotency,
+    #[error("Proxy header `{name}`: {reason}")]
+    ProxyHeader { name: String, reason: String },
+    #[error("The proxy path prefix `{0}` should start with `/`")]
+    ProxyPath(String),
+    #[error("Network `{name}`: {reason}")]
+    Network { name: String, reason: String },
+    #[error("The prewarm `refresh_margin` ({refresh_margin:?}) should be longer than 0 and shorter than the `slot_cache_ttl` ({slot_cache_ttl:?})")]
+    Prewarm {
+        refresh_margin: Duration,
+        slot_cache_ttl: Duration,
+    },
+    #[error("The negative_slots `first_interval` ({first_interval:?}) should be longer than 0 and not longer than the `revalidate_for` ({revalidate_for:?})")]
+    NegativeSlots {
+        first_interval: Duration,
+        revalidate_for: Duration,
+    },
+}
+
+fn default_proxy_enabled() -> bool {
+    true
+}
+
+fn seconds_to_std_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+    use std::convert::TryFrom;
+    use toml::Value;
+
+    let toml_value: Value = Value::deserialize(deserializer)?;
+
+    let seconds = match toml_value {
+        Value::Integer(secs) => u64::try_from(secs).map_err(Error::custom),
+        Value::String(duration) => parse_seconds(&duration).map_err(Error::custom),
+        _ => Err(Error::custom(
+            "Only integers (seconds) or strings with a unit (e.g. `30s`, `5m`, `1h`) allowed for this value",
+        )),
+    }?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parses seconds with an optional unit - `s` (default), `m` or `h`, e.g. `30`, `30s`, `5m`, `1h`
+fn parse_seconds(duration: &str) -> Result<u64, String> {
+    let duration = duration.trim();
+    let (number, multiplier) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        _ => (duration, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid duration `{}`", duration))
+}
+
+fn option_seconds_to_std_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Seconds(#[serde(deserialize_with = "seconds_to_std_duration")] Duration);
+
+    Ok(Option::<Seconds>::deserialize(deserializer)?.map(|Seconds(duration)| duration))
+}
+
+fn option_std_duration_to_seconds<S>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match duration {
+        Some(duration) => serializer.serialize_some(&duration.as_secs()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn validators_seconds_to_std_duration<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<ApiUrl, Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Seconds(#[serde(deserialize_with = "seconds_to_std_duration")] Duration);
+
+    let validators = HashMap::<ApiUrl, Seconds>::deserialize(deserializer)?;
+
+    Ok(validators
+        .into_iter()
+        .map(|(validator, Seconds(duration))| (validator, duration))
+        .collect())
+}
+
+fn validators_std_duration_to_seconds<S>(
+    validators: &HashMap<ApiUrl, Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(
+        validators
+            .iter()
+            .map(|(validator, duration)| (validator, duration.as_secs())),
+    )
+}
+
+fn std_duration_to_seconds<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u64(duration.as_secs())
+}
+
+fn milliseconds_to_std_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+}
+
+fn std_duration_to_milliseconds<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use std::convert::TryFrom;
+
+    serializer.serialize_u64(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let token = "super-secret-admin-token";
+        let mut config = DEVELOPMENT.clone();
+        config.admin_token = Some(Secret::from(token.to_string()));
+        let dsn = "https://public_key@o0.ingest.sentry.io/0";
+        config.sentry_dsn = Some(Secret::from(dsn.to_string()));
+
+        let json = serde_json::to_string(&config).expect("Should serialize");
+        assert!(!json.contains(token));
+        assert!(!json.contains(dsn));
+
+        let serialized: serde_json::Value =
+            serde_json::from_str(&json).expect("Should deserialize");
+        assert_eq!(REDACTED, serialized["admin_token"]);
+        assert_eq!(REDACTED, serialized["sentry_dsn"]);
+        assert_eq!("defaults", serialized["source"]["type"]);
+        assert_eq!("development", serialized["source"]["environment"]);
+
+        assert!(!format!("{:?}", config).contains(token));
+        assert_eq!(token, config.admin_token.expect("Should be set").expose());
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_vars_override_list_values() {
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[(
+                "SUPERMARKET_VALIDATORS",
+                "https://a.adex.network/, https://b.adex.network/",
+            )]),
+        )
+        .expect("Should load config");
+
+        let expected: HashSet<ApiUrl> = vec![
+            "https://a.adex.network/".parse().expect("Valid URL"),
+            "https://b.adex.network/".parse().expect("Valid URL"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(expected, config.validators);
+        assert_eq!(
+            vec!["SUPERMARKET_VALIDATORS".to_string()],
+            config.env_overrides
+        );
+    }
+
+    #[test]
+    fn env_vars_override_durations() {
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[
+                ("SUPERMARKET_FETCH_CAMPAIGNS_EVERY", "5m"),
+                ("SUPERMARKET_UPDATE_CAMPAIGNS_EVERY", "1h"),
+                ("SUPERMARKET_RECENCY", "30s"),
+                ("SUPERMARKET_TIMEOUTS__VALIDATOR_REQUEST", "7"),
+                // not a Supermarket variable
+                ("RECENCY", "invalid"),
+            ]),
+        )
+        .expect("Should load config");
+
+        assert_eq!(Duration::from_secs(300), config.fetch_campaigns_every);
+        assert_eq!(Duration::from_secs(3600), config.update_campaigns_every);
+        assert_eq!(Duration::from_secs(30), config.recency);
+        assert_eq!(Duration::from_secs(7), config.timeouts.validator_request);
+        assert_eq!(4, config.env_overrides.len());
+    }
+
+    #[test]
+    fn env_vars_take_precedence_over_the_config_file() {
+        let path = std::env::temp_dir().join("supermarket-env-precedence-test.toml");
+        std::fs::write(&path, "fetch_campaigns_every = 100").expect("Should write config file");
+        let path = path.to_str().expect("Valid path");
+
+        let file_config = Config::with_vars(Some(path), Environment::Development, vec![])
+            .expect("Should load config");
+        assert_eq!(Duration::from_secs(100), file_config.fetch_campaigns_every);
+
+        let config = Config::with_vars(
+            Some(path),
+            Environment::Development,
+            vars(&[
+                ("SUPERMARKET_FETCH_CAMPAIGNS_EVERY", "5m"),
+                ("SUPERMARKET_LIMITS__MAX_CHANNELS_EARNING_FROM", "10"),
+            ]),
+        )
+        .expect("Should load config");
+
+        assert_eq!(Duration::from_secs(300), config.fetch_campaigns_every);
+        assert_eq!(10, config.limits.max_channels_earning_from);
+        // not overridden values remain from the file
+        assert_eq!(
+            file_config.update_campaigns_every,
+            config.update_campaigns_every
+        );
+        assert_eq!(
+            ConfigSource::File {
+                path: path.to_string(),
+                environment: Environment::Development,
+            },
+            config.source
+        );
+    }
+
+    #[test]
+    fn env_var_conversion_errors_name_the_variable() {
+        for (name, value) in &[
+            ("SUPERMARKET_LIMITS__MAX_CHANNELS_EARNING_FROM", "many"),
+            ("SUPERMARKET_FETCH_CAMPAIGNS_EVERY", "5 minutes"),
+            ("SUPERMARKET_VALIDATORS", "not a url"),
+        ] {
+            let error = Config::with_vars(None, Environment::Development, vars(&[(*name, *value)]))
+                .expect_err("Should fail to convert the value");
+
+            match error {
+                Error::EnvVar { name: actual, .. } => assert_eq!(*name, actual),
+                error => panic!("Expected an EnvVar error, got: {:?}", error),
+            }
+        }
+    }
+
+    #[test]
+    fn env_vars_are_converted_to_the_type_of_the_field() {
+        let slot = "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C";
+        // neither of them is in the toml
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[
+                (
+                    "SUPERMARKET_SLOT_OVERRIDES__QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C__BLOCKED",
+                    "true",
+                ),
+                ("SUPERMARKET_WARM_FROM", "http://localhost:3000/"),
+            ]),
+        )
+        .expect("Should load config");
+        // the map key is kept as given, so the override matches the (case-sensitive) ipfs
+        assert!(config.slot_overrides[slot].blocked);
+        assert!(!config.slot_overrides.contains_key(&slot.to_lowercase()));
+        assert!(config.warm_from.is_some());
+
+        // a String field keeps the numbers as they are
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[("SUPERMARKET_ADMIN_TOKEN", "1234")]),
+        )
+        .expect("Should load config");
+        assert_eq!(
+            Some("1234"),
+            config.admin_token.as_ref().map(|token| token.expose())
+        );
+    }
+
+    #[test]
+    fn only_the_field_segments_of_env_vars_are_lowercased() {
+        for (name, expected) in &[
+            (
+                "SLOT_OVERRIDES__QmVw__MIN_PRICE__0xDAI",
+                &["slot_overrides", "QmVw", "min_price", "0xDAI"][..],
+            ),
+            (
+                "NETWORKS__Staging__MARKET_URL",
+                &["networks", "Staging", "market_url"][..],
+            ),
+            (
+                "PROXY__EXTRA_HEADERS__X-Network",
+                &["proxy", "extra_headers", "X-Network"][..],
+            ),
+            // the `EmptyReason`s are fields as well
+            (
+                "CACHE_CONTROL__EMPTY_MAX_AGE__NO_MATCH",
+                &["cache_control", "empty_max_age", "no_match"][..],
+            ),
+            (
+                "TIMEOUTS__VALIDATOR_REQUEST",
+                &["timeouts", "validator_request"][..],
+            ),
+        ] {
+            assert_eq!(*expected, env_path(name).as_slice(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_env_vars_are_rejected() {
+        for name in &[
+            "SUPERMARKET_NOT_A_FIELD",
+            "SUPERMARKET_MARKET__URLL",
+            "SUPERMARKET_TIMEOUTS__NOT_A_FIELD__NESTED",
+        ] {
+            match Config::with_vars(None, Environment::Development, vars(&[(*name, "5")])) {
+                Err(Error::UnknownEnvVar(actual)) => assert_eq!(*name, actual),
+                result => panic!(
+                    "Expected an UnknownEnvVar error for {}, got: {:?}",
+                    name, result
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn profiles_differ() {
+        let development = Config::with_vars(None, Environment::Development, vec![])
+            .expect("Should load development profile");
+        let production = Config::with_vars(None, Environment::Production, vec![])
+            .expect("Should load production profile");
+
+        assert_ne!(
+            development.fetch_campaigns_every,
+            production.fetch_campaigns_every
+        );
+        assert_ne!(
+            development.update_campaigns_every,
+            production.update_campaigns_every
+        );
+        assert_ne!(
+            development.timeouts.cache_update_campaign_statuses,
+            production.timeouts.cache_update_campaign_statuses
+        );
+        assert_ne!(development.market.url, production.market.url);
+        assert_ne!(development.validators, production.validators);
+
+        assert_eq!(
+            ConfigSource::Defaults {
+                environment: Environment::Production
+            },
+            production.source
+        );
+    }
+
+    #[test]
+    fn file_and_env_vars_are_layered_on_top_of_the_profile() {
+        let path = std::env::temp_dir().join("supermarket-profile-layering-test.toml");
+        std::fs::write(
+            &path,
+            "update_campaigns_every = 5\n[timeouts]\nvalidator_request = 3\n",
+        )
+        .expect("Should write config file");
+        let path = path.to_str().expect("Valid path");
+
+        let config = Config::with_vars(
+            Some(path),
+            Environment::Production,
+            vars(&[("SUPERMARKET_MARKET__URL", "https://market.example.com/")]),
+        )
+        .expect("Should load config");
+
+        // the file wins over the profile
+        assert_eq!(Duration::from_secs(5), config.update_campaigns_every);
+        assert_eq!(Duration::from_secs(3), config.timeouts.validator_request);
+        // the env variable wins over the profile
+        assert_eq!(
+            "https://market.example.com/"
+                .parse::<ApiUrl>()
+                .expect("Valid URL"),
+            config.market.url
+        );
+        // the rest is from the profile
+        assert_eq!(
+            PRODUCTION.fetch_campaigns_every,
+            config.fetch_campaigns_every
+        );
+        assert_eq!(
+            PRODUCTION.timeouts.cache_update_campaign_statuses,
+            config.timeouts.cache_update_campaign_statuses
+        );
+        assert_eq!(PRODUCTION.validators, config.validators);
+    }
+
+    #[test]
+    fn validator_timeout_overrides_should_be_shorter_than_the_cache_timeouts() {
+        let path = std::env::temp_dir().join("supermarket-validator-timeouts-test.toml");
+        let path = path.to_str().expect("Valid path");
+
+        std::fs::write(
+            path,
+            "[timeouts.validators]\n\"https://slow.adex.network/\" = \"9s\"",
+        )
+        .expect("Should write config file");
+
+        let config = Config::with_vars(Some(path), Environment::Development, vec![])
+            .expect("Should load config");
+        let slow: ApiUrl = "https://slow.adex.network/".parse().expect("Valid URL");
+        assert_eq!(
+            Some(&Duration::from_secs(9)),
+            config.timeouts.validators.get(&slow)
+        );
+
+        // equal to the `cache_update_campaign_statuses` timeout of `dev.toml`
+        std::fs::write(
+            path,
+            "[timeouts.validators]\n\"https://slow.adex.network/\" = 10",
+        )
+        .expect("Should write config file");
+
+        match Config::with_vars(Some(path), Environment::Development, vec![]) {
+            Err(Error::ValidatorTimeout { validator, .. }) => assert_eq!(slow, validator),
+            result => panic!("Expected a ValidatorTimeout error, got: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn invalid_server_settings_fail_the_validation() {
+        assert_eq!(Server::default(), DEVELOPMENT.server);
+        assert_eq!(Server::default(), PRODUCTION.server);
+        assert!(Server::default().validate().is_ok());
+
+        let invalid = vec![
+            vec![("SUPERMARKET_SERVER__MAX_BUF_SIZE", "4096")],
+            vec![
+                ("SUPERMARKET_SERVER__HTTP1_KEEPALIVE", "false"),
+                ("SUPERMARKET_SERVER__HTTP1_PIPELINE_FLUSH", "true"),
+            ],
+            vec![("SUPERMARKET_SERVER__SHUTDOWN_TIMEOUT", "0")],
+        ];
+
+        for overrides in invalid {
+            match Config::with_vars(None, Environment::Development, vars(&overrides)) {
+                Err(Error::Server { .. }) => {}
+                result => panic!(
+                    "Expected a Server error for {:?}, got: {:?}",
+                    overrides, result
+                ),
+            }
+        }
+
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[
+                ("SUPERMARKET_SERVER__TCP_NODELAY", "true"),
+                ("SUPERMARKET_SERVER__SHUTDOWN_TIMEOUT", "1m"),
+            ]),
+        )
+        .expect("Should load config");
+        assert!(config.server.tcp_nodelay);
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            config.server.shutdown_timeout
+        );
+    }
+
+    #[test]
+    fn keep_warm_is_disabled_by_default_and_requires_an_interval() {
+        assert_eq!(KeepWarm::default(), DEVELOPMENT.keep_warm);
+        assert!(!PRODUCTION.keep_warm.enabled);
+
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[
+                ("SUPERMARKET_KEEP_WARM__ENABLED", "true"),
+                ("SUPERMARKET_KEEP_WARM__INTERVAL", "1m"),
+            ]),
+        )
+        .expect("Should load config");
+        assert!(config.keep_warm.enabled);
+        assert_eq!(Duration::from_secs(60), config.keep_warm.interval);
+
+        match Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[
+                ("SUPERMARKET_KEEP_WARM__ENABLED", "true"),
+                ("SUPERMARKET_KEEP_WARM__INTERVAL", "0"),
+            ]),
+        ) {
+            Err(Error::KeepWarm) => {}
+            result => panic!("Expected a KeepWarm error, got: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn sampling_is_disabled_by_default_and_requires_a_single_destination() {
+        assert_eq!(Sampling::default(), DEVELOPMENT.sampling);
+        assert!(!PRODUCTION.sampling.is_enabled());
+
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[
+                ("SUPERMARKET_SAMPLING__RATE", "0.001"),
+                ("SUPERMARKET_SAMPLING__PATH", "/tmp/samples.jsonl"),
+            ]),
+        )
+        .expect("Should load config");
+        assert!(config.sampling.is_enabled());
+
+        let invalid: &[&[(&str, &str)]] = &[
+            &[("SUPERMARKET_SAMPLING__RATE", "1.5")],
+            // without a destination
+            &[("SUPERMARKET_SAMPLING__RATE", "0.001")],
+            &[
+                ("SUPERMARKET_SAMPLING__RATE", "0.001"),
+                ("SUPERMARKET_SAMPLING__PATH", "/tmp/samples.jsonl"),
+                ("SUPERMARKET_SAMPLING__BUFFER", "0"),
+            ],
+        ];
+        for invalid in invalid {
+            match Config::with_vars(None, Environment::Development, vars(invalid)) {
+                Err(Error::Sampling) => {}
+                result => panic!("Expected a Sampling error, got: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn client_deadline_min_should_not_be_longer_than_the_max() {
+        assert_eq!(
+            ClientDeadline::default(),
+            DEVELOPMENT.timeouts.client_deadline
+        );
+        assert!(PRODUCTION.timeouts.client_deadline.validate().is_ok());
+
+        for &(min, max) in &[("0", "300"), ("500", "300")] {
+            match Config::with_vars(
+                None,
+                Environment::Development,
+                vars(&[
+                    ("SUPERMARKET_TIMEOUTS__CLIENT_DEADLINE__MIN", min),
+                    ("SUPERMARKET_TIMEOUTS__CLIENT_DEADLINE__MAX", max),
+                ]),
+            ) {
+                Err(Error::ClientDeadline { .. }) => {}
+                result => panic!("Expected a ClientDeadline error, got: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn networks_replace_the_market_validators_and_intervals_of_the_default_one() {
+        assert!(DEVELOPMENT.networks.is_empty());
+        assert!(PRODUCTION.networks.is_empty());
+
+        let path = std::env::temp_dir().join("supermarket-networks-test.toml");
+        let load = |toml: &str| {
+            std::fs::write(&path, toml).expect("Should write config file");
+            Config::new(path.to_str(), Environment::Development)
+        };
+
+        let config = load(
+            r#"
+            [networks.staging]
+            market_url = "https://market-staging.adex.network/"
+            validators = ["https://tom-staging.adex.network"]
+            update_campaigns_every = 30
+            hosts = ["staging.supermarket.adex.network"]
+            "#,
+        )
+        .expect("Should load config");
+        let staging = config.network("staging").expect("Should have the network");
+
+        assert_eq!(
+            "https://market-staging.adex.network/"
+                .parse::<ApiUrl>()
+                .unwrap(),
+            staging.market.url
+        );
+        assert_eq!(
+            vec!["https://tom-staging.adex.network"
+                .parse::<ApiUrl>()
+                .unwrap()]
+            .into_iter()
+            .collect::<HashSet<_>>(),
+            staging.validators
+        );
+        assert_eq!(
+            DEVELOPMENT.fetch_campaigns_every,
+            staging.fetch_campaigns_every
+        );
+        assert_eq!(Duration::from_secs(30), staging.update_campaigns_every);
+        assert!(staging.networks.is_empty());
+        assert!(config.network("production").is_none());
+
+        for toml in &[
+            "[networks.Staging]\nmarket_url = \"https://market.adex.network/\"\nvalidators = []",
+            "[networks.stats]\nmarket_url = \"https://market.adex.network/\"\nvalidators = []",
+            "[networks.units-for-slot]\nmarket_url = \"https://market.adex.network/\"\nvalidators = []",
+            "[networks.default]\nmarket_url = \"https://market.adex.network/\"\nvalidators = []",
+            "[networks.a]\nmarket_url = \"https://market.adex.network/\"\nvalidators = []\nhosts = [\"a.local\"]\n\
+             [networks.b]\nmarket_url = \"https://market.adex.network/\"\nvalidators = []\nhosts = [\"A.local\"]",
+        ] {
+            match load(toml) {
+                Err(Error::Network { .. }) => {}
+                result => panic!("Expected a Network error for {}, got: {:?}", toml, result),
+            }
+        }
+        std::fs::remove_file(&path).expect("Should remove config file");
+    }
+
+    #[test]
+    fn validators_are_persisted_to_the_config_file() {
+        let tom: ApiUrl = "https://tom.adex.network".parse().expect("Valid URL");
+        let jerry: ApiUrl = "http://localhost:8005".parse().expect("Valid URL");
+
+        assert!(matches!(
+            DEVELOPMENT.persist_validators(&HashSet::new()),
+            Err(Error::NoConfigFile)
+        ));
+
+        let path = std::env::temp_dir().join("supermarket-persist-validators-test.toml");
+        std::fs::write(
+            &path,
+            r#"
+            # the comments aren't kept
+            validators = ["https://tom.adex.network"]
+            recency = 90
+
+            [slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C]
+            blocked = true
+            "#,
+        )
+        .expect("Should write config file");
+        let config =
+            Config::new(path.to_str(), Environment::Development).expect("Should load config");
+        assert_eq!(
+            std::iter::once(tom.clone()).collect::<HashSet<_>>(),
+            config.validators
+        );
+
+        let validators = vec![tom, jerry].into_iter().collect::<HashSet<_>>();
+        config
+            .persist_validators(&validators)
+            .expect("Should persist the Validators");
+        let reloaded = config.reload().expect("Should reload config");
+
+        let overridden = Config::with_vars(
+            path.to_str(),
+            Environment::Development,
+            vars(&[("SUPERMARKET_VALIDATORS", "http://localhost:8005")]),
+        )
+        .expect("Should load config");
+        std::fs::remove_file(&path).expect("Should remove config file");
+
+        assert_eq!(validators, reloaded.validators);
+        // the rest of the file is kept
+        assert_eq!(Duration::from_secs(90), reloaded.recency);
+        assert!(reloaded.slot_overrides["QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C"].blocked);
+
+        assert!(matches!(
+            overridden.persist_validators(&validators),
+            Err(Error::ValidatorsOverridden(name)) if name == "SUPERMARKET_VALIDATORS"
+        ));
+    }
+
+    #[test]
+    fn slot_overrides_are_reloaded_from_the_config_file() {
+        assert!(DEVELOPMENT.slot_overrides.is_empty());
+        assert!(PRODUCTION.slot_overrides.is_empty());
+
+        let path = std::env::temp_dir().join("supermarket-slot-overrides-test.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C]
+            blocked = true
+            "#,
+        )
+        .expect("Should write config file");
+        let config =
+            Config::new(path.to_str(), Environment::Development).expect("Should load config");
+        assert_eq!(
+            Some(&SlotOverride {
+                blocked: true,
+                ..Default::default()
+            }),
+            config
+                .slot_overrides
+                .get("QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C")
+        );
+
+        std::fs::write(
+            &path,
+            r#"
+            [slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C]
+            force_fallback = true
+
+            [slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C.min_price]
+            "0x6B175474E89094C44Da98b954EedeAC495271d0F" = "2000"
+            "#,
+        )
+        .expect("Should write config file");
+        let reloaded = config.reload().expect("Should reload config");
+        std::fs::remove_file(&path).expect("Should remove config file");
+
+        let slot_override =
+            &reloaded.slot_overrides["QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C"];
+        assert!(!slot_override.blocked && slot_override.force_fallback);
+        assert_eq!(config.source, reloaded.source);
+
+        // only a higher min price of the same deposit asset applies
+        let dai = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+        for (global, deposit_asset, expected) in &[
+            (1_000, dai, 2_000),
+            (3_000, dai, 3_000),
+            (1_000, "0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359", 1_000),
+        ] {
+            assert_eq!(
+                &BigNum::from(*expected),
+                slot_override.min_impression_price(&BigNum::from(*global), deposit_asset)
+            );
+        }
+    }
+
+    #[test]
+    fn prewarm_refresh_margin_should_be_shorter_than_the_ttl() {
+        assert_eq!(Prewarm::default(), DEVELOPMENT.prewarm);
+        assert!(PRODUCTION.prewarm.is_enabled());
+        assert!(PRODUCTION.prewarm.validate().is_ok());
+
+        for &(slot_cache_ttl, refresh_margin) in &[("60", "0"), ("60", "60"), ("60", "2m")] {
+            match Config::with_vars(
+                None,
+                Environment::Development,
+                vars(&[
+                    ("SUPERMARKET_PREWARM__SLOT_CACHE_TTL", slot_cache_ttl),
+                    ("SUPERMARKET_PREWARM__TOP_SLOTS", "100"),
+                    ("SUPERMARKET_PREWARM__REFRESH_MARGIN", refresh_margin),
+                ]),
+            ) {
+                Err(Error::Prewarm { .. }) => {}
+                result => panic!(
+                    "Expected a Prewarm error for {:?}, got: {:?}",
+                    refresh_margin, result
+                ),
+            }
+        }
+
+        // not validated when disabled
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[("SUPERMARKET_PREWARM__REFRESH_MARGIN", "0")]),
+        )
+        .expect("Should load config");
+        assert!(!config.prewarm.is_enabled());
+    }
+
+    #[test]
+    fn negative_slots_first_interval_should_be_within_the_revalidation() {
+        assert!(!DEVELOPMENT.negative_slots.is_revalidated());
+        assert!(PRODUCTION.negative_slots.is_revalidated());
+        assert!(PRODUCTION.negative_slots.validate().is_ok());
+
+        for first_interval in &["0", "301"] {
+            match Config::with_vars(
+                None,
+                Environment::Development,
+                vars(&[
+                    ("SUPERMARKET_NEGATIVE_SLOTS__TTL", "60"),
+                    ("SUPERMARKET_NEGATIVE_SLOTS__FIRST_INTERVAL", first_interval),
+                    ("SUPERMARKET_NEGATIVE_SLOTS__REVALIDATE_FOR", "300"),
+                ]),
+            ) {
+                Err(Error::NegativeSlots { .. }) => {}
+                result => panic!(
+                    "Expected a NegativeSlots error for {:?}, got: {:?}",
+                    first_interval, result
+                ),
+            }
+        }
+
+        // not validated without the revalidation
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[
+                ("SUPERMARKET_NEGATIVE_SLOTS__TTL", "60"),
+                ("SUPERMARKET_NEGATIVE_SLOTS__QUEUE_SIZE", "0"),
+                ("SUPERMARKET_NEGATIVE_SLOTS__FIRST_INTERVAL", "0"),
+            ]),
+        )
+        .expect("Should load config");
+        assert!(!config.negative_slots.is_revalidated());
+    }
+
+    #[test]
+    fn proxy_headers_are_validated() {
+        let version = crate::build_info::VERSION;
+        let expected_forwarded_by = format!("adex-supermarket/{}", version);
+        assert_eq!(
+            Some(&expected_forwarded_by),
+            DEVELOPMENT
+                .proxy
+                .headers()
+                .expect("Valid headers")
+                .iter()
+                .find(|(name, _)| name.as_str() == "x-forwarded-by")
+                .map(|(_, value)| value.to_str().expect("ASCII value").to_string())
+                .as_ref()
+        );
+
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[("SUPERMARKET_PROXY__USER_AGENT", "supermarket/{version}")]),
+        )
+        .expect("Should load config");
+        let headers = config.proxy.headers().expect("Valid headers");
+        assert_eq!(
+            Some(format!("supermarket/{}", version)),
+            headers
+                .iter()
+                .find(|(name, _)| *name == USER_AGENT)
+                .map(|(_, value)| value.to_str().expect("ASCII value").to_string())
+        );
+
+        let invalid = |name: &str, value: &str| {
+            let mut proxy = ProxyHeaders::default();
+            proxy
+                .extra_headers
+                .insert(name.to_string(), value.to_string());
+
+            proxy.headers()
+        };
+        for protected in &[
+            "Host",
+            "connection",
+            "Transfer-Encoding",
+            "user-agent",
+            "TE",
+        ] {
+            match invalid(protected, "value") {
+                Err(Error::ProxyHeader { name, .. }) if name == *protected => {}
+                result => panic!("Expected {} to be protected, got: {:?}", protected, result),
+            }
+        }
+        for (name, value) in &[
+            ("x-invalid name", "value"),
+            ("x-non-ascii", "супермаркет"),
+            ("x-newline", "line\nbreak"),
+        ] {
+            match invalid(name, value) {
+                Err(Error::ProxyHeader { .. }) => {}
+                result => panic!(
+                    "Expected {}: {} to be invalid, got: {:?}",
+                    name, value, result
+                ),
+            }
+        }
+
+        let mut proxy = ProxyHeaders::default();
+        proxy.user_agent = Some("ünicode".to_string());
+        assert!(proxy.headers().is_err());
+    }
+
+    #[test]
+    fn the_shortest_max_age_of_the_reasons_is_used() {
+        let config = Config::with_vars(
+            None,
+            Environment::Development,
+            vars(&[(
+                "SUPERMARKET_CACHE_CONTROL__EMPTY_MAX_AGE__NO_ACTIVE_CAMPAIGNS",
+                "3",
+            )]),
+        )
+        .expect("Should load config");
+        assert_eq!(
+            Some(&3),
+            config
+                .cache_control
+                .empty_max_age
+                .get(&EmptyReason::NoActiveCampaigns)
+        );
+
+        let mut cache_control = CacheControl {
+            max_age: Some(60),
+            ..CacheControl::default()
+        };
+        cache_control
+            .empty_max_age
+            .insert(EmptyReason::NoActiveCampaigns, 5);
+        cache_control.empty_max_age.insert(EmptyReason::NoMatch, 30);
+
+        assert_eq!(Some(60), cache_control.max_age(vec![None]));
+        assert_eq!(
+            Some(30),
+            cache_control.max_age(vec![None, Some(EmptyReason::NoMatch)])
+        );
+        assert_eq!(
+            Some(5),
+            cache_control.max_age(vec![
+                Some(EmptyReason::NoActiveCampaigns),
+                Some(EmptyReason::NoMatch)
+            ])
+        );
+        // without an override it's the `max_age`
+        assert_eq!(
+            Some(60),
+            cache_control.max_age(vec![Some(EmptyReason::NoUnitsOfType)])
+        );
+
+        cache_control.max_age = None;
+        assert_eq!(None, cache_control.max_age(vec![None]));
+        assert_eq!(
+            Some(30),
+            cache_control.max_age(vec![None, Some(EmptyReason::NoMatch)])
+        );
+    }
 }
@@ -0,0 +1,187 @@
+//! Error reporting to [Sentry.io](https://sentry.io) (the error tracker, not the AdEx Validator [`SentryApi`](crate::SentryApi)).
+//!
+//! Enabled with the `sentry-reporting` feature and the `sentry_dsn` of the [`Config`].
+//! Without either of them all the functions are no-ops.
+use crate::{util::LogSampler, Config};
+use slog::Drain;
+use std::time::Duration;
+
+/// How often the same error of the same target (e.g. a Campaign) is reported, see [`report_sampled_error`]
+pub const REPORT_SAMPLING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+lazy_static::lazy_static! {
+    /// Samples the reports of the errors which repeat on every Cache update, e.g. a Campaign's status failing
+    static ref REPORT_SAMPLER: LogSampler = LogSampler::new(REPORT_SAMPLING_INTERVAL);
+}
+
+/// Keeps the Sentry client alive and flushes the pending events when dropped
+#[must_use = "Events are reported only while the guard is alive"]
+pub struct Guard {
+    #[cfg(feature = "sentry-reporting")]
+    _client: Option<sentry::ClientInitGuard>,
+}
+
+/// Initializes the Sentry client if the `sentry_dsn` is set.
+/// The default integrations report the panics, incl. the ones of the Cache tasks.
+pub fn init(config: &Config) -> Guard {
+    #[cfg(feature = "sentry-reporting")]
+    {
+        let client = config.sentry_dsn.as_ref().map(|dsn| {
+            sentry::init((
+                dsn.expose(),
+                sentry::ClientOptions {
+                    release: sentry::release_name!(),
+                    ..Default::default()
+                },
+            ))
+        });
+
+        Guard { _client: client }
+    }
+
+    #[cfg(not(feature = "sentry-reporting"))]
+    {
+        let _ = config;
+
+        Guard {}
+    }
+}
+
+/// Wraps the `drain`, so the log records (above `debug`) are attached as breadcrumbs to the reported events
+#[cfg(feature = "sentry-reporting")]
+pub fn drain<D: Drain>(drain: D) -> sentry_slog::SentryDrain<D> {
+    use sentry_slog::LevelFilter;
+
+    sentry_slog::SentryDrain::new(drain).filter(|level| match level {
+        slog::Level::Trace | slog::Level::Debug => LevelFilter::Ignore,
+        _ => LevelFilter::Breadcrumb,
+    })
+}
+
+/// Wraps the `drain`, so the log records (above `debug`) are attached as breadcrumbs to the reported events
+#[cfg(not(feature = "sentry-reporting"))]
+pub fn drain<D: Drain>(drain: D) -> D {
+    drain
+}
+
+/// Reports an error event with the `tags`, e.g. `[("request_id", "...")]` or `[("channel_id", "...")]`
+pub fn report_error(message: &str, tags: &[(&str, &str)]) {
+    #[cfg(feature = "sentry-reporting")]
+    sentry::with_scope(
+        |scope| {
+            for (key, value) in tags {
+                scope.set_tag(key, value);
+            }
+        },
+        || sentry::capture_message(message, sentry::Level::Error),
+    );
+
+    #[cfg(not(feature = "sentry-reporting"))]
+    let _ = (message, tags);
+}
+
+/// Same as [`report_error`] (with the `details` appended to the `message`), but the errors with the same `message` & `tags`
+/// are reported at most once per [`REPORT_SAMPLING_INTERVAL`], with the number of the suppressed ones in the `suppressed` tag.
+/// The `details` (e.g. the error itself) are not part of the sampling.
+pub fn report_sampled_error(message: &str, details: &str, tags: &[(&str, &str)]) {
+    report_sampled(&REPORT_SAMPLER, message, details, tags)
+}
+
+fn report_sampled(sampler: &LogSampler, message: &str, details: &str, tags: &[(&str, &str)]) {
+    let tag_values = tags
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>();
+    let keys = tag_values.iter().map(String::as_str).collect::<Vec<_>>();
+
+    if let Some(suppressed) = sampler.sample(message, &keys) {
+        let suppressed = suppressed.to_string();
+        let tags = tags
+            .iter()
+            .cloned()
+            .chain(std::iter::once(("suppressed", suppressed.as_str())))
+            .collect::<Vec<_>>();
+
+        report_error(&format!("{}: {}", message, details), &tags);
+    }
+}
+
+#[cfg(all(test, feature = "sentry-reporting"))]
+mod test {
+    use super::*;
+    use crate::util::test::MockClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn reports_the_error_with_the_tags() {
+        let events = sentry::test::with_captured_events(|| {
+            report_error(
+                "Handling request failed",
+                &[("request_id", "176e1b4f5a0-2a")],
+            )
+        });
+
+        assert_eq!(1, events.len());
+        assert_eq!(
+            Some("Handling request failed"),
+            events[0].message.as_deref()
+        );
+        assert_eq!(sentry::Level::Error, events[0].level);
+        assert_eq!(
+            Some("176e1b4f5a0-2a"),
+            events[0].tags.get("request_id").map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn the_same_error_of_a_target_is_reported_once_per_interval() {
+        let clock = MockClock::new();
+        let sampler = LogSampler::with_clock(REPORT_SAMPLING_INTERVAL, Arc::new(clock.clone()));
+        let report = |channel_id: &str, error: &str| {
+            report_sampled(
+                &sampler,
+                "Error getting Campaign status",
+                error,
+                &[("channel_id", channel_id)],
+            )
+        };
+
+        let events = sentry::test::with_captured_events(|| {
+            // every Cache update
+            for _ in 0..3 {
+                report("0x01", "timed out");
+                report("0x02", "timed out");
+            }
+            report("0x01", "connection refused");
+
+            clock.advance(REPORT_SAMPLING_INTERVAL);
+            report("0x01", "timed out");
+        });
+
+        let reported = events
+            .iter()
+            .map(|event| {
+                (
+                    event.message.clone().unwrap_or_default(),
+                    event.tags.get("channel_id").cloned().unwrap_or_default(),
+                    event.tags.get("suppressed").cloned().unwrap_or_default(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let expected = vec![
+            ("Error getting Campaign status: timed out", "0x01", "0"),
+            ("Error getting Campaign status: timed out", "0x02", "0"),
+            ("Error getting Campaign status: timed out", "0x01", "3"),
+        ]
+        .into_iter()
+        .map(|(message, channel_id, suppressed)| {
+            (
+                message.to_string(),
+                channel_id.to_string(),
+                suppressed.to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+        assert_eq!(expected, reported);
+    }
+}
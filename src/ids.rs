@@ -0,0 +1,146 @@
+//! Parsing the hex identifiers of the routes & the Validators' responses in a canonical form,
+//! regardless of their case (e.g. checksummed addresses) and of the `0x` prefix.
+//!
+//! [`ChannelId`] & [`ValidatorId`] are foreign types, so they're parsed through the [`Canonical`] wrapper,
+//! e.g. `let Canonical(channel_id) = id.parse::<Canonical<ChannelId>>()?`
+use primitives::{ChannelId, ValidatorId};
+use std::{convert::TryFrom, str::FromStr};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Malformed {kind}: {id}")]
+pub struct MalformedId {
+    pub kind: &'static str,
+    pub id: String,
+}
+
+/// A [`ChannelId`] (32 bytes) or a [`ValidatorId`] / address (20 bytes) parsed from hex in either case,
+/// with or without the `0x` prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Canonical<T>(pub T);
+
+impl FromStr for Canonical<ChannelId> {
+    type Err = MalformedId;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let malformed = || MalformedId {
+            kind: "ChannelId",
+            id: id.to_string(),
+        };
+
+        canonical_hex(id, 32)
+            .and_then(|hex| hex.parse().ok())
+            .map(Canonical)
+            .ok_or_else(malformed)
+    }
+}
+
+impl FromStr for Canonical<ValidatorId> {
+    type Err = MalformedId;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let malformed = || MalformedId {
+            kind: "address",
+            id: id.to_string(),
+        };
+
+        canonical_hex(id, 20)
+            .and_then(|hex| ValidatorId::try_from(hex.as_str()).ok())
+            .map(Canonical)
+            .ok_or_else(malformed)
+    }
+}
+
+/// The lowercase `0x`-prefixed form of the hex of `bytes` length, `None` if it's not one
+pub fn canonical_hex(id: &str, bytes: usize) -> Option<String> {
+    let id = id.trim();
+    let hex = id
+        .strip_prefix("0x")
+        .or_else(|| id.strip_prefix("0X"))
+        .unwrap_or(id);
+
+    if hex.len() != bytes * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some(format!("0x{}", hex.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHANNEL_ID: &str = "0x061d5e2a67d0a9a10f1c732bca12a676d83f79663a396f7d87b3e30b9b411088";
+    const ADDRESS: &str = "0xB7d3F81E857692d13e9D63b232A90F4A1793189E";
+
+    #[test]
+    fn the_same_channel_id_in_every_form_is_equal() {
+        let forms = vec![
+            CHANNEL_ID.to_string(),
+            CHANNEL_ID.to_ascii_uppercase().replacen("0X", "0x", 1),
+            CHANNEL_ID.trim_start_matches("0x").to_ascii_uppercase(),
+        ];
+
+        let parsed = forms
+            .iter()
+            .map(|form| {
+                form.parse::<Canonical<ChannelId>>()
+                    .expect("Should parse")
+                    .0
+            })
+            .collect::<Vec<_>>();
+        assert!(parsed.iter().all(|channel_id| *channel_id == parsed[0]));
+        assert_eq!(CHANNEL_ID, parsed[0].to_string());
+    }
+
+    #[test]
+    fn the_same_address_in_every_form_is_equal() {
+        let (lowercase, uppercase) = (ADDRESS.to_ascii_lowercase(), ADDRESS.to_ascii_uppercase());
+        let Canonical(checksummed) = ADDRESS
+            .parse::<Canonical<ValidatorId>>()
+            .expect("Should parse");
+
+        for form in &[
+            lowercase.as_str(),
+            lowercase.trim_start_matches("0x"),
+            uppercase.as_str(),
+        ] {
+            let Canonical(parsed) = form
+                .parse::<Canonical<ValidatorId>>()
+                .expect("Should parse");
+            assert_eq!(checksummed, parsed, "{}", form);
+        }
+    }
+
+    #[test]
+    fn malformed_ids_are_rejected() {
+        let (too_long, not_hex, double_prefix) = (
+            format!("{}00", CHANNEL_ID),
+            CHANNEL_ID.replace('a', "g"),
+            format!("0x0x{}", &CHANNEL_ID[4..]),
+        );
+
+        for id in &[
+            "",
+            "0x",
+            &CHANNEL_ID[..64],
+            too_long.as_str(),
+            not_hex.as_str(),
+            double_prefix.as_str(),
+        ] {
+            assert_eq!(
+                Err(MalformedId {
+                    kind: "ChannelId",
+                    id: id.to_string()
+                }),
+                id.parse::<Canonical<ChannelId>>(),
+            );
+        }
+
+        assert!("0xB7d3F81E857692d13e9D63b232A90F4A1793189"
+            .parse::<Canonical<ValidatorId>>()
+            .is_err());
+        // a ChannelId isn't an address
+        assert!(CHANNEL_ID.parse::<Canonical<ValidatorId>>().is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Keeps the pooled connections to the Market and the Validators alive in between the requests,
+//! so the first requests after an idle period don't wait for a new (TLS) connection,
+//! see [`KeepWarm`](crate::config::KeepWarm).
+use crate::{
+    cache::{ApiClient, Client},
+    market::{MarketApi, Proxy},
+    metrics::KEEP_WARM_REQUESTS,
+    util::Backoff,
+};
+use async_trait::async_trait;
+use futures::future::join_all;
+use slog::{debug, info, warn, Logger};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::time::{interval_at, Instant};
+
+pub type WarmUpError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A client whose pooled connections are kept alive
+#[async_trait]
+pub trait WarmUp: Send + Sync {
+    /// The `target` of the logs and the [`KEEP_WARM_REQUESTS`] metric
+    fn target(&self) -> &'static str;
+
+    /// A lightweight request which reuses a pooled connection (or opens a new one)
+    async fn warm_up(&self) -> Result<(), WarmUpError>;
+}
+
+#[async_trait]
+impl WarmUp for MarketApi {
+    fn target(&self) -> &'static str {
+        "market"
+    }
+
+    async fn warm_up(&self) -> Result<(), WarmUpError> {
+        MarketApi::warm_up(self).await.map_err(Into::into)
+    }
+}
+
+#[async_trait]
+impl WarmUp for Proxy {
+    fn target(&self) -> &'static str {
+        "market_proxy"
+    }
+
+    async fn warm_up(&self) -> Result<(), WarmUpError> {
+        Proxy::warm_up(self).await.map_err(Into::into)
+    }
+}
+
+/// All the current Validators of the Cache, it fails only if all of them fail
+#[async_trait]
+impl WarmUp for ApiClient {
+    fn target(&self) -> &'static str {
+        "validators"
+    }
+
+    async fn warm_up(&self) -> Result<(), WarmUpError> {
+        let validators = self.validators().await;
+        let results = join_all(
+            validators
+                .iter()
+                .map(|validator| self.sentry.warm_up(validator)),
+        )
+        .await;
+
+        let failed = results.iter().filter(|result| result.is_err()).count();
+        match results.into_iter().find_map(Result::err) {
+            Some(error) if failed == validators.len() => Err(error.into()),
+            Some(error) => {
+                debug!(&self.logger, "Keeping the connection to a Validator alive failed"; "failed" => failed, "error" => %error);
+
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Every `interval` (starting one `interval` after it's called) warms up all the `targets` until the `shutdown`.
+pub async fn keep_warm(
+    logger: Logger,
+    targets: Vec<Arc<dyn WarmUp>>,
+    interval: Duration,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut ticks = interval_at(Instant::now() + interval, interval);
+    let mut backoffs = vec![Backoff::default(); targets.len()];
+    futures::pin_mut!(shutdown);
+
+    info!(&logger, "Keeping the connections warm"; "interval" => ?interval, "targets" => targets.len());
+    loop {
+        let warm_up_all = async {
+            ticks.tick().await;
+
+            let warm_ups = targets.iter().zip(backoffs.iter_mut()).map(|(target, backoff)| {
+                let logger = &logger;
+
+                async move {
+                    let name = target.target();
+                    if backoff.is_open() {
+                        KEEP_WARM_REQUESTS
+                            .with_label_values(&[name, "backed_off"])
+                            .inc();
+
+                        return;
+                    }
+
+                    let result = target.warm_up().await;
+                    backoff.record(result.is_ok());
+                    match result {
+                        Ok(()) => KEEP_WARM_REQUESTS.with_label_values(&[name, "ok"]).inc(),
+                        Err(error) => {
+                            KEEP_WARM_REQUESTS.with_label_values(&[name, "error"]).inc();
+                            warn!(logger, "Keeping the connection alive failed, backing off"; "target" => name, "failures" => backoff.failures(), "error" => %error);
+                        }
+                    }
+                }
+            });
+
+            join_all(warm_ups).await;
+        };
+
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = warm_up_all => {}
+        }
+    }
+
+    info!(&logger, "Stopped keeping the connections warm");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::{test::discard_logger, MAX_BACKOFF_INTERVALS};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use tokio::sync::oneshot;
+
+    #[derive(Debug, Default)]
+    struct CountingTarget {
+        calls: AtomicU64,
+        failing: AtomicBool,
+    }
+
+    impl CountingTarget {
+        fn calls(&self) -> u64 {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl WarmUp for CountingTarget {
+        fn target(&self) -> &'static str {
+            "counting"
+        }
+
+        async fn warm_up(&self) -> Result<(), WarmUpError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if self.failing.load(Ordering::SeqCst) {
+                Err("the target is down".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Advances the paused time and lets the spawned task run
+    async fn advance(duration: Duration) {
+        tokio::time::advance(duration).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn targets_are_warmed_up_every_interval_until_the_shutdown() {
+        tokio::time::pause();
+        let interval = Duration::from_secs(30);
+        let target = Arc::new(CountingTarget::default());
+        let (shutdown, on_shutdown) = oneshot::channel::<()>();
+
+        let task = tokio::spawn(keep_warm(
+            discard_logger(),
+            vec![target.clone()],
+            interval,
+            async {
+                let _ = on_shutdown.await;
+            },
+        ));
+
+        // not immediately, the connections were just opened
+        advance(Duration::from_secs(0)).await;
+        assert_eq!(0, target.calls());
+        advance(interval - Duration::from_secs(1)).await;
+        assert_eq!(0, target.calls());
+
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(1, target.calls());
+        advance(interval / 2).await;
+        assert_eq!(1, target.calls());
+        advance(interval / 2).await;
+        assert_eq!(2, target.calls());
+
+        shutdown.send(()).expect("The task should be running");
+        task.await.expect("The task should stop on shutdown");
+
+        advance(interval * 3).await;
+        assert_eq!(2, target.calls());
+    }
+
+    #[tokio::test]
+    async fn failing_targets_are_backed_off() {
+        tokio::time::pause();
+        let interval = Duration::from_secs(30);
+        let target = Arc::new(CountingTarget::default());
+        target.failing.store(true, Ordering::SeqCst);
+        let (shutdown, on_shutdown) = oneshot::channel::<()>();
+
+        let task = tokio::spawn(keep_warm(
+            discard_logger(),
+            vec![target.clone()],
+            interval,
+            async {
+                let _ = on_shutdown.await;
+            },
+        ));
+
+        // the ticks at which it's requested: 1, 3 (skipped 1), 6 (skipped 2), 11 (skipped 4)
+        let mut calls = vec![];
+        for _ in 0..11 {
+            advance(interval).await;
+            calls.push(target.calls());
+        }
+        assert_eq!(vec![1, 1, 2, 2, 2, 3, 3, 3, 3, 3, 4], calls);
+
+        // the next one after the backoff succeeds and resets it
+        target.failing.store(false, Ordering::SeqCst);
+        for _ in 0..MAX_BACKOFF_INTERVALS {
+            advance(interval).await;
+        }
+        assert_eq!(5, target.calls());
+        advance(interval).await;
+        assert_eq!(6, target.calls());
+
+        shutdown.send(()).expect("The task should be running");
+        task.await.expect("The task should stop on shutdown");
+    }
+}
@@ -1,29 +1,72 @@
 #![deny(clippy::all)]
 #![deny(rust_2018_idioms)]
+pub use build_info::BuildInfo;
 pub use cache::Cache;
-use hyper::{Body, Method, Request, Response, Server};
+use futures::{future::BoxFuture, Future, FutureExt};
+use hyper::{
+    server::conn::{AddrIncoming, AddrStream},
+    Body, Method, Request, Response, Server,
+};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use thiserror::Error;
+use tokio::task::JoinHandle;
 
-use http::{HeaderValue, StatusCode};
-use slog::{error, info, Logger};
+use http::{header::CONTENT_TYPE, HeaderValue, StatusCode};
+use slog::{debug, error, info, warn, Logger};
 
+pub mod access_log;
+pub mod admin;
+pub mod anomaly;
+pub mod bot;
+pub mod build_info;
 pub mod cache;
+pub mod campaigns;
+pub mod check;
 pub mod config;
+pub mod error_reporting;
+pub mod ids;
+pub mod keep_warm;
 pub mod market;
+pub mod metrics;
+pub mod network;
+pub mod openapi;
 pub mod sentry_api;
 pub mod status;
 mod units_for_slot;
 pub mod util;
 
 use market::{MarketApi, MarketUrl, Proxy};
-use units_for_slot::get_units_for_slot;
+use network::{Networks, Upstream, DEFAULT_NETWORK};
+use units_for_slot::get_units_for_slot_coalesced;
 
 pub use config::{Config, Timeouts};
 pub use sentry_api::SentryApi;
 
 pub(crate) static ROUTE_UNITS_FOR_SLOT: &str = "/units-for-slot/";
+pub(crate) static ROUTE_HEALTHZ: &str = "/healthz";
+pub(crate) static ROUTE_READYZ: &str = "/readyz";
+pub(crate) static ROUTE_METRICS: &str = "/metrics";
+pub(crate) static ROUTE_VERSION: &str = "/version";
+/// The OpenAPI description of the routes handled by the Supermarket, see [`openapi`]
+pub(crate) static ROUTE_OPENAPI: &str = "/openapi.json";
+/// `/campaigns/:id/balances`, the rest of the `/campaigns` routes are proxied to the Market
+pub(crate) static ROUTE_CAMPAIGNS: &str = "/campaigns/";
+/// `/slots/:ipfs` is proxied to the Market, see [`prewarm::warm_from_proxied`](units_for_slot::prewarm::warm_from_proxied)
+pub(crate) static ROUTE_SLOTS: &str = "/slots/";
+pub(crate) static ROUTE_STATS: &str = "/stats";
+/// Admin route, `/stats/publishers/:address`
+pub(crate) static ROUTE_PUBLISHER_STATS: &str = "/stats/publishers/";
+/// Admin route
+pub(crate) static ROUTE_CONFIG: &str = "/config";
+/// Admin route
+pub(crate) static ROUTE_VALIDATORS: &str = "/validators";
+/// Admin route
+pub(crate) static ROUTE_VALIDATORS_REFRESH: &str = "/validators/refresh";
+/// Admin route
+pub(crate) static ROUTE_CACHE_SNAPSHOT: &str = "/internal/cache-snapshot";
+/// Admin routes, none of them are proxied to the Market
+pub(crate) static ROUTE_INTERNAL: &str = "/internal/";
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -39,6 +82,25 @@ pub enum Error {
     Serde(#[from] serde_json::error::Error),
     #[error(transparent)]
     SentryApi(#[from] sentry_api::Error),
+    #[error(transparent)]
+    Prometheus(#[from] prometheus::Error),
+    #[error("Opening the access log: {0}")]
+    AccessLog(#[from] std::io::Error),
+    #[error("Opening the units-for-slot samples file: {0}")]
+    Sampling(#[source] std::io::Error),
+    #[error("Listening for SIGHUP to reload the slot overrides: {0}")]
+    SlotOverrides(#[source] std::io::Error),
+    #[error("Persisting the Validators to the config file: {0}")]
+    PersistValidators(#[source] config::Error),
+    #[error("Verifying the Market on startup: {0}")]
+    MarketProbe(#[from] market::ProbeError),
+    #[error(transparent)]
+    MarketUrl(#[from] market::base_url::InvalidMarketUrl),
+    #[error(transparent)]
+    MarketApi(#[from] market::BuildError),
+    /// The error of the units-for-slot request which was coalesced with identical ones
+    #[error("{0}")]
+    Coalesced(Arc<Error>),
 }
 
 impl From<http::uri::InvalidUri> for Error {
@@ -54,68 +116,596 @@ pub async fn serve(
     market_url: MarketUrl,
     config: Config,
 ) -> Result<(), Error> {
-    use hyper::service::{make_service_fn, service_fn};
+    // all of the Market URLs are checked before any of the networks is started
+    market::base_url::validate(&market_url)?;
+    for network in config.networks.values() {
+        market::base_url::validate(&network.market_url)?;
+    }
 
-    let proxy_client = market::Proxy::new(market_url.clone(), &config, logger.clone());
+    let build_info = BuildInfo::new(&market_url);
+    info!(
+        &logger,
+        "Supermarket build";
+        "version" => build_info.version,
+        "git commit" => build_info.git_commit,
+        "build timestamp" => build_info.build_timestamp,
+        "market host" => &build_info.market_host,
+    );
 
-    let market = Arc::new(MarketApi::new(market_url, &config, logger.clone())?);
+    #[cfg(feature = "cache-metrics")]
+    cache::lock::warn_on_waits_over(logger.clone(), config.cache_lock_wait_warning);
 
-    let cache = spawn_fetch_campaigns(logger.clone(), config.clone()).await?;
+    let shutdown = shutdown_signal(logger.clone()).shared();
 
-    // And a MakeService to handle each connection...
-    let make_service = make_service_fn(|_| {
-        let proxy_client = proxy_client.clone();
-        let cache = cache.clone();
-        let logger = logger.clone();
-        let market = market.clone();
-        let config = config.clone();
-        async move {
-            Ok::<_, Error>(service_fn(move |req| {
-                let proxy_client = proxy_client.clone();
-                let cache = cache.clone();
-                let market = market.clone();
-                let logger = logger.clone();
-                let config = config.clone();
-                async move {
-                    match handle(req, config, cache, proxy_client, logger.clone(), market).await {
-                        Err(error) => {
-                            error!(&logger, "Error ocurred"; "error" => ?error);
-                            Err(error)
-                        }
-                        Ok(resp) => Ok(resp),
-                    }
-                }
-            }))
+    let (default, default_updates) = start_network(
+        DEFAULT_NETWORK,
+        &logger,
+        market_url,
+        config.clone(),
+        shutdown.clone(),
+    )
+    .await?;
+    let mut cache_updates = vec![default_updates];
+    let mut networks = Networks::new(default);
+    for (name, network) in config.networks.iter() {
+        let network_config = config
+            .network(name)
+            .expect("The network should be in the Config");
+        let (upstream, updates) = start_network(
+            name,
+            &logger,
+            network.market_url.clone(),
+            network_config,
+            shutdown.clone(),
+        )
+        .await?;
+
+        cache_updates.push(updates);
+        networks = networks.with(upstream, &network.hosts);
+    }
+
+    if config.keep_warm.enabled {
+        let mut targets: Vec<Arc<dyn keep_warm::WarmUp>> = vec![];
+        for upstream in networks.iter() {
+            targets.push(upstream.market.clone());
+            if let Some(proxy) = &upstream.proxy {
+                targets.push(Arc::new(proxy.clone()));
+            }
+            targets.push(Arc::new(upstream.cache.client().clone()));
         }
-    });
 
-    // Then bind and serve...
-    let server = Server::bind(&addr).serve(make_service);
+        tokio::spawn(keep_warm::keep_warm(
+            logger.clone(),
+            targets,
+            config.keep_warm.interval,
+            shutdown.clone(),
+        ));
+    }
 
-    let graceful = server.with_graceful_shutdown(shutdown_signal(logger.clone()));
+    // Then bind and serve...
+    let servers = bind(&addr, logger.clone(), networks, shutdown.clone())?;
+    info!(&logger, "Web server listening on: {}", servers.addr);
+    if let Some(admin_addr) = servers.admin_addr {
+        info!(&logger, "Admin server listening on: {}", admin_addr);
+    }
+    let graceful = servers.graceful;
 
     // And run forever...
-    if let Err(e) = graceful.await {
+    let result = match config.server.shutdown_timeout {
+        Some(shutdown_timeout) => {
+            let timed_out = async {
+                shutdown.await;
+                tokio::time::delay_for(shutdown_timeout).await;
+            };
+
+            tokio::select! {
+                result = graceful => result,
+                _ = timed_out => {
+                    error!(&logger, "In-flight requests are still running after the shutdown timeout"; "shutdown_timeout" => ?shutdown_timeout);
+
+                    Ok(())
+                }
+            }
+        }
+        None => graceful.await,
+    };
+
+    if let Err(e) = result {
         error!(&logger, "server error: {}", e);
     }
 
+    // the Cache update in progress is either applied as a whole or discarded
+    for updates in cache_updates {
+        if let Err(error) = updates.await {
+            error!(&logger, "The task updating the Cache failed"; "error" => ?error);
+        }
+    }
+
     Ok(())
 }
 
-async fn handle<C: cache::Client>(
-    req: Request<Body>,
+/// Starts the network `name`: verifies its Market and spawns the tasks of its Cache,
+/// incl. the watchdog, the diagnostics, the reloading of the slot overrides, the prewarming and the media checks.
+///
+/// Returns the [`Upstream`] of the network and the handle of the task updating its Cache, see [`spawn_update_campaigns`].
+async fn start_network(
+    name: &str,
+    logger: &Logger,
+    market_url: MarketUrl,
     config: Config,
-    cache: Cache<C>,
-    market_proxy: Proxy,
+    shutdown: impl Future<Output = ()> + Clone + Send + 'static,
+) -> Result<(Upstream<cache::ApiClient>, JoinHandle<()>), Error> {
+    let logger = logger.new(slog::o!("network" => name.to_string()));
+
+    let market = Arc::new(MarketApi::new(market_url, &config, logger.clone())?);
+    let proxy = if config.proxy_enabled {
+        Some(Proxy::new(market.url().clone(), &config, logger.clone()))
+    } else {
+        warn!(
+            &logger,
+            "The proxy to the Market is DISABLED (`proxy_enabled = false`), only the routes of the Supermarket are served";
+            "market host" => market::market_host(market.url()),
+        );
+
+        None
+    };
+
+    market
+        .verify_on_start(&config.market.verify_market_on_start)
+        .await?;
+
+    let (cache, cache_updates) =
+        spawn_fetch_campaigns(name, logger.clone(), config.clone(), shutdown).await?;
+
+    spawn_watchdog(logger.clone(), cache.clone(), config.clone());
+
+    spawn_diagnostics_listener(
+        logger.clone(),
+        cache.clone(),
+        market.circuit_breaker().clone(),
+        config.clone(),
+    );
+
+    units_for_slot::overrides::spawn_reload_on_hangup(
+        logger.clone(),
+        cache.clone(),
+        config.clone(),
+    )
+    .map_err(Error::SlotOverrides)?;
+
+    if config.prewarm.is_enabled() {
+        spawn_prewarm(
+            logger.clone(),
+            market.clone(),
+            cache.clone(),
+            config.clone(),
+        );
+    }
+
+    if config.negative_slots.is_revalidated() {
+        spawn_negative_slots_revalidation(
+            logger.clone(),
+            market.clone(),
+            cache.clone(),
+            config.clone(),
+        );
+    }
+
+    if config.media_check.is_enabled() {
+        spawn_media_check(logger.clone(), cache.clone(), config.clone())?;
+    }
+
+    if config.anomaly_blocking.enabled {
+        anomaly::spawn_compaction(logger.clone(), cache.clone(), config.clone());
+    }
+
+    units_for_slot::serve_stats::spawn_flush(cache.clone(), config.clone());
+
+    let upstream = Upstream {
+        name: name.to_string(),
+        config,
+        cache,
+        proxy,
+        market,
+        logger,
+    };
+
+    Ok((upstream, cache_updates))
+}
+
+/// Which routes are served by a listener, see [`Config::admin_listen`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Listener {
+    /// All the routes, there's no separate admin listener
+    All,
+    /// All but the admin routes
+    Public,
+    /// Only the admin routes
+    Admin,
+}
+
+impl Listener {
+    /// The health, readiness, metrics & version routes are served by every listener
+    fn serves(self, path: &str) -> bool {
+        let is_shared = [ROUTE_HEALTHZ, ROUTE_READYZ, ROUTE_METRICS, ROUTE_VERSION].contains(&path);
+
+        match self {
+            Listener::All => true,
+            Listener::Public => is_shared || !is_admin_route(path),
+            Listener::Admin => is_shared || is_admin_route(path),
+        }
+    }
+}
+
+/// `/config`, `/validators`, `/validators/*`, `/stats/publishers/*`, `/campaigns/*/units`, `/campaigns/*/serve-stats` & `/internal/*`
+fn is_admin_route(path: &str) -> bool {
+    path == ROUTE_CONFIG
+        || campaigns::units_route(path).is_some()
+        || campaigns::serve_stats_route(path).is_some()
+        || path.starts_with(ROUTE_PUBLISHER_STATS)
+        || is_validators_route(path)
+        || path.starts_with(ROUTE_INTERNAL)
+}
+
+/// `/validators` & `/validators/*`, but not e.g. `/validatorsx`
+fn is_validators_route(path: &str) -> bool {
+    path == ROUTE_VALIDATORS
+        || path
+            .strip_prefix(ROUTE_VALIDATORS)
+            .map_or(false, |rest| rest.starts_with('/'))
+}
+
+/// The bound server and the admin one (if any), see [`bind`]
+struct Servers {
+    addr: SocketAddr,
+    admin_addr: Option<SocketAddr>,
+    /// Runs both servers until the `shutdown`, it fails if either of them fails
+    graceful: BoxFuture<'static, Result<(), hyper::Error>>,
+}
+
+/// Binds the server to the `addr` and, if the [`Config::admin_listen`] is set,
+/// the admin server to it. Both serve all the `networks` (see [`Networks::select`])
+/// with the settings of the default one and shut down gracefully on the `shutdown`.
+fn bind<C: cache::Client + Send + Sync + 'static>(
+    addr: &SocketAddr,
     logger: Logger,
-    market: Arc<MarketApi>,
+    networks: Networks<C>,
+    shutdown: impl Future<Output = ()> + Clone + Send + 'static,
+) -> Result<Servers, Error> {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let config = networks.default_network().config.clone();
+
+    let access_log = match config.access_log.path.as_ref() {
+        Some(path) => Some(access_log::AccessLog::open(
+            logger.clone(),
+            path,
+            config.access_log.buffer,
+        )?),
+        None => None,
+    };
+    let sampler = units_for_slot::sampling::Sampler::spawn(
+        logger.clone(),
+        &config.sampling,
+        config.timeouts.global_request,
+    )
+    .map_err(Error::Sampling)?;
+
+    // A MakeService to handle each connection of the listener...
+    let make_service = |listener: Listener| {
+        let networks = networks.clone();
+        let config = config.clone();
+        let access_log = access_log.clone();
+        let sampler = sampler.clone();
+
+        make_service_fn(move |conn: &AddrStream| {
+            let remote_addr = admin::RemoteAddr(conn.remote_addr());
+            let networks = networks.clone();
+            let config = config.clone();
+            let access_log = access_log.clone();
+            let sampler = sampler.clone();
+            async move {
+                Ok::<_, Error>(service_fn(move |mut req: Request<Body>| {
+                    req.extensions_mut().insert(remote_addr);
+                    if let Some(sampler) = sampler.as_ref() {
+                        req.extensions_mut().insert(sampler.clone());
+                    }
+                    // with the path prefix of the network (if any)
+                    let request_line = access_log.as_ref().map(|_| {
+                        access_log::RequestLine::new(
+                            &req,
+                            &config.trusted_proxies,
+                            networks.default_network().cache.clock().now_utc(),
+                        )
+                    });
+                    let upstream = networks.select(&mut req).clone();
+                    let logger = upstream.logger.clone();
+                    let access_log = access_log.clone();
+                    async move {
+                        match handle(req, listener, upstream).await {
+                            Err(error) => {
+                                error!(&logger, "Error ocurred"; "error" => ?error);
+                                Err(error)
+                            }
+                            Ok(resp) => {
+                                if let (Some(access_log), Some(request_line)) =
+                                    (access_log, request_line)
+                                {
+                                    access_log.log(request_line.format(&resp));
+                                }
+
+                                Ok(resp)
+                            }
+                        }
+                    }
+                }))
+            }
+        })
+    };
+
+    let public = if config.admin_listen.is_some() {
+        Listener::Public
+    } else {
+        Listener::All
+    };
+    let server = server_builder(addr, &config.server)?.serve(make_service(public));
+    let addr = server.local_addr();
+    let graceful = server.with_graceful_shutdown(shutdown.clone());
+
+    match config.admin_listen.as_ref() {
+        Some(admin_listen) => {
+            let admin_server =
+                server_builder(admin_listen, &config.server)?.serve(make_service(Listener::Admin));
+            let admin_addr = admin_server.local_addr();
+            let admin_graceful = admin_server.with_graceful_shutdown(shutdown);
+
+            Ok(Servers {
+                addr,
+                admin_addr: Some(admin_addr),
+                graceful: futures::future::try_join(graceful, admin_graceful)
+                    .map(|result| result.map(drop))
+                    .boxed(),
+            })
+        }
+        None => Ok(Servers {
+            addr,
+            admin_addr: None,
+            graceful: graceful.boxed(),
+        }),
+    }
+}
+
+/// Binds the [`Server`] to the `addr` with the [`config::Server`] settings
+fn server_builder(
+    addr: &SocketAddr,
+    config: &config::Server,
+) -> Result<hyper::server::Builder<AddrIncoming>, Error> {
+    let builder = Server::try_bind(addr)?
+        .tcp_nodelay(config.tcp_nodelay)
+        .tcp_sleep_on_accept_errors(config.sleep_on_errors)
+        .http1_keepalive(config.http1_keepalive)
+        .http1_pipeline_flush(config.http1_pipeline_flush)
+        .http1_max_buf_size(config.max_buf_size);
+
+    Ok(builder)
+}
+
+/// The `route` label of the in-flight requests metrics
+fn route_label(path: &str) -> &'static str {
+    match path {
+        route if route.starts_with(ROUTE_UNITS_FOR_SLOT) => "units_for_slot",
+        route if route == ROUTE_HEALTHZ => "healthz",
+        route if route == ROUTE_READYZ => "readyz",
+        route if route == ROUTE_METRICS => "metrics",
+        route if route == ROUTE_VERSION => "version",
+        route if route == ROUTE_OPENAPI => "openapi",
+        route if campaigns::balances_route(route).is_some() => "campaign_balances",
+        route if campaigns::units_route(route).is_some() => "campaign_units",
+        route if campaigns::serve_stats_route(route).is_some() => "campaign_serve_stats",
+        route if route == ROUTE_STATS => "stats",
+        route if route.starts_with(ROUTE_PUBLISHER_STATS) => "publisher_stats",
+        route if route == ROUTE_CONFIG => "config",
+        route if route == ROUTE_VALIDATORS_REFRESH => "validators_refresh",
+        route if route == ROUTE_CACHE_SNAPSHOT => "cache_snapshot",
+        route if is_validators_route(route) => "validators",
+        _ => "market_proxy",
+    }
+}
+
+async fn handle<C: cache::Client + Send + Sync + 'static>(
+    mut req: Request<Body>,
+    listener: Listener,
+    upstream: Upstream<C>,
+) -> Result<Response<Body>, Error> {
+    let _in_flight = metrics::InFlight::start(&upstream.name, route_label(req.uri().path()));
+
+    if !req.headers().contains_key(util::REQUEST_ID_HEADER) {
+        let request_id = HeaderValue::from_str(&util::new_request_id())
+            .expect("The generated request ID should be a valid header value");
+        req.headers_mut()
+            .insert(util::REQUEST_ID_HEADER, request_id);
+    }
+    let request_id = req.headers()[util::REQUEST_ID_HEADER].clone();
+
+    // the blocked client IPs & AdSlots are not routed, see `anomaly_blocking`
+    let anomaly_tracker =
+        anomaly::Tracker::of(&req, &upstream.config, &upstream.cache, &upstream.logger);
+    let blocked = match &anomaly_tracker {
+        Some(tracker) => tracker.blocked_response().await,
+        None => None,
+    };
+
+    let is_blocked = blocked.is_some();
+
+    let mut response = match blocked {
+        Some(blocked) => blocked,
+        None => match route(req, listener, upstream).await {
+            Ok(response) => response,
+            Err(error) => {
+                error_reporting::report_error(
+                    &format!("Handling request failed: {}", error),
+                    &[("request_id", request_id.to_str().unwrap_or_default())],
+                );
+
+                return Err(error);
+            }
+        },
+    };
+    // the blocked requests themselves are not counted
+    match &anomaly_tracker {
+        Some(tracker) if !is_blocked => tracker.record(response.status()).await,
+        _ => {}
+    }
+    response
+        .headers_mut()
+        .insert(util::REQUEST_ID_HEADER, request_id);
+
+    Ok(response)
+}
+
+async fn route<C: cache::Client + Send + Sync + 'static>(
+    req: Request<Body>,
+    listener: Listener,
+    upstream: Upstream<C>,
 ) -> Result<Response<Body>, Error> {
-    let is_units_for_slot = req.uri().path().starts_with(ROUTE_UNITS_FOR_SLOT);
+    let Upstream {
+        config,
+        cache,
+        proxy: market_proxy,
+        market,
+        logger,
+        ..
+    } = upstream;
+    let path = req.uri().path();
+    if !listener.serves(path) {
+        return Ok(not_found());
+    }
+
+    let is_units_for_slot = path.starts_with(ROUTE_UNITS_FOR_SLOT);
+    // `/validators/:host`
+    let validator_host = path
+        .strip_prefix(ROUTE_VALIDATORS)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|host| !host.is_empty());
+    let campaign_balances = campaigns::balances_route(path);
+    let campaign_units = campaigns::units_route(path);
+    let campaign_serve_stats = campaigns::serve_stats_route(path);
+    // `/stats/publishers/:address`
+    let publisher_stats = path
+        .strip_prefix(ROUTE_PUBLISHER_STATS)
+        .filter(|address| !address.is_empty() && !address.contains('/'));
+    // the proxied `POST /channel/:id/events` are validated before they are proxied
+    let events_submission = config.proxy.events.enabled
+        && req.method() == Method::POST
+        && market::events::submission_route(path).is_some();
+    // the proxied `GET /slots/:ipfs` warms the cached AdSlots of the units-for-slot
+    let proxied_slot = units_for_slot::prewarm::slot_route(path)
+        .filter(|_| req.method() == Method::GET)
+        .map(ToString::to_string);
+
+    match (path, req.method()) {
+        (route, &Method::GET) if route == ROUTE_HEALTHZ => Ok(ok()),
+        (route, &Method::GET) if route == ROUTE_READYZ => {
+            if !cache.is_ready(&config).await {
+                Ok(service_unavailable())
+            } else {
+                Ok(ok())
+            }
+        }
+        (route, &Method::GET) if route == ROUTE_METRICS => {
+            let build_info = BuildInfo::new(market.url());
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Body::from(metrics::encode(&build_info.labels())?))?)
+        }
+        (route, &Method::GET) if route == ROUTE_VERSION => {
+            let build_info = BuildInfo::new(market.url());
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(&build_info)?))?)
+        }
+        (route, &Method::GET) if route == ROUTE_OPENAPI => openapi::get_openapi(),
+        (route, &Method::GET) if route == ROUTE_CONFIG => match admin::authorize(&req, &config) {
+            Ok(()) => admin::get_config(&config, &cache).await,
+            Err(response) => Ok(response),
+        },
+        (route, &Method::POST) if route == ROUTE_VALIDATORS_REFRESH => {
+            match admin::authorize(&req, &config) {
+                Ok(()) => {
+                    admin::idempotency::idempotent(req, &cache, &config, |req| {
+                        admin::refresh_validator(req, &cache)
+                    })
+                    .await
+                }
+                Err(response) => Ok(response),
+            }
+        }
+        (route, &Method::GET) if route == ROUTE_CACHE_SNAPSHOT => {
+            match admin::authorize(&req, &config) {
+                Ok(()) => admin::get_cache_snapshot(&cache).await,
+                Err(response) => Ok(response),
+            }
+        }
+        (route, &Method::GET) if route == ROUTE_VALIDATORS => match admin::authorize(&req, &config)
+        {
+            Ok(()) => admin::get_validators(&cache).await,
+            Err(response) => Ok(response),
+        },
+        (route, &Method::PUT) if route == ROUTE_VALIDATORS => match admin::authorize(&req, &config)
+        {
+            Ok(()) => {
+                let config = &config;
+                admin::idempotency::idempotent(req, &cache, config, |req| {
+                    admin::put_validators(req, &cache, config)
+                })
+                .await
+            }
+            Err(response) => Ok(response),
+        },
+        (_, &Method::DELETE) if validator_host.is_some() => match admin::authorize(&req, &config) {
+            Ok(()) => {
+                // the host is borrowed from the request, which is buffered for its `Idempotency-Key`
+                let host = validator_host.unwrap_or_default().to_string();
+                let persist = admin::persist_requested(req.uri().query());
+                let (cache, config) = (&cache, &config);
+                admin::idempotency::idempotent(req, cache, config, |_| async move {
+                    admin::delete_validator(&host, persist, cache, config).await
+                })
+                .await
+            }
+            Err(response) => Ok(response),
+        },
+        (_, &Method::GET) if campaign_balances.is_some() => {
+            campaigns::get_balances(campaign_balances.unwrap_or_default(), &cache).await
+        }
+        (_, &Method::GET) if campaign_units.is_some() => match admin::authorize(&req, &config) {
+            Ok(()) => {
+                admin::get_campaign_units(campaign_units.unwrap_or_default(), &cache, &config).await
+            }
+            Err(response) => Ok(response),
+        },
+        (_, &Method::GET) if campaign_serve_stats.is_some() => {
+            match admin::authorize(&req, &config) {
+                Ok(()) => {
+                    let channel_id = campaign_serve_stats.unwrap_or_default();
 
-    match (is_units_for_slot, req.method()) {
-        (true, &Method::GET) => {
+                    admin::get_campaign_serve_stats(channel_id, &cache, &config).await
+                }
+                Err(response) => Ok(response),
+            }
+        }
+        (route, &Method::GET) if route == ROUTE_STATS => campaigns::get_stats(&cache).await,
+        (_, &Method::GET) if publisher_stats.is_some() => match admin::authorize(&req, &config) {
+            Ok(()) => admin::get_publisher_stats(publisher_stats.unwrap_or_default(), &cache).await,
+            Err(response) => Ok(response),
+        },
+        (_, &Method::GET) | (_, &Method::POST) if is_units_for_slot => {
             let mut response =
-                get_units_for_slot(&logger, market.clone(), &config, &cache, req).await?;
+                get_units_for_slot_coalesced(logger, market, config, cache, req).await?;
 
             response
                 .headers_mut()
@@ -123,14 +713,54 @@ async fn handle<C: cache::Client>(
 
             Ok(response)
         }
-        _ => match market_proxy.proxy(req).await {
-            Ok(response) => Ok(response),
-            Err(err) => {
-                error!(&logger, "Proxying request to market failed"; "error" => ?err);
-
-                Ok(service_unavailable())
+        // without the proxy (see `proxy_enabled`) the rest of the routes are not found,
+        // with it only the allowed paths are proxied (see `proxy.paths`)
+        _ => match market_proxy {
+            Some(_) if !market::paths::is_proxied(path, &config.proxy.paths) => {
+                Ok(market::paths::refused())
+            }
+            Some(market_proxy) if events_submission => {
+                match market::events::validate(req, &config.proxy.events).await {
+                    Ok(req) => {
+                        proxy_to_market(&logger, &cache, &config, &market_proxy, req, None).await
+                    }
+                    Err(response) => Ok(response),
+                }
+            }
+            Some(market_proxy) => {
+                proxy_to_market(&logger, &cache, &config, &market_proxy, req, proxied_slot).await
             }
+            None => Ok(not_found()),
+        },
+    }
+}
+
+/// Proxies the request to the Market, warming the AdSlot of the proxied `GET /slots/:ipfs`,
+/// `503 Service Unavailable` if it fails
+async fn proxy_to_market<C: cache::Client + Send + Sync + 'static>(
+    logger: &Logger,
+    cache: &Cache<C>,
+    config: &Config,
+    market_proxy: &Proxy,
+    req: Request<Body>,
+    proxied_slot: Option<String>,
+) -> Result<Response<Body>, Error> {
+    match market_proxy.proxy(req).await {
+        Ok(response) => match proxied_slot {
+            Some(ipfs) => Ok(units_for_slot::prewarm::warm_from_proxied(
+                logger, cache, config, &ipfs, response,
+            )
+            .await?),
+            None => Ok(response),
         },
+        Err(err) => {
+            let message = "Proxying request to market failed";
+            if let Some(suppressed) = util::ERROR_SAMPLER.sample(message, &["error"]) {
+                error!(logger, "{}", message; "error" => ?err, "suppressed" => suppressed);
+            }
+
+            Ok(service_unavailable())
+        }
     }
 }
 
@@ -143,13 +773,52 @@ async fn shutdown_signal(logger: Logger) {
 }
 
 async fn spawn_fetch_campaigns(
+    network: &str,
     logger: Logger,
     config: Config,
-) -> Result<Cache<cache::ApiClient>, Error> {
-    let api_client = cache::ApiClient::init(logger.clone(), config.clone()).await?;
-    let cache = Cache::initialize(api_client).await;
+    shutdown: impl Future<Output = ()> + Clone + Send + 'static,
+) -> Result<(Cache<cache::ApiClient>, JoinHandle<()>), Error> {
+    // the Campaign statuses and the staleness of the Cache & the units-for-slot follow the same clock
+    let clock: Arc<dyn util::Clock> = Arc::new(util::SystemClock);
+    let api_client = cache::ApiClient::init(logger.clone(), config.clone())
+        .await?
+        .with_clock(clock.clone());
+    let builder = Cache::builder(api_client)
+        .network(network)
+        .clock(clock)
+        .limits((&config.limits).into())
+        .stats_assets(config.stats.assets.clone())
+        .slot_overrides(config.slot_overrides.clone())
+        .validator_allowlist((&config).into());
+    let cache = match config.warm_from.as_ref() {
+        Some(replica) => {
+            let admin_token = config.admin_token.as_ref().map(|token| token.expose());
 
-    let cache_spawn = cache.clone();
+            builder
+                .initialize_warm_from(replica, admin_token, config.timeouts.global_request)
+                .await
+        }
+        // the Campaigns are fetched by the task updating the Cache, while the requests are served
+        None => builder.build(),
+    };
+
+    let cache_updates = spawn_update_campaigns(logger, cache.clone(), config, shutdown);
+
+    Ok((cache, cache_updates))
+}
+
+/// Initializes the Cache if it's not yet (see [`Cache::fetch_initial_campaigns`]), then
+/// every `fetch_campaigns_every` fetches the new Campaigns and every `update_campaigns_every` updates the Active ones.
+///
+/// On the `shutdown` the fetching from the Validators in progress is aborted and its results are discarded,
+/// while applying the already fetched ones is always completed, so the Cache (and its snapshots) is never partially updated.
+/// The returned handle completes once the task has stopped.
+fn spawn_update_campaigns<C: cache::Client + Send + Sync + 'static>(
+    logger: Logger,
+    cache: Cache<C>,
+    config: Config,
+    shutdown: impl Future<Output = ()> + Clone + Send + 'static,
+) -> JoinHandle<()> {
     // Every few minutes, we will get the non-finalized from the market,
     // in order to keep discovering new campaigns.
     tokio::spawn(async move {
@@ -157,6 +826,14 @@ async fn spawn_fetch_campaigns(
         use tokio::time::{interval, timeout, Instant};
         info!(&logger, "Task for updating campaign has been spawned");
 
+        if !cache.is_initialized().await && !cache.fetch_initial_campaigns(shutdown.clone()).await {
+            info!(
+                &logger,
+                "Shutting down, the Cache initialization was aborted"
+            );
+            return;
+        }
+
         // Every X seconds, we will update our active campaigns from the
         // validators (update their latest balance tree).
         let new_interval = interval(config.fetch_campaigns_every).map(TimeFor::New);
@@ -169,25 +846,62 @@ async fn spawn_fetch_campaigns(
 
         let mut select_time = select(new_interval, update_interval);
 
-        while let Some(time_for) = select_time.next().await {
+        loop {
+            let time_for = tokio::select! {
+                time_for = select_time.next() => time_for,
+                _ = shutdown.clone() => None,
+            };
+
             match time_for {
-                TimeFor::New(_) => {
+                Some(TimeFor::New(_)) => {
                     let timeout_duration = config.timeouts.cache_fetch_campaigns_from_market;
+                    let collect = timeout(timeout_duration, cache.collect_new_campaigns());
+
+                    let collected = tokio::select! {
+                        collected = collect => collected,
+                        _ = shutdown.clone() => {
+                            info!(&logger, "Shutting down, discarding the new Campaigns being fetched");
+                            break;
+                        }
+                    };
 
-                    match timeout(timeout_duration, cache_spawn.fetch_new_campaigns()).await {
+                    match collected {
                         Err(_elapsed) => error!(
                             &logger,
                             "Fetching new Campaigns timed out";
                             "allowed secs" => timeout_duration.as_secs()
                         ),
-                        _ => info!(&logger, "New Campaigns fetched from Validators!"),
+                        Ok(campaigns) => {
+                            cache.apply_new_campaigns(campaigns).await;
+                            info!(&logger, "New Campaigns fetched from Validators!")
+                        }
                     }
                 }
-                TimeFor::Update(_) => {
+                Some(TimeFor::Update(_)) => {
                     let timeout_duration = config.timeouts.cache_update_campaign_statuses;
+                    let collect = timeout(timeout_duration, cache.collect_campaign_updates());
+
+                    let collected = tokio::select! {
+                        collected = collect => collected,
+                        _ = shutdown.clone() => {
+                            info!(&logger, "Shutting down, discarding the Campaign updates being fetched");
+                            break;
+                        }
+                    };
 
-                    match timeout(timeout_duration, cache_spawn.fetch_campaign_updates()).await {
-                        Ok(_) => info!(&logger, "Campaigns statuses updated from Validators!"),
+                    match collected {
+                        Ok(updates) => {
+                            let diff = cache.apply_campaign_updates(updates).await;
+                            info!(
+                                &logger,
+                                "Campaigns statuses updated from Validators!";
+                                "added" => diff.added.len(),
+                                "removed" => diff.removed.len(),
+                                "status changed" => diff.status_changed.len(),
+                                "balances changed" => diff.balances_changed.len(),
+                                "spec changed" => diff.spec_changed.len(),
+                            )
+                        }
                         Err(_elapsed) => error!(
                                 &logger,
                                 "Updating Campaigns statuses timed out";
@@ -195,11 +909,186 @@ async fn spawn_fetch_campaigns(
                         ),
                     }
                 }
+                None => break,
             }
         }
+
+        info!(&logger, "Task for updating campaigns has stopped");
+    })
+}
+
+/// Every `update_campaigns_every` checks if the Cache has become stale,
+/// i.e. the task updating the Cache is stuck or keeps timing out,
+/// and whether it's degraded, see [`DegradationPolicy`](config::DegradationPolicy).
+fn spawn_watchdog(logger: Logger, cache: Cache<cache::ApiClient>, config: Config) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(config.update_campaigns_every);
+        let mut was_stale = false;
+        let mut was_degraded = false;
+
+        loop {
+            ticks.tick().await;
+
+            let is_stale = cache.is_stale(&config).await;
+            match (was_stale, is_stale) {
+                (false, true) => {
+                    metrics::CACHE_STALE.inc();
+
+                    let last_runs = *cache.last_runs.read().await;
+                    error!(
+                        &logger,
+                        "Cache is stale, Campaigns were not updated in time";
+                        "last runs" => ?last_runs,
+                        "watchdog multiplier" => config.watchdog_multiplier
+                    );
+                }
+                (true, false) => info!(&logger, "Cache is up to date again"),
+                _ => {}
+            }
+
+            was_stale = is_stale;
+
+            if let Some(max_staleness) = config.max_campaign_staleness {
+                cache.check_staleness(max_staleness).await;
+            }
+
+            let is_degraded = cache.is_degraded(&config).await;
+            let policy = config.degradation_policy.as_str();
+            match (was_degraded, is_degraded) {
+                (false, true) => {
+                    error!(&logger, "Cache is degraded, units-for-slot is served by the degradation policy"; "policy" => policy)
+                }
+                (true, false) => info!(&logger, "Cache is no longer degraded"; "policy" => policy),
+                _ => {}
+            }
+            metrics::CACHE_DEGRADED
+                .with_label_values(&[cache.network(), policy])
+                .set(is_degraded as i64);
+            was_degraded = is_degraded;
+        }
     });
+}
+
+/// Every `refresh_margin` refreshes the most requested AdSlots before they expire from the slot cache,
+/// while the Market keeps failing the refreshes are backed off (see [`MarketApi::circuit_breaker`]).
+fn spawn_prewarm(
+    logger: Logger,
+    market: Arc<MarketApi>,
+    cache: Cache<cache::ApiClient>,
+    config: Config,
+) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(config.prewarm.refresh_margin);
+
+        loop {
+            ticks.tick().await;
+
+            let refreshed =
+                units_for_slot::prewarm::refresh_popular_slots(&logger, &market, &cache, &config)
+                    .await;
 
-    Ok(cache)
+            if !refreshed.is_empty() {
+                debug!(&logger, "Pre-warmed {} AdSlots", refreshed.len(); "AdSlots" => ?refreshed);
+            }
+        }
+    });
+}
+
+/// Revalidates the recently negative cached AdSlots every `first_interval`,
+/// see [`negative_slots`](units_for_slot::negative_slots)
+fn spawn_negative_slots_revalidation(
+    logger: Logger,
+    market: Arc<MarketApi>,
+    cache: Cache<cache::ApiClient>,
+    config: Config,
+) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(config.negative_slots.first_interval);
+
+        loop {
+            ticks.tick().await;
+
+            let promoted = units_for_slot::negative_slots::revalidate_negative_slots(
+                &logger, &market, &cache, &config,
+            )
+            .await;
+
+            if !promoted.is_empty() {
+                debug!(&logger, "{} negative cached AdSlots are in the Market now", promoted.len(); "AdSlots" => ?promoted);
+            }
+        }
+    });
+}
+
+/// Checks the media of the served AdUnits one at a time, at most `max_checks_per_second`,
+/// see [`media_check`](units_for_slot::media_check)
+fn spawn_media_check(
+    logger: Logger,
+    cache: Cache<cache::ApiClient>,
+    config: Config,
+) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(config.media_check.timeout)
+        .build()?;
+
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(config.media_check.interval());
+
+        loop {
+            ticks.tick().await;
+
+            units_for_slot::media_check::check_next(
+                &logger,
+                &client,
+                &cache,
+                config.media_check.ttl,
+            )
+            .await;
+        }
+    });
+
+    Ok(())
+}
+
+/// On every `SIGUSR1` signal it logs the current Config and the Cache diagnostics (incl. the Market's `circuit_breaker`)
+fn spawn_diagnostics_listener(
+    logger: Logger,
+    cache: Cache<cache::ApiClient>,
+    circuit_breaker: util::CircuitBreaker,
+    config: Config,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user_signal =
+        signal(SignalKind::user_defined1()).expect("failed to install SIGUSR1 signal handler");
+
+    tokio::spawn(async move {
+        while user_signal.recv().await.is_some() {
+            info!(&logger, "SIGUSR1 received, dumping diagnostics"; "config" => ?config);
+
+            cache.dump_diagnostics(&logger, &circuit_breaker).await;
+            info!(
+                &logger,
+                "Cache degradation";
+                "degraded" => cache.is_degraded(&config).await,
+                "policy" => config.degradation_policy.as_str(),
+            );
+        }
+    });
+}
+
+pub(crate) fn ok() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::empty())
+        .expect("OK response should be valid")
+}
+
+pub(crate) fn bad_request(message: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(message))
+        .expect("Bad Request response should be valid")
 }
 
 pub(crate) fn not_found() -> Response<Body> {
@@ -209,9 +1098,938 @@ pub(crate) fn not_found() -> Response<Body> {
         .expect("Not Found response should be valid")
 }
 
+pub(crate) fn gone() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::GONE)
+        .body(Body::empty())
+        .expect("Gone response should be valid")
+}
+
 pub(crate) fn service_unavailable() -> Response<Body> {
     Response::builder()
         .status(StatusCode::SERVICE_UNAVAILABLE)
         .body(Body::empty())
         .expect("Bad Request response should be valid")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cache::MockClient, config::DEVELOPMENT, util::test::discard_logger};
+    use std::collections::HashMap;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    /// The `name`d network with the Market at the `market_url` and the `cache`
+    fn upstream<C: cache::Client>(
+        name: &str,
+        config: &Config,
+        cache: Cache<C>,
+        market_url: MarketUrl,
+        logger: Logger,
+    ) -> Upstream<C> {
+        Upstream {
+            name: name.to_string(),
+            config: config.clone(),
+            cache,
+            proxy: if config.proxy_enabled {
+                Some(Proxy::new(market_url.clone(), config, logger.clone()))
+            } else {
+                None
+            },
+            market: Arc::new(
+                MarketApi::new(market_url, config, logger.clone())
+                    .expect("should create market instance"),
+            ),
+            logger,
+        }
+    }
+
+    #[tokio::test]
+    async fn version_route_is_handled_locally() {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+
+        // nothing should be proxied to the Market
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0_u64)
+            .mount(&server)
+            .await;
+
+        let market_url: MarketUrl = (server.uri() + "/market/")
+            .parse()
+            .expect("Wrong Market url");
+        let cache =
+            Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+        let upstream = upstream(
+            DEFAULT_NETWORK,
+            &DEVELOPMENT,
+            cache,
+            market_url.clone(),
+            logger,
+        );
+
+        let request = Request::get(ROUTE_VERSION)
+            .body(Body::empty())
+            .expect("Should build Request");
+
+        let response = handle(request, Listener::All, upstream)
+            .await
+            .expect("Should handle request");
+
+        assert_eq!(StatusCode::OK, response.status());
+        // a request ID is generated for requests without one
+        assert!(response.headers().contains_key(util::REQUEST_ID_HEADER));
+
+        let build_info: serde_json::Value =
+            serde_json::from_slice(&hyper::body::to_bytes(response).await.unwrap())
+                .expect("Should deserialize");
+
+        assert_eq!(env!("CARGO_PKG_VERSION"), build_info["version"]);
+        assert_eq!(build_info::GIT_COMMIT, build_info["gitCommit"]);
+        assert!(build_info["buildTimestamp"].is_string());
+        assert_eq!(market::market_host(&market_url), build_info["marketHost"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_units_for_slot_paths_are_bad_requests() {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+
+        // nothing should be requested from or proxied to the Market
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0_u64)
+            .mount(&server)
+            .await;
+
+        let market_url: MarketUrl = (server.uri() + "/market/")
+            .parse()
+            .expect("Wrong Market url");
+        let cache =
+            Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+        let upstream = upstream(DEFAULT_NETWORK, &DEVELOPMENT, cache, market_url, logger);
+
+        for path in &[
+            "/units-for-slot/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C",
+            "/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C/",
+            "/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C%2Fslots",
+        ] {
+            let request = Request::get(*path)
+                .body(Body::empty())
+                .expect("Should build Request");
+
+            let response = handle(request, Listener::All, upstream.clone())
+                .await
+                .expect("Should handle request");
+
+            assert_eq!(StatusCode::BAD_REQUEST, response.status(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn generated_units_for_slot_paths_are_routed_by_their_ad_slot_path() {
+        use proptest::{prelude::*, test_runner::TestRunner};
+        use units_for_slot::AdSlotPath;
+        use wiremock::matchers::path_regex;
+
+        let runtime = tokio::runtime::Runtime::new().expect("Should build the Runtime");
+        let (server, upstream) = runtime.handle().block_on(async {
+            let server = MockServer::start().await;
+            // only the single alphanumeric ipfs segment is fetched as an AdSlot
+            Mock::given(method("GET"))
+                .and(path_regex("^/market/slots/[a-zA-Z0-9]+$"))
+                .respond_with(ResponseTemplate::new(404))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(0_u64)
+                .mount(&server)
+                .await;
+
+            let market_url: MarketUrl = (server.uri() + "/market/")
+                .parse()
+                .expect("Wrong Market url");
+            let cache =
+                Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+            let upstream = upstream(
+                DEFAULT_NETWORK,
+                &DEVELOPMENT,
+                cache,
+                market_url,
+                discard_logger(),
+            );
+
+            (server, upstream)
+        });
+
+        let paths = prop_oneof![
+            "/units-for-slot/[a-zA-Z0-9/%.\\-]{0,64}",
+            "(/units-for-slot)+/[a-zA-Z0-9]{1,46}(/|%2F|%2f|/units-for-slot/)?[a-zA-Z0-9]{0,8}",
+        ];
+        let mut runner = TestRunner::default();
+        runner
+            .run(&paths, |path| {
+                let request = Request::get(path.as_str())
+                    .body(Body::empty())
+                    .expect("Should build Request");
+                let response = runtime
+                    .handle()
+                    .block_on(handle(request, Listener::All, upstream.clone()))
+                    .expect("Should handle request");
+
+                let expected = match AdSlotPath::parse(&path) {
+                    AdSlotPath::Invalid => StatusCode::BAD_REQUEST,
+                    // the Market doesn't have the AdSlot
+                    AdSlotPath::Ipfs(_) | AdSlotPath::Missing => StatusCode::NOT_FOUND,
+                };
+                prop_assert_eq!(expected, response.status(), "{}", path);
+
+                Ok(())
+            })
+            .expect("Should route every generated path");
+
+        // verifies that nothing but the AdSlots was requested from the Market
+        drop(server);
+    }
+
+    #[tokio::test]
+    async fn clients_with_too_many_client_errors_are_blocked_until_it_expires() {
+        use crate::util::test::MockClock;
+
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0_u64)
+            .mount(&server)
+            .await;
+        let market_url: MarketUrl = (server.uri() + "/market/")
+            .parse()
+            .expect("Wrong Market url");
+
+        let mut config = DEVELOPMENT.clone();
+        config.anomaly_blocking.enabled = true;
+        config.anomaly_blocking.ip_threshold = 3;
+        config.anomaly_blocking.slot_threshold = 0;
+        let settings = config.anomaly_blocking;
+
+        let clock = MockClock::new();
+        let cache = Cache::builder(MockClient::init(vec![HashMap::new()], vec![], None).await)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        let upstream = upstream(DEFAULT_NETWORK, &config, cache.clone(), market_url, logger);
+
+        let request = |path: &str, ip: &str| {
+            let mut request = Request::get(path)
+                .body(Body::empty())
+                .expect("Should build Request");
+            request.extensions_mut().insert(admin::RemoteAddr(
+                format!("{}:51000", ip).parse().expect("Valid address"),
+            ));
+
+            request
+        };
+        let status = |request: Request<Body>| {
+            let upstream = upstream.clone();
+
+            async move {
+                handle(request, Listener::All, upstream)
+                    .await
+                    .expect("Should handle request")
+            }
+        };
+        let invalid_path = "/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C/";
+
+        for _ in 0..3 {
+            let response = status(request(invalid_path, "10.0.0.1")).await;
+            assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        }
+
+        let blocked = status(request(ROUTE_STATS, "10.0.0.1")).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, blocked.status());
+        assert_eq!(
+            settings.block_duration.as_secs().to_string(),
+            blocked.headers()[http::header::RETRY_AFTER]
+        );
+        // the other clients and the health routes are not blocked
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            status(request(invalid_path, "10.0.0.2")).await.status()
+        );
+        assert_eq!(
+            StatusCode::OK,
+            status(request(ROUTE_HEALTHZ, "10.0.0.1")).await.status()
+        );
+        assert_eq!(1, cache.stats().await.blocked.ips);
+        assert_eq!(0, cache.stats().await.blocked.slots);
+
+        clock.advance(settings.block_duration);
+        assert_eq!(0, cache.stats().await.blocked.ips);
+        assert_eq!(
+            StatusCode::OK,
+            status(request(ROUTE_STATS, "10.0.0.1")).await.status()
+        );
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            status(request(invalid_path, "10.0.0.1")).await.status()
+        );
+    }
+
+    #[tokio::test]
+    async fn the_cache_is_warmed_up_from_a_running_replica() {
+        use crate::{
+            cache::{CacheBuilder, Campaign},
+            config::Secret,
+            status::Status,
+            util::{test::MockClock, Clock},
+        };
+        use chrono::Utc;
+        use hyper::service::{make_service_fn, service_fn};
+        use primitives::{
+            util::{tests::prep_db::DUMMY_CHANNEL, ApiUrl},
+            ChannelId,
+        };
+        use std::{convert::Infallible, time::Duration};
+        use wiremock::matchers::path;
+
+        let logger = discard_logger();
+        let mut config = DEVELOPMENT.clone();
+        config.admin_token = Some(Secret::from("admin-token".to_string()));
+
+        fn campaign(id: u8) -> Campaign {
+            let mut channel = DUMMY_CHANNEL.clone();
+            channel.id = ChannelId::from([id; 32]);
+
+            Campaign::new(channel, Status::Active, Default::default())
+        }
+        fn campaigns_of(ids: &[u8]) -> HashMap<ChannelId, Campaign> {
+            ids.iter()
+                .map(|id| campaign(*id))
+                .map(|campaign| (campaign.channel.id, campaign))
+                .collect()
+        }
+        // the new replica, its own Validators have other Campaigns
+        async fn new_replica(clock: &MockClock) -> CacheBuilder<MockClient> {
+            let client = MockClient::init(vec![campaigns_of(&[3])], vec![], None).await;
+
+            Cache::builder(client).clock(Arc::new(clock.clone()))
+        }
+
+        // the running replica, its Campaigns were refreshed a minute before the snapshot
+        let replica_clock = MockClock::new();
+        let replica_cache = Cache::builder(
+            MockClient::init(vec![campaigns_of(&[1, 2])], vec![], logger.clone()).await,
+        )
+        .clock(Arc::new(replica_clock.clone()))
+        .initialize()
+        .await;
+        replica_clock.advance(Duration::from_secs(60));
+
+        let market_url: MarketUrl = "http://localhost:4000/market/"
+            .parse()
+            .expect("Wrong Market url");
+        let replica_upstream = upstream(
+            DEFAULT_NETWORK,
+            &config,
+            replica_cache,
+            market_url,
+            logger.clone(),
+        );
+
+        let server = server_builder(&"127.0.0.1:0".parse().unwrap(), &config.server)
+            .expect("Should bind the Server")
+            .serve(make_service_fn(move |_| {
+                let upstream = replica_upstream.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        handle(req, Listener::All, upstream.clone())
+                    }))
+                }
+            }));
+        let replica: ApiUrl = format!("http://{}/", server.local_addr())
+            .parse()
+            .expect("Wrong replica url");
+        tokio::spawn(server);
+
+        let timeout = Duration::from_secs(5);
+
+        let clock = MockClock::new();
+        let warm = new_replica(&clock)
+            .await
+            .initialize_warm_from(&replica, Some("admin-token"), timeout)
+            .await;
+        let mut warm_campaigns = warm.active.read().await.keys().copied().collect::<Vec<_>>();
+        warm_campaigns.sort();
+        assert_eq!(
+            vec![campaign(1).channel.id, campaign(2).channel.id],
+            warm_campaigns
+        );
+        for (channel_id, refreshed) in warm.refreshed.read().await.iter() {
+            let age = clock.now_instant() - refreshed.instant;
+            assert!(
+                age >= Duration::from_secs(59),
+                "The refresh age of {} should be kept, got: {:?}",
+                channel_id,
+                age
+            );
+        }
+
+        // unauthorized, the Campaigns are fetched from the Validators instead
+        let cold = new_replica(&clock)
+            .await
+            .initialize_warm_from(&replica, Some("wrong-token"), timeout)
+            .await;
+        assert_eq!(
+            vec![campaign(3).channel.id],
+            cold.active.read().await.keys().copied().collect::<Vec<_>>()
+        );
+
+        // a snapshot of another version
+        let other_version = MockServer::start().await;
+        Mock::given(path("/internal/cache-snapshot"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "version": cache::snapshot::SNAPSHOT_VERSION + 1,
+                "takenAt": Utc::now(),
+                "active": [],
+                "finalized": [],
+            })))
+            .mount(&other_version)
+            .await;
+        let other_version: ApiUrl = (other_version.uri() + "/")
+            .parse()
+            .expect("Wrong replica url");
+
+        let cold = new_replica(&clock)
+            .await
+            .initialize_warm_from(&other_version, Some("admin-token"), timeout)
+            .await;
+        assert_eq!(
+            vec![campaign(3).channel.id],
+            cold.active.read().await.keys().copied().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_routes_are_served_only_on_the_admin_listener() {
+        use crate::config::Secret;
+        use std::time::Duration;
+        use tokio::sync::oneshot;
+
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+
+        // nothing should be proxied to the Market
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0_u64)
+            .mount(&server)
+            .await;
+
+        let mut config = DEVELOPMENT.clone();
+        config.admin_token = Some(Secret::from("admin-token".to_string()));
+        config.admin_listen = Some("127.0.0.1:0".parse().unwrap());
+
+        let market_url: MarketUrl = (server.uri() + "/market/")
+            .parse()
+            .expect("Wrong Market url");
+        let cache =
+            Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+        let networks = Networks::new(upstream(
+            DEFAULT_NETWORK,
+            &config,
+            cache,
+            market_url,
+            logger.clone(),
+        ));
+
+        let (shutdown, on_shutdown) = oneshot::channel::<()>();
+        let servers = bind(
+            &"127.0.0.1:0".parse().unwrap(),
+            logger,
+            networks,
+            on_shutdown.map(drop).shared(),
+        )
+        .expect("Should bind both servers");
+        let admin_addr = servers.admin_addr.expect("Should bind the admin server");
+        assert_ne!(servers.addr, admin_addr);
+        let running = tokio::spawn(servers.graceful);
+
+        let client = reqwest::Client::new();
+        let status_of = |addr: SocketAddr, path: &str| {
+            let request = client
+                .get(&format!("http://{}{}", addr, path))
+                .bearer_auth("admin-token")
+                .send();
+
+            async move { request.await.expect("Should make the request").status() }
+        };
+        let channel_id = primitives::util::tests::prep_db::DUMMY_CHANNEL.id;
+        let balances = format!("/campaigns/{}/balances", channel_id);
+
+        // public listener
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(servers.addr, ROUTE_CONFIG).await
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(servers.addr, ROUTE_VALIDATORS).await
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(servers.addr, ROUTE_CACHE_SNAPSHOT).await
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(servers.addr, &balances).await
+        );
+        assert_eq!(StatusCode::OK, status_of(servers.addr, ROUTE_HEALTHZ).await);
+
+        // admin listener
+        assert_eq!(StatusCode::OK, status_of(admin_addr, ROUTE_CONFIG).await);
+        assert_eq!(
+            StatusCode::OK,
+            status_of(admin_addr, ROUTE_VALIDATORS).await
+        );
+        assert_eq!(
+            StatusCode::OK,
+            status_of(admin_addr, ROUTE_CACHE_SNAPSHOT).await
+        );
+        assert_eq!(StatusCode::OK, status_of(admin_addr, ROUTE_HEALTHZ).await);
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(admin_addr, &balances).await
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_of(
+                admin_addr,
+                "/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C"
+            )
+            .await
+        );
+        // would be proxied to the Market on the public listener
+        assert_eq!(StatusCode::NOT_FOUND, status_of(admin_addr, "/slots").await);
+
+        // both shut down together
+        shutdown.send(()).expect("The servers should be running");
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("Both servers should shut down")
+            .expect("Should not panic")
+            .expect("Should shut down gracefully");
+    }
+
+    #[tokio::test]
+    async fn only_the_allowed_paths_are_proxied() {
+        use wiremock::matchers::path;
+
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/market/tags"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1_u64)
+            .mount(&server)
+            .await;
+        // nothing else should be proxied to the Market
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0_u64)
+            .mount(&server)
+            .await;
+
+        let mut config = DEVELOPMENT.clone();
+        config.proxy.paths.denied = vec!["/campaigns/by-owner".to_string()];
+
+        let market_url: MarketUrl = (server.uri() + "/market/")
+            .parse()
+            .expect("Wrong Market url");
+        let cache =
+            Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+        let upstream = upstream(DEFAULT_NETWORK, &config, cache, market_url, logger);
+        let proxy = |path: &str| {
+            let request = Request::get(path)
+                .body(Body::empty())
+                .expect("Should build Request");
+
+            handle(request, Listener::All, upstream.clone())
+        };
+
+        assert_eq!(
+            StatusCode::OK,
+            proxy("/tags").await.expect("Should proxy").status()
+        );
+
+        for refused in &[
+            "/admin",
+            "/users/0x06",
+            "/tags/%2e%2e/admin",
+            "/tags/%252e%252e/admin",
+            "/tags/../admin",
+            "/campaigns/by-owner/0x06",
+        ] {
+            let response = proxy(refused).await.expect("Should handle request");
+            assert_eq!(StatusCode::FORBIDDEN, response.status(), "{}", refused);
+
+            let body: serde_json::Value =
+                serde_json::from_slice(&hyper::body::to_bytes(response).await.unwrap())
+                    .expect("Should deserialize");
+            assert_eq!(serde_json::json!({ "error": "path not allowed" }), body);
+        }
+    }
+
+    #[tokio::test]
+    async fn without_the_proxy_only_the_local_routes_are_served() {
+        use std::time::Duration;
+        use tokio::sync::oneshot;
+
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+
+        // nothing should reach the Market
+        for verb in &["GET", "POST"] {
+            Mock::given(method(*verb))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(0_u64)
+                .mount(&server)
+                .await;
+        }
+
+        let mut config = DEVELOPMENT.clone();
+        config.proxy_enabled = false;
+
+        let market_url: MarketUrl = (server.uri() + "/market/")
+            .parse()
+            .expect("Wrong Market url");
+        let cache =
+            Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+        let upstream = upstream(DEFAULT_NETWORK, &config, cache, market_url, logger.clone());
+        assert!(upstream.proxy.is_none());
+
+        let (shutdown, on_shutdown) = oneshot::channel::<()>();
+        let servers = bind(
+            &"127.0.0.1:0".parse().unwrap(),
+            logger,
+            Networks::new(upstream),
+            on_shutdown.map(drop).shared(),
+        )
+        .expect("Should bind the server");
+        let running = tokio::spawn(servers.graceful);
+
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://{}{}", servers.addr, path);
+        let get = |path: &str| {
+            let request = client.get(&url(path)).send();
+
+            async move { request.await.expect("Should make the request").status() }
+        };
+
+        // the proxied routes
+        assert_eq!(StatusCode::NOT_FOUND, get("/channel/list").await);
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get("/slots/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C").await
+        );
+        let channel_id = primitives::util::tests::prep_db::DUMMY_CHANNEL.id;
+        let events = client
+            .post(&url(&format!("/channel/{}/events", channel_id)))
+            .json(&serde_json::json!({ "events": [] }))
+            .send()
+            .await
+            .expect("Should make the request");
+        assert_eq!(StatusCode::NOT_FOUND, events.status());
+
+        // the local routes
+        assert_eq!(StatusCode::OK, get(ROUTE_HEALTHZ).await);
+        assert_eq!(StatusCode::OK, get(ROUTE_VERSION).await);
+        assert_eq!(StatusCode::OK, get(ROUTE_STATS).await);
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            get("/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C/").await
+        );
+
+        shutdown.send(()).expect("The server should be running");
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("The server should shut down")
+            .expect("Should not panic")
+            .expect("Should shut down gracefully");
+    }
+
+    #[tokio::test]
+    async fn requests_are_served_by_the_network_of_their_path_prefix_or_host() {
+        use crate::{cache::Campaign, status::Status};
+        use primitives::util::tests::prep_db::DUMMY_CHANNEL;
+        use tokio::sync::oneshot;
+        use wiremock::matchers::path;
+
+        let logger = discard_logger();
+        let market = |name: &'static str, expected: u64| async move {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/tags"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(name))
+                .expect(expected)
+                .mount(&server)
+                .await;
+
+            server
+        };
+        let default_market = market(DEFAULT_NETWORK, 1).await;
+        let staging_market = market("staging", 2).await;
+        let market_url = |server: &MockServer| -> MarketUrl {
+            format!("{}/", server.uri())
+                .parse()
+                .expect("Valid Market URL")
+        };
+
+        let default_cache =
+            Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+        let campaign = Campaign::new(DUMMY_CHANNEL.clone(), Status::Active, Default::default());
+        let staging_cache = Cache::initialize(
+            MockClient::init(
+                vec![vec![(campaign.channel.id, campaign)].into_iter().collect()],
+                vec![],
+                None,
+            )
+            .await,
+        )
+        .await;
+        let networks = Networks::new(upstream(
+            DEFAULT_NETWORK,
+            &DEVELOPMENT,
+            default_cache,
+            market_url(&default_market),
+            logger.clone(),
+        ))
+        .with(
+            upstream(
+                "staging",
+                &DEVELOPMENT,
+                staging_cache,
+                market_url(&staging_market),
+                logger.clone(),
+            ),
+            &["staging.supermarket.local".to_string()],
+        );
+
+        let (shutdown, on_shutdown) = oneshot::channel::<()>();
+        let servers = bind(
+            &"127.0.0.1:0".parse().unwrap(),
+            logger,
+            networks,
+            on_shutdown.map(drop).shared(),
+        )
+        .expect("Should bind the server");
+        let addr = servers.addr;
+        let running = tokio::spawn(servers.graceful);
+
+        let client = reqwest::Client::new();
+        let get = |path: &str, host: Option<&str>| {
+            let mut request = client.get(&format!("http://{}{}", addr, path));
+            if let Some(host) = host {
+                request = request.header(http::header::HOST, host);
+            }
+
+            async move {
+                request
+                    .send()
+                    .await
+                    .expect("Should make the request")
+                    .text()
+                    .await
+                    .expect("Should read the body")
+            }
+        };
+        let active_campaigns = |stats: String| {
+            serde_json::from_str::<serde_json::Value>(&stats).expect("Should deserialize")["active"]
+                .clone()
+        };
+
+        // proxied to the Market of the network, without the prefix
+        assert_eq!(DEFAULT_NETWORK, get("/tags", None).await);
+        assert_eq!("staging", get("/staging/tags", None).await);
+        assert_eq!(
+            "staging",
+            get("/tags", Some("staging.supermarket.local:3000")).await
+        );
+
+        // served from the Cache of the network
+        assert_eq!(0, active_campaigns(get(ROUTE_STATS, None).await));
+        assert_eq!(1, active_campaigns(get("/staging/stats", None).await));
+        assert_eq!(
+            1,
+            active_campaigns(get(ROUTE_STATS, Some("staging.supermarket.local")).await)
+        );
+
+        shutdown.send(()).expect("The server should be running");
+        running
+            .await
+            .expect("Should not panic")
+            .expect("Should shut down gracefully");
+        // the Markets verify the expected requests on drop
+    }
+
+    #[tokio::test]
+    async fn shutting_down_discards_the_campaign_updates_in_progress() {
+        use crate::status::Status;
+        use primitives::{supermarket::Campaign, util::tests::prep_db::DUMMY_CHANNEL};
+        use std::time::Duration;
+        use tokio::sync::oneshot;
+
+        let channel = DUMMY_CHANNEL.clone();
+        let campaigns = vec![(
+            channel.id,
+            Campaign::new(channel.clone(), Status::Active, Default::default()),
+        )]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+        // the slow update would finalize the only Campaign
+        let update = (HashMap::new(), vec![channel.id].into_iter().collect());
+        let client = MockClient::init(vec![campaigns], vec![update], None)
+            .await
+            .with_update_delay(Duration::from_secs(60));
+        let cache = Cache::initialize(client).await;
+        let last_update = cache.last_runs.read().await.campaign_updates;
+
+        let (shutdown, on_shutdown) = oneshot::channel::<()>();
+        let task = spawn_update_campaigns(
+            discard_logger(),
+            cache.clone(),
+            DEVELOPMENT.clone(),
+            on_shutdown.map(drop).shared(),
+        );
+
+        // the first ticks are immediate, so the update is in progress
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        shutdown.send(()).expect("The task should be running");
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("The task should stop without waiting for the update")
+            .expect("Should not panic");
+
+        assert!(cache.finalized.read().await.is_empty());
+        assert_eq!(
+            Some(&Status::Active),
+            cache
+                .active
+                .read()
+                .await
+                .get(&channel.id)
+                .map(|campaign| &campaign.status)
+        );
+        assert_eq!(last_update, cache.last_runs.read().await.campaign_updates);
+    }
+
+    #[test]
+    fn listeners_split_the_admin_routes() {
+        for admin in &[
+            "/config",
+            "/validators",
+            "/validators/refresh",
+            "/validators/localhost:8005",
+            "/internal/cache-snapshot",
+            "/stats/publishers/0xB7d3F81E857692d13e9D63b232A90F4A1793189E",
+            "/campaigns/0x061d5e2a67d0a9a10f1c732bca12a676d83f79663a396f7d87b3e30b9b411088/units",
+        ] {
+            assert!(Listener::Admin.serves(admin), "{}", admin);
+            assert!(!Listener::Public.serves(admin), "{}", admin);
+            assert!(Listener::All.serves(admin), "{}", admin);
+        }
+
+        for public in &[
+            "/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C",
+            "/validatorsx",
+            "/stats",
+            "/slots",
+            "/campaigns/0x061d5e2a67d0a9a10f1c732bca12a676d83f79663a396f7d87b3e30b9b411088/balances",
+        ] {
+            assert!(!Listener::Admin.serves(public), "{}", public);
+            assert!(Listener::Public.serves(public), "{}", public);
+        }
+
+        for shared in &[ROUTE_HEALTHZ, ROUTE_READYZ, ROUTE_METRICS, ROUTE_VERSION] {
+            assert!(Listener::Admin.serves(shared), "{}", shared);
+            assert!(Listener::Public.serves(shared), "{}", shared);
+        }
+    }
+
+    #[test]
+    fn only_the_validators_routes_are_labeled_as_such() {
+        assert_eq!("validators", route_label("/validators"));
+        assert_eq!("validators", route_label("/validators/localhost:8005"));
+        assert_eq!("validators_refresh", route_label("/validators/refresh"));
+        assert_eq!("market_proxy", route_label("/validatorsx"));
+        assert_eq!("market_proxy", route_label("/validators-list"));
+    }
+
+    /// Serves `200 OK` with the `server` settings and returns the read response
+    /// and whether the connection was closed by the server after it.
+    async fn serve_single_request(server: &config::Server) -> (String, bool) {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::{
+            convert::Infallible,
+            io::{Read, Write},
+            net::TcpStream,
+            time::Duration,
+        };
+
+        let builder = server_builder(&"127.0.0.1:0".parse().unwrap(), server)
+            .expect("Should bind the Server");
+        let http_server = builder.serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::from("OK")))
+            }))
+        }));
+        let addr = http_server.local_addr();
+        tokio::spawn(http_server);
+
+        tokio::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(addr).expect("Should connect");
+            stream
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .expect("Should set the read timeout");
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .expect("Should write the request");
+
+            let mut response = vec![];
+            // a timeout means that the connection was kept alive
+            let closed = stream.read_to_end(&mut response).is_ok();
+
+            (String::from_utf8_lossy(&response).into_owned(), closed)
+        })
+        .await
+        .expect("Should read the response")
+    }
+
+    #[tokio::test]
+    async fn server_settings_are_applied() {
+        let defaults = config::Server::default();
+        let builder = server_builder(&"127.0.0.1:0".parse().unwrap(), &defaults)
+            .expect("Should bind the Server");
+        assert!(format!("{:?}", builder).contains("tcp_nodelay: false"));
+
+        let tuned = config::Server {
+            tcp_nodelay: true,
+            http1_keepalive: false,
+            ..Default::default()
+        };
+        let builder = server_builder(&"127.0.0.1:0".parse().unwrap(), &tuned)
+            .expect("Should bind the Server");
+        assert!(format!("{:?}", builder).contains("tcp_nodelay: true"));
+
+        let (response, closed) = serve_single_request(&defaults).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(!closed, "The connection should be kept alive");
+
+        let (response, closed) = serve_single_request(&tuned).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(closed, "The connection should be closed without keep-alive");
+    }
+}
@@ -1,7 +1,7 @@
 #![deny(clippy::all)]
 #![deny(rust_2018_idioms)]
-use clap::{crate_version, App, Arg};
-use supermarket::{config::Environment, serve, Config};
+use clap::{crate_version, App, AppSettings, Arg, SubCommand};
+use supermarket::{check::check, config::Environment, market, serve, Config};
 
 use slog::{info, Drain};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
@@ -14,27 +14,30 @@ const DEFAULT_IP_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = App::new("Supermarket")
         .version(crate_version!())
+        .setting(AppSettings::VersionlessSubcommands)
         .arg(
             Arg::with_name("marketUrl")
                 .short("m")
-                .help("URL for the market")
-                .required(true)
-                .takes_value(true),
+                .long("marketUrl")
+                .help("URL for the market, if not set the URL of the config profile will be used")
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::with_name("config")
                 .short("c")
+                .long("config")
                 .help("Config file path")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(SubCommand::with_name("serve").about("Serves the Supermarket (default)"))
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Validates the config and probes the upstreams without serving"),
         )
         .get_matches();
 
-    let market_url = cli
-        .value_of("marketUrl")
-        .expect("No Market URL provided!")
-        .parse()
-        .expect("Market Url couldn't be parsed as URL");
-
     let environment = std::env::var("ENV")
         .ok()
         .map(|s| Environment::from_str(&s))
@@ -57,18 +60,55 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Construct our SocketAddr to listen on...
     let socket_addr: SocketAddr = (ip_addr, port).into();
 
-    let config_path = cli.value_of("config");
-
-    let config = Config::new(config_path, environment)?;
-
+    // the global arguments are also set on the matches of the subcommand
+    let args = cli.subcommand_matches("check").unwrap_or(&cli);
+    let config_path = args.value_of("config");
+
+    let config = match Config::new(config_path, environment) {
+        Ok(config) => config,
+        Err(error) if cli.subcommand_matches("check").is_some() => {
+            println!("[FAIL] config: {}", error);
+            std::process::exit(1);
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let market_url = match args.value_of("marketUrl").map(market::base_url::parse) {
+        Some(Ok(market_url)) => market_url,
+        Some(Err(error)) if cli.subcommand_matches("check").is_some() => {
+            println!("[FAIL] market: {}", error);
+            std::process::exit(1);
+        }
+        Some(Err(error)) => return Err(error.into()),
+        None => config.market.url.clone(),
+    };
+
+    if cli.subcommand_matches("check").is_some() {
+        let report = check(&logger(), &config, market_url).await;
+        println!("{}", report);
+
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
+
+    let _error_reporting = supermarket::error_reporting::init(&config);
     let logger = logger();
 
     info!(
         &logger,
-        "ENV: `{}`; IP_ADDR: `{}`; PORT: `{}`; {:#?}", environment, ip_addr, port, config
+        "Active config profile: `{}`", environment;
+        "source" => ?config.source,
+        "env overrides" => ?config.env_overrides,
     );
 
-    info!(&logger, "Web server listening on: {}", &socket_addr);
+    info!(
+        &logger,
+        "ENV: `{}`; IP_ADDR: `{}`; PORT: `{}`; MARKET_URL: `{}`; {:#?}",
+        environment,
+        ip_addr,
+        port,
+        market_url,
+        config
+    );
 
     Ok(serve(socket_addr, logger, market_url, config).await?)
 }
@@ -77,6 +117,7 @@ pub fn logger() -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let drain = slog_async::Async::new(drain).chan_size(1024).build().fuse();
+    let drain = supermarket::error_reporting::drain(drain).fuse();
 
     slog::Logger::root(drain, slog::o!())
 }
@@ -3,21 +3,152 @@ use primitives::{
     util::ApiUrl,
     AdSlot, AdUnit,
 };
-use reqwest::{Client, Error, StatusCode};
-use slog::{info, Logger};
-use std::fmt;
+use reqwest::{
+    header::{
+        HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED,
+    },
+    Client, ClientBuilder, Error, StatusCode,
+};
+use serde::Serialize;
+use slog::{error, info, Logger};
+use std::{fmt, io::Write, time::Duration};
+
+use crate::{config::VerifyMarketOnStart, util::CircuitBreaker, Config};
 
-use crate::Config;
+pub mod base_url;
+pub mod events;
+pub mod paths;
 
-pub use proxy::Proxy;
+pub use proxy::{ProxiedResponse, Proxy, UpstreamUri};
 
 pub type MarketUrl = ApiUrl;
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The periodic requests to the Market are skipped for 5s after a failure, twice as long after each consecutive one,
+/// see [`MarketApi::circuit_breaker`]
+pub const CIRCUIT_BREAKER_BASE: Duration = Duration::from_secs(5);
+
+/// The connection settings of the [`Config`] for the [`MarketApi`],
+/// the [`Proxy`] applies the same ones to its connector (see [`Proxy::new`])
+fn client_builder(config: &Config) -> ClientBuilder {
+    Client::builder()
+        .timeout(config.timeouts.global_request)
+        .tcp_keepalive(config.market.keep_alive_interval)
+}
+
+/// The host (and port if it's set) of the Market URL, e.g. `market.adex.network`
+pub fn market_host(market_url: &MarketUrl) -> String {
+    let url = market_url.to_url();
+    let host = url
+        .host_str()
+        .expect("MarketUrl always has a host")
+        .to_string();
+
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    }
+}
+
+/// Why probing the Market failed, see [`MarketApi::probe`]
+#[derive(Debug, thiserror::Error)]
+pub enum ProbeError {
+    #[error("The Market host could not be resolved (DNS error): {0}")]
+    Dns(Error),
+    #[error("TLS error while connecting to the Market: {0}")]
+    Tls(Error),
+    #[error("The Market responded with a non-success status `{0}`")]
+    Status(StatusCode),
+    #[error("Requesting the Market failed: {0}")]
+    Request(Error),
+}
+
+impl From<Error> for ProbeError {
+    /// Uses the messages of the whole chain of sources to tell DNS and TLS errors apart,
+    /// since `reqwest` doesn't expose them otherwise
+    fn from(error: Error) -> Self {
+        let mut chain = vec![];
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&error);
+        while let Some(current) = source {
+            chain.push(current.to_string().to_lowercase());
+            source = current.source();
+        }
+        let chain = chain.join(": ");
+
+        if chain.contains("dns error") || chain.contains("failed to lookup address") {
+            ProbeError::Dns(error)
+        } else if ["tls", "ssl", "certificate", "handshake"]
+            .iter()
+            .any(|tls| chain.contains(tls))
+        {
+            ProbeError::Tls(error)
+        } else {
+            ProbeError::Request(error)
+        }
+    }
+}
+
+/// Why POSTing to the Market failed, see [`MarketApi::post_json`]
+#[derive(Debug, thiserror::Error)]
+pub enum PostError {
+    #[error("Serializing the body failed: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Compressing the body failed: {0}")]
+    Compress(#[from] std::io::Error),
+    #[error("Requesting the Market failed: {0}")]
+    Request(#[from] Error),
+}
+
+/// The `ETag` and `Last-Modified` of a fetched AdSlot for revalidating it with a conditional request,
+/// see [`MarketApi::fetch_slot_if_modified`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SlotVersion {
+    pub etag: Option<HeaderValue>,
+    pub last_modified: Option<HeaderValue>,
+}
+
+impl SlotVersion {
+    /// Whether the Market returned neither of them, i.e. the AdSlot can't be revalidated
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// The result of [`MarketApi::fetch_slot_if_modified`]
+#[derive(Debug)]
+pub enum SlotFetch {
+    Modified(AdSlotResponse, SlotVersion),
+    /// The `304 Not Modified` of a conditional request, the cached AdSlot is still valid
+    NotModified,
+    NotFound,
+}
+
+/// The AdUnits of an AdSlot, see [`MarketApi::fetch_units`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SlotUnits {
+    pub units: Vec<AdUnit>,
+    /// The Market has more AdUnits than the `max_units` which were fetched
+    pub truncated: bool,
+}
+
+/// Why building the [`MarketApi`] failed, see [`MarketApi::new`]
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    #[error(transparent)]
+    MarketUrl(#[from] base_url::InvalidMarketUrl),
+    #[error("Building the HTTP client of the Market failed: {0}")]
+    Client(#[from] Error),
+}
+
 #[derive(Debug, Clone)]
 pub struct MarketApi {
-    pub market_url: MarketUrl,
+    market_url: MarketUrl,
     client: Client,
+    /// See [`Market.gzip_requests_over`](crate::config::Market::gzip_requests_over)
+    gzip_requests_over: Option<usize>,
+    /// See [`MarketApi::circuit_breaker`]
+    circuit_breaker: CircuitBreaker,
     logger: Logger,
 }
 
@@ -51,34 +182,164 @@ impl MarketApi {
     /// It should always be > 1
     const MARKET_AD_UNITS_LIMIT: u64 = 1_000;
 
-    pub fn new(market_url: MarketUrl, config: &Config, logger: Logger) -> Result<Self> {
-        // @TODO: maybe add timeout?
-        let client = Client::builder()
-            .tcp_keepalive(config.market.keep_alive_interval)
+    /// The responses of the Market are accepted (and decompressed) with gzip & brotli.
+    ///
+    /// Fails with all of the problems of the `market_url` (see [`base_url::parse`]),
+    /// instead of failing on the first request to the Market.
+    pub fn new(
+        market_url: MarketUrl,
+        config: &Config,
+        logger: Logger,
+    ) -> std::result::Result<Self, BuildError> {
+        base_url::validate(&market_url)?;
+
+        let client = client_builder(config)
             .cookie_store(true)
+            .gzip(true)
+            .brotli(true)
             .build()?;
 
         Ok(Self {
             market_url,
             client,
+            gzip_requests_over: config.market.gzip_requests_over,
+            circuit_breaker: CircuitBreaker::new(CIRCUIT_BREAKER_BASE),
             logger,
         })
     }
 
+    /// The validated & normalized URL of the Market, see [`base_url::parse`]
+    pub fn url(&self) -> &MarketUrl {
+        &self.market_url
+    }
+
+    /// Shared by the periodic requests to the Market (the pre-warming & the revalidation of the negative cached AdSlots),
+    /// while the Market is down they're skipped, the clones of the `MarketApi` share it as well
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+
+    /// POSTs the JSON `body` to the `path` of the Market URL,
+    /// with `Content-Encoding: gzip` if it's larger than the [`Market.gzip_requests_over`](crate::config::Market::gzip_requests_over)
+    pub async fn post_json<T: Serialize + ?Sized>(
+        &self,
+        path: &str,
+        body: &T,
+    ) -> std::result::Result<reqwest::Response, PostError> {
+        let url = self
+            .market_url
+            .join(path)
+            .expect("Wrong Market Url for the POST endpoint");
+        let body = serde_json::to_vec(body)?;
+
+        let request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json");
+        let request = match self.gzip_requests_over {
+            Some(gzip_over) if body.len() > gzip_over => {
+                request.header(CONTENT_ENCODING, "gzip").body(gzip(&body)?)
+            }
+            _ => request.body(body),
+        };
+
+        Ok(request.send().await?)
+    }
+
+    /// Requests the Market URL and expects a `2xx` response
+    pub async fn probe(&self) -> std::result::Result<(), ProbeError> {
+        let response = self.client.get(self.market_url.to_url()).send().await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(ProbeError::Status(response.status()))
+        }
+    }
+
+    /// Probes the Market on startup if it's `enabled`.
+    /// On failure it returns the error only if `strict` is set, otherwise it logs it and continues.
+    pub async fn verify_on_start(
+        &self,
+        verify: &VerifyMarketOnStart,
+    ) -> std::result::Result<(), ProbeError> {
+        if !verify.enabled {
+            return Ok(());
+        }
+
+        match self.probe().await {
+            Ok(()) => {
+                info!(&self.logger, "The Market is reachable"; "market" => %self.market_url);
+
+                Ok(())
+            }
+            Err(probe_error) if verify.strict => Err(probe_error),
+            Err(probe_error) => {
+                error!(
+                    &self.logger,
+                    "THE MARKET IS UNREACHABLE, continuing since `strict` is not set";
+                    "market" => %self.market_url,
+                    "error" => %probe_error,
+                );
+
+                Ok(())
+            }
+        }
+    }
+
     /// ipfs: ipfs hash
     /// Handles the 404 case, returning a None, instead of Error
     pub async fn fetch_slot(&self, ipfs: &str) -> Result<Option<AdSlotResponse>> {
+        match self.fetch_slot_if_modified(ipfs, None).await? {
+            SlotFetch::Modified(ad_slot_response, _) => Ok(Some(ad_slot_response)),
+            // the request is not conditional, so it's never `NotModified`
+            SlotFetch::NotModified | SlotFetch::NotFound => Ok(None),
+        }
+    }
+
+    /// Fetches the AdSlot with `If-None-Match` & `If-Modified-Since` from the `version` of the cached one (if any),
+    /// returning [`SlotFetch::NotModified`] only for such a conditional request.
+    /// If the Market omits both the `ETag` and `Last-Modified` the returned [`SlotVersion`] is empty
+    /// and the AdSlot is fetched as usual the next time.
+    pub async fn fetch_slot_if_modified(
+        &self,
+        ipfs: &str,
+        version: Option<&SlotVersion>,
+    ) -> Result<SlotFetch> {
         let url = self
             .market_url
             .join(&format!("slots/{}", ipfs))
             .expect("Wrong Market Url for /slots/{IPFS} endpoint");
 
-        let response = self.client.get(url).send().await?;
-        if StatusCode::NOT_FOUND == response.status() {
-            Ok(None)
-        } else {
-            let ad_slot_response = response.json::<AdSlotResponse>().await?;
-            Ok(Some(ad_slot_response))
+        let version = version.filter(|version| !version.is_empty());
+        let mut request = self.client.get(url);
+        if let Some(version) = version {
+            if let Some(etag) = &version.etag {
+                request = request.header(IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(last_modified) = &version.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+
+        let response = request.send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(SlotFetch::NotFound),
+            StatusCode::NOT_MODIFIED if version.is_some() => Ok(SlotFetch::NotModified),
+            // not mistaken for an AdSlot which can't be deserialized
+            status if !status.is_success() => Err(response
+                .error_for_status()
+                .expect_err("Only the error statuses are left")),
+            _ => {
+                let headers = response.headers();
+                let version = SlotVersion {
+                    etag: headers.get(ETAG).cloned(),
+                    last_modified: headers.get(LAST_MODIFIED).cloned(),
+                };
+                let ad_slot_response = response.json::<AdSlotResponse>().await?;
+
+                Ok(SlotFetch::Modified(ad_slot_response, version))
+            }
         }
     }
 
@@ -100,14 +361,38 @@ impl MarketApi {
         }
     }
 
-    pub async fn fetch_units(&self, ad_slot: &AdSlot) -> Result<Vec<AdUnit>> {
+    /// `HEAD` request to the Market URL, keeping a pooled connection alive (or opening a new one),
+    /// the status of the response doesn't matter.
+    pub async fn warm_up(&self) -> Result<()> {
+        self.client
+            .head(self.market_url.to_url())
+            .send()
+            .await
+            .map(drop)
+    }
+
+    /// Fetches the AdUnits of the AdSlot's type, the Market filters them by the `type`.
+    ///
+    /// The AdSlot has no other properties by which the Market can filter the AdUnits,
+    /// the units of other types (if the Market ignores the filter) are dropped.
+    /// With `max_units` the pages stop at it (one more AdUnit is requested in order to know if there are more),
+    /// the Market has no ordering of the best AdUnits first, so they are the first ones in its order.
+    pub async fn fetch_units(
+        &self,
+        ad_slot: &AdSlot,
+        max_units: Option<usize>,
+    ) -> Result<SlotUnits> {
         let mut units = Vec::new();
         let mut skip: u64 = 0;
-        let limit = Self::MARKET_AD_UNITS_LIMIT;
+        let max_units = max_units.map(|max_units| max_units as u64);
 
         loop {
+            let limit = match max_units {
+                Some(max_units) => Self::MARKET_AD_UNITS_LIMIT.min(max_units + 1 - skip),
+                None => Self::MARKET_AD_UNITS_LIMIT,
+            };
             // if one page fail, simply return the error for now
-            let mut page_results = self.fetch_units_page(&ad_slot.ad_type, skip).await?;
+            let mut page_results = self.fetch_units_page(&ad_slot.ad_type, skip, limit).await?;
             // get the count before appending the page results to all
             let count = page_results.len() as u64;
 
@@ -118,25 +403,58 @@ impl MarketApi {
 
             // if the Market returns < market fetch limit
             // we've got all AdSlots from all pages!
-            if count < limit {
+            // With `max_units`, stop once there's one more
+            if count < limit || max_units.map_or(false, |max_units| skip > max_units) {
                 // so break out of the loop
                 break;
             }
         }
 
-        Ok(units)
+        let truncated = match max_units {
+            Some(max_units) if units.len() as u64 > max_units => {
+                units.truncate(max_units as usize);
+                info!(
+                    &self.logger,
+                    "The AdUnits of the AdSlot were truncated to the max_units_per_slot";
+                    "type" => &ad_slot.ad_type,
+                    "max_units" => max_units,
+                );
+
+                true
+            }
+            _ => false,
+        };
+
+        let fetched = units.len();
+        units.retain(|unit| unit.ad_type == ad_slot.ad_type);
+        if units.len() < fetched {
+            error!(
+                &self.logger,
+                "The Market returned AdUnits of other types than the AdSlot's";
+                "type" => &ad_slot.ad_type,
+                "dropped" => fetched - units.len(),
+            );
+        }
+
+        Ok(SlotUnits { units, truncated })
+    }
+
+    /// The `units` path with the (URL-encoded) query of the page
+    fn units_page_path(ad_type: &str, skip: u64, limit: u64) -> String {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("limit", &limit.to_string())
+            .append_pair("skip", &skip.to_string())
+            .append_pair("type", ad_type)
+            .finish();
+
+        format!("units?{}", query)
     }
 
     /// `skip` - how many records it should skip (pagination)
-    async fn fetch_units_page(&self, ad_type: &str, skip: u64) -> Result<Vec<AdUnit>> {
+    async fn fetch_units_page(&self, ad_type: &str, skip: u64, limit: u64) -> Result<Vec<AdUnit>> {
         let url = self
             .market_url
-            .join(&format!(
-                "units?limit={}&skip={}&type={}",
-                Self::MARKET_AD_UNITS_LIMIT,
-                skip,
-                ad_type,
-            ))
+            .join(&Self::units_page_path(ad_type, skip, limit))
             .expect("Wrong Market Url for /units endpoint");
 
         let response = self.client.get(url).send().await?;
@@ -203,23 +521,76 @@ impl MarketApi {
     }
 }
 
+fn gzip(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(bytes)?;
+
+    encoder.finish()
+}
+
 mod proxy {
-    use std::sync::Arc;
+    use std::{
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+        time::Duration,
+    };
 
+    use futures::{
+        ready,
+        stream::{BoxStream, Stream, StreamExt},
+    };
     use http::{
-        header::{HeaderMap, HeaderName, HOST},
+        header::{HeaderMap, HeaderName, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+        uri::{Authority, Parts, PathAndQuery, Scheme},
         HeaderValue, Method, Request, Response, Uri,
     };
-    use hyper::{client::connect::HttpConnector, Body, Client};
+    use hyper::{
+        body::{Bytes, HttpBody},
+        client::connect::HttpConnector,
+        service::Service,
+        Body, Client,
+    };
     use hyper_tls::HttpsConnector;
-    use slog::{debug, Logger};
+    use slog::{debug, error, Logger};
     use thiserror::Error;
+    use tokio::time::timeout;
+
+    use crate::{
+        config::HostHeader,
+        metrics::{
+            MARKET_PROXY_CONNECTIONS, PROXY_RESPONSES_TOO_LARGE, PROXY_RESPONSE_BYTES,
+            PROXY_RESPONSE_BYTES_IN_FLIGHT, PROXY_RESPONSE_SIZE,
+        },
+        Config,
+    };
+
+    use super::{market_host, MarketUrl};
 
-    use crate::Config;
+    type HyperClient = Client<CountingConnector<HttpsConnector<HttpConnector>>>;
 
-    use super::MarketUrl;
+    /// Counts the new connections (i.e. the TCP & TLS handshakes) to the Market in the [`MARKET_PROXY_CONNECTIONS`],
+    /// the requests over the pooled connections don't go through the connector.
+    #[derive(Debug, Clone)]
+    pub struct CountingConnector<C> {
+        inner: C,
+    }
 
-    type HyperClient = Client<HttpsConnector<HttpConnector>>;
+    impl<C: Service<Uri>> Service<Uri> for CountingConnector<C> {
+        type Response = C::Response;
+        type Error = C::Error;
+        type Future = C::Future;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            MARKET_PROXY_CONNECTIONS.inc();
+
+            self.inner.call(uri)
+        }
+    }
 
     #[derive(Debug, Error)]
     pub enum Error {
@@ -229,11 +600,237 @@ mod proxy {
             method: Method,
             source: hyper::Error,
         },
+        #[error("The Market didn't respond to `{method}` `{uri}` within {timeout:?}")]
+        Timeout {
+            uri: Uri,
+            method: Method,
+            timeout: Duration,
+        },
         #[error("Failed to parse URI for request `{uri}`")]
         Uri {
             uri: String,
             source: http::uri::InvalidUri,
         },
+        #[error("Failed to build the Market URI for request `{uri}`")]
+        UriParts {
+            uri: Uri,
+            source: http::uri::InvalidUriParts,
+        },
+        #[error(
+            "The Market response for `{uri}` of {length} bytes exceeds the max of {max} bytes"
+        )]
+        ResponseTooLarge { uri: Uri, length: u64, max: u64 },
+    }
+
+    /// The errors of streaming a proxied response body, which abort (truncate) the response
+    #[derive(Debug, Error)]
+    pub enum BodyError {
+        #[error("Failed to stream the Market response")]
+        Market(#[from] hyper::Error),
+        #[error("The Market response exceeded the max of {max} bytes and was truncated")]
+        TooLarge { max: u64 },
+    }
+
+    /// The body of a proxied response, streamed as it is while counting its bytes in the metrics.
+    /// Once it exceeds the `max` it's aborted with [`BodyError::TooLarge`] and the `path` is logged.
+    struct CountedBody {
+        inner: BoxStream<'static, hyper::Result<Bytes>>,
+        bytes: u64,
+        max: Option<u64>,
+        path: String,
+        logger: Logger,
+        done: bool,
+    }
+
+    impl Stream for CountedBody {
+        type Item = Result<Bytes, BodyError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            let chunk = match ready!(self.inner.as_mut().poll_next(cx)) {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+                None => {
+                    self.done = true;
+                    return Poll::Ready(None);
+                }
+            };
+
+            let length = chunk.len() as u64;
+            self.bytes += length;
+            PROXY_RESPONSE_BYTES.inc_by(length);
+            PROXY_RESPONSE_BYTES_IN_FLIGHT.add(length as i64);
+
+            match self.max {
+                Some(max) if self.bytes > max => {
+                    self.done = true;
+                    PROXY_RESPONSES_TOO_LARGE.inc();
+                    error!(&self.logger, "Truncated a proxied response exceeding the max size"; "path" => &self.path, "bytes" => self.bytes, "max" => max);
+
+                    Poll::Ready(Some(Err(BodyError::TooLarge { max })))
+                }
+                _ => Poll::Ready(Some(Ok(chunk))),
+            }
+        }
+    }
+
+    impl Drop for CountedBody {
+        fn drop(&mut self) {
+            PROXY_RESPONSE_SIZE.observe(self.bytes as f64);
+            PROXY_RESPONSE_BYTES_IN_FLIGHT.sub(self.bytes as i64);
+        }
+    }
+
+    /// The pre-parsed parts of the [`MarketUrl`] for building the URIs of the proxied requests
+    /// without formatting and re-parsing the whole URI.
+    #[derive(Debug, Clone)]
+    pub struct UpstreamUri {
+        scheme: Scheme,
+        authority: Authority,
+        /// The path of the [`MarketUrl`] without the trailing `/`, e.g. `/market`.
+        /// Empty if the Market is on the root path.
+        base_path: String,
+    }
+
+    impl UpstreamUri {
+        pub fn new(market_url: &MarketUrl) -> Self {
+            let parts = market_url
+                .to_string()
+                .parse::<Uri>()
+                .expect("The MarketUrl should be a valid URI")
+                .into_parts();
+
+            let base_path = parts
+                .path_and_query
+                .as_ref()
+                .map(|p_q| p_q.path().trim_end_matches('/').to_string())
+                .unwrap_or_default();
+
+            Self {
+                scheme: parts.scheme.expect("The MarketUrl always has a scheme"),
+                authority: parts.authority.expect("The MarketUrl always has a host"),
+                base_path,
+            }
+        }
+
+        /// The URI of the Market for the `path_and_query` of the proxied request,
+        /// e.g. `/slots/Qm..?query` => `https://market.adex.network/market/slots/Qm..?query`
+        ///
+        /// If the Market is on the root path, the `path_and_query` (and it's `Bytes`) is reused as it is.
+        pub fn uri(&self, uri: &Uri) -> Result<Uri, Error> {
+            let path_and_query = match uri.path_and_query() {
+                Some(p_q) if self.base_path.is_empty() && p_q.as_str().starts_with('/') => {
+                    p_q.clone()
+                }
+                p_q => {
+                    let p_q = p_q.map(PathAndQuery::as_str).unwrap_or_default();
+                    let mut path_and_query =
+                        String::with_capacity(self.base_path.len() + p_q.len() + 1);
+                    path_and_query.push_str(&self.base_path);
+                    // the MarketUrl (i.e. ApiUrl) always suffixes the path with `/`
+                    if !p_q.starts_with('/') {
+                        path_and_query.push('/');
+                    }
+                    path_and_query.push_str(p_q);
+
+                    PathAndQuery::from_maybe_shared(Bytes::from(path_and_query)).map_err(|err| {
+                        Error::Uri {
+                            uri: uri.to_string(),
+                            source: err,
+                        }
+                    })?
+                }
+            };
+
+            let mut parts = Parts::default();
+            parts.scheme = Some(self.scheme.clone());
+            parts.authority = Some(self.authority.clone());
+            parts.path_and_query = Some(path_and_query);
+
+            Uri::from_parts(parts).map_err(|err| Error::UriParts {
+                uri: uri.clone(),
+                source: err,
+            })
+        }
+    }
+
+    /// The post-processing of a proxied response, which keeps the framing headers
+    /// (`Content-Length` & `Transfer-Encoding`) consistent with the body:
+    ///
+    /// - unless the body is replaced it's streamed as it is, with the framing headers of the Market
+    /// - once the body is replaced (or re-encoded) the framing headers are dropped,
+    ///   so `hyper` sets them for the new body
+    /// - the framing headers can't be set or removed directly
+    #[derive(Debug)]
+    pub struct ProxiedResponse {
+        parts: http::response::Parts,
+        body: Body,
+    }
+
+    impl ProxiedResponse {
+        pub fn new(response: Response<Body>) -> Self {
+            let (parts, body) = response.into_parts();
+
+            Self { parts, body }
+        }
+
+        fn is_framing(name: &HeaderName) -> bool {
+            name == CONTENT_LENGTH || name == TRANSFER_ENCODING
+        }
+
+        pub fn headers(&self) -> &HeaderMap {
+            &self.parts.headers
+        }
+
+        /// Returns `false` and leaves the headers untouched for the framing headers
+        pub fn insert_header(&mut self, name: HeaderName, value: HeaderValue) -> bool {
+            if Self::is_framing(&name) {
+                return false;
+            }
+
+            self.parts.headers.insert(name, value);
+            true
+        }
+
+        /// Returns `false` and leaves the headers untouched for the framing headers
+        pub fn remove_header(&mut self, name: &HeaderName) -> bool {
+            if Self::is_framing(name) {
+                return false;
+            }
+
+            self.parts.headers.remove(name);
+            true
+        }
+
+        /// Replaces the body and drops the framing headers of the Market.
+        /// If the new body has another encoding, the `Content-Encoding` should be changed as well.
+        pub fn replace_body(&mut self, body: impl Into<Body>) {
+            self.parts.headers.remove(CONTENT_LENGTH);
+            self.parts.headers.remove(TRANSFER_ENCODING);
+            self.body = body.into();
+        }
+
+        /// Buffers the whole body and replaces it with the mapped one, see [`ProxiedResponse::replace_body`]
+        pub async fn map_body<F>(mut self, map: F) -> Result<Self, hyper::Error>
+        where
+            F: FnOnce(Bytes) -> Bytes,
+        {
+            let body = std::mem::take(&mut self.body);
+            let bytes = hyper::body::to_bytes(body).await?;
+            self.replace_body(map(bytes));
+
+            Ok(self)
+        }
+
+        pub fn into_response(self) -> Response<Body> {
+            Response::from_parts(self.parts, self.body)
+        }
     }
 
     #[derive(Debug, Clone)]
@@ -247,7 +844,8 @@ mod proxy {
     /// Proxy used for proxying all requests intended for the Market.
     /// It's cheap to `clone()` it.
     ///
-    /// Internally it uses [`hyper::Client`](hyper::Client) with [`hyper_tls::HttpsConnector`](hyper_tls::HttpsConnector)
+    /// Internally it uses [`hyper::Client`](hyper::Client) with [`hyper_tls::HttpsConnector`](hyper_tls::HttpsConnector),
+    /// the request & response bodies are streamed and the responses (incl. redirects) are passed through as they are.
     #[derive(Debug, Clone)]
     pub struct Proxy {
         inner: Arc<ProxyInner>,
@@ -256,8 +854,11 @@ mod proxy {
     #[derive(Debug, Clone)]
     pub struct ProxyInner {
         client: HyperClient,
+        /// Bounds the connecting & the response headers, but not the streamed bodies
+        timeout: Duration,
         default_headers: DefaultHeaders,
-        market_url: MarketUrl,
+        upstream_uri: UpstreamUri,
+        max_response_bytes: Option<u64>,
         logger: Logger,
     }
 
@@ -266,42 +867,45 @@ mod proxy {
         ///
         /// For requests to the Market:
         ///
-        /// - `HOST` - `market_url` is used to set the `HOST` header of the request (required for Cloudflare)
+        /// - `HOST` - `market_url` is used to set the `HOST` header of the request (required for Cloudflare),
+        ///    unless the incoming one is preserved, see [`HostHeader`].
         ///    This is done because the initial request that we proxy contains a `HOST` header so we need to override it with the correct one - the Market.
+        /// - the `extra_headers` and the `User-Agent` (if set) of the [`ProxyHeaders`](crate::config::ProxyHeaders)
         ///
         /// For the response of the Supermarket (before proxied response is returned):
         ////
         /// - `x-served-by` with value `adex-supermarket-proxy`
         ///
-        /// Sets the HTTP/1 & HTTP/2 `Keep-Alive` based on the passed [`Config`](crate::Config)
+        /// Uses the same `global_request` timeout & TCP `Keep-Alive` as the [`MarketApi`](super::MarketApi), see [`Config`](crate::Config),
+        /// but the timeout bounds only the connecting and the response headers, so the streamed bodies aren't cut off.
         pub fn new(market_url: MarketUrl, config: &Config, logger: Logger) -> Self {
-            // for Cloudflare we need to add a HOST header
-            let market_host_header = {
-                let url = market_url.to_url();
-                let host = url
-                    .host_str()
-                    .expect("MarketUrl always has a host")
-                    .to_string();
-
-                match url.port() {
-                    Some(port) => format!("{}:{}", host, port),
-                    None => host,
-                }
-            };
+            let mut request_headers: HeaderMap = config
+                .proxy
+                .headers()
+                .expect("The proxy headers are validated on loading the Config")
+                .into_iter()
+                .collect();
+            // for Cloudflare we need to add a HOST header.
+            // A preserved one is passed through, without one the client sets it from the URL
+            if config.proxy.host == HostHeader::Rewrite {
+                let host: HeaderValue = market_host(&market_url)
+                    .parse()
+                    .expect("The MarketUrl should be valid HOST header");
 
-            let host: HeaderValue = market_host_header
-                .parse()
-                .expect("The MarketUrl should be valid HOST header");
+                request_headers.insert(HOST, host);
+            }
 
             let client = {
-                let mut http = hyper::client::HttpConnector::new();
+                let mut http = HttpConnector::new();
                 http.set_keepalive(Some(config.market.keep_alive_interval));
+                http.set_connect_timeout(Some(config.timeouts.global_request));
                 // allow of `https://` in URIs
                 http.enforce_http(false);
 
-                let https = HttpsConnector::new_with_connector(http);
+                let https = CountingConnector {
+                    inner: HttpsConnector::new_with_connector(http),
+                };
 
-                // since original request contains a HOST header we need to manually override it and we can't use `.set_host(true)`
                 Client::builder()
                     .http2_keep_alive_interval(config.market.keep_alive_interval)
                     .build(https)
@@ -310,8 +914,9 @@ mod proxy {
             Self {
                 inner: Arc::new(ProxyInner {
                     client,
+                    timeout: config.timeouts.global_request,
                     default_headers: DefaultHeaders {
-                        request: vec![(HOST, host)].into_iter().collect(),
+                        request: request_headers,
                         response: vec![(
                             HeaderName::from_static("x-served-by"),
                             HeaderValue::from_static("adex-supermarket-proxy"),
@@ -319,57 +924,1013 @@ mod proxy {
                         .into_iter()
                         .collect(),
                     },
-                    market_url,
+                    upstream_uri: UpstreamUri::new(&market_url),
+                    max_response_bytes: config.proxy.max_response_bytes,
                     logger,
                 }),
             }
         }
 
-        /// Also sets the default headers like `HOST: marketUrl`
+        /// Also sets the default headers like `HOST: marketUrl`.
+        ///
+        /// With the `max_response_bytes` of the [`ProxyHeaders`](crate::config::ProxyHeaders)
+        /// a response with a larger `Content-Length` fails with [`Error::ResponseTooLarge`]
+        /// and the other ones are truncated once they exceed it, see [`BodyError::TooLarge`].
         pub async fn proxy(&self, mut request: Request<Body>) -> Result<Response<Body>, Error> {
             let method = request.method().clone();
-            let path_and_query = request
-                .uri()
-                .path_and_query()
-                .map(|p_q| {
-                    let string = p_q.to_string();
-                    // the MarketUrl (i.e. ApiUrl) always suffixes the path
-                    string
-                        .strip_prefix('/')
-                        .map(ToString::to_string)
-                        .unwrap_or(string)
-                })
-                .unwrap_or_default();
-
-            let uri_string = format!("{}{}", self.inner.market_url, path_and_query);
-            let uri = uri_string.parse::<Uri>().map_err(|err| Error::Uri {
-                uri: uri_string,
-                source: err,
-            })?;
+            let uri = self.inner.upstream_uri.uri(request.uri())?;
             *request.uri_mut() = uri.clone();
-            request
-                .headers_mut()
-                .extend(self.inner.default_headers.request.clone());
-
-            let mut proxy_response =
-                self.inner
-                    .client
-                    .request(request)
-                    .await
-                    .map_err(|err| Error::Proxy {
+
+            let headers = request.headers_mut();
+            for (name, value) in self.inner.default_headers.request.iter() {
+                headers.insert(name, value.clone());
+            }
+
+            // the body is streamed afterwards, without a timeout
+            let market_response =
+                match timeout(self.inner.timeout, self.inner.client.request(request)).await {
+                    Ok(response) => response.map_err(|err| Error::Proxy {
                         uri: uri.clone(),
                         method: method.clone(),
                         source: err,
-                    })?;
+                    })?,
+                    Err(_elapsed) => {
+                        return Err(Error::Timeout {
+                            uri,
+                            method,
+                            timeout: self.inner.timeout,
+                        })
+                    }
+                };
+
+            let max = self.inner.max_response_bytes;
+            if let (Some(max), Some(length)) = (max, market_response.body().size_hint().exact()) {
+                if length > max {
+                    PROXY_RESPONSES_TOO_LARGE.inc();
+
+                    return Err(Error::ResponseTooLarge { uri, length, max });
+                }
+            }
+
+            let (parts, body) = market_response.into_parts();
+            let body = CountedBody {
+                inner: body.boxed(),
+                bytes: 0,
+                max,
+                path: uri.path().to_string(),
+                logger: self.inner.logger.clone(),
+                done: false,
+            };
+            let response = Response::from_parts(parts, Body::wrap_stream(body));
 
             // add the additional response headers to the Response from the Market
-            proxy_response
-                .headers_mut()
-                .extend(self.inner.default_headers.response.clone());
+            let mut proxy_response = ProxiedResponse::new(response);
+            for (name, value) in self.inner.default_headers.response.iter() {
+                proxy_response.insert_header(name.clone(), value.clone());
+            }
 
             debug!(&self.inner.logger, "Proxied request to Market"; "uri" => %uri, "method" => %method);
 
-            Ok(proxy_response)
+            Ok(proxy_response.into_response())
+        }
+
+        /// `HEAD` request to the Market URL, keeping a pooled connection alive (or opening a new one)
+        pub async fn warm_up(&self) -> Result<(), Error> {
+            let request = Request::head("/")
+                .body(Body::empty())
+                .expect("Should build the warm-up request");
+
+            self.proxy(request).await.map(drop)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config::{HostHeader, DEVELOPMENT},
+        util::test::{discard_logger, MemoryDrain},
+    };
+    use http::{
+        header::{HeaderName, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+        HeaderValue, Request, Response, Uri,
+    };
+    use hyper::Body;
+    use primitives::util::tests::prep_db::{DUMMY_AD_UNITS, IDS};
+    use std::io::Read;
+    use wiremock::{
+        matchers::{body_string, header, header_exists, method, path, query_param},
+        Match, Mock, MockServer, ResponseTemplate,
+    };
+
+    /// The previous way of building the proxied URIs by formatting and parsing the whole URI
+    fn formatted_upstream_uri(market_url: &MarketUrl, uri: &Uri) -> Uri {
+        let path_and_query = uri
+            .path_and_query()
+            .map(|p_q| {
+                let string = p_q.to_string();
+                string
+                    .strip_prefix('/')
+                    .map(ToString::to_string)
+                    .unwrap_or(string)
+            })
+            .unwrap_or_default();
+
+        format!("{}{}", market_url, path_and_query)
+            .parse()
+            .expect("Should parse the formatted URI")
+    }
+
+    #[test]
+    fn upstream_uris_are_identical_to_the_formatted_ones() {
+        let market_urls = [
+            "http://localhost:4000/",
+            "https://market.adex.network/",
+            "https://market.adex.network/market/",
+            "http://127.0.0.1:8005/nested/market/",
+        ];
+        let uris = [
+            "/",
+            "/slots/QmQ696XttGrC456X3uBDMViPAJi24VJmbXAzayNw5maoLe",
+            "/units?limit=10&skip=20",
+            "/tags/",
+            "/?",
+            "/campaigns?status=Active,Ready&byCreator=0x2892f6C41E0718eeeDd49D98D648C789668cA67d",
+            "/path%20with%20escapes?q=%3Fa%3Db",
+            "//double/slash",
+            "/units-for-slot/Qm?depositAsset=0x6B175474E89094C44Da98b954EedeAC495271d0F#fragment",
+            "*",
+        ];
+
+        for market_url in market_urls.iter() {
+            let market_url: MarketUrl = market_url.parse().expect("Valid Market URL");
+            let upstream_uri = UpstreamUri::new(&market_url);
+
+            for uri in uris.iter() {
+                let uri: Uri = uri.parse().expect("Valid URI");
+
+                let upstream = upstream_uri.uri(&uri).expect("Should build the URI");
+                let formatted = formatted_upstream_uri(&market_url, &uri);
+
+                assert_eq!(formatted, upstream, "Market URL: {}", market_url);
+                assert_eq!(formatted.to_string(), upstream.to_string());
+            }
+        }
+    }
+
+    /// Serves a single `Transfer-Encoding: chunked` response, returns the Market URL
+    fn chunked_market() -> MarketUrl {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Should bind");
+        let addr = listener.local_addr().expect("Should have an address");
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Should accept");
+            let mut request = [0_u8; 1024];
+            let _ = stream.read(&mut request);
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n5\r\nHello\r\n6\r\n world\r\n0\r\n\r\n")
+                .expect("Should write the response");
+        });
+
+        format!("http://{}/", addr)
+            .parse()
+            .expect("Valid Market URL")
+    }
+
+    async fn proxy_get(market_url: MarketUrl, path: &str) -> Response<Body> {
+        let proxy = Proxy::new(market_url, &DEVELOPMENT, discard_logger());
+        let request = Request::get(path)
+            .body(Body::empty())
+            .expect("Should build the request");
+
+        proxy
+            .proxy(request)
+            .await
+            .expect("Should proxy the request")
+    }
+
+    #[tokio::test]
+    async fn chunked_responses_are_streamed_as_they_are() {
+        let response = proxy_get(chunked_market(), "/tags").await;
+
+        assert_eq!("chunked", response.headers()[TRANSFER_ENCODING]);
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        assert_eq!("adex-supermarket-proxy", response.headers()["x-served-by"]);
+
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read the body");
+        assert_eq!(&b"Hello world"[..], &body[..]);
+    }
+
+    /// A Market which responds with the headers after the `headers_delay`
+    /// and with the last chunk of the body after the `body_delay`
+    fn slow_market(headers_delay: Duration, body_delay: Duration) -> MarketUrl {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Should bind");
+        let addr = listener.local_addr().expect("Should have an address");
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Should accept");
+            let mut request = [0_u8; 1024];
+            let _ = stream.read(&mut request);
+
+            std::thread::sleep(headers_delay);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n5\r\nHello\r\n");
+            let _ = stream.flush();
+            std::thread::sleep(body_delay);
+            let _ = stream.write_all(b"6\r\n world\r\n0\r\n\r\n");
+        });
+
+        format!("http://{}/", addr)
+            .parse()
+            .expect("Valid Market URL")
+    }
+
+    #[tokio::test]
+    async fn the_timeout_bounds_only_the_response_headers() {
+        let mut config = DEVELOPMENT.clone();
+        config.timeouts.global_request = Duration::from_millis(200);
+
+        // the body is streamed for longer than the timeout
+        let market_url = slow_market(Duration::from_millis(0), Duration::from_millis(400));
+        let proxy = Proxy::new(market_url, &config, discard_logger());
+        let request = Request::get("/tags")
+            .body(Body::empty())
+            .expect("Should build the request");
+        let response = proxy
+            .proxy(request)
+            .await
+            .expect("Should proxy the request");
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read the whole body");
+        assert_eq!(&b"Hello world"[..], &body[..]);
+
+        let market_url = slow_market(Duration::from_millis(400), Duration::from_millis(0));
+        let proxy = Proxy::new(market_url, &config, discard_logger());
+        let request = Request::get("/tags")
+            .body(Body::empty())
+            .expect("Should build the request");
+        match proxy.proxy(request).await {
+            Err(proxy::Error::Timeout { timeout, .. }) => {
+                assert_eq!(config.timeouts.global_request, timeout)
+            }
+            other => panic!("Expected the headers to time out, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_exceeding_the_max_size_are_truncated_or_rejected() {
+        let mut config = DEVELOPMENT.clone();
+        config.proxy.max_response_bytes = Some(8);
+        let too_large = || crate::metrics::PROXY_RESPONSES_TOO_LARGE.get();
+        let before = too_large();
+
+        // "Hello" is streamed, " world" exceeds the max
+        let drain = MemoryDrain::default();
+        let proxy = Proxy::new(chunked_market(), &config, drain.logger());
+        let request = Request::get("/tags")
+            .body(Body::empty())
+            .expect("Should build the request");
+        let response = proxy
+            .proxy(request)
+            .await
+            .expect("Should proxy the request");
+        assert_eq!(StatusCode::OK, response.status());
+
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+        let records = drain.records();
+        let (_, key_values) = records
+            .iter()
+            .find(|(message, _)| message.contains("Truncated"))
+            .expect("Should log the truncated response");
+        assert_eq!("/tags", key_values["path"]);
+        assert_eq!("11", key_values["bytes"]);
+
+        // with a `Content-Length` it's rejected before streaming the body
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/units"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("a longer body"))
+            .mount(&server)
+            .await;
+        let market_url = format!("{}/", server.uri())
+            .parse()
+            .expect("Valid Market URL");
+        let proxy = Proxy::new(market_url, &config, discard_logger());
+        let request = Request::get("/units")
+            .body(Body::empty())
+            .expect("Should build the request");
+
+        match proxy.proxy(request).await {
+            Err(proxy::Error::ResponseTooLarge { length, max, .. }) => {
+                assert_eq!((13, 8), (length, max))
+            }
+            other => panic!("Expected a too large response, got: {:?}", other),
+        }
+        assert!(too_large() >= before + 2);
+    }
+
+    #[tokio::test]
+    async fn modifying_the_body_drops_the_framing_headers() {
+        let chunked = ProxiedResponse::new(proxy_get(chunked_market(), "/tags").await)
+            .map_body(|body| body.to_ascii_uppercase().into())
+            .await
+            .expect("Should read the body")
+            .into_response();
+
+        assert!(chunked.headers().get(TRANSFER_ENCODING).is_none());
+        assert!(chunked.headers().get(CONTENT_LENGTH).is_none());
+        assert_eq!("text/plain", chunked.headers()[CONTENT_TYPE]);
+        let body = hyper::body::to_bytes(chunked.into_body())
+            .await
+            .expect("Should read the body");
+        assert_eq!(&b"HELLO WORLD"[..], &body[..]);
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/tags"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .mount(&server)
+            .await;
+        let market_url = format!("{}/", server.uri())
+            .parse()
+            .expect("Valid Market URL");
+
+        let mut response = ProxiedResponse::new(proxy_get(market_url, "/tags").await);
+        assert_eq!("5", response.headers()[CONTENT_LENGTH]);
+
+        // only adding headers keeps the `Content-Length`
+        assert!(response.insert_header(
+            HeaderName::from_static("x-cache"),
+            HeaderValue::from_static("MISS")
+        ));
+        assert!(!response.insert_header(CONTENT_LENGTH, HeaderValue::from_static("100")));
+        assert!(!response.remove_header(&TRANSFER_ENCODING));
+        assert_eq!("5", response.headers()[CONTENT_LENGTH]);
+        assert_eq!("MISS", response.headers()["x-cache"]);
+
+        response.replace_body("a longer body");
+        let response = response.into_response();
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read the body");
+        assert_eq!(&b"a longer body"[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn redirects_and_request_bodies_are_passed_through() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/old"))
+            .respond_with(ResponseTemplate::new(301).insert_header("Location", "/new"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/session"))
+            .and(body_string("{\"identity\":\"0x1\"}"))
+            .respond_with(ResponseTemplate::new(201).set_body_string("created"))
+            .mount(&server)
+            .await;
+        let market_url: MarketUrl = format!("{}/", server.uri())
+            .parse()
+            .expect("Valid Market URL");
+
+        let redirect = proxy_get(market_url.clone(), "/old").await;
+        assert_eq!(StatusCode::MOVED_PERMANENTLY, redirect.status());
+        assert_eq!("/new", redirect.headers()["location"]);
+
+        let proxy = Proxy::new(market_url, &DEVELOPMENT, discard_logger());
+        let request = Request::post("/session")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"identity\":\"0x1\"}"))
+            .expect("Should build the request");
+        let response = proxy
+            .proxy(request)
+            .await
+            .expect("Should proxy the request");
+
+        assert_eq!(StatusCode::CREATED, response.status());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read the body");
+        assert_eq!(&b"created"[..], &body[..]);
+    }
+
+    #[tokio::test]
+    async fn proxy_headers_are_set_on_the_proxied_requests() {
+        let server = MockServer::start().await;
+        let market_url: MarketUrl = format!("{}/", server.uri())
+            .parse()
+            .expect("Valid Market URL");
+        let forwarded_by = format!("adex-supermarket/{}", crate::build_info::VERSION);
+
+        Mock::given(method("GET"))
+            .and(path("/slots"))
+            .and(header("x-forwarded-by", forwarded_by.as_str()))
+            .and(header("x-source", "supermarket"))
+            .and(header("user-agent", "AdView/1.0"))
+            .and(header("host", market_host(&market_url).as_str()))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/units"))
+            .and(header("x-forwarded-by", forwarded_by.as_str()))
+            .and(header("user-agent", forwarded_by.as_str()))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let request = |path: &str| {
+            Request::get(path)
+                .header("x-forwarded-by", "spoofed")
+                .header("x-source", "spoofed")
+                .header("user-agent", "AdView/1.0")
+                .header("host", "supermarket.adex.network")
+                .body(Body::empty())
+                .expect("Should build the request")
+        };
+
+        // the incoming User-Agent is preserved
+        let mut config = DEVELOPMENT.clone();
+        config
+            .proxy
+            .extra_headers
+            .insert("X-Source".to_string(), "supermarket".to_string());
+        let proxy = Proxy::new(market_url.clone(), &config, discard_logger());
+        let response = proxy
+            .proxy(request("/slots"))
+            .await
+            .expect("Should proxy the request");
+        assert_eq!(StatusCode::OK, response.status());
+
+        // the incoming User-Agent is replaced
+        let mut config = DEVELOPMENT.clone();
+        config.proxy.user_agent = Some("adex-supermarket/{version}".to_string());
+        let proxy = Proxy::new(market_url, &config, discard_logger());
+        let response = proxy
+            .proxy(request("/units"))
+            .await
+            .expect("Should proxy the request");
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    /// Proxies a `GET` request with the `incoming_host` to a Market serving a single response,
+    /// returns the `Host` headers of the request received by the Market and the Market URL
+    async fn proxied_hosts(
+        config: &Config,
+        incoming_host: Option<&str>,
+    ) -> (Vec<String>, MarketUrl) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Should bind");
+        let addr = listener.local_addr().expect("Should have an address");
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("Should accept");
+            let mut request = [0_u8; 4096];
+            let read = stream.read(&mut request).expect("Should read the request");
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .expect("Should write the response");
+            let _ = sender.send(String::from_utf8_lossy(&request[..read]).to_string());
+        });
+
+        let market_url: MarketUrl = format!("http://localhost:{}/", addr.port())
+            .parse()
+            .expect("Valid Market URL");
+        let proxy = Proxy::new(market_url.clone(), config, discard_logger());
+        let mut request = Request::get("/slots");
+        if let Some(host) = incoming_host {
+            request = request.header("host", host);
+        }
+        let response = proxy
+            .proxy(
+                request
+                    .body(Body::empty())
+                    .expect("Should build the request"),
+            )
+            .await
+            .expect("Should proxy the request");
+        assert_eq!(StatusCode::OK, response.status());
+
+        let request = receiver.recv().expect("Should receive the request");
+        let hosts = request
+            .lines()
+            .filter_map(|line| {
+                let (name, value) = line.split_at(line.find(':')?);
+                if name.eq_ignore_ascii_case("host") {
+                    Some(value[1..].trim().to_string())
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        (hosts, market_url)
+    }
+
+    #[tokio::test]
+    async fn the_host_header_is_rewritten_or_preserved() {
+        let mut config = DEVELOPMENT.clone();
+        assert_eq!(HostHeader::Rewrite, config.proxy.host);
+
+        // the non-default port is part of the rewritten Host
+        let (hosts, market_url) = proxied_hosts(&config, Some("supermarket.adex.network")).await;
+        assert_eq!(vec![market_host(&market_url)], hosts);
+        assert!(hosts[0].starts_with("localhost:"));
+
+        config.proxy.host = HostHeader::Preserve;
+        let (hosts, _) = proxied_hosts(&config, Some("supermarket.adex.network")).await;
+        assert_eq!(vec!["supermarket.adex.network".to_string()], hosts);
+
+        // without an incoming Host the one of the Market URL is set by the client
+        let (hosts, market_url) = proxied_hosts(&config, None).await;
+        assert_eq!(vec![market_host(&market_url)], hosts);
+    }
+
+    #[test]
+    fn the_market_host_has_only_the_non_default_ports() {
+        let host = |market_url: &str| market_host(&market_url.parse().expect("Valid Market URL"));
+
+        assert_eq!("market.adex.network", host("https://market.adex.network/"));
+        assert_eq!(
+            "market.adex.network",
+            host("https://market.adex.network:443/")
+        );
+        assert_eq!(
+            "market.adex.network",
+            host("http://market.adex.network:80/market/")
+        );
+        assert_eq!(
+            "market.adex.network:8443",
+            host("https://market.adex.network:8443/")
+        );
+        assert_eq!(
+            "127.0.0.1:8005",
+            host("http://127.0.0.1:8005/nested/market/")
+        );
+    }
+
+    /// Matches the requests with a gzipped JSON body
+    struct GzippedJson(serde_json::Value);
+
+    impl Match for GzippedJson {
+        fn matches(&self, request: &wiremock::Request) -> bool {
+            let mut json = Vec::new();
+            flate2::read::GzDecoder::new(&request.body[..])
+                .read_to_end(&mut json)
+                .is_ok()
+                && serde_json::from_slice::<serde_json::Value>(&json)
+                    .ok()
+                    .as_ref()
+                    == Some(&self.0)
         }
     }
+
+    #[test]
+    fn the_units_query_is_url_encoded() {
+        assert_eq!(
+            "units?limit=1000&skip=0&type=legacy_250x250",
+            MarketApi::units_page_path("legacy_250x250", 0, 1_000)
+        );
+        assert_eq!(
+            "units?limit=1000&skip=2000&type=legacy+300x100%26skip%3D0",
+            MarketApi::units_page_path("legacy 300x100&skip=0", 2000, 1_000)
+        );
+        assert_eq!(
+            "units?limit=1000&skip=0&type=%D0%B1%D0%B0%D0%BD%D0%B5%D1%80",
+            MarketApi::units_page_path("банер", 0, 1_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn units_are_filtered_by_the_slot_type() {
+        let server = MockServer::start().await;
+        let ad_slot = AdSlot {
+            ipfs: "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C".to_string(),
+            ad_type: "legacy 250x250".to_string(),
+            archived: false,
+            created: chrono::Utc::now(),
+            description: None,
+            fallback_unit: None,
+            min_per_impression: None,
+            modified: None,
+            owner: IDS["publisher"],
+            title: None,
+            website: None,
+            rules: vec![],
+        };
+
+        let mut of_the_slot_type = DUMMY_AD_UNITS[0].clone();
+        of_the_slot_type.ad_type = ad_slot.ad_type.clone();
+        let mut of_other_type = DUMMY_AD_UNITS[1].clone();
+        of_other_type.ad_type = "legacy_728x90".to_string();
+
+        Mock::given(method("GET"))
+            .and(path("/units"))
+            .and(query_param("type", "legacy 250x250"))
+            .and(query_param("skip", "0"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![
+                    of_the_slot_type.clone(),
+                    of_other_type,
+                ])),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let drain = MemoryDrain::default();
+        let units = market(&format!("{}/", server.uri()), drain.logger())
+            .fetch_units(&ad_slot, None)
+            .await
+            .expect("Should fetch the AdUnits")
+            .units;
+
+        // the unit of the other type, returned despite the filter, is dropped
+        assert_eq!(
+            vec![of_the_slot_type.ipfs],
+            units.into_iter().map(|unit| unit.ipfs).collect::<Vec<_>>()
+        );
+        let records = drain.records();
+        assert_eq!(1, records.len());
+        assert_eq!("1", records[0].1["dropped"]);
+    }
+
+    #[tokio::test]
+    async fn the_units_pages_stop_at_the_max_units() {
+        let server = MockServer::start().await;
+        let ad_slot = AdSlot {
+            ipfs: "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C".to_string(),
+            ad_type: "legacy_250x250".to_string(),
+            archived: false,
+            created: chrono::Utc::now(),
+            description: None,
+            fallback_unit: None,
+            min_per_impression: None,
+            modified: None,
+            owner: IDS["publisher"],
+            title: None,
+            website: None,
+            rules: vec![],
+        };
+        let units = |count: usize| {
+            let mut unit = DUMMY_AD_UNITS[0].clone();
+            unit.ad_type = ad_slot.ad_type.clone();
+
+            AdUnitsResponse(vec![unit; count])
+        };
+        let page = |skip: &str, limit: &str, response: AdUnitsResponse, expected: u64| {
+            Mock::given(method("GET"))
+                .and(path("/units"))
+                .and(query_param("skip", skip))
+                .and(query_param("limit", limit))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&response))
+                .expect(expected)
+        };
+
+        // the Market has more AdUnits than all of the pages
+        page("0", "1000", units(1_000), 1).mount(&server).await;
+        page("1000", "2", units(2), 1).mount(&server).await;
+        page("0", "4", units(4), 1).mount(&server).await;
+        page("0", "11", units(2), 1).mount(&server).await;
+
+        let market = market(&format!("{}/", server.uri()), discard_logger());
+        let fetched = |max_units: usize| market.fetch_units(&ad_slot, Some(max_units));
+
+        let capped = fetched(1_001).await.expect("Should fetch the AdUnits");
+        assert_eq!(1_001, capped.units.len());
+        assert!(capped.truncated);
+
+        let capped = fetched(3).await.expect("Should fetch the AdUnits");
+        assert_eq!(3, capped.units.len());
+        assert!(capped.truncated);
+
+        let below = fetched(10).await.expect("Should fetch the AdUnits");
+        assert_eq!(2, below.units.len());
+        assert!(!below.truncated);
+    }
+
+    #[tokio::test]
+    async fn slots_are_revalidated_with_their_etag_and_last_modified() {
+        let server = MockServer::start().await;
+        let ad_slot_response = |ipfs: &str| AdSlotResponse {
+            slot: AdSlot {
+                ipfs: ipfs.to_string(),
+                ad_type: "legacy_250x250".to_string(),
+                archived: false,
+                created: chrono::Utc::now(),
+                description: None,
+                fallback_unit: None,
+                min_per_impression: None,
+                modified: None,
+                owner: IDS["publisher"],
+                title: None,
+                website: None,
+                rules: vec![],
+            },
+            accepted_referrers: vec![],
+            categories: vec![],
+            alexa_rank: None,
+        };
+        let last_modified = "Mon, 01 Mar 2021 12:00:00 GMT";
+
+        // mounted first, so it takes precedence for the conditional requests with the current ETag
+        Mock::given(method("GET"))
+            .and(path("/slots/QmVersioned"))
+            .and(header("if-none-match", "\"v2\""))
+            .and(header("if-modified-since", last_modified))
+            .respond_with(ResponseTemplate::new(304))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/slots/QmVersioned"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("etag", "\"v2\"")
+                    .insert_header("last-modified", last_modified)
+                    .set_body_json(&ad_slot_response("QmVersioned")),
+            )
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/slots/QmUnversioned"))
+            .and(header_exists("if-none-match"))
+            .respond_with(ResponseTemplate::new(304))
+            .expect(0)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/slots/QmUnversioned"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(&ad_slot_response("QmUnversioned")),
+            )
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let market = market(&format!("{}/", server.uri()), discard_logger());
+
+        let version = match market
+            .fetch_slot_if_modified("QmVersioned", None)
+            .await
+            .expect("Should fetch the AdSlot")
+        {
+            SlotFetch::Modified(response, version) => {
+                assert_eq!("QmVersioned", response.slot.ipfs);
+                version
+            }
+            fetched => panic!("Expected a modified AdSlot, got: {:?}", fetched),
+        };
+        assert_eq!(
+            SlotVersion {
+                etag: Some(HeaderValue::from_static("\"v2\"")),
+                last_modified: Some(HeaderValue::from_static(last_modified)),
+            },
+            version
+        );
+        assert!(matches!(
+            market
+                .fetch_slot_if_modified("QmVersioned", Some(&version))
+                .await,
+            Ok(SlotFetch::NotModified)
+        ));
+
+        // an outdated ETag is modified
+        let outdated = SlotVersion {
+            etag: Some(HeaderValue::from_static("\"v1\"")),
+            ..version
+        };
+        assert!(matches!(
+            market
+                .fetch_slot_if_modified("QmVersioned", Some(&outdated))
+                .await,
+            Ok(SlotFetch::Modified(_, _))
+        ));
+
+        // without an ETag & Last-Modified it's always fetched unconditionally
+        let version = match market
+            .fetch_slot_if_modified("QmUnversioned", None)
+            .await
+            .expect("Should fetch the AdSlot")
+        {
+            SlotFetch::Modified(_, version) => version,
+            fetched => panic!("Expected a modified AdSlot, got: {:?}", fetched),
+        };
+        assert!(version.is_empty());
+        assert!(matches!(
+            market
+                .fetch_slot_if_modified("QmUnversioned", Some(&version))
+                .await,
+            Ok(SlotFetch::Modified(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn gzipped_responses_are_deserialized() {
+        let server = MockServer::start().await;
+        let ad_units = AdUnitsResponse(DUMMY_AD_UNITS.iter().cloned().map(Into::into).collect());
+        let json = serde_json::to_vec(&ad_units).expect("Should serialize");
+
+        Mock::given(method("GET"))
+            .and(path("/units"))
+            .and(header_exists("accept-encoding"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-encoding", "gzip")
+                    .set_body_raw(gzip(&json).expect("Should gzip"), "application/json"),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let units = market(&format!("{}/", server.uri()), discard_logger())
+            .fetch_units_page("legacy_250x250", 0)
+            .await
+            .expect("Should decompress & deserialize the AdUnits");
+
+        assert_eq!(
+            serde_json::to_value(&ad_units.0).expect("Should serialize"),
+            serde_json::to_value(&units).expect("Should serialize")
+        );
+    }
+
+    #[tokio::test]
+    async fn large_request_bodies_are_gzipped() {
+        let server = MockServer::start().await;
+        let small = serde_json::json!({ "small": true });
+        let large = serde_json::json!({ "large": "a".repeat(100) });
+
+        Mock::given(method("POST"))
+            .and(path("/events"))
+            .and(header("content-encoding", "gzip"))
+            .and(GzippedJson(large.clone()))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/events"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let mut config = DEVELOPMENT.clone();
+        config.market.gzip_requests_over = Some(64);
+        let market = MarketApi::new(
+            format!("{}/", server.uri())
+                .parse()
+                .expect("Valid Market URL"),
+            &config,
+            discard_logger(),
+        )
+        .expect("Should create MarketApi");
+
+        let response = market
+            .post_json("events", &small)
+            .await
+            .expect("Should POST");
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = market
+            .post_json("events", &large)
+            .await
+            .expect("Should POST");
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        config.market.gzip_requests_over = None;
+        let market = MarketApi::new(market.market_url, &config, discard_logger())
+            .expect("Should create MarketApi");
+        let response = market
+            .post_json("events", &large)
+            .await
+            .expect("Should POST");
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    fn market(market_url: &str, logger: Logger) -> MarketApi {
+        MarketApi::new(
+            market_url.parse().expect("Valid Market URL"),
+            &DEVELOPMENT,
+            logger,
+        )
+        .expect("Should create MarketApi")
+    }
+
+    #[tokio::test]
+    async fn probing_the_market() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/market/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/down/"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let logger = discard_logger();
+
+        assert!(market(&format!("{}/market/", server.uri()), logger.clone())
+            .probe()
+            .await
+            .is_ok());
+
+        match market(&format!("{}/down/", server.uri()), logger.clone())
+            .probe()
+            .await
+        {
+            Err(ProbeError::Status(status)) => {
+                assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status)
+            }
+            result => panic!("Expected a Status error, got: {:?}", result),
+        }
+
+        // the `.invalid` TLD never resolves
+        match market("http://supermarket-probe.invalid/", logger.clone())
+            .probe()
+            .await
+        {
+            Err(ProbeError::Dns(_)) => {}
+            result => panic!("Expected a DNS error, got: {:?}", result),
+        }
+
+        // the mock server doesn't speak TLS
+        let https_url = server.uri().replacen("http://", "https://", 1);
+        match market(&format!("{}/market/", https_url), logger)
+            .probe()
+            .await
+        {
+            Err(ProbeError::Tls(_)) => {}
+            result => panic!("Expected a TLS error, got: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn verifying_the_market_on_start() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        let market_url = format!("{}/market/", server.uri());
+
+        let disabled = VerifyMarketOnStart {
+            enabled: false,
+            strict: true,
+        };
+        assert!(market(&market_url, discard_logger())
+            .verify_on_start(&disabled)
+            .await
+            .is_ok());
+
+        let strict = VerifyMarketOnStart {
+            enabled: true,
+            strict: true,
+        };
+        match market(&market_url, discard_logger())
+            .verify_on_start(&strict)
+            .await
+        {
+            Err(ProbeError::Status(status)) => {
+                assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status)
+            }
+            result => panic!("Expected a Status error, got: {:?}", result),
+        }
+
+        let drain = MemoryDrain::default();
+        let lenient = VerifyMarketOnStart {
+            enabled: true,
+            strict: false,
+        };
+        assert!(market(&market_url, drain.logger())
+            .verify_on_start(&lenient)
+            .await
+            .is_ok());
+
+        let records = drain.records();
+        let (message, key_values) = records
+            .iter()
+            .find(|(message, _)| message.contains("UNREACHABLE"))
+            .expect("Should log the failed probe");
+        assert!(message.contains("`strict` is not set"));
+        assert!(key_values["error"].contains("500"));
+    }
 }
@@ -0,0 +1,240 @@
+//! Validating the events submitted through the proxy (`POST /channel/:id/events`) before they are proxied,
+//! so the obviously malformed submissions don't reach the Market, see [`EventValidation`].
+//!
+//! Only the bodies of the submissions are buffered, the other proxied requests are streamed.
+use crate::{bad_request, config::EventValidation, metrics::PROXY_EVENTS_REFUSED};
+use http::{header::CONTENT_LENGTH, Request, Response, StatusCode};
+use hyper::{body::HttpBody, Body};
+use serde::Deserialize;
+
+/// The types of the events submitted by the SDK
+pub const EVENT_TYPES: [&str; 2] = ["IMPRESSION", "CLICK"];
+
+/// The other fields of the body and the events are not validated
+#[derive(Debug, Deserialize)]
+struct Submission {
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    event_type: String,
+    publisher: String,
+}
+
+/// The `:id` of `/channel/:id/events`
+pub fn submission_route(path: &str) -> Option<&str> {
+    path.strip_prefix("/channel/")?
+        .strip_suffix("/events")
+        .filter(|id| !id.is_empty() && !id.contains('/'))
+}
+
+/// Buffers the body of the submission (up to the `max_body_bytes`) and returns the request with it, if it's valid.
+/// Otherwise it returns the response, counted in the [`PROXY_EVENTS_REFUSED`]:
+///
+/// - `413 Payload Too Large` if the body (or its `Content-Length`) is larger than the `max_body_bytes`
+/// - `400 Bad Request` with the reason if the body isn't a JSON object with a non-empty `events` array,
+///   or an event has an unknown `type` (see [`EVENT_TYPES`]) or a `publisher` which isn't an address
+pub async fn validate(
+    request: Request<Body>,
+    validation: &EventValidation,
+) -> Result<Request<Body>, Response<Body>> {
+    let (parts, mut body) = request.into_parts();
+    let max = validation.max_body_bytes;
+    let too_large = || {
+        PROXY_EVENTS_REFUSED.with_label_values(&["too_large"]).inc();
+
+        Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(Body::from(format!(
+                "The body should be at most {} bytes",
+                max
+            )))
+            .expect("Response should be valid")
+    };
+
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if content_length.map_or(false, |length| length > max) {
+        return Err(too_large());
+    }
+
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|error| bad_request(format!("Reading body: {}", error)))?;
+
+        if (bytes.len() + chunk.len()) as u64 > max {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    if let Err(reason) = validate_body(&bytes) {
+        PROXY_EVENTS_REFUSED.with_label_values(&["malformed"]).inc();
+
+        return Err(bad_request(reason));
+    }
+
+    Ok(Request::from_parts(parts, Body::from(bytes)))
+}
+
+fn validate_body(bytes: &[u8]) -> Result<(), String> {
+    let submission: Submission = serde_json::from_slice(bytes).map_err(|error| {
+        format!(
+            "Malformed events at line {} column {}: {}",
+            error.line(),
+            error.column(),
+            error
+        )
+    })?;
+
+    if submission.events.is_empty() {
+        return Err("There should be at least one event".to_string());
+    }
+
+    for (index, event) in submission.events.iter().enumerate() {
+        if !EVENT_TYPES.contains(&event.event_type.as_str()) {
+            return Err(format!(
+                "events[{}]: unknown type `{}`",
+                index, event.event_type
+            ));
+        }
+
+        if !is_address(&event.publisher) {
+            return Err(format!(
+                "events[{}]: the publisher `{}` is not an address",
+                index, event.publisher
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// `0x` and 40 hex digits, in either case
+fn is_address(address: &str) -> bool {
+    address.strip_prefix("0x").map_or(false, |hex| {
+        hex.len() == 40 && hex.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const PUBLISHER: &str = "0xB7d3F81E857692d13e9D63b232A90F4A1793189E";
+
+    fn submission(body: impl Into<Body>) -> Request<Body> {
+        Request::post(
+            "/channel/0x061d5e2a67d0a9a10f1c732bca12a676d83f79663a396f7d87b3e30b9b411088/events",
+        )
+        .body(body.into())
+        .expect("Should build the request")
+    }
+
+    async fn refused(request: Request<Body>, validation: &EventValidation) -> StatusCode {
+        validate(request, validation)
+            .await
+            .expect_err("Should refuse the submission")
+            .status()
+    }
+
+    #[test]
+    fn only_the_event_submissions_are_matched() {
+        assert_eq!(Some("0x06"), submission_route("/channel/0x06/events"));
+        assert_eq!(None, submission_route("/channel//events"));
+        assert_eq!(None, submission_route("/channel/0x06/events/extra"));
+        assert_eq!(None, submission_route("/channel/0x06/last-approved"));
+        assert_eq!(None, submission_route("/units-for-slot/Qm"));
+    }
+
+    #[tokio::test]
+    async fn valid_events_are_proxied_with_the_same_body() {
+        let body = json!({
+            "events": [
+                { "type": "IMPRESSION", "publisher": PUBLISHER, "adUnit": "QmUnit", "adSlot": "QmSlot" },
+                { "type": "CLICK", "publisher": PUBLISHER.to_lowercase(), "referrer": "https://adex.network" },
+            ]
+        })
+        .to_string();
+
+        let request = validate(submission(body.clone()), &EventValidation::default())
+            .await
+            .expect("Should be valid");
+        let proxied = hyper::body::to_bytes(request.into_body())
+            .await
+            .expect("Should read the body");
+        assert_eq!(body.as_bytes(), &proxied[..]);
+    }
+
+    #[tokio::test]
+    async fn malformed_events_are_a_bad_request() {
+        let malformed_before = PROXY_EVENTS_REFUSED.with_label_values(&["malformed"]).get();
+        let validation = EventValidation::default();
+        let event = |event_type: &str, publisher: &str| {
+            json!({ "events": [{ "type": event_type, "publisher": publisher }] }).to_string()
+        };
+
+        for body in vec![
+            "not json".to_string(),
+            json!([{ "type": "IMPRESSION", "publisher": PUBLISHER }]).to_string(),
+            json!({ "events": [] }).to_string(),
+            json!({ "events": [{ "type": "IMPRESSION" }] }).to_string(),
+            event("PAYOUT", PUBLISHER),
+            event("impression", PUBLISHER),
+            event("IMPRESSION", "0xB7d3F81E857692d13e9D63b232A90F4A1793189"),
+            event("IMPRESSION", &PUBLISHER.replace("0x", "")),
+            event("CLICK", "0xZZd3F81E857692d13e9D63b232A90F4A1793189E"),
+        ] {
+            assert_eq!(
+                StatusCode::BAD_REQUEST,
+                refused(submission(body.clone()), &validation).await,
+                "{}",
+                body
+            );
+        }
+        assert!(
+            PROXY_EVENTS_REFUSED.with_label_values(&["malformed"]).get() >= malformed_before + 9
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_refused() {
+        let validation = EventValidation {
+            enabled: true,
+            max_body_bytes: 64,
+        };
+        let body = json!({
+            "events": [{ "type": "IMPRESSION", "publisher": PUBLISHER }, { "type": "CLICK", "publisher": PUBLISHER }]
+        })
+        .to_string();
+        assert!(body.len() > 64);
+
+        // by the `Content-Length`, without reading the body
+        let mut request = submission(body.clone());
+        request
+            .headers_mut()
+            .insert(CONTENT_LENGTH, body.len().into());
+        assert_eq!(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            refused(request, &validation).await
+        );
+
+        // streamed without a `Content-Length`
+        let chunks = body
+            .as_bytes()
+            .chunks(16)
+            .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        let streamed = submission(Body::wrap_stream(futures::stream::iter(chunks)));
+        assert_eq!(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            refused(streamed, &validation).await
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Which paths are proxied to the Market, so the Supermarket isn't an open relay to every route of the Market
+//! (incl. its admin routes), see [`ProxyPaths`].
+//!
+//! The prefixes are matched against the decoded & normalized path, so neither `..` nor `//` get around them.
+//! The raw path is what's proxied, so the escaped `.`, `/` & `%` (incl. the double-encoded ones, e.g. `%252e%252e`)
+//! are refused, as the Market might decode them differently than the checked path.
+use crate::{config::ProxyPaths, metrics::PROXY_PATHS_REFUSED};
+use http::{header::CONTENT_TYPE, Response, StatusCode};
+use hyper::Body;
+
+/// The escapes of `.`, `/` & `%`, compared case-insensitively
+const AMBIGUOUS_ESCAPES: [&str; 3] = ["%2e", "%2f", "%25"];
+
+/// Whether the request `path` is proxied: it's not under any of the `denied` prefixes
+/// and it's under one of the `allowed` ones (or the proxy is `open_proxy`).
+/// A path which can't be decoded (malformed escapes or not UTF-8) or has any of the [`AMBIGUOUS_ESCAPES`] is never proxied.
+pub fn is_proxied(path: &str, paths: &ProxyPaths) -> bool {
+    if has_ambiguous_escapes(path) {
+        return false;
+    }
+
+    let path = match normalize(path) {
+        Some(path) => path,
+        None => return false,
+    };
+
+    if paths.denied.iter().any(|prefix| is_under(&path, prefix)) {
+        return false;
+    }
+
+    paths.open_proxy || paths.allowed.iter().any(|prefix| is_under(&path, prefix))
+}
+
+/// The percent-decoded path without the empty & `.` segments and with the `..` segments resolved,
+/// e.g. `/units/%2E%2E//admin/` is `/admin`. `None` if it can't be decoded
+pub fn normalize(path: &str) -> Option<String> {
+    let decoded = percent_decode(path)?;
+
+    let mut segments: Vec<&str> = vec![];
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    Some(format!("/{}", segments.join("/")))
+}
+
+/// The `403 Forbidden` of the paths which are not proxied, counted in the [`PROXY_PATHS_REFUSED`]
+pub fn refused() -> Response<Body> {
+    PROXY_PATHS_REFUSED.inc();
+
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({ "error": "path not allowed" }).to_string(),
+        ))
+        .expect("Should create the refused path response")
+}
+
+/// The path is the `prefix` or one of its sub-paths, i.e. `/units` covers `/units/Qm` but not `/unitsadmin`
+fn is_under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+
+    prefix.is_empty()
+        || path
+            .strip_prefix(prefix)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn has_ambiguous_escapes(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+
+    AMBIGUOUS_ESCAPES.iter().any(|escape| path.contains(escape))
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = path.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paths(allowed: &[&str], denied: &[&str]) -> ProxyPaths {
+        ProxyPaths {
+            open_proxy: false,
+            allowed: allowed.iter().map(ToString::to_string).collect(),
+            denied: denied.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn paths_are_decoded_and_normalized() {
+        assert_eq!(Some("/".to_string()), normalize(""));
+        assert_eq!(Some("/".to_string()), normalize("/"));
+        assert_eq!(Some("/units/Qm".to_string()), normalize("//units/./Qm/"));
+        assert_eq!(Some("/admin".to_string()), normalize("/units/../admin"));
+        assert_eq!(Some("/admin".to_string()), normalize("/units/%2E%2e/admin"));
+        assert_eq!(Some("/admin".to_string()), normalize("/../../admin"));
+        assert_eq!(Some("/units/a b".to_string()), normalize("/units/a%20b"));
+        assert_eq!(Some("/admin".to_string()), normalize("/units%2F..%2Fadmin"));
+        // UTF-8 segments, e.g. of the tags, are decoded as they are
+        assert_eq!(
+            Some("/tags/кино/cafe\u{301}".to_string()),
+            normalize("/tags/%D0%BA%D0%B8%D0%BD%D0%BE/cafe%CC%81")
+        );
+
+        assert_eq!(None, normalize("/units/%2"));
+        assert_eq!(None, normalize("/units/%zz"));
+        assert_eq!(None, normalize("/units/%FF"));
+    }
+
+    #[test]
+    fn only_the_allowed_paths_which_are_not_denied_are_proxied() {
+        let paths = paths(&["/units", "/campaigns/"], &["/campaigns/admin"]);
+
+        for allowed in &[
+            "/units",
+            "/units/",
+            "/units/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C",
+            "/campaigns",
+            "/campaigns/0x06",
+            "/tags/../units",
+            "/campaigns/admin-stats",
+        ] {
+            assert!(is_proxied(allowed, &paths), "{}", allowed);
+        }
+
+        for denied in &[
+            "/",
+            "/unitsadmin",
+            "/admin",
+            "/units/../admin",
+            "/units/%2e%2e/admin",
+            "/units/%2",
+            "/campaigns/admin",
+            "/campaigns/admin/0x06",
+            "//campaigns//admin",
+            "/campaigns/%61dmin",
+        ] {
+            assert!(!is_proxied(denied, &paths), "{}", denied);
+        }
+    }
+
+    #[test]
+    fn the_escaped_dots_slashes_and_percents_are_never_proxied() {
+        let paths = paths(&["/allowed"], &[]);
+
+        // decoded once they're still under the allowed prefix, but not if the Market decodes them again
+        for escaped in &[
+            "/allowed/%252e%252e/admin",
+            "/allowed/%252E%252E/admin",
+            "/allowed%252F..%252Fadmin",
+            "/allowed/%25252e%25252e/admin",
+            "/allowed/%2e%2e/allowed",
+            "/allowed/%2E",
+            "/allowed/a%2Fb",
+            "/allowed/100%25",
+        ] {
+            assert!(!is_proxied(escaped, &paths), "{}", escaped);
+        }
+
+        assert!(is_proxied("/allowed/a%20b", &paths));
+        assert!(is_proxied("/allowed/./a/../b", &paths));
+    }
+
+    #[test]
+    fn the_default_policy_allows_only_the_known_market_paths() {
+        let default = ProxyPaths::default();
+
+        for allowed in &[
+            "/units",
+            "/slots/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C",
+            "/campaigns",
+            "/tags",
+            "/channel/0x061d5e2a67d0a9a10f1c732bca12a676d83f79663a396f7d87b3e30b9b411088/events",
+        ] {
+            assert!(is_proxied(allowed, &default), "{}", allowed);
+        }
+        for denied in &["/", "/admin", "/users", "/units/../admin"] {
+            assert!(!is_proxied(denied, &default), "{}", denied);
+        }
+
+        // the escape hatch proxies everything but the denied paths
+        let open = ProxyPaths {
+            open_proxy: true,
+            ..paths(&[], &["/admin"])
+        };
+        assert!(is_proxied("/users", &open));
+        assert!(is_proxied("/", &open));
+        assert!(!is_proxied("/admin/users", &open));
+        assert!(!is_proxied("/users/../admin", &open));
+    }
+}
@@ -0,0 +1,482 @@
+//! Prometheus metrics of the Supermarket, served on the [`ROUTE_METRICS`](crate::ROUTE_METRICS) route.
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, proto::LabelPair, register_gauge_vec, register_histogram,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    /// Incremented every time the watchdog finds that the Cache has become stale
+    pub static ref CACHE_STALE: IntCounter = register_int_counter!(
+        "supermarket_cache_stale_total",
+        "Number of times the Cache became stale because it wasn't updated in time"
+    )
+    .expect("Metric should be created and registered");
+
+    /// `1` while the Cache of the `network` is degraded (see [`Cache::is_degraded`](crate::cache::Cache::is_degraded)), by the degradation `policy`
+    pub static ref CACHE_DEGRADED: IntGaugeVec = register_int_gauge_vec!(
+        "supermarket_cache_degraded",
+        "Whether the Cache is degraded (stale, empty or all of its Campaigns are stale), by network and degradation policy",
+        &["network", "policy"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The units-for-slot responses served while the Cache is degraded, by the degradation `policy`
+    pub static ref DEGRADED_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "supermarket_degraded_responses_total",
+        "Number of units-for-slot responses served by the degradation policy while the Cache is degraded",
+        &["policy"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the Campaigns evicted from the Cache because of the Cache limits
+    pub static ref CAMPAIGNS_EVICTED: IntCounter = register_int_counter!(
+        "supermarket_campaigns_evicted_total",
+        "Number of Campaigns evicted from the Cache because of the max_campaigns or max_cache_bytes limits"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the Campaigns which weren't refreshed within the `max_campaign_staleness`
+    pub static ref CAMPAIGNS_STALE: IntCounter = register_int_counter!(
+        "supermarket_stale_campaigns_total",
+        "Number of times a Campaign was not refreshed within the max_campaign_staleness and was excluded from serving"
+    )
+    .expect("Metric should be created and registered");
+
+    /// The discovered Campaigns skipped because of an invalid spec by the failing `rule`, counted once per Campaign,
+    /// see [`validation`](crate::cache::validation)
+    pub static ref INVALID_CAMPAIGNS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_invalid_campaigns_total",
+        "Number of discovered Campaigns skipped because of an invalid spec by the failing rule",
+        &["rule"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the access log lines dropped because the writer couldn't keep up,
+    /// see [`AccessLog`](crate::access_log::AccessLog)
+    pub static ref ACCESS_LOG_DROPPED: IntCounter = register_int_counter!(
+        "supermarket_access_log_dropped_total",
+        "Number of access log lines dropped because the access log writer couldn't keep up"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the units-for-slot samples dropped because the writer couldn't keep up,
+    /// see [`Sampler`](crate::units_for_slot::sampling::Sampler)
+    pub static ref SAMPLES_DROPPED: IntCounter = register_int_counter!(
+        "supermarket_units_for_slot_samples_dropped_total",
+        "Number of units-for-slot samples dropped because the sample writer couldn't keep up"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the Active Campaigns whose spec or targeting rules were amended
+    pub static ref CAMPAIGN_SPEC_CHANGES: IntCounter = register_int_counter!(
+        "supermarket_campaign_spec_changes_total",
+        "Number of times the spec or the targeting rules of an Active Campaign changed and it was replaced"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the malformed `ipfs://` media URLs which were left as they are in the units-for-slot responses
+    pub static ref MALFORMED_IPFS_URLS: IntCounter = register_int_counter!(
+        "supermarket_malformed_ipfs_urls_total",
+        "Number of malformed ipfs media URLs which were not rewritten to the IPFS gateway"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented every time the wall-clock time is found earlier than the last one used for the Campaign statuses,
+    /// see [`NonDecreasingClock`](crate::util::NonDecreasingClock)
+    pub static ref CLOCK_STEPPED_BACK: IntCounter = register_int_counter!(
+        "supermarket_clock_stepped_back_total",
+        "Number of times the system clock was found stepped backwards and the elapsed time was clamped to zero"
+    )
+    .expect("Metric should be created and registered");
+
+    /// The discovered Campaigns with a Leader or a Follower which isn't on the `validator_allowlist`, counted once per Campaign,
+    /// see [`ValidatorAllowlist`](crate::cache::allowlist::ValidatorAllowlist)
+    pub static ref RESTRICTED_CAMPAIGNS: IntCounter = register_int_counter!(
+        "supermarket_restricted_campaigns_total",
+        "Number of discovered Campaigns with a Validator which is not on the allowlist"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the units-for-slot requests whose AdSlot rules exceed the complexity limits, see [`RulesComplexity`](crate::cache::validation::RulesComplexity)
+    pub static ref ADSLOT_RULES_OVER_LIMITS: IntCounter = register_int_counter!(
+        "supermarket_adslot_rules_over_limits_total",
+        "Number of units-for-slot requests whose AdSlot targeting rules were too complex to evaluate"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the units whose targeting rules exhausted the evaluation budget by `rules`: `campaign` or `adslot`,
+    /// see [`BudgetedRules`](crate::units_for_slot::evaluation::BudgetedRules)
+    pub static ref RULES_EVALUATION_BUDGET_EXHAUSTED: IntCounterVec = register_int_counter_vec!(
+        "supermarket_rules_evaluation_budget_exhausted_total",
+        "Number of units whose targeting rules exhausted the evaluation budget and were not shown, by rules",
+        &["rules"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the checks of the AdUnits' media on the IPFS gateway by `result`: `reachable`, `unreachable` or `failed`,
+    /// see [`media_check`](crate::units_for_slot::media_check)
+    pub static ref MEDIA_CHECKS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_media_checks_total",
+        "Number of the AdUnit media checks on the IPFS gateway by result",
+        &["result"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// How long the read & write locks of the Cache waited by `site` (`file:line`) & `mode`,
+    /// only with the `cache-metrics` feature, see [`Lock`](crate::cache::lock::Lock)
+    #[cfg(feature = "cache-metrics")]
+    pub static ref CACHE_LOCK_WAIT: HistogramVec = register_histogram_vec!(
+        "supermarket_cache_lock_wait_seconds",
+        "Time spent waiting for the Cache locks by call site and mode",
+        &["site", "mode"],
+        exponential_buckets(0.0001, 4.0, 8).expect("Buckets should be valid")
+    )
+    .expect("Metric should be created and registered");
+
+    /// The requests currently being handled, see [`InFlight`]
+    pub static ref IN_FLIGHT_REQUESTS: IntGauge = register_int_gauge!(
+        "supermarket_in_flight_requests",
+        "Number of requests currently being handled"
+    )
+    .expect("Metric should be created and registered");
+
+    /// The requests currently being handled by `network` and `route`, see [`InFlight`]
+    pub static ref ROUTE_IN_FLIGHT_REQUESTS: IntGaugeVec = register_int_gauge_vec!(
+        "supermarket_route_in_flight_requests",
+        "Number of requests currently being handled by network and route",
+        &["network", "route"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The requests to the Validators by `validator` (see [`SentryApi::validator_label`](crate::SentryApi::validator_label)) and `endpoint`, see [`SentryApi`](crate::SentryApi)
+    pub static ref VALIDATOR_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_validator_requests_total",
+        "Number of requests to the Validators",
+        &["validator", "endpoint"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The failed requests to the Validators (incl. timeouts and invalid responses) by `validator` (see [`SentryApi::validator_label`](crate::SentryApi::validator_label)) and `endpoint`
+    pub static ref VALIDATOR_REQUEST_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_validator_request_errors_total",
+        "Number of failed requests to the Validators",
+        &["validator", "endpoint"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The malformed entries (e.g. Channels) skipped in the responses of the Validators by `validator` (see [`SentryApi::validator_label`](crate::SentryApi::validator_label)) and `endpoint`
+    pub static ref VALIDATOR_MALFORMED_ENTRIES: IntCounterVec = register_int_counter_vec!(
+        "supermarket_validator_malformed_entries_total",
+        "Number of malformed entries skipped in the responses of the Validators",
+        &["validator", "endpoint"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The new connections of the [`Proxy`](crate::market::Proxy) to the Market, i.e. the TCP & TLS handshakes
+    pub static ref MARKET_PROXY_CONNECTIONS: IntCounter = register_int_counter!(
+        "supermarket_market_proxy_connections_total",
+        "Number of new connections (incl. the TCP & TLS handshakes) opened to the Market by the proxy"
+    )
+    .expect("Metric should be created and registered");
+
+    /// The keep-warm requests by `target` and `result` (`ok`, `error` or `backed_off`), see [`keep_warm`](crate::keep_warm)
+    pub static ref KEEP_WARM_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_keep_warm_requests_total",
+        "Number of requests keeping the pooled connections to the Market and the Validators alive",
+        &["target", "result"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The units-for-slot fetches from the Market which timed out by `phase` (`fetch_slot` or `fetch_units`)
+    pub static ref MARKET_FETCH_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_market_fetch_timeouts_total",
+        "Number of units-for-slot fetches of the AdSlot or its AdUnits from the Market which timed out",
+        &["phase"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The units-for-slot requests aborted because the client's deadline ran out by `phase` (`fetch_slot`, `fetch_units` or `targeting`),
+    /// see [`deadline`](crate::units_for_slot::deadline)
+    pub static ref DEADLINES_EXCEEDED: IntCounterVec = register_int_counter_vec!(
+        "supermarket_units_for_slot_deadline_exceeded_total",
+        "Number of units-for-slot requests aborted because the client's deadline ran out by phase",
+        &["phase"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The units-for-slot responses (of each type) by the selection `strategy` which ordered their units,
+    /// see [`Strategy`](crate::units_for_slot::selection::Strategy)
+    pub static ref SELECTION_STRATEGIES: IntCounterVec = register_int_counter_vec!(
+        "supermarket_units_for_slot_selection_strategy_total",
+        "Number of units-for-slot responses by the strategy which ordered their units",
+        &["strategy"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The Campaigns dropped by each `step` of the [`UnitsForSlotPipeline`](crate::units_for_slot::UnitsForSlotPipeline)
+    pub static ref UNITS_FOR_SLOT_DROPPED: IntCounterVec = register_int_counter_vec!(
+        "supermarket_units_for_slot_dropped_campaigns_total",
+        "Number of Campaigns dropped by each step of the units-for-slot pipeline",
+        &["step"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The units-for-slot requests whose AdSlot couldn't be fetched from the Market by `error`
+    /// (`not_found`, `market_unavailable` or `invalid_response`), see [`SlotFetchError`](crate::units_for_slot::SlotFetchError)
+    pub static ref SLOT_FETCH_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_slot_fetch_errors_total",
+        "Number of units-for-slot requests whose AdSlot couldn't be fetched from the Market by error",
+        &["error"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The lookups of the AdSlots cache by `result` (`hit`, `revalidate` or `miss`), see [`Prewarm`](crate::config::Prewarm)
+    pub static ref SLOT_CACHE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_slot_cache_requests_total",
+        "Number of units-for-slot requests served with a cached AdSlot (hit), revalidating an expired one (revalidate) or fetching it from the Market (miss)",
+        &["result"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The conditional requests for the expired AdSlots by `result` (`not_modified` or `modified`),
+    /// see [`prewarm`](crate::units_for_slot::prewarm)
+    pub static ref SLOT_REVALIDATIONS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_slot_revalidations_total",
+        "Number of expired AdSlots revalidated with the Market by whether they were modified",
+        &["result"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The units-for-slot requests answered with the negative cached `404` of an AdSlot,
+    /// see [`NegativeSlots`](crate::config::NegativeSlots)
+    pub static ref NEGATIVE_SLOT_HITS: IntCounter = register_int_counter!(
+        "supermarket_negative_slot_hits_total",
+        "Number of units-for-slot requests answered with a cached 404 of the AdSlot without requesting the Market"
+    )
+    .expect("Metric should be created and registered");
+
+    /// The background revalidations of the negative cached AdSlots by `result`
+    /// (`promoted`, `not_found`, `error` or `backed_off`)
+    pub static ref NEGATIVE_SLOT_REVALIDATIONS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_negative_slot_revalidations_total",
+        "Number of the negative cached AdSlots revalidated with the Market in the background by whether it returned them",
+        &["result"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The mutating admin requests with an `Idempotency-Key` by `result`
+    /// (`executed`, `replayed`, `in_progress`, `mismatch`, `too_large` or `full`)
+    pub static ref ADMIN_IDEMPOTENCY_KEYS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_admin_idempotency_keys_total",
+        "Number of the mutating admin requests with an Idempotency-Key by whether they were executed or answered with the response of the key",
+        &["result"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The background refreshes of the most requested AdSlots by `result` (`ok`, `error` or `backed_off`)
+    pub static ref SLOT_PREWARMS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_slot_prewarms_total",
+        "Number of the most requested AdSlots refreshed in the background before they expire",
+        &["result"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The Active Campaigns by `network` and `status`, see [`CacheStats`](crate::cache::stats::CacheStats)
+    pub static ref CAMPAIGNS_BY_STATUS: IntGaugeVec = register_int_gauge_vec!(
+        "supermarket_campaigns",
+        "Number of Active Campaigns in the Cache by network and status",
+        &["network", "status"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The Active Campaigns by `network` and deposit `asset` (the ones not in `stats.assets` are under `other`)
+    pub static ref ASSET_CAMPAIGNS: IntGaugeVec = register_int_gauge_vec!(
+        "supermarket_asset_campaigns",
+        "Number of Active Campaigns in the Cache by network and deposit asset",
+        &["network", "asset"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The sum of the deposits of the Active Campaigns by `network` and deposit `asset`
+    pub static ref ASSET_DEPOSITED: GaugeVec = register_gauge_vec!(
+        "supermarket_asset_deposited",
+        "Sum of the deposits of the Active Campaigns by network and deposit asset",
+        &["network", "asset"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The sum of the balances (already paid out) of the Active Campaigns by `network` and deposit `asset`
+    pub static ref ASSET_DISTRIBUTED: GaugeVec = register_gauge_vec!(
+        "supermarket_asset_distributed",
+        "Sum of the balances of the Active Campaigns by network and deposit asset",
+        &["network", "asset"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The sum of the remaining budgets of the Active Campaigns by `network` and deposit `asset`
+    pub static ref ASSET_REMAINING: GaugeVec = register_gauge_vec!(
+        "supermarket_asset_remaining",
+        "Sum of the deposits of the Active Campaigns which haven't been paid out yet by network and deposit asset",
+        &["network", "asset"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The duration of the requests to the Validators by `validator` (see [`SentryApi::validator_label`](crate::SentryApi::validator_label)) and `endpoint`
+    pub static ref VALIDATOR_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "supermarket_validator_request_duration_seconds",
+        "Duration of the requests to the Validators in seconds",
+        &["validator", "endpoint"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the bytes of the proxied responses streamed from the Market
+    pub static ref PROXY_RESPONSE_BYTES: IntCounter = register_int_counter!(
+        "supermarket_proxy_response_bytes_total",
+        "Number of bytes of the proxied responses streamed from the Market"
+    )
+    .expect("Metric should be created and registered");
+
+    /// The bytes streamed so far by the proxied responses which haven't finished yet
+    pub static ref PROXY_RESPONSE_BYTES_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "supermarket_proxy_response_bytes_in_flight",
+        "Number of bytes streamed so far by the proxied responses which are still in flight"
+    )
+    .expect("Metric should be created and registered");
+
+    /// The size of the proxied response bodies, from 1 KiB to 16 MiB
+    pub static ref PROXY_RESPONSE_SIZE: Histogram = register_histogram!(
+        "supermarket_proxy_response_size_bytes",
+        "Size of the proxied response bodies in bytes",
+        exponential_buckets(1024.0, 4.0, 8).expect("Buckets should be valid")
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the event submissions refused before they are proxied by `reason`: `too_large` or `malformed`,
+    /// see [`events`](crate::market::events)
+    pub static ref PROXY_EVENTS_REFUSED: IntCounterVec = register_int_counter_vec!(
+        "supermarket_proxy_events_refused_total",
+        "Number of event submissions refused by the validation before they are proxied by reason",
+        &["reason"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the requests which weren't proxied because their path isn't allowed,
+    /// see [`ProxyPaths`](crate::config::ProxyPaths)
+    pub static ref PROXY_PATHS_REFUSED: IntCounter = register_int_counter!(
+        "supermarket_proxy_paths_refused_total",
+        "Number of requests refused because their path is not allowed to be proxied"
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with every block of a client IP or an AdSlot with too many `4xx` responses by `key`: `ip` or `slot`,
+    /// see [`anomaly`](crate::anomaly)
+    pub static ref ANOMALY_BLOCKS: IntCounterVec = register_int_counter_vec!(
+        "supermarket_anomaly_blocks_total",
+        "Number of the temporary blocks of client IPs and AdSlots with too many 4xx responses by key",
+        &["key"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The currently blocked client IPs & AdSlots by `network` and `key`: `ip` or `slot`, set with every compaction of the counters
+    pub static ref ANOMALY_BLOCKED: IntGaugeVec = register_int_gauge_vec!(
+        "supermarket_anomaly_blocked",
+        "Number of the currently blocked client IPs and AdSlots by network and key",
+        &["network", "key"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with every flush of the units-for-slot responses which served the units of a Campaign
+    /// by `position`: `any` or `top`, see [`serve_stats`](crate::units_for_slot::serve_stats)
+    pub static ref CAMPAIGN_SERVES: IntCounterVec = register_int_counter_vec!(
+        "supermarket_campaign_serves_total",
+        "Number of the Campaigns served in the units-for-slot responses by position",
+        &["position"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// The Campaigns with serve stats by `network`, set with every flush of the serve counters
+    pub static ref SERVE_STATS_CAMPAIGNS: IntGaugeVec = register_int_gauge_vec!(
+        "supermarket_serve_stats_campaigns",
+        "Number of the Campaigns with serve stats within the retention by network",
+        &["network"]
+    )
+    .expect("Metric should be created and registered");
+
+    /// Incremented with the proxied responses rejected or truncated for exceeding the `proxy.max_response_bytes`
+    pub static ref PROXY_RESPONSES_TOO_LARGE: IntCounter = register_int_counter!(
+        "supermarket_proxy_responses_too_large_total",
+        "Number of proxied responses rejected or truncated because they exceeded the max response size"
+    )
+    .expect("Metric should be created and registered");
+}
+
+/// Counts a request in the [`IN_FLIGHT_REQUESTS`] and [`ROUTE_IN_FLIGHT_REQUESTS`] gauges until it's dropped
+#[must_use = "The request is counted only until the guard is dropped"]
+pub struct InFlight {
+    route: IntGauge,
+}
+
+impl InFlight {
+    pub fn start(network: &str, route: &str) -> Self {
+        let route = ROUTE_IN_FLIGHT_REQUESTS.with_label_values(&[network, route]);
+
+        route.inc();
+        IN_FLIGHT_REQUESTS.inc();
+
+        Self { route }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.route.dec();
+        IN_FLIGHT_REQUESTS.dec();
+    }
+}
+
+/// Encodes all the registered metrics in the Prometheus text format.
+/// The `const_labels` are added to every metric.
+pub fn encode(const_labels: &[(&str, &str)]) -> Result<Vec<u8>, prometheus::Error> {
+    let mut families = prometheus::gather();
+
+    for family in families.iter_mut() {
+        for metric in family.mut_metric().iter_mut() {
+            for (name, value) in const_labels {
+                let mut label = LabelPair::new();
+                label.set_name(name.to_string());
+                label.set_value(value.to_string());
+
+                metric.mut_label().push(label);
+            }
+        }
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&families, &mut buffer)?;
+
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn in_flight_requests_are_counted_until_dropped() {
+        let route = || ROUTE_IN_FLIGHT_REQUESTS.with_label_values(&["default", "in_flight_test"]);
+
+        let first = InFlight::start("default", "in_flight_test");
+        let second = InFlight::start("default", "in_flight_test");
+        let _other_network = InFlight::start("staging", "in_flight_test");
+        assert_eq!(2, route().get());
+
+        drop(first);
+        assert_eq!(1, route().get());
+
+        drop(second);
+        assert_eq!(0, route().get());
+    }
+}
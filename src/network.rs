@@ -0,0 +1,191 @@
+//! The AdEx networks served by the Supermarket: the default one and the named ones of the [`Config::networks`],
+//! each with its own Market, Validators and [`Cache`].
+//!
+//! A request is served by the named network if its path is prefixed with `/<name>`, which is stripped
+//! before routing it (e.g. `/staging/units-for-slot/:ipfs` is `/units-for-slot/:ipfs` of `staging`),
+//! or if it's sent to one of the network's `hosts`. The rest are served by the default network.
+use crate::{
+    cache::{self, Cache},
+    market::{MarketApi, Proxy},
+    Config,
+};
+use http::{header::HOST, uri::PathAndQuery, Request, Uri};
+use hyper::Body;
+use slog::Logger;
+use std::{collections::HashMap, sync::Arc};
+
+/// The name of the network of the Supermarket's own `market_url` and `validators`, e.g. in the metrics & the logs
+pub const DEFAULT_NETWORK: &str = "default";
+
+/// Everything the requests of a network are served with.
+/// The `logger` has the `network` of the logged records.
+#[derive(Debug, Clone)]
+pub struct Upstream<C: cache::Client> {
+    pub name: String,
+    pub config: Config,
+    pub cache: Cache<C>,
+    /// The proxy of the routes which aren't handled by the Supermarket, `None` with the `proxy_enabled` off
+    pub proxy: Option<Proxy>,
+    pub market: Arc<MarketApi>,
+    pub logger: Logger,
+}
+
+/// The [`Upstream`]s of all the networks, see [`Networks::select`].
+/// It's cheap to `clone()` it.
+#[derive(Debug, Clone)]
+pub struct Networks<C: cache::Client> {
+    default: Upstream<C>,
+    named: Arc<HashMap<String, Upstream<C>>>,
+    /// The network name by its lowercase host
+    hosts: Arc<HashMap<String, String>>,
+}
+
+impl<C: cache::Client> Networks<C> {
+    /// Only the default network
+    pub fn new(default: Upstream<C>) -> Self {
+        Self {
+            default,
+            named: Default::default(),
+            hosts: Default::default(),
+        }
+    }
+
+    /// Adds the named network, served on its `hosts` as well (see [`Network::hosts`](crate::config::Network::hosts))
+    pub fn with(mut self, upstream: Upstream<C>, hosts: &[String]) -> Self {
+        let named_hosts = Arc::make_mut(&mut self.hosts);
+        for host in hosts {
+            named_hosts.insert(host.to_ascii_lowercase(), upstream.name.clone());
+        }
+        Arc::make_mut(&mut self.named).insert(upstream.name.clone(), upstream);
+
+        self
+    }
+
+    /// The default network, its Config has the shared settings (e.g. the server or the access log)
+    pub fn default_network(&self) -> &Upstream<C> {
+        &self.default
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Upstream<C>> {
+        std::iter::once(&self.default).chain(self.named.values())
+    }
+
+    /// The network of the `request` by its path prefix, stripping it from the URI, or by its `Host`
+    pub fn select(&self, request: &mut Request<Body>) -> &Upstream<C> {
+        if let Some((upstream, stripped)) = self.by_prefix(request.uri()) {
+            *request.uri_mut() = stripped;
+
+            return upstream;
+        }
+
+        self.by_host(request).unwrap_or(&self.default)
+    }
+
+    fn by_prefix(&self, uri: &Uri) -> Option<(&Upstream<C>, Uri)> {
+        let path = uri.path();
+        let name = path.strip_prefix('/')?.split('/').next()?;
+        let upstream = self.named.get(name)?;
+
+        let rest = &path[name.len() + 1..];
+        let stripped = match (rest, uri.query()) {
+            ("", None) => "/".to_string(),
+            ("", Some(query)) => format!("/?{}", query),
+            (rest, None) => rest.to_string(),
+            (rest, Some(query)) => format!("{}?{}", rest, query),
+        };
+
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(
+            stripped
+                .parse::<PathAndQuery>()
+                .expect("A suffix of a valid path should be valid"),
+        );
+
+        Uri::from_parts(parts)
+            .ok()
+            .map(|stripped| (upstream, stripped))
+    }
+
+    fn by_host(&self, request: &Request<Body>) -> Option<&Upstream<C>> {
+        let host = match request.headers().get(HOST) {
+            Some(host) => host.to_str().ok()?,
+            None => request.uri().host()?,
+        };
+        // without the port, incl. the IPv6 ones, e.g. `[::1]:3000`
+        let host = match host.rfind(':') {
+            Some(colon) if !host[colon..].contains(']') => &host[..colon],
+            _ => host,
+        };
+
+        self.hosts
+            .get(&host.to_ascii_lowercase())
+            .and_then(|name| self.named.get(name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cache::MockClient, config::DEVELOPMENT, util::test::discard_logger};
+
+    async fn upstream(name: &str) -> Upstream<MockClient> {
+        let logger = discard_logger();
+        let market_url: crate::market::MarketUrl =
+            "http://localhost:8005/".parse().expect("Valid Market URL");
+
+        Upstream {
+            name: name.to_string(),
+            config: DEVELOPMENT.clone(),
+            cache: Cache::initialize(
+                MockClient::init(vec![Default::default()], vec![], None).await,
+            )
+            .await,
+            proxy: Some(Proxy::new(market_url.clone(), &DEVELOPMENT, logger.clone())),
+            market: Arc::new(
+                MarketApi::new(market_url, &DEVELOPMENT, logger.clone())
+                    .expect("Should build the MarketApi"),
+            ),
+            logger,
+        }
+    }
+
+    #[tokio::test]
+    async fn the_network_is_selected_by_the_path_prefix_or_the_host() {
+        let networks = Networks::new(upstream(DEFAULT_NETWORK).await).with(
+            upstream("staging").await,
+            &["Staging.Supermarket.Local".to_string()],
+        );
+
+        let select = |uri: &str, host: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(host) = host {
+                request = request.header(HOST, host);
+            }
+            let mut request = request.body(Body::empty()).expect("Should build request");
+            let name = networks.select(&mut request).name.clone();
+
+            (name, request.uri().to_string())
+        };
+        let selected = |name: &str, uri: &str| (name.to_string(), uri.to_string());
+
+        assert_eq!(
+            selected("staging", "/units-for-slot/Qm?depositAsset=0x1"),
+            select("/staging/units-for-slot/Qm?depositAsset=0x1", None)
+        );
+        assert_eq!(selected("staging", "/"), select("/staging", None));
+        assert_eq!(selected("staging", "/?q=1"), select("/staging?q=1", None));
+        assert_eq!(
+            selected("staging", "/stats"),
+            select("/stats", Some("staging.supermarket.local:3000"))
+        );
+        assert_eq!(
+            selected(DEFAULT_NETWORK, "/stagingx/tags"),
+            select("/stagingx/tags", None)
+        );
+        assert_eq!(
+            selected(DEFAULT_NETWORK, "/tags"),
+            select("/tags", Some("supermarket.local"))
+        );
+        assert_eq!(2, networks.iter().count());
+    }
+}
@@ -0,0 +1,733 @@
+//! The OpenAPI 3 description of the routes handled by the Supermarket (the proxied Market routes aren't described),
+//! served at `GET /openapi.json`.
+//!
+//! The query parameters of the units-for-slot are generated from the same [`PARAMETERS`] the requests are parsed by,
+//! the schemas of the responses are maintained here and checked against the serialized responses in the tests.
+use crate::{
+    units_for_slot::{
+        query::{ParameterKind, ParameterSpec, Repeated, PARAMETERS, TIMEZONE_OFFSET_BOUNDS},
+        EmptyReason, SlotFetchError, Strategy, MAX_BODY_SIZE, SUPPORTED_VERSIONS,
+    },
+    Error,
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use hyper::{Body, Response};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+
+lazy_static! {
+    /// The [`document`] serialized once
+    static ref DOCUMENT: String = document().to_string();
+}
+
+/// `GET /openapi.json`
+pub fn get_openapi() -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(DOCUMENT.as_str()))?)
+}
+
+pub fn document() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "AdEx Supermarket",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "The routes handled by the Supermarket, the rest of the routes are proxied to the Market.",
+        },
+        "paths": {
+            "/units-for-slot/{slotIpfs}": {
+                "get": units_for_slot_operation(
+                    "The AdUnits (with their Campaigns) which can be shown in the AdSlot",
+                    None,
+                ),
+                "post": units_for_slot_operation(
+                    "Same as the `GET`, with the values derived from the headers & query overridden by the body",
+                    Some(json!({
+                        "required": false,
+                        "content": {
+                            "application/json": { "schema": schema_ref("RequestInput") }
+                        },
+                    })),
+                ),
+            },
+            "/campaigns/{channelId}/balances": {
+                "get": {
+                    "summary": "The cached balances of an Active Campaign",
+                    "parameters": [{
+                        "name": "channelId",
+                        "in": "path",
+                        "required": true,
+                        "description": "In either case, with or without the `0x` prefix",
+                        "schema": { "type": "string" },
+                    }],
+                    "responses": {
+                        "200": json_response("The balances, empty until the Leader has a NewState", schema_ref("CampaignBalances")),
+                        "400": text_response("Malformed ChannelId"),
+                        "404": empty_response("The Campaign is not in the Cache"),
+                    },
+                },
+            },
+            "/stats": {
+                "get": {
+                    "summary": "The aggregates of the Active Campaigns, computed every time they change",
+                    "responses": {
+                        "200": json_response("The stats", schema_ref("CacheStats")),
+                    },
+                },
+            },
+            "/healthz": {
+                "get": {
+                    "summary": "Whether the server is running",
+                    "responses": { "200": empty_response("Always, while the server is running") },
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "summary": "Whether the Cache is ready",
+                    "responses": {
+                        "200": empty_response("Ready"),
+                        "503": empty_response("The Cache is stale or still initializing"),
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "The Prometheus metrics",
+                    "responses": {
+                        "200": {
+                            "description": "The metrics in the Prometheus text format",
+                            "content": { "text/plain": { "schema": { "type": "string" } } },
+                        },
+                    },
+                },
+            },
+            "/version": {
+                "get": {
+                    "summary": "The build information",
+                    "responses": { "200": json_response("The build", schema_ref("BuildInfo")) },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": {
+                        "200": json_response("The OpenAPI document", json!({ "type": "object" })),
+                    },
+                },
+            },
+        },
+        "components": { "schemas": schemas() },
+    })
+}
+
+fn units_for_slot_operation(summary: &str, request_body: Option<Value>) -> Value {
+    let (min_offset, max_offset) = TIMEZONE_OFFSET_BOUNDS;
+    let versions = SUPPORTED_VERSIONS
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut parameters = vec![
+        json!({
+            "name": "slotIpfs",
+            "in": "path",
+            "required": true,
+            "description": "A single alphanumeric segment",
+            "schema": { "type": "string", "pattern": "^[a-zA-Z0-9]+$" },
+        }),
+        json!({
+            "name": "X-Request-Timeout-Ms",
+            "in": "header",
+            "required": false,
+            "description": "The client's deadline in milliseconds, clamped to the `timeouts.client_deadline` of the config",
+            "schema": { "type": "integer", "minimum": 0 },
+        }),
+    ];
+    parameters.extend(PARAMETERS.iter().map(|spec| {
+        let mut parameter = query_parameter(spec);
+        if spec.name == "tz" {
+            parameter["schema"]["minimum"] = min_offset.into();
+            parameter["schema"]["maximum"] = max_offset.into();
+        }
+
+        parameter
+    }));
+
+    let mut operation = json!({
+        "summary": summary,
+        "description": format!(
+            "`Accept: application/json; version=N` selects the version of the response ({}), \
+            echoed in the `X-Response-Version` header. The version `1` is described, \
+            with more than one `?type=` the response is `{{\"types\": {{\"<type>\": <response>}}}}`.",
+            versions
+        ),
+        "parameters": parameters,
+        "responses": {
+            "200": json_response("The matched AdUnits", schema_ref("PagedResponse")),
+            "204": empty_response("A suspected bot with the `block` policy or a mismatching `Referer` with the `strict_referrer_check`"),
+            "400": text_response("A malformed path, query parameter or body"),
+            "404": json_response("The AdSlot was not found in the Market", schema_ref("SlotError")),
+            "406": text_response("An unsupported version in the `Accept` header"),
+            "410": empty_response("The AdSlot is archived"),
+            "429": empty_response("The client IP or the AdSlot is temporarily blocked, see the `Retry-After`"),
+            "502": json_response("The Market is unavailable or its AdSlot is invalid", schema_ref("SlotError")),
+            "504": json_response(
+                "The `X-Request-Timeout-Ms` deadline was exceeded",
+                json!({
+                    "type": "object",
+                    "required": ["reason"],
+                    "properties": { "reason": { "type": "string", "enum": ["deadline_exceeded"] } },
+                }),
+            ),
+        },
+    });
+    if let Some(request_body) = request_body {
+        operation["requestBody"] = request_body;
+        operation["responses"]["413"] =
+            text_response(&format!("The body is larger than {} bytes", MAX_BODY_SIZE));
+    }
+
+    operation
+}
+
+fn query_parameter(spec: &ParameterSpec) -> Value {
+    let value = match spec.kind {
+        ParameterKind::Flag => json!({ "type": "boolean" }),
+        ParameterKind::Integer => json!({ "type": "integer" }),
+        ParameterKind::Number => json!({ "type": "number" }),
+        ParameterKind::String => json!({ "type": "string" }),
+    };
+    let (schema, explode) = match spec.repeated {
+        Repeated::Accumulate => (json!({ "type": "array", "items": value }), true),
+        Repeated::Last => (value, false),
+    };
+
+    let mut description = spec.description.to_string();
+    if !spec.aliases.is_empty() {
+        description.push_str(&format!(". Also as `{}`", spec.aliases.join("`, `")));
+    }
+
+    let mut parameter = json!({
+        "name": spec.name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": schema,
+        "explode": explode,
+    });
+    if spec.kind == ParameterKind::Flag {
+        parameter["allowEmptyValue"] = true.into();
+    }
+
+    parameter
+}
+
+fn schemas() -> Value {
+    let reasons = EmptyReason::ALL
+        .iter()
+        .map(|reason| reason.as_str())
+        .collect::<Vec<_>>();
+    let strategies = Strategy::ALL
+        .iter()
+        .map(|strategy| strategy.as_str())
+        .collect::<Vec<_>>();
+    let slot_errors = SlotFetchError::ALL
+        .iter()
+        .map(|error| error.message())
+        .collect::<Vec<_>>();
+
+    json!({
+        "PagedResponse": {
+            "type": "object",
+            "description": "The version 1 of the units-for-slot response",
+            "required": ["totalMatched", "skip", "limit", "dayTime", "personalized", "strategy", "units"],
+            "properties": {
+                "targetingInputBase": { "type": "object", "description": "The targeting input of the request" },
+                "acceptedReferrers": { "type": "array", "items": { "type": "string" } },
+                "fallbackUnit": { "type": "object", "nullable": true },
+                "campaigns": { "type": "array", "items": { "type": "object" } },
+                "totalMatched": { "type": "integer", "description": "All of the matched units, before the `skip` & `limit`" },
+                "skip": { "type": "integer" },
+                "limit": { "type": "integer", "nullable": true },
+                "dayTime": schema_ref("DayTime"),
+                "personalized": { "type": "boolean", "description": "Whether the personal inputs were used" },
+                "strategy": schema_ref("Strategy"),
+                "suspectBot": { "type": "boolean", "description": "Only with the `flag` bots policy" },
+                "referrerMismatch": { "type": "boolean", "description": "The `Referer` doesn't match the AdSlot's website" },
+                "archivedUnits": { "type": "integer", "description": "Only with `?debug=true`" },
+                "generation": { "type": "integer", "description": "The generation of the Campaigns the units were matched from, only with `?debug=true`" },
+                "reason": schema_ref("EmptyReason"),
+                "truncated": { "type": "boolean", "description": "The AdUnits of the AdSlot were truncated to the `limits.max_units_per_slot`" },
+                "units": { "type": "array", "items": schema_ref("MatchedUnit") },
+            },
+        },
+        "MatchedUnit": {
+            "type": "object",
+            "description": "The fields of the AdUnit with its `price` and the details of the Campaign it was matched from",
+            "required": ["campaign", "alsoAvailableIn"],
+            "properties": {
+                "campaign": schema_ref("CampaignDetails"),
+                "alsoAvailableIn": {
+                    "type": "array",
+                    "description": "The ChannelIds of the other Campaigns which matched the same unit, highest price first",
+                    "items": { "type": "string" },
+                },
+                "score": { "type": "number", "description": "Only with `?debug=true`" },
+                "lastRefreshed": { "type": "string", "format": "date-time", "description": "Only with `?debug=true`" },
+            },
+        },
+        "CampaignDetails": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": [
+                "channelId", "creator", "depositAsset", "pricingBounds", "pricingBoundsByEvent",
+                "validUntil", "leaderUrl", "followerUrl",
+            ],
+            "properties": {
+                "channelId": { "type": "string" },
+                "creator": { "type": "string" },
+                "depositAsset": { "type": "string" },
+                "pricingBounds": schema_ref("PricingBounds"),
+                "pricingBoundsByEvent": {
+                    "type": "object",
+                    "description": "By event type, `IMPRESSION` and `CLICK` if it's set",
+                    "additionalProperties": schema_ref("PricingBounds"),
+                },
+                "validUntil": { "type": "string", "format": "date-time" },
+                "leaderUrl": { "type": "string" },
+                "followerUrl": { "type": "string" },
+            },
+        },
+        "PricingBounds": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["min", "max"],
+            "properties": {
+                "min": { "type": "string" },
+                "max": { "type": "string" },
+            },
+        },
+        "DayTime": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["hour", "dayOfWeek", "timezoneOffset"],
+            "properties": {
+                "hour": { "type": "integer", "minimum": 0, "maximum": 23 },
+                "dayOfWeek": { "type": "integer", "minimum": 0, "maximum": 6, "description": "`0` is Sunday" },
+                "timezoneOffset": { "type": "integer" },
+            },
+        },
+        "EmptyReason": {
+            "type": "string",
+            "description": "Why there are no matched units",
+            "enum": reasons,
+        },
+        "Strategy": {
+            "type": "string",
+            "description": "The selection strategy which ordered the matched units",
+            "enum": strategies,
+        },
+        "SlotError": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["error"],
+            "properties": { "error": { "type": "string", "enum": slot_errors } },
+        },
+        "RequestInput": {
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {
+                "country": { "type": "string", "nullable": true },
+                "userAgentOs": { "type": "string", "nullable": true },
+                "userAgentBrowserFamily": { "type": "string", "nullable": true },
+                "publisherId": { "type": "string", "nullable": true },
+                "segments": { "type": "array", "items": { "type": "string" } },
+                "acceptedAssets": { "type": "array", "items": { "type": "string" } },
+                "types": { "type": "array", "items": { "type": "string" } },
+            },
+        },
+        "CampaignBalances": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["channelId", "status", "balances", "validators"],
+            "properties": {
+                "channelId": { "type": "string" },
+                "status": { "description": "The status of the Campaign" },
+                "balances": { "type": "object", "additionalProperties": { "type": "string" } },
+                "stateRoot": { "type": "string" },
+                "received": { "type": "string", "format": "date-time" },
+                "validators": { "type": "array", "items": schema_ref("CampaignValidator") },
+            },
+        },
+        "CampaignValidator": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["id", "url", "fee"],
+            "properties": {
+                "id": { "type": "string" },
+                "url": { "type": "string" },
+                "fee": { "type": "string" },
+            },
+        },
+        "CacheStats": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": [
+                "computedAt", "active", "finalized", "skippedInvalid", "restricted", "blocked", "validators", "byStatus", "byAsset",
+            ],
+            "properties": {
+                "computedAt": { "type": "string", "format": "date-time", "nullable": true },
+                "active": { "type": "integer" },
+                "finalized": { "type": "integer" },
+                "skippedInvalid": { "type": "integer" },
+                "restricted": { "type": "integer" },
+                "blocked": schema_ref("BlockedStats"),
+                "validators": schema_ref("ValidatorChanges"),
+                "byStatus": { "type": "object", "additionalProperties": { "type": "integer" } },
+                "byAsset": { "type": "object", "additionalProperties": schema_ref("AssetStats") },
+            },
+        },
+        "AssetStats": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["campaigns", "deposited", "distributed", "remaining"],
+            "properties": {
+                "campaigns": { "type": "integer" },
+                "deposited": { "type": "string" },
+                "distributed": { "type": "string" },
+                "remaining": { "type": "string" },
+            },
+        },
+        "BlockedStats": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["ips", "slots"],
+            "properties": {
+                "ips": { "type": "integer" },
+                "slots": { "type": "integer" },
+            },
+        },
+        "ValidatorChanges": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["changedAt", "added", "removed"],
+            "properties": {
+                "changedAt": { "type": "string", "format": "date-time", "nullable": true },
+                "added": { "type": "array", "items": { "type": "string" } },
+                "removed": { "type": "array", "items": { "type": "string" } },
+            },
+        },
+        "BuildInfo": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["version", "gitCommit", "buildTimestamp", "marketHost"],
+            "properties": {
+                "version": { "type": "string" },
+                "gitCommit": { "type": "string" },
+                "buildTimestamp": { "type": "string" },
+                "marketHost": { "type": "string" },
+            },
+        },
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+fn text_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" } } },
+    })
+}
+
+fn empty_response(description: &str) -> Value {
+    json!({ "description": description })
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{
+        build_info::BuildInfo,
+        cache::{Cache, MockClient},
+        units_for_slot::{CampaignDetails, DayTime, RequestInput},
+    };
+    use chrono::Utc;
+    use primitives::util::tests::prep_db::DUMMY_CHANNEL;
+    use serde::Serialize;
+    use std::collections::HashSet;
+
+    /// Checks the `value` against the schema of the `document`'s components by its `name`,
+    /// for the subset of the JSON Schema used in the [`document`]
+    pub fn conforms(document: &Value, name: &str, value: &Value) -> Result<(), String> {
+        conforms_to(document, &schema_ref(name), value, name)
+    }
+
+    fn conforms_to(
+        document: &Value,
+        schema: &Value,
+        value: &Value,
+        at: &str,
+    ) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let pointer = reference.trim_start_matches('#');
+            let resolved = document
+                .pointer(pointer)
+                .ok_or_else(|| format!("{}: unresolved `{}`", at, reference))?;
+
+            return conforms_to(document, resolved, value, at);
+        }
+
+        if value.is_null() {
+            return match schema["nullable"].as_bool() {
+                Some(true) => Ok(()),
+                _ if schema.get("type").is_none() => Ok(()),
+                _ => Err(format!("{}: null isn't nullable", at)),
+            };
+        }
+
+        let matches_type = match schema["type"].as_str() {
+            Some("object") => value.is_object(),
+            Some("array") => value.is_array(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            Some(other) => return Err(format!("{}: unknown type `{}`", at, other)),
+            None => true,
+        };
+        if !matches_type {
+            return Err(format!(
+                "{}: `{}` isn't of type {}",
+                at, value, schema["type"]
+            ));
+        }
+
+        if let Some(allowed) = schema["enum"].as_array() {
+            if !allowed.contains(value) {
+                return Err(format!("{}: `{}` isn't one of {:?}", at, value, allowed));
+            }
+        }
+
+        if let Some(items) = value.as_array() {
+            for (index, item) in items.iter().enumerate() {
+                conforms_to(
+                    document,
+                    &schema["items"],
+                    item,
+                    &format!("{}[{}]", at, index),
+                )?;
+            }
+        }
+
+        if let Some(object) = value.as_object() {
+            let properties = schema["properties"]
+                .as_object()
+                .cloned()
+                .unwrap_or_default();
+            for required in schema["required"].as_array().into_iter().flatten() {
+                let required = required.as_str().unwrap_or_default();
+                if !object.contains_key(required) {
+                    return Err(format!("{}: missing the required `{}`", at, required));
+                }
+            }
+
+            for (key, field) in object {
+                let at = format!("{}.{}", at, key);
+                match (properties.get(key), &schema["additionalProperties"]) {
+                    (Some(property), _) => conforms_to(document, property, field, &at)?,
+                    (None, Value::Bool(false)) => return Err(format!("{}: not described", at)),
+                    (None, Value::Object(additional)) => {
+                        conforms_to(document, &Value::Object(additional.clone()), field, &at)?
+                    }
+                    (None, _) => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn serialized(value: impl Serialize) -> Value {
+        serde_json::to_value(value).expect("Should serialize")
+    }
+
+    #[test]
+    fn the_document_is_a_valid_openapi_document() {
+        let document = document();
+
+        assert!(document["openapi"]
+            .as_str()
+            .map_or(false, |version| version.starts_with("3.0.")));
+        assert!(document["info"]["title"].is_string());
+        assert!(document["info"]["version"].is_string());
+
+        let methods = [
+            "get", "put", "post", "delete", "options", "head", "patch", "trace",
+        ];
+        let paths = document["paths"].as_object().expect("Should have paths");
+        assert!(!paths.is_empty());
+
+        for (path, item) in paths {
+            assert!(path.starts_with('/'), "{}", path);
+            let templated = path
+                .split('/')
+                .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+                .collect::<HashSet<_>>();
+
+            for (method, operation) in item.as_object().expect("Should be a Path Item") {
+                assert!(methods.contains(&method.as_str()), "{} {}", method, path);
+
+                let responses = operation["responses"]
+                    .as_object()
+                    .expect("Should have responses");
+                assert!(!responses.is_empty(), "{} {}", method, path);
+                for (status, response) in responses {
+                    assert!(
+                        status.parse::<StatusCode>().is_ok(),
+                        "{} {} {}",
+                        method,
+                        path,
+                        status
+                    );
+                    assert!(
+                        response["description"].is_string(),
+                        "{} {} {}",
+                        method,
+                        path,
+                        status
+                    );
+                }
+
+                let parameters = operation["parameters"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default();
+                let mut unique = HashSet::new();
+                for parameter in &parameters {
+                    let (name, location) = (
+                        parameter["name"].as_str().expect("Should have a name"),
+                        parameter["in"].as_str().expect("Should have a location"),
+                    );
+                    assert!(
+                        unique.insert((name, location)),
+                        "{} {} {}",
+                        method,
+                        path,
+                        name
+                    );
+                    assert!(["query", "header", "path", "cookie"].contains(&location));
+                    assert!(parameter["schema"].is_object(), "{}", name);
+                    if location == "path" {
+                        assert_eq!(Some(true), parameter["required"].as_bool(), "{}", name);
+                        assert!(templated.contains(name), "{} {} {}", method, path, name);
+                    }
+                }
+                let declared = parameters
+                    .iter()
+                    .filter(|parameter| parameter["in"] == "path")
+                    .filter_map(|parameter| parameter["name"].as_str())
+                    .collect::<HashSet<_>>();
+                assert_eq!(templated, declared, "{} {}", method, path);
+            }
+        }
+
+        // every reference is resolved
+        let mut references = vec![];
+        collect_references(&document, &mut references);
+        assert!(!references.is_empty());
+        for reference in references {
+            assert!(
+                document
+                    .pointer(reference.trim_start_matches('#'))
+                    .is_some(),
+                "{}",
+                reference
+            );
+        }
+    }
+
+    fn collect_references(value: &Value, references: &mut Vec<String>) {
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::String(reference)) = object.get("$ref") {
+                    references.push(reference.clone());
+                }
+                object
+                    .values()
+                    .for_each(|value| collect_references(value, references));
+            }
+            Value::Array(array) => array
+                .iter()
+                .for_each(|value| collect_references(value, references)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_query_parameter_is_described() {
+        let document = document();
+        let parameters = document["paths"]["/units-for-slot/{slotIpfs}"]["get"]["parameters"]
+            .as_array()
+            .expect("Should have parameters")
+            .iter()
+            .filter(|parameter| parameter["in"] == "query")
+            .map(|parameter| parameter["name"].as_str().unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        let expected = PARAMETERS.iter().map(|spec| spec.name).collect::<Vec<_>>();
+        assert_eq!(expected, parameters);
+    }
+
+    #[tokio::test]
+    async fn the_responses_conform_to_their_schemas() {
+        let document = document();
+        let cache = Cache::initialize(MockClient::init(vec![], vec![], None).await).await;
+
+        conforms(&document, "CacheStats", &serialized(cache.stats().await))
+            .expect("The stats should conform");
+        conforms(
+            &document,
+            "BuildInfo",
+            &serialized(BuildInfo::new(
+                &"http://localhost:3012/market/".parse().expect("Valid URL"),
+            )),
+        )
+        .expect("The build info should conform");
+        conforms(
+            &document,
+            "CampaignDetails",
+            &serialized(CampaignDetails::from(&*DUMMY_CHANNEL)),
+        )
+        .expect("The Campaign details should conform");
+        conforms(
+            &document,
+            "DayTime",
+            &serialized(DayTime::new(Utc::now(), 120).expect("Valid offset")),
+        )
+        .expect("The day time should conform");
+        conforms(
+            &document,
+            "RequestInput",
+            &serialized(RequestInput::default()),
+        )
+        .expect("The request input should conform");
+
+        // the undescribed fields are caught
+        let mut stats = serialized(cache.stats().await);
+        stats["undescribed"] = json!(true);
+        assert!(conforms(&document, "CacheStats", &stats).is_err());
+    }
+}
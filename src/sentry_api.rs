@@ -1,20 +1,94 @@
-use chrono::Utc;
-use futures::future::{try_join_all, TryFutureExt};
+use chrono::{DateTime, Utc};
+use futures::{
+    future,
+    stream::{self, Stream, StreamExt, TryStreamExt},
+};
 use primitives::{
-    sentry::{
-        channel_list::ChannelListQuery, ChannelListResponse, LastApprovedResponse,
-        ValidatorMessage, ValidatorMessageResponse,
-    },
+    sentry::{channel_list::ChannelListQuery, LastApprovedResponse, ValidatorMessage},
     util::ApiUrl,
     Channel, ChannelId, ValidatorDesc,
 };
-use reqwest::{Client, Response};
-use std::time::Duration;
+use reqwest::{Client, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use slog::{info, o, warn, Discard, Logger};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 use thiserror::Error;
+use tokio::sync::RwLock;
+
+use crate::{
+    config::{ChannelList, LastApprovedBatch},
+    ids::Canonical,
+    metrics::{
+        VALIDATOR_MALFORMED_ENTRIES, VALIDATOR_REQUESTS, VALIDATOR_REQUEST_DURATION,
+        VALIDATOR_REQUEST_ERRORS,
+    },
+    util::{Clock, SystemClock},
+    Timeouts,
+};
+
+/// The Validator endpoints, used as the `endpoint` label of the Validator metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    ChannelList,
+    LastApproved,
+    LastApprovedBatch,
+    ValidatorMessages,
+}
+
+impl Endpoint {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Endpoint::ChannelList => "channel_list",
+            Endpoint::LastApproved => "last_approved",
+            Endpoint::LastApprovedBatch => "last_approved_batch",
+            Endpoint::ValidatorMessages => "validator_messages",
+        }
+    }
+}
+
+/// The `validator` label of the Validator metrics for the Validators which aren't configured,
+/// e.g. the ones from the spec of a Campaign, see [`SentryApi::validator_label`]
+pub const OTHER_VALIDATOR_LABEL: &str = "other";
+
+/// The host (and port) of the `url`, it identifies the Validator (e.g. in the `/validators/:host` admin route)
+pub fn validator_host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct SentryApi {
     client: Client,
+    /// The timeout of the requests to Validators without an override
+    request_timeout: Duration,
+    /// Per-validator overrides of the `request_timeout`
+    validator_timeouts: HashMap<ApiUrl, Duration>,
+    /// The timeout of the client, every request is capped by it
+    global_timeout: Duration,
+    /// The concurrency & limit of the `/channel/list` pages
+    channel_list: ChannelList,
+    /// For the `validUntil` filter of the `/channel/list`
+    clock: Arc<dyn Clock>,
+    /// The number of skipped malformed entries per Validator host since the start
+    malformed_entries: Arc<RwLock<HashMap<String, u64>>>,
+    /// The hosts of the configured Validators, the only `validator` label values of the metrics besides [`OTHER_VALIDATOR_LABEL`]
+    validators: Arc<RwLock<HashSet<String>>>,
+    /// The size of the batched `last-approved` requests, see [`SentryApi::prefetch_last_approved`]
+    last_approved: LastApprovedBatch,
+    /// The Validator hosts which don't support the batched `last-approved`
+    batch_unsupported: Arc<RwLock<HashSet<String>>>,
+    /// The batched `last-approved` per Validator URL & Channel, until they are requested
+    prefetched: Arc<RwLock<HashMap<(String, ChannelId), LastApprovedResponse>>>,
+    logger: Logger,
 }
 #[derive(Debug, Error)]
 pub enum Error {
@@ -22,6 +96,30 @@ pub enum Error {
     ParsingUrl(#[from] primitives::util::api::Error),
     #[error("Request to Sentry: {0}")]
     Reqwest(#[from] reqwest::Error),
+    #[error("Validator Url: {0}")]
+    Url(#[from] url::ParseError),
+}
+
+/// A `/channel/list` page with the Channels deserialized one by one, see [`SentryApi::parse_entries`]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChannelListPage {
+    channels: Vec<Value>,
+    total_pages: u64,
+}
+
+/// The batched `last-approved` per ChannelId, deserialized one by one
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LastApprovedBatchResponse {
+    last_approved: HashMap<String, Value>,
+}
+
+/// The validator messages deserialized one by one, see [`SentryApi::parse_entries`]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidatorMessagesPage {
+    validator_messages: Vec<Value>,
 }
 
 /// SentryApi talks directly to Sentry
@@ -29,37 +127,189 @@ impl SentryApi {
     pub fn new(request_timeout: Duration) -> Result<Self, Error> {
         let client = Client::builder().timeout(request_timeout).build()?;
 
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            request_timeout,
+            validator_timeouts: HashMap::new(),
+            global_timeout: request_timeout,
+            channel_list: ChannelList::default(),
+            clock: Arc::new(SystemClock),
+            malformed_entries: Default::default(),
+            validators: Default::default(),
+            last_approved: LastApprovedBatch::default(),
+            batch_unsupported: Default::default(),
+            prefetched: Default::default(),
+            logger: Logger::root(Discard, o!()),
+        })
     }
 
-    pub async fn get_validator_channels(&self, validator: &ApiUrl) -> Result<Vec<Channel>, Error> {
-        let first_page = self.fetch_page(&validator, 0).await?;
+    /// Uses the [`Timeouts::global_request`] for the client and the [`Timeouts::validator_request`]
+    /// for each request, unless the Validator has an override in [`Timeouts::validators`].
+    /// Neither of them can be longer than the [`Timeouts::global_request`], see [`SentryApi::timeout_for`].
+    pub fn with_timeouts(timeouts: &Timeouts) -> Result<Self, Error> {
+        let client = Client::builder().timeout(timeouts.global_request).build()?;
 
-        if first_page.total_pages < 2 {
-            Ok(first_page.channels)
-        } else {
-            let all: Vec<ChannelListResponse> =
-                try_join_all((1..first_page.total_pages).map(|i| self.fetch_page(&validator, i)))
-                    .await?;
+        Ok(Self {
+            client,
+            request_timeout: timeouts.validator_request,
+            validator_timeouts: timeouts.validators.clone(),
+            global_timeout: timeouts.global_request,
+            channel_list: ChannelList::default(),
+            clock: Arc::new(SystemClock),
+            malformed_entries: Default::default(),
+            validators: Default::default(),
+            last_approved: LastApprovedBatch::default(),
+            batch_unsupported: Default::default(),
+            prefetched: Default::default(),
+            logger: Logger::root(Discard, o!()),
+        })
+    }
+
+    /// Replaces the default [`ChannelList`] pagination settings
+    pub fn with_channel_list(mut self, channel_list: ChannelList) -> Self {
+        self.channel_list = channel_list;
+        self
+    }
 
-            let result_all: Vec<Channel> = std::iter::once(first_page)
-                .chain(all.into_iter())
-                .flat_map(|ch| ch.channels.into_iter())
-                .collect();
-            Ok(result_all)
+    /// Replaces the default [`LastApprovedBatch`] size
+    pub fn with_last_approved(mut self, last_approved: LastApprovedBatch) -> Self {
+        self.last_approved = last_approved;
+        self
+    }
+
+    /// The configured Validators, the requests to the rest of them are labeled as [`OTHER_VALIDATOR_LABEL`] in the metrics
+    pub fn with_validators(mut self, validators: &HashSet<ApiUrl>) -> Self {
+        self.validators = Arc::new(RwLock::new(validator_hosts(validators)));
+        self
+    }
+
+    /// Replaces the configured Validators, e.g. when they're changed at runtime, see [`SentryApi::with_validators`]
+    pub async fn set_validators(&self, validators: &HashSet<ApiUrl>) {
+        *self.validators.write().await = validator_hosts(validators);
+    }
+
+    /// The `validator` label of the metrics: the `host` of a configured Validator or [`OTHER_VALIDATOR_LABEL`],
+    /// so the label values are bounded by the configured Validators and not by the spec of the Campaigns.
+    pub async fn validator_label(&self, host: &str) -> String {
+        if self.validators.read().await.contains(host) {
+            host.to_string()
+        } else {
+            OTHER_VALIDATOR_LABEL.to_string()
         }
     }
 
+    /// Replaces the system clock used for filtering the expired Channels
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replaces the default logger which discards the logs
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = logger;
+        self
+    }
+
+    /// The number of skipped malformed entries per Validator host since the start
+    pub async fn malformed_entries(&self) -> HashMap<String, u64> {
+        self.malformed_entries.read().await.clone()
+    }
+
+    /// The timeout for a request to the `validator`, either it's override or the default one,
+    /// within the timeout of the client (a longer per-request timeout would replace it)
+    pub fn timeout_for(&self, validator: &ApiUrl) -> Duration {
+        self.validator_timeouts
+            .get(validator)
+            .copied()
+            .unwrap_or(self.request_timeout)
+            .min(self.global_timeout)
+    }
+
+    /// Fetches all the pages of the Validator's `/channel/list`, see [`SentryApi::validator_channel_pages`].
+    /// A Channel moving between the pages while they are fetched is returned only once.
+    pub async fn get_validator_channels(&self, validator: &ApiUrl) -> Result<Vec<Channel>, Error> {
+        let mut seen = HashSet::new();
+
+        self.validator_channel_pages(validator)
+            .map_ok(|channels| {
+                channels
+                    .into_iter()
+                    .filter(|channel| seen.insert(channel.id))
+                    .collect::<Vec<_>>()
+            })
+            .try_concat()
+            .await
+    }
+
+    /// The Channels of each page of the Validator's `/channel/list` as soon as it arrives,
+    /// the pages after the first one are requested by its `totalPages`, [`ChannelList::page_concurrency`] at a time.
+    /// It ends with the first failed page.
+    ///
+    /// At most [`ChannelList::max_pages`] pages are fetched, if there are more it's logged.
+    ///
+    /// Only the Channels valid at the time of the clock are requested, the expired ones are dropped
+    /// in case the Validator doesn't support the `validUntil` filter.
+    ///
+    /// The malformed Channels are skipped, while the rest of the page is still used.
+    pub fn validator_channel_pages<'a>(
+        &'a self,
+        validator: &'a ApiUrl,
+    ) -> impl Stream<Item = Result<Vec<Channel>, Error>> + 'a {
+        let now = self.clock.now_utc();
+
+        stream::once(self.fetch_page(validator, 0, now))
+            .map_ok(move |first_page| {
+                let total_pages = first_page.total_pages;
+                let max_pages = self.channel_list.max_pages;
+                if total_pages > max_pages {
+                    warn!(
+                        &self.logger,
+                        "Validator has more Channel pages than the limit, the rest are skipped";
+                        "validator" => %validator,
+                        "total pages" => total_pages,
+                        "max pages" => max_pages,
+                    );
+                }
+
+                let rest = stream::iter(1..total_pages.min(max_pages))
+                    .map(move |page| self.fetch_page(validator, page, now))
+                    .buffered(self.channel_list.page_concurrency);
+
+                stream::once(future::ready(Ok(first_page))).chain(rest)
+            })
+            .try_flatten()
+            .and_then(move |page| self.parse_channels(validator, page, now))
+    }
+
+    /// The well-formed Channels of the `page` which are still valid at `now`
+    async fn parse_channels(
+        &self,
+        validator: &ApiUrl,
+        page: ChannelListPage,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Channel>, Error> {
+        let host = validator_host(&validator.to_url());
+        let channels = self
+            .parse_entries::<Channel>(&host, Endpoint::ChannelList, page.channels)
+            .await
+            .into_iter()
+            .filter(|channel| channel.valid_until >= now)
+            .collect();
+
+        Ok(channels)
+    }
+
     async fn fetch_page(
         &self,
         validator: &ApiUrl,
         page: u64,
-    ) -> Result<ChannelListResponse, Error> {
+        valid_until_ge: DateTime<Utc>,
+    ) -> Result<ChannelListPage, Error> {
         let query = ChannelListQuery {
             page,
-            valid_until_ge: Utc::now(),
-            creator: None,
-            validator: None,
+            valid_until_ge,
+            creator: self.channel_list.creator.clone(),
+            validator: self.channel_list.validator,
         };
 
         let url = validator
@@ -69,12 +319,8 @@ impl SentryApi {
             ))
             .expect("Url should be valid");
 
-        Ok(self
-            .client
-            .get(url)
-            .send()
-            .and_then(|res: Response| res.json::<ChannelListResponse>())
-            .await?)
+        self.get(Endpoint::ChannelList, url, self.timeout_for(validator))
+            .await
     }
 
     pub async fn get_last_approved(
@@ -82,6 +328,15 @@ impl SentryApi {
         channel_id: ChannelId,
         validator: &ValidatorDesc,
     ) -> Result<LastApprovedResponse, Error> {
+        let prefetched = self
+            .prefetched
+            .write()
+            .await
+            .remove(&(validator.url.clone(), channel_id));
+        if let Some(last_approved) = prefetched {
+            return Ok(last_approved);
+        }
+
         // if the validator API URL is wrong, return an error instead of `panic!`ing
         let api_url = ApiUrl::parse(&validator.url)?;
 
@@ -93,7 +348,143 @@ impl SentryApi {
             ))
             .expect("Url should be valid");
 
-        Ok(self.client.get(url).send().await?.json().await?)
+        self.get(Endpoint::LastApproved, url, self.timeout_for(&api_url))
+            .await
+    }
+
+    /// Requests the `last-approved` of the Channels from the Validator [`LastApprovedBatch::batch_size`] at a time,
+    /// they are kept until [`SentryApi::get_last_approved`] is called for them (instead of making a request).
+    ///
+    /// If the Validator responds with `404 Not Found` or `400 Bad Request` its host is remembered
+    /// and it's no longer batched. The Channels which failed or are missing from the response
+    /// are left to be requested one by one. Returns the number of the prefetched Channels.
+    pub async fn prefetch_last_approved(
+        &self,
+        validator: &ValidatorDesc,
+        channel_ids: &[ChannelId],
+    ) -> usize {
+        let batch_size = self.last_approved.batch_size;
+        let api_url = match ApiUrl::parse(&validator.url) {
+            Ok(api_url) => api_url,
+            Err(_) => return 0,
+        };
+        let host = validator_host(&api_url.to_url());
+        if batch_size == 0
+            || channel_ids.len() < 2
+            || self.batch_unsupported.read().await.contains(&host)
+        {
+            return 0;
+        }
+
+        let mut prefetched = 0;
+        for batch in channel_ids.chunks(batch_size) {
+            match self.get_last_approved_batch(&api_url, batch).await {
+                Ok(Some(responses)) => {
+                    prefetched += responses.len();
+                    self.prefetched
+                        .write()
+                        .await
+                        .extend(responses.into_iter().map(|(channel_id, response)| {
+                            ((validator.url.clone(), channel_id), response)
+                        }));
+                }
+                Ok(None) => {
+                    info!(
+                        &self.logger,
+                        "Validator doesn't support the batched last-approved, its Channels are requested one by one";
+                        "validator" => &host,
+                    );
+                    self.batch_unsupported.write().await.insert(host);
+
+                    break;
+                }
+                Err(err) => warn!(
+                    &self.logger,
+                    "Batched last-approved failed, the Channels are requested one by one";
+                    "validator" => &host,
+                    "channels" => batch.len(),
+                    "error" => ?err,
+                ),
+            }
+        }
+
+        prefetched
+    }
+
+    /// Drops the prefetched `last-approved` of the Channels which weren't requested,
+    /// see [`SentryApi::prefetch_last_approved`]
+    pub async fn discard_prefetched(&self, channel_ids: &HashSet<ChannelId>) {
+        self.prefetched
+            .write()
+            .await
+            .retain(|(_, channel_id), _| !channel_ids.contains(channel_id));
+    }
+
+    /// `None` if the Validator doesn't support the batched `last-approved`
+    async fn get_last_approved_batch(
+        &self,
+        api_url: &ApiUrl,
+        channel_ids: &[ChannelId],
+    ) -> Result<Option<HashMap<ChannelId, LastApprovedResponse>>, Error> {
+        let channels = channel_ids
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let url = api_url
+            .join(&format!(
+                "channel/last-approved?withHeartbeat=true&channels={}",
+                channels
+            ))
+            .expect("Url should be valid");
+
+        let label = self.validator_label(&validator_host(&url)).await;
+        let labels = [label.as_str(), Endpoint::LastApprovedBatch.as_str()];
+
+        let timer = VALIDATOR_REQUEST_DURATION
+            .with_label_values(&labels)
+            .start_timer();
+        let result = async {
+            let response = self
+                .client
+                .get(url)
+                .timeout(self.timeout_for(api_url))
+                .send()
+                .await?;
+
+            match response.status() {
+                StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST => Ok(None),
+                _ => response
+                    .error_for_status()?
+                    .json::<LastApprovedBatchResponse>()
+                    .await
+                    .map(Some),
+            }
+        }
+        .await
+        .map_err(Error::from);
+        timer.observe_duration();
+
+        VALIDATOR_REQUESTS.with_label_values(&labels).inc();
+        if result.is_err() {
+            VALIDATOR_REQUEST_ERRORS.with_label_values(&labels).inc();
+        }
+
+        let responses = result?.map(|response| {
+            response
+                .last_approved
+                .into_iter()
+                .filter_map(|(channel_id, last_approved)| {
+                    let Canonical(channel_id) = channel_id.parse::<Canonical<ChannelId>>().ok()?;
+                    let last_approved = serde_json::from_value(last_approved).ok()?;
+
+                    Some((channel_id, last_approved))
+                })
+                .filter(|(channel_id, _)| channel_ids.contains(channel_id))
+                .collect()
+        });
+
+        Ok(responses)
     }
 
     pub async fn get_latest_new_state(
@@ -101,16 +492,694 @@ impl SentryApi {
         channel_id: ChannelId,
         validator: &ValidatorDesc,
     ) -> Result<Option<ValidatorMessage>, Error> {
-        let url = &format!(
+        let url = Url::parse(&format!(
             "{}/channel/{}/validator-messages/{}/NewState?limit=1",
             validator.url.trim_end_matches('/'),
             channel_id,
             validator.id
-        );
+        ))?;
+        let timeout = ApiUrl::parse(&validator.url)
+            .map(|api_url| self.timeout_for(&api_url))
+            .unwrap_or(self.request_timeout);
 
-        let response: ValidatorMessageResponse = self.client.get(url).send().await?.json().await?;
-        let message = response.validator_messages.into_iter().next();
+        let host = validator_host(&url);
+        let response: ValidatorMessagesPage =
+            self.get(Endpoint::ValidatorMessages, url, timeout).await?;
+        let message = self
+            .parse_entries(
+                &host,
+                Endpoint::ValidatorMessages,
+                response.validator_messages,
+            )
+            .await
+            .into_iter()
+            .next();
 
         Ok(message)
     }
+
+    /// `HEAD` request to the Validator URL, keeping a pooled connection alive (or opening a new one),
+    /// the status of the response doesn't matter.
+    pub async fn warm_up(&self, validator: &ApiUrl) -> Result<(), Error> {
+        self.client
+            .head(validator.to_url())
+            .timeout(self.timeout_for(validator))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deserializes each of the entries on its own, so a malformed entry doesn't fail the whole response.
+    ///
+    /// The malformed entries are skipped and logged with the path of the error,
+    /// they are counted per Validator `host` in [`SentryApi::malformed_entries`] and the metrics.
+    async fn parse_entries<T: DeserializeOwned>(
+        &self,
+        host: &str,
+        endpoint: Endpoint,
+        entries: Vec<Value>,
+    ) -> Vec<T> {
+        let mut malformed = 0;
+        let parsed = entries
+            .into_iter()
+            .enumerate()
+            .filter_map(
+                |(index, entry)| match serde_path_to_error::deserialize::<_, T>(entry) {
+                    Ok(parsed) => Some(parsed),
+                    Err(error) => {
+                        warn!(
+                            &self.logger,
+                            "Skipped a malformed entry from the Validator";
+                            "validator" => host,
+                            "endpoint" => endpoint.as_str(),
+                            "index" => index,
+                            "path" => %error.path(),
+                            "error" => %error.inner(),
+                        );
+                        malformed += 1;
+
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        if malformed > 0 {
+            let label = self.validator_label(host).await;
+            VALIDATOR_MALFORMED_ENTRIES
+                .with_label_values(&[label.as_str(), endpoint.as_str()])
+                .inc_by(malformed);
+            *self
+                .malformed_entries
+                .write()
+                .await
+                .entry(host.to_string())
+                .or_insert(0) += malformed;
+        }
+
+        parsed
+    }
+
+    /// Makes a `GET` request to the Validator and records the request, its duration
+    /// and whether it failed in the Validator metrics
+    async fn get<T: DeserializeOwned>(
+        &self,
+        endpoint: Endpoint,
+        url: Url,
+        timeout: Duration,
+    ) -> Result<T, Error> {
+        let label = self.validator_label(&validator_host(&url)).await;
+        let labels = [label.as_str(), endpoint.as_str()];
+
+        let timer = VALIDATOR_REQUEST_DURATION
+            .with_label_values(&labels)
+            .start_timer();
+        let result = async {
+            self.client
+                .get(url)
+                .timeout(timeout)
+                .send()
+                .await?
+                .json::<T>()
+                .await
+        }
+        .await
+        .map_err(Error::from);
+        timer.observe_duration();
+
+        VALIDATOR_REQUESTS.with_label_values(&labels).inc();
+        if result.is_err() {
+            VALIDATOR_REQUEST_ERRORS.with_label_values(&labels).inc();
+        }
+
+        result
+    }
+}
+
+fn validator_hosts(validators: &HashSet<ApiUrl>) -> HashSet<String> {
+    validators
+        .iter()
+        .map(|validator| validator_host(&validator.to_url()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::DEVELOPMENT, util::test::MockClock};
+    use primitives::{sentry::ChannelListResponse, util::tests::prep_db::DUMMY_CHANNEL};
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn per_validator_timeout_overrides_are_honored() {
+        let mock_server = MockServer::start().await;
+
+        let slow_url: ApiUrl = format!("{}/slow", mock_server.uri())
+            .parse()
+            .expect("Valid URL");
+        let default_url: ApiUrl = format!("{}/default", mock_server.uri())
+            .parse()
+            .expect("Valid URL");
+
+        let channels = ChannelListResponse {
+            channels: vec![],
+            total_pages: 1,
+            total: 0,
+            page: 0,
+        };
+
+        for prefix in &["/slow", "/default"] {
+            Mock::given(method("GET"))
+                .and(path(format!("{}/channel/list", prefix)))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(&channels)
+                        .set_delay(Duration::from_millis(1500)),
+                )
+                .mount(&mock_server)
+                .await;
+        }
+
+        let mut timeouts = DEVELOPMENT.timeouts.clone();
+        timeouts.validator_request = Duration::from_millis(500);
+        timeouts
+            .validators
+            .insert(slow_url.clone(), Duration::from_secs(5));
+
+        let sentry = SentryApi::with_timeouts(&timeouts).expect("Should build SentryApi");
+
+        assert_eq!(Duration::from_secs(5), sentry.timeout_for(&slow_url));
+        assert_eq!(Duration::from_millis(500), sentry.timeout_for(&default_url));
+
+        let slow_channels = sentry
+            .get_validator_channels(&slow_url)
+            .await
+            .expect("The override should allow the slow Validator to respond");
+        assert!(slow_channels.is_empty());
+
+        match sentry.get_validator_channels(&default_url).await {
+            Err(Error::Reqwest(error)) => assert!(error.is_timeout()),
+            result => panic!("Expected a timeout, got: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn validator_timeouts_stay_within_the_global_request_timeout() {
+        let slow_url: ApiUrl = "https://slow.adex.network/".parse().expect("Valid URL");
+        let default_url: ApiUrl = "https://tom.adex.network/".parse().expect("Valid URL");
+
+        let mut timeouts = DEVELOPMENT.timeouts.clone();
+        timeouts.global_request = Duration::from_secs(15);
+        timeouts.validator_request = Duration::from_secs(20);
+        timeouts
+            .validators
+            .insert(slow_url.clone(), Duration::from_secs(60));
+
+        let sentry = SentryApi::with_timeouts(&timeouts).expect("Should build SentryApi");
+        assert_eq!(Duration::from_secs(15), sentry.timeout_for(&slow_url));
+        assert_eq!(Duration::from_secs(15), sentry.timeout_for(&default_url));
+
+        timeouts.validator_request = Duration::from_secs(5);
+        let sentry = SentryApi::with_timeouts(&timeouts).expect("Should build SentryApi");
+        assert_eq!(Duration::from_secs(5), sentry.timeout_for(&default_url));
+    }
+
+    #[tokio::test]
+    async fn validator_requests_are_recorded_in_the_metrics() {
+        let mock_server = MockServer::start().await;
+        let validator_url: ApiUrl = mock_server.uri().parse().expect("Valid URL");
+        let host = validator_host(&Url::parse(&mock_server.uri()).expect("Valid URL"));
+        // e.g. the Follower from the spec of a Campaign
+        let spec_server = MockServer::start().await;
+        let spec_host = validator_host(&Url::parse(&spec_server.uri()).expect("Valid URL"));
+
+        let channels = ChannelListResponse {
+            channels: vec![],
+            total_pages: 1,
+            total: 0,
+            page: 0,
+        };
+        Mock::given(method("GET"))
+            .and(path("/channel/list"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&channels))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/channel/{}/last-approved", DUMMY_CHANNEL.id)))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/channel/{}/last-approved", DUMMY_CHANNEL.id)))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&spec_server)
+            .await;
+
+        let sentry = SentryApi::with_timeouts(&DEVELOPMENT.timeouts)
+            .expect("Should build SentryApi")
+            .with_validators(&std::iter::once(validator_url.clone()).collect());
+
+        sentry
+            .get_validator_channels(&validator_url)
+            .await
+            .expect("Should fetch the Channels");
+
+        let mut leader = DUMMY_CHANNEL.spec.validators.leader().clone();
+        leader.url = mock_server.uri();
+        sentry
+            .get_last_approved(DUMMY_CHANNEL.id, &leader)
+            .await
+            .expect_err("Should fail with 500 Internal Server Error");
+
+        let channel_list = [host.as_str(), Endpoint::ChannelList.as_str()];
+        assert_eq!(1, VALIDATOR_REQUESTS.with_label_values(&channel_list).get());
+        assert_eq!(
+            0,
+            VALIDATOR_REQUEST_ERRORS
+                .with_label_values(&channel_list)
+                .get()
+        );
+        assert_eq!(
+            1,
+            VALIDATOR_REQUEST_DURATION
+                .with_label_values(&channel_list)
+                .get_sample_count()
+        );
+
+        let last_approved = [host.as_str(), Endpoint::LastApproved.as_str()];
+        assert_eq!(
+            1,
+            VALIDATOR_REQUESTS.with_label_values(&last_approved).get()
+        );
+        assert_eq!(
+            1,
+            VALIDATOR_REQUEST_ERRORS
+                .with_label_values(&last_approved)
+                .get()
+        );
+        assert_eq!(
+            1,
+            VALIDATOR_REQUEST_DURATION
+                .with_label_values(&last_approved)
+                .get_sample_count()
+        );
+
+        let mut follower = DUMMY_CHANNEL.spec.validators.follower().clone();
+        follower.url = spec_server.uri();
+        sentry
+            .get_last_approved(DUMMY_CHANNEL.id, &follower)
+            .await
+            .expect_err("Should fail with 500 Internal Server Error");
+
+        let spec_last_approved = [spec_host.as_str(), Endpoint::LastApproved.as_str()];
+        assert_eq!(
+            0,
+            VALIDATOR_REQUESTS
+                .with_label_values(&spec_last_approved)
+                .get(),
+            "The Validators which aren't configured shouldn't have their own label"
+        );
+        assert_eq!(
+            0,
+            VALIDATOR_REQUEST_ERRORS
+                .with_label_values(&spec_last_approved)
+                .get()
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_configured_validators_have_their_own_label() {
+        let tom: ApiUrl = "https://tom.adex.network".parse().expect("Valid URL");
+        let jerry: ApiUrl = "http://localhost:8006".parse().expect("Valid URL");
+
+        let sentry = SentryApi::with_timeouts(&DEVELOPMENT.timeouts)
+            .expect("Should build SentryApi")
+            .with_validators(&std::iter::once(tom).collect());
+        assert_eq!(
+            "tom.adex.network",
+            sentry.validator_label("tom.adex.network").await
+        );
+        assert_eq!(
+            OTHER_VALIDATOR_LABEL,
+            sentry.validator_label("localhost:8006").await
+        );
+        assert_eq!(
+            OTHER_VALIDATOR_LABEL,
+            sentry.validator_label("spec-only.example.com").await
+        );
+
+        sentry
+            .set_validators(&std::iter::once(jerry).collect())
+            .await;
+        assert_eq!(
+            OTHER_VALIDATOR_LABEL,
+            sentry.validator_label("tom.adex.network").await
+        );
+        assert_eq!(
+            "localhost:8006",
+            sentry.validator_label("localhost:8006").await
+        );
+    }
+
+    fn channel(id: u8) -> Channel {
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.id = ChannelId::from([id; 32]);
+
+        channel
+    }
+
+    /// Mounts a Validator with 3 pages of Channels, the 3rd Channel is on both the 2nd and 3rd page
+    async fn mount_three_pages(mock_server: &MockServer) {
+        let pages = vec![
+            vec![channel(1), channel(2)],
+            vec![channel(3), channel(4)],
+            vec![channel(3), channel(5)],
+        ];
+
+        for (page, channels) in pages.into_iter().enumerate() {
+            let response = ChannelListResponse {
+                channels,
+                total_pages: 3,
+                total: 6,
+                page: page as u64,
+            };
+
+            Mock::given(method("GET"))
+                .and(path("/channel/list"))
+                .and(query_param("page", page.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&response))
+                .expect(1)
+                .mount(mock_server)
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn all_channel_pages_are_fetched_without_duplicates() {
+        let mock_server = MockServer::start().await;
+        let validator_url: ApiUrl = mock_server.uri().parse().expect("Valid URL");
+        mount_three_pages(&mock_server).await;
+
+        let sentry = SentryApi::with_timeouts(&DEVELOPMENT.timeouts)
+            .expect("Should build SentryApi")
+            .with_channel_list(ChannelList {
+                page_concurrency: 2,
+                max_pages: 100,
+                ..Default::default()
+            });
+
+        let channels = sentry
+            .get_validator_channels(&validator_url)
+            .await
+            .expect("Should fetch all the pages");
+
+        let ids = channels
+            .iter()
+            .map(|channel| channel.id)
+            .collect::<Vec<_>>();
+        let expected = (1..=5).map(|id| channel(id).id).collect::<Vec<_>>();
+        assert_eq!(expected, ids);
+    }
+
+    #[tokio::test]
+    async fn channel_pages_over_the_limit_are_skipped() {
+        let mock_server = MockServer::start().await;
+        let validator_url: ApiUrl = mock_server.uri().parse().expect("Valid URL");
+
+        for page in 0..2 {
+            let response = ChannelListResponse {
+                channels: vec![channel(page + 1)],
+                total_pages: 3,
+                total: 3,
+                page: page.into(),
+            };
+
+            Mock::given(method("GET"))
+                .and(path("/channel/list"))
+                .and(query_param("page", page.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&response))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/channel/list"))
+            .and(query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let sentry = SentryApi::with_timeouts(&DEVELOPMENT.timeouts)
+            .expect("Should build SentryApi")
+            .with_channel_list(ChannelList {
+                page_concurrency: 4,
+                max_pages: 2,
+                ..Default::default()
+            });
+
+        let channels = sentry
+            .get_validator_channels(&validator_url)
+            .await
+            .expect("Should fetch the pages within the limit");
+
+        assert_eq!(
+            vec![channel(1).id, channel(2).id],
+            channels
+                .iter()
+                .map(|channel| channel.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_channels_are_filtered_by_the_validator_and_the_client() {
+        let mock_server = MockServer::start().await;
+        let validator_url: ApiUrl = mock_server.uri().parse().expect("Valid URL");
+
+        let clock = Arc::new(MockClock::new());
+        let now = clock.now_utc();
+
+        let mut valid = channel(1);
+        valid.valid_until = now + chrono::Duration::days(1);
+        let mut expired = channel(2);
+        expired.valid_until = now - chrono::Duration::minutes(1);
+
+        // the Validator ignores the `validUntil` filter
+        let response = ChannelListResponse {
+            channels: vec![valid.clone(), expired],
+            total_pages: 1,
+            total: 2,
+            page: 0,
+        };
+        Mock::given(method("GET"))
+            .and(path("/channel/list"))
+            .and(query_param("validUntil", now.timestamp().to_string()))
+            .and(query_param("creator", valid.creator.to_string()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&response))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let sentry = SentryApi::with_timeouts(&DEVELOPMENT.timeouts)
+            .expect("Should build SentryApi")
+            .with_channel_list(ChannelList {
+                creator: Some(valid.creator.to_string()),
+                ..Default::default()
+            })
+            .with_clock(clock);
+
+        let channels = sentry
+            .get_validator_channels(&validator_url)
+            .await
+            .expect("Should fetch the Channels with the filters");
+
+        assert_eq!(
+            vec![valid.id],
+            channels
+                .iter()
+                .map(|channel| channel.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_entries_are_skipped_and_counted() {
+        let mock_server = MockServer::start().await;
+        let validator_url: ApiUrl = mock_server.uri().parse().expect("Valid URL");
+        let host = validator_host(&Url::parse(&mock_server.uri()).expect("Valid URL"));
+
+        let mut malformed_spec = serde_json::to_value(&channel(3)).expect("Should serialize");
+        malformed_spec["spec"] = json!(42);
+        let response = json!({
+            "channels": [channel(1), "garbage", malformed_spec, channel(2)],
+            "totalPages": 1,
+            "total": 4,
+            "page": 0,
+        });
+        Mock::given(method("GET"))
+            .and(path("/channel/list"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&response))
+            .mount(&mock_server)
+            .await;
+
+        let leader = DUMMY_CHANNEL.spec.validators.leader();
+        Mock::given(method("GET"))
+            .and(path(format!(
+                "/channel/{}/validator-messages/{}/NewState",
+                DUMMY_CHANNEL.id, leader.id
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&json!({
+                "validatorMessages": [{ "msg": "garbage" }],
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let sentry =
+            SentryApi::with_timeouts(&DEVELOPMENT.timeouts).expect("Should build SentryApi");
+
+        let channels = sentry
+            .get_validator_channels(&validator_url)
+            .await
+            .expect("The well-formed Channels should still be used");
+        assert_eq!(
+            vec![channel(1).id, channel(2).id],
+            channels
+                .iter()
+                .map(|channel| channel.id)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(&2), sentry.malformed_entries().await.get(&host));
+
+        let mut leader = leader.clone();
+        leader.url = mock_server.uri();
+        let new_state = sentry
+            .get_latest_new_state(DUMMY_CHANNEL.id, &leader)
+            .await
+            .expect("The response should be well-formed");
+        assert!(new_state.is_none());
+
+        assert_eq!(Some(&3), sentry.malformed_entries().await.get(&host));
+        for (endpoint, malformed) in &[(Endpoint::ChannelList, 2), (Endpoint::ValidatorMessages, 1)]
+        {
+            assert_eq!(
+                *malformed,
+                VALIDATOR_MALFORMED_ENTRIES
+                    .with_label_values(&[host.as_str(), endpoint.as_str()])
+                    .get()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn last_approved_is_prefetched_in_batches() {
+        let mock_server = MockServer::start().await;
+        let mut leader = DUMMY_CHANNEL.spec.validators.leader().clone();
+        leader.url = mock_server.uri();
+
+        let channel_ids = vec![ChannelId::from([1; 32]), ChannelId::from([2; 32])];
+        let last_approved = LastApprovedResponse {
+            last_approved: None,
+            heartbeats: Some(vec![]),
+        };
+        // the 3rd Channel wasn't requested
+        let batch = channel_ids
+            .iter()
+            .chain(std::iter::once(&ChannelId::from([3; 32])))
+            .map(|channel_id| (channel_id.to_string(), &last_approved))
+            .collect::<HashMap<_, _>>();
+        let response = json!({ "lastApproved": batch });
+        Mock::given(method("GET"))
+            .and(path("/channel/last-approved"))
+            .and(query_param(
+                "channels",
+                format!("{},{}", channel_ids[0], channel_ids[1]),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&response))
+            .expect(1_u64)
+            .mount(&mock_server)
+            .await;
+        for channel_id in &channel_ids {
+            Mock::given(method("GET"))
+                .and(path(format!("/channel/{}/last-approved", channel_id)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&last_approved))
+                .expect(0_u64)
+                .mount(&mock_server)
+                .await;
+        }
+
+        let sentry = SentryApi::new(Duration::from_secs(5)).expect("Should build SentryApi");
+        assert_eq!(
+            2,
+            sentry.prefetch_last_approved(&leader, &channel_ids).await
+        );
+
+        for channel_id in &channel_ids {
+            let response = sentry
+                .get_last_approved(*channel_id, &leader)
+                .await
+                .expect("Should be prefetched");
+            assert_eq!(
+                Some(0),
+                response.heartbeats.map(|heartbeats| heartbeats.len())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn validators_without_batched_last_approved_fall_back_to_single_requests() {
+        let mock_server = MockServer::start().await;
+        let mut leader = DUMMY_CHANNEL.spec.validators.leader().clone();
+        leader.url = mock_server.uri();
+
+        let channel_ids = vec![ChannelId::from([1; 32]), ChannelId::from([2; 32])];
+        let last_approved = LastApprovedResponse {
+            last_approved: None,
+            heartbeats: Some(vec![]),
+        };
+        Mock::given(method("GET"))
+            .and(path("/channel/last-approved"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1_u64)
+            .mount(&mock_server)
+            .await;
+        for channel_id in &channel_ids {
+            Mock::given(method("GET"))
+                .and(path(format!("/channel/{}/last-approved", channel_id)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&last_approved))
+                .expect(2_u64)
+                .mount(&mock_server)
+                .await;
+        }
+
+        let sentry = SentryApi::new(Duration::from_secs(5)).expect("Should build SentryApi");
+        for _ in 0..2 {
+            // the Validator is batched only the first time
+            assert_eq!(
+                0,
+                sentry.prefetch_last_approved(&leader, &channel_ids).await
+            );
+
+            for channel_id in &channel_ids {
+                sentry
+                    .get_last_approved(*channel_id, &leader)
+                    .await
+                    .expect("Should be requested on its own");
+            }
+        }
+
+        // with batching disabled the Validator isn't batched at all
+        let disabled = SentryApi::new(Duration::from_secs(5))
+            .expect("Should build SentryApi")
+            .with_last_approved(LastApprovedBatch { batch_size: 0 });
+        assert_eq!(
+            0,
+            disabled.prefetch_last_approved(&leader, &channel_ids).await
+        );
+    }
 }
@@ -1,4 +1,8 @@
-use crate::sentry_api::{Error, SentryApi};
+use crate::{
+    sentry_api::{Error, SentryApi},
+    util::Clock,
+    Config,
+};
 use chrono::{DateTime, Duration, Utc};
 use primitives::{
     sentry::{HeartbeatValidatorMessage, LastApprovedResponse, NewStateValidatorMessage},
@@ -9,6 +13,69 @@ use primitives::{
 // Re-export the Status & Finalized enums
 pub use primitives::supermarket::{Finalized, Status};
 
+/// The Leader's last approved NewState, from which the balances of the status are taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastNewState {
+    pub state_root: String,
+    pub received: DateTime<Utc>,
+}
+
+/// The balances of the Leader's last approved NewState and of the NewState last approved by the Follower
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Balances {
+    pub leader: BalancesMap,
+    /// Empty for the Finalized Campaigns and until the Follower approves a NewState
+    pub follower: BalancesMap,
+}
+
+/// The per-address maximum of the `leader` & `follower` balances, i.e. the most spent view of the Campaign.
+///
+/// The Leader's NewState may be ahead of the Follower's ApproveState,
+/// so the remaining budget & the publisher earnings are checked against the more conservative of the two.
+pub fn effective_balances(leader: &BalancesMap, follower: &BalancesMap) -> BalancesMap {
+    let mut effective = leader.clone();
+    for (address, balance) in follower.iter() {
+        match effective.get_mut(address) {
+            Some(spent) if *spent >= *balance => {}
+            Some(spent) => *spent = balance.clone(),
+            None => {
+                effective.insert(*address, balance.clone());
+            }
+        }
+    }
+
+    effective
+}
+
+/// How old the Validators' Heartbeats can be to count as recent,
+/// see [`Config.recency`](crate::Config::recency) & [`Config.clock_skew_tolerance`](crate::Config::clock_skew_tolerance)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatRecency {
+    pub recency: Duration,
+    pub clock_skew_tolerance: Duration,
+}
+
+impl Default for HeartbeatRecency {
+    fn default() -> Self {
+        Self {
+            recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+        }
+    }
+}
+
+impl From<&Config> for HeartbeatRecency {
+    fn from(config: &Config) -> Self {
+        let to_chrono =
+            |duration| Duration::from_std(duration).expect("The Duration should be in range");
+
+        Self {
+            recency: to_chrono(config.recency),
+            clock_skew_tolerance: to_chrono(config.clock_skew_tolerance),
+        }
+    }
+}
+
 #[cfg(test)]
 #[path = "status_test.rs"]
 pub mod test;
@@ -17,6 +84,11 @@ struct Messages {
     leader: LastApprovedResponse,
     follower: LastApprovedResponse,
     recency: Duration,
+    /// The Heartbeats dated up to this much after `now` count as sent `now`,
+    /// and the ones up to this much older than the `recency` still count as recent
+    clock_skew_tolerance: Duration,
+    /// The moment of the status check
+    now: DateTime<Utc>,
 }
 
 impl Messages {
@@ -27,6 +99,17 @@ impl Messages {
             .and_then(|last_approved| last_approved.new_state.as_ref())
     }
 
+    fn get_leader_last_new_state(&self) -> Option<LastNewState> {
+        self.get_leader_new_state()
+            .and_then(|new_state| match &new_state.msg {
+                MessageTypes::NewState(msg) => Some(LastNewState {
+                    state_root: msg.state_root.clone(),
+                    received: new_state.received,
+                }),
+                _ => None,
+            })
+    }
+
     fn get_leader_new_state_balances(&self) -> BalancesMap {
         self.leader
             .last_approved
@@ -39,6 +122,19 @@ impl Messages {
             .unwrap_or_default()
     }
 
+    /// The balances of the NewState approved by the Follower, which may be behind the Leader's
+    fn get_follower_approved_balances(&self) -> BalancesMap {
+        self.follower
+            .last_approved
+            .as_ref()
+            .and_then(|last_approved| last_approved.new_state.as_ref())
+            .and_then(|new_state| match &new_state.msg {
+                MessageTypes::NewState(new_state) => Some(new_state.balances.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
     fn get_follower_approve_state_msg(&self) -> Option<&ApproveState> {
         self.follower
             .last_approved
@@ -121,12 +217,22 @@ impl Messages {
             .any(|heartbeat_msg| match (from, &heartbeat_msg.msg) {
                 (Some(from), MessageTypes::Heartbeat(heartbeat))
                     if &heartbeat_msg.from == from
-                        && is_date_recent(self.recency, &heartbeat.timestamp) =>
+                        && is_date_recent(
+                            self.recency,
+                            self.clock_skew_tolerance,
+                            &self.now,
+                            &heartbeat.timestamp,
+                        ) =>
                 {
                     true
                 }
                 (None, MessageTypes::Heartbeat(heartbeat))
-                    if is_date_recent(self.recency, &heartbeat.timestamp) =>
+                    if is_date_recent(
+                        self.recency,
+                        self.clock_skew_tolerance,
+                        &self.now,
+                        &heartbeat.timestamp,
+                    ) =>
                 {
                     true
                 }
@@ -150,9 +256,15 @@ pub enum IsFinalized {
 /// - Is Channel expired?
 /// - Is in withdraw period?
 /// - Is Channel exhausted?
-pub async fn is_finalized(sentry: &SentryApi, channel: &Channel) -> Result<IsFinalized, Error> {
+pub async fn is_finalized(
+    sentry: &SentryApi,
+    channel: &Channel,
+    clock: &dyn Clock,
+) -> Result<IsFinalized, Error> {
+    let now = clock.now_utc();
+
     // Is Channel expired?
-    if Utc::now() > channel.valid_until {
+    if now > channel.valid_until {
         let balances = fetch_balances(&sentry, &channel).await?;
 
         return Ok(IsFinalized::Yes {
@@ -162,7 +274,7 @@ pub async fn is_finalized(sentry: &SentryApi, channel: &Channel) -> Result<IsFin
     }
 
     // Is in withdraw period?
-    if Utc::now() > channel.spec.withdraw_period_start {
+    if now > channel.spec.withdraw_period_start {
         let balances = fetch_balances(&sentry, &channel).await?;
 
         return Ok(IsFinalized::Yes {
@@ -210,13 +322,24 @@ pub async fn is_finalized(sentry: &SentryApi, channel: &Channel) -> Result<IsFin
     })
 }
 
+/// Returns the Status and the Leader's & Follower's balances of the Campaign, with the Leader's NewState they come from.
+/// For Finalized Campaigns the NewState and the Follower's balances are not returned.
 pub async fn get_status(
     sentry: &SentryApi,
     channel: &Channel,
-) -> Result<(Status, BalancesMap), Error> {
+    clock: &dyn Clock,
+    heartbeat_recency: HeartbeatRecency,
+) -> Result<(Status, Balances, Option<LastNewState>), Error> {
     // continue only if Campaign is not Finalized
-    let leader_la = match is_finalized(sentry, channel).await? {
-        IsFinalized::Yes { reason, balances } => return Ok((Status::Finalized(reason), balances)),
+    let leader_la = match is_finalized(sentry, channel, clock).await? {
+        IsFinalized::Yes { reason, balances } => {
+            let balances = Balances {
+                leader: balances,
+                follower: Default::default(),
+            };
+
+            return Ok((Status::Finalized(reason), balances, None));
+        }
         IsFinalized::No { leader } => leader,
     };
 
@@ -227,53 +350,73 @@ pub async fn get_status(
     let messages = Messages {
         leader: *leader_la,
         follower: follower_la,
-        recency: Duration::minutes(4),
+        recency: heartbeat_recency.recency,
+        clock_skew_tolerance: heartbeat_recency.clock_skew_tolerance,
+        now: clock.now_utc(),
     };
 
+    let status = get_unfinalized_status(sentry, channel, &messages).await?;
+
+    let balances = Balances {
+        leader: messages.get_leader_new_state_balances(),
+        follower: messages.get_follower_approved_balances(),
+    };
+
+    Ok((status, balances, messages.get_leader_last_new_state()))
+}
+
+async fn get_unfinalized_status(
+    sentry: &SentryApi,
+    channel: &Channel,
+    messages: &Messages,
+) -> Result<Status, Error> {
+    // the Campaign is not served before its `activeFrom`, regardless of the Validators' messages
+    if is_scheduled(channel, messages.now) {
+        return Ok(Status::Pending);
+    }
+
     // impl: isInitializing
-    if is_initializing(&messages) {
-        return Ok((
-            Status::Initializing,
-            messages.get_leader_new_state_balances(),
-        ));
+    if is_initializing(messages) {
+        return Ok(Status::Initializing);
     }
 
     // impl: isOffline
-    let offline = is_offline(&messages);
+    let offline = is_offline(messages);
 
     // impl: isDisconnected
-    let disconnected = is_disconnected(&channel, &messages);
+    let disconnected = is_disconnected(channel, messages);
 
     // impl: isInvalid
-    let rejected_state = is_rejected_state(&channel, &messages, &sentry).await?;
+    let rejected_state = is_rejected_state(channel, messages, sentry).await?;
 
     // impl: isUnhealthy
-    let unhealthy = is_unhealthy(&messages);
+    let unhealthy = is_unhealthy(messages);
 
     if disconnected || offline || rejected_state || unhealthy {
-        let status = Status::Unsound {
+        return Ok(Status::Unsound {
             disconnected,
             offline,
             rejected_state,
             unhealthy,
-        };
-        return Ok((status, messages.get_leader_new_state_balances()));
+        });
     }
 
-    let channel_active_from = match channel.spec.active_from {
-        Some(active_from) if active_from < Utc::now() => true,
-        None => true,
-        _ => false,
-    };
-
     // isActive & isReady (we don't need distinguish between Active & Ready here)
-    if is_active(&messages) || (is_ready(&messages) && channel_active_from) {
-        Ok((Status::Active, messages.get_leader_new_state_balances()))
+    if is_active(messages) || is_ready(messages) {
+        Ok(Status::Active)
     } else {
-        Ok((Status::Waiting, messages.get_leader_new_state_balances()))
+        Ok(Status::Waiting)
     }
 }
 
+/// The Campaign's `activeFrom` is after `now`, until then it's `Pending` and it's not served
+pub fn is_scheduled(channel: &Channel, now: DateTime<Utc>) -> bool {
+    channel
+        .spec
+        .active_from
+        .map_or(false, |active_from| active_from > now)
+}
+
 /// Calls SentryApi for the Leader's LastApproved NewState and returns the NewState Balance
 async fn fetch_balances(sentry: &SentryApi, channel: &Channel) -> Result<BalancesMap, Error> {
     let leader_la = sentry
@@ -303,8 +446,21 @@ fn is_offline(messages: &Messages) -> bool {
     !messages.has_recent_leader_hb() || !messages.has_recent_follower_hb()
 }
 
-fn is_date_recent(recency: Duration, date: &DateTime<Utc>) -> bool {
-    date >= &(Utc::now() - recency)
+/// The `date` is clamped to `now` if it's within the `clock_skew_tolerance` after it,
+/// dates further in the future are not recent (i.e. the Validator's clock is off).
+/// The dates older than the `recency` + `clock_skew_tolerance` are not recent either.
+fn is_date_recent(
+    recency: Duration,
+    clock_skew_tolerance: Duration,
+    now: &DateTime<Utc>,
+    date: &DateTime<Utc>,
+) -> bool {
+    if date > &(*now + clock_skew_tolerance) {
+        return false;
+    }
+    let date = std::cmp::min(date, now);
+
+    date >= &(*now - (recency + clock_skew_tolerance))
 }
 
 /// validators have recent Heartbeat messages, but they don't seem to be propagating messages between one another (the majority of Heartbeats are not found on both validators)
@@ -341,7 +497,7 @@ async fn is_rejected_state(
     let date_diff = leader_new_state.received - latest_new_state.received;
     let is_last_approved_old = date_diff < Duration::zero();
     let is_latest_new_state_a_minute_old =
-        (Utc::now() - latest_new_state.received) > Duration::minutes(1);
+        (messages.now - latest_new_state.received) > Duration::minutes(1);
 
     Ok(is_last_approved_old && is_latest_new_state_a_minute_old)
 }
@@ -1,5 +1,5 @@
 use super::*;
-use crate::sentry_api::SentryApi;
+use crate::{sentry_api::SentryApi, util::test::MockClock};
 use chrono::{Duration, Utc};
 use lazy_static::lazy_static;
 use primitives::{
@@ -92,8 +92,11 @@ mod is_finalized {
     #[tokio::test]
     async fn it_is_finalized_when_expired() {
         let server = MockServer::start().await;
+        let clock = MockClock::new();
         let mut channel = get_request_channel(&server);
-        channel.valid_until = Utc::now() - Duration::seconds(5);
+        channel.valid_until = clock.now_utc() + Duration::seconds(5);
+        // the Channel expires while the Campaign is in the Cache
+        clock.advance(std::time::Duration::from_secs(10));
 
         let response = LastApprovedResponse {
             last_approved: None,
@@ -107,7 +110,7 @@ mod is_finalized {
 
         let sentry = SentryApi::new(*SENTRY_API_TIMEOUT).expect("Should work");
 
-        let actual = is_finalized(&sentry, &channel)
+        let actual = is_finalized(&sentry, &channel, &clock)
             .await
             .expect("Should query dummy server");
 
@@ -122,8 +125,10 @@ mod is_finalized {
     #[tokio::test]
     async fn it_is_finalized_when_in_withdraw_period() {
         let server = MockServer::start().await;
+        let clock = MockClock::new();
         let mut channel = get_request_channel(&server);
-        channel.spec.withdraw_period_start = Utc::now() - Duration::seconds(5);
+        channel.spec.withdraw_period_start = clock.now_utc() + Duration::seconds(5);
+        clock.advance(std::time::Duration::from_secs(10));
 
         let response = LastApprovedResponse {
             last_approved: None,
@@ -137,7 +142,7 @@ mod is_finalized {
 
         let sentry = SentryApi::new(*SENTRY_API_TIMEOUT).expect("Should work");
 
-        let actual = is_finalized(&sentry, &channel)
+        let actual = is_finalized(&sentry, &channel, &clock)
             .await
             .expect("Should query dummy server");
 
@@ -152,6 +157,7 @@ mod is_finalized {
     #[tokio::test]
     async fn it_is_finalized_when_channel_is_exhausted() {
         let server = MockServer::start().await;
+        let clock = MockClock::new();
         let channel = get_request_channel(&server);
 
         let leader = channel.spec.validators.leader().id;
@@ -190,7 +196,7 @@ mod is_finalized {
 
         let sentry = SentryApi::new(*SENTRY_API_TIMEOUT).expect("Should work");
 
-        let actual = is_finalized(&sentry, &channel)
+        let actual = is_finalized(&sentry, &channel, &clock)
             .await
             .expect("Should query dummy server");
 
@@ -205,6 +211,7 @@ mod is_finalized {
     #[tokio::test]
     async fn it_is_not_finalized() {
         let server = MockServer::start().await;
+        let clock = MockClock::new();
         let channel = get_request_channel(&server);
 
         let leader = channel.spec.validators.leader().id;
@@ -238,7 +245,7 @@ mod is_finalized {
 
         let sentry = SentryApi::new(*SENTRY_API_TIMEOUT).expect("Should work");
 
-        let actual = is_finalized(&sentry, &channel)
+        let actual = is_finalized(&sentry, &channel, &clock)
             .await
             .expect("Should query dummy server");
 
@@ -267,6 +274,8 @@ mod is_offline {
                 heartbeats: follower,
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         }
     }
 
@@ -348,34 +357,86 @@ mod is_date_recent {
 
     #[test]
     fn now_date_is_recent() {
-        let now = Utc::now();
+        let clock = MockClock::new();
         let recency = *RECENCY;
         assert!(
-            is_date_recent(recency, &now),
+            is_date_recent(
+                recency,
+                Duration::zero(),
+                &clock.now_utc(),
+                &clock.now_utc()
+            ),
             "The present moment is a recent date!"
         )
     }
 
     #[test]
     fn slightly_past_is_recent() {
+        let clock = MockClock::new();
         let recency = *RECENCY;
-        let on_the_edge = Utc::now() - Duration::minutes(3);
+        let on_the_edge = clock.now_utc();
+        clock.advance(std::time::Duration::from_secs(4 * 60));
         assert!(
-            is_date_recent(recency, &on_the_edge),
+            is_date_recent(recency, Duration::zero(), &clock.now_utc(), &on_the_edge),
             "When date is just as old as the recency limit, it still counts as recent"
         )
     }
 
     #[test]
     fn old_date_is_not_recent() {
+        let clock = MockClock::new();
         let recency = *RECENCY;
-        let past = Utc::now() - Duration::minutes(10);
+        let past = clock.now_utc();
+        clock.advance(std::time::Duration::from_secs(4 * 60 + 1));
         assert_eq!(
-            is_date_recent(recency, &past),
+            is_date_recent(recency, Duration::zero(), &clock.now_utc(), &past),
             false,
             "Date older than the recency limit is not recent"
         )
     }
+
+    #[test]
+    fn the_clock_skew_is_tolerated_in_both_directions() {
+        let now = MockClock::new().now_utc();
+        let recency = *RECENCY;
+        let tolerance = Duration::minutes(2);
+
+        let table = [
+            // in the past: within the recency, within the tolerance and beyond it
+            (now - recency + Duration::seconds(1), true),
+            (now - recency - Duration::seconds(1), true),
+            (now - recency - tolerance, true),
+            (now - recency - tolerance - Duration::seconds(1), false),
+            // in the future: clamped to `now` within the tolerance
+            (now + Duration::seconds(1), true),
+            (now + tolerance, true),
+            (now + tolerance + Duration::seconds(1), false),
+            (now + Duration::days(1), false),
+        ];
+
+        for (date, expected) in table.iter() {
+            assert_eq!(
+                *expected,
+                is_date_recent(recency, tolerance, &now, date),
+                "{} should be recent: {} (now: {})",
+                date,
+                expected,
+                now
+            );
+        }
+    }
+
+    #[test]
+    fn without_tolerance_future_dates_are_not_recent() {
+        let now = MockClock::new().now_utc();
+
+        assert!(!is_date_recent(
+            *RECENCY,
+            Duration::zero(),
+            &now,
+            &(now + Duration::seconds(1))
+        ));
+    }
 }
 
 mod is_initializing {
@@ -393,6 +454,8 @@ mod is_initializing {
                 heartbeats: Some(vec![]),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -419,6 +482,8 @@ mod is_initializing {
                 heartbeats: Some(vec![heartbeat]),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -446,6 +511,8 @@ mod is_initializing {
                 heartbeats: Some(vec![]),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -478,6 +545,8 @@ mod is_initializing {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -523,6 +592,8 @@ mod is_disconnected {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -563,6 +634,8 @@ mod is_disconnected {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -603,6 +676,8 @@ mod is_disconnected {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -643,6 +718,8 @@ mod is_disconnected {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -683,6 +760,8 @@ mod is_disconnected {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -732,6 +811,8 @@ mod is_rejected_state {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         Mock::given(method("GET"))
@@ -785,6 +866,8 @@ mod is_rejected_state {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
         let mock_response = ValidatorMessageResponse {
             validator_messages: vec![latest_new_state],
@@ -843,6 +926,8 @@ mod is_rejected_state {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         let mock_response = ValidatorMessageResponse {
@@ -901,6 +986,8 @@ mod is_rejected_state {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         let mock_response = ValidatorMessageResponse {
@@ -960,6 +1047,8 @@ mod is_rejected_state {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
         let sentry = SentryApi::new(*SENTRY_API_TIMEOUT).expect("Should work");
         let mock_response = ValidatorMessageResponse {
@@ -1018,6 +1107,8 @@ mod is_rejected_state {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
         let mock_response = ValidatorMessageResponse {
             validator_messages: vec![latest_new_state],
@@ -1075,6 +1166,8 @@ mod is_unhealthy {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1115,6 +1208,8 @@ mod is_unhealthy {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1154,6 +1249,8 @@ mod is_unhealthy {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: Duration::minutes(4),
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1198,6 +1295,8 @@ mod is_active {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1238,6 +1337,8 @@ mod is_active {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1278,6 +1379,8 @@ mod is_active {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1318,6 +1421,8 @@ mod is_active {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1358,6 +1463,8 @@ mod is_active {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1397,6 +1504,8 @@ mod is_active {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1438,6 +1547,8 @@ mod is_ready {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1475,6 +1586,8 @@ mod is_ready {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1512,6 +1625,8 @@ mod is_ready {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1549,6 +1664,8 @@ mod is_ready {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1588,6 +1705,8 @@ mod is_ready {
                 heartbeats: Some(follower_heartbeats),
             },
             recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
         };
 
         assert_eq!(
@@ -1597,3 +1716,190 @@ mod is_ready {
         )
     }
 }
+
+mod is_scheduled {
+    use super::*;
+
+    /// Ready, i.e. recent heartbeats from both Validators and no NewState yet
+    fn get_ready_messages(channel: &Channel, now: DateTime<Utc>) -> Messages {
+        let heartbeats = || {
+            vec![
+                get_heartbeat_msg(Duration::zero(), channel.spec.validators.leader().id),
+                get_heartbeat_msg(Duration::zero(), channel.spec.validators.follower().id),
+            ]
+        };
+
+        Messages {
+            leader: LastApprovedResponse {
+                last_approved: None,
+                heartbeats: Some(heartbeats()),
+            },
+            follower: LastApprovedResponse {
+                last_approved: None,
+                heartbeats: Some(heartbeats()),
+            },
+            recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now,
+        }
+    }
+
+    #[test]
+    fn the_campaign_is_scheduled_until_its_active_from() {
+        let clock = MockClock::new();
+        let mut channel = DUMMY_CHANNEL.clone();
+
+        channel.spec.active_from = None;
+        assert!(!is_scheduled(&channel, clock.now_utc()));
+
+        let active_from = clock.now_utc() + Duration::minutes(1);
+        channel.spec.active_from = Some(active_from);
+        assert!(is_scheduled(&channel, clock.now_utc()));
+        assert!(is_scheduled(
+            &channel,
+            active_from - Duration::milliseconds(1)
+        ));
+        assert!(!is_scheduled(&channel, active_from));
+        assert!(!is_scheduled(
+            &channel,
+            active_from + Duration::milliseconds(1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_is_pending_until_its_active_from_and_then_active() {
+        let server = MockServer::start().await;
+        let sentry = SentryApi::new(std::time::Duration::from_secs(20)).expect("Should work");
+        let clock = MockClock::new();
+
+        let mut channel = get_request_channel(&server);
+        channel.spec.active_from = Some(clock.now_utc() + Duration::minutes(1));
+
+        let messages = get_ready_messages(&channel, clock.now_utc());
+        let status = get_unfinalized_status(&sentry, &channel, &messages)
+            .await
+            .expect("Should get the status");
+        assert_eq!(Status::Pending, status);
+
+        clock.advance(std::time::Duration::from_secs(59));
+        let messages = get_ready_messages(&channel, clock.now_utc());
+        let status = get_unfinalized_status(&sentry, &channel, &messages)
+            .await
+            .expect("Should get the status");
+        assert_eq!(Status::Pending, status);
+
+        // the same messages from the Validators, only the time has passed
+        clock.advance(std::time::Duration::from_secs(2));
+        let messages = get_ready_messages(&channel, clock.now_utc());
+        let status = get_unfinalized_status(&sentry, &channel, &messages)
+            .await
+            .expect("Should get the status");
+        assert_eq!(Status::Active, status);
+    }
+
+    #[tokio::test]
+    async fn the_heartbeats_stay_recent_when_the_clock_steps_back() {
+        use crate::util::{test::discard_logger, NonDecreasingClock};
+        use std::sync::Arc;
+
+        let server = MockServer::start().await;
+        let sentry = SentryApi::new(std::time::Duration::from_secs(20)).expect("Should work");
+        let clock = MockClock::new();
+        let status_clock = NonDecreasingClock::new(Arc::new(clock.clone()), discard_logger());
+        let channel = get_request_channel(&server);
+
+        clock.advance(std::time::Duration::from_secs(1));
+        let messages = get_ready_messages(&channel, status_clock.now_utc());
+        let status = get_unfinalized_status(&sentry, &channel, &messages)
+            .await
+            .expect("Should get the status");
+        assert_eq!(Status::Active, status);
+
+        // NTP steps the clock back, the Heartbeats sent before are dated in its future
+        clock.step_back(std::time::Duration::from_secs(10));
+        let messages = get_ready_messages(&channel, clock.now_utc());
+        let status = get_unfinalized_status(&sentry, &channel, &messages)
+            .await
+            .expect("Should get the status");
+        assert!(
+            matches!(status, Status::Unsound { offline: true, .. }),
+            "{:?}",
+            status
+        );
+
+        let messages = get_ready_messages(&channel, status_clock.now_utc());
+        let status = get_unfinalized_status(&sentry, &channel, &messages)
+            .await
+            .expect("Should get the status");
+        assert_eq!(Status::Active, status);
+    }
+}
+
+mod effective_balances {
+    use super::*;
+    use primitives::BigNum;
+
+    fn balances(balances: &[(&str, u64)]) -> BalancesMap {
+        balances
+            .iter()
+            .map(|(address, balance)| (IDS[*address], BigNum::from(*balance)))
+            .collect()
+    }
+
+    #[test]
+    fn it_is_the_most_spent_per_address() {
+        let leader = balances(&[("publisher", 100), ("publisher2", 50), ("leader", 10)]);
+        let follower = balances(&[("publisher", 80), ("publisher2", 70), ("follower", 5)]);
+
+        let expected = balances(&[
+            ("publisher", 100),
+            ("publisher2", 70),
+            ("leader", 10),
+            ("follower", 5),
+        ]);
+        assert_eq!(expected, effective_balances(&leader, &follower));
+        assert_eq!(expected, effective_balances(&follower, &leader));
+
+        assert_eq!(leader, effective_balances(&leader, &BalancesMap::default()));
+        assert_eq!(
+            follower,
+            effective_balances(&BalancesMap::default(), &follower)
+        );
+    }
+
+    #[test]
+    fn the_follower_balances_are_the_ones_of_its_approved_new_state() {
+        let new_state = |balances: BalancesMap| {
+            let mut new_state = get_new_state_msg();
+            if let MessageTypes::NewState(msg) = &mut new_state.msg {
+                msg.balances = balances;
+            }
+
+            new_state
+        };
+        let last_approved = |balances: BalancesMap| LastApprovedResponse {
+            last_approved: Some(LastApproved {
+                new_state: Some(new_state(balances)),
+                approve_state: Some(get_approve_state_msg(true)),
+            }),
+            heartbeats: None,
+        };
+
+        let messages = Messages {
+            leader: last_approved(balances(&[("publisher", 100)])),
+            follower: last_approved(balances(&[("publisher", 80)])),
+            recency: *RECENCY,
+            clock_skew_tolerance: Duration::zero(),
+            now: Utc::now(),
+        };
+
+        assert_eq!(
+            balances(&[("publisher", 100)]),
+            messages.get_leader_new_state_balances()
+        );
+        assert_eq!(
+            balances(&[("publisher", 80)]),
+            messages.get_follower_approved_balances()
+        );
+    }
+}
@@ -1,32 +1,653 @@
 use crate::{
-    cache::{Cache, Campaign, Client},
+    bad_request,
+    bot::{is_suspect_bot, BotPolicy},
+    cache::{validation::RulesComplexity, Cache, Campaign, Client},
+    config::{DegradationPolicy, SlotOverride, UnreachableMediaPolicy},
+    gone,
+    market::{SlotFetch, SlotUnits},
+    metrics::{
+        ADSLOT_RULES_OVER_LIMITS, DEGRADED_RESPONSES, MALFORMED_IPFS_URLS, MARKET_FETCH_TIMEOUTS,
+        SELECTION_STRATEGIES, SLOT_REVALIDATIONS, UNITS_FOR_SLOT_DROPPED,
+    },
     not_found, service_unavailable,
-    status::Status,
+    util::{request_id, Clock},
     Config, Error, MarketApi, ROUTE_UNITS_FOR_SLOT,
 };
-use chrono::Utc;
-use http::header::{HeaderName, CONTENT_TYPE};
-use hyper::{header::USER_AGENT, Body, Request, Response};
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
+use http::{
+    header::{HeaderMap, HeaderName, CACHE_CONTROL, CONTENT_TYPE},
+    request::Parts,
+    Method, StatusCode,
+};
+use hyper::{body::HttpBody, header::USER_AGENT, Body, Request, Response};
+use lru::LruCache;
 use primitives::{
     market::AdSlotResponse,
     supermarket::units_for_slot::response,
     supermarket::units_for_slot::response::Response as UnitsForSlotResponse,
-    targeting::{eval_with_callback, get_pricing_bounds, input, input::Input, Output},
-    AdUnit, ValidatorId,
+    targeting::{get_pricing_bounds, input, input::Input, Output, Rules},
+    AdUnit, BigNum, Channel, ChannelId, ValidatorId,
 };
+use serde::{Deserialize, Serialize};
 use slog::{debug, error, warn, Logger};
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::{timeout, Instant};
 use url::{form_urlencoded, Url};
 use woothee::{parser::Parser, woothee::VALUE_UNKNOWN};
 
 lazy_static::lazy_static! {
     pub(crate) static ref CLOUDFLARE_IPCOUNTY_HEADER: HeaderName = HeaderName::from_static("cf-ipcountry");
+    /// Set on the responses served while the Cache is degraded, see [`DegradationPolicy`]
+    pub static ref DEGRADED_HEADER: HeaderName = HeaderName::from_static("x-degraded");
 }
 
+/// The maximum size in bytes of the [`RequestInput`] body of `POST` requests
+pub const MAX_BODY_SIZE: usize = 16 * 1024;
+
+pub use coalesce::CoalescedRequests;
+pub use consent::Consent;
+pub use memo::TargetingMemo;
+pub use pipeline::UnitsForSlotPipeline;
+pub use query::UnitsForSlotQuery;
+pub use reason::EmptyReason;
+pub use referrer::Referrer;
+pub use selection::Strategy;
+pub use slot_error::SlotFetchError;
+pub use variables::Variables;
+pub use version::{
+    PagedResponseV2, PerTypeResponse, ResponseVersion, RESPONSE_VERSION_HEADER, SUPPORTED_VERSIONS,
+};
+
+mod coalesce;
+mod consent;
+pub mod deadline;
+pub mod evaluation;
+pub mod ipfs;
+pub mod media_check;
+mod memo;
+pub mod negative_slots;
+pub mod overrides;
+pub mod pipeline;
+pub mod prewarm;
+pub mod publisher_stats;
+pub mod query;
+pub mod reason;
+mod referrer;
+pub mod sampling;
+pub mod selection;
+pub mod serve_stats;
+mod slot_error;
+pub mod tags;
+pub mod variables;
+mod version;
+
 #[cfg(test)]
 #[path = "units_for_slot_test.rs"]
 pub mod test;
 
+/// The cached [`MatchedUnits`] with the moment they were cached, keyed by the AdSlot and the targeting inputs
+/// (see [`matched_units_key`]).
+///
+/// They expire by the moment they were cached, so the lookups don't change the order of the entries
+/// and once `units_for_slot_cache_size` of them are cached, the least recently cached one is evicted.
+#[derive(Debug)]
+pub struct MatchedUnitsCache {
+    entries: LruCache<String, (Instant, MatchedUnits)>,
+}
+
+impl Default for MatchedUnitsCache {
+    fn default() -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+        }
+    }
+}
+
+impl MatchedUnitsCache {
+    /// The matched units cached within the `ttl`
+    // the `LruCache` is looked up only by a `&String` without its `nightly` feature
+    #[allow(clippy::ptr_arg)]
+    pub fn get(&self, key: &String, now: Instant, ttl: Duration) -> Option<&MatchedUnits> {
+        self.entries
+            .peek(key)
+            .filter(|(cached_at, _)| now.saturating_duration_since(*cached_at) < ttl)
+            .map(|(_, matched_units)| matched_units)
+    }
+
+    /// Drops the expired entries and caches the matched units, evicting the least recently cached ones above the `capacity`
+    pub fn insert(
+        &mut self,
+        key: String,
+        now: Instant,
+        matched_units: MatchedUnits,
+        ttl: Duration,
+        capacity: usize,
+    ) {
+        if self.entries.cap() != capacity {
+            self.entries.resize(capacity);
+        }
+
+        // the least recently cached entry is the oldest one
+        while self
+            .entries
+            .peek_lru()
+            .map_or(false, |(_, (cached_at, _))| {
+                now.saturating_duration_since(*cached_at) >= ttl
+            })
+        {
+            self.entries.pop_lru();
+        }
+
+        self.entries.put(key, (now, matched_units));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The [`UnitsForSlotResponse`] with the pagination of the matched units.
+///
+/// This is the version 1 of the response, see [`ResponseVersion`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PagedResponse {
+    #[serde(flatten)]
+    pub response: UnitsForSlotResponse,
+    /// The number of all matched units, before applying `skip` & `limit`
+    pub total_matched: usize,
+    pub skip: usize,
+    pub limit: Option<usize>,
+    /// The time of the request in the viewer's timezone (see the `?tz=` query parameter)
+    pub day_time: DayTime,
+    /// Whether personal inputs were used, see [`Consent`]
+    pub personalized: bool,
+    /// The [`Strategy`] which ordered the units, see [`Selection`](crate::config::Selection)
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Set only with the `flag` [`BotPolicy`], when the request is from a suspected bot
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub suspect_bot: bool,
+    /// Set when the `Referer` doesn't match the AdSlot's website, see [`Referrer`].
+    /// With the `strict_referrer_check` such requests are not served.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub referrer_mismatch: bool,
+    /// The archived units of the Campaigns which were left out of the matching, only shown with `?debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_units: Option<usize>,
+    /// The generation of the Campaigns the units were matched from (see [`Cache::current`]),
+    /// only shown with `?debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    /// Why there are no matched units (regardless of the page), see [`EmptyReason`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<EmptyReason>,
+    /// Set when the AdUnits fetched for the AdSlot were truncated to the `max_units_per_slot`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// The units of the page, each with the details of its Campaign.
+    /// A unit matched from multiple Campaigns appears once, see [`MatchedUnit::also_available_in`].
+    pub units: Vec<MatchedUnit>,
+}
+
+/// A matched unit with the details of the Campaign it was matched from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchedUnit {
+    #[serde(flatten)]
+    pub unit: response::UnitsWithPrice,
+    pub campaign: CampaignDetails,
+    /// The other Campaigns which matched the same unit (by ipfs), sorted by price (highest first)
+    #[serde(default)]
+    pub also_available_in: Vec<ChannelId>,
+    /// The targeting score, only shown with `?debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// When was the Campaign last refreshed in the Cache, only shown with `?debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_refreshed: Option<DateTime<Utc>>,
+}
+
+/// A Campaign with the units that matched the targeting
+#[derive(Debug, Clone)]
+pub struct TargetedCampaign {
+    pub campaign: response::Campaign,
+    pub details: CampaignDetails,
+    /// The targeting score of each of the `campaign.units_with_price`
+    pub scores: Vec<f64>,
+}
+
+/// The Campaign details required by the SDK for submitting events
+/// and for client-side checks, pulled from the [`Cache`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CampaignDetails {
+    pub channel_id: ChannelId,
+    pub creator: ValidatorId,
+    pub deposit_asset: String,
+    /// The `IMPRESSION` pricing bounds, which the price of the units is within
+    pub pricing_bounds: PricingBounds,
+    /// The pricing bounds of the spec by event type: `IMPRESSION` (same as the `pricing_bounds`) and `CLICK` if it's set
+    pub pricing_bounds_by_event: BTreeMap<String, PricingBounds>,
+    pub valid_until: DateTime<Utc>,
+    /// The Sentry URL of the leader
+    pub leader_url: String,
+    /// The Sentry URL of the follower
+    pub follower_url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PricingBounds {
+    pub min: BigNum,
+    pub max: BigNum,
+}
+
+impl From<&Channel> for CampaignDetails {
+    /// Without the `IMPRESSION` bounds in the spec's `pricingBounds`, they are the legacy `minPerImpression` & `maxPerImpression`
+    fn from(channel: &Channel) -> Self {
+        let impression = get_pricing_bounds(channel, "IMPRESSION");
+        let pricing_bounds = PricingBounds {
+            min: impression.min,
+            max: impression.max,
+        };
+
+        let mut pricing_bounds_by_event = BTreeMap::new();
+        pricing_bounds_by_event.insert("IMPRESSION".to_string(), pricing_bounds.clone());
+        let click = channel
+            .spec
+            .pricing_bounds
+            .as_ref()
+            .and_then(|by_event| by_event.click.as_ref());
+        if let Some(click) = click {
+            pricing_bounds_by_event.insert(
+                "CLICK".to_string(),
+                PricingBounds {
+                    min: click.min.clone(),
+                    max: click.max.clone(),
+                },
+            );
+        }
+
+        Self {
+            channel_id: channel.id,
+            creator: channel.creator,
+            deposit_asset: channel.deposit_asset.clone(),
+            pricing_bounds,
+            pricing_bounds_by_event,
+            valid_until: channel.valid_until,
+            leader_url: channel.spec.validators.leader().url.clone(),
+            follower_url: channel.spec.validators.follower().url.clone(),
+        }
+    }
+}
+
+/// The time of the request in the viewer's timezone, used for dayparting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DayTime {
+    /// From `0` to `23`
+    pub hour: u32,
+    /// From `0` (Sunday) to `6` (Saturday), same as JavaScript's `Date.getDay()`
+    pub day_of_week: u32,
+    /// The UTC offset in minutes, e.g. `120` for UTC+2 and `-300` for UTC-5
+    pub timezone_offset: i32,
+}
+
+impl DayTime {
+    pub fn new(now: DateTime<Utc>, timezone_offset: i32) -> Option<Self> {
+        let offset = FixedOffset::east_opt(timezone_offset.checked_mul(60)?)?;
+        let local = now.with_timezone(&offset);
+
+        Some(Self {
+            hour: local.hour(),
+            day_of_week: local.weekday().num_days_from_sunday(),
+            timezone_offset,
+        })
+    }
+}
+
+/// The JSON body of `POST /units-for-slot/:slotIpfs`.
+/// Every set value overrides the value derived from the request headers and query.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RequestInput {
+    /// Overrides the `cf-ipcountry` header
+    pub country: Option<String>,
+    /// Overrides the OS from the `User-Agent` header
+    pub user_agent_os: Option<String>,
+    /// Overrides the browser family from the `User-Agent` header
+    pub user_agent_browser_family: Option<String>,
+    /// Overrides the owner of the AdSlot
+    pub publisher_id: Option<ValidatorId>,
+    /// Interest segments of the viewer, the targeting rules `get` them as `segments`, see [`variables`]
+    #[serde(default)]
+    pub segments: Vec<String>,
+    /// Overrides the `?depositAsset=` query parameters, the addresses of the assets
+    #[serde(default)]
+    pub accepted_assets: Vec<ValidatorId>,
+    /// Overrides the `?type=` query parameters
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+/// The `?skip=N` and `?limit=N` of the matched units
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pagination {
+    pub skip: usize,
+    pub limit: Option<usize>,
+}
+
+impl From<&UnitsForSlotQuery> for Pagination {
+    fn from(query: &UnitsForSlotQuery) -> Self {
+        Self {
+            skip: query.skip,
+            limit: query.limit,
+        }
+    }
+}
+
+/// The matched units of all Campaigns sorted by price (highest first).
+///
+/// They are cached for `units_for_slot_cache_ttl` (see [`Config`]) within the same generation of the Campaigns,
+/// so paging through the units of the same AdSlot and query is stable while the Campaigns don't change.
+#[derive(Debug, Clone)]
+pub struct MatchedUnits {
+    /// The matched Campaigns without their units
+    campaigns: Vec<(response::Campaign, CampaignDetails)>,
+    /// The sorted and deduplicated units
+    units: Vec<RankedUnit>,
+    /// The archived units (of the AdSlot type) of the Campaigns, which are never matched
+    archived_units: usize,
+    /// Why there are no matched units
+    empty_reason: Option<EmptyReason>,
+    /// The generation of the Campaigns they were matched from, see [`Cache::current`]
+    generation: u64,
+}
+
+#[derive(Debug, Clone)]
+struct RankedUnit {
+    /// The index of the Campaign
+    campaign: usize,
+    unit: response::UnitsWithPrice,
+    score: f64,
+    also_available_in: Vec<ChannelId>,
+}
+
+impl MatchedUnits {
+    pub fn new(mut targeted: Vec<TargetedCampaign>) -> Self {
+        // the Campaigns come from the Cache `HashMap`, so we need a deterministic order
+        targeted.sort_by_cached_key(|targeted| targeted.campaign.channel.id.to_string());
+
+        let mut campaigns = Vec::with_capacity(targeted.len());
+        let mut units = vec![];
+        for (index, mut targeted) in targeted.into_iter().enumerate() {
+            let campaign_units = std::mem::take(&mut targeted.campaign.units_with_price);
+            units.extend(
+                campaign_units
+                    .into_iter()
+                    .zip(targeted.scores)
+                    .map(|(unit, score)| (index, unit, score)),
+            );
+
+            campaigns.push((targeted.campaign, targeted.details));
+        }
+        // stable sort - units with the same price keep their order
+        units.sort_by(|(_, a, _), (_, b, _)| b.price.cmp(&a.price));
+
+        // Deduplicate the units by ipfs, keeping the best-paying one (i.e. the first after sorting).
+        // All the Campaigns are `Active` (see `UnitsForSlotPipeline`), so it's from a healthy Campaign.
+        let mut ranked: Vec<RankedUnit> = vec![];
+        // unit ipfs -> position in the ranked units
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (index, unit, score) in units {
+            match positions.get(&unit.unit.id) {
+                Some(&position) => {
+                    let channel_id = campaigns[index].0.channel.id;
+                    let kept = &mut ranked[position];
+
+                    if kept.campaign != index && !kept.also_available_in.contains(&channel_id) {
+                        kept.also_available_in.push(channel_id);
+                    }
+                }
+                None => {
+                    positions.insert(unit.unit.id.clone(), ranked.len());
+                    ranked.push(RankedUnit {
+                        campaign: index,
+                        unit,
+                        score,
+                        also_available_in: vec![],
+                    });
+                }
+            }
+        }
+
+        Self {
+            campaigns,
+            units: ranked,
+            archived_units: 0,
+            empty_reason: None,
+            generation: 0,
+        }
+    }
+
+    /// Sets the number of the archived units which were left out of the matching
+    pub fn with_archived_units(self, archived_units: usize) -> Self {
+        Self {
+            archived_units,
+            ..self
+        }
+    }
+
+    /// Sets the generation of the Campaigns they were matched from
+    pub fn with_generation(self, generation: u64) -> Self {
+        Self { generation, ..self }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Sets the reason derived from the [`Funnel`](reason::Funnel) of the matching
+    pub fn with_empty_reason(self, empty_reason: Option<EmptyReason>) -> Self {
+        Self {
+            empty_reason,
+            ..self
+        }
+    }
+
+    /// Reorders the units by the selection `strategy`, the `round_robin_offset` is used only by the `round_robin`
+    pub fn with_strategy(mut self, strategy: Strategy, round_robin_offset: usize) -> Self {
+        let mut candidates = self
+            .units
+            .iter()
+            .enumerate()
+            .map(|(index, ranked)| selection::Candidate {
+                index,
+                price: &ranked.unit.price,
+                score: ranked.score,
+            })
+            .collect();
+        selection::select(strategy, round_robin_offset, &mut candidates);
+        let order = candidates
+            .into_iter()
+            .map(|candidate| candidate.index)
+            .collect::<Vec<_>>();
+
+        let mut units = std::mem::take(&mut self.units)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.units = order
+            .into_iter()
+            .filter_map(|index| units[index].take())
+            .collect();
+
+        self
+    }
+
+    /// Ranks the units whose media is known to be unreachable below the rest, keeping their order, or drops them by the `policy`,
+    /// see [`media_check`]
+    pub fn with_unreachable_media(
+        mut self,
+        is_unreachable: impl Fn(&str) -> bool,
+        policy: UnreachableMediaPolicy,
+    ) -> Self {
+        match policy {
+            UnreachableMediaPolicy::Demote => self
+                .units
+                .sort_by_cached_key(|ranked| is_unreachable(&ranked.unit.unit.id)),
+            UnreachableMediaPolicy::Exclude => self
+                .units
+                .retain(|ranked| !is_unreachable(&ranked.unit.unit.id)),
+        }
+
+        self
+    }
+
+    pub fn total(&self) -> usize {
+        self.units.len()
+    }
+
+    pub fn archived_units(&self) -> usize {
+        self.archived_units
+    }
+
+    pub fn empty_reason(&self) -> Option<EmptyReason> {
+        self.empty_reason
+    }
+
+    /// Returns the page:
+    /// - the Campaigns with only their units in the page,
+    /// ordered by the first unit of each Campaign (the highest paying one, unless reordered by the selection strategy)
+    /// - the units in the page with the details of their Campaign (and their score if `debug` is set)
+    pub fn page(
+        &self,
+        pagination: Pagination,
+        debug: bool,
+    ) -> (Vec<response::Campaign>, Vec<MatchedUnit>) {
+        let units = self
+            .units
+            .iter()
+            .skip(pagination.skip)
+            .take(pagination.limit.unwrap_or(usize::MAX));
+
+        let mut campaigns: Vec<response::Campaign> = vec![];
+        let mut matched_units = vec![];
+        // Campaign index -> position in the page
+        let mut positions = HashMap::new();
+        for ranked in units {
+            let (campaign, details) = &self.campaigns[ranked.campaign];
+
+            let position = *positions.entry(ranked.campaign).or_insert_with(|| {
+                campaigns.push(campaign.clone());
+                campaigns.len() - 1
+            });
+
+            campaigns[position]
+                .units_with_price
+                .push(ranked.unit.clone());
+            matched_units.push(MatchedUnit {
+                unit: ranked.unit.clone(),
+                campaign: details.clone(),
+                also_available_in: ranked.also_available_in.clone(),
+                score: if debug { Some(ranked.score) } else { None },
+                last_refreshed: None,
+            });
+        }
+
+        (campaigns, matched_units)
+    }
+}
+
+/// Set instead of the [`DegradationPolicy`] header when fetching the AdUnits of the AdSlot timed out
+const UNITS_TIMEOUT_DEGRADED: &str = "units-timeout";
+
+/// The AdSlot ipfs of the `/units-for-slot/:slotIpfs` path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AdSlotPath<'a> {
+    Ipfs(&'a str),
+    /// `/units-for-slot/` (or any other path) without the ipfs
+    Missing,
+    /// Anything but a single alphanumeric segment after the prefix,
+    /// e.g. additional segments, encoded separators or a repeated prefix
+    Invalid,
+}
+
+impl<'a> AdSlotPath<'a> {
+    pub(crate) fn parse(path: &'a str) -> Self {
+        match path.strip_prefix(ROUTE_UNITS_FOR_SLOT) {
+            None | Some("") => Self::Missing,
+            Some(ipfs) if ipfs.chars().all(|c| c.is_ascii_alphanumeric()) => Self::Ipfs(ipfs),
+            Some(_) => Self::Invalid,
+        }
+    }
+}
+
+/// Whether the request is from a suspected bot, never with the `serve` [`BotPolicy`]
+pub(crate) fn suspected_bot(headers: &HeaderMap, config: &Config) -> bool {
+    match config.bots.policy {
+        BotPolicy::Serve => false,
+        _ => is_suspect_bot(headers, &config.bots.deny_list),
+    }
+}
+
+/// The OS & the browser family of the `user-agent`
+pub(crate) fn parse_user_agent(headers: &HeaderMap) -> (Option<String>, Option<String>) {
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    let parsed = Parser::new().parse(user_agent);
+    // WARNING! This will return only the OS type, e.g. `Linux` and not the actual distribution name e.g. `Ubuntu`
+    // By contrast `ua-parser-js` will return `Ubuntu` (distribution) and not the OS type `Linux`.
+    // `UAParser(...).os.name` (`ua-parser-js: 0.7.22`)
+    let user_agent_os = parsed
+        .as_ref()
+        .map(|p| {
+            if p.os != VALUE_UNKNOWN {
+                Some(p.os.to_string())
+            } else {
+                None
+            }
+        })
+        .flatten();
+
+    // Corresponds to `UAParser(...).browser.name` (`ua-parser-js: 0.7.22`)
+    let user_agent_browser_family = parsed
+        .as_ref()
+        .map(|p| {
+            if p.name != VALUE_UNKNOWN {
+                Some(p.name.to_string())
+            } else {
+                None
+            }
+        })
+        .flatten();
+
+    (user_agent_os, user_agent_browser_family)
+}
+
+/// The `cf-ipcountry` of the request
+pub(crate) fn request_country(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CLOUDFLARE_IPCOUNTY_HEADER.clone())
+        .and_then(|h| h.to_str().map(ToString::to_string).ok())
+}
+
 pub async fn get_units_for_slot<C: Client>(
     logger: &Logger,
     market: Arc<MarketApi>,
@@ -34,317 +655,1386 @@ pub async fn get_units_for_slot<C: Client>(
     cache: &Cache<C>,
     req: Request<Body>,
 ) -> Result<Response<Body>, Error> {
-    let ipfs = req.uri().path().trim_start_matches(ROUTE_UNITS_FOR_SLOT);
-    if ipfs.is_empty() {
-        Ok(not_found())
+    let now = cache.clock().now_utc();
+    let mut response = get_units_for_slot_at(logger, market, config, cache, req, now).await?;
+    record_delivery(cache, &mut response).await;
+
+    Ok(response)
+}
+
+/// Same as [`get_units_for_slot`] but the identical concurrent `GET` requests
+/// (by the AdSlot, the query and the headers used for the targeting) are coalesced,
+/// i.e. only the first one is handled and the rest share its response, see [`CoalescedRequests`].
+///
+/// Successful responses are also shared with the identical requests within the `units_for_slot_coalesce_window`.
+/// The requests with a non-deterministic selection [`Strategy`] (`weighted_random` & `round_robin`) are never coalesced,
+/// each of them is served its own order.
+pub async fn get_units_for_slot_coalesced<C>(
+    logger: Logger,
+    market: Arc<MarketApi>,
+    config: Config,
+    cache: Cache<C>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Error>
+where
+    C: Client + Send + Sync + 'static,
+{
+    let window = config.units_for_slot_coalesce_window;
+    let key = match AdSlotPath::parse(req.uri().path()) {
+        AdSlotPath::Ipfs(ipfs)
+            if req.method() == Method::GET
+                && window > Duration::from_secs(0)
+                && requested_strategy(req.uri().query(), &config).is_deterministic() =>
+        {
+            let suspect_bot = suspected_bot(req.headers(), &config);
+
+            coalesce::coalescing_key(ipfs, req.uri().query(), req.headers(), suspect_bot)
+        }
+        _ => return get_units_for_slot(&logger, market, &config, &cache, req).await,
+    };
+
+    let leader = cache.clone();
+    let now = cache.clock().now_instant();
+    let in_flight =
+        cache
+            .coalesced_requests
+            .write()
+            .await
+            .get_or_start(key, window, now, async move {
+                let cache = leader;
+                let now = cache.clock().now_utc();
+                let response = get_units_for_slot_at(&logger, market, &config, &cache, req, now)
+                    .await
+                    .map_err(Arc::new)?;
+
+                coalesce::SharedResponse::new(response, cache.clock().now_instant())
+                    .await
+                    .map_err(Arc::new)
+            });
+
+    let shared = in_flight.await.map_err(Error::Coalesced)?;
+    // every coalesced request delivers the response, not only the one which did the work
+    let mut response = shared.to_response();
+    record_delivery(&cache, &mut response).await;
+
+    Ok(response)
+}
+
+/// The selection [`Strategy`] of the `GET` request, the malformed queries are refused by [`get_units_for_slot`]
+fn requested_strategy(query: Option<&str>, config: &Config) -> Strategy {
+    UnitsForSlotQuery::parse_with_overridden(query.unwrap_or_default())
+        .ok()
+        .and_then(|(query, _)| query.strategy)
+        .unwrap_or(config.selection.default)
+}
+
+/// Same as [`get_units_for_slot`] but uses the passed `now` as the time of the request.
+///
+/// Both `GET` and `POST` requests go through the same pipeline,
+/// for `POST` the [`RequestInput`] body overrides the values from the headers & query.
+pub async fn get_units_for_slot_at<C: Client>(
+    logger: &Logger,
+    market: Arc<MarketApi>,
+    config: &Config,
+    cache: &Cache<C>,
+    req: Request<Body>,
+    now: DateTime<Utc>,
+) -> Result<Response<Body>, Error> {
+    let started = cache.clock().now_instant();
+    let mut phases = Phases::default();
+    let (req, body) = req.into_parts();
+    // set by the server when the sampling is enabled, see `Sampling`
+    let sampler = req.extensions.get::<sampling::Sampler>();
+    let deadline =
+        deadline::Deadline::from_headers(&req.headers, &config.timeouts.client_deadline, started);
+
+    let ipfs = match AdSlotPath::parse(req.uri.path()) {
+        AdSlotPath::Ipfs(ipfs) => ipfs,
+        AdSlotPath::Missing => return Ok(not_found()),
+        AdSlotPath::Invalid => {
+            return Ok(bad_request(
+                "The path should be `/units-for-slot/:slotIpfs`".to_string(),
+            ))
+        }
+    };
+
+    // consulted before anything else, a blocked AdSlot isn't even fetched from the Market
+    let slot_override = cache
+        .slot_overrides
+        .read()
+        .await
+        .get(ipfs)
+        .cloned()
+        .unwrap_or_default();
+    if slot_override.blocked {
+        debug!(&logger, "Refused a request for a blocked AdSlot"; "AdSlot" => ipfs);
+
+        return Ok(overrides::blocked());
+    }
+
+    let version = match ResponseVersion::from_headers(&req.headers) {
+        Ok(version) => version,
+        Err(requested) => return Ok(version::not_acceptable(&requested)),
+    };
+
+    let suspect_bot = suspected_bot(&req.headers, config);
+    if suspect_bot && config.bots.policy == BotPolicy::Block {
+        debug!(&logger, "Blocked a suspected bot"; "AdSlot" => ipfs);
+
+        return Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .expect("Should create response"));
+    }
+
+    let mut request_input = if req.method == Method::POST {
+        match read_request_input(&req, body).await {
+            Ok(request_input) => request_input,
+            Err(response) => return Ok(response),
+        }
     } else {
-        let ad_slot_response = match market.fetch_slot(&ipfs).await {
-            Ok(Some(response)) => {
-                debug!(&logger, "Fetched AdSlot"; "AdSlot" => ipfs);
-                response
+        RequestInput::default()
+    };
+
+    let raw_query = req.uri.query().unwrap_or_default();
+    let query = match UnitsForSlotQuery::parse_with_overridden(raw_query) {
+        Ok((query, overridden)) => {
+            if !overridden.is_empty() {
+                debug!(&logger, "Repeated query parameters, using their last values"; "parameters" => ?overridden);
             }
-            Ok(None) => {
-                warn!(
-                    &logger,
-                    "AdSlot ({}) not found in Market",
-                    ipfs;
-                    "AdSlot" => ipfs
-                );
-                return Ok(not_found());
+
+            query
+        }
+        Err(malformed) => return Ok(bad_request(malformed.to_string())),
+    };
+    let strategy = match query.strategy {
+        Some(strategy)
+            if strategy != config.selection.default
+                && !config.selection.allowed.contains(&strategy) =>
+        {
+            return Ok(bad_request(format!(
+                "The selection strategy `{}` is not allowed",
+                strategy
+            )))
+        }
+        Some(strategy) => strategy,
+        None => config.selection.default,
+    };
+    let day_time =
+        DayTime::new(now, query.timezone_offset).expect("The offset should be within bounds");
+
+    let consent = Consent::new(&req.headers, query.gdpr_consent.as_deref());
+    if !consent.is_personalized() {
+        debug!(&logger, "Personal inputs are not used"; "consent" => ?consent);
+
+        request_input.publisher_id = None;
+        request_input.segments.clear();
+    }
+    let variables = Variables::new(day_time, &request_input.segments);
+
+    let phase = cache.clock().now_instant();
+    // when fetching the AdUnits times out only the fallback AdUnit is served
+    let mut units_timed_out = false;
+    let cached_slot = match prewarm::cached_slot(cache, config, ipfs).await {
+        prewarm::SlotLookup::Fresh(cached_slot) if cached_slot.units.is_some() => {
+            debug!(&logger, "Using the cached AdSlot"; "AdSlot" => ipfs);
+
+            cached_slot
+        }
+        // cached from a proxied response, without its AdUnits
+        prewarm::SlotLookup::Fresh(proxied) => {
+            debug!(&logger, "Using the AdSlot cached from a proxied response"; "AdSlot" => ipfs);
+
+            let phase = cache.clock().now_instant();
+            let fetch_units = fetch_slot_units(
+                logger,
+                &market,
+                config,
+                cache.clock(),
+                &proxied.slot,
+                deadline,
+            );
+            let units = match fetch_units.await {
+                Ok(units) => units,
+                Err(response) => return Ok(response),
+            };
+            phases.fetch_units = cache.clock().elapsed_since(phase);
+
+            match units {
+                Some(units) => prewarm::cache_units(cache, config, ipfs, &proxied, units).await,
+                None => {
+                    units_timed_out = true;
+                    proxied
+                }
             }
-            Err(err) => {
-                error!(&logger, "Error fetching AdSlot"; "AdSlot" => ipfs, "error" => ?err);
+        }
+        lookup => {
+            if negative_slots::is_negative(cache, config, ipfs).await {
+                debug!(&logger, "The AdSlot is negative cached, it's not requested from the Market"; "AdSlot" => ipfs);
 
-                return Ok(service_unavailable());
+                return Ok(SlotFetchError::NotFound.into_response(config.market.retry_after));
             }
-        };
 
-        let units = match market.fetch_units(&ad_slot_response.slot).await {
-            Ok(units) => units,
-            Err(error) => {
-                error!(&logger, "Error fetching AdUnits for AdSlot"; "AdSlot" => ipfs, "error" => ?error);
+            let stale = lookup.stale();
+            let version = stale.as_ref().map(|stale| &stale.version);
+            let fetch_slot = market.fetch_slot_if_modified(&ipfs, version);
+            let (slot_timeout, by_deadline) = deadline::cap(
+                deadline,
+                config.timeouts.market_fetch_slot,
+                cache.clock().now_instant(),
+            );
+            // `None` if the stale AdSlot is not modified
+            let fetched = match timeout(slot_timeout, fetch_slot).await {
+                Err(_elapsed) if by_deadline => {
+                    debug!(&logger, "The client's deadline ran out while fetching the AdSlot"; "AdSlot" => ipfs);
 
-                return Ok(service_unavailable());
-            }
-        };
+                    return Ok(deadline::exceeded("fetch_slot"));
+                }
+                Err(_elapsed) => {
+                    warn!(&logger, "Fetching the AdSlot timed out"; "AdSlot" => ipfs);
+                    MARKET_FETCH_TIMEOUTS
+                        .with_label_values(&["fetch_slot"])
+                        .inc();
 
-        let accepted_referrers = ad_slot_response.accepted_referrers.clone();
-        let fallback_unit: Option<AdUnit> = match ad_slot_response.slot.fallback_unit.as_ref() {
-            Some(unit_ipfs) => {
-                let ad_unit_response = match market.fetch_unit(&unit_ipfs).await {
-                    Ok(Some(response)) => {
-                        debug!(&logger, "Fetched AdUnit"; "AdUnit" => unit_ipfs);
-                        response
+                    return Ok(
+                        SlotFetchError::MarketUnavailable.into_response(config.market.retry_after)
+                    );
+                }
+                Ok(Ok(SlotFetch::Modified(response, version))) => {
+                    debug!(&logger, "Fetched AdSlot"; "AdSlot" => ipfs);
+                    if stale.is_some() {
+                        SLOT_REVALIDATIONS.with_label_values(&["modified"]).inc();
                     }
-                    Ok(None) => {
-                        warn!(
-                            &logger,
-                            "AdSlot fallback AdUnit ({}) not found in Market",
-                            unit_ipfs;
-                            "AdUnit" => unit_ipfs,
-                            "AdSlot" => ad_slot_response.slot.ipfs,
-                        );
-
-                        return Ok(not_found());
+
+                    Some((response, version))
+                }
+                Ok(Ok(SlotFetch::NotModified)) => None,
+                Ok(Ok(SlotFetch::NotFound)) => {
+                    warn!(
+                        &logger,
+                        "AdSlot ({}) not found in Market",
+                        ipfs;
+                        "AdSlot" => ipfs
+                    );
+                    negative_slots::cache_miss(cache, config, ipfs).await;
+
+                    return Ok(SlotFetchError::NotFound.into_response(config.market.retry_after));
+                }
+                Ok(Err(err)) => {
+                    let slot_error = SlotFetchError::from_error(&err);
+                    error!(&logger, "Error fetching AdSlot"; "AdSlot" => ipfs, "error" => ?err, "reason" => slot_error.as_str());
+
+                    return Ok(slot_error.into_response(config.market.retry_after));
+                }
+            };
+            phases.fetch_slot = cache.clock().elapsed_since(phase);
+
+            match fetched {
+                // only a conditional request, i.e. with the stale AdSlot, is not modified
+                None => {
+                    debug!(&logger, "The cached AdSlot is not modified"; "AdSlot" => ipfs);
+                    let stale = stale.expect("Only the stale AdSlot is revalidated");
+
+                    prewarm::revalidated_slot(cache, config, ipfs, &stale).await
+                }
+                Some((ad_slot_response, version)) => {
+                    let phase = cache.clock().now_instant();
+                    let fetch_units = fetch_slot_units(
+                        logger,
+                        &market,
+                        config,
+                        cache.clock(),
+                        &ad_slot_response,
+                        deadline,
+                    );
+                    let units = match fetch_units.await {
+                        Ok(units) => units,
+                        Err(response) => return Ok(response),
+                    };
+                    phases.fetch_units = cache.clock().elapsed_since(phase);
+
+                    match units {
+                        Some(units) => {
+                            prewarm::cache_slot(
+                                cache,
+                                config,
+                                ipfs,
+                                ad_slot_response,
+                                version,
+                                units,
+                            )
+                            .await
+                        }
+                        // the AdSlot without its AdUnits is not cached
+                        None => {
+                            units_timed_out = true;
+
+                            Arc::new(prewarm::CachedSlot {
+                                rules_complexity: RulesComplexity::of(&ad_slot_response.slot.rules),
+                                slot: ad_slot_response,
+                                units: None,
+                                units_truncated: false,
+                                version,
+                                fetched_at: cache.clock().now_instant(),
+                            })
+                        }
                     }
-                    Err(error) => {
-                        error!(&logger,
-                            "Error when fetching AdSlot fallback AdUnit ({}) from Market",
-                            unit_ipfs;
-                            "AdSlot" => ipfs,
-                            "Fallback AdUnit" => unit_ipfs,
-                            "error" => ?error
-                        );
-
-                        return Ok(service_unavailable());
+                }
+            }
+        }
+    };
+    let ad_slot_response = &cached_slot.slot;
+    // the archived state is cached with the AdSlot, so it's not fetched on every request
+    if ad_slot_response.slot.archived {
+        debug!(&logger, "Refused a request for an archived AdSlot"; "AdSlot" => ipfs);
+
+        return Ok(gone());
+    }
+    // only the existing AdSlots are counted, not every requested path
+    prewarm::count_request(cache, config, ipfs);
+
+    let referrer = Referrer::new(&req.headers, ad_slot_response.slot.website.as_deref());
+    let referrer_mismatch = referrer.is_mismatch(config.require_referrer);
+    if referrer_mismatch && config.strict_referrer_check {
+        debug!(&logger, "Refused a request with a mismatching Referer"; "AdSlot" => ipfs, "referrer" => ?referrer);
+
+        return Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .expect("Should create response"));
+    }
+
+    let phase = cache.clock().now_instant();
+    let accepted_referrers = ad_slot_response.accepted_referrers.clone();
+    let fallback_unit: Option<AdUnit> = match ad_slot_response.slot.fallback_unit.as_ref() {
+        Some(unit_ipfs) => {
+            let fetch_unit = market.fetch_unit(&unit_ipfs);
+            let fetched = match deadline {
+                Some(deadline) => {
+                    match timeout(deadline.remaining(cache.clock().now_instant()), fetch_unit).await
+                    {
+                        Ok(fetched) => fetched,
+                        Err(_elapsed) => {
+                            debug!(&logger, "The client's deadline ran out while fetching the fallback AdUnit"; "AdSlot" => ipfs);
+
+                            return Ok(deadline::exceeded("fetch_units"));
+                        }
                     }
-                };
+                }
+                None => fetch_unit.await,
+            };
+            let ad_unit_response = match fetched {
+                Ok(Some(response)) => {
+                    debug!(&logger, "Fetched AdUnit"; "AdUnit" => unit_ipfs);
+                    response
+                }
+                Ok(None) => {
+                    warn!(
+                        &logger,
+                        "AdSlot fallback AdUnit ({}) not found in Market",
+                        unit_ipfs;
+                        "AdUnit" => unit_ipfs,
+                        "AdSlot" => ad_slot_response.slot.ipfs,
+                    );
 
-                Some(ad_unit_response.unit)
-            }
-            None => None,
+                    return Ok(not_found());
+                }
+                Err(error) => {
+                    error!(&logger,
+                        "Error when fetching AdSlot fallback AdUnit ({}) from Market",
+                        unit_ipfs;
+                        "AdSlot" => ipfs,
+                        "Fallback AdUnit" => unit_ipfs,
+                        "error" => ?error
+                    );
+
+                    return Ok(service_unavailable());
+                }
+            };
+
+            // an archived fallback AdUnit is not shown
+            Some(ad_unit_response.unit).filter(|ad_unit| !ad_unit.archived)
+        }
+        None => None,
+    };
+    phases.fetch_units += cache.clock().elapsed_since(phase);
+
+    let units_count = cached_slot.units.as_ref().map_or(0, Vec::len);
+    debug!(&logger, "Fetched {} AdUnits for AdSlot", units_count; "AdSlot" => ipfs, "truncated" => cached_slot.units_truncated);
+    // For each adUnits apply input
+    let (user_agent_os, user_agent_browser_family) = parse_user_agent(&req.headers);
+    let country = request_country(&req.headers);
+
+    let user_agent_os = request_input.user_agent_os.clone().or(user_agent_os);
+    let user_agent_browser_family = request_input
+        .user_agent_browser_family
+        .clone()
+        .or(user_agent_browser_family);
+    let country = request_input.country.clone().or(country);
+    let deposit_assets = if request_input.accepted_assets.is_empty() {
+        &query.deposit_asset
+    } else {
+        &request_input.accepted_assets
+    };
+
+    let hostname = Url::parse(&ad_slot_response.slot.website.clone().unwrap_or_default())
+        .ok()
+        .and_then(|url| url.host().map(|h| h.to_string()))
+        .unwrap_or_default();
+
+    let publisher_id = request_input
+        .publisher_id
+        .unwrap_or(ad_slot_response.slot.owner);
+
+    // We return those in the result (which means AdView would have those) but we don't actually use them
+    // we do that in order to have the same variables as the validator, so that the `price` is the same
+    let targeting_input_ad_slot = Some(input::AdSlot {
+        categories: tags::normalize_tags(&ad_slot_response.categories, config.tags.case_folding),
+        hostname,
+        alexa_rank: ad_slot_response.alexa_rank,
+    });
+
+    let targeting_input_base = Input {
+        ad_view: None,
+        global: input::Global {
+            ad_slot_id: ad_slot_response.slot.ipfs.clone(),
+            ad_slot_type: ad_slot_response.slot.ad_type.clone(),
+            publisher_id,
+            country,
+            event_type: "IMPRESSION".to_string(),
+            seconds_since_epoch: now,
+            user_agent_os,
+            user_agent_browser_family: user_agent_browser_family.clone(),
+        },
+        ad_unit_id: None,
+        balances: None,
+        channel: None,
+        ad_slot: None,
+    };
+
+    // Without `?type=`s it's the type of the AdSlot, a single type keeps the flat response
+    let mut types = if request_input.types.is_empty() {
+        query.types.clone()
+    } else {
+        request_input.types.clone()
+    };
+    let mut seen = HashSet::new();
+    types.retain(|ad_type| !ad_type.is_empty() && seen.insert(ad_type.clone()));
+    if types.is_empty() {
+        types.push(ad_slot_response.slot.ad_type.clone());
+    }
+    let per_type = types.len() > 1;
+
+    // the policy applies only while the Cache is degraded
+    let degradation = match config.degradation_policy {
+        DegradationPolicy::Strict => None,
+        policy if cache.is_degraded(config).await => Some(policy),
+        _ => None,
+    };
+    if let Some(policy) = degradation {
+        debug!(&logger, "Serving from a degraded Cache"; "AdSlot" => ipfs, "policy" => policy.as_str());
+        DEGRADED_RESPONSES
+            .with_label_values(&[policy.as_str()])
+            .inc();
+    }
+
+    let phase = cache.clock().now_instant();
+    let now = cache.clock().now_instant();
+    // every type is evaluated against the same snapshot of the Campaigns, whatever updates run meanwhile
+    let generation = cache.current().await;
+    // filtered only once and shared by all of the types
+    let mut campaigns_limited_by_earner: Option<Vec<Campaign>> = None;
+    let mut active_campaigns = 0;
+    let mut responses = BTreeMap::new();
+    for ad_type in types {
+        // the pipeline runs against the same AdSlot, but with the requested type
+        let type_slot_response = if ad_type == ad_slot_response.slot.ad_type {
+            Cow::Borrowed(ad_slot_response)
+        } else {
+            let mut type_slot_response = ad_slot_response.clone();
+            type_slot_response.slot.ad_type = ad_type.clone();
+
+            Cow::Owned(type_slot_response)
         };
+        let mut targeting_input_base = targeting_input_base.clone();
+        targeting_input_base.global.ad_slot_type = ad_type.clone();
 
-        debug!(&logger, "Fetched {} AdUnits for AdSlot", units.len(); "AdSlot" => ipfs);
-        let query = req.uri().query().unwrap_or_default();
-        let parsed_query = form_urlencoded::parse(query.as_bytes());
+        let cache_key = matched_units_key(
+            ipfs,
+            &ad_type,
+            &query,
+            deposit_assets,
+            &targeting_input_base,
+            &variables,
+        );
+        // the degraded results are neither cached nor served from the cache
+        let cached_matched_units = match degradation {
+            _ if units_timed_out => None,
+            Some(_) => None,
+            None => cache
+                .matched_units
+                .read()
+                .await
+                .get(&cache_key, now, config.units_for_slot_cache_ttl)
+                .filter(|matched_units| matched_units.generation() == generation.number)
+                .cloned(),
+        };
+
+        let matched_units = match cached_matched_units {
+            Some(matched_units) => {
+                debug!(&logger, "Using cached matched units"; "AdSlot" => ipfs, "type" => &ad_type);
+
+                matched_units
+            }
+            None => {
+                // nobody will read the response of the matching
+                if deadline.map_or(false, |deadline| {
+                    deadline.is_exceeded(cache.clock().now_instant())
+                }) {
+                    debug!(&logger, "The client's deadline ran out before the targeting"; "AdSlot" => ipfs, "type" => &ad_type);
 
-        let deposit_assets: Vec<String> = parsed_query
-            .filter_map(|(key, value)| {
-                if key == "depositAsset" {
-                    Some(value.to_string())
-                } else {
-                    None
+                    return Ok(deadline::exceeded("targeting"));
                 }
-            })
-            .collect();
-        // For each adUnits apply input
-        let ua_parser = Parser::new();
-        let user_agent = req
-            .headers()
-            .get(USER_AGENT)
-            .and_then(|h| h.to_str().map(ToString::to_string).ok())
-            .unwrap_or_default();
-        let parsed = ua_parser.parse(&user_agent);
-        // WARNING! This will return only the OS type, e.g. `Linux` and not the actual distribution name e.g. `Ubuntu`
-        // By contrast `ua-parser-js` will return `Ubuntu` (distribution) and not the OS type `Linux`.
-        // `UAParser(...).os.name` (`ua-parser-js: 0.7.22`)
-        let user_agent_os = parsed
-            .as_ref()
-            .map(|p| {
-                if p.os != VALUE_UNKNOWN {
-                    Some(p.os.to_string())
-                } else {
-                    None
+
+                if campaigns_limited_by_earner.is_none() {
+                    let campaigns = match degradation {
+                        _ if units_timed_out => vec![],
+                        _ if slot_override.force_fallback => vec![],
+                        Some(DegradationPolicy::FallbackOnly) => vec![],
+                        _ => {
+                            let context = pipeline::Context {
+                                config,
+                                refreshed: &generation.refreshed,
+                                follower_balances: &generation.follower_balances,
+                                now,
+                                now_utc: cache.clock().now_utc(),
+                                publisher_id,
+                                deposit_assets,
+                                serve_stale: degradation == Some(DegradationPolicy::ServeStale),
+                                restricted: &generation.restricted,
+                            };
+                            let run = UnitsForSlotPipeline::default().run(
+                                &context,
+                                generation.active.values().map(Arc::as_ref).collect(),
+                            );
+
+                            for drops in run.drops.iter().filter(|drops| drops.dropped > 0) {
+                                debug!(&logger, "Campaigns dropped by the pipeline"; "step" => drops.step, "dropped" => drops.dropped, "remaining" => drops.remaining);
+                                UNITS_FOR_SLOT_DROPPED
+                                    .with_label_values(&[drops.step])
+                                    .inc_by(drops.dropped as u64);
+                            }
+                            active_campaigns = run
+                                .remaining_after(pipeline::STATUS.name)
+                                .unwrap_or_default();
+
+                            run.candidates.into_iter().cloned().collect()
+                        }
+                    };
+
+                    debug!(&logger, "Fetched Cache campaigns limited by earner (publisher)"; "campaigns" => campaigns.len(), "publisher_id" => %publisher_id);
+
+                    campaigns_limited_by_earner = Some(campaigns);
                 }
-            })
-            .flatten();
+                let campaigns = campaigns_limited_by_earner.clone().unwrap_or_default();
+                let archived = archived_units(&campaigns, &ad_type);
+                let (with_units, above_min_price) = count_with_units(
+                    &campaigns,
+                    &ad_type,
+                    &config.limits.global_min_impression_price,
+                    &slot_override,
+                );
+                let eligible = campaigns.len();
 
-        // Corresponds to `UAParser(...).browser.name` (`ua-parser-js: 0.7.22`)
-        let user_agent_browser_family = parsed
-            .as_ref()
-            .map(|p| {
-                if p.name != VALUE_UNKNOWN {
-                    Some(p.name.to_string())
-                } else {
-                    None
+                // the time is bucketed in order for the targeting results to be memoized
+                let mut input_base = targeting_input_base.clone();
+                if config.targeting_memo_size > 0 {
+                    input_base.global.seconds_since_epoch =
+                        memo::bucket_seconds(input_base.global.seconds_since_epoch);
                 }
-            })
-            .flatten();
+                let targeting = Targeting {
+                    config,
+                    logger,
+                    input_base: &input_base,
+                    ad_slot_response: &type_slot_response,
+                    min_score: query.min_score.unwrap_or(config.limits.min_targeting_score),
+                    no_targeting: query.no_targeting,
+                    slot_override: &slot_override,
+                    variables: &variables,
+                    slot_rules_complexity: cached_slot.rules_complexity,
+                };
 
-        let country = req
-            .headers()
-            .get(CLOUDFLARE_IPCOUNTY_HEADER.clone())
-            .and_then(|h| h.to_str().map(ToString::to_string).ok());
+                let campaigns =
+                    apply_targeting_memoized(cache, generation.number, &targeting, campaigns).await;
+                let matched_units = MatchedUnits::new(campaigns)
+                    .with_archived_units(archived)
+                    .with_generation(generation.number);
+                let funnel = reason::Funnel {
+                    units_timed_out,
+                    forced_fallback: slot_override.force_fallback,
+                    fallback_only: degradation == Some(DegradationPolicy::FallbackOnly),
+                    active: active_campaigns,
+                    eligible,
+                    with_units,
+                    above_min_price,
+                    matched: matched_units.total(),
+                };
+                let matched_units = matched_units.with_empty_reason(reason::empty_reason(&funnel));
 
-        let hostname = Url::parse(&ad_slot_response.slot.website.clone().unwrap_or_default())
-            .ok()
-            .and_then(|url| url.host().map(|h| h.to_string()))
-            .unwrap_or_default();
+                if degradation.is_none() && !units_timed_out {
+                    cache.matched_units.write().await.insert(
+                        cache_key,
+                        now,
+                        matched_units.clone(),
+                        config.units_for_slot_cache_ttl,
+                        config.units_for_slot_cache_size,
+                    );
+                }
 
-        let publisher_id = ad_slot_response.slot.owner;
+                matched_units
+            }
+        };
 
-        let campaigns_limited_by_earner =
-            get_campaigns(cache, config, &deposit_assets, publisher_id).await;
+        targeting_input_base.ad_slot = targeting_input_ad_slot.clone();
 
-        debug!(&logger, "Fetched Cache campaigns limited by earner (publisher)"; "campaigns" => campaigns_limited_by_earner.len(), "publisher_id" => %publisher_id);
+        let round_robin_offset = match strategy {
+            Strategy::RoundRobin => {
+                cache
+                    .round_robin
+                    .write()
+                    .await
+                    .next(ipfs, &ad_type, now, &config.selection)
+            }
+            _ => 0,
+        };
+        let matched_units = matched_units.with_strategy(strategy, round_robin_offset);
+        SELECTION_STRATEGIES
+            .with_label_values(&[strategy.as_str()])
+            .inc();
 
-        // We return those in the result (which means AdView would have those) but we don't actually use them
-        // we do that in order to have the same variables as the validator, so that the `price` is the same
-        let targeting_input_ad_slot = Some(input::AdSlot {
-            categories: ad_slot_response.categories.clone(),
-            hostname,
-            alexa_rank: ad_slot_response.alexa_rank,
-        });
+        let media_check = &config.media_check;
+        let matched_units = if media_check.is_enabled() {
+            let checks = cache.media_checks.read().await;
+            matched_units.with_unreachable_media(
+                |unit| checks.is_unreachable(unit, now, media_check.ttl),
+                media_check.policy,
+            )
+        } else {
+            matched_units
+        };
 
-        let mut targeting_input_base = Input {
-            ad_view: None,
-            global: input::Global {
-                ad_slot_id: ad_slot_response.slot.ipfs.clone(),
-                ad_slot_type: ad_slot_response.slot.ad_type.clone(),
-                publisher_id,
-                country,
-                event_type: "IMPRESSION".to_string(),
-                seconds_since_epoch: Utc::now(),
-                user_agent_os,
-                user_agent_browser_family: user_agent_browser_family.clone(),
+        let (campaigns, mut units) = matched_units.page(Pagination::from(&query), query.debug);
+        if media_check.is_enabled() {
+            cache.media_checks.write().await.enqueue(
+                units.iter().map(|matched| &matched.unit.unit),
+                &config.ipfs_gateway,
+                now,
+                media_check.ttl,
+            );
+        }
+        if query.debug {
+            for unit in units.iter_mut() {
+                unit.last_refreshed = generation
+                    .refreshed
+                    .get(&unit.campaign.channel_id)
+                    .map(|refreshed| refreshed.at);
+            }
+        }
+        let mut response = PagedResponse {
+            response: UnitsForSlotResponse {
+                targeting_input_base,
+                accepted_referrers: accepted_referrers.clone(),
+                campaigns,
+                fallback_unit: fallback_unit.as_ref().map(response::AdUnit::from),
+            },
+            total_matched: matched_units.total(),
+            skip: query.skip,
+            limit: query.limit,
+            day_time,
+            personalized: consent.is_personalized(),
+            strategy,
+            suspect_bot,
+            referrer_mismatch,
+            archived_units: if query.debug {
+                Some(matched_units.archived_units())
+            } else {
+                None
             },
-            ad_unit_id: None,
-            balances: None,
-            channel: None,
-            ad_slot: None,
+            generation: if query.debug {
+                Some(matched_units.generation())
+            } else {
+                None
+            },
+            reason: matched_units.empty_reason(),
+            truncated: cached_slot.units_truncated,
+            units,
         };
+        if !query.raw_ipfs {
+            let malformed = ipfs::rewrite_media_urls(&config.ipfs_gateway, &mut response);
+            if malformed > 0 {
+                debug!(&logger, "Malformed ipfs media URLs were left as they are"; "AdSlot" => ipfs, "malformed" => malformed);
+                MALFORMED_IPFS_URLS.inc_by(malformed);
+            }
+        }
+        responses.insert(ad_type, response);
+    }
+
+    phases.targeting = cache.clock().elapsed_since(phase);
+
+    let max_age = config
+        .cache_control
+        .max_age(responses.values().map(|response| response.reason));
+
+    let served = if responses
+        .values()
+        .any(|response| !response.units.is_empty())
+    {
+        publisher_stats::Served::Matched
+    } else if fallback_unit.is_some() {
+        publisher_stats::Served::Fallback
+    } else {
+        publisher_stats::Served::Nothing
+    };
+    let mut delivery = serve_stats::Delivery {
+        publisher: ad_slot_response.slot.owner,
+        served,
+        campaigns: vec![],
+    };
+    for response in responses.values() {
+        let first_page = response.skip == 0;
+        let mut served = HashSet::new();
+        for (position, matched) in response.units.iter().enumerate() {
+            let channel_id = matched.campaign.channel_id;
+            if served.insert(channel_id) {
+                delivery
+                    .campaigns
+                    .push((channel_id, first_page && position == 0));
+            }
+        }
+    }
+
+    if let Some(sampler) = sampler.filter(|sampler| sampler.should_sample()) {
+        let mut targeting_input = targeting_input_base.clone();
+        targeting_input.ad_slot = targeting_input_ad_slot;
+
+        sampler.send(sampling::Sample {
+            time: cache.clock().now_utc(),
+            path: req.uri.path().to_string(),
+            query: req.uri.query().map(ToString::to_string),
+            targeting_input: sampler.targeting_input(&targeting_input),
+            units: responses
+                .values()
+                .flat_map(|response| response.units.iter())
+                .map(|matched| matched.unit.unit.id.clone())
+                .collect(),
+        });
+    }
+
+    let phase = cache.clock().now_instant();
+    let body = if per_type {
+        version.to_json_per_type(responses)?
+    } else {
+        let (_, response) = responses
+            .into_iter()
+            .next()
+            .expect("There is always at least one type");
+
+        version.to_json(response)?
+    };
+    phases.serialization = cache.clock().elapsed_since(phase);
 
-        let campaigns = apply_targeting(
-            config,
-            logger,
-            campaigns_limited_by_earner,
-            targeting_input_base.clone(),
-            ad_slot_response,
+    let total = cache.clock().elapsed_since(started);
+    if total > config.slow_request_threshold {
+        warn!(
+            &logger,
+            "Slow units-for-slot request";
+            "request_id" => request_id(&req.headers),
+            "AdSlot" => ipfs,
+            "slowest_phase" => phases.slowest(),
+            "total_ms" => total.as_millis() as u64,
+            "fetch_slot_ms" => phases.fetch_slot.as_millis() as u64,
+            "fetch_units_ms" => phases.fetch_units.as_millis() as u64,
+            "targeting_ms" => phases.targeting.as_millis() as u64,
+            "serialization_ms" => phases.serialization.as_millis() as u64,
+        );
+    }
+
+    let mut response = Response::builder()
+        .status(http::StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .header(RESPONSE_VERSION_HEADER.clone(), version.as_str());
+    let degraded = match degradation {
+        _ if units_timed_out => Some(UNITS_TIMEOUT_DEGRADED),
+        degradation => degradation.and_then(|policy| policy.degraded_header()),
+    };
+    if let Some(degraded) = degraded {
+        response = response.header(DEGRADED_HEADER.clone(), degraded);
+    }
+    if let Some(max_age) = max_age {
+        response = response.header(CACHE_CONTROL, format!("max-age={}", max_age));
+    }
+
+    // recorded once the response is delivered, see `get_units_for_slot` & `get_units_for_slot_coalesced`
+    Ok(response
+        .extension(delivery)
+        .body(Body::from(body))
+        .expect("Should create response"))
+}
+
+/// Records the [`serve_stats::Delivery`] of the response, if it served the AdSlot
+async fn record_delivery<C: Client>(cache: &Cache<C>, response: &mut Response<Body>) {
+    if let Some(delivery) = response.extensions_mut().remove::<serve_stats::Delivery>() {
+        delivery.record(cache).await;
+    }
+}
+
+/// The time spent in each phase of a units-for-slot request, logged for slow requests
+#[derive(Debug, Default)]
+struct Phases {
+    /// Fetching the AdSlot from the Market
+    fetch_slot: Duration,
+    /// Fetching the AdUnits and the fallback AdUnit from the Market
+    fetch_units: Duration,
+    /// Getting the Campaigns and applying the targeting (or using the cached matched units)
+    targeting: Duration,
+    serialization: Duration,
+}
+
+impl Phases {
+    fn slowest(&self) -> &'static str {
+        let phases = [
+            ("fetch_slot", self.fetch_slot),
+            ("fetch_units", self.fetch_units),
+            ("targeting", self.targeting),
+            ("serialization", self.serialization),
+        ];
+
+        phases
+            .iter()
+            .max_by_key(|(_, duration)| *duration)
+            .map(|(phase, _)| *phase)
+            .expect("There are phases")
+    }
+}
+
+/// Reads the JSON [`RequestInput`] body, on error it returns the response:
+///
+/// - `415 Unsupported Media Type` if the `Content-Type` is not `application/json`
+/// - `413 Payload Too Large` if the body is larger than [`MAX_BODY_SIZE`]
+/// - `400 Bad Request` with the location of the error if the JSON is malformed
+async fn read_request_input(req: &Parts, mut body: Body) -> Result<RequestInput, Response<Body>> {
+    let status_response = |status: StatusCode, message: String| {
+        Response::builder()
+            .status(status)
+            .body(Body::from(message))
+            .expect("Response should be valid")
+    };
+
+    let is_json = req
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+        .unwrap_or(false);
+    if !is_json {
+        return Err(status_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Only `application/json` body is supported".to_string(),
+        ));
+    }
+
+    let too_large = || {
+        status_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The body should be at most {} bytes", MAX_BODY_SIZE),
         )
-        .await;
+    };
+    let mut bytes = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|error| {
+            status_response(StatusCode::BAD_REQUEST, format!("Reading body: {}", error))
+        })?;
 
-        targeting_input_base.ad_slot = targeting_input_ad_slot;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
 
-        let response = UnitsForSlotResponse {
-            targeting_input_base,
-            accepted_referrers,
-            campaigns,
-            fallback_unit: fallback_unit.map(|ad_unit| response::AdUnit::from(&ad_unit)),
-        };
+    serde_json::from_slice(&bytes).map_err(|error| {
+        bad_request(format!(
+            "Malformed JSON body at line {} column {}: {}",
+            error.line(),
+            error.column(),
+            error
+        ))
+    })
+}
 
-        Ok(Response::builder()
-            .status(http::StatusCode::OK)
-            .header(CONTENT_TYPE, "application/json")
-            .body(Body::from(serde_json::to_string(&response)?))
-            .expect("Should create response"))
+/// Fetches the AdUnits of the AdSlot (up to the `max_units_per_slot`) within the `market_fetch_units` timeout, `None` if it timed out.
+/// On error it returns the `503 Service Unavailable` response
+/// and if the client's `deadline` runs out first, the [`deadline::exceeded`] one.
+async fn fetch_slot_units(
+    logger: &Logger,
+    market: &MarketApi,
+    config: &Config,
+    clock: &dyn Clock,
+    ad_slot_response: &AdSlotResponse,
+    deadline: Option<deadline::Deadline>,
+) -> Result<Option<SlotUnits>, Response<Body>> {
+    let ipfs = &ad_slot_response.slot.ipfs;
+    let fetch_units = market.fetch_units(&ad_slot_response.slot, config.limits.max_units_per_slot);
+    let (units_timeout, by_deadline) = deadline::cap(
+        deadline,
+        config.timeouts.market_fetch_units,
+        clock.now_instant(),
+    );
+
+    match timeout(units_timeout, fetch_units).await {
+        Err(_elapsed) if by_deadline => {
+            debug!(logger, "The client's deadline ran out while fetching the AdUnits for AdSlot"; "AdSlot" => ipfs);
+
+            Err(deadline::exceeded("fetch_units"))
+        }
+        Err(_elapsed) => {
+            warn!(logger, "Fetching the AdUnits for AdSlot timed out, serving only the fallback AdUnit"; "AdSlot" => ipfs);
+            MARKET_FETCH_TIMEOUTS
+                .with_label_values(&["fetch_units"])
+                .inc();
+
+            Ok(None)
+        }
+        Ok(Ok(units)) => Ok(Some(units)),
+        Ok(Err(error)) => {
+            error!(logger, "Error fetching AdUnits for AdSlot"; "AdSlot" => ipfs, "error" => ?error);
+
+            Err(service_unavailable())
+        }
     }
 }
 
+/// The key of the [`MatchedUnitsCache`] - the AdSlot with the AdUnit type, the query parameters
+/// which change the matching (sorted by their names) and the targeting inputs derived from the request,
+/// so that the raw query, `User-Agent` or body don't make each request a key of its own.
+fn matched_units_key(
+    ipfs: &str,
+    ad_type: &str,
+    query: &UnitsForSlotQuery,
+    deposit_assets: &[ValidatorId],
+    input_base: &Input,
+    variables: &Variables,
+) -> String {
+    let deposit_assets: BTreeSet<_> = deposit_assets.iter().map(ToString::to_string).collect();
+
+    let mut parameters = form_urlencoded::Serializer::new(String::new());
+    parameters.extend_pairs(
+        deposit_assets
+            .iter()
+            .map(|deposit_asset| ("depositAsset", deposit_asset.as_str())),
+    );
+    if let Some(min_score) = query.min_score {
+        parameters.append_pair("minScore", &min_score.to_string());
+    }
+    if query.no_targeting {
+        parameters.append_pair("noTargeting", "true");
+    }
+
+    let global = &input_base.global;
+    format!(
+        "{}:{}?{}|{}|{}|{}|{}|{}|{}|{}",
+        ipfs,
+        ad_type,
+        parameters.finish(),
+        global.country.as_deref().unwrap_or_default(),
+        global.user_agent_os.as_deref().unwrap_or_default(),
+        global
+            .user_agent_browser_family
+            .as_deref()
+            .unwrap_or_default(),
+        global.publisher_id,
+        variables.hour,
+        variables.day_of_week,
+        serde_json::to_string(&variables.segments).expect("Should serialize the segments")
+    )
+}
+
+/// The Active Campaigns for the publisher selected by the [`UnitsForSlotPipeline`], with `serve_stale`
+/// (see [`DegradationPolicy::ServeStale`]) the ones which weren't refreshed within the `max_campaign_staleness` are included as well.
+#[cfg(test)]
 async fn get_campaigns<C: Client>(
     cache: &Cache<C>,
     config: &Config,
-    deposit_assets: &[String],
+    deposit_assets: &[ValidatorId],
     publisher_id: ValidatorId,
+    serve_stale: bool,
 ) -> Vec<Campaign> {
-    let active_campaigns = cache.active.read().await;
+    let generation = cache.current().await;
+    let context = pipeline::Context {
+        config,
+        refreshed: &generation.refreshed,
+        follower_balances: &generation.follower_balances,
+        now: cache.clock().now_instant(),
+        now_utc: cache.clock().now_utc(),
+        publisher_id,
+        deposit_assets,
+        serve_stale,
+        restricted: &generation.restricted,
+    };
 
-    let (mut campaigns_by_earner, rest_of_campaigns): (Vec<&Campaign>, Vec<&Campaign>) =
-        active_campaigns
-            .iter()
-            .filter_map(|(_, campaign)| {
-                // The Supermarket has the Active status combining Active & Ready from Market
-                if campaign.status == Status::Active
-                    && campaign.channel.creator != publisher_id
-                    && (deposit_assets.is_empty()
-                        || deposit_assets.contains(&campaign.channel.deposit_asset))
-                {
-                    Some(campaign)
-                } else {
-                    None
-                }
-            })
-            .partition(|&campaign| campaign.balances.contains_key(&publisher_id));
+    UnitsForSlotPipeline::default()
+        .run(
+            &context,
+            generation.active.values().map(Arc::as_ref).collect(),
+        )
+        .candidates
+        .into_iter()
+        .cloned()
+        .collect()
+}
 
-    if campaigns_by_earner.len() >= config.limits.max_channels_earning_from.into() {
-        campaigns_by_earner.into_iter().cloned().collect()
-    } else {
-        campaigns_by_earner.extend(rest_of_campaigns.iter());
+/// The number of archived units of the `ad_type` of the `campaigns`, they are left out of the matching
+fn archived_units(campaigns: &[Campaign], ad_type: &str) -> usize {
+    campaigns
+        .iter()
+        .flat_map(|campaign| campaign.channel.spec.ad_units.iter())
+        .filter(|ad_unit| ad_unit.archived && ad_unit.ad_type == ad_type)
+        .count()
+}
 
-        campaigns_by_earner.into_iter().cloned().collect()
-    }
+/// The number of `campaigns` with non-archived units of the `ad_type`
+/// and how many of them have a max IMPRESSION price which is not below the `min_impression_price`
+/// (or the higher one of the [`SlotOverride`] for their deposit asset),
+/// i.e. their units are not dropped by the price regardless of the targeting.
+fn count_with_units(
+    campaigns: &[Campaign],
+    ad_type: &str,
+    min_impression_price: &BigNum,
+    slot_override: &SlotOverride,
+) -> (usize, usize) {
+    let with_units = campaigns
+        .iter()
+        .filter(|campaign| {
+            campaign
+                .channel
+                .spec
+                .ad_units
+                .iter()
+                .filter(|ad_unit| ad_unit.ad_type == ad_type)
+                .any(pipeline::is_unit_matchable)
+        })
+        .collect::<Vec<_>>();
+    let above_min_price = with_units
+        .iter()
+        .filter(|campaign| {
+            &get_pricing_bounds(&campaign.channel, "IMPRESSION").max
+                >= slot_override
+                    .min_impression_price(min_impression_price, &campaign.channel.deposit_asset)
+        })
+        .count();
+
+    (with_units.len(), above_min_price)
 }
 
+/// Applies the [`Targeting`] to the `campaigns` without memoizing the results
+#[cfg(test)]
 async fn apply_targeting(
     config: &Config,
     logger: &Logger,
     campaigns: Vec<Campaign>,
     input_base: Input,
     ad_slot_response: AdSlotResponse,
-) -> Vec<response::Campaign> {
-    campaigns
+    min_score: f64,
+    no_targeting: bool,
+) -> Vec<TargetedCampaign> {
+    let variables = Variables::new(
+        DayTime::new(input_base.global.seconds_since_epoch, 0).expect("Valid offset"),
+        &[],
+    );
+    let targeting = Targeting {
+        config,
+        logger,
+        input_base: &input_base,
+        ad_slot_response: &ad_slot_response,
+        min_score,
+        no_targeting,
+        slot_override: &SlotOverride::default(),
+        variables: &variables,
+        slot_rules_complexity: RulesComplexity::of(&ad_slot_response.slot.rules),
+    };
+    if targeting.slot_rules_over_limits() {
+        return vec![];
+    }
+
+    targeting.campaigns(campaigns)
+}
+
+/// Same as [`apply_targeting`] but reuses the results memoized in the [`Cache::targeting_memo`]
+/// for the same AdSlot, Campaign and targeting inputs, as long as the Active Campaigns haven't changed,
+/// i.e. the `campaigns` are of the current `generation` (see [`Cache::current`]).
+async fn apply_targeting_memoized<C: Client>(
+    cache: &Cache<C>,
+    generation: u64,
+    targeting: &Targeting<'_>,
+    campaigns: Vec<Campaign>,
+) -> Vec<TargetedCampaign> {
+    if targeting.slot_rules_over_limits() {
+        return vec![];
+    }
+
+    let capacity = targeting.config.targeting_memo_size;
+    let fingerprint = match targeting.fingerprint() {
+        Some(fingerprint) if capacity > 0 => fingerprint,
+        _ => return targeting.campaigns(campaigns),
+    };
+
+    let ipfs = &targeting.ad_slot_response.slot.ipfs;
+    // only a newer generation needs the write lock, for dropping the results
+    if cache.targeting_memo.read().await.generation() < generation {
+        cache.targeting_memo.write().await.sync(generation);
+    }
+    let memoized = {
+        let memo = cache.targeting_memo.read().await;
+        let is_current = memo.generation() == generation;
+
+        campaigns
+            .into_iter()
+            .map(|campaign| {
+                let key = (ipfs.clone(), campaign.channel.id, fingerprint);
+                let targeted = if is_current { memo.get(&key) } else { None };
+
+                (key, campaign, targeted)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut evaluated = vec![];
+    let targeted_campaigns = memoized
         .into_iter()
-        .filter_map::<response::Campaign, _>(|campaign| {
-            let ad_units = campaign
-                .channel
-                .spec
-                .ad_units
-                .iter()
-                .filter(|ad_unit| ad_unit.ad_type == ad_slot_response.slot.ad_type)
-                .cloned()
-                .collect::<Vec<_>>();
+        .filter_map(|(key, campaign, targeted)| match targeted {
+            Some(targeted) => targeted,
+            None => {
+                let targeted = targeting.campaign(campaign);
+                evaluated.push((key, targeted.clone()));
 
-            if ad_units.is_empty() {
-                None
+                targeted
+            }
+        })
+        .collect();
+
+    if !evaluated.is_empty() {
+        let mut memo = cache.targeting_memo.write().await;
+        for (key, targeted) in evaluated {
+            memo.insert(generation, key, targeted, capacity);
+        }
+    }
+
+    targeted_campaigns
+}
+
+/// The targeting of a single request, which is the same for all of the Campaigns.
+///
+/// The targeting score of a unit is the `boost` of the [`Output`] after applying
+/// the Campaign and the AdSlot rules (starting at `1.0`).
+/// Units with a score lower than `min_score` are dropped.
+///
+/// With `no_targeting` the targeting rules of the Campaigns are not applied,
+/// only the AdSlot rules.
+///
+/// Units with a price lower than the `global_min_impression_price` are dropped,
+/// or than the higher `min_price` of the [`SlotOverride`] for the deposit asset of their Campaign.
+///
+/// The tags the targeting rules compare the AdSlot tags with are normalized, see [`tags`],
+/// and the variables which aren't part of the [`Input`] are replaced with their values, see [`variables`].
+pub(crate) struct Targeting<'a> {
+    pub(crate) config: &'a Config,
+    pub(crate) logger: &'a Logger,
+    pub(crate) input_base: &'a Input,
+    pub(crate) ad_slot_response: &'a AdSlotResponse,
+    pub(crate) min_score: f64,
+    pub(crate) no_targeting: bool,
+    pub(crate) slot_override: &'a SlotOverride,
+    pub(crate) variables: &'a Variables,
+    /// Of the AdSlot rules, see [`CachedSlot::rules_complexity`](prewarm::CachedSlot::rules_complexity)
+    pub(crate) slot_rules_complexity: RulesComplexity,
+}
+
+impl Targeting<'_> {
+    /// Hashes the inputs of the targeting which are the same for all of the Campaigns,
+    /// the `seconds_since_epoch` should be bucketed (see [`memo::bucket_seconds`]) beforehand.
+    ///
+    /// Returns `None` if the inputs cannot be serialized.
+    pub(crate) fn fingerprint(&self) -> Option<u64> {
+        let inputs = serde_json::to_string(&(
+            self.input_base,
+            &self.ad_slot_response.slot.ad_type,
+            &self.ad_slot_response.slot.rules,
+            self.min_score,
+            self.no_targeting,
+            &self.config.limits.global_min_impression_price,
+            &self.slot_override.min_price,
+            &self.config.tags,
+            self.variables,
+        ))
+        .ok()?;
+
+        let mut hasher = DefaultHasher::new();
+        inputs.hash(&mut hasher);
+
+        Some(hasher.finish())
+    }
+
+    /// AdSlot rules exceeding the [`RulesComplexity`] limits are not evaluated and none of the units are shown,
+    /// the Campaigns with such rules are skipped when they're discovered instead.
+    /// They are logged and counted in the [`ADSLOT_RULES_OVER_LIMITS`].
+    ///
+    /// The complexity is computed once with the cached AdSlot, the substituted variables are accounted for
+    /// by the budget of the evaluation instead, see [`evaluation`].
+    pub(crate) fn slot_rules_over_limits(&self) -> bool {
+        let complexity = self.slot_rules_complexity;
+        if complexity.is_within_limits() {
+            return false;
+        }
+
+        ADSLOT_RULES_OVER_LIMITS.inc();
+        warn!(
+            self.logger,
+            "The AdSlot targeting rules are too complex, no units are shown";
+            "ipfs" => &self.ad_slot_response.slot.ipfs,
+            "depth" => complexity.depth,
+            "nodes" => complexity.nodes,
+        );
+
+        true
+    }
+
+    pub(crate) fn campaigns(&self, campaigns: Vec<Campaign>) -> Vec<TargetedCampaign> {
+        campaigns
+            .into_iter()
+            .filter_map(|campaign| self.campaign(campaign))
+            .collect()
+    }
+
+    /// Applies the targeting to the units of the Campaign, except for the archived ones,
+    /// returns `None` if none of them matched
+    pub(crate) fn campaign(&self, campaign: Campaign) -> Option<TargetedCampaign> {
+        let ad_units = campaign
+            .channel
+            .spec
+            .ad_units
+            .iter()
+            .filter(|ad_unit| {
+                ad_unit.ad_type == self.ad_slot_response.slot.ad_type
+                    && pipeline::is_unit_matchable(ad_unit)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if ad_units.is_empty() {
+            None
+        } else {
+            let targeting_rules = if self.no_targeting {
+                Rules(vec![])
+            } else if !campaign.channel.targeting_rules.is_empty() {
+                campaign.channel.targeting_rules.clone()
             } else {
-                let targeting_rules = if !campaign.channel.targeting_rules.is_empty() {
-                    campaign.channel.targeting_rules.clone()
-                } else {
-                    campaign.channel.spec.targeting_rules.clone()
+                campaign.channel.spec.targeting_rules.clone()
+            };
+            // served with the normalized tags of the AdSlot, see `tags`, and the values of the variables
+            let targeting_rules = evaluation::BudgetedRules::new(
+                self.variables.substitute(tags::normalize_rules(
+                    targeting_rules,
+                    self.config.tags.case_folding,
+                )),
+                evaluation::RulesOf::Campaign,
+            );
+            let slot_rules = evaluation::BudgetedRules::new(
+                self.variables
+                    .substitute(self.ad_slot_response.slot.rules.clone()),
+                evaluation::RulesOf::AdSlot,
+            );
+            let campaign_input = self
+                .input_base
+                .clone()
+                .with_channel(campaign.channel.clone());
+
+            let (matching_units, scores): (Vec<response::UnitsWithPrice>, Vec<f64>) = ad_units
+            .into_iter()
+            .filter_map(|ad_unit| {
+                let mut unit_input = campaign_input.clone();
+                unit_input.ad_unit_id = Some(ad_unit.ipfs.clone());
+
+                let pricing_bounds = get_pricing_bounds(&campaign.channel, "IMPRESSION");
+                let mut output = Output {
+                    show: true,
+                    boost: 1.0,
+                    // only "IMPRESSION" event can be used for this `Output`
+                    price: vec![("IMPRESSION".to_string(), pricing_bounds.min.clone())]
+                        .into_iter()
+                        .collect(),
                 };
-                let campaign_input = input_base.clone().with_channel(campaign.channel.clone());
 
-                let matching_units: Vec<response::UnitsWithPrice> = ad_units
-                    .into_iter()
-                    .filter_map(|ad_unit| {
-                        let mut unit_input = campaign_input.clone();
-                        unit_input.ad_unit_id = Some(ad_unit.ipfs.clone());
-
-                        let pricing_bounds = get_pricing_bounds(&campaign.channel, "IMPRESSION");
-                        let mut output = Output {
-                            show: true,
-                            boost: 1.0,
-                            // only "IMPRESSION" event can be used for this `Output`
-                            price: vec![("IMPRESSION".to_string(), pricing_bounds.min.clone())]
-                                .into_iter()
-                                .collect(),
-                        };
-
-                        let on_type_error_campaign = |error, rule| error!(logger, "Rule evaluation error for {:?}", campaign.channel.id; "error" => ?error, "rule" => ?rule);
-                        eval_with_callback(&targeting_rules, &unit_input, &mut output, Some(on_type_error_campaign));
-
-                        if !output.show {
-                            return None;
-                        }
+                // shared by the Campaign & the AdSlot rules, see `evaluation`
+                let mut budget = evaluation::MAX_EVALUATION_STEPS;
+                let on_type_error_campaign = |error, rule| error!(self.logger, "Rule evaluation error for {:?}", campaign.channel.id; "error" => ?error, "rule" => ?rule);
+                targeting_rules.eval(&unit_input, &mut output, &mut budget, on_type_error_campaign);
 
-                        let max_price = match output.price.get("IMPRESSION") {
-                            Some(output_price) => output_price.min(&pricing_bounds.max).clone(),
-                            None => pricing_bounds.max,
-                        };
-                        let price = pricing_bounds.min.max(max_price);
+                if !output.show {
+                    return None;
+                }
 
-                        if price < config.limits.global_min_impression_price {
-                            return None;
-                        }
+                let max_price = match output.price.get("IMPRESSION") {
+                    Some(output_price) => output_price.min(&pricing_bounds.max).clone(),
+                    None => pricing_bounds.max,
+                };
+                let price = pricing_bounds.min.max(max_price);
 
-                        // Execute the adSlot rules after we've taken the price since they're not
-                        // allowed to change the price
-                        let on_type_error_adslot = |error, rule| error!(logger, "Rule evaluation error AdSlot {:?}", ad_slot_response.slot.ipfs; "error" => ?error, "rule" => ?rule);
+                let min_price = self.slot_override.min_impression_price(
+                    &self.config.limits.global_min_impression_price,
+                    &campaign.channel.deposit_asset,
+                );
+                if &price < min_price {
+                    return None;
+                }
 
-                        eval_with_callback(&ad_slot_response.slot.rules, &unit_input, &mut output, Some(on_type_error_adslot));
-                        if !output.show {
-                            return None;
-                        }
+                // Execute the adSlot rules after we've taken the price since they're not
+                // allowed to change the price
+                let on_type_error_adslot = |error, rule| error!(self.logger, "Rule evaluation error AdSlot {:?}", self.ad_slot_response.slot.ipfs; "error" => ?error, "rule" => ?rule);
 
-                        let ad_unit = response::AdUnit::from(&ad_unit);
+                slot_rules.eval(&unit_input, &mut output, &mut budget, on_type_error_adslot);
+                if !output.show {
+                    return None;
+                }
+
+                let score = output.boost;
+                if score < self.min_score {
+                    return None;
+                }
+
+                let ad_unit = response::AdUnit::from(&ad_unit);
+
+                Some((response::UnitsWithPrice {
+                    unit: ad_unit,
+                    price,
+                }, score))
+            })
+            .unzip();
 
-                        Some(response::UnitsWithPrice {
-                            unit: ad_unit,
-                            price,
-                        })
-                    })
-                    .collect();
+            if matching_units.is_empty() {
+                None
+            } else {
+                let details = CampaignDetails::from(&campaign.channel);
 
-                if matching_units.is_empty() {
-                    None
-                } else {
-                    Some(response::Campaign {
+                Some(TargetedCampaign {
+                    campaign: response::Campaign {
                         channel: campaign.channel.into(),
-                        targeting_rules,
+                        targeting_rules: targeting_rules.into_rules(),
                         units_with_price: matching_units,
-                    })
-                }
+                    },
+                    details,
+                    scores,
+                })
             }
-        })
-        .collect()
+        }
+    }
 }
@@ -0,0 +1,213 @@
+use super::{parse_user_agent, request_country, serve_stats::Delivery};
+use crate::Error;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use http::{HeaderMap, StatusCode};
+use hyper::{body::Bytes, Body, Response};
+use std::{collections::HashMap, fmt, future::Future, sync::Arc, time::Duration};
+use tokio::time::Instant;
+use url::form_urlencoded;
+
+/// The headers which change the units-for-slot response, besides the path & query
+/// and the values derived from the client's headers (see [`coalescing_key`])
+const KEY_HEADERS: [&str; 4] = [
+    "accept",
+    "referer",
+    "dnt",
+    // a deadline may abort the request, see `deadline`
+    "x-request-timeout-ms",
+];
+
+/// A units-for-slot response which can be shared between the coalesced requests
+#[derive(Debug, Clone)]
+pub struct SharedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Recorded by each of the requests it's delivered to, see [`Delivery::record`]
+    delivery: Option<Delivery>,
+    /// Successful responses are shared with the identical requests for the coalescing window after this moment
+    completed_at: Instant,
+}
+
+impl SharedResponse {
+    pub async fn new(response: Response<Body>, completed_at: Instant) -> Result<Self, Error> {
+        let (mut parts, body) = response.into_parts();
+
+        Ok(Self {
+            status: parts.status,
+            headers: parts.headers,
+            body: hyper::body::to_bytes(body).await?,
+            delivery: parts.extensions.remove(),
+            completed_at,
+        })
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        if let Some(delivery) = &self.delivery {
+            response.extensions_mut().insert(delivery.clone());
+        }
+
+        response
+    }
+}
+
+type SharedResult = Result<SharedResponse, Arc<Error>>;
+type InFlight = Shared<BoxFuture<'static, SharedResult>>;
+
+/// The in-flight (and briefly the completed) units-for-slot requests, keyed by [`coalescing_key`].
+///
+/// The first request does the work, while the identical concurrent requests await the same future.
+/// Errors and non-successful responses are shared only with the requests which were already waiting,
+/// the next identical request starts over.
+#[derive(Default)]
+pub struct CoalescedRequests {
+    in_flight: HashMap<String, InFlight>,
+}
+
+impl fmt::Debug for CoalescedRequests {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CoalescedRequests")
+            .field("in_flight", &self.in_flight.len())
+            .finish()
+    }
+}
+
+impl CoalescedRequests {
+    /// Returns the in-flight future for the `key` or starts the `request`.
+    pub fn get_or_start<F>(
+        &mut self,
+        key: String,
+        window: Duration,
+        now: Instant,
+        request: F,
+    ) -> InFlight
+    where
+        F: Future<Output = SharedResult> + Send + 'static,
+    {
+        // drop the completed requests which can't be shared anymore
+        self.in_flight
+            .retain(|_, in_flight| is_shareable(in_flight, window, now));
+
+        self.in_flight
+            .entry(key)
+            .or_insert_with(|| request.boxed().shared())
+            .clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+}
+
+/// The request is still in-flight or it has successfully completed within the `window` before `now`
+fn is_shareable(in_flight: &InFlight, window: Duration, now: Instant) -> bool {
+    match in_flight.peek() {
+        None => true,
+        Some(Ok(response)) => {
+            response.status.is_success()
+                && now.saturating_duration_since(response.completed_at) < window
+        }
+        Some(Err(_)) => false,
+    }
+}
+
+/// The AdSlot ipfs, the query (with sorted parameters), the [`KEY_HEADERS`] and the targeting inputs
+/// derived from the client's headers: the country, the `user-agent` OS & browser family and the bot verdict.
+///
+/// The client's IP & raw `user-agent` are not part of the key, so the requests of different clients
+/// with the same derived inputs are coalesced as well.
+pub fn coalescing_key(
+    ipfs: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    suspect_bot: bool,
+) -> String {
+    let mut pairs =
+        form_urlencoded::parse(query.unwrap_or_default().as_bytes()).collect::<Vec<_>>();
+    pairs.sort();
+
+    let mut key = form_urlencoded::Serializer::new(format!("{}?", ipfs));
+    key.extend_pairs(pairs);
+    let (user_agent_os, user_agent_browser_family) = parse_user_agent(headers);
+    key.append_pair("country", &request_country(headers).unwrap_or_default());
+    key.append_pair("os", &user_agent_os.unwrap_or_default());
+    key.append_pair("browser", &user_agent_browser_family.unwrap_or_default());
+    key.append_pair("bot", &suspect_bot.to_string());
+    for name in KEY_HEADERS.iter() {
+        for value in headers.get_all(*name) {
+            key.append_pair(name, &String::from_utf8_lossy(value.as_bytes()));
+        }
+    }
+
+    key.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn the_query_is_normalized_in_the_key() {
+        let headers = HeaderMap::new();
+
+        assert_eq!(
+            coalescing_key("Qm", Some("limit=10&skip=0"), &headers, false),
+            coalescing_key("Qm", Some("skip=0&limit=10"), &headers, false),
+        );
+        assert_ne!(
+            coalescing_key("Qm", Some("limit=10"), &headers, false),
+            coalescing_key("Qm", Some("limit=20"), &headers, false),
+        );
+        assert_ne!(
+            coalescing_key("Qm", None, &headers, false),
+            coalescing_key("QmOther", None, &headers, false),
+        );
+
+        let mut from_bg = HeaderMap::new();
+        from_bg.insert("cf-ipcountry", HeaderValue::from_static("BG"));
+        assert_ne!(
+            coalescing_key("Qm", None, &headers, false),
+            coalescing_key("Qm", None, &from_bg, false),
+        );
+    }
+
+    #[test]
+    fn only_the_derived_client_inputs_are_in_the_key() {
+        let client = |ip: &'static str, user_agent: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("cf-connecting-ip", HeaderValue::from_static(ip));
+            headers.insert("x-forwarded-for", HeaderValue::from_static(ip));
+            headers.insert("user-agent", HeaderValue::from_static(user_agent));
+            headers.insert("cf-ipcountry", HeaderValue::from_static("BG"));
+
+            headers
+        };
+        let firefox_80 =
+            "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:80.0) Gecko/20100101 Firefox/80.0";
+        let firefox_81 =
+            "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:81.0) Gecko/20100101 Firefox/81.0";
+        let chrome = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/86.0.4240.75 Safari/537.36";
+
+        // the IP & the browser version aren't targeting inputs
+        assert_eq!(
+            coalescing_key("Qm", None, &client("1.1.1.1", firefox_80), false),
+            coalescing_key("Qm", None, &client("2.2.2.2", firefox_81), false),
+        );
+        assert_ne!(
+            coalescing_key("Qm", None, &client("1.1.1.1", firefox_80), false),
+            coalescing_key("Qm", None, &client("1.1.1.1", chrome), false),
+        );
+        assert_ne!(
+            coalescing_key("Qm", None, &client("1.1.1.1", firefox_80), false),
+            coalescing_key("Qm", None, &client("1.1.1.1", firefox_80), true),
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use http::HeaderMap;
+
+/// The TCF (Transparency & Consent Framework) versions accepted for the `?gdpr_consent=` string
+const TCF_VERSIONS: [u8; 2] = [1, 2];
+
+/// The consent of the viewer for personalizing the units-for-slot response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consent {
+    /// The `DNT: 1` header is set, it takes precedence over the consent string
+    DoNotTrack,
+    /// No `?gdpr_consent=` TCF string
+    Missing,
+    /// The `?gdpr_consent=` is not a valid TCF string
+    Invalid,
+    /// A valid `?gdpr_consent=` TCF string
+    Given,
+}
+
+impl Consent {
+    pub fn new(headers: &HeaderMap, gdpr_consent: Option<&str>) -> Self {
+        let do_not_track = headers
+            .get("dnt")
+            .and_then(|dnt| dnt.to_str().ok())
+            .map(|dnt| dnt.trim() == "1")
+            .unwrap_or(false);
+
+        match gdpr_consent {
+            _ if do_not_track => Self::DoNotTrack,
+            None => Self::Missing,
+            Some(tcf_string) if is_valid_tcf_string(tcf_string) => Self::Given,
+            Some(_) => Self::Invalid,
+        }
+    }
+
+    /// Personal inputs (publisher address, interest segments) are used only with a given consent
+    pub fn is_personalized(&self) -> bool {
+        *self == Self::Given
+    }
+}
+
+/// Shallow validation of the TCF string, without parsing it:
+/// - every segment (separated by `.`) is a non-empty base64url string (without padding)
+/// - the version, i.e. the first 6 bits (= the first base64url character), is a known TCF version
+fn is_valid_tcf_string(tcf_string: &str) -> bool {
+    let valid_segments = tcf_string.split('.').all(|segment| {
+        !segment.is_empty() && segment.bytes().all(|byte| base64url_value(byte).is_some())
+    });
+
+    let version = tcf_string.bytes().next().and_then(base64url_value);
+
+    valid_segments && version.map_or(false, |version| TCF_VERSIONS.contains(&version))
+}
+
+/// The 6 bits value of a base64url character
+fn base64url_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'A'..=b'Z' => Some(byte - b'A'),
+        b'a'..=b'z' => Some(byte - b'a' + 26),
+        b'0'..=b'9' => Some(byte - b'0' + 52),
+        b'-' => Some(62),
+        b'_' => Some(63),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    /// An example TCF v2 string from the IAB specification
+    const TCF_V2: &str = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAAAAAAAA.IFoEUQQgAIQwgIwQABAEAAAAOIAACAIAAAAQAIAgEAACEAAAAAgAQBAAAAAAAGBAAgAAAAAAAAFAAECAAAgAAQARAEQAAAAAJAAIAAgAAAYQEAAAQmAgBC3ZAYzUw";
+
+    #[test]
+    fn the_three_consent_states() {
+        let no_headers = HeaderMap::new();
+
+        assert_eq!(Consent::Given, Consent::new(&no_headers, Some(TCF_V2)));
+        assert!(Consent::new(&no_headers, Some(TCF_V2)).is_personalized());
+
+        assert_eq!(Consent::Missing, Consent::new(&no_headers, None));
+        assert!(!Consent::Missing.is_personalized());
+
+        for invalid in &[
+            "",
+            "not base64!",
+            "COvFyGBOvFyGB=",
+            "A0000",
+            "D0000",
+            "COvF..IFoE",
+        ] {
+            let consent = Consent::new(&no_headers, Some(invalid));
+
+            assert_eq!(Consent::Invalid, consent, "{}", invalid);
+            assert!(!consent.is_personalized());
+        }
+
+        let mut dnt = HeaderMap::new();
+        dnt.insert("DNT", HeaderValue::from_static("1"));
+        assert_eq!(Consent::DoNotTrack, Consent::new(&dnt, Some(TCF_V2)));
+        assert_eq!(Consent::DoNotTrack, Consent::new(&dnt, None));
+        assert!(!Consent::DoNotTrack.is_personalized());
+
+        // only `DNT: 1` means do not track
+        let mut tracking_allowed = HeaderMap::new();
+        tracking_allowed.insert("DNT", HeaderValue::from_static("0"));
+        assert_eq!(
+            Consent::Given,
+            Consent::new(&tracking_allowed, Some(TCF_V2))
+        );
+    }
+
+    #[test]
+    fn tcf_versions() {
+        // version 1 - `B`
+        assert!(is_valid_tcf_string("BOEFEAyOEFEAyAHABDENAI4AAAB9vABAASA"));
+        // version 2 - `C`
+        assert!(is_valid_tcf_string(TCF_V2));
+        // version 3 - `D`
+        assert!(!is_valid_tcf_string("DOEFEAyOEFEAyAHABDENAI4AAAB9vABAASA"));
+    }
+}
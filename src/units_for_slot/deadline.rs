@@ -0,0 +1,141 @@
+//! The deadline of a units-for-slot request set by the client with the [`REQUEST_TIMEOUT_HEADER`],
+//! e.g. the SDK gives up after 300ms.
+//!
+//! The remaining time caps the timeouts of fetching the AdSlot & its AdUnits from the Market
+//! and it's checked before the targeting, when it runs out the request is aborted (see [`exceeded`])
+//! instead of completing work nobody will read.
+use crate::{config::ClientDeadline, metrics::DEADLINES_EXCEEDED};
+use http::{
+    header::{HeaderName, CONTENT_TYPE},
+    HeaderMap, StatusCode,
+};
+use hyper::{Body, Response};
+use std::time::Duration;
+use tokio::time::Instant;
+
+lazy_static::lazy_static! {
+    /// The client's timeout in milliseconds, clamped to the [`ClientDeadline`]
+    pub static ref REQUEST_TIMEOUT_HEADER: HeaderName = HeaderName::from_static("x-request-timeout-ms");
+}
+
+/// The moment the client gives up on the request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// The deadline of the request `started` at that moment, by the [`REQUEST_TIMEOUT_HEADER`] clamped to the `bounds`.
+    /// `None` without the header (or with a malformed one), the usual timeouts apply.
+    pub fn from_headers(
+        headers: &HeaderMap,
+        bounds: &ClientDeadline,
+        started: Instant,
+    ) -> Option<Self> {
+        let millis = headers
+            .get(&*REQUEST_TIMEOUT_HEADER)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        let timeout = Duration::from_millis(millis)
+            .max(bounds.min)
+            .min(bounds.max);
+
+        Some(Self(started + timeout))
+    }
+
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.0.saturating_duration_since(now)
+    }
+
+    pub fn is_exceeded(&self, now: Instant) -> bool {
+        now >= self.0
+    }
+}
+
+/// The `timeout` capped by the remaining time of the `deadline` (if any)
+/// and whether it's the deadline which is the shorter of the two
+pub fn cap(deadline: Option<Deadline>, timeout: Duration, now: Instant) -> (Duration, bool) {
+    match deadline.map(|deadline| deadline.remaining(now)) {
+        Some(remaining) if remaining < timeout => (remaining, true),
+        _ => (timeout, false),
+    }
+}
+
+/// `504 Gateway Timeout` with the `deadline_exceeded` reason, counted in the [`DEADLINES_EXCEEDED`] by the `phase`
+pub fn exceeded(phase: &str) -> Response<Body> {
+    DEADLINES_EXCEEDED.with_label_values(&[phase]).inc();
+
+    Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({ "reason": "deadline_exceeded" }).to_string(),
+        ))
+        .expect("Should create the deadline exceeded response")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn deadline(header: Option<&'static str>, started: Instant) -> Option<Deadline> {
+        let mut headers = HeaderMap::new();
+        if let Some(header) = header {
+            headers.insert(
+                REQUEST_TIMEOUT_HEADER.clone(),
+                HeaderValue::from_static(header),
+            );
+        }
+        let bounds = ClientDeadline {
+            min: Duration::from_millis(50),
+            max: Duration::from_millis(1000),
+        };
+
+        Deadline::from_headers(&headers, &bounds, started)
+    }
+
+    #[test]
+    fn the_client_timeout_is_clamped_to_the_bounds() {
+        let started = Instant::now();
+        let after = |millis| Some(Deadline(started + Duration::from_millis(millis)));
+
+        assert_eq!(after(300), deadline(Some("300"), started));
+        assert_eq!(after(50), deadline(Some("0"), started));
+        assert_eq!(after(1000), deadline(Some("60000"), started));
+        for header in [None, Some("soon"), Some("-1"), Some("0.3")].iter() {
+            assert_eq!(None, deadline(*header, started), "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn the_remaining_time_caps_the_timeouts() {
+        let started = Instant::now();
+        let timeout = Duration::from_millis(1000);
+        let now = started + Duration::from_millis(100);
+
+        assert_eq!((timeout, false), cap(None, timeout, now));
+        assert_eq!(
+            (Duration::from_millis(200), true),
+            cap(deadline(Some("300"), started), timeout, now)
+        );
+        // longer than the timeout
+        assert_eq!(
+            (Duration::from_millis(500), false),
+            cap(
+                deadline(Some("5000"), started),
+                Duration::from_millis(500),
+                now
+            )
+        );
+
+        let deadline = deadline(Some("300"), started).expect("Should have a deadline");
+        assert!(!deadline.is_exceeded(now));
+        assert!(deadline.is_exceeded(started + Duration::from_millis(300)));
+        assert_eq!(
+            Duration::from_millis(0),
+            deadline.remaining(started + Duration::from_millis(400))
+        );
+    }
+}
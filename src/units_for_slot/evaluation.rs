@@ -0,0 +1,213 @@
+//! Evaluating the targeting rules of a unit within a budget of steps.
+//!
+//! The rules are checked against the [`RulesComplexity`] limits when they're discovered (or the AdSlot is fetched),
+//! but substituting the [`Variables`](super::Variables) of the request, e.g. the viewer's `segments`, can grow them past those.
+//! The rule engine recurses through every node of the rules, so each rule spends its nodes from the budget of the unit
+//! before it's evaluated and the ones which don't fit (or are nested too deep) aren't evaluated at all.
+use crate::{
+    cache::validation::{RulesComplexity, MAX_RULES_DEPTH, MAX_RULES_NODES},
+    metrics::RULES_EVALUATION_BUDGET_EXHAUSTED,
+};
+use primitives::targeting::{eval_with_callback, Error, Input, Output, Rule, Rules};
+
+/// The most steps (functions & values) evaluated for a unit, the Campaign & the AdSlot rules together
+pub const MAX_EVALUATION_STEPS: usize = 2 * MAX_RULES_NODES;
+
+/// Whose rules are evaluated, the `rules` label of [`RULES_EVALUATION_BUDGET_EXHAUSTED`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RulesOf {
+    Campaign,
+    AdSlot,
+}
+
+impl RulesOf {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Campaign => "campaign",
+            Self::AdSlot => "adslot",
+        }
+    }
+}
+
+/// The targeting rules with the [`RulesComplexity`] of each of them,
+/// computed once per Campaign and evaluated for each of its units
+#[derive(Debug, Clone)]
+pub struct BudgetedRules {
+    rules: Rules,
+    complexities: Vec<RulesComplexity>,
+    of: RulesOf,
+}
+
+impl BudgetedRules {
+    pub fn new(rules: Rules, of: RulesOf) -> Self {
+        let complexities = RulesComplexity::of_each(&rules.0);
+
+        Self {
+            rules,
+            complexities,
+            of,
+        }
+    }
+
+    pub fn into_rules(self) -> Rules {
+        self.rules
+    }
+
+    /// Evaluates the rules one after the other like [`eval_with_callback`], until one of them hides the unit.
+    ///
+    /// Each rule spends its nodes from the `budget` before it's evaluated. A rule which doesn't fit in what's left of it
+    /// or is nested deeper than [`MAX_RULES_DEPTH`] hides the unit (`show` is `false`) without being evaluated,
+    /// counted in [`RULES_EVALUATION_BUDGET_EXHAUSTED`].
+    pub fn eval<F: Fn(Error, &Rule)>(
+        &self,
+        input: &Input,
+        output: &mut Output,
+        budget: &mut usize,
+        on_type_error: F,
+    ) {
+        for (rule, complexity) in self.rules.0.iter().zip(self.complexities.iter()) {
+            if complexity.nodes > *budget || complexity.depth > MAX_RULES_DEPTH {
+                output.show = false;
+                RULES_EVALUATION_BUDGET_EXHAUSTED
+                    .with_label_values(&[self.of.as_str()])
+                    .inc();
+
+                return;
+            }
+            *budget -= complexity.nodes;
+
+            eval_with_callback(
+                std::slice::from_ref(rule),
+                input,
+                output,
+                Some(&on_type_error),
+            );
+            if !output.show {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::units_for_slot::{DayTime, Variables};
+    use primitives::{targeting::input, util::tests::prep_db::IDS, BigNum};
+
+    fn input() -> Input {
+        Input {
+            ad_view: None,
+            global: input::Global {
+                ad_slot_id: "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C".to_string(),
+                ad_slot_type: "legacy_250x250".to_string(),
+                publisher_id: IDS["publisher"],
+                country: None,
+                event_type: "IMPRESSION".to_string(),
+                seconds_since_epoch: chrono::Utc::now(),
+                user_agent_os: None,
+                user_agent_browser_family: None,
+            },
+            ad_unit_id: None,
+            balances: None,
+            channel: None,
+            ad_slot: None,
+        }
+    }
+
+    fn shown_output() -> Output {
+        Output {
+            show: true,
+            boost: 1.0,
+            price: vec![("IMPRESSION".to_string(), BigNum::from(1))]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Doubles the boost of the viewers in the `sports` segment, with `segments` substituted
+    fn sports_fans(segments: usize) -> BudgetedRules {
+        let rules: Rules = serde_json::from_value(serde_json::json!([
+            { "if": [
+                { "intersects": [{ "get": "segments" }, ["sports"]] },
+                { "set": ["boost", { "mul": [{ "get": "boost" }, 2.0] }] }
+            ] },
+        ]))
+        .expect("Should deserialize the rules");
+        let segments = (0..segments)
+            .map(|segment| format!("segment-{}", segment))
+            .chain(std::iter::once("sports".to_string()))
+            .collect::<Vec<_>>();
+        let day_time = DayTime::new(chrono::Utc::now(), 0).expect("Valid offset");
+
+        BudgetedRules::new(
+            Variables::new(day_time, &segments).substitute(rules),
+            RulesOf::Campaign,
+        )
+    }
+
+    #[test]
+    fn the_rules_within_the_budget_are_evaluated() {
+        let rules = sports_fans(10);
+        let nodes = RulesComplexity::of(&rules.rules.0).nodes;
+
+        let mut output = shown_output();
+        let mut budget = MAX_EVALUATION_STEPS;
+        rules.eval(&input(), &mut output, &mut budget, |_error, _rule| {});
+
+        assert!(output.show);
+        assert!((output.boost - 2.0).abs() < f64::EPSILON);
+        assert_eq!(MAX_EVALUATION_STEPS - nodes, budget);
+    }
+
+    #[test]
+    fn the_rules_exhausting_the_budget_hide_the_unit() {
+        let exhausted = || {
+            RULES_EVALUATION_BUDGET_EXHAUSTED
+                .with_label_values(&[RulesOf::Campaign.as_str()])
+                .get()
+        };
+        let before = exhausted();
+
+        // the substituted segments grow the rules past the budget
+        let rules = sports_fans(MAX_EVALUATION_STEPS);
+        let mut output = shown_output();
+        let mut budget = MAX_EVALUATION_STEPS;
+        rules.eval(&input(), &mut output, &mut budget, |_error, _rule| {});
+
+        assert!(!output.show);
+        assert!((output.boost - 1.0).abs() < f64::EPSILON);
+        assert_eq!(MAX_EVALUATION_STEPS, budget, "Nothing should be evaluated");
+        assert!(exhausted() > before);
+
+        // what's left of the budget after the Campaign rules
+        let rules = sports_fans(10);
+        let mut output = shown_output();
+        let mut budget = 5;
+        rules.eval(&input(), &mut output, &mut budget, |_error, _rule| {});
+
+        assert!(!output.show);
+    }
+
+    #[test]
+    fn the_rules_nested_too_deep_hide_the_unit() {
+        let mut boost = serde_json::json!({ "get": "boost" });
+        for _ in 0..MAX_RULES_DEPTH {
+            boost = serde_json::json!({ "mul": [boost, 1.1] });
+        }
+        let rules: Rules = serde_json::from_value(serde_json::json!([{ "set": ["boost", boost] }]))
+            .expect("Should deserialize the rules");
+
+        let mut output = shown_output();
+        let mut budget = MAX_EVALUATION_STEPS;
+        BudgetedRules::new(rules, RulesOf::AdSlot).eval(
+            &input(),
+            &mut output,
+            &mut budget,
+            |_error, _rule| {},
+        );
+
+        assert!(!output.show);
+        assert!((output.boost - 1.0).abs() < f64::EPSILON);
+    }
+}
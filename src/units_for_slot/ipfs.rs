@@ -0,0 +1,158 @@
+//! Rewriting the `ipfs://` media URLs of the AdUnits to URLs of the IPFS gateway
+use primitives::{supermarket::units_for_slot::response, util::ApiUrl};
+use thiserror::Error;
+
+use super::PagedResponse;
+
+const IPFS_SCHEME: &str = "ipfs:";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Malformed ipfs URI: {0}")]
+pub struct MalformedIpfs(pub String);
+
+/// Rewrites `ipfs://<hash>` (with an optional subpath, query or fragment) to `<gateway>ipfs/<hash>`,
+/// `ipfs://ipfs/<hash>` is accepted as well.
+///
+/// Returns `None` for any other URL (e.g. an already `https://` one), which should be left as it is.
+pub fn gateway_url(gateway: &ApiUrl, media_url: &str) -> Result<Option<String>, MalformedIpfs> {
+    let media_url = media_url.trim();
+    let malformed = || MalformedIpfs(media_url.to_string());
+
+    let is_ipfs = media_url
+        .get(..IPFS_SCHEME.len())
+        .map_or(false, |scheme| scheme.eq_ignore_ascii_case(IPFS_SCHEME));
+    if !is_ipfs {
+        return Ok(None);
+    }
+
+    let content = media_url[IPFS_SCHEME.len()..]
+        .strip_prefix("//")
+        .ok_or_else(malformed)?;
+    let content = content.strip_prefix("ipfs/").unwrap_or(content);
+
+    let hash_end = content.find(&['/', '?', '#'][..]).unwrap_or(content.len());
+    let (hash, rest) = content.split_at(hash_end);
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(malformed());
+    }
+
+    gateway
+        .join(&format!("ipfs/{}{}", hash, rest))
+        .map(|url| Some(url.to_string()))
+        .map_err(|_| malformed())
+}
+
+/// Rewrites the media URLs of the units, the units of the Campaigns and the fallback unit of the response.
+///
+/// The malformed `ipfs://` URLs are left as they are, returns their number.
+pub fn rewrite_media_urls(gateway: &ApiUrl, response: &mut PagedResponse) -> u64 {
+    let units = response
+        .response
+        .campaigns
+        .iter_mut()
+        .flat_map(|campaign| campaign.units_with_price.iter_mut())
+        .chain(response.units.iter_mut().map(|matched| &mut matched.unit))
+        .map(|unit_with_price| &mut unit_with_price.unit)
+        .chain(response.response.fallback_unit.as_mut());
+
+    units
+        .map(|unit| rewrite_media_url(gateway, unit))
+        .filter(|rewritten| !rewritten)
+        .count() as u64
+}
+
+/// `false` if the media URL is a malformed `ipfs://` one
+fn rewrite_media_url(gateway: &ApiUrl, unit: &mut response::AdUnit) -> bool {
+    match gateway_url(gateway, &unit.media_url) {
+        Ok(Some(url)) => {
+            unit.media_url = url;
+
+            true
+        }
+        Ok(None) => true,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "QmcUVX7fvoLMM93uN2bD3wGTH8MXSxeL8hojYfL2Lhp7mR";
+
+    #[test]
+    fn rewrites_the_ipfs_urls() {
+        let gateway: ApiUrl = "https://ipfs.adex.network/".parse().expect("Valid URL");
+        let gateway_url = |media_url: &str| gateway_url(&gateway, media_url);
+        let expected = |path: &str| -> Result<_, MalformedIpfs> {
+            Ok(Some(format!("https://ipfs.adex.network/ipfs/{}", path)))
+        };
+
+        // ipfs URIs
+        assert_eq!(expected(HASH), gateway_url(&format!("ipfs://{}", HASH)));
+        assert_eq!(expected(HASH), gateway_url(&format!("IPFS://{}", HASH)));
+        assert_eq!(expected(HASH), gateway_url(&format!(" ipfs://{} ", HASH)));
+        assert_eq!(
+            expected(HASH),
+            gateway_url(&format!("ipfs://ipfs/{}", HASH))
+        );
+        // subpaths, queries & fragments
+        let subpath = format!("{}/banner/300x250.png", HASH);
+        assert_eq!(
+            expected(&subpath),
+            gateway_url(&format!("ipfs://{}", subpath))
+        );
+        let with_query = format!("{}?filename=banner.png#top", HASH);
+        assert_eq!(
+            expected(&with_query),
+            gateway_url(&format!("ipfs://{}", with_query))
+        );
+        assert_eq!(
+            expected(&format!("{}/", HASH)),
+            gateway_url(&format!("ipfs://{}/", HASH))
+        );
+
+        // other URLs are left as they are
+        for media_url in &[
+            format!("https://ipfs.adex.network/ipfs/{}", HASH),
+            format!("http://example.com/{}", HASH),
+            "data:image/png;base64,iVBORw0KGgo=".to_string(),
+            "/relative/banner.png".to_string(),
+            String::new(),
+        ] {
+            assert_eq!(Ok(None), gateway_url(media_url), "{}", media_url);
+        }
+
+        // malformed ipfs URIs
+        for media_url in &[
+            "ipfs://".to_string(),
+            "ipfs:///banner.png".to_string(),
+            format!("ipfs:{}", HASH),
+            format!("ipfs://{}!", HASH),
+            format!("ipfs://ipfs.io/ipfs/{}", HASH),
+            "ipfs://ipfs/".to_string(),
+        ] {
+            assert_eq!(
+                Err(MalformedIpfs(media_url.to_string())),
+                gateway_url(media_url),
+                "{}",
+                media_url
+            );
+        }
+    }
+
+    #[test]
+    fn gateways_with_a_path_are_supported() {
+        let gateway: ApiUrl = "https://cdn.adex.network/gateway/"
+            .parse()
+            .expect("Valid URL");
+
+        assert_eq!(
+            Ok(Some(format!(
+                "https://cdn.adex.network/gateway/ipfs/{}",
+                HASH
+            ))),
+            gateway_url(&gateway, &format!("ipfs://{}", HASH))
+        );
+    }
+}
@@ -0,0 +1,273 @@
+//! Checking in the background whether the `ipfs://` media of the served AdUnits can still be fetched
+//! from the IPFS gateway (e.g. it's no longer pinned), so the publishers don't render broken ads.
+//! See [`MediaCheck`](crate::config::MediaCheck).
+//!
+//! The units-for-slot requests only read the known results and queue the media of the AdUnits they serve
+//! (see [`MediaChecks::enqueue`]), the `HEAD` requests to the gateway are made by [`check_next`], one at a time.
+use super::ipfs;
+use crate::{
+    cache::{Cache, Client},
+    metrics::MEDIA_CHECKS,
+};
+use primitives::{supermarket::units_for_slot::response, util::ApiUrl};
+use slog::{debug, warn, Logger};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// At most this many media URLs wait to be checked, the rest are queued by the later requests
+pub const MAX_PENDING_CHECKS: usize = 1_000;
+/// At most this many results are kept, the expired ones are dropped first
+pub const MAX_CHECKED_UNITS: usize = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reachability {
+    /// The gateway responded with a `2xx` or a `3xx`
+    Reachable,
+    /// The gateway responded with a `4xx`, e.g. `404 Not Found`
+    Unreachable,
+}
+
+/// The results of the checks and the media URLs waiting to be checked, by the AdUnit ipfs
+#[derive(Debug, Default)]
+pub struct MediaChecks {
+    checked: HashMap<String, (Instant, Reachability)>,
+    /// The AdUnit ipfs and the gateway URL of its media, in the order they were queued
+    pending: VecDeque<(String, String)>,
+    /// The pending AdUnits and the one being checked
+    queued: HashSet<String>,
+}
+
+impl MediaChecks {
+    /// Whether the media of the AdUnit was found unreachable within the `ttl`
+    pub fn is_unreachable(&self, unit: &str, now: Instant, ttl: Duration) -> bool {
+        match self.checked.get(unit) {
+            Some((checked_at, Reachability::Unreachable)) => {
+                now.saturating_duration_since(*checked_at) < ttl
+            }
+            _ => false,
+        }
+    }
+
+    /// Queues the `ipfs://` media of the AdUnits which weren't checked within the `ttl`,
+    /// the other media URLs are not fetched from the `gateway`
+    pub fn enqueue<'a>(
+        &mut self,
+        units: impl IntoIterator<Item = &'a response::AdUnit>,
+        gateway: &ApiUrl,
+        now: Instant,
+        ttl: Duration,
+    ) {
+        for unit in units {
+            if self.pending.len() >= MAX_PENDING_CHECKS {
+                return;
+            }
+
+            let is_known = self.checked.get(&unit.id).map_or(false, |(checked_at, _)| {
+                now.saturating_duration_since(*checked_at) < ttl
+            });
+            if is_known || self.queued.contains(&unit.id) {
+                continue;
+            }
+
+            if let Ok(Some(url)) = ipfs::gateway_url(gateway, &unit.media_url) {
+                self.queued.insert(unit.id.clone());
+                self.pending.push_back((unit.id.clone(), url));
+            }
+        }
+    }
+
+    /// The next AdUnit & media URL to check, it stays queued until it's [`MediaChecks::record`]ed
+    pub fn next(&mut self) -> Option<(String, String)> {
+        self.pending.pop_front()
+    }
+
+    /// Records the result of the check, `None` if it failed (e.g. a timeout or a `5xx` of the gateway),
+    /// then the AdUnit is queued again by the next request serving it
+    pub fn record(
+        &mut self,
+        unit: String,
+        reachability: Option<Reachability>,
+        now: Instant,
+        ttl: Duration,
+    ) {
+        self.queued.remove(&unit);
+
+        if let Some(reachability) = reachability {
+            if self.checked.len() >= MAX_CHECKED_UNITS {
+                self.checked
+                    .retain(|_, (checked_at, _)| now.saturating_duration_since(*checked_at) < ttl);
+            }
+            if self.checked.len() < MAX_CHECKED_UNITS {
+                self.checked.insert(unit, (now, reachability));
+            }
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Checks the media of the next queued AdUnit with a `HEAD` request (the `client` has the timeout),
+/// counted in the [`MEDIA_CHECKS`] by the result.
+///
+/// Returns the checked AdUnit and the result, `None` if nothing is queued.
+pub async fn check_next<C: Client>(
+    logger: &Logger,
+    client: &reqwest::Client,
+    cache: &Cache<C>,
+    ttl: Duration,
+) -> Option<(String, Option<Reachability>)> {
+    let (unit, url) = cache.media_checks.write().await.next()?;
+
+    let reachability = match client.head(&url).send().await {
+        Ok(response) if response.status().is_client_error() => {
+            warn!(logger, "The media of an AdUnit is unreachable"; "unit" => &unit, "url" => &url, "status" => response.status().as_u16());
+
+            Some(Reachability::Unreachable)
+        }
+        Ok(response) if response.status().is_server_error() => {
+            debug!(logger, "Checking the media of an AdUnit failed"; "unit" => &unit, "url" => &url, "status" => response.status().as_u16());
+
+            None
+        }
+        Ok(_) => Some(Reachability::Reachable),
+        Err(error) => {
+            debug!(logger, "Checking the media of an AdUnit failed"; "unit" => &unit, "url" => &url, "error" => %error);
+
+            None
+        }
+    };
+
+    let result = match reachability {
+        Some(Reachability::Reachable) => "reachable",
+        Some(Reachability::Unreachable) => "unreachable",
+        None => "failed",
+    };
+    MEDIA_CHECKS.with_label_values(&[result]).inc();
+
+    let now = cache.clock().now_instant();
+    cache
+        .media_checks
+        .write()
+        .await
+        .record(unit.clone(), reachability, now, ttl);
+
+    Some((unit, reachability))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cache::MockClient, config::DEVELOPMENT, util::test::discard_logger};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn unit(id: &str, media_url: &str) -> response::AdUnit {
+        let mut unit = response::AdUnit::from(&primitives::util::tests::prep_db::DUMMY_AD_UNITS[0]);
+        unit.id = id.to_string();
+        unit.media_url = media_url.to_string();
+
+        unit
+    }
+
+    #[test]
+    fn only_the_ipfs_media_which_isnt_known_is_queued() {
+        let gateway: ApiUrl = "https://ipfs.adex.network/".parse().expect("Valid gateway");
+        let ttl = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut checks = MediaChecks::default();
+
+        let units = vec![
+            unit("QmPinned", "ipfs://QmPinnedMedia"),
+            unit("QmHttps", "https://adex.network/banner.png"),
+            unit("QmMalformed", "ipfs://"),
+            unit("QmPinned", "ipfs://QmPinnedMedia"),
+        ];
+        checks.enqueue(&units, &gateway, now, ttl);
+        assert_eq!(1, checks.pending());
+
+        let (id, url) = checks.next().expect("Should be queued");
+        assert_eq!("QmPinned", id);
+        assert_eq!("https://ipfs.adex.network/ipfs/QmPinnedMedia", url);
+        // it's being checked
+        checks.enqueue(&units, &gateway, now, ttl);
+        assert_eq!(0, checks.pending());
+
+        checks.record(id, Some(Reachability::Unreachable), now, ttl);
+        assert!(checks.is_unreachable("QmPinned", now, ttl));
+        checks.enqueue(&units, &gateway, now, ttl);
+        assert_eq!(0, checks.pending());
+
+        // checked again once the result expires
+        let later = now + ttl;
+        assert!(!checks.is_unreachable("QmPinned", later, ttl));
+        checks.enqueue(&units, &gateway, later, ttl);
+        assert_eq!(1, checks.pending());
+    }
+
+    #[tokio::test]
+    async fn unreachable_media_is_recorded() {
+        let gateway = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .and(path("/ipfs/QmPinnedMedia"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&gateway)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/ipfs/QmUnpinnedMedia"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&gateway)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/ipfs/QmFailingMedia"))
+            .respond_with(ResponseTemplate::new(502))
+            .mount(&gateway)
+            .await;
+        let gateway_url: ApiUrl = format!("{}/", gateway.uri())
+            .parse()
+            .expect("Valid gateway");
+
+        let logger = discard_logger();
+        let cache = Cache::initialize(MockClient::init(vec![], vec![], None).await).await;
+        let client = reqwest::Client::new();
+        let ttl = DEVELOPMENT.media_check.ttl;
+        let now = cache.clock().now_instant();
+
+        let units = vec![
+            unit("QmPinned", "ipfs://QmPinnedMedia"),
+            unit("QmUnpinned", "ipfs://QmUnpinnedMedia"),
+            unit("QmFailing", "ipfs://QmFailingMedia"),
+        ];
+        cache
+            .media_checks
+            .write()
+            .await
+            .enqueue(&units, &gateway_url, now, ttl);
+
+        let mut results = vec![];
+        while let Some(checked) = check_next(&logger, &client, &cache, ttl).await {
+            results.push(checked);
+        }
+        assert_eq!(
+            vec![
+                ("QmPinned".to_string(), Some(Reachability::Reachable)),
+                ("QmUnpinned".to_string(), Some(Reachability::Unreachable)),
+                ("QmFailing".to_string(), None),
+            ],
+            results
+        );
+
+        let checks = cache.media_checks.read().await;
+        assert!(!checks.is_unreachable("QmPinned", now, ttl));
+        assert!(checks.is_unreachable("QmUnpinned", now, ttl));
+        // the failed check is retried with the next request
+        assert!(!checks.is_unreachable("QmFailing", now, ttl));
+        assert!(!checks.queued.contains("QmFailing"));
+    }
+}
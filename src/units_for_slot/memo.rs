@@ -0,0 +1,208 @@
+use super::TargetedCampaign;
+use primitives::ChannelId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The granularity of the `secondsSinceEpoch` of the targeting input when memoizing,
+/// otherwise no two requests would share the same input.
+pub const SECONDS_BUCKET: u64 = 60;
+
+/// When the capacity is reached, `1 / EVICTION_FRACTION` of it (the least recently used results) is evicted at once,
+/// so finding them is amortized over the next insertions instead of scanning all the results for every one of them.
+pub const EVICTION_FRACTION: usize = 8;
+
+/// The AdSlot ipfs, the Campaign and the fingerprint of the targeting input (see [`Targeting::fingerprint`](super::Targeting::fingerprint))
+pub type MemoKey = (String, ChannelId, u64);
+
+/// A memoized result and the tick of its last use
+#[derive(Debug)]
+struct Memoized {
+    last_used: AtomicU64,
+    targeted: Option<TargetedCampaign>,
+}
+
+/// The memoized targeting results of the Campaigns per AdSlot.
+///
+/// The results are valid only for one generation of the Campaigns in the [`Cache`](crate::cache::Cache),
+/// once it changes all of them are dropped.
+/// When the `capacity` is reached the least recently used results are evicted, see [`EVICTION_FRACTION`].
+///
+/// The recency is tracked with atomics, so the results are looked up with only a read lock of the memo.
+#[derive(Debug, Default)]
+pub struct TargetingMemo {
+    generation: u64,
+    /// Increased on every access, used for finding the least recently used results
+    tick: AtomicU64,
+    entries: HashMap<MemoKey, Memoized>,
+}
+
+impl TargetingMemo {
+    /// The generation of the Campaigns the results are of
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Drops all of the results if there is a newer `generation` of the Campaigns.
+    ///
+    /// Returns `false` if the memoized results are of a newer generation than the passed one.
+    pub fn sync(&mut self, generation: u64) -> bool {
+        if generation > self.generation {
+            self.entries.clear();
+            self.generation = generation;
+        }
+
+        self.generation == generation
+    }
+
+    pub fn get(&self, key: &MemoKey) -> Option<Option<TargetedCampaign>> {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+
+        self.entries.get(key).map(|memoized| {
+            memoized.last_used.fetch_max(tick, Ordering::Relaxed);
+
+            memoized.targeted.clone()
+        })
+    }
+
+    /// The result is not memoized if it was evaluated for another `generation` of the Campaigns
+    pub fn insert(
+        &mut self,
+        generation: u64,
+        key: MemoKey,
+        targeted: Option<TargetedCampaign>,
+        capacity: usize,
+    ) {
+        if capacity == 0 || self.generation != generation {
+            return;
+        }
+
+        if self.entries.len() >= capacity && !self.entries.contains_key(&key) {
+            self.evict(capacity);
+        }
+
+        let tick = self.tick.get_mut();
+        *tick += 1;
+        let memoized = Memoized {
+            last_used: AtomicU64::new(*tick),
+            targeted,
+        };
+        self.entries.insert(key, memoized);
+    }
+
+    /// Evicts the least recently used results, at least enough to make room for a new one
+    fn evict(&mut self, capacity: usize) {
+        let count = (self.entries.len() + 1)
+            .saturating_sub(capacity)
+            .max(capacity / EVICTION_FRACTION)
+            .max(1)
+            .min(self.entries.len());
+        if count == 0 {
+            return;
+        }
+
+        let mut last_used = self
+            .entries
+            .values_mut()
+            .map(|memoized| *memoized.last_used.get_mut())
+            .collect::<Vec<_>>();
+        last_used.sort_unstable();
+        // every access has its own tick
+        let threshold = last_used[count - 1];
+
+        self.entries
+            .retain(|_, memoized| *memoized.last_used.get_mut() > threshold);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Rounds down the `seconds_since_epoch` to the [`SECONDS_BUCKET`]
+pub fn bucket_seconds(seconds_since_epoch: u64) -> u64 {
+    seconds_since_epoch - seconds_since_epoch % SECONDS_BUCKET
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(slot: &str, channel: u8) -> MemoKey {
+        (slot.to_string(), ChannelId::from([channel; 32]), 1)
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut memo = TargetingMemo::default();
+
+        memo.insert(0, key("slot", 1), None, 2);
+        memo.insert(0, key("slot", 2), None, 2);
+        // use the first one, so the second one is the least recently used
+        assert!(memo.get(&key("slot", 1)).is_some());
+
+        memo.insert(0, key("slot", 3), None, 2);
+
+        assert_eq!(2, memo.len());
+        assert!(memo.get(&key("slot", 1)).is_some());
+        assert!(memo.get(&key("slot", 2)).is_none());
+        assert!(memo.get(&key("slot", 3)).is_some());
+    }
+
+    #[test]
+    fn evicts_a_fraction_of_the_capacity_at_once() {
+        let mut memo = TargetingMemo::default();
+        let capacity = 4 * EVICTION_FRACTION;
+
+        for channel in 0..capacity {
+            memo.insert(0, key("slot", channel as u8), None, capacity);
+        }
+        // use the first ones, so they're kept
+        for channel in 0..4 {
+            assert!(memo.get(&key("slot", channel)).is_some());
+        }
+
+        memo.insert(0, key("slot", 200), None, capacity);
+
+        assert_eq!(capacity - 4 + 1, memo.len());
+        for channel in 0..4 {
+            assert!(memo.get(&key("slot", channel)).is_some());
+        }
+        // the least recently used ones
+        for channel in 4..8 {
+            assert!(memo.get(&key("slot", channel)).is_none());
+        }
+        assert!(memo.get(&key("slot", 8)).is_some());
+        assert!(memo.get(&key("slot", 200)).is_some());
+    }
+
+    #[test]
+    fn a_new_generation_drops_the_results() {
+        let mut memo = TargetingMemo::default();
+        assert!(memo.sync(1));
+        memo.insert(1, key("slot", 1), None, 10);
+        // evaluated with the previous generation
+        memo.insert(0, key("slot", 2), None, 10);
+
+        assert!(memo.sync(1));
+        assert!(memo.get(&key("slot", 2)).is_none());
+        assert!(memo.get(&key("slot", 1)).is_some());
+
+        assert!(memo.sync(2));
+        assert!(memo.is_empty());
+        // a request with the older Campaigns doesn't drop the newer results
+        memo.insert(2, key("slot", 1), None, 10);
+        assert!(!memo.sync(1));
+        assert_eq!(1, memo.len());
+    }
+
+    #[test]
+    fn buckets_the_seconds() {
+        assert_eq!(1_600_000_020, bucket_seconds(1_600_000_020));
+        assert_eq!(1_600_000_020, bucket_seconds(1_600_000_079));
+        assert_eq!(1_600_000_080, bucket_seconds(1_600_000_080));
+    }
+}
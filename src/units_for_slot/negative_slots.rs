@@ -0,0 +1,637 @@
+//! Caching the AdSlots which the Market responded with `404 Not Found`, so their requests don't hit the Market
+//! for the [`NegativeSlots.ttl`](crate::config::NegativeSlots::ttl).
+//!
+//! A just created AdSlot (e.g. while a publisher is onboarding) is often requested before it's in the Market,
+//! so the most recent misses are revalidated in the background for the first `revalidate_for` after their first miss,
+//! `first_interval` after it and then twice as long after each revalidation (e.g. 5s, 15s, 35s, 75s, ...).
+//! The AdSlot is cached (see [`prewarm`](super::prewarm)) the moment the Market returns it.
+use crate::{
+    cache::{Cache, Client},
+    config::NegativeSlots,
+    market::{self, SlotFetch, SlotUnits, SlotVersion},
+    metrics::{NEGATIVE_SLOT_HITS, NEGATIVE_SLOT_REVALIDATIONS},
+    units_for_slot::prewarm,
+    Config, MarketApi,
+};
+use futures::stream::{self, StreamExt};
+use lru::LruCache;
+use primitives::market::AdSlotResponse;
+use slog::{debug, warn, Logger};
+use std::{collections::VecDeque, time::Duration};
+use tokio::time::Instant;
+
+/// The maximum number of negative cached AdSlots, the least recently missed one is dropped for a new one
+pub const MAX_NEGATIVE_SLOTS: usize = 10_000;
+/// How many AdSlots are revalidated concurrently
+pub const REVALIDATION_CONCURRENCY: usize = 8;
+/// The interval between the revalidations stops doubling after this many of them
+const MAX_DOUBLINGS: u32 = 16;
+
+/// The `404 Not Found` of an AdSlot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Miss {
+    pub first_miss: Instant,
+    /// The last `404` of the Market (of a request or a revalidation), the AdSlot is negative for the `ttl` after it
+    pub checked_at: Instant,
+    /// The revalidations since the `first_miss`
+    pub revalidations: u32,
+}
+
+impl Miss {
+    /// `first_interval` after the first miss, then twice as long after each revalidation
+    pub fn next_revalidation(&self, first_interval: Duration) -> Instant {
+        self.checked_at + first_interval * 2_u32.pow(self.revalidations.min(MAX_DOUBLINGS))
+    }
+}
+
+/// The AdSlots which the Market doesn't have, by ipfs
+#[derive(Debug)]
+pub struct NegativeSlotCache {
+    /// By their last `404`, i.e. the least recently missed one is the least recently checked one
+    misses: LruCache<String, Miss>,
+    /// The revalidated AdSlots, the earliest first miss first, at most the `queue_size` most recent ones
+    queue: VecDeque<String>,
+}
+
+impl Default for NegativeSlotCache {
+    fn default() -> Self {
+        Self {
+            // bounded by the `MAX_NEGATIVE_SLOTS`, without allocating all of them upfront
+            misses: LruCache::unbounded(),
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl NegativeSlotCache {
+    /// Whether the Market responded `404` for the AdSlot within the `ttl`
+    pub fn is_negative(&self, ipfs: &str, now: Instant, ttl: Duration) -> bool {
+        self.get(ipfs).map_or(false, |miss| {
+            now.saturating_duration_since(miss.checked_at) < ttl
+        })
+    }
+
+    pub fn get(&self, ipfs: &str) -> Option<&Miss> {
+        // the `LruCache` is looked up only by a `&String` without its `nightly` feature
+        self.misses.peek(&ipfs.to_string())
+    }
+
+    /// Caches the `404` of a request for the AdSlot and queues it for the revalidation,
+    /// the oldest queued one is no longer revalidated once the `queue_size` is reached.
+    /// An AdSlot which is still negative (e.g. a concurrent request) keeps its first miss.
+    pub fn miss(&mut self, ipfs: &str, now: Instant, config: &NegativeSlots) {
+        let key = ipfs.to_string();
+        if let Some(miss) = self.misses.get_mut(&key) {
+            if now.saturating_duration_since(miss.checked_at) < config.ttl {
+                miss.checked_at = now;
+
+                return;
+            }
+        }
+
+        if !self.misses.contains(&key) && self.misses.len() >= MAX_NEGATIVE_SLOTS {
+            self.misses.pop_lru();
+        }
+
+        self.misses.put(
+            key,
+            Miss {
+                first_miss: now,
+                checked_at: now,
+                revalidations: 0,
+            },
+        );
+
+        self.queue.retain(|queued| queued != ipfs);
+        if config.queue_size > 0 {
+            self.queue.push_back(ipfs.to_string());
+        }
+        while self.queue.len() > config.queue_size {
+            self.queue.pop_front();
+        }
+    }
+
+    /// The queued AdSlots due for a revalidation, the earliest first miss first.
+    ///
+    /// Drops the queued ones which are no longer negative or were missed more than `revalidate_for` ago.
+    pub fn due(&mut self, now: Instant, config: &NegativeSlots) -> Vec<String> {
+        let misses = &self.misses;
+        self.queue.retain(|ipfs| {
+            misses.peek(ipfs).map_or(false, |miss| {
+                now.saturating_duration_since(miss.checked_at) < config.ttl
+                    && now.saturating_duration_since(miss.first_miss) < config.revalidate_for
+            })
+        });
+
+        self.queue
+            .iter()
+            .filter(|ipfs| {
+                misses.peek(*ipfs).map_or(false, |miss| {
+                    miss.next_revalidation(config.first_interval) <= now
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The revalidated AdSlot is still not in the Market
+    pub fn still_missing(&mut self, ipfs: &str, now: Instant) {
+        if let Some(miss) = self.misses.get_mut(&ipfs.to_string()) {
+            miss.checked_at = now;
+            miss.revalidations += 1;
+        }
+    }
+
+    pub fn remove(&mut self, ipfs: &str) {
+        if self.misses.pop(&ipfs.to_string()).is_some() {
+            self.queue.retain(|queued| queued != ipfs);
+        }
+    }
+
+    /// The queued AdSlots, incl. the ones which are no longer due
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.misses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.misses.is_empty()
+    }
+}
+
+/// Whether the Market recently responded `404` for the AdSlot (if the negative caching is enabled),
+/// counted in the [`NEGATIVE_SLOT_HITS`]
+pub async fn is_negative<C: Client>(cache: &Cache<C>, config: &Config, ipfs: &str) -> bool {
+    let ttl = config.negative_slots.ttl;
+    if ttl == Duration::from_secs(0) {
+        return false;
+    }
+
+    let now = cache.clock().now_instant();
+    let negative = cache
+        .negative_slots
+        .read()
+        .await
+        .is_negative(ipfs, now, ttl);
+    if negative {
+        NEGATIVE_SLOT_HITS.inc();
+    }
+
+    negative
+}
+
+/// Caches the `404` of the Market for the AdSlot (if the negative caching is enabled)
+pub async fn cache_miss<C: Client>(cache: &Cache<C>, config: &Config, ipfs: &str) {
+    if config.negative_slots.ttl == Duration::from_secs(0) {
+        return;
+    }
+
+    let now = cache.clock().now_instant();
+    cache
+        .negative_slots
+        .write()
+        .await
+        .miss(ipfs, now, &config.negative_slots);
+}
+
+/// Revalidates the queued negative AdSlots which are due (see [`NegativeSlotCache::due`])
+/// and caches the ones the Market returns, so they are served right away.
+///
+/// The first failed revalidation stops the rest and opens the [`MarketApi::circuit_breaker`] (i.e. the Market is likely down),
+/// while it's open (incl. by the pre-warming) nothing is revalidated.
+/// Returns the AdSlots which are in the Market now, the earliest first miss first.
+pub async fn revalidate_negative_slots<C: Client>(
+    logger: &Logger,
+    market: &MarketApi,
+    cache: &Cache<C>,
+    config: &Config,
+) -> Vec<String> {
+    let now = cache.clock().now_instant();
+    let due = cache
+        .negative_slots
+        .write()
+        .await
+        .due(now, &config.negative_slots);
+
+    if due.is_empty() {
+        return vec![];
+    }
+
+    let circuit_breaker = market.circuit_breaker();
+    if circuit_breaker.is_open(now) {
+        NEGATIVE_SLOT_REVALIDATIONS
+            .with_label_values(&["backed_off"])
+            .inc_by(due.len() as u64);
+
+        return vec![];
+    }
+
+    let mut revalidations = stream::iter(due)
+        .map(|ipfs| async move {
+            let fetched = fetch(market, config, &ipfs).await;
+
+            (ipfs, fetched)
+        })
+        .buffered(REVALIDATION_CONCURRENCY);
+
+    let mut promoted = vec![];
+    let mut failed = false;
+    while let Some((ipfs, fetched)) = revalidations.next().await {
+        match fetched {
+            Ok(Some((slot, version, units))) => {
+                debug!(logger, "A negative cached AdSlot is in the Market now"; "AdSlot" => &ipfs);
+
+                // caching it drops the negative one as well
+                prewarm::cache_slot(cache, config, &ipfs, slot, version, units).await;
+                NEGATIVE_SLOT_REVALIDATIONS
+                    .with_label_values(&["promoted"])
+                    .inc();
+
+                promoted.push(ipfs);
+            }
+            Ok(None) => {
+                let now = cache.clock().now_instant();
+                cache.negative_slots.write().await.still_missing(&ipfs, now);
+                NEGATIVE_SLOT_REVALIDATIONS
+                    .with_label_values(&["not_found"])
+                    .inc();
+            }
+            Err(error) => {
+                NEGATIVE_SLOT_REVALIDATIONS
+                    .with_label_values(&["error"])
+                    .inc();
+                warn!(logger, "Revalidating a negative cached AdSlot failed, backing off"; "AdSlot" => &ipfs, "failures" => circuit_breaker.failures() + 1, "error" => ?error);
+
+                failed = true;
+                break;
+            }
+        }
+    }
+    circuit_breaker.record(!failed, cache.clock().now_instant());
+
+    promoted
+}
+
+/// The AdSlot and its AdUnits, `None` if it's still not in the Market
+async fn fetch(
+    market: &MarketApi,
+    config: &Config,
+    ipfs: &str,
+) -> market::Result<Option<(AdSlotResponse, SlotVersion, SlotUnits)>> {
+    match market.fetch_slot_if_modified(ipfs, None).await? {
+        SlotFetch::Modified(slot, version) => {
+            let units = market
+                .fetch_units(&slot.slot, config.limits.max_units_per_slot)
+                .await?;
+
+            Ok(Some((slot, version, units)))
+        }
+        // only a conditional request is `NotModified`
+        SlotFetch::NotModified | SlotFetch::NotFound => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cache::MockClient,
+        config::DEVELOPMENT,
+        util::{
+            test::{discard_logger, MockClock},
+            Clock,
+        },
+    };
+    use primitives::{
+        market::AdUnitsResponse,
+        util::tests::prep_db::{DUMMY_AD_UNITS, IDS},
+        AdSlot,
+    };
+    use std::{collections::HashMap, sync::Arc};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn negative_slots() -> NegativeSlots {
+        NegativeSlots {
+            ttl: Duration::from_secs(60),
+            queue_size: 2,
+            first_interval: Duration::from_secs(5),
+            revalidate_for: Duration::from_secs(300),
+        }
+    }
+
+    fn ad_slot_response(ipfs: &str) -> AdSlotResponse {
+        AdSlotResponse {
+            slot: AdSlot {
+                ipfs: ipfs.to_string(),
+                ad_type: "legacy_250x250".to_string(),
+                archived: false,
+                created: chrono::Utc::now(),
+                description: None,
+                fallback_unit: None,
+                min_per_impression: None,
+                modified: None,
+                owner: IDS["publisher"],
+                title: None,
+                website: None,
+                rules: vec![],
+            },
+            accepted_referrers: vec![],
+            categories: vec![],
+            alexa_rank: None,
+        }
+    }
+
+    #[test]
+    fn the_most_recent_misses_are_revalidated_with_exponential_spacing() {
+        let config = negative_slots();
+        let start = Instant::now();
+        let mut negative = NegativeSlotCache::default();
+
+        for ipfs in &["slot-a", "slot-b", "slot-c"] {
+            negative.miss(ipfs, start, &config);
+        }
+        // all of them are negative, only the 2 most recent ones are revalidated
+        for ipfs in &["slot-a", "slot-b", "slot-c"] {
+            assert!(negative.is_negative(ipfs, start, config.ttl));
+        }
+        assert_eq!(2, negative.queued());
+        assert!(negative.due(start, &config).is_empty());
+
+        // revalidated 5s, 15s, 35s, 75s, 155s after the first miss and never after the `revalidate_for`
+        let mut revalidated_at = vec![];
+        for second in 0..=400 {
+            let now = start + Duration::from_secs(second);
+            let due = negative.due(now, &config);
+            if !due.is_empty() {
+                assert_eq!(vec!["slot-b", "slot-c"], due);
+                revalidated_at.push(second);
+            }
+            for ipfs in due {
+                negative.still_missing(&ipfs, now);
+            }
+        }
+        assert_eq!(vec![5, 15, 35, 75, 155], revalidated_at);
+        assert_eq!(
+            5,
+            negative
+                .get("slot-c")
+                .expect("Should be cached")
+                .revalidations
+        );
+
+        // not revalidated, so it expired after the `ttl`
+        let later = start + Duration::from_secs(100);
+        assert!(!negative.is_negative("slot-a", later, config.ttl));
+        // the revalidations keep the rest negative
+        assert!(negative.is_negative("slot-c", later, config.ttl));
+
+        // a miss after it expired is a new first miss
+        let now = start + Duration::from_secs(400);
+        negative.miss("slot-a", now, &config);
+        assert_eq!(
+            Some(&Miss {
+                first_miss: now,
+                checked_at: now,
+                revalidations: 0,
+            }),
+            negative.get("slot-a")
+        );
+        assert_eq!(
+            vec!["slot-a"],
+            negative.due(now + config.first_interval, &config)
+        );
+    }
+
+    #[tokio::test]
+    async fn a_slot_appearing_30_seconds_after_the_first_miss_is_cached() {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+        let market = MarketApi::new(
+            (server.uri() + "/market/")
+                .parse()
+                .expect("Wrong Market url"),
+            &DEVELOPMENT,
+            logger.clone(),
+        )
+        .expect("should create market instance");
+
+        let mut config = DEVELOPMENT.clone();
+        config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+        config.negative_slots = negative_slots();
+
+        let clock = MockClock::new();
+        let cache = Cache::builder(MockClient::init(vec![HashMap::new()], vec![], None).await)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+
+        // revalidated 5s & 15s after the first miss, before it's in the Market
+        Mock::given(method("GET"))
+            .and(path("/market/slots/slot-new"))
+            .respond_with(ResponseTemplate::new(404))
+            .up_to_n_times(2)
+            .expect(2_u64)
+            .mount(&server)
+            .await;
+
+        cache_miss(&cache, &config, "slot-new").await;
+        assert!(is_negative(&cache, &config, "slot-new").await);
+
+        for _ in 0..6 {
+            clock.advance(Duration::from_secs(5));
+            let promoted = revalidate_negative_slots(&logger, &market, &cache, &config).await;
+            assert!(promoted.is_empty());
+        }
+        assert!(is_negative(&cache, &config, "slot-new").await);
+        assert_eq!(
+            2,
+            cache
+                .negative_slots
+                .read()
+                .await
+                .get("slot-new")
+                .expect("Should be negative")
+                .revalidations
+        );
+
+        // the publisher created the AdSlot 30s after the first miss
+        let mut ad_unit = DUMMY_AD_UNITS[0].clone();
+        ad_unit.ad_type = "legacy_250x250".to_string();
+        Mock::given(method("GET"))
+            .and(path("/market/slots/slot-new"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&ad_slot_response("slot-new")))
+            .expect(1_u64)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/market/units"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![ad_unit.clone()])),
+            )
+            .mount(&server)
+            .await;
+
+        // the next revalidation is 35s after the first miss
+        clock.advance(Duration::from_secs(5));
+        let promoted = revalidate_negative_slots(&logger, &market, &cache, &config).await;
+        assert_eq!(vec!["slot-new"], promoted);
+
+        assert!(!is_negative(&cache, &config, "slot-new").await);
+        assert!(cache.negative_slots.read().await.is_empty());
+        let now = clock.now_instant();
+        let cached = cache
+            .slots
+            .read()
+            .await
+            .get("slot-new", now, config.prewarm.slot_cache_ttl)
+            .expect("Should be cached");
+        let units = cached.units.as_ref().expect("Should have the AdUnits");
+        assert_eq!(1, units.len());
+        assert_eq!(ad_unit.ipfs, units[0].ipfs);
+
+        // nothing is left to revalidate
+        clock.advance(Duration::from_secs(60));
+        assert!(revalidate_negative_slots(&logger, &market, &cache, &config)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn revalidating_backs_off_while_the_market_is_down() {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+        let market = MarketApi::new(
+            (server.uri() + "/market/")
+                .parse()
+                .expect("Wrong Market url"),
+            &DEVELOPMENT,
+            logger.clone(),
+        )
+        .expect("should create market instance");
+
+        let mut config = DEVELOPMENT.clone();
+        config.negative_slots = negative_slots();
+
+        let clock = MockClock::new();
+        let cache = Cache::builder(MockClient::init(vec![HashMap::new()], vec![], None).await)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        cache_miss(&cache, &config, "slot-a").await;
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2_u64)
+            .mount(&server)
+            .await;
+
+        // fails and skips the next interval
+        clock.advance(Duration::from_secs(5));
+        for _ in 0..2 {
+            assert!(revalidate_negative_slots(&logger, &market, &cache, &config)
+                .await
+                .is_empty());
+        }
+        assert_eq!(1, market.circuit_breaker().failures());
+        // a failed revalidation doesn't reschedule it
+        assert_eq!(
+            0,
+            cache
+                .negative_slots
+                .read()
+                .await
+                .get("slot-a")
+                .expect("Should be negative")
+                .revalidations
+        );
+
+        // requested again after the backoff
+        clock.advance(market::CIRCUIT_BREAKER_BASE);
+        assert!(revalidate_negative_slots(&logger, &market, &cache, &config)
+            .await
+            .is_empty());
+        assert_eq!(2, market.circuit_breaker().failures());
+        assert!(is_negative(&cache, &config, "slot-a").await);
+    }
+
+    #[tokio::test]
+    async fn the_circuit_breaker_is_shared_with_the_other_market_requests() {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+        let market = MarketApi::new(
+            (server.uri() + "/market/")
+                .parse()
+                .expect("Wrong Market url"),
+            &DEVELOPMENT,
+            logger.clone(),
+        )
+        .expect("should create market instance");
+
+        let mut config = DEVELOPMENT.clone();
+        config.negative_slots = negative_slots();
+
+        let clock = MockClock::new();
+        let cache = Cache::builder(MockClient::init(vec![HashMap::new()], vec![], None).await)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        cache_miss(&cache, &config, "slot-a").await;
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1_u64)
+            .mount(&server)
+            .await;
+
+        // e.g. the pre-warming failed with a clone of the `MarketApi`
+        clock.advance(Duration::from_secs(5));
+        market
+            .clone()
+            .circuit_breaker()
+            .record(false, clock.now_instant());
+        assert!(revalidate_negative_slots(&logger, &market, &cache, &config)
+            .await
+            .is_empty());
+
+        clock.advance(market::CIRCUIT_BREAKER_BASE);
+        assert!(revalidate_negative_slots(&logger, &market, &cache, &config)
+            .await
+            .is_empty());
+        assert_eq!(0, market.circuit_breaker().failures());
+        assert_eq!(
+            1,
+            cache
+                .negative_slots
+                .read()
+                .await
+                .get("slot-a")
+                .expect("Should be negative")
+                .revalidations
+        );
+    }
+
+    #[test]
+    fn the_least_recently_missed_slot_is_evicted() {
+        let config = negative_slots();
+        let start = Instant::now();
+        let mut negative = NegativeSlotCache::default();
+
+        for index in 0..MAX_NEGATIVE_SLOTS {
+            negative.miss(&format!("slot-{}", index), start, &config);
+        }
+        // missed again, so it's the most recent one
+        negative.miss("slot-0", start + Duration::from_secs(1), &config);
+
+        negative.miss("slot-new", start + Duration::from_secs(2), &config);
+        assert_eq!(MAX_NEGATIVE_SLOTS, negative.len());
+        assert!(negative.get("slot-0").is_some());
+        assert!(negative.get("slot-1").is_none());
+        assert!(negative.get("slot-new").is_some());
+    }
+}
@@ -0,0 +1,109 @@
+//! The operator's overrides of single AdSlots, see [`SlotOverride`](crate::config::SlotOverride).
+//!
+//! They are kept in the [`Cache::slot_overrides`] and replaced by the ones of the reloaded config on `SIGHUP`,
+//! the briefly cached units-for-slot results are cleared as well, so the next requests already use them.
+use crate::{
+    cache::{Cache, Client},
+    Config,
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use hyper::{Body, Response};
+use slog::{error, info, Logger};
+use std::io;
+
+/// The response of the units-for-slot requests for a `blocked` AdSlot
+pub fn blocked() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({ "error": "slot blocked" }).to_string(),
+        ))
+        .expect("Should create the blocked AdSlot response")
+}
+
+/// Reloads the overrides on every `SIGHUP`, see [`reload`]
+pub fn spawn_reload_on_hangup<C>(logger: Logger, cache: Cache<C>, config: Config) -> io::Result<()>
+where
+    C: Client + Send + Sync + 'static,
+{
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            reload(&logger, &cache, &config).await;
+        }
+    });
+
+    Ok(())
+}
+
+/// Replaces the overrides of the Cache with the ones of the reloaded `config` (see [`Config::reload`])
+/// and clears the cached matched units. If the config fails to load the current overrides are kept.
+pub async fn reload<C: Client>(logger: &Logger, cache: &Cache<C>, config: &Config) {
+    match config.reload() {
+        Ok(reloaded) => {
+            info!(logger, "Reloaded the slot overrides"; "slots" => reloaded.slot_overrides.len());
+
+            *cache.slot_overrides.write().await = reloaded.slot_overrides;
+            cache.matched_units.write().await.clear();
+        }
+        Err(err) => {
+            error!(logger, "Failed to reload the slot overrides, keeping the current ones"; "error" => %err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cache::MockClient,
+        config::{Environment, SlotOverride},
+        units_for_slot::MatchedUnits,
+        util::test::discard_logger,
+    };
+    use std::collections::HashMap;
+    use tokio::time::Instant;
+
+    const SLOT: &str = "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C";
+
+    #[tokio::test]
+    async fn the_overrides_are_replaced_by_the_reloaded_ones_unless_the_config_is_invalid() {
+        let path = std::env::temp_dir().join("supermarket-slot-overrides-reload-test.toml");
+        let write = |toml: &str| std::fs::write(&path, toml).expect("Should write config file");
+
+        write("[slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C]\nblocked = true");
+        let config =
+            Config::new(path.to_str(), Environment::Development).expect("Should load config");
+        let cache = Cache::builder(MockClient::init(vec![HashMap::new()], vec![], None).await)
+            .slot_overrides(config.slot_overrides.clone())
+            .initialize()
+            .await;
+        assert!(cache.slot_overrides.read().await[SLOT].blocked);
+
+        cache.matched_units.write().await.insert(
+            SLOT.to_string(),
+            Instant::now(),
+            MatchedUnits::new(vec![]),
+            config.units_for_slot_cache_ttl,
+            config.units_for_slot_cache_size,
+        );
+        write("[slot_overrides.QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C]\nforce_fallback = true");
+        reload(&discard_logger(), &cache, &config).await;
+
+        let forced = SlotOverride {
+            force_fallback: true,
+            ..Default::default()
+        };
+        assert_eq!(Some(&forced), cache.slot_overrides.read().await.get(SLOT));
+        assert!(cache.matched_units.read().await.is_empty());
+
+        write("slot_overrides = \"not a table\"");
+        reload(&discard_logger(), &cache, &config).await;
+        std::fs::remove_file(&path).expect("Should remove config file");
+
+        assert_eq!(Some(&forced), cache.slot_overrides.read().await.get(SLOT));
+    }
+}
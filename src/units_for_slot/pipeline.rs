@@ -0,0 +1,541 @@
+//! The selection of the Campaigns served to a units-for-slot request, as an ordered list of named [`Step`]s.
+//!
+//! Each step filters the candidates left by the previous one and the number of the candidates it dropped is recorded,
+//! for the debug logs and the [`UNITS_FOR_SLOT_DROPPED`](crate::metrics::UNITS_FOR_SLOT_DROPPED) metric.
+//! The targeting of the selected Campaigns is memoized across the requests, so it runs after the pipeline,
+//! see [`apply_targeting_memoized`](super::apply_targeting_memoized).
+use crate::{
+    cache::{effective_balances, remaining_budget_by, Campaign, FollowerBalances, RefreshedCache},
+    ids::Canonical,
+    status::{is_scheduled, Status},
+    Config,
+};
+use chrono::{DateTime, Utc};
+use primitives::{AdUnit, BigNum, ChannelId, ValidatorId};
+use std::collections::HashSet;
+use tokio::time::Instant;
+
+/// A Campaign of the Cache which is still served to the request
+pub type Candidate<'a> = &'a Campaign;
+
+/// The values of the request and the Cache which the steps depend on
+pub struct Context<'a> {
+    pub config: &'a Config,
+    pub refreshed: &'a RefreshedCache,
+    /// The remaining budget & the publisher earnings are by the [`effective_balances`]
+    pub follower_balances: &'a FollowerBalances,
+    pub now: Instant,
+    pub now_utc: DateTime<Utc>,
+    pub publisher_id: ValidatorId,
+    /// Any deposit asset if empty, otherwise the Campaigns' deposit assets are compared as addresses (in either case)
+    pub deposit_assets: &'a [ValidatorId],
+    /// See [`DegradationPolicy::ServeStale`](crate::config::DegradationPolicy::ServeStale)
+    pub serve_stale: bool,
+    /// The Campaigns restricted by the [`Config::validator_allowlist`](crate::Config::validator_allowlist)
+    pub restricted: &'a HashSet<ChannelId>,
+}
+
+/// A named filter of the candidates
+#[derive(Clone, Copy)]
+pub struct Step {
+    pub name: &'static str,
+    filter: for<'a> fn(&Context<'_>, Vec<Candidate<'a>>) -> Vec<Candidate<'a>>,
+}
+
+impl Step {
+    pub const fn new(
+        name: &'static str,
+        filter: for<'a> fn(&Context<'_>, Vec<Candidate<'a>>) -> Vec<Candidate<'a>>,
+    ) -> Self {
+        Self { name, filter }
+    }
+
+    pub fn run<'a>(
+        &self,
+        context: &Context<'_>,
+        candidates: Vec<Candidate<'a>>,
+    ) -> Vec<Candidate<'a>> {
+        (self.filter)(context, candidates)
+    }
+}
+
+impl std::fmt::Debug for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Step").field("name", &self.name).finish()
+    }
+}
+
+/// How many candidates a [`Step`] dropped and how many were left after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepDrops {
+    pub step: &'static str,
+    pub dropped: usize,
+    pub remaining: usize,
+}
+
+/// The result of [`UnitsForSlotPipeline::run`]
+#[derive(Debug)]
+pub struct PipelineRun<'a> {
+    pub candidates: Vec<Candidate<'a>>,
+    /// In the order of the steps
+    pub drops: Vec<StepDrops>,
+}
+
+impl PipelineRun<'_> {
+    /// The candidates left after the `step`, `None` if there is no such step
+    pub fn remaining_after(&self, step: &str) -> Option<usize> {
+        self.drops
+            .iter()
+            .find(|drops| drops.step == step)
+            .map(|drops| drops.remaining)
+    }
+}
+
+/// The ordered [`Step`]s selecting the Campaigns, see [`UnitsForSlotPipeline::default`]
+#[derive(Debug, Clone)]
+pub struct UnitsForSlotPipeline {
+    steps: Vec<Step>,
+}
+
+/// The Supermarket's Active status combines the Active & Ready of the Market
+pub const STATUS: Step = Step::new("status", status);
+/// The Campaigns with a Validator which isn't on the `validator_allowlist`
+pub const RESTRICTED: Step = Step::new("restricted", restricted);
+/// The Campaigns without a remaining budget by the most spent of the Leader's & Follower's balances
+pub const EXHAUSTED: Step = Step::new("exhausted", exhausted);
+/// The Campaigns which weren't refreshed within the `max_campaign_staleness`, unless serving the stale ones
+pub const STALE: Step = Step::new("stale", stale);
+/// The Campaigns before their `activeFrom`
+pub const SCHEDULED: Step = Step::new("scheduled", scheduled);
+/// The Campaigns created by the publisher
+pub const CREATOR: Step = Step::new("creator", creator);
+/// The Campaigns without one of the accepted deposit assets
+pub const DEPOSIT_ASSET: Step = Step::new("deposit_asset", deposit_asset);
+/// Only the Campaigns the publisher earns from (by the Leader's or the Follower's balances),
+/// once they are at least `max_channels_earning_from`
+pub const EARNER_LIMIT: Step = Step::new("earner_limit", earner_limit);
+
+/// The steps which don't depend on the request (the publisher & the query),
+/// i.e. whether the Campaign is served at all, see [`failed_campaign_steps`]
+pub const CAMPAIGN_STEPS: [Step; 5] = [STATUS, RESTRICTED, EXHAUSTED, STALE, SCHEDULED];
+
+impl Default for UnitsForSlotPipeline {
+    fn default() -> Self {
+        let mut steps = CAMPAIGN_STEPS.to_vec();
+        steps.extend(&[CREATOR, DEPOSIT_ASSET, EARNER_LIMIT]);
+
+        Self::new(steps)
+    }
+}
+
+impl UnitsForSlotPipeline {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self { steps }
+    }
+
+    pub fn steps(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.steps.iter().map(|step| step.name)
+    }
+
+    /// Runs the steps in order, recording the candidates dropped by each of them
+    pub fn run<'a>(
+        &self,
+        context: &Context<'_>,
+        candidates: Vec<Candidate<'a>>,
+    ) -> PipelineRun<'a> {
+        let mut drops = Vec::with_capacity(self.steps.len());
+
+        let candidates = self.steps.iter().fold(candidates, |candidates, step| {
+            let before = candidates.len();
+            let candidates = step.run(context, candidates);
+
+            drops.push(StepDrops {
+                step: step.name,
+                dropped: before.saturating_sub(candidates.len()),
+                remaining: candidates.len(),
+            });
+
+            candidates
+        });
+
+        PipelineRun { candidates, drops }
+    }
+}
+
+/// The names of the [`CAMPAIGN_STEPS`] dropping the `campaign`, empty if it's served to the AdSlots of its units' types.
+/// The `publisher_id` & the `deposit_assets` of the `context` are not used.
+pub fn failed_campaign_steps(context: &Context<'_>, campaign: &Campaign) -> Vec<&'static str> {
+    CAMPAIGN_STEPS
+        .iter()
+        .filter(|step| step.run(context, vec![campaign]).is_empty())
+        .map(|step| step.name)
+        .collect()
+}
+
+/// Whether the AdUnit of a served Campaign is matched for the AdSlots of its type: it has a type and it's not archived
+pub fn is_unit_matchable(ad_unit: &AdUnit) -> bool {
+    !ad_unit.archived && !ad_unit.ad_type.is_empty()
+}
+
+fn status<'a>(_: &Context<'_>, mut candidates: Vec<Candidate<'a>>) -> Vec<Candidate<'a>> {
+    candidates.retain(|campaign| campaign.status == Status::Active);
+
+    candidates
+}
+
+fn restricted<'a>(context: &Context<'_>, mut candidates: Vec<Candidate<'a>>) -> Vec<Candidate<'a>> {
+    candidates.retain(|campaign| !context.restricted.contains(&campaign.channel.id));
+
+    candidates
+}
+
+fn exhausted<'a>(context: &Context<'_>, mut candidates: Vec<Candidate<'a>>) -> Vec<Candidate<'a>> {
+    candidates.retain(|campaign| {
+        let balances = effective_balances(campaign, context.follower_balances);
+
+        remaining_budget_by(campaign, &balances) > BigNum::from(0)
+    });
+
+    candidates
+}
+
+fn stale<'a>(context: &Context<'_>, mut candidates: Vec<Candidate<'a>>) -> Vec<Candidate<'a>> {
+    let max_staleness = match context.config.max_campaign_staleness {
+        Some(max_staleness) if !context.serve_stale => max_staleness,
+        _ => return candidates,
+    };
+
+    candidates.retain(|campaign| {
+        context
+            .refreshed
+            .get(&campaign.channel.id)
+            .map_or(true, |refreshed| {
+                !refreshed.is_stale(max_staleness, context.now)
+            })
+    });
+
+    candidates
+}
+
+fn scheduled<'a>(context: &Context<'_>, mut candidates: Vec<Candidate<'a>>) -> Vec<Candidate<'a>> {
+    candidates.retain(|campaign| !is_scheduled(&campaign.channel, context.now_utc));
+
+    candidates
+}
+
+fn creator<'a>(context: &Context<'_>, mut candidates: Vec<Candidate<'a>>) -> Vec<Candidate<'a>> {
+    candidates.retain(|campaign| campaign.channel.creator != context.publisher_id);
+
+    candidates
+}
+
+fn deposit_asset<'a>(
+    context: &Context<'_>,
+    mut candidates: Vec<Candidate<'a>>,
+) -> Vec<Candidate<'a>> {
+    if !context.deposit_assets.is_empty() {
+        // in either case, the malformed ones are never accepted
+        candidates.retain(|campaign| {
+            campaign
+                .channel
+                .deposit_asset
+                .parse::<Canonical<ValidatorId>>()
+                .map_or(false, |Canonical(deposit_asset)| {
+                    context.deposit_assets.contains(&deposit_asset)
+                })
+        });
+    }
+
+    candidates
+}
+
+/// The Campaigns the publisher earns from come first
+fn earner_limit<'a>(context: &Context<'_>, candidates: Vec<Candidate<'a>>) -> Vec<Candidate<'a>> {
+    let (mut by_earner, rest): (Vec<Candidate<'a>>, Vec<Candidate<'a>>) =
+        candidates.into_iter().partition(|campaign| {
+            effective_balances(campaign, context.follower_balances)
+                .contains_key(&context.publisher_id)
+        });
+
+    if by_earner.len() < context.config.limits.max_channels_earning_from.into() {
+        by_earner.extend(rest);
+    }
+
+    by_earner
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cache::Refreshed, config::DEVELOPMENT};
+    use chrono::Duration as ChronoDuration;
+    use primitives::{
+        util::tests::prep_db::{DUMMY_CHANNEL, IDS},
+        ChannelId,
+    };
+    use std::time::Duration;
+
+    fn campaign(id: u8, status: Status) -> Campaign {
+        let mut channel = DUMMY_CHANNEL.clone();
+        channel.id = ChannelId::from([id; 32]);
+
+        Campaign {
+            channel,
+            status,
+            balances: Default::default(),
+        }
+    }
+
+    fn ids(candidates: &[Candidate<'_>]) -> Vec<ChannelId> {
+        candidates
+            .iter()
+            .map(|campaign| campaign.channel.id)
+            .collect()
+    }
+
+    lazy_static::lazy_static! {
+        static ref NO_FOLLOWER_BALANCES: FollowerBalances = FollowerBalances::new();
+        static ref NONE_RESTRICTED: HashSet<ChannelId> = HashSet::new();
+    }
+
+    /// Without the Follower's balances
+    fn context<'a>(config: &'a Config, refreshed: &'a RefreshedCache) -> Context<'a> {
+        Context {
+            config,
+            refreshed,
+            follower_balances: &NO_FOLLOWER_BALANCES,
+            now: Instant::now(),
+            now_utc: Utc::now(),
+            publisher_id: IDS["publisher"],
+            deposit_assets: &[],
+            serve_stale: false,
+            restricted: &NONE_RESTRICTED,
+        }
+    }
+
+    #[test]
+    fn only_the_active_campaigns_pass_the_status() {
+        let refreshed = RefreshedCache::new();
+        let context = context(&DEVELOPMENT, &refreshed);
+        let (active, waiting) = (campaign(1, Status::Active), campaign(2, Status::Waiting));
+
+        let candidates = STATUS.run(&context, vec![&active, &waiting]);
+        assert_eq!(vec![active.channel.id], ids(&candidates));
+    }
+
+    #[test]
+    fn the_restricted_campaigns_are_dropped() {
+        let refreshed = RefreshedCache::new();
+        let (allowed, restricted) = (campaign(1, Status::Active), campaign(2, Status::Active));
+        let restricted_ids = vec![restricted.channel.id].into_iter().collect();
+        let mut context = context(&DEVELOPMENT, &refreshed);
+        context.restricted = &restricted_ids;
+
+        let candidates = RESTRICTED.run(&context, vec![&allowed, &restricted]);
+        assert_eq!(vec![allowed.channel.id], ids(&candidates));
+        assert_eq!(
+            vec![RESTRICTED.name],
+            failed_campaign_steps(&context, &restricted)
+        );
+    }
+
+    #[test]
+    fn stale_campaigns_are_dropped_unless_serving_stale() {
+        let mut config = DEVELOPMENT.clone();
+        config.max_campaign_staleness = Some(Duration::from_secs(60));
+
+        let (fresh, stale, never_refreshed) = (
+            campaign(1, Status::Active),
+            campaign(2, Status::Active),
+            campaign(3, Status::Active),
+        );
+        let now = Instant::now();
+        let refreshed_at = |ago: u64| Refreshed {
+            at: Utc::now(),
+            instant: now - Duration::from_secs(ago),
+        };
+        let refreshed = vec![
+            (fresh.channel.id, refreshed_at(30)),
+            (stale.channel.id, refreshed_at(61)),
+        ]
+        .into_iter()
+        .collect::<RefreshedCache>();
+
+        let mut context = context(&config, &refreshed);
+        context.now = now;
+        let candidates = vec![&fresh, &stale, &never_refreshed];
+
+        assert_eq!(
+            vec![fresh.channel.id, never_refreshed.channel.id],
+            ids(&STALE.run(&context, candidates.clone()))
+        );
+
+        context.serve_stale = true;
+        assert_eq!(3, STALE.run(&context, candidates).len());
+    }
+
+    #[test]
+    fn scheduled_and_own_campaigns_are_dropped() {
+        let refreshed = RefreshedCache::new();
+        let context = context(&DEVELOPMENT, &refreshed);
+
+        let served = campaign(1, Status::Active);
+        let mut scheduled = campaign(2, Status::Active);
+        scheduled.channel.spec.active_from = Some(context.now_utc + ChronoDuration::minutes(1));
+        let mut own = campaign(3, Status::Active);
+        own.channel.creator = context.publisher_id;
+
+        let candidates = vec![&served, &scheduled, &own];
+        assert_eq!(
+            vec![served.channel.id, own.channel.id],
+            ids(&SCHEDULED.run(&context, candidates.clone()))
+        );
+        assert_eq!(
+            vec![served.channel.id, scheduled.channel.id],
+            ids(&CREATOR.run(&context, candidates))
+        );
+    }
+
+    #[test]
+    fn only_the_accepted_deposit_assets_are_served() {
+        let refreshed = RefreshedCache::new();
+        let accepted = campaign(1, Status::Active);
+        let mut other = campaign(2, Status::Active);
+        other.channel.deposit_asset = "OTHER".to_string();
+        let candidates = vec![&accepted, &other];
+
+        // any deposit asset
+        let mut context = context(&DEVELOPMENT, &refreshed);
+        assert_eq!(2, DEPOSIT_ASSET.run(&context, candidates.clone()).len());
+
+        let Canonical(deposit_asset) = accepted
+            .channel
+            .deposit_asset
+            .to_ascii_lowercase()
+            .parse::<Canonical<ValidatorId>>()
+            .expect("Valid address");
+        let deposit_assets = [deposit_asset];
+        context.deposit_assets = &deposit_assets;
+        assert_eq!(
+            vec![accepted.channel.id],
+            ids(&DEPOSIT_ASSET.run(&context, candidates))
+        );
+    }
+
+    #[test]
+    fn the_campaigns_the_publisher_earns_from_are_limited() {
+        let mut config = DEVELOPMENT.clone();
+        config.limits.max_channels_earning_from = 2;
+        let refreshed = RefreshedCache::new();
+        let context = context(&config, &refreshed);
+
+        let earning = |id| {
+            let mut campaign = campaign(id, Status::Active);
+            campaign.balances.insert(context.publisher_id, 10.into());
+
+            campaign
+        };
+        let (first, second, other) = (earning(1), earning(2), campaign(3, Status::Active));
+
+        // below the limit the rest of the Campaigns follow
+        assert_eq!(
+            vec![first.channel.id, other.channel.id],
+            ids(&EARNER_LIMIT.run(&context, vec![&other, &first]))
+        );
+        assert_eq!(
+            vec![first.channel.id, second.channel.id],
+            ids(&EARNER_LIMIT.run(&context, vec![&first, &other, &second]))
+        );
+    }
+
+    #[test]
+    fn the_follower_balances_are_reconciled_for_the_budget_and_the_earnings() {
+        let mut config = DEVELOPMENT.clone();
+        config.limits.max_channels_earning_from = 1;
+        let refreshed = RefreshedCache::new();
+
+        let mut ahead = campaign(1, Status::Active);
+        ahead.channel.deposit_amount = 1_000.into();
+        ahead.balances.insert(IDS["leader"], 600.into());
+        let mut earning = campaign(2, Status::Active);
+        earning.channel.deposit_amount = 1_000.into();
+        let candidates = vec![&ahead, &earning];
+
+        // only the Leader's balances
+        let mut context = context(&config, &refreshed);
+        assert_eq!(2, EXHAUSTED.run(&context, candidates.clone()).len());
+        assert_eq!(2, EARNER_LIMIT.run(&context, candidates.clone()).len());
+
+        // the Follower approved less for the Leader's address,
+        // but the rest of the deposit to an address missing from the Leader's NewState
+        let follower_balances: FollowerBalances = vec![
+            (
+                ahead.channel.id,
+                vec![(IDS["leader"], 500.into()), (IDS["follower"], 400.into())]
+                    .into_iter()
+                    .collect(),
+            ),
+            (
+                earning.channel.id,
+                vec![(context.publisher_id, 10.into())]
+                    .into_iter()
+                    .collect(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        context.follower_balances = &follower_balances;
+
+        assert_eq!(
+            vec![earning.channel.id],
+            ids(&EXHAUSTED.run(&context, candidates.clone()))
+        );
+        assert_eq!(
+            vec![earning.channel.id],
+            ids(&EARNER_LIMIT.run(&context, candidates))
+        );
+    }
+
+    #[test]
+    fn only_the_campaign_steps_are_checked_for_a_single_campaign() {
+        let refreshed = RefreshedCache::new();
+        let context = context(&DEVELOPMENT, &refreshed);
+
+        let mut own = campaign(1, Status::Active);
+        own.channel.creator = context.publisher_id;
+        assert!(failed_campaign_steps(&context, &own).is_empty());
+
+        let mut scheduled = campaign(2, Status::Waiting);
+        scheduled.channel.spec.active_from = Some(context.now_utc + ChronoDuration::minutes(1));
+        assert_eq!(
+            vec![STATUS.name, SCHEDULED.name],
+            failed_campaign_steps(&context, &scheduled)
+        );
+    }
+
+    #[test]
+    fn the_drops_of_each_step_are_recorded() {
+        let refreshed = RefreshedCache::new();
+        let context = context(&DEVELOPMENT, &refreshed);
+
+        let active = campaign(1, Status::Active);
+        let waiting = campaign(2, Status::Waiting);
+        let mut own = campaign(3, Status::Active);
+        own.channel.creator = context.publisher_id;
+
+        let pipeline = UnitsForSlotPipeline::default();
+        let run = pipeline.run(&context, vec![&active, &waiting, &own]);
+        assert_eq!(vec![active.channel.id], ids(&run.candidates));
+        assert_eq!(pipeline.steps().count(), run.drops.len());
+
+        let dropped = run
+            .drops
+            .iter()
+            .filter(|drops| drops.dropped > 0)
+            .map(|drops| (drops.step, drops.dropped))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![("status", 1), ("creator", 1)], dropped);
+        assert_eq!(Some(2), run.remaining_after("status"));
+        assert_eq!(None, run.remaining_after("targeting"));
+    }
+}
@@ -0,0 +1,927 @@
+//! Caching the AdSlots (with their AdUnits) fetched from the Market and refreshing the most requested ones
+//! in the background, slightly before they expire, so their requests don't wait for the Market.
+//!
+//! The expired AdSlots which the Market returned with an `ETag` or `Last-Modified` are revalidated
+//! with a conditional request instead, a `304 Not Modified` extends them for another `slot_cache_ttl`
+//! without fetching their AdUnits again.
+//!
+//! The AdSlots of the proxied `GET /slots/:ipfs` responses are cached as well (see [`warm_from_proxied`]),
+//! so a units-for-slot request for them only fetches their AdUnits.
+//!
+//! The targeting results depend on the inputs of each request (`User-Agent`, country, etc.),
+//! they are reused by the [`TargetingMemo`](super::TargetingMemo) instead.
+use crate::{
+    cache::{validation::RulesComplexity, Cache, Client},
+    market::{self, ProxiedResponse, SlotFetch, SlotUnits, SlotVersion},
+    metrics::{SLOT_CACHE_REQUESTS, SLOT_PREWARMS, SLOT_REVALIDATIONS},
+    Config, MarketApi, ROUTE_SLOTS,
+};
+use futures::stream::{self, StreamExt};
+use http::{
+    header::{CONTENT_ENCODING, ETAG, LAST_MODIFIED},
+    StatusCode,
+};
+use hyper::{body::Bytes, Body, Response};
+use primitives::{market::AdSlotResponse, AdUnit};
+use slog::{debug, warn, Logger};
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io::Read,
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// The maximum number of AdSlots whose popularity is tracked, see [`SlotPopularity`]
+pub const MAX_TRACKED_SLOTS: usize = 10_000;
+/// The request counts are halved every 10 minutes, so the popularity follows the recent traffic
+pub const POPULARITY_HALF_LIFE: Duration = Duration::from_secs(10 * 60);
+/// AdSlots whose decayed count drops below this are no longer tracked
+const MIN_POPULARITY: f64 = 0.5;
+/// How many AdSlots are refreshed concurrently
+pub const REFRESH_CONCURRENCY: usize = 8;
+/// The expired AdSlots with a [`SlotVersion`] are kept for revalidation for this many `slot_cache_ttl`s
+pub const REVALIDATABLE_FOR_TTLS: u32 = 10;
+
+/// An AdSlot and its AdUnits as fetched from the Market
+#[derive(Debug)]
+pub struct CachedSlot {
+    pub slot: AdSlotResponse,
+    /// `None` until fetched, e.g. for the AdSlots of the proxied responses
+    pub units: Option<Vec<AdUnit>>,
+    /// The AdUnits were truncated to the `max_units_per_slot`, see [`SlotUnits`]
+    pub units_truncated: bool,
+    /// For revalidating the AdSlot once it expires, empty if the Market didn't return it
+    pub version: SlotVersion,
+    pub fetched_at: Instant,
+    /// Of the AdSlot targeting rules, computed once instead of on every request,
+    /// see [`Targeting::slot_rules_over_limits`](super::Targeting::slot_rules_over_limits)
+    pub rules_complexity: RulesComplexity,
+}
+
+/// A lookup of the [`SlotCache`]
+#[derive(Debug)]
+pub enum SlotLookup {
+    /// Fetched within the `slot_cache_ttl`
+    Fresh(Arc<CachedSlot>),
+    /// Expired, but it can be revalidated with its [`SlotVersion`]
+    Stale(Arc<CachedSlot>),
+    Missing,
+}
+
+impl SlotLookup {
+    /// The expired AdSlot which should be revalidated
+    pub fn stale(self) -> Option<Arc<CachedSlot>> {
+        match self {
+            Self::Stale(stale) => Some(stale),
+            _ => None,
+        }
+    }
+}
+
+/// The fetched AdSlots by ipfs, valid for the [`Prewarm.slot_cache_ttl`](crate::config::Prewarm::slot_cache_ttl)
+#[derive(Debug, Default)]
+pub struct SlotCache {
+    entries: HashMap<String, Arc<CachedSlot>>,
+}
+
+impl SlotCache {
+    /// The AdSlot, if it was fetched within the `ttl`
+    pub fn get(&self, ipfs: &str, now: Instant, ttl: Duration) -> Option<Arc<CachedSlot>> {
+        self.entries
+            .get(ipfs)
+            .filter(|cached| now.saturating_duration_since(cached.fetched_at) < ttl)
+            .cloned()
+    }
+
+    /// The AdSlot if it was fetched within the `ttl`, or if it can be revalidated
+    pub fn lookup(&self, ipfs: &str, now: Instant, ttl: Duration) -> SlotLookup {
+        match self.entries.get(ipfs) {
+            Some(cached) if now.saturating_duration_since(cached.fetched_at) < ttl => {
+                SlotLookup::Fresh(cached.clone())
+            }
+            Some(cached) if is_revalidatable(cached, now, ttl) => SlotLookup::Stale(cached.clone()),
+            _ => SlotLookup::Missing,
+        }
+    }
+
+    /// The AdSlot regardless of when it was fetched
+    pub fn latest(&self, ipfs: &str) -> Option<Arc<CachedSlot>> {
+        self.entries.get(ipfs).cloned()
+    }
+
+    /// Inserts the AdSlot and drops the expired ones which can't be revalidated
+    pub fn insert(&mut self, ipfs: String, slot: Arc<CachedSlot>, now: Instant, ttl: Duration) {
+        self.entries.retain(|_, cached| {
+            now.saturating_duration_since(cached.fetched_at) < ttl
+                || is_revalidatable(cached, now, ttl)
+        });
+        self.entries.insert(ipfs, slot);
+    }
+
+    /// Whether the AdSlot is not cached or it expires within the `margin`
+    pub fn expires_within(
+        &self,
+        ipfs: &str,
+        now: Instant,
+        ttl: Duration,
+        margin: Duration,
+    ) -> bool {
+        self.entries.get(ipfs).map_or(true, |cached| {
+            now.saturating_duration_since(cached.fetched_at) + margin >= ttl
+        })
+    }
+
+    pub fn remove(&mut self, ipfs: &str) {
+        self.entries.remove(ipfs);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Whether the AdSlot has a [`SlotVersion`] and it expired less than [`REVALIDATABLE_FOR_TTLS`] ago
+fn is_revalidatable(cached: &CachedSlot, now: Instant, ttl: Duration) -> bool {
+    !cached.version.is_empty()
+        && now.saturating_duration_since(cached.fetched_at) < ttl * REVALIDATABLE_FOR_TTLS
+}
+
+/// The number of shards of the [`SlotPopularity`]
+const POPULARITY_SHARDS: usize = 16;
+
+/// The requests of an AdSlot, the `hits` since the last decay are added to its decayed `count` by the next one
+#[derive(Debug, Default)]
+struct Popularity {
+    hits: AtomicU64,
+    count: f64,
+}
+
+impl Popularity {
+    fn requests(&self) -> f64 {
+        self.count + self.hits.load(AtomicOrdering::Relaxed) as f64
+    }
+}
+
+/// The request counts of the AdSlots, decaying with the [`POPULARITY_HALF_LIFE`].
+///
+/// The requests of the tracked AdSlots are counted under the read lock of their shard (sharded by the ipfs),
+/// only the first request of an AdSlot takes the write lock. While a shard tracks its share of the [`MAX_TRACKED_SLOTS`]
+/// the new AdSlots are not tracked, until the next [`decay`](Self::decay) keeps only the more requested half of them.
+#[derive(Debug)]
+pub struct SlotPopularity {
+    shards: Vec<RwLock<HashMap<String, Popularity>>>,
+    decayed_at: Mutex<Option<Instant>>,
+}
+
+impl Default for SlotPopularity {
+    fn default() -> Self {
+        Self {
+            shards: (0..POPULARITY_SHARDS).map(|_| RwLock::default()).collect(),
+            decayed_at: Mutex::default(),
+        }
+    }
+}
+
+impl SlotPopularity {
+    /// Counts a request of the AdSlot
+    pub fn hit(&self, ipfs: &str) {
+        let shard = &self.shards[shard_of(ipfs)];
+
+        {
+            let counts = shard
+                .read()
+                .expect("The slot popularity should not be poisoned");
+            if let Some(popularity) = counts.get(ipfs) {
+                popularity.hits.fetch_add(1, AtomicOrdering::Relaxed);
+
+                return;
+            }
+        }
+
+        let mut counts = shard
+            .write()
+            .expect("The slot popularity should not be poisoned");
+        let capacity = MAX_TRACKED_SLOTS / POPULARITY_SHARDS;
+        if counts.len() >= capacity && !counts.contains_key(ipfs) {
+            return;
+        }
+        counts
+            .entry(ipfs.to_string())
+            .or_default()
+            .hits
+            .fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    /// Decays the counts by the time passed since the last decay,
+    /// drops the AdSlots which are barely requested anymore and the less requested half of the full shards.
+    pub fn decay(&self, now: Instant) {
+        let decayed_at = self
+            .decayed_at
+            .lock()
+            .expect("The slot popularity should not be poisoned")
+            .replace(now)
+            .unwrap_or(now);
+        let half_lives = now.saturating_duration_since(decayed_at).as_secs_f64()
+            / POPULARITY_HALF_LIFE.as_secs_f64();
+        let factor = 0.5_f64.powf(half_lives);
+        let capacity = MAX_TRACKED_SLOTS / POPULARITY_SHARDS;
+
+        for shard in self.shards.iter() {
+            let mut counts = shard
+                .write()
+                .expect("The slot popularity should not be poisoned");
+
+            counts.retain(|_, popularity| {
+                let hits = popularity.hits.swap(0, AtomicOrdering::Relaxed);
+                popularity.count = (popularity.count + hits as f64) * factor;
+
+                popularity.count >= MIN_POPULARITY
+            });
+
+            if counts.len() >= capacity {
+                let mut by_count = counts
+                    .iter()
+                    .map(|(ipfs, popularity)| (ipfs.clone(), popularity.count))
+                    .collect::<Vec<_>>();
+                by_count.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+                for (ipfs, _) in by_count.into_iter().skip(capacity / 2) {
+                    counts.remove(&ipfs);
+                }
+            }
+        }
+    }
+
+    /// The `n` most requested AdSlots, the most requested first
+    pub fn top(&self, n: usize) -> Vec<String> {
+        let mut counts = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard
+                .read()
+                .expect("The slot popularity should not be poisoned");
+
+            counts.extend(
+                shard
+                    .iter()
+                    .map(|(ipfs, popularity)| (ipfs.clone(), popularity.requests())),
+            );
+        }
+        // the ipfs breaks the ties, so the order is deterministic
+        counts.sort_by(|(a_ipfs, a), (b_ipfs, b)| {
+            b.partial_cmp(a)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a_ipfs.cmp(b_ipfs))
+        });
+
+        counts.into_iter().take(n).map(|(ipfs, _)| ipfs).collect()
+    }
+
+    pub fn remove(&self, ipfs: &str) {
+        self.shards[shard_of(ipfs)]
+            .write()
+            .expect("The slot popularity should not be poisoned")
+            .remove(ipfs);
+    }
+
+    /// The decayed requests of the AdSlot, if it's tracked
+    pub fn requests(&self, ipfs: &str) -> Option<f64> {
+        self.shards[shard_of(ipfs)]
+            .read()
+            .expect("The slot popularity should not be poisoned")
+            .get(ipfs)
+            .map(Popularity::requests)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .read()
+                    .expect("The slot popularity should not be poisoned")
+                    .len()
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn shard_of(ipfs: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    ipfs.hash(&mut hasher);
+
+    hasher.finish() as usize % POPULARITY_SHARDS
+}
+
+/// Counts a request of the AdSlot for its popularity, once the AdSlot was resolved
+/// (i.e. cached or fetched from the Market), so the popularity only tracks the existing AdSlots.
+pub fn count_request<C: Client>(cache: &Cache<C>, config: &Config, ipfs: &str) {
+    if config.prewarm.is_enabled() {
+        cache.slot_popularity.hit(ipfs);
+    }
+}
+
+/// The cached AdSlot, if it's still fresh or it can be revalidated.
+///
+/// Counts the request in the [`SLOT_CACHE_REQUESTS`], its popularity is counted by [`count_request`] once the AdSlot is resolved.
+pub async fn cached_slot<C: Client>(cache: &Cache<C>, config: &Config, ipfs: &str) -> SlotLookup {
+    let ttl = config.prewarm.slot_cache_ttl;
+    if ttl == Duration::from_secs(0) {
+        return SlotLookup::Missing;
+    }
+
+    let now = cache.clock().now_instant();
+    let lookup = cache.slots.read().await.lookup(ipfs, now, ttl);
+    let result = match lookup {
+        SlotLookup::Fresh(_) => "hit",
+        SlotLookup::Stale(_) => "revalidate",
+        SlotLookup::Missing => "miss",
+    };
+    SLOT_CACHE_REQUESTS.with_label_values(&[result]).inc();
+
+    lookup
+}
+
+/// Caches the fetched AdSlot (if the caching is enabled)
+pub async fn cache_slot<C: Client>(
+    cache: &Cache<C>,
+    config: &Config,
+    ipfs: &str,
+    slot: AdSlotResponse,
+    version: SlotVersion,
+    units: SlotUnits,
+) -> Arc<CachedSlot> {
+    let cached = CachedSlot {
+        rules_complexity: RulesComplexity::of(&slot.slot.rules),
+        slot,
+        units: Some(units.units),
+        units_truncated: units.truncated,
+        version,
+        fetched_at: cache.clock().now_instant(),
+    };
+
+    insert(cache, config, ipfs, cached).await
+}
+
+/// Caches the AdSlot of a proxied response again with its fetched AdUnits,
+/// it still expires `slot_cache_ttl` after the proxied response
+pub async fn cache_units<C: Client>(
+    cache: &Cache<C>,
+    config: &Config,
+    ipfs: &str,
+    proxied: &CachedSlot,
+    units: SlotUnits,
+) -> Arc<CachedSlot> {
+    let cached = CachedSlot {
+        slot: proxied.slot.clone(),
+        units: Some(units.units),
+        units_truncated: units.truncated,
+        version: proxied.version.clone(),
+        fetched_at: proxied.fetched_at,
+        rules_complexity: proxied.rules_complexity,
+    };
+
+    insert(cache, config, ipfs, cached).await
+}
+
+/// Caches the AdSlot and drops its negative cached `404`, since the Market has it now
+async fn insert<C: Client>(
+    cache: &Cache<C>,
+    config: &Config,
+    ipfs: &str,
+    cached: CachedSlot,
+) -> Arc<CachedSlot> {
+    let ttl = config.prewarm.slot_cache_ttl;
+    let cached = Arc::new(cached);
+    cache.negative_slots.write().await.remove(ipfs);
+
+    if ttl > Duration::from_secs(0) {
+        let now = cache.clock().now_instant();
+        cache
+            .slots
+            .write()
+            .await
+            .insert(ipfs.to_string(), cached.clone(), now, ttl);
+    }
+
+    cached
+}
+
+/// Caches the `stale` AdSlot again, since the Market responded with `304 Not Modified`
+pub async fn revalidated_slot<C: Client>(
+    cache: &Cache<C>,
+    config: &Config,
+    ipfs: &str,
+    stale: &CachedSlot,
+) -> Arc<CachedSlot> {
+    SLOT_REVALIDATIONS
+        .with_label_values(&["not_modified"])
+        .inc();
+
+    let cached = CachedSlot {
+        slot: stale.slot.clone(),
+        units: stale.units.clone(),
+        units_truncated: stale.units_truncated,
+        version: stale.version.clone(),
+        fetched_at: cache.clock().now_instant(),
+        rules_complexity: stale.rules_complexity,
+    };
+
+    insert(cache, config, ipfs, cached).await
+}
+
+/// The AdSlot ipfs of the proxied `/slots/:ipfs` route
+pub(crate) fn slot_route(path: &str) -> Option<&str> {
+    path.strip_prefix(ROUTE_SLOTS)
+        .filter(|ipfs| !ipfs.is_empty() && !ipfs.contains('/'))
+}
+
+/// Caches the AdSlot of the proxied `GET /slots/:ipfs` `response` without its AdUnits,
+/// unless a fresh one is already cached or the caching is disabled.
+///
+/// Only the `200 OK` responses which are not compressed (or are gzipped) are buffered and parsed,
+/// the response is passed through with the same body either way.
+pub async fn warm_from_proxied<C: Client>(
+    logger: &Logger,
+    cache: &Cache<C>,
+    config: &Config,
+    ipfs: &str,
+    response: Response<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let ttl = config.prewarm.slot_cache_ttl;
+    if ttl == Duration::from_secs(0) || response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+
+    let gzipped = match response.headers().get(CONTENT_ENCODING) {
+        None => false,
+        Some(encoding) if encoding == "identity" => false,
+        Some(encoding) if encoding == "gzip" => true,
+        Some(_) => return Ok(response),
+    };
+
+    let now = cache.clock().now_instant();
+    if cache.slots.read().await.get(ipfs, now, ttl).is_some() {
+        return Ok(response);
+    }
+
+    let proxied = ProxiedResponse::new(response);
+    let version = SlotVersion {
+        etag: proxied.headers().get(ETAG).cloned(),
+        last_modified: proxied.headers().get(LAST_MODIFIED).cloned(),
+    };
+    let mut body = Bytes::new();
+    let proxied = proxied
+        .map_body(|bytes| {
+            body = bytes.clone();
+            bytes
+        })
+        .await?;
+
+    match parse_slot(&body, gzipped) {
+        Ok(slot) if slot.slot.ipfs == ipfs => {
+            debug!(logger, "Caching the AdSlot of a proxied response"; "AdSlot" => ipfs);
+
+            let cached = CachedSlot {
+                rules_complexity: RulesComplexity::of(&slot.slot.rules),
+                slot,
+                units: None,
+                units_truncated: false,
+                version,
+                fetched_at: now,
+            };
+            insert(cache, config, ipfs, cached).await;
+        }
+        Ok(slot) => {
+            warn!(logger, "The proxied AdSlot has another ipfs, it's not cached"; "AdSlot" => ipfs, "proxied AdSlot" => &slot.slot.ipfs);
+        }
+        Err(error) => {
+            warn!(logger, "Parsing the proxied AdSlot failed, it's not cached"; "AdSlot" => ipfs, "error" => ?error);
+        }
+    }
+
+    Ok(proxied.into_response())
+}
+
+fn parse_slot(body: &[u8], gzipped: bool) -> std::io::Result<AdSlotResponse> {
+    if !gzipped {
+        return Ok(serde_json::from_slice(body)?);
+    }
+
+    let mut json = vec![];
+    flate2::read::GzDecoder::new(body).read_to_end(&mut json)?;
+
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Refreshes the [`Prewarm.top_slots`](crate::config::Prewarm::top_slots) most requested AdSlots
+/// which are not cached or expire within the `refresh_margin`, the most requested first.
+/// The cached ones with a [`SlotVersion`] are revalidated.
+///
+/// The first failed refresh stops the rest and opens the [`MarketApi::circuit_breaker`] (i.e. the Market is likely down),
+/// while it's open (incl. by the revalidation of the negative cached AdSlots) nothing is refreshed.
+/// Returns the refreshed AdSlots in the order of their popularity.
+pub async fn refresh_popular_slots<C: Client>(
+    logger: &Logger,
+    market: &MarketApi,
+    cache: &Cache<C>,
+    config: &Config,
+) -> Vec<String> {
+    let prewarm = &config.prewarm;
+    let now = cache.clock().now_instant();
+
+    cache.slot_popularity.decay(now);
+    let top = cache.slot_popularity.top(prewarm.top_slots);
+
+    let expiring = {
+        let slots = cache.slots.read().await;
+
+        top.into_iter()
+            .filter(|ipfs| {
+                slots.expires_within(ipfs, now, prewarm.slot_cache_ttl, prewarm.refresh_margin)
+            })
+            .map(|ipfs| {
+                let latest = slots.latest(&ipfs);
+
+                (ipfs, latest)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    if expiring.is_empty() {
+        return vec![];
+    }
+
+    let circuit_breaker = market.circuit_breaker();
+    if circuit_breaker.is_open(now) {
+        SLOT_PREWARMS
+            .with_label_values(&["backed_off"])
+            .inc_by(expiring.len() as u64);
+
+        return vec![];
+    }
+
+    let mut refreshes = stream::iter(expiring)
+        .map(|(ipfs, latest)| async move {
+            let fetched = fetch(market, config, &ipfs, latest).await;
+
+            (ipfs, fetched)
+        })
+        .buffered(REFRESH_CONCURRENCY);
+
+    let mut refreshed = vec![];
+    let mut failed = false;
+    while let Some((ipfs, fetched)) = refreshes.next().await {
+        match fetched {
+            Ok(Fetched::Modified(slot, version, units)) => {
+                cache_slot(cache, config, &ipfs, slot, version, units).await;
+                SLOT_PREWARMS.with_label_values(&["ok"]).inc();
+
+                refreshed.push(ipfs);
+            }
+            Ok(Fetched::NotModified(latest)) => {
+                revalidated_slot(cache, config, &ipfs, &latest).await;
+                SLOT_PREWARMS.with_label_values(&["ok"]).inc();
+
+                refreshed.push(ipfs);
+            }
+            Ok(Fetched::NotFound) => {
+                debug!(logger, "A popular AdSlot is no longer in the Market"; "AdSlot" => &ipfs);
+
+                cache.slot_popularity.remove(&ipfs);
+                cache.slots.write().await.remove(&ipfs);
+            }
+            Err(error) => {
+                SLOT_PREWARMS.with_label_values(&["error"]).inc();
+                warn!(logger, "Refreshing a popular AdSlot failed, backing off"; "AdSlot" => &ipfs, "failures" => circuit_breaker.failures() + 1, "error" => ?error);
+
+                failed = true;
+                break;
+            }
+        }
+    }
+    circuit_breaker.record(!failed, cache.clock().now_instant());
+
+    refreshed
+}
+
+/// A refreshed AdSlot, see [`fetch`]
+enum Fetched {
+    Modified(AdSlotResponse, SlotVersion, SlotUnits),
+    /// The latest cached AdSlot is still valid
+    NotModified(Arc<CachedSlot>),
+    NotFound,
+}
+
+/// Revalidates the `latest` cached AdSlot (if it has a [`SlotVersion`]),
+/// the AdUnits are fetched only if the AdSlot was modified
+async fn fetch(
+    market: &MarketApi,
+    config: &Config,
+    ipfs: &str,
+    latest: Option<Arc<CachedSlot>>,
+) -> market::Result<Fetched> {
+    let version = latest.as_ref().map(|latest| &latest.version);
+    match (market.fetch_slot_if_modified(ipfs, version).await?, latest) {
+        (SlotFetch::Modified(slot, version), latest) => {
+            if latest.map_or(false, |latest| !latest.version.is_empty()) {
+                SLOT_REVALIDATIONS.with_label_values(&["modified"]).inc();
+            }
+            let units = market
+                .fetch_units(&slot.slot, config.limits.max_units_per_slot)
+                .await?;
+
+            Ok(Fetched::Modified(slot, version, units))
+        }
+        (SlotFetch::NotModified, Some(latest)) => Ok(Fetched::NotModified(latest)),
+        // only a conditional request (i.e. with the `latest` AdSlot) is `NotModified`
+        (SlotFetch::NotModified, None) | (SlotFetch::NotFound, _) => Ok(Fetched::NotFound),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cache::MockClient,
+        config::DEVELOPMENT,
+        util::{
+            test::{discard_logger, MockClock},
+            Clock,
+        },
+    };
+    use primitives::{
+        market::AdUnitsResponse,
+        util::tests::prep_db::{DUMMY_AD_UNITS, IDS},
+        AdSlot,
+    };
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn ad_slot_response(ipfs: &str) -> AdSlotResponse {
+        AdSlotResponse {
+            slot: AdSlot {
+                ipfs: ipfs.to_string(),
+                ad_type: "legacy_250x250".to_string(),
+                archived: false,
+                created: chrono::Utc::now(),
+                description: None,
+                fallback_unit: None,
+                min_per_impression: None,
+                modified: None,
+                owner: IDS["publisher"],
+                title: None,
+                website: None,
+                rules: vec![],
+            },
+            accepted_referrers: vec![],
+            categories: vec![],
+            alexa_rank: None,
+        }
+    }
+
+    #[test]
+    fn the_most_requested_slots_are_first() {
+        let popularity = SlotPopularity::default();
+        for (ipfs, requests) in &[("a", 3), ("b", 10), ("c", 1), ("d", 3), ("e", 7)] {
+            for _ in 0..*requests {
+                popularity.hit(ipfs);
+            }
+        }
+
+        // ties are ordered by the ipfs
+        assert_eq!(vec!["b", "e", "a", "d", "c"], popularity.top(10));
+        assert_eq!(vec!["b", "e"], popularity.top(2));
+    }
+
+    #[test]
+    fn the_counts_decay_over_time() {
+        let start = Instant::now();
+        let popularity = SlotPopularity::default();
+        for _ in 0..4 {
+            popularity.hit("old");
+        }
+        popularity.decay(start);
+
+        popularity.hit("new");
+        popularity.hit("new");
+        popularity.decay(start + POPULARITY_HALF_LIFE * 2);
+        // 4 requests two half-lives ago count as 1, the ones since the last decay are decayed as well
+        assert_eq!(vec!["old", "new"], popularity.top(2));
+        assert!((popularity.requests("old").expect("Tracked") - 1.0).abs() < 1e-9);
+        assert!((popularity.requests("new").expect("Tracked") - 0.5).abs() < 1e-9);
+
+        // barely requested ones are dropped
+        popularity.decay(start + POPULARITY_HALF_LIFE * 3);
+        assert_eq!(vec!["old"], popularity.top(2));
+    }
+
+    #[test]
+    fn the_tracked_slots_are_bounded_until_the_decay() {
+        let start = Instant::now();
+        let popularity = SlotPopularity::default();
+        popularity.decay(start);
+
+        popularity.hit("popular");
+        popularity.hit("popular");
+        for i in 0..MAX_TRACKED_SLOTS * 2 {
+            popularity.hit(&format!("slot-{}", i));
+        }
+        assert!(popularity.len() <= MAX_TRACKED_SLOTS);
+        // the tracked ones are still counted
+        popularity.hit("popular");
+        assert_eq!(Some(3.0), popularity.requests("popular"));
+
+        // the less requested half of the full shards makes room for the new AdSlots
+        popularity.decay(start);
+        assert!(popularity.len() <= MAX_TRACKED_SLOTS / 2);
+        assert_eq!(Some(3.0), popularity.requests("popular"));
+
+        popularity.hit("new");
+        assert_eq!(Some(1.0), popularity.requests("new"));
+    }
+
+    #[tokio::test]
+    async fn the_cached_slots_keep_the_complexity_of_their_rules() {
+        let mut config = DEVELOPMENT.clone();
+        config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+        let cache =
+            Cache::initialize(MockClient::init(vec![HashMap::new()], vec![], None).await).await;
+
+        let mut slot = ad_slot_response("slot-a");
+        slot.slot.rules = serde_json::from_value(serde_json::json!([
+            { "onlyShowIf": { "intersects": [{ "get": "adSlot.categories" }, ["IAB3", "IAB5"]] } },
+        ]))
+        .expect("Should deserialize the rules");
+        let complexity = RulesComplexity::of(&slot.slot.rules);
+        assert!(complexity.nodes > 0);
+
+        let units = SlotUnits {
+            units: vec![],
+            truncated: false,
+        };
+        let cached = cache_slot(
+            &cache,
+            &config,
+            "slot-a",
+            slot,
+            SlotVersion::default(),
+            units,
+        )
+        .await;
+        assert_eq!(complexity, cached.rules_complexity);
+
+        let revalidated = revalidated_slot(&cache, &config, "slot-a", &cached).await;
+        assert_eq!(complexity, revalidated.rules_complexity);
+    }
+
+    #[tokio::test]
+    async fn popular_slots_are_refreshed_most_requested_first_before_they_expire() {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+        let market = MarketApi::new(
+            (server.uri() + "/market/")
+                .parse()
+                .expect("Wrong Market url"),
+            &DEVELOPMENT,
+            logger.clone(),
+        )
+        .expect("should create market instance");
+
+        let mut config = DEVELOPMENT.clone();
+        config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+        config.prewarm.top_slots = 3;
+        config.prewarm.refresh_margin = Duration::from_secs(10);
+
+        let clock = MockClock::new();
+        let cache = Cache::builder(MockClient::init(vec![HashMap::new()], vec![], None).await)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+
+        // the seeded popularity table, `gone` is no longer in the Market
+        let seeded = [
+            ("slot-d", 2),
+            ("slot-a", 9),
+            ("gone", 8),
+            ("slot-c", 5),
+            ("slot-b", 7),
+        ];
+        for (ipfs, requests) in seeded.iter() {
+            for _ in 0..*requests {
+                cache.slot_popularity.hit(ipfs);
+            }
+
+            if *ipfs == "gone" {
+                continue;
+            }
+            Mock::given(method("GET"))
+                .and(path(format!("/market/slots/{}", ipfs)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&ad_slot_response(ipfs)))
+                .mount(&server)
+                .await;
+        }
+        let mut ad_unit = DUMMY_AD_UNITS[0].clone();
+        ad_unit.ad_type = "legacy_250x250".to_string();
+        Mock::given(method("GET"))
+            .and(path("/market/units"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![ad_unit.clone()])),
+            )
+            .mount(&server)
+            .await;
+
+        let refreshed = refresh_popular_slots(&logger, &market, &cache, &config).await;
+        assert_eq!(vec!["slot-a", "slot-b"], refreshed);
+        // `gone` is dropped, so the next most requested one takes its place
+        assert_eq!(2, cache.slots.read().await.len());
+
+        clock.advance(Duration::from_secs(5));
+        let refreshed = refresh_popular_slots(&logger, &market, &cache, &config).await;
+        assert_eq!(vec!["slot-c"], refreshed);
+
+        // nothing expires within the margin yet
+        clock.advance(Duration::from_secs(44));
+        let refreshed = refresh_popular_slots(&logger, &market, &cache, &config).await;
+        assert!(refreshed.is_empty());
+
+        // `slot-a` & `slot-b` expire within the margin, before `slot-c`
+        clock.advance(Duration::from_secs(1));
+        let refreshed = refresh_popular_slots(&logger, &market, &cache, &config).await;
+        assert_eq!(vec!["slot-a", "slot-b"], refreshed);
+
+        let now = clock.now_instant();
+        let cached = cache
+            .slots
+            .read()
+            .await
+            .get("slot-a", now, config.prewarm.slot_cache_ttl)
+            .expect("Should be cached");
+        assert_eq!("slot-a", cached.slot.slot.ipfs);
+        let units = cached.units.as_ref().expect("Should have the AdUnits");
+        assert_eq!(1, units.len());
+        assert_eq!(ad_unit.ipfs, units[0].ipfs);
+    }
+
+    #[tokio::test]
+    async fn refreshing_backs_off_while_the_market_is_down() {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+        let market = MarketApi::new(
+            (server.uri() + "/market/")
+                .parse()
+                .expect("Wrong Market url"),
+            &DEVELOPMENT,
+            logger.clone(),
+        )
+        .expect("should create market instance");
+
+        let mut config = DEVELOPMENT.clone();
+        config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+        config.prewarm.top_slots = 10;
+        config.prewarm.refresh_margin = Duration::from_secs(10);
+
+        let clock = MockClock::new();
+        let cache = Cache::builder(MockClient::init(vec![HashMap::new()], vec![], None).await)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+        cache.slot_popularity.hit("slot-a");
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2_u64)
+            .mount(&server)
+            .await;
+
+        // fails and skips the next interval
+        for _ in 0..2 {
+            assert!(refresh_popular_slots(&logger, &market, &cache, &config)
+                .await
+                .is_empty());
+        }
+        assert_eq!(1, market.circuit_breaker().failures());
+
+        // requested again after the backoff
+        clock.advance(market::CIRCUIT_BREAKER_BASE);
+        assert!(refresh_popular_slots(&logger, &market, &cache, &config)
+            .await
+            .is_empty());
+        assert_eq!(2, market.circuit_breaker().failures());
+    }
+}
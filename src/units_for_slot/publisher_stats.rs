@@ -0,0 +1,301 @@
+//! The units-for-slot requests per publisher (the owner of the requested AdSlot) in hourly buckets,
+//! served on the [`ROUTE_PUBLISHER_STATS`](crate::ROUTE_PUBLISHER_STATS) admin route.
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use primitives::ValidatorId;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// The maximum number of publishers which are tracked, the least recently requested one is dropped for a new one
+pub const MAX_TRACKED_PUBLISHERS: usize = 10_000;
+/// How many hourly buckets are kept, i.e. the requests of the last 24 hours (incl. the current one)
+pub const RETENTION_HOURS: i64 = 24;
+
+/// The served units-for-slot responses of a publisher's AdSlots
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestCounts {
+    pub requests: u64,
+    /// The responses with at least one matched AdUnit
+    pub matched: u64,
+    /// The responses with only the fallback AdUnit
+    pub fallbacks: u64,
+}
+
+impl RequestCounts {
+    fn add(&mut self, other: &Self) {
+        self.requests += other.requests;
+        self.matched += other.matched;
+        self.fallbacks += other.fallbacks;
+    }
+}
+
+/// What a units-for-slot response served, see [`PublisherStats::record`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Served {
+    Matched,
+    Fallback,
+    Nothing,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HourlyCounts {
+    /// The start of the hour
+    pub hour: DateTime<Utc>,
+    #[serde(flatten)]
+    pub counts: RequestCounts,
+}
+
+/// The response of `GET /stats/publishers/:address`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublisherRequests {
+    pub publisher: ValidatorId,
+    /// The sum of the `hourly` counts
+    pub total: RequestCounts,
+    /// The hours with requests within the retention, the oldest first
+    pub hourly: Vec<HourlyCounts>,
+}
+
+#[derive(Debug)]
+struct TrackedPublisher {
+    last_request: DateTime<Utc>,
+    /// By the start of the hour
+    buckets: BTreeMap<DateTime<Utc>, RequestCounts>,
+}
+
+impl TrackedPublisher {
+    /// Drops the buckets which are out of the retention
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let oldest = oldest_hour(now);
+        self.buckets = self.buckets.split_off(&oldest);
+    }
+}
+
+/// The request counts of the publishers, bounded by the [`MAX_TRACKED_PUBLISHERS`] and the [`RETENTION_HOURS`]
+#[derive(Debug, Default)]
+pub struct PublisherStats {
+    publishers: HashMap<ValidatorId, TrackedPublisher>,
+}
+
+impl PublisherStats {
+    /// Counts a served response of the publisher's AdSlot in the bucket of the current hour
+    pub fn record(&mut self, publisher: ValidatorId, served: Served, now: DateTime<Utc>) {
+        if !self.publishers.contains_key(&publisher) {
+            self.make_room(now);
+        }
+
+        let tracked = self
+            .publishers
+            .entry(publisher)
+            .or_insert_with(|| TrackedPublisher {
+                last_request: now,
+                buckets: BTreeMap::new(),
+            });
+        tracked.last_request = now;
+        tracked.expire(now);
+
+        let counts = tracked.buckets.entry(hour_of(now)).or_default();
+        counts.requests += 1;
+        match served {
+            Served::Matched => counts.matched += 1,
+            Served::Fallback => counts.fallbacks += 1,
+            Served::Nothing => {}
+        }
+    }
+
+    /// The counts of the publisher within the retention, empty if it's not tracked
+    pub fn get(&self, publisher: ValidatorId, now: DateTime<Utc>) -> PublisherRequests {
+        let oldest = oldest_hour(now);
+        let hourly = self
+            .publishers
+            .get(&publisher)
+            .map(|tracked| {
+                tracked
+                    .buckets
+                    .range(oldest..)
+                    .map(|(hour, counts)| HourlyCounts {
+                        hour: *hour,
+                        counts: *counts,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut total = RequestCounts::default();
+        for hourly in hourly.iter() {
+            total.add(&hourly.counts);
+        }
+
+        PublisherRequests {
+            publisher,
+            total,
+            hourly,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.publishers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.publishers.is_empty()
+    }
+
+    /// Drops the publishers without requests within the retention and,
+    /// if there are still [`MAX_TRACKED_PUBLISHERS`], the least recently requested one.
+    fn make_room(&mut self, now: DateTime<Utc>) {
+        if self.publishers.len() < MAX_TRACKED_PUBLISHERS {
+            return;
+        }
+
+        let oldest = oldest_hour(now);
+        self.publishers
+            .retain(|_, tracked| tracked.last_request >= oldest);
+
+        if self.publishers.len() >= MAX_TRACKED_PUBLISHERS {
+            let least_recent = self
+                .publishers
+                .iter()
+                .min_by_key(|(_, tracked)| tracked.last_request)
+                .map(|(publisher, _)| *publisher);
+
+            if let Some(least_recent) = least_recent {
+                self.publishers.remove(&least_recent);
+            }
+        }
+    }
+}
+
+/// The start of the hour
+fn hour_of(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(Duration::hours(1))
+        .expect("Truncating to an hour should not overflow")
+}
+
+/// The start of the oldest hour within the retention
+fn oldest_hour(now: DateTime<Utc>) -> DateTime<Utc> {
+    hour_of(now) - Duration::hours(RETENTION_HOURS - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::{test::MockClock, Clock};
+    use chrono::TimeZone;
+    use primitives::util::tests::prep_db::IDS;
+
+    #[test]
+    fn requests_are_counted_in_hourly_buckets() {
+        let clock = MockClock::starting_at(Utc.ymd(2021, 3, 1).and_hms(10, 30, 0));
+        let publisher = IDS["publisher"];
+        let mut stats = PublisherStats::default();
+
+        stats.record(publisher, Served::Matched, clock.now_utc());
+        stats.record(publisher, Served::Fallback, clock.now_utc());
+        // rolls over to the next hour
+        clock.advance(std::time::Duration::from_secs(45 * 60));
+        stats.record(publisher, Served::Nothing, clock.now_utc());
+        stats.record(publisher, Served::Matched, clock.now_utc());
+        stats.record(IDS["publisher2"], Served::Matched, clock.now_utc());
+
+        let requests = stats.get(publisher, clock.now_utc());
+        let hours = requests
+            .hourly
+            .iter()
+            .map(|hourly| (hourly.hour, hourly.counts))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    Utc.ymd(2021, 3, 1).and_hms(10, 0, 0),
+                    RequestCounts {
+                        requests: 2,
+                        matched: 1,
+                        fallbacks: 1,
+                    }
+                ),
+                (
+                    Utc.ymd(2021, 3, 1).and_hms(11, 0, 0),
+                    RequestCounts {
+                        requests: 2,
+                        matched: 1,
+                        fallbacks: 0,
+                    }
+                ),
+            ],
+            hours
+        );
+        assert_eq!(
+            RequestCounts {
+                requests: 4,
+                matched: 2,
+                fallbacks: 1,
+            },
+            requests.total
+        );
+
+        // the 10:00 bucket is out of the retention at 10:00 the next day
+        clock.advance(std::time::Duration::from_secs(
+            RETENTION_HOURS as u64 * 60 * 60 - 60 * 60,
+        ));
+        let requests = stats.get(publisher, clock.now_utc());
+        assert_eq!(1, requests.hourly.len());
+        assert_eq!(2, requests.total.requests);
+
+        stats.record(publisher, Served::Matched, clock.now_utc());
+        let requests = stats.get(publisher, clock.now_utc());
+        assert_eq!(
+            vec![
+                Utc.ymd(2021, 3, 1).and_hms(11, 0, 0),
+                Utc.ymd(2021, 3, 2).and_hms(10, 0, 0),
+            ],
+            requests
+                .hourly
+                .iter()
+                .map(|hourly| hourly.hour)
+                .collect::<Vec<_>>()
+        );
+
+        // an untracked publisher has no requests
+        let untracked = stats.get(IDS["leader"], clock.now_utc());
+        assert!(untracked.hourly.is_empty());
+        assert_eq!(RequestCounts::default(), untracked.total);
+    }
+
+    #[test]
+    fn the_least_recently_requested_publisher_is_evicted() {
+        let clock = MockClock::starting_at(Utc.ymd(2021, 3, 1).and_hms(10, 0, 0));
+        let mut stats = PublisherStats::default();
+        let publisher = |index: usize| {
+            let mut bytes = [0_u8; 20];
+            bytes[..8].copy_from_slice(&(index as u64).to_be_bytes());
+
+            ValidatorId::from(&bytes)
+        };
+
+        for index in 0..MAX_TRACKED_PUBLISHERS {
+            stats.record(publisher(index), Served::Matched, clock.now_utc());
+            clock.advance(std::time::Duration::from_millis(1));
+        }
+        // the first publisher is requested again, so the second one is the least recent
+        stats.record(publisher(0), Served::Matched, clock.now_utc());
+        clock.advance(std::time::Duration::from_millis(1));
+
+        stats.record(IDS["publisher"], Served::Matched, clock.now_utc());
+        assert_eq!(MAX_TRACKED_PUBLISHERS, stats.len());
+        assert!(stats.get(publisher(1), clock.now_utc()).hourly.is_empty());
+        assert_eq!(2, stats.get(publisher(0), clock.now_utc()).total.requests);
+        assert_eq!(
+            1,
+            stats.get(IDS["publisher"], clock.now_utc()).total.requests
+        );
+
+        // the publishers without requests within the retention are dropped first
+        clock.advance(std::time::Duration::from_secs(
+            RETENTION_HOURS as u64 * 60 * 60,
+        ));
+        stats.record(IDS["publisher2"], Served::Fallback, clock.now_utc());
+        assert_eq!(1, stats.len());
+    }
+}
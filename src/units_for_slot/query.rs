@@ -0,0 +1,554 @@
+use super::selection::Strategy;
+use crate::ids::Canonical;
+use primitives::ValidatorId;
+use thiserror::Error;
+
+/// The smallest and the largest allowed UTC offsets in minutes for the `?tz=` query parameter
+pub const TIMEZONE_OFFSET_BOUNDS: (i32, i32) = (-12 * 60, 14 * 60);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Malformed query parameter `{parameter}`")]
+pub struct MalformedParameter {
+    pub parameter: &'static str,
+}
+
+/// How the repeated occurrences of a query parameter are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeated {
+    /// All of the values are used, e.g. `?depositAsset=A&depositAsset=B`
+    Accumulate,
+    /// The last value is used, e.g. `5` of `?limit=10&limit=5`
+    Last,
+}
+
+/// The type of the value of a query parameter, described in the [`openapi`](crate::openapi) document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterKind {
+    /// `?name`, `?name=true` or `?name=false`
+    Flag,
+    Integer,
+    Number,
+    String,
+}
+
+/// A query parameter of the units-for-slot route, see [`PARAMETERS`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParameterSpec {
+    pub name: &'static str,
+    /// The legacy names of the parameter (e.g. the snake_case ones), they are the same as the `name`
+    pub aliases: &'static [&'static str],
+    pub repeated: Repeated,
+    pub kind: ParameterKind,
+    /// Served in the [`openapi`](crate::openapi) document
+    pub description: &'static str,
+}
+
+/// All of the parameters of [`UnitsForSlotQuery`] with their aliases
+pub const PARAMETERS: &[ParameterSpec] = &[
+    ParameterSpec {
+        name: "noTargeting",
+        aliases: &["no_targeting"],
+        repeated: Repeated::Last,
+        kind: ParameterKind::Flag,
+        description: "The targeting rules of the Campaigns are not applied",
+    },
+    ParameterSpec {
+        name: "depositAsset",
+        aliases: &["deposit_asset"],
+        repeated: Repeated::Accumulate,
+        kind: ParameterKind::String,
+        description: "Only the Campaigns with one of the deposit assets (addresses in either case), empty values are ignored",
+    },
+    ParameterSpec {
+        name: "skip",
+        aliases: &[],
+        repeated: Repeated::Last,
+        kind: ParameterKind::Integer,
+        description: "The number of the matched units (sorted by price) to skip",
+    },
+    ParameterSpec {
+        name: "limit",
+        aliases: &[],
+        repeated: Repeated::Last,
+        kind: ParameterKind::Integer,
+        description: "At most this many matched units are returned",
+    },
+    ParameterSpec {
+        name: "tz",
+        aliases: &[],
+        repeated: Repeated::Last,
+        kind: ParameterKind::Integer,
+        description: "The UTC offset of the viewer in minutes (from `-720` to `840`), e.g. `120` or `-300`, defaults to `0`",
+    },
+    ParameterSpec {
+        name: "minScore",
+        aliases: &["min_score"],
+        repeated: Repeated::Last,
+        kind: ParameterKind::Number,
+        description: "Overrides the `min_targeting_score` of the config",
+    },
+    ParameterSpec {
+        name: "debug",
+        aliases: &[],
+        repeated: Repeated::Last,
+        kind: ParameterKind::Flag,
+        description: "Shows the score of each unit, when its Campaign was last refreshed and the archived units",
+    },
+    ParameterSpec {
+        name: "gdpr_consent",
+        aliases: &["gdprConsent"],
+        repeated: Repeated::Last,
+        kind: ParameterKind::String,
+        description: "The TCF consent string, without a valid one the personal inputs are not used",
+    },
+    ParameterSpec {
+        name: "type",
+        aliases: &[],
+        repeated: Repeated::Accumulate,
+        kind: ParameterKind::String,
+        description: "The AdUnit types (sizes) to return instead of the AdSlot's one, empty and duplicate values are ignored",
+    },
+    ParameterSpec {
+        name: "rawIpfs",
+        aliases: &["raw_ipfs"],
+        repeated: Repeated::Last,
+        kind: ParameterKind::Flag,
+        description: "The `ipfs://` media URLs are not rewritten to the IPFS gateway",
+    },
+    ParameterSpec {
+        name: "strategy",
+        aliases: &[],
+        repeated: Repeated::Last,
+        kind: ParameterKind::String,
+        description: "The order of the matched units (`highest_price`, `best_score`, `weighted_random` or `round_robin`), \
+            only the default one and the ones allowed by the `selection` config",
+    },
+];
+
+/// The spec of the parameter by its name or one of its aliases, `None` for unknown parameters
+pub fn parameter(key: &str) -> Option<&'static ParameterSpec> {
+    PARAMETERS
+        .iter()
+        .find(|spec| spec.name == key || spec.aliases.contains(&key))
+}
+
+/// The query parameters of the units-for-slot route, see [`PARAMETERS`].
+/// Unknown parameters are ignored.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UnitsForSlotQuery {
+    /// `?noTargeting` or `?noTargeting=true` - the targeting rules of the Campaigns are not applied
+    pub no_targeting: bool,
+    /// `?depositAsset=` can be repeated, the addresses are parsed in either case (see [`Canonical`]), empty values are ignored
+    pub deposit_asset: Vec<ValidatorId>,
+    /// `?skip=N`
+    pub skip: usize,
+    /// `?limit=N`
+    pub limit: Option<usize>,
+    /// `?tz=N` - the UTC offset of the viewer in minutes, defaults to `0` (UTC)
+    pub timezone_offset: i32,
+    /// `?minScore=N` - overrides the `min_targeting_score` of the [`Config`](crate::Config)
+    pub min_score: Option<f64>,
+    /// `?debug=true` - shows the score of each unit
+    pub debug: bool,
+    /// `?gdpr_consent=` - the TCF consent string, empty values are ignored
+    pub gdpr_consent: Option<String>,
+    /// `?type=` can be repeated for the units of multiple sizes, empty and duplicate values are ignored
+    pub types: Vec<String>,
+    /// `?rawIpfs` or `?rawIpfs=true` - the `ipfs://` media URLs are not rewritten to the IPFS gateway
+    pub raw_ipfs: bool,
+    /// `?strategy=` - the [`Strategy`] instead of the default one of the [`Selection`](crate::config::Selection)
+    pub strategy: Option<Strategy>,
+}
+
+impl UnitsForSlotQuery {
+    /// See [`UnitsForSlotQuery::parse_with_overridden`]
+    pub fn parse(query: &str) -> Result<Self, MalformedParameter> {
+        Self::parse_with_overridden(query).map(|(parsed, _)| parsed)
+    }
+
+    /// The values of the [`Repeated::Accumulate`] parameters are accumulated,
+    /// for the [`Repeated::Last`] ones the last value is used (even if it's under an alias)
+    /// and the overridden parameters are returned (once each).
+    /// A malformed value is an error even if it's overridden.
+    pub fn parse_with_overridden(
+        query: &str,
+    ) -> Result<(Self, Vec<&'static str>), MalformedParameter> {
+        let mut parsed = Self::default();
+        let (mut seen, mut overridden) = (vec![], vec![]);
+
+        for (key, value) in pairs(query) {
+            let spec = match key.as_deref().and_then(parameter) {
+                Some(spec) => spec,
+                None => continue,
+            };
+            if spec.repeated == Repeated::Last {
+                if !seen.contains(&spec.name) {
+                    seen.push(spec.name);
+                } else if !overridden.contains(&spec.name) {
+                    overridden.push(spec.name);
+                }
+            }
+
+            let value = value.ok_or_else(|| malformed(spec.name))?;
+
+            match spec.name {
+                "noTargeting" => parsed.no_targeting = parse_flag(&value, spec.name)?,
+                "depositAsset" if !value.is_empty() => {
+                    let Canonical(deposit_asset) =
+                        value.parse().map_err(|_| malformed(spec.name))?;
+
+                    parsed.deposit_asset.push(deposit_asset)
+                }
+                "skip" => parsed.skip = value.parse().map_err(|_| malformed(spec.name))?,
+                "limit" => parsed.limit = Some(value.parse().map_err(|_| malformed(spec.name))?),
+                "tz" => {
+                    let (min, max) = TIMEZONE_OFFSET_BOUNDS;
+
+                    parsed.timezone_offset = value
+                        .parse::<i32>()
+                        .ok()
+                        .filter(|offset| (min..=max).contains(offset))
+                        .ok_or_else(|| malformed(spec.name))?;
+                }
+                "minScore" => {
+                    parsed.min_score = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|score| score.is_finite())
+                        .map(Some)
+                        .ok_or_else(|| malformed(spec.name))?;
+                }
+                "debug" => parsed.debug = parse_flag(&value, spec.name)?,
+                "rawIpfs" => parsed.raw_ipfs = parse_flag(&value, spec.name)?,
+                "strategy" => {
+                    parsed.strategy = Some(value.parse().map_err(|_| malformed(spec.name))?)
+                }
+                "gdpr_consent" if !value.is_empty() => parsed.gdpr_consent = Some(value),
+                "type" if !value.is_empty() && !parsed.types.iter().any(|ty| *ty == value) => {
+                    parsed.types.push(value)
+                }
+                _ => {}
+            }
+        }
+
+        Ok((parsed, overridden))
+    }
+}
+
+/// The decoded `key=value` pairs of the `application/x-www-form-urlencoded` query (`+` is a space).
+/// Unlike the [`form_urlencoded::parse`](url::form_urlencoded::parse) a key or a value which isn't UTF-8 once decoded
+/// is `None` (so it's refused) instead of having the invalid bytes replaced with `U+FFFD`,
+/// the malformed escapes (e.g. `100%`) are kept as they are.
+fn pairs(query: &str) -> impl Iterator<Item = (Option<String>, Option<String>)> + '_ {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut split = pair.splitn(2, '=');
+            let key = split.next().unwrap_or_default();
+            let value = split.next().unwrap_or_default();
+
+            (decode(key), decode(value))
+        })
+}
+
+fn decode(component: &str) -> Option<String> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = match bytes[index] {
+            b'%' => bytes
+                .get(index + 1..index + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()),
+            _ => None,
+        };
+
+        match (escaped, bytes[index]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (None, b'+') => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            (None, byte) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn malformed(parameter: &'static str) -> MalformedParameter {
+    MalformedParameter { parameter }
+}
+
+/// A flag without a value (e.g. `?debug`) is `true`
+fn parse_flag(value: &str, parameter: &'static str) -> Result<bool, MalformedParameter> {
+    match value {
+        "" | "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(malformed(*parameter)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DAI: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+    const SAI: &str = "0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359";
+
+    fn asset(address: &str) -> ValidatorId {
+        let Canonical(asset) = address.parse().expect("Valid address");
+
+        asset
+    }
+
+    #[test]
+    fn parses_the_query() {
+        let query = UnitsForSlotQuery::parse(&format!(
+            "noTargeting&depositAsset={}&depositAsset={}&skip=10&limit=5&tz=-300&minScore=1.5&debug=true&gdpr_consent=CO&type=legacy_300x250&type=legacy_728x90&rawIpfs&strategy=best_score&unknown=1",
+            DAI, SAI
+        ))
+        .expect("Should parse");
+
+        let expected = UnitsForSlotQuery {
+            no_targeting: true,
+            deposit_asset: vec![asset(DAI), asset(SAI)],
+            skip: 10,
+            limit: Some(5),
+            timezone_offset: -300,
+            min_score: Some(1.5),
+            debug: true,
+            gdpr_consent: Some("CO".to_string()),
+            types: vec!["legacy_300x250".to_string(), "legacy_728x90".to_string()],
+            raw_ipfs: true,
+            strategy: Some(Strategy::BestScore),
+        };
+
+        assert_eq!(expected, query);
+        assert_eq!(
+            Ok(UnitsForSlotQuery::default()),
+            UnitsForSlotQuery::parse("")
+        );
+    }
+
+    #[test]
+    fn substrings_of_parameters_are_ignored() {
+        let query = UnitsForSlotQuery::parse("foo=noTargetingPlease&noTargetingPlease=true")
+            .expect("Should parse");
+
+        assert!(!query.no_targeting);
+    }
+
+    #[test]
+    fn duplicated_parameters() {
+        // the last value is used
+        let query =
+            UnitsForSlotQuery::parse("limit=1&limit=2&noTargeting&noTargeting=false&tz=60&tz=120")
+                .expect("Should parse");
+        assert_eq!(Some(2), query.limit);
+        assert!(!query.no_targeting);
+        assert_eq!(120, query.timezone_offset);
+
+        // all deposit assets are used, in either case
+        let query = UnitsForSlotQuery::parse(&format!(
+            "depositAsset={}&depositAsset={}&depositAsset={}",
+            DAI,
+            DAI.to_ascii_lowercase(),
+            SAI
+        ))
+        .expect("Should parse");
+        assert_eq!(
+            vec![asset(DAI), asset(DAI), asset(SAI)],
+            query.deposit_asset
+        );
+
+        // the types are deduplicated
+        let query =
+            UnitsForSlotQuery::parse("type=legacy_728x90&type=legacy_300x250&type=legacy_728x90")
+                .expect("Should parse");
+        assert_eq!(vec!["legacy_728x90", "legacy_300x250"], query.types);
+
+        // a malformed duplicate is still an error
+        assert_eq!(
+            Err(malformed("skip")),
+            UnitsForSlotQuery::parse("skip=1&skip=many")
+        );
+    }
+
+    #[test]
+    fn repeated_parameters_by_their_spec() {
+        // every accumulated parameter is used with both of its values
+        // and every other one with its last value, also under an alias
+        let values = |name: &str| match name {
+            "noTargeting" | "debug" | "rawIpfs" => ("false", "true"),
+            "tz" => ("60", "120"),
+            "minScore" => ("0.5", "1.5"),
+            "strategy" => ("best_score", "round_robin"),
+            "depositAsset" => (DAI, SAI),
+            _ => ("1", "2"),
+        };
+        for spec in PARAMETERS {
+            for last in std::iter::once(&spec.name).chain(spec.aliases) {
+                let (first_value, last_value) = values(spec.name);
+                let query = format!("{}={}&{}={}", spec.name, first_value, last, last_value);
+                let (parsed, overridden) =
+                    UnitsForSlotQuery::parse_with_overridden(&query).expect("Should parse");
+
+                let only_last = UnitsForSlotQuery::parse(&format!("{}={}", spec.name, last_value))
+                    .expect("Should parse");
+                match spec.repeated {
+                    Repeated::Last => {
+                        assert_eq!(only_last, parsed, "{}", query);
+                        assert_eq!(vec![spec.name], overridden, "{}", query);
+                    }
+                    Repeated::Accumulate => {
+                        assert_ne!(only_last, parsed, "{}", query);
+                        assert!(overridden.is_empty(), "{}", query);
+                    }
+                }
+            }
+        }
+
+        let (_, overridden) = UnitsForSlotQuery::parse_with_overridden(
+            "limit=1&limit=2&limit=3&minScore=1&min_score=2&skip=1&type=a&type=b",
+        )
+        .expect("Should parse");
+        assert_eq!(vec!["limit", "minScore"], overridden);
+    }
+
+    #[test]
+    fn aliases() {
+        let query = UnitsForSlotQuery::parse(&format!(
+            "no_targeting&deposit_asset={}&depositAsset={}&min_score=1.5&gdprConsent=CO&raw_ipfs=true",
+            DAI, SAI
+        ))
+        .expect("Should parse");
+
+        let expected = UnitsForSlotQuery {
+            no_targeting: true,
+            deposit_asset: vec![asset(DAI), asset(SAI)],
+            min_score: Some(1.5),
+            gdpr_consent: Some("CO".to_string()),
+            raw_ipfs: true,
+            ..Default::default()
+        };
+        assert_eq!(expected, query);
+
+        // malformed values are named by the parameter, not by the alias
+        assert_eq!(
+            Err(malformed("minScore")),
+            UnitsForSlotQuery::parse("min_score=high")
+        );
+    }
+
+    #[test]
+    fn parameter_names_are_unique() {
+        let mut names = PARAMETERS
+            .iter()
+            .flat_map(|spec| std::iter::once(&spec.name).chain(spec.aliases))
+            .collect::<Vec<_>>();
+        let count = names.len();
+        names.sort();
+        names.dedup();
+
+        assert_eq!(count, names.len());
+        for name in names {
+            assert!(parameter(name).is_some());
+        }
+        assert_eq!(None, parameter("unknown"));
+    }
+
+    #[test]
+    fn empty_parameters() {
+        let query =
+            UnitsForSlotQuery::parse("noTargeting=&depositAsset=&debug&gdpr_consent=&type=")
+                .expect("Should parse");
+        assert!(query.no_targeting);
+        assert!(query.deposit_asset.is_empty());
+        assert!(query.debug);
+        assert_eq!(None, query.gdpr_consent);
+        assert!(query.types.is_empty());
+
+        for (query, parameter) in &[
+            ("skip=", "skip"),
+            ("limit=", "limit"),
+            ("tz=", "tz"),
+            ("minScore=", "minScore"),
+        ] {
+            assert_eq!(
+                Err(malformed(*parameter)),
+                UnitsForSlotQuery::parse(query),
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_parameters_are_named() {
+        for (query, parameter) in &[
+            ("noTargeting=yes", "noTargeting"),
+            ("skip=-1", "skip"),
+            ("limit=ten", "limit"),
+            ("tz=UTC", "tz"),
+            ("tz=841", "tz"),
+            ("tz=-721", "tz"),
+            ("minScore=NaN", "minScore"),
+            ("debug=1", "debug"),
+            ("depositAsset=0xA", "depositAsset"),
+            ("depositAsset=DAI", "depositAsset"),
+            (
+                "depositAsset=0x6B175474E89094C44Da98b954EedeAC495271d0",
+                "depositAsset",
+            ),
+        ] {
+            let error = UnitsForSlotQuery::parse(query).expect_err("Should be malformed");
+
+            assert_eq!(*parameter, error.parameter);
+            assert_eq!(
+                format!("Malformed query parameter `{}`", parameter),
+                error.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn utf8_values_are_decoded_and_the_invalid_ones_refused() {
+        // Cyrillic, a combining accent & an emoji, percent-encoded and as they are
+        let query = UnitsForSlotQuery::parse(
+            "gdpr_consent=%D0%BA%D0%B8%D0%BD%D0%BE+cafe%CC%81+%F0%9F%8E%AE&type=legacy_300x250&type=кино&type=100%",
+        )
+        .expect("Should parse");
+        assert_eq!(Some("кино cafe\u{301} 🎮".to_string()), query.gdpr_consent);
+        // a malformed escape is kept as it is
+        assert_eq!(vec!["legacy_300x250", "кино", "100%"], query.types);
+
+        // not replaced with `U+FFFD`
+        for (query, parameter) in &[
+            ("type=%FF", "type"),
+            ("gdpr_consent=%D0", "gdpr_consent"),
+            ("depositAsset=0x%C3%28", "depositAsset"),
+        ] {
+            assert_eq!(
+                Err(malformed(*parameter)),
+                UnitsForSlotQuery::parse(query),
+                "{}",
+                query
+            );
+        }
+        // unless it's an unknown parameter
+        assert_eq!(
+            Ok(UnitsForSlotQuery::default()),
+            UnitsForSlotQuery::parse("%FF=1&unknown=%FF")
+        );
+    }
+}
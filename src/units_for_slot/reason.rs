@@ -0,0 +1,198 @@
+//! Why a units-for-slot response has no matched units, derived from the stage of the pipeline
+//! at which there were no Campaigns (or units) left, see [`empty_reason`].
+use serde::{Deserialize, Serialize};
+
+/// The `reason` of a units-for-slot response without matched units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyReason {
+    /// Fetching the AdUnits of the AdSlot timed out, the Campaigns weren't looked up
+    UnitsTimeout,
+    /// The AdSlot's override forces its fallback AdUnit, the Campaigns weren't looked up
+    ForcedFallback,
+    /// The Cache is degraded and the `fallback-only` policy is applied, the Campaigns weren't looked up
+    Degraded,
+    /// There are no Active Campaigns in the Cache
+    NoActiveCampaigns,
+    /// None of the Active Campaigns is served to the publisher,
+    /// e.g. because of their deposit asset, staleness or the publisher is the creator
+    NoEligibleCampaigns,
+    /// None of the Campaigns has (non-archived) units of the type
+    NoUnitsOfType,
+    /// The max IMPRESSION price of all the Campaigns with units of the type is below the `global_min_impression_price`
+    FilteredByPrice,
+    /// None of the units passed the targeting
+    NoMatch,
+}
+
+impl EmptyReason {
+    /// Every reason in the order of the pipeline stages
+    pub const ALL: [EmptyReason; 8] = [
+        Self::UnitsTimeout,
+        Self::ForcedFallback,
+        Self::Degraded,
+        Self::NoActiveCampaigns,
+        Self::NoEligibleCampaigns,
+        Self::NoUnitsOfType,
+        Self::FilteredByPrice,
+        Self::NoMatch,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UnitsTimeout => "units_timeout",
+            Self::ForcedFallback => "forced_fallback",
+            Self::Degraded => "degraded",
+            Self::NoActiveCampaigns => "no_active_campaigns",
+            Self::NoEligibleCampaigns => "no_eligible_campaigns",
+            Self::NoUnitsOfType => "no_units_of_type",
+            Self::FilteredByPrice => "filtered_by_price",
+            Self::NoMatch => "no_match",
+        }
+    }
+}
+
+/// What was left after each stage of the units-for-slot pipeline for a single type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Funnel {
+    pub units_timed_out: bool,
+    /// Only the fallback AdUnit is served because of the `force_fallback` of the AdSlot's override
+    pub forced_fallback: bool,
+    /// Only the fallback AdUnit is served because of the `fallback-only` degradation policy
+    pub fallback_only: bool,
+    /// The Active Campaigns in the Cache
+    pub active: usize,
+    /// The Campaigns served to the publisher
+    pub eligible: usize,
+    /// The eligible Campaigns with non-archived units of the type
+    pub with_units: usize,
+    /// The Campaigns with units whose max IMPRESSION price is not below the `global_min_impression_price`
+    pub above_min_price: usize,
+    /// The matched units
+    pub matched: usize,
+}
+
+/// The first stage which emptied out, `None` if there are matched units
+pub fn empty_reason(funnel: &Funnel) -> Option<EmptyReason> {
+    let reason = if funnel.units_timed_out {
+        EmptyReason::UnitsTimeout
+    } else if funnel.forced_fallback {
+        EmptyReason::ForcedFallback
+    } else if funnel.fallback_only {
+        EmptyReason::Degraded
+    } else if funnel.active == 0 {
+        EmptyReason::NoActiveCampaigns
+    } else if funnel.eligible == 0 {
+        EmptyReason::NoEligibleCampaigns
+    } else if funnel.with_units == 0 {
+        EmptyReason::NoUnitsOfType
+    } else if funnel.above_min_price == 0 {
+        EmptyReason::FilteredByPrice
+    } else if funnel.matched == 0 {
+        EmptyReason::NoMatch
+    } else {
+        return None;
+    };
+
+    Some(reason)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_reason_is_the_first_stage_which_emptied_out() {
+        let all = Funnel {
+            units_timed_out: false,
+            forced_fallback: false,
+            fallback_only: false,
+            active: 5,
+            eligible: 4,
+            with_units: 3,
+            above_min_price: 2,
+            matched: 1,
+        };
+
+        let table = [
+            (all, None),
+            (
+                Funnel {
+                    units_timed_out: true,
+                    fallback_only: true,
+                    ..Funnel::default()
+                },
+                Some(EmptyReason::UnitsTimeout),
+            ),
+            (
+                Funnel {
+                    forced_fallback: true,
+                    fallback_only: true,
+                    ..Funnel::default()
+                },
+                Some(EmptyReason::ForcedFallback),
+            ),
+            (
+                Funnel {
+                    fallback_only: true,
+                    ..all
+                },
+                Some(EmptyReason::Degraded),
+            ),
+            (Funnel::default(), Some(EmptyReason::NoActiveCampaigns)),
+            (
+                Funnel {
+                    eligible: 0,
+                    with_units: 0,
+                    above_min_price: 0,
+                    matched: 0,
+                    ..all
+                },
+                Some(EmptyReason::NoEligibleCampaigns),
+            ),
+            (
+                Funnel {
+                    with_units: 0,
+                    above_min_price: 0,
+                    matched: 0,
+                    ..all
+                },
+                Some(EmptyReason::NoUnitsOfType),
+            ),
+            (
+                Funnel {
+                    above_min_price: 0,
+                    matched: 0,
+                    ..all
+                },
+                Some(EmptyReason::FilteredByPrice),
+            ),
+            (Funnel { matched: 0, ..all }, Some(EmptyReason::NoMatch)),
+        ];
+
+        for (funnel, expected) in table.iter() {
+            assert_eq!(*expected, empty_reason(funnel), "{:?}", funnel);
+        }
+    }
+
+    #[test]
+    fn the_reason_serializes_as_str() {
+        for reason in [
+            EmptyReason::UnitsTimeout,
+            EmptyReason::ForcedFallback,
+            EmptyReason::Degraded,
+            EmptyReason::NoActiveCampaigns,
+            EmptyReason::NoEligibleCampaigns,
+            EmptyReason::NoUnitsOfType,
+            EmptyReason::FilteredByPrice,
+            EmptyReason::NoMatch,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                serde_json::json!(reason.as_str()),
+                serde_json::to_value(reason).expect("Should serialize")
+            );
+        }
+    }
+}
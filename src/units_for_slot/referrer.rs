@@ -0,0 +1,201 @@
+use http::{header::REFERER, HeaderMap};
+use url::Url;
+
+/// The `Referer` of the units-for-slot request compared to the `website` of the AdSlot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Referrer {
+    /// No (valid) `Referer` header
+    Missing,
+    /// The AdSlot has no (valid) `website` to compare it to
+    Unverified,
+    /// The host of the `Referer` is the website's host or one of its subdomains
+    Matching,
+    /// The host of the `Referer` is another site
+    Mismatching,
+}
+
+impl Referrer {
+    pub fn new(headers: &HeaderMap, website: Option<&str>) -> Self {
+        let referrer = headers
+            .get(REFERER)
+            .and_then(|referer| referer.to_str().ok())
+            .and_then(site_host);
+
+        match (referrer, website.and_then(site_host)) {
+            (None, _) => Self::Missing,
+            (Some(_), None) => Self::Unverified,
+            (Some(referrer), Some(website)) if is_same_site(&referrer, &website) => Self::Matching,
+            (Some(_), Some(_)) => Self::Mismatching,
+        }
+    }
+
+    /// A `Missing` referrer is a mismatch only if it's required,
+    /// see [`Config.require_referrer`](crate::Config::require_referrer)
+    pub fn is_mismatch(&self, require_referrer: bool) -> bool {
+        match self {
+            Self::Mismatching => true,
+            Self::Missing => require_referrer,
+            Self::Unverified | Self::Matching => false,
+        }
+    }
+}
+
+/// The lowercase host of the URL without the port and a leading `www.`,
+/// a URL without a scheme (e.g. `example.com:8080/page`) is parsed as `http://`
+fn site_host(url: &str) -> Option<String> {
+    let url = url.trim();
+    let parsed = Url::parse(url)
+        .ok()
+        .filter(|parsed| parsed.host_str().is_some())
+        .or_else(|| Url::parse(&format!("http://{}", url)).ok())?;
+
+    let host = parsed.host_str()?.trim_end_matches('.').to_lowercase();
+    if host.is_empty() {
+        return None;
+    }
+
+    match host.strip_prefix("www.") {
+        Some(without_www) => Some(without_www.to_string()),
+        None => Some(host),
+    }
+}
+
+/// The same host or a subdomain (of any depth) of the website
+fn is_same_site(referrer: &str, website: &str) -> bool {
+    referrer == website
+        || referrer
+            .strip_suffix(website)
+            .map_or(false, |subdomain| subdomain.ends_with('.'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn referrer(referer: Option<&str>, website: Option<&str>) -> Referrer {
+        let mut headers = HeaderMap::new();
+        if let Some(referer) = referer {
+            headers.insert(
+                REFERER,
+                HeaderValue::from_str(referer).expect("Valid header"),
+            );
+        }
+
+        Referrer::new(&headers, website)
+    }
+
+    #[test]
+    fn referrers_are_compared_to_the_website_host() {
+        use Referrer::*;
+
+        // (Referer, website, expected)
+        let cases = vec![
+            (
+                Some("https://example.com/page"),
+                Some("https://example.com"),
+                Matching,
+            ),
+            (
+                Some("http://example.com"),
+                Some("https://example.com/"),
+                Matching,
+            ),
+            (
+                Some("https://EXAMPLE.com/"),
+                Some("https://example.com"),
+                Matching,
+            ),
+            // `www.` on either side
+            (
+                Some("https://www.example.com/"),
+                Some("https://example.com"),
+                Matching,
+            ),
+            (
+                Some("https://example.com/"),
+                Some("https://www.example.com"),
+                Matching,
+            ),
+            // deeper subdomains
+            (
+                Some("https://blog.example.com/post"),
+                Some("https://example.com"),
+                Matching,
+            ),
+            (
+                Some("https://a.b.example.com/"),
+                Some("https://www.example.com"),
+                Matching,
+            ),
+            // ports
+            (
+                Some("https://example.com:8443/"),
+                Some("https://example.com"),
+                Matching,
+            ),
+            (
+                Some("http://localhost:3000/"),
+                Some("http://localhost:8080"),
+                Matching,
+            ),
+            // a website without a scheme
+            (Some("https://example.com/"), Some("example.com"), Matching),
+            (
+                Some("https://example.com/"),
+                Some("www.example.com:8080/ads"),
+                Matching,
+            ),
+            // other sites
+            (
+                Some("https://example.org/"),
+                Some("https://example.com"),
+                Mismatching,
+            ),
+            (
+                Some("https://notexample.com/"),
+                Some("https://example.com"),
+                Mismatching,
+            ),
+            (
+                Some("https://example.com.evil.io/"),
+                Some("https://example.com"),
+                Mismatching,
+            ),
+            // a parent domain isn't the website
+            (
+                Some("https://example.com/"),
+                Some("https://blog.example.com"),
+                Mismatching,
+            ),
+            // missing or invalid
+            (None, Some("https://example.com"), Missing),
+            (Some(""), Some("https://example.com"), Missing),
+            (None, None, Missing),
+            (Some("https://example.com/"), None, Unverified),
+            (Some("https://example.com/"), Some(""), Unverified),
+        ];
+
+        for (referer, website, expected) in cases {
+            assert_eq!(
+                expected,
+                referrer(referer, website),
+                "Referer: {:?}, website: {:?}",
+                referer,
+                website
+            );
+        }
+    }
+
+    #[test]
+    fn missing_referrers_are_allowed_unless_required() {
+        assert!(!Referrer::Missing.is_mismatch(false));
+        assert!(Referrer::Missing.is_mismatch(true));
+
+        for require_referrer in &[false, true] {
+            assert!(Referrer::Mismatching.is_mismatch(*require_referrer));
+            assert!(!Referrer::Matching.is_mismatch(*require_referrer));
+            assert!(!Referrer::Unverified.is_mismatch(*require_referrer));
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Sampling of the units-for-slot requests & the units of their responses for offline analysis,
+//! see [`Sampling`](crate::config::Sampling).
+//!
+//! The samples are serialized and written as JSONL (to a file or an HTTP collector) by a writer task fed by a bounded channel,
+//! when it's full (e.g. the collector is slow) the samples are dropped instead of blocking the requests.
+use crate::{config::Sampling, metrics::SAMPLES_DROPPED};
+use chrono::{DateTime, Utc};
+use http::header::CONTENT_TYPE;
+use primitives::targeting::input::Input;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use slog::{error, Logger};
+use std::{
+    fs::OpenOptions,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+use url::Url;
+
+/// The field of the targeting input which is redacted, unless the `include_publisher` is set
+const PUBLISHER_ID: &str = "publisherId";
+
+/// A sampled units-for-slot request with the units of its response
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sample {
+    pub time: DateTime<Utc>,
+    pub path: String,
+    pub query: Option<String>,
+    /// The targeting input derived from the request & the AdSlot, see [`Sampler::targeting_input`]
+    pub targeting_input: serde_json::Value,
+    /// The ipfs of the returned units (of all the requested types) in the order they were returned
+    pub units: Vec<String>,
+}
+
+/// Decides which requests are sampled and sends their samples to the writer task,
+/// which stops when all of its clones are dropped
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    rate: f64,
+    include_publisher: bool,
+    rng: Arc<Mutex<StdRng>>,
+    sender: Sender<Sample>,
+}
+
+/// Where the writer task writes the samples to
+enum Destination {
+    File(File),
+    Collector {
+        client: reqwest::Client,
+        url: Url,
+        timeout: Duration,
+    },
+}
+
+impl Sampler {
+    /// Opens (or creates) the file for appending (if it's the destination) and spawns the writer task.
+    /// `None` if the sampling is disabled.
+    pub fn spawn(
+        logger: Logger,
+        config: &Sampling,
+        collector_timeout: Duration,
+    ) -> io::Result<Option<Self>> {
+        if !config.is_enabled() {
+            return Ok(None);
+        }
+
+        let destination = match (config.path.as_ref(), config.collector.as_ref()) {
+            (Some(path), _) => Destination::File(File::from_std(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            (None, Some(url)) => Destination::Collector {
+                client: reqwest::Client::new(),
+                url: url.clone(),
+                timeout: collector_timeout,
+            },
+            // prevented by `Sampling::validate`
+            (None, None) => return Ok(None),
+        };
+        let (sampler, receiver) = Self::channel(config);
+        tokio::spawn(write_samples(logger, destination, receiver));
+
+        Ok(Some(sampler))
+    }
+
+    /// The sampler without the writer task, the samples are left in the receiver
+    pub(crate) fn channel(config: &Sampling) -> (Self, Receiver<Sample>) {
+        let (sender, receiver) = channel(config.buffer);
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let sampler = Self {
+            rate: config.rate,
+            include_publisher: config.include_publisher,
+            rng: Arc::new(Mutex::new(rng)),
+            sender,
+        };
+
+        (sampler, receiver)
+    }
+
+    /// Whether the current request should be sampled, with the probability of the `rate`
+    pub fn should_sample(&self) -> bool {
+        self.rate > 0.0
+            && self
+                .rng
+                .lock()
+                .expect("Should lock the sampling RNG")
+                .gen::<f64>()
+                < self.rate
+    }
+
+    /// The targeting `input` with the publisher address redacted, unless the `include_publisher` is set
+    pub fn targeting_input(&self, input: &Input) -> serde_json::Value {
+        let mut input = serde_json::to_value(input).unwrap_or_default();
+        if !self.include_publisher {
+            redact(&mut input, PUBLISHER_ID);
+        }
+
+        input
+    }
+
+    /// Never blocks, if the channel is full the sample is dropped and counted in the [`SAMPLES_DROPPED`]
+    pub fn send(&self, sample: Sample) {
+        match self.sender.clone().try_send(sample) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => SAMPLES_DROPPED.inc(),
+        }
+    }
+}
+
+/// Removes the `field` from the JSON objects, incl. the nested ones
+fn redact(value: &mut serde_json::Value, field: &str) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.remove(field);
+            object.values_mut().for_each(|nested| redact(nested, field));
+        }
+        serde_json::Value::Array(array) => {
+            array.iter_mut().for_each(|nested| redact(nested, field))
+        }
+        _ => {}
+    }
+}
+
+/// Runs on the writer task until all the [`Sampler`]s are dropped,
+/// each sample is a JSONL line appended to the file or `POST`ed to the collector.
+async fn write_samples(
+    logger: Logger,
+    mut destination: Destination,
+    mut receiver: Receiver<Sample>,
+) {
+    while let Some(sample) = receiver.recv().await {
+        let line = match serde_json::to_string(&sample) {
+            Ok(json) => format!("{}\n", json),
+            Err(err) => {
+                error!(&logger, "Failed to serialize the units-for-slot sample"; "error" => %err);
+                continue;
+            }
+        };
+
+        let written = match &mut destination {
+            // the write completes in the background until the file is flushed
+            Destination::File(file) => async {
+                file.write_all(line.as_bytes()).await?;
+                file.flush().await
+            }
+            .await
+            .map_err(|err| err.to_string()),
+            Destination::Collector {
+                client,
+                url,
+                timeout,
+            } => client
+                .post(url.clone())
+                .timeout(*timeout)
+                .header(CONTENT_TYPE, "application/x-ndjson")
+                .body(line)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(drop)
+                .map_err(|err| err.to_string()),
+        };
+
+        if let Err(err) = written {
+            error!(&logger, "Failed to write the units-for-slot sample"; "error" => err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::discard_logger;
+
+    fn sampling(rate: f64, seed: Option<u64>) -> Sampling {
+        Sampling {
+            rate,
+            seed,
+            buffer: 2,
+            ..Default::default()
+        }
+    }
+
+    fn sample(unit: &str) -> Sample {
+        Sample {
+            time: Utc::now(),
+            path: "/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C".to_string(),
+            query: Some("depositAsset=0x6B175474E89094C44Da98b954EedeAC495271d0F".to_string()),
+            targeting_input: serde_json::json!({ "country": "BG" }),
+            units: vec![unit.to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn the_sampling_rate_is_deterministic_with_a_seed() {
+        let decisions = |config: &Sampling| {
+            let (sampler, _receiver) = Sampler::channel(config);
+
+            (0..10_000)
+                .map(|_| sampler.should_sample())
+                .collect::<Vec<_>>()
+        };
+
+        let seeded = decisions(&sampling(0.1, Some(42)));
+        assert_eq!(seeded, decisions(&sampling(0.1, Some(42))));
+        assert_ne!(seeded, decisions(&sampling(0.1, Some(43))));
+
+        let sampled = seeded.iter().filter(|sampled| **sampled).count();
+        assert!(
+            (800..1200).contains(&sampled),
+            "About 10% should be sampled, got {}",
+            sampled
+        );
+
+        assert!(decisions(&sampling(0.0, Some(42)))
+            .iter()
+            .all(|sampled| !sampled));
+        assert!(decisions(&sampling(1.0, Some(42)))
+            .iter()
+            .all(|sampled| *sampled));
+    }
+
+    #[tokio::test]
+    async fn samples_are_dropped_when_the_channel_is_full() {
+        let (sampler, mut receiver) = Sampler::channel(&sampling(1.0, None));
+        let dropped = SAMPLES_DROPPED.get();
+
+        for i in 0..5 {
+            sampler.send(sample(&format!("unit {}", i)));
+        }
+
+        assert!(SAMPLES_DROPPED.get() - dropped >= 3);
+        for i in 0..2 {
+            assert_eq!(
+                Some(sample(&format!("unit {}", i)).units),
+                receiver.try_recv().ok().map(|sample| sample.units)
+            );
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn the_publisher_is_redacted_unless_included() {
+        let mut input = serde_json::json!({
+            "adSlotId": "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C",
+            "publisherId": "0xB7d3F81E857692d13e9D63b232A90F4A1793189E",
+            "adSlot": { "hostname": "adex.network" },
+        });
+        redact(&mut input, PUBLISHER_ID);
+
+        assert_eq!(
+            serde_json::json!({
+                "adSlotId": "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C",
+                "adSlot": { "hostname": "adex.network" },
+            }),
+            input
+        );
+    }
+
+    #[tokio::test]
+    async fn the_samples_are_appended_to_the_file_as_jsonl() {
+        let path = std::env::temp_dir().join(format!(
+            "supermarket-samples-{}.jsonl",
+            crate::util::new_request_id()
+        ));
+        let config = Sampling {
+            path: Some(path.clone()),
+            ..sampling(1.0, None)
+        };
+        let sampler = Sampler::spawn(discard_logger(), &config, Duration::from_secs(1))
+            .expect("Should open the file")
+            .expect("Sampling is enabled");
+        sampler.send(sample("QmcUVX7fvoLMM93uN2bD3wGTH8MXSxeL8hojYfL2Lhp7mR"));
+        sampler.send(sample("QmVhRDGXoM3Fg3HZD5xwMuxtb9ZErwC8wHt8CjsfxaiUbZ"));
+
+        let mut lines = vec![];
+        for _ in 0..50 {
+            lines = std::fs::read_to_string(&path)
+                .expect("Should read the samples")
+                .lines()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            if lines.len() == 2 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&path).expect("Should remove the samples");
+
+        let units = lines
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("Should be JSON"))
+            .map(|sample| sample["units"][0].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "QmcUVX7fvoLMM93uN2bD3wGTH8MXSxeL8hojYfL2Lhp7mR",
+                "QmVhRDGXoM3Fg3HZD5xwMuxtb9ZErwC8wHt8CjsfxaiUbZ"
+            ],
+            units
+        );
+    }
+}
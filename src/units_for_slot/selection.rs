@@ -0,0 +1,368 @@
+//! The order in which the matched units are served, applied as the final step of the matching,
+//! see [`Selection`](crate::config::Selection).
+//!
+//! The matched units are ranked by price and deduplicated first (see [`MatchedUnits`](super::MatchedUnits)),
+//! then the [`Strategy`] of the request reorders them.
+//! Only the deterministic strategies (`highest_price` & `best_score`) keep the `?skip=` & `?limit=` pages stable.
+use crate::config::Selection;
+use primitives::BigNum;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::HashMap, fmt, str::FromStr, time::Duration};
+use tokio::time::Instant;
+
+/// The strategies of the [`Selection`], selected by the config or by the `?strategy=` of a request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// The highest paying units first, see [`HighestPrice`]
+    HighestPrice,
+    /// The units with the best targeting score first, see [`BestScore`]
+    BestScore,
+    /// A random order weighted by the prices, see [`WeightedRandom`]
+    WeightedRandom,
+    /// Every request of an AdSlot starts with the next unit, see [`RoundRobin`]
+    RoundRobin,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 4] = [
+        Self::HighestPrice,
+        Self::BestScore,
+        Self::WeightedRandom,
+        Self::RoundRobin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HighestPrice => "highest_price",
+            Self::BestScore => "best_score",
+            Self::WeightedRandom => "weighted_random",
+            Self::RoundRobin => "round_robin",
+        }
+    }
+
+    /// The identical requests are served the same order,
+    /// only their responses are shared between the coalesced requests
+    pub fn is_deterministic(&self) -> bool {
+        matches!(self, Self::HighestPrice | Self::BestScore)
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Self::HighestPrice
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|strategy| strategy.as_str() == name)
+            .ok_or(())
+    }
+}
+
+/// A matched unit as seen by the [`SelectionStrategy`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate<'a> {
+    /// The position of the unit in the ranking by price
+    pub index: usize,
+    pub price: &'a BigNum,
+    pub score: f64,
+}
+
+/// Reorders the matched units (ranked by price), the first ones are served first
+pub trait SelectionStrategy {
+    fn select(&mut self, candidates: &mut Vec<Candidate<'_>>);
+}
+
+/// Keeps the ranking by price, the units with the same price keep their order
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HighestPrice;
+
+impl SelectionStrategy for HighestPrice {
+    fn select(&mut self, candidates: &mut Vec<Candidate<'_>>) {
+        candidates.sort_by(|a, b| b.price.cmp(a.price));
+    }
+}
+
+/// The best targeting score first, the units with the same score keep their order by price
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BestScore;
+
+impl SelectionStrategy for BestScore {
+    fn select(&mut self, candidates: &mut Vec<Candidate<'_>>) {
+        candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    }
+}
+
+/// A random order where a unit paying twice as much is twice as likely to be first
+/// (a weighted sampling without replacement), the units without a price are last
+#[derive(Debug, Clone)]
+pub struct WeightedRandom<R> {
+    rng: R,
+}
+
+impl<R: Rng> WeightedRandom<R> {
+    pub fn new(rng: R) -> Self {
+        Self { rng }
+    }
+}
+
+impl<R: Rng> SelectionStrategy for WeightedRandom<R> {
+    fn select(&mut self, candidates: &mut Vec<Candidate<'_>>) {
+        // the `ln(u) / weight` keys (of a uniform `u`) ordered from the largest are the weighted sample
+        let mut keyed = candidates
+            .drain(..)
+            .map(|candidate| {
+                let weight = to_f64(candidate.price);
+                let key = if weight > 0.0 {
+                    self.rng.gen::<f64>().ln() / weight
+                } else {
+                    f64::NEG_INFINITY
+                };
+
+                (key, candidate)
+            })
+            .collect::<Vec<_>>();
+        keyed.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+        candidates.extend(keyed.into_iter().map(|(_, candidate)| candidate));
+    }
+}
+
+/// Starts with the unit at the `offset` of the ranking by price (wrapping around), keeping the order of the rest,
+/// the `offset` is the number of the previous requests of the AdSlot, see [`RoundRobinPositions`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoundRobin {
+    pub offset: usize,
+}
+
+impl SelectionStrategy for RoundRobin {
+    fn select(&mut self, candidates: &mut Vec<Candidate<'_>>) {
+        HighestPrice.select(candidates);
+
+        if !candidates.is_empty() {
+            let offset = self.offset % candidates.len();
+            candidates.rotate_left(offset);
+        }
+    }
+}
+
+/// Applies the `strategy` to the `candidates`, the `round_robin_offset` is used only by the [`RoundRobin`]
+pub fn select(strategy: Strategy, round_robin_offset: usize, candidates: &mut Vec<Candidate<'_>>) {
+    match strategy {
+        Strategy::HighestPrice => HighestPrice.select(candidates),
+        Strategy::BestScore => BestScore.select(candidates),
+        Strategy::WeightedRandom => WeightedRandom::new(rand::thread_rng()).select(candidates),
+        Strategy::RoundRobin => RoundRobin {
+            offset: round_robin_offset,
+        }
+        .select(candidates),
+    }
+}
+
+/// The number of the requests of each AdSlot (and type) served with the [`RoundRobin`],
+/// forgotten once it isn't requested for the `round_robin_ttl`
+#[derive(Debug, Default)]
+pub struct RoundRobinPositions {
+    /// (AdSlot ipfs, AdUnit type) -> (the next offset, the last request)
+    positions: HashMap<(String, String), (usize, Instant)>,
+}
+
+impl RoundRobinPositions {
+    /// The offset of this request of the AdSlot, the next one gets the following offset.
+    /// When the `round_robin_max_slots` are tracked the expired positions are dropped
+    /// and if none of them expired the new AdSlot isn't tracked (it always starts from the first unit).
+    pub fn next(&mut self, slot: &str, ad_type: &str, now: Instant, config: &Selection) -> usize {
+        let key = (slot.to_string(), ad_type.to_string());
+        let ttl = config.round_robin_ttl;
+
+        if !self.positions.contains_key(&key)
+            && self.positions.len() >= config.round_robin_max_slots
+        {
+            self.expire(now, ttl);
+            if self.positions.len() >= config.round_robin_max_slots {
+                return 0;
+            }
+        }
+
+        let (offset, last_request) = self.positions.entry(key).or_insert((0, now));
+        if now.saturating_duration_since(*last_request) >= ttl {
+            *offset = 0;
+        }
+        let current = *offset;
+        *offset = offset.wrapping_add(1);
+        *last_request = now;
+
+        current
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        self.positions
+            .retain(|_, (_, last_request)| now.saturating_duration_since(*last_request) < ttl);
+    }
+}
+
+/// The weights are only approximate for prices beyond the `f64` precision
+fn to_f64(amount: &BigNum) -> f64 {
+    amount.to_string().parse().unwrap_or(f64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn prices() -> Vec<BigNum> {
+        vec![300.into(), 100.into(), 200.into(), 100.into()]
+    }
+
+    fn candidates<'a>(prices: &'a [BigNum], scores: &[f64]) -> Vec<Candidate<'a>> {
+        prices
+            .iter()
+            .zip(scores)
+            .enumerate()
+            .map(|(index, (price, score))| Candidate {
+                index,
+                price,
+                score: *score,
+            })
+            .collect()
+    }
+
+    fn order(candidates: &[Candidate<'_>]) -> Vec<usize> {
+        candidates.iter().map(|candidate| candidate.index).collect()
+    }
+
+    #[test]
+    fn highest_price_keeps_the_order_of_the_same_prices() {
+        let prices = prices();
+        let mut candidates = candidates(&prices, &[1.0, 1.0, 1.0, 1.0]);
+
+        HighestPrice.select(&mut candidates);
+        assert_eq!(vec![0, 2, 1, 3], order(&candidates));
+    }
+
+    #[test]
+    fn best_score_keeps_the_order_of_the_same_scores() {
+        let prices = prices();
+        let mut candidates = candidates(&prices, &[0.5, 2.0, 1.0, 2.0]);
+
+        BestScore.select(&mut candidates);
+        assert_eq!(vec![1, 3, 2, 0], order(&candidates));
+    }
+
+    #[test]
+    fn weighted_random_prefers_the_higher_prices() {
+        let prices: Vec<BigNum> = vec![900.into(), 100.into(), 0.into()];
+        let mut strategy = WeightedRandom::new(StdRng::seed_from_u64(7));
+
+        let mut first = [0_usize; 3];
+        for _ in 0..1_000 {
+            let mut candidates = candidates(&prices, &[1.0, 1.0, 1.0]);
+            strategy.select(&mut candidates);
+
+            assert_eq!(3, candidates.len());
+            // without a price it's never before the paying ones
+            assert_eq!(2, candidates[2].index);
+            first[candidates[0].index] += 1;
+        }
+
+        // 90% of the time, give or take
+        assert!((850..=950).contains(&first[0]), "{:?}", first);
+        assert_eq!(1_000, first[0] + first[1]);
+    }
+
+    #[test]
+    fn round_robin_starts_each_request_with_the_next_unit() {
+        let config = Selection {
+            round_robin_ttl: Duration::from_secs(60),
+            round_robin_max_slots: 2,
+            ..Selection::default()
+        };
+        let prices = prices();
+        let mut positions = RoundRobinPositions::default();
+        let now = Instant::now();
+
+        let orders = (0..5)
+            .map(|_| {
+                let mut candidates = candidates(&prices, &[1.0, 1.0, 1.0, 1.0]);
+                RoundRobin {
+                    offset: positions.next("QmSlot", "legacy_300x250", now, &config),
+                }
+                .select(&mut candidates);
+
+                order(&candidates)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                vec![0, 2, 1, 3],
+                vec![2, 1, 3, 0],
+                vec![1, 3, 0, 2],
+                vec![3, 0, 2, 1],
+                vec![0, 2, 1, 3],
+            ],
+            orders
+        );
+
+        // every type of the AdSlot has its own position
+        assert_eq!(0, positions.next("QmSlot", "legacy_728x90", now, &config));
+        // the limit of the tracked AdSlots is reached, none of them expired
+        assert_eq!(0, positions.next("QmOther", "legacy_300x250", now, &config));
+        assert_eq!(0, positions.next("QmOther", "legacy_300x250", now, &config));
+        assert_eq!(2, positions.len());
+
+        // once expired, the AdSlot starts over and the expired ones make room for the new ones
+        let later = now + config.round_robin_ttl;
+        assert_eq!(
+            0,
+            positions.next("QmSlot", "legacy_300x250", later, &config)
+        );
+        assert_eq!(
+            1,
+            positions.next("QmSlot", "legacy_300x250", later, &config)
+        );
+        assert_eq!(
+            0,
+            positions.next("QmOther", "legacy_300x250", later, &config)
+        );
+        assert_eq!(
+            1,
+            positions.next("QmOther", "legacy_300x250", later, &config)
+        );
+        assert_eq!(2, positions.len());
+    }
+
+    #[test]
+    fn strategies_are_parsed_by_their_names() {
+        for strategy in Strategy::ALL.iter() {
+            assert_eq!(Ok(*strategy), strategy.as_str().parse());
+            assert_eq!(
+                serde_json::json!(strategy.as_str()),
+                serde_json::to_value(strategy).expect("Should serialize")
+            );
+        }
+        assert_eq!(Err(()), "random".parse::<Strategy>());
+    }
+}
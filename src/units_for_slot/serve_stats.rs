@@ -0,0 +1,456 @@
+//! How many times the units of each Campaign were served by the units-for-slot, for estimating its pacing.
+//!
+//! The requests only increment the atomic [`ServeCounters`] (sharded by the ChannelId, so they rarely wait for each other),
+//! which are flushed every `flush_interval` (see [`ServeStats`](crate::config::ServeStats)) into the hourly buckets of
+//! the [`ServeHistory`] by [`spawn_flush`], served on the admin `/campaigns/:id/serve-stats` route.
+use super::publisher_stats::Served;
+use crate::{
+    cache::{Cache, Client},
+    config::{Config, ServeStats},
+    metrics::{CAMPAIGN_SERVES, SERVE_STATS_CAMPAIGNS},
+};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use primitives::{ChannelId, ValidatorId};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+/// The number of shards of the [`ServeCounters`]
+pub const SHARDS: usize = 16;
+
+/// The units-for-slot responses which served the units of a Campaign
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServeCounts {
+    /// The responses with at least one unit of the Campaign, anywhere in the page
+    pub served: u64,
+    /// The responses with a unit of the Campaign at the top, i.e. the first unit of the first page
+    pub top: u64,
+}
+
+impl ServeCounts {
+    fn add(&mut self, other: &Self) {
+        self.served += other.served;
+        self.top += other.top;
+    }
+}
+
+/// What a units-for-slot response served, recorded with [`Delivery::record`] for every delivered response,
+/// i.e. also for each of the requests coalesced with it
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// The owner of the AdSlot
+    pub publisher: ValidatorId,
+    pub served: Served,
+    /// The served Campaigns (once per response) and whether one of their units was at the top
+    pub campaigns: Vec<(ChannelId, bool)>,
+}
+
+impl Delivery {
+    /// Counts the response in the [`ServeCounters`] and the [`PublisherStats`](super::publisher_stats::PublisherStats)
+    pub async fn record<C: Client>(&self, cache: &Cache<C>) {
+        for (channel_id, top) in self.campaigns.iter() {
+            cache.serve_counters.record(*channel_id, *top);
+        }
+        cache.publisher_stats.write().await.record(
+            self.publisher,
+            self.served,
+            cache.clock().now_utc(),
+        );
+    }
+}
+
+#[derive(Debug, Default)]
+struct AtomicCounts {
+    served: AtomicU64,
+    top: AtomicU64,
+}
+
+/// The counts of the responses since the last flush.
+///
+/// The counters are incremented under the read lock of their shard,
+/// only the first serve of a Campaign since the last flush takes the write lock.
+#[derive(Debug)]
+pub struct ServeCounters {
+    shards: Vec<RwLock<HashMap<ChannelId, AtomicCounts>>>,
+}
+
+impl Default for ServeCounters {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+impl ServeCounters {
+    /// Counts a response which served the units of the Campaign, at the `top` or not
+    pub fn record(&self, channel_id: ChannelId, top: bool) {
+        let increment = |counts: &AtomicCounts| {
+            counts.served.fetch_add(1, Ordering::Relaxed);
+            if top {
+                counts.top.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        let shard = &self.shards[shard_of(&channel_id)];
+
+        {
+            let counters = shard
+                .read()
+                .expect("The serve counters should not be poisoned");
+            if let Some(counts) = counters.get(&channel_id) {
+                return increment(counts);
+            }
+        }
+
+        let mut counters = shard
+            .write()
+            .expect("The serve counters should not be poisoned");
+        increment(counters.entry(channel_id).or_default());
+    }
+
+    /// Takes the counts since the last flush, the Campaigns which weren't served since then are dropped
+    pub fn take(&self) -> Vec<(ChannelId, ServeCounts)> {
+        let mut taken = vec![];
+
+        for shard in self.shards.iter() {
+            let mut counters = shard
+                .write()
+                .expect("The serve counters should not be poisoned");
+
+            counters.retain(|channel_id, counts| {
+                let counts = ServeCounts {
+                    served: counts.served.swap(0, Ordering::Relaxed),
+                    top: counts.top.swap(0, Ordering::Relaxed),
+                };
+                if counts.served == 0 {
+                    return false;
+                }
+
+                taken.push((*channel_id, counts));
+                true
+            });
+        }
+
+        taken
+    }
+}
+
+fn shard_of(channel_id: &ChannelId) -> usize {
+    let mut hasher = DefaultHasher::new();
+    channel_id.hash(&mut hasher);
+
+    hasher.finish() as usize % SHARDS
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HourlyServes {
+    /// The start of the hour
+    pub hour: DateTime<Utc>,
+    #[serde(flatten)]
+    pub counts: ServeCounts,
+}
+
+/// The response of `GET /campaigns/:id/serve-stats`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CampaignServes {
+    pub channel_id: ChannelId,
+    /// The sum of the `hourly` counts
+    pub total: ServeCounts,
+    /// The hours with serves within the retention, the oldest first
+    pub hourly: Vec<HourlyServes>,
+}
+
+#[derive(Debug)]
+struct TrackedCampaign {
+    last_served: DateTime<Utc>,
+    /// By the start of the hour
+    buckets: BTreeMap<DateTime<Utc>, ServeCounts>,
+}
+
+/// The flushed [`ServeCounters`] of the Campaigns in hourly buckets,
+/// bounded by the `max_campaigns` and the `retention_hours` of the [`ServeStats`].
+///
+/// The counts are added to the bucket of the hour they are flushed in,
+/// so the serves right before the end of an hour may be in the next one.
+#[derive(Debug, Default)]
+pub struct ServeHistory {
+    campaigns: HashMap<ChannelId, TrackedCampaign>,
+}
+
+impl ServeHistory {
+    /// Adds the taken [`ServeCounters`] to the bucket of the current hour
+    pub fn flush(
+        &mut self,
+        taken: Vec<(ChannelId, ServeCounts)>,
+        now: DateTime<Utc>,
+        settings: &ServeStats,
+    ) {
+        let oldest = oldest_hour(now, settings);
+
+        for (channel_id, counts) in taken {
+            if !self.campaigns.contains_key(&channel_id) {
+                self.make_room(now, settings);
+            }
+
+            let tracked = self
+                .campaigns
+                .entry(channel_id)
+                .or_insert_with(|| TrackedCampaign {
+                    last_served: now,
+                    buckets: BTreeMap::new(),
+                });
+            tracked.last_served = now;
+            tracked.buckets = tracked.buckets.split_off(&oldest);
+            tracked
+                .buckets
+                .entry(hour_of(now))
+                .or_default()
+                .add(&counts);
+        }
+    }
+
+    /// The counts of the Campaign within the retention, empty if it's not tracked
+    pub fn get(
+        &self,
+        channel_id: ChannelId,
+        now: DateTime<Utc>,
+        settings: &ServeStats,
+    ) -> CampaignServes {
+        let oldest = oldest_hour(now, settings);
+        let hourly = self
+            .campaigns
+            .get(&channel_id)
+            .map(|tracked| {
+                tracked
+                    .buckets
+                    .range(oldest..)
+                    .map(|(hour, counts)| HourlyServes {
+                        hour: *hour,
+                        counts: *counts,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let mut total = ServeCounts::default();
+        for hourly in hourly.iter() {
+            total.add(&hourly.counts);
+        }
+
+        CampaignServes {
+            channel_id,
+            total,
+            hourly,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.campaigns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.campaigns.is_empty()
+    }
+
+    /// Drops the Campaigns without serves within the retention and,
+    /// if there are still `max_campaigns`, the least recently served one.
+    fn make_room(&mut self, now: DateTime<Utc>, settings: &ServeStats) {
+        if self.campaigns.len() < settings.max_campaigns {
+            return;
+        }
+
+        let oldest = oldest_hour(now, settings);
+        self.campaigns
+            .retain(|_, tracked| tracked.last_served >= oldest);
+
+        if self.campaigns.len() >= settings.max_campaigns {
+            let least_recent = self
+                .campaigns
+                .iter()
+                .min_by_key(|(_, tracked)| tracked.last_served)
+                .map(|(channel_id, _)| *channel_id);
+
+            if let Some(least_recent) = least_recent {
+                self.campaigns.remove(&least_recent);
+            }
+        }
+    }
+}
+
+/// Every `flush_interval` flushes the [`ServeCounters`] into the [`ServeHistory`] and the [`CAMPAIGN_SERVES`]
+pub fn spawn_flush<C: Client + Send + Sync + 'static>(cache: Cache<C>, config: Config) {
+    tokio::spawn(async move {
+        let settings = config.serve_stats;
+        let mut ticks = tokio::time::interval(settings.flush_interval);
+
+        loop {
+            ticks.tick().await;
+
+            let taken = cache.serve_counters.take();
+            let mut total = ServeCounts::default();
+            for (_, counts) in taken.iter() {
+                total.add(counts);
+            }
+            CAMPAIGN_SERVES
+                .with_label_values(&["any"])
+                .inc_by(total.served);
+            CAMPAIGN_SERVES
+                .with_label_values(&["top"])
+                .inc_by(total.top);
+
+            let mut history = cache.serve_history.write().await;
+            history.flush(taken, cache.clock().now_utc(), &settings);
+            SERVE_STATS_CAMPAIGNS
+                .with_label_values(&[cache.network()])
+                .set(history.len() as i64);
+        }
+    });
+}
+
+/// The start of the hour
+fn hour_of(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(Duration::hours(1))
+        .expect("Truncating to an hour should not overflow")
+}
+
+/// The start of the oldest hour within the retention
+fn oldest_hour(now: DateTime<Utc>, settings: &ServeStats) -> DateTime<Utc> {
+    hour_of(now) - Duration::hours(i64::from(settings.retention_hours) - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::{test::MockClock, Clock};
+    use chrono::TimeZone;
+    use primitives::util::tests::prep_db::DUMMY_CHANNEL;
+
+    fn other_channel() -> ChannelId {
+        ChannelId::from([1; 32])
+    }
+
+    #[test]
+    fn the_top_position_is_counted_apart_from_anywhere() {
+        let counters = ServeCounters::default();
+        let (channel_id, other) = (DUMMY_CHANNEL.id, other_channel());
+
+        counters.record(channel_id, true);
+        counters.record(channel_id, false);
+        counters.record(channel_id, false);
+        counters.record(other, false);
+
+        let mut taken = counters.take();
+        taken.sort_by_key(|(_, counts)| counts.served);
+        assert_eq!(
+            vec![
+                (other, ServeCounts { served: 1, top: 0 }),
+                (channel_id, ServeCounts { served: 3, top: 1 }),
+            ],
+            taken
+        );
+
+        // the counts are taken only once
+        assert!(counters.take().is_empty());
+        counters.record(other, true);
+        assert_eq!(
+            vec![(other, ServeCounts { served: 1, top: 1 })],
+            counters.take()
+        );
+    }
+
+    #[test]
+    fn the_flushed_counts_roll_over_hourly_within_the_retention() {
+        let clock = MockClock::starting_at(Utc.ymd(2021, 3, 1).and_hms(10, 59, 0));
+        let settings = ServeStats {
+            retention_hours: 3,
+            ..ServeStats::default()
+        };
+        let channel_id = DUMMY_CHANNEL.id;
+        let mut history = ServeHistory::default();
+
+        history.flush(
+            vec![(channel_id, ServeCounts { served: 2, top: 1 })],
+            clock.now_utc(),
+            &settings,
+        );
+        // rolls over to the next hour
+        clock.advance(std::time::Duration::from_secs(2 * 60));
+        history.flush(
+            vec![(channel_id, ServeCounts { served: 3, top: 0 })],
+            clock.now_utc(),
+            &settings,
+        );
+        history.flush(
+            vec![(channel_id, ServeCounts { served: 1, top: 1 })],
+            clock.now_utc(),
+            &settings,
+        );
+
+        let serves = history.get(channel_id, clock.now_utc(), &settings);
+        assert_eq!(
+            vec![
+                (
+                    Utc.ymd(2021, 3, 1).and_hms(10, 0, 0),
+                    ServeCounts { served: 2, top: 1 }
+                ),
+                (
+                    Utc.ymd(2021, 3, 1).and_hms(11, 0, 0),
+                    ServeCounts { served: 4, top: 1 }
+                ),
+            ],
+            serves
+                .hourly
+                .iter()
+                .map(|hourly| (hourly.hour, hourly.counts))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(ServeCounts { served: 6, top: 2 }, serves.total);
+
+        // the 10:00 bucket is out of the retention of 3 hours at 13:00
+        clock.advance(std::time::Duration::from_secs(2 * 60 * 60));
+        let serves = history.get(channel_id, clock.now_utc(), &settings);
+        assert_eq!(1, serves.hourly.len());
+        assert_eq!(ServeCounts { served: 4, top: 1 }, serves.total);
+
+        // an untracked Campaign has no serves
+        let untracked = history.get(other_channel(), clock.now_utc(), &settings);
+        assert!(untracked.hourly.is_empty());
+        assert_eq!(ServeCounts::default(), untracked.total);
+    }
+
+    #[test]
+    fn the_least_recently_served_campaign_is_dropped() {
+        let clock = MockClock::starting_at(Utc.ymd(2021, 3, 1).and_hms(10, 0, 0));
+        let settings = ServeStats {
+            max_campaigns: 2,
+            ..ServeStats::default()
+        };
+        let counts = ServeCounts { served: 1, top: 0 };
+        let mut history = ServeHistory::default();
+
+        history.flush(vec![(DUMMY_CHANNEL.id, counts)], clock.now_utc(), &settings);
+        clock.advance(std::time::Duration::from_secs(1));
+        history.flush(vec![(other_channel(), counts)], clock.now_utc(), &settings);
+        clock.advance(std::time::Duration::from_secs(1));
+
+        let third = ChannelId::from([2; 32]);
+        history.flush(vec![(third, counts)], clock.now_utc(), &settings);
+
+        assert_eq!(2, history.len());
+        let now = clock.now_utc();
+        assert!(history
+            .get(DUMMY_CHANNEL.id, now, &settings)
+            .hourly
+            .is_empty());
+        assert_eq!(1, history.get(third, now, &settings).total.served);
+    }
+}
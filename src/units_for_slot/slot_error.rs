@@ -0,0 +1,129 @@
+//! The responses of the units-for-slot requests whose AdSlot couldn't be fetched from the Market,
+//! so that a missing AdSlot isn't mistaken for an unavailable Market (and vice versa).
+use crate::metrics::SLOT_FETCH_ERRORS;
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use hyper::{Body, Response};
+use std::time::Duration;
+
+/// Why the AdSlot of a units-for-slot request couldn't be fetched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotFetchError {
+    /// The Market responded with `404 Not Found`
+    NotFound,
+    /// The Market responded with an error status or it timed out (incl. connection errors)
+    MarketUnavailable,
+    /// The Market responded with an AdSlot which couldn't be deserialized
+    InvalidResponse,
+}
+
+impl SlotFetchError {
+    /// Every error, in the order of the status
+    pub const ALL: [SlotFetchError; 3] = [
+        Self::NotFound,
+        Self::MarketUnavailable,
+        Self::InvalidResponse,
+    ];
+
+    /// Distinguishes the AdSlot responses which couldn't be deserialized from the unavailable Market
+    pub fn from_error(error: &reqwest::Error) -> Self {
+        if error.is_decode() {
+            Self::InvalidResponse
+        } else {
+            Self::MarketUnavailable
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::MarketUnavailable => "market_unavailable",
+            Self::InvalidResponse => "invalid_response",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NotFound => "slot not found",
+            Self::MarketUnavailable => "market unavailable",
+            Self::InvalidResponse => "invalid slot response",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MarketUnavailable | Self::InvalidResponse => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Counted in the [`SLOT_FETCH_ERRORS`] by `error`,
+    /// the unavailable Market responses have the `Retry-After` (in seconds)
+    pub fn into_response(self, retry_after: Duration) -> Response<Body> {
+        SLOT_FETCH_ERRORS.with_label_values(&[self.as_str()]).inc();
+
+        let mut response = Response::builder()
+            .status(self.status())
+            .header(CONTENT_TYPE, "application/json");
+        if self == Self::MarketUnavailable {
+            response = response.header(RETRY_AFTER, retry_after.as_secs());
+        }
+
+        response
+            .body(Body::from(
+                serde_json::json!({ "error": self.message() }).to_string(),
+            ))
+            .expect("Should create the AdSlot fetch error response")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_responses_have_the_error_and_only_the_unavailable_market_is_retried() {
+        let table = [
+            (SlotFetchError::NotFound, 404, "slot not found", None),
+            (
+                SlotFetchError::MarketUnavailable,
+                502,
+                "market unavailable",
+                Some("5"),
+            ),
+            (
+                SlotFetchError::InvalidResponse,
+                502,
+                "invalid slot response",
+                None,
+            ),
+        ];
+
+        for (error, status, message, retry_after) in table.iter() {
+            let errors = || SLOT_FETCH_ERRORS.with_label_values(&[error.as_str()]).get();
+            let before = errors();
+
+            let response = error.into_response(Duration::from_secs(5));
+            assert_eq!(*status, response.status().as_u16());
+            assert_eq!("application/json", response.headers()[CONTENT_TYPE]);
+            assert_eq!(
+                *retry_after,
+                response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .map(|value| value.to_str().expect("ASCII value"))
+            );
+            assert!(errors() > before);
+
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .expect("Should read the body");
+            assert_eq!(
+                serde_json::json!({ "error": message }),
+                serde_json::from_slice::<serde_json::Value>(&body).expect("Should be JSON")
+            );
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Normalizing the tags of the AdSlots (their `categories` from the Market) and the tags
+//! the targeting rules of the Campaigns compare them with, so the same tag matches regardless of its
+//! unicode form (e.g. a precomposed `é` or an `e` with a combining accent) and, with the
+//! [`Tags.case_folding`](crate::config::Tags::case_folding), regardless of its case.
+//!
+//! The tag rules (e.g. `intersects` with `adSlot.categories`) are evaluated by the AdView with the
+//! `targetingInputBase` of the response, so both the tags of the AdSlot in it and the served targeting rules are normalized.
+use crate::config::CaseFolding;
+use primitives::targeting::Rules;
+use serde_json::Value;
+use unicode_normalization::UnicodeNormalization;
+
+/// The variable of the AdSlot tags in the targeting rules
+pub const TAGS_VARIABLE: &str = "adSlot.categories";
+
+/// The NFC form of the (case folded) tag
+pub fn normalize(tag: &str, case_folding: CaseFolding) -> String {
+    match case_folding {
+        CaseFolding::None => tag.nfc().collect(),
+        // lowercasing a composed character may decompose it, so it's composed afterwards
+        CaseFolding::Lowercase => tag.to_lowercase().nfc().collect(),
+    }
+}
+
+/// The normalized tags without the duplicates (e.g. the case variants of a tag), in their order
+pub fn normalize_tags(tags: &[String], case_folding: CaseFolding) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = normalize(tag, case_folding);
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+
+    normalized
+}
+
+/// Normalizes the string arguments of the functions which get the [`TAGS_VARIABLE`]
+/// (incl. the strings of their array arguments), the rest of the `rules` are left as they are.
+///
+/// The `rules` are returned unchanged if they don't use the tags (or they can't be serialized).
+pub fn normalize_rules(rules: Rules, case_folding: CaseFolding) -> Rules {
+    let mut serialized = match serde_json::to_value(&rules.0) {
+        Ok(serialized) => serialized,
+        Err(_) => return rules,
+    };
+
+    if !normalize_tag_arguments(&mut serialized, case_folding) {
+        return rules;
+    }
+
+    serde_json::from_value(serialized)
+        .map(Rules)
+        .unwrap_or(rules)
+}
+
+/// Walks the serialized rules with an explicit stack (see [`RulesComplexity`](crate::cache::validation::RulesComplexity))
+/// and returns whether any of the functions gets the [`TAGS_VARIABLE`]
+fn normalize_tag_arguments(rules: &mut Value, case_folding: CaseFolding) -> bool {
+    let mut uses_tags = false;
+
+    let mut stack = vec![rules];
+    while let Some(node) = stack.pop() {
+        match node {
+            Value::Array(values) => stack.extend(values.iter_mut()),
+            Value::Object(function) => {
+                for arguments in function.values_mut() {
+                    if let Value::Array(arguments) = &mut *arguments {
+                        if arguments.iter().any(is_tags_variable) {
+                            uses_tags = true;
+                            arguments
+                                .iter_mut()
+                                .for_each(|argument| normalize_strings(argument, case_folding));
+                        }
+                    }
+
+                    stack.push(arguments);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    uses_tags
+}
+
+/// `{ "get": "adSlot.categories" }`
+fn is_tags_variable(argument: &Value) -> bool {
+    match argument {
+        Value::Object(function) => {
+            function.len() == 1
+                && function.get("get").and_then(Value::as_str) == Some(TAGS_VARIABLE)
+        }
+        _ => false,
+    }
+}
+
+/// A string or the strings of an array, the functions (e.g. a nested `get`) are left as they are
+fn normalize_strings(argument: &mut Value, case_folding: CaseFolding) {
+    match argument {
+        Value::String(tag) => *tag = normalize(tag, case_folding),
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                if let Value::String(tag) = value {
+                    *tag = normalize(tag, case_folding);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use primitives::{
+        targeting::{eval_with_callback, input, Function, Input, Output, Value as RuleValue},
+        util::tests::prep_db::IDS,
+        BigNum,
+    };
+
+    /// `onlyShowIf` the AdSlot has any of the `tags`
+    fn tag_rules(tags: &[&str]) -> Rules {
+        let tags = RuleValue::Array(tags.iter().map(|tag| RuleValue::new_string(tag)).collect());
+        let intersects = Function::new_intersects(Function::new_get(TAGS_VARIABLE), tags);
+
+        Rules(vec![Function::new_only_show_if(intersects).into()])
+    }
+
+    /// Whether the `rules` show a unit on an AdSlot with the `tags`, as evaluated by the AdView
+    fn shows(rules: &Rules, tags: Vec<String>) -> bool {
+        let input = Input {
+            ad_view: None,
+            global: input::Global {
+                ad_slot_id: "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C".to_string(),
+                ad_slot_type: "legacy_250x250".to_string(),
+                publisher_id: IDS["publisher"],
+                country: None,
+                event_type: "IMPRESSION".to_string(),
+                seconds_since_epoch: chrono::Utc::now(),
+                user_agent_os: None,
+                user_agent_browser_family: None,
+            },
+            ad_unit_id: None,
+            balances: None,
+            channel: None,
+            ad_slot: Some(input::AdSlot {
+                categories: tags,
+                hostname: "adex.network".to_string(),
+                alexa_rank: None,
+            }),
+        };
+        let mut output = Output {
+            show: true,
+            boost: 1.0,
+            price: vec![("IMPRESSION".to_string(), BigNum::from(1))]
+                .into_iter()
+                .collect(),
+        };
+
+        eval_with_callback(&rules.0, &input, &mut output, Some(|_error, _rule| {}));
+
+        output.show
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn tags_are_composed_and_case_folded() {
+        // a precomposed `é` & an `e` with a combining acute accent
+        assert_eq!("café", normalize("cafe\u{301}", CaseFolding::None));
+        assert_eq!("café", normalize("caf\u{e9}", CaseFolding::None));
+        assert_eq!("CAFÉ", normalize("CAFE\u{301}", CaseFolding::None));
+        assert_eq!("café", normalize("CAFE\u{301}", CaseFolding::Lowercase));
+
+        // mixed scripts & emoji
+        assert_eq!(
+            "спорт-sport ⚽",
+            normalize("Спорт-Sport ⚽", CaseFolding::Lowercase)
+        );
+        assert_eq!("🎮 игры", normalize("🎮 ИГРЫ", CaseFolding::Lowercase));
+        // `й` as `и` with a combining breve
+        assert_eq!("кино й", normalize("КИНО И\u{306}", CaseFolding::Lowercase));
+        // ASCII tags (e.g. the IAB categories) keep their case without the folding
+        assert_eq!("IAB13-7", normalize("IAB13-7", CaseFolding::None));
+    }
+
+    #[test]
+    fn case_variant_duplicates_are_dropped() {
+        let slot_tags = tags(&["Кино", "КИНО", "Cafe\u{301}", "café", "🎮 Games", "IAB3"]);
+
+        assert_eq!(
+            tags(&["кино", "café", "🎮 games", "iab3"]),
+            normalize_tags(&slot_tags, CaseFolding::Lowercase)
+        );
+        assert_eq!(
+            tags(&["Кино", "КИНО", "Café", "café", "🎮 Games", "IAB3"]),
+            normalize_tags(&slot_tags, CaseFolding::None)
+        );
+    }
+
+    #[test]
+    fn the_tags_match_after_the_normalization() {
+        for (campaign_tags, slot_tags) in &[
+            (&["КИНО"][..], &["кино"][..]),
+            (&["Cafe\u{301}"][..], &["CAF\u{c9}"][..]),
+            (&["🎮 Games", "IAB5"][..], &["Новости", "🎮 GAMES"][..]),
+            (&["Sport-Спорт"][..], &["IAB3", "SPORT-спорт"][..]),
+        ] {
+            let rules = tag_rules(campaign_tags);
+            let case = format!("{:?}", (campaign_tags, slot_tags));
+
+            // the same tags in another form or case don't match as they are
+            assert!(!shows(&rules, tags(slot_tags)), "{}", case);
+
+            let rules = normalize_rules(rules, CaseFolding::Lowercase);
+            let slot_tags = normalize_tags(&tags(slot_tags), CaseFolding::Lowercase);
+            assert!(shows(&rules, slot_tags), "{}", case);
+        }
+
+        // other tags still don't match
+        let rules = normalize_rules(tag_rules(&["Кино"]), CaseFolding::Lowercase);
+        assert!(!shows(
+            &rules,
+            normalize_tags(&tags(&["КИНОТЕАТР"]), CaseFolding::Lowercase)
+        ));
+    }
+
+    #[test]
+    fn only_the_arguments_of_the_tag_functions_are_normalized() {
+        let tags_and_type = Function::new_and(
+            Function::new_intersects(
+                Function::new_get(TAGS_VARIABLE),
+                RuleValue::Array(vec![RuleValue::new_string("КИНО")]),
+            ),
+            Function::new_eq(
+                Function::new_get("adSlotType"),
+                RuleValue::new_string("LEGACY_250x250"),
+            ),
+        );
+        let rules = Rules(vec![Function::new_only_show_if(tags_and_type).into()]);
+
+        let normalized = serde_json::to_value(&normalize_rules(rules, CaseFolding::Lowercase).0)
+            .expect("Should serialize");
+        assert_eq!(
+            serde_json::json!([{ "onlyShowIf": { "and": [
+                { "intersects": [{ "get": TAGS_VARIABLE }, ["кино"]] },
+                { "eq": [{ "get": "adSlotType" }, "LEGACY_250x250"] },
+            ] } }]),
+            normalized
+        );
+
+        // without the tags they're left as they are
+        let rules = Rules(vec![Function::new_only_show_if(Function::new_eq(
+            Function::new_get("adSlotType"),
+            RuleValue::new_string("LEGACY_250x250"),
+        ))
+        .into()]);
+        let serialized = serde_json::to_value(&rules.0).expect("Should serialize");
+        assert_eq!(
+            serialized,
+            serde_json::to_value(&normalize_rules(rules, CaseFolding::Lowercase).0)
+                .expect("Should serialize")
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! The targeting variables of the request which aren't part of the [`Input`](primitives::targeting::Input)
+//! of the rule engine: the viewer's `hour` & `dayOfWeek` (see [`DayTime`]) and their interest `segments`
+//! (only with the consent, see [`Consent`](super::Consent)).
+//!
+//! The rule engine only knows the variables of the `Input`, so the `get`s of these variables are replaced
+//! with their values before the rules are evaluated. The served targeting rules have them replaced as well,
+//! so the AdView evaluates them the same way with the `targetingInputBase` of the response.
+use super::DayTime;
+use primitives::targeting::Rules;
+use serde::Serialize;
+use serde_json::Value;
+
+/// The viewer's hour, from `0` to `23`
+pub const HOUR_VARIABLE: &str = "hour";
+/// The viewer's day of the week, from `0` (Sunday) to `6` (Saturday)
+pub const DAY_OF_WEEK_VARIABLE: &str = "dayOfWeek";
+/// The viewer's interest segments, an array of strings
+pub const SEGMENTS_VARIABLE: &str = "segments";
+
+/// The values of the variables for a single request, they're part of the memoized & cached targeting results keys
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Variables {
+    pub hour: u32,
+    pub day_of_week: u32,
+    /// Sorted & without duplicates
+    pub segments: Vec<String>,
+}
+
+impl Variables {
+    pub fn new(day_time: DayTime, segments: &[String]) -> Self {
+        let mut segments = segments.to_vec();
+        segments.sort();
+        segments.dedup();
+
+        Self {
+            hour: day_time.hour,
+            day_of_week: day_time.day_of_week,
+            segments,
+        }
+    }
+
+    fn value(&self, variable: &str) -> Option<Value> {
+        match variable {
+            HOUR_VARIABLE => Some(self.hour.into()),
+            DAY_OF_WEEK_VARIABLE => Some(self.day_of_week.into()),
+            SEGMENTS_VARIABLE => Some(self.segments.clone().into()),
+            _ => None,
+        }
+    }
+
+    /// Replaces every `{ "get": variable }` of the variables with its value, the rest of the `rules` are left as they are.
+    ///
+    /// The `rules` are returned unchanged if they don't use the variables (or they can't be serialized).
+    pub fn substitute(&self, rules: Rules) -> Rules {
+        let mut serialized = match serde_json::to_value(&rules.0) {
+            Ok(serialized) => serialized,
+            Err(_) => return rules,
+        };
+
+        if !self.substitute_values(&mut serialized) {
+            return rules;
+        }
+
+        serde_json::from_value(serialized)
+            .map(Rules)
+            .unwrap_or(rules)
+    }
+
+    /// Walks the serialized rules with an explicit stack (see [`RulesComplexity`](crate::cache::validation::RulesComplexity))
+    /// and returns whether any of the variables was replaced
+    fn substitute_values(&self, rules: &mut Value) -> bool {
+        let mut substituted = false;
+
+        let mut stack = vec![rules];
+        while let Some(node) = stack.pop() {
+            if let Some(value) = self.variable_value(node) {
+                *node = value;
+                substituted = true;
+
+                continue;
+            }
+
+            match node {
+                Value::Array(values) => stack.extend(values.iter_mut()),
+                Value::Object(function) => stack.extend(function.values_mut()),
+                _ => {}
+            }
+        }
+
+        substituted
+    }
+
+    /// The value of `{ "get": variable }` if it's one of the variables
+    fn variable_value(&self, node: &Value) -> Option<Value> {
+        match node {
+            Value::Object(function) if function.len() == 1 => function
+                .get("get")
+                .and_then(Value::as_str)
+                .and_then(|variable| self.value(variable)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use primitives::{
+        targeting::{eval_with_callback, input, Input, Output},
+        util::tests::prep_db::IDS,
+        BigNum,
+    };
+
+    fn variables(hour: u32, day_of_week: u32) -> Variables {
+        Variables {
+            hour,
+            day_of_week,
+            segments: vec![],
+        }
+    }
+
+    /// Whether the `rules` show a unit, as evaluated by the AdView
+    fn shows(rules: &Rules) -> bool {
+        let input = Input {
+            ad_view: None,
+            global: input::Global {
+                ad_slot_id: "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C".to_string(),
+                ad_slot_type: "legacy_250x250".to_string(),
+                publisher_id: IDS["publisher"],
+                country: None,
+                event_type: "IMPRESSION".to_string(),
+                seconds_since_epoch: chrono::Utc::now(),
+                user_agent_os: None,
+                user_agent_browser_family: None,
+            },
+            ad_unit_id: None,
+            balances: None,
+            channel: None,
+            ad_slot: None,
+        };
+        let mut output = Output {
+            show: true,
+            boost: 1.0,
+            price: vec![("IMPRESSION".to_string(), BigNum::from(1))]
+                .into_iter()
+                .collect(),
+        };
+
+        eval_with_callback(&rules.0, &input, &mut output, Some(|_error, _rule| {}));
+
+        output.show
+    }
+
+    /// `onlyShowIf` the viewer's hour is from `from` to `to`
+    fn hour_rules(from: u32, to: u32) -> Rules {
+        serde_json::from_value(serde_json::json!([
+            { "onlyShowIf": { "and": [
+                { "gte": [{ "get": HOUR_VARIABLE }, from] },
+                { "lte": [{ "get": HOUR_VARIABLE }, to] },
+            ] } },
+        ]))
+        .expect("Should deserialize the rules")
+    }
+
+    #[test]
+    fn the_variables_are_replaced_with_their_values() {
+        let rules: Rules = serde_json::from_value(serde_json::json!([
+            { "onlyShowIf": { "and": [
+                { "in": [[1, 2, 3, 4, 5], { "get": DAY_OF_WEEK_VARIABLE }] },
+                { "gte": [{ "get": HOUR_VARIABLE }, 9] },
+            ] } },
+            { "onlyShowIf": { "eq": [{ "get": "adSlotType" }, "legacy_250x250"] } },
+        ]))
+        .expect("Should deserialize the rules");
+
+        let substituted =
+            serde_json::to_value(&variables(14, 3).substitute(rules).0).expect("Should serialize");
+        assert_eq!(
+            serde_json::json!([
+                { "onlyShowIf": { "and": [
+                    { "in": [[1, 2, 3, 4, 5], 3] },
+                    { "gte": [14, 9] },
+                ] } },
+                { "onlyShowIf": { "eq": [{ "get": "adSlotType" }, "legacy_250x250"] } },
+            ]),
+            substituted
+        );
+    }
+
+    #[test]
+    fn the_hour_rules_match_after_the_substitution() {
+        let business_hours = hour_rules(9, 17);
+
+        assert!(shows(&variables(9, 1).substitute(business_hours.clone())));
+        assert!(shows(&variables(17, 1).substitute(business_hours.clone())));
+        assert!(!shows(&variables(8, 1).substitute(business_hours.clone())));
+        assert!(!shows(&variables(18, 1).substitute(business_hours)));
+    }
+
+    #[test]
+    fn the_segment_rules_match_after_the_substitution() {
+        let sports_fans: Rules = serde_json::from_value(serde_json::json!([
+            { "onlyShowIf": { "intersects": [{ "get": SEGMENTS_VARIABLE }, ["sports", "football"]] } },
+        ]))
+        .expect("Should deserialize the rules");
+        let with_segments = |segments: &[&str]| {
+            let segments = segments.iter().map(ToString::to_string).collect::<Vec<_>>();
+            let day_time = DayTime::new(chrono::Utc::now(), 0).expect("Valid offset");
+
+            Variables::new(day_time, &segments).substitute(sports_fans.clone())
+        };
+
+        assert!(shows(&with_segments(&["news", "football"])));
+        assert!(!shows(&with_segments(&["news", "cooking"])));
+        // e.g. without the consent
+        assert!(!shows(&with_segments(&[])));
+    }
+
+    #[test]
+    fn the_segments_are_sorted_and_deduplicated() {
+        let day_time = DayTime::new(chrono::Utc::now(), 0).expect("Valid offset");
+        let segments = ["sports", "news", "sports"]
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec!["news", "sports"],
+            Variables::new(day_time, &segments).segments
+        );
+    }
+}
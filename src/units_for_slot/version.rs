@@ -0,0 +1,322 @@
+use super::{CampaignDetails, DayTime, EmptyReason, PagedResponse, Strategy};
+use chrono::{DateTime, Utc};
+use http::{
+    header::{HeaderName, ACCEPT},
+    HeaderMap, StatusCode,
+};
+use hyper::{Body, Response};
+use primitives::{
+    supermarket::units_for_slot::response::{self, Response as UnitsForSlotResponse},
+    targeting::input::Input,
+    ChannelId,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use url::Url;
+
+/// The versions of the units-for-slot response which can be requested with
+/// `Accept: application/json; version=N`
+pub const SUPPORTED_VERSIONS: [u8; 2] = [1, 2];
+
+lazy_static::lazy_static! {
+    /// The version of the units-for-slot response, echoed in every successful response
+    pub static ref RESPONSE_VERSION_HEADER: HeaderName = HeaderName::from_static("x-response-version");
+}
+
+/// The version of the units-for-slot response format, selected by the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseVersion {
+    /// The [`PagedResponse`], the default for requests without a version
+    V1,
+    /// The [`PagedResponseV2`] with the Campaign objects and the debug fields of the units
+    V2,
+}
+
+impl Default for ResponseVersion {
+    fn default() -> Self {
+        Self::V1
+    }
+}
+
+impl ResponseVersion {
+    /// The `version` parameter of the first JSON media range of the `Accept` header (incl. `*/*` & `application/*`).
+    /// Without one it's [`ResponseVersion::V1`], for an unsupported version it returns the requested one.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, String> {
+        let media_ranges = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .flat_map(|accept| accept.split(','));
+
+        for media_range in media_ranges {
+            let mut params = media_range.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let is_json = ["application/json", "application/*", "*/*"]
+                .iter()
+                .any(|json| media_type.eq_ignore_ascii_case(json));
+            if !is_json {
+                continue;
+            }
+
+            let version = params.find_map(|param| {
+                let mut param = param.splitn(2, '=');
+                let name = param.next().unwrap_or_default().trim();
+
+                if name.eq_ignore_ascii_case("version") {
+                    param.next().map(|value| value.trim().trim_matches('"'))
+                } else {
+                    None
+                }
+            });
+
+            match version {
+                Some("1") => return Ok(Self::V1),
+                Some("2") => return Ok(Self::V2),
+                Some(unsupported) => return Err(unsupported.to_string()),
+                None => continue,
+            }
+        }
+
+        Ok(Self::default())
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V1 => "1",
+            Self::V2 => "2",
+        }
+    }
+
+    /// Serializes the response in this version
+    pub fn to_json(&self, response: PagedResponse) -> Result<String, serde_json::Error> {
+        match self {
+            Self::V1 => serde_json::to_string(&response),
+            Self::V2 => serde_json::to_string(&PagedResponseV2::from(response)),
+        }
+    }
+
+    /// Serializes the [`PerTypeResponse`] with the response of each type in this version
+    pub fn to_json_per_type(
+        &self,
+        responses: BTreeMap<String, PagedResponse>,
+    ) -> Result<String, serde_json::Error> {
+        match self {
+            Self::V1 => serde_json::to_string(&PerTypeResponse { types: responses }),
+            Self::V2 => serde_json::to_string(&PerTypeResponse {
+                types: responses
+                    .into_iter()
+                    .map(|(ad_type, response)| (ad_type, PagedResponseV2::from(response)))
+                    .collect::<BTreeMap<_, _>>(),
+            }),
+        }
+    }
+}
+
+/// The response for multiple `?type=`s (e.g. for header bidding), with the response of each AdUnit type.
+///
+/// The requests for a single type keep the flat response.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PerTypeResponse<R> {
+    pub types: BTreeMap<String, R>,
+}
+
+/// `406 Not Acceptable` with the [`SUPPORTED_VERSIONS`]
+pub fn not_acceptable(requested: &str) -> Response<Body> {
+    let supported = SUPPORTED_VERSIONS
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Response::builder()
+        .status(StatusCode::NOT_ACCEPTABLE)
+        .body(Body::from(format!(
+            "Unsupported response version `{}`, supported versions: {}",
+            requested, supported
+        )))
+        .expect("Not Acceptable response should be valid")
+}
+
+/// The version 2 of the units-for-slot response.
+///
+/// The Campaigns carry their details, while the units only reference their Campaign
+/// and have the `debug` fields grouped, see [`UnitV2`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PagedResponseV2 {
+    pub targeting_input_base: Input,
+    pub accepted_referrers: Vec<Url>,
+    pub fallback_unit: Option<response::AdUnit>,
+    /// The Campaigns of the page with only their units in the page
+    pub campaigns: Vec<CampaignV2>,
+    pub units: Vec<UnitV2>,
+    pub page: Page,
+    pub day_time: DayTime,
+    pub personalized: bool,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub suspect_bot: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub referrer_mismatch: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CampaignV2 {
+    #[serde(flatten)]
+    pub campaign: response::Campaign,
+    pub details: CampaignDetails,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnitV2 {
+    #[serde(flatten)]
+    pub unit: response::UnitsWithPrice,
+    /// The Campaign (of the `campaigns`) from which the unit was matched
+    pub channel_id: ChannelId,
+    pub also_available_in: Vec<ChannelId>,
+    /// Only with `?debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug: Option<UnitDebug>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnitDebug {
+    pub score: f64,
+    pub last_refreshed: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page {
+    pub total_matched: usize,
+    pub skip: usize,
+    pub limit: Option<usize>,
+    /// Only with `?debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_units: Option<usize>,
+    /// Only with `?debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<EmptyReason>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl From<PagedResponse> for PagedResponseV2 {
+    fn from(paged: PagedResponse) -> Self {
+        let UnitsForSlotResponse {
+            targeting_input_base,
+            accepted_referrers,
+            campaigns,
+            fallback_unit,
+        } = paged.response;
+
+        // every Campaign of the page has at least one unit in it
+        let mut details = paged
+            .units
+            .iter()
+            .map(|matched| (matched.campaign.channel_id, matched.campaign.clone()))
+            .collect::<HashMap<_, _>>();
+        let campaigns = campaigns
+            .into_iter()
+            .filter_map(|campaign| {
+                let details = details.remove(&campaign.channel.id)?;
+
+                Some(CampaignV2 { campaign, details })
+            })
+            .collect();
+
+        let units = paged
+            .units
+            .into_iter()
+            .map(|matched| UnitV2 {
+                unit: matched.unit,
+                channel_id: matched.campaign.channel_id,
+                also_available_in: matched.also_available_in,
+                debug: matched.score.map(|score| UnitDebug {
+                    score,
+                    last_refreshed: matched.last_refreshed,
+                }),
+            })
+            .collect();
+
+        Self {
+            targeting_input_base,
+            accepted_referrers,
+            fallback_unit,
+            campaigns,
+            units,
+            page: Page {
+                total_matched: paged.total_matched,
+                skip: paged.skip,
+                limit: paged.limit,
+                archived_units: paged.archived_units,
+                generation: paged.generation,
+                reason: paged.reason,
+                truncated: paged.truncated,
+            },
+            day_time: paged.day_time,
+            personalized: paged.personalized,
+            strategy: paged.strategy,
+            suspect_bot: paged.suspect_bot,
+            referrer_mismatch: paged.referrer_mismatch,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(value));
+
+        headers
+    }
+
+    #[test]
+    fn the_version_is_selected_by_the_accept_header() {
+        assert_eq!(
+            Ok(ResponseVersion::V1),
+            ResponseVersion::from_headers(&HeaderMap::new())
+        );
+        assert_eq!(
+            Ok(ResponseVersion::V1),
+            ResponseVersion::from_headers(&accept("application/json"))
+        );
+        assert_eq!(
+            Ok(ResponseVersion::V1),
+            ResponseVersion::from_headers(&accept("application/json; version=1"))
+        );
+        assert_eq!(
+            Ok(ResponseVersion::V2),
+            ResponseVersion::from_headers(&accept("application/json;version=\"2\""))
+        );
+        assert_eq!(
+            Ok(ResponseVersion::V2),
+            ResponseVersion::from_headers(&accept("text/html, */*; q=0.8; version=2"))
+        );
+        // the version of other media types is ignored
+        assert_eq!(
+            Ok(ResponseVersion::V1),
+            ResponseVersion::from_headers(&accept("text/html; version=3"))
+        );
+
+        assert_eq!(
+            Err("3".to_string()),
+            ResponseVersion::from_headers(&accept("application/json; version=3"))
+        );
+        assert_eq!(
+            Err("two".to_string()),
+            ResponseVersion::from_headers(&accept("application/json; Version=two"))
+        );
+    }
+}
@@ -1,12 +1,23 @@
 use super::*;
 use crate::{
-    cache::mock_client::MockClient, config::DEVELOPMENT, util::test::discard_logger, MarketApi,
+    bot::CidrSet,
+    cache::mock_client::MockClient,
+    config::{DegradationPolicy, DEVELOPMENT},
+    ids::Canonical,
+    market::Proxy,
+    metrics::DEADLINES_EXCEEDED,
+    status::Status,
+    util::{
+        test::{discard_logger, MockClock},
+        Clock,
+    },
+    MarketApi,
 };
 use chrono::{DateTime, TimeZone, Utc};
 use http::{header::USER_AGENT, request::Request};
 use hyper::Body;
 use primitives::{
-    market::AdUnitsResponse,
+    market::{AdUnitResponse, AdUnitsResponse},
     supermarket::units_for_slot::response::{
         Campaign as ResponseCampaign, Channel as ResponseChannel, UnitsWithPrice,
     },
@@ -18,7 +29,7 @@ use primitives::{
 use std::{collections::HashMap, iter::Iterator, str::FromStr, sync::Arc};
 use url::Url;
 use wiremock::{
-    matchers::{method, path},
+    matchers::{header, method, path},
     Mock, MockServer, ResponseTemplate,
 };
 
@@ -45,9 +56,19 @@ fn get_units_with_price(channel: &Channel) -> Vec<UnitsWithPrice> {
         .spec
         .ad_units
         .iter()
-        .map(|u| UnitsWithPrice {
-            unit: u.into(),
-            price: channel.spec.min_per_impression.clone(),
+        .map(|u| {
+            let mut unit: response::AdUnit = u.into();
+            // the `ipfs://` media URLs are rewritten to the IPFS gateway of the config
+            if let Ok(Some(media_url)) =
+                ipfs::gateway_url(&DEVELOPMENT.ipfs_gateway, &unit.media_url)
+            {
+                unit.media_url = media_url;
+            }
+
+            UnitsWithPrice {
+                unit,
+                price: channel.spec.min_per_impression.clone(),
+            }
         })
         .collect()
 }
@@ -180,6 +201,153 @@ fn mock_multiple_cache_campaigns(channels: Vec<Channel>) -> HashMap<ChannelId, C
     campaigns
 }
 
+/// The AdUnits of the `channel` as the Market serves them
+fn market_ad_units(channel: &Channel) -> AdUnitsResponse {
+    AdUnitsResponse(
+        channel
+            .spec
+            .ad_units
+            .clone()
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+    )
+}
+
+/// The Market (a mock server without any routes) with the Cache and the config of a units-for-slot test
+struct Setup {
+    logger: Logger,
+    config: Config,
+    server: MockServer,
+    market: Arc<MarketApi>,
+    cache: Cache<MockClient>,
+}
+
+impl Setup {
+    /// With the `campaigns` in the Cache
+    async fn new(campaigns: HashMap<ChannelId, Campaign>) -> Self {
+        let mock_client = MockClient::init(vec![campaigns], vec![], None).await;
+
+        Self::with_cache(Cache::initialize(mock_client).await).await
+    }
+
+    async fn with_cache(cache: Cache<MockClient>) -> Self {
+        let logger = discard_logger();
+        let server = MockServer::start().await;
+        let market = Arc::new(
+            MarketApi::new(
+                (server.uri() + "/market/")
+                    .parse()
+                    .expect("Wrong Market url"),
+                &DEVELOPMENT,
+                logger.clone(),
+            )
+            .expect("should create market instance"),
+        );
+
+        Self {
+            logger,
+            config: DEVELOPMENT.clone(),
+            server,
+            market,
+            cache,
+        }
+    }
+
+    /// The Market serves the AdSlot and the `ad_units` for `/market/units`
+    async fn mount_slot(&self, ad_slot: &AdSlotResponse, ad_units: &AdUnitsResponse) {
+        Mock::given(method("GET"))
+            .and(path("/market/units"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(ad_units))
+            .mount(&self.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/market/slots/{}", ad_slot.slot.ipfs)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(ad_slot))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// The Market serves the AdSlot (without AdUnits), which is expected to be fetched `expected_fetches` times
+    async fn mount_expected_slot(&self, ad_slot: &AdSlotResponse, expected_fetches: u64) {
+        Mock::given(method("GET"))
+            .and(path("/market/units"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![])))
+            .mount(&self.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/market/slots/{}", ad_slot.slot.ipfs)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(ad_slot))
+            .expect(expected_fetches)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// The Market serves the AdSlot and its fallback AdUnit, it responds to `/market/slots` and `/market/units` after the delays
+    async fn mount_slow_slot(
+        &self,
+        ad_slot: &AdSlotResponse,
+        fallback_unit: &AdUnit,
+        slot_delay: Duration,
+        units_delay: Duration,
+    ) {
+        Mock::given(method("GET"))
+            .and(path("/market/units"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(&AdUnitsResponse(vec![]))
+                    .set_delay(units_delay),
+            )
+            .mount(&self.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/market/slots/{}", ad_slot.slot.ipfs)))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(ad_slot)
+                    .set_delay(slot_delay),
+            )
+            .mount(&self.server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/market/units/{}", fallback_unit.ipfs)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&AdUnitResponse {
+                unit: fallback_unit.clone(),
+            }))
+            .mount(&self.server)
+            .await;
+    }
+
+    async fn units_for_slot(&self, request: Request<Body>) -> Response<Body> {
+        get_units_for_slot(
+            &self.logger,
+            self.market.clone(),
+            &self.config,
+            &self.cache,
+            request,
+        )
+        .await
+        .expect("call shouldn't fail with provided data")
+    }
+
+    async fn units_for_slot_at(
+        &self,
+        request: Request<Body>,
+        now: DateTime<Utc>,
+    ) -> Response<Body> {
+        get_units_for_slot_at(
+            &self.logger,
+            self.market.clone(),
+            &self.config,
+            &self.cache,
+            request,
+            now,
+        )
+        .await
+        .expect("call shouldn't fail with provided data")
+    }
+}
+
 #[tokio::test]
 async fn targeting_input() {
     let logger = discard_logger();
@@ -251,9 +419,16 @@ async fn targeting_input() {
 
     assert_eq!(http::StatusCode::OK, actual_response.status());
 
+    let body = hyper::body::to_bytes(actual_response).await.unwrap();
+    crate::openapi::test::conforms(
+        &crate::openapi::document(),
+        "PagedResponse",
+        &serde_json::from_slice(&body).expect("Should deserialize"),
+    )
+    .expect("The response should conform to the OpenAPI document");
+
     let units_for_slot: UnitsForSlotResponse =
-        serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
-            .expect("Should deserialize");
+        serde_json::from_slice(&body).expect("Should deserialize");
 
     // we must use the same timestamp as the response, otherwise our tests will fail randomly
     let expected_response = get_expected_response(
@@ -676,6 +851,47 @@ async fn price_less_than_min_per_impression() {
     );
 }
 
+#[tokio::test]
+async fn deposit_assets_are_addresses_in_either_case() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channel))
+        .await;
+
+    for deposit_asset in &[
+        channel.deposit_asset.to_ascii_lowercase(),
+        channel
+            .deposit_asset
+            .to_ascii_uppercase()
+            .replacen("0X", "0x", 1),
+    ] {
+        let query = format!("depositAsset={}", deposit_asset);
+        let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+        let actual_response = setup.units_for_slot(request).await;
+        assert_eq!(http::StatusCode::OK, actual_response.status());
+
+        let paged: PagedResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+                .expect("Should deserialize");
+        assert_eq!(1, paged.response.campaigns.len(), "{}", deposit_asset);
+    }
+
+    for malformed in &["DAI", "0x6B175474E89094C44Da98b954EedeAC495271d0"] {
+        let query = format!("depositAsset={}", malformed);
+        let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+        let actual_response = setup.units_for_slot(request).await;
+        assert_eq!(http::StatusCode::BAD_REQUEST, actual_response.status());
+
+        let body = hyper::body::to_bytes(actual_response).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("`depositAsset`"));
+    }
+}
+
 #[tokio::test]
 async fn non_matching_deposit_asset() {
     let logger = discard_logger();
@@ -959,3 +1175,3543 @@ async fn get_sample_units_for_slot_output() {
 
     println!("{}", units_for_slot_pretty);
 }
+
+#[tokio::test]
+async fn paging_through_the_matched_units() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+
+    // 3 Campaigns with different prices and AdUnits
+    let channels: Vec<Channel> = (1..=3_u8)
+        .map(|i| {
+            let mut channel = mock_channel(&rules);
+            channel.id = ChannelId::from([i; 32]);
+            channel.spec.min_per_impression = (u64::from(i) * 100_000_000_000_000).into();
+            for ad_unit in channel.spec.ad_units.iter_mut() {
+                ad_unit.ipfs = format!("{}{}", ad_unit.ipfs, i);
+            }
+
+            channel
+        })
+        .collect();
+    let units_per_channel = channels[0].spec.ad_units.len();
+    let total_units = units_per_channel * channels.len();
+
+    let setup = Setup::new(mock_multiple_cache_campaigns(channels.clone())).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channels[0]))
+        .await;
+
+    let get_page = |skip: usize, limit: usize| {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?depositAsset={}&skip={}&limit={}",
+            mock_slot.slot.ipfs, DUMMY_CHANNEL.deposit_asset, skip, limit
+        ))
+        .header(USER_AGENT, TEST_USER_AGENT)
+        .header(CLOUDFLARE_IPCOUNTY_HEADER.clone(), TEST_CLOUDFLARE_IPCOUNTY)
+        .body(Body::empty())
+        .unwrap();
+
+        let setup = &setup;
+        async move {
+            let actual_response = setup.units_for_slot(request).await;
+
+            assert_eq!(http::StatusCode::OK, actual_response.status());
+
+            serde_json::from_slice::<PagedResponse>(
+                &hyper::body::to_bytes(actual_response).await.unwrap(),
+            )
+            .expect("Should deserialize")
+        }
+    };
+
+    let limit = units_per_channel;
+    let mut all_units = vec![];
+    for page in 0..3 {
+        let paged = get_page(page * limit, limit).await;
+
+        assert_eq!(total_units, paged.total_matched);
+        assert_eq!(page * limit, paged.skip);
+        assert_eq!(Some(limit), paged.limit);
+
+        // requesting the same page again returns the same units
+        let again = get_page(page * limit, limit).await;
+        assert_eq!(paged.response.campaigns, again.response.campaigns);
+
+        for campaign in paged.response.campaigns {
+            for unit in campaign.units_with_price {
+                all_units.push((campaign.channel.id, unit.unit.id, unit.price));
+            }
+        }
+    }
+
+    assert_eq!(total_units, all_units.len());
+    // sorted by price, starting from the highest paying Campaign
+    assert!(all_units.windows(2).all(|pair| pair[0].2 >= pair[1].2));
+    assert_eq!(ChannelId::from([3; 32]), all_units[0].0);
+    // no unit is returned twice
+    let unique: std::collections::HashSet<_> = all_units
+        .iter()
+        .map(|(channel_id, unit_id, _)| (channel_id.to_string(), unit_id.clone()))
+        .collect();
+    assert_eq!(total_units, unique.len());
+
+    // skipping beyond the end returns an empty list
+    let beyond = get_page(total_units, limit).await;
+    assert!(beyond.response.campaigns.is_empty());
+    assert_eq!(total_units, beyond.total_matched);
+}
+
+#[tokio::test]
+async fn malformed_pagination_is_a_bad_request() {
+    let setup = Setup::new(HashMap::new()).await;
+
+    let request =
+        Request::get("/units-for-slot/QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C?skip=-1")
+            .body(Body::empty())
+            .unwrap();
+
+    let actual_response = setup.units_for_slot(request).await;
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, actual_response.status());
+    let body = hyper::body::to_bytes(actual_response).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("skip"));
+}
+
+#[test]
+fn the_matched_units_cache_is_bounded_and_expires() {
+    let ttl = Duration::from_secs(30);
+    let now = Instant::now();
+    let mut cache = MatchedUnitsCache::default();
+
+    cache.insert("first".into(), now, MatchedUnits::new(vec![]), ttl, 2);
+    cache.insert("second".into(), now, MatchedUnits::new(vec![]), ttl, 2);
+    // the lookups don't change the order, the first one is still the least recently cached
+    assert!(cache.get(&"first".into(), now, ttl).is_some());
+    cache.insert("third".into(), now, MatchedUnits::new(vec![]), ttl, 2);
+
+    assert_eq!(2, cache.len());
+    assert!(cache.get(&"first".into(), now, ttl).is_none());
+    assert!(cache.get(&"second".into(), now, ttl).is_some());
+
+    let later = now + ttl;
+    assert!(cache.get(&"third".into(), later, ttl).is_none());
+    cache.insert("fourth".into(), later, MatchedUnits::new(vec![]), ttl, 2);
+    assert_eq!(1, cache.len(), "The expired entries should be dropped");
+
+    cache.insert("fifth".into(), later, MatchedUnits::new(vec![]), ttl, 0);
+    assert!(
+        cache.is_empty(),
+        "A capacity of 0 should disable the caching"
+    );
+}
+
+#[test]
+fn the_matched_units_key_has_only_the_normalized_inputs() {
+    const DAI: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+    const SAI: &str = "0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359";
+
+    let input = |country: &str| Input {
+        ad_view: None,
+        global: input::Global {
+            ad_slot_id: "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C".to_string(),
+            ad_slot_type: "legacy_250x250".to_string(),
+            publisher_id: IDS["publisher"],
+            country: Some(country.to_string()),
+            event_type: "IMPRESSION".to_string(),
+            seconds_since_epoch: Utc::now(),
+            user_agent_os: Some("Linux".to_string()),
+            user_agent_browser_family: Some("Firefox".to_string()),
+        },
+        ad_unit_id: None,
+        balances: None,
+        channel: None,
+        ad_slot: None,
+    };
+    let key_at_hour =
+        |raw_query: &str, deposit_assets: &[&str], segments: &[&str], country: &str, hour: u32| {
+            let query = UnitsForSlotQuery::parse(raw_query).expect("Should parse the query");
+            let deposit_assets: Vec<ValidatorId> = deposit_assets
+                .iter()
+                .map(|address| {
+                    let Canonical(deposit_asset) = address.parse().expect("Valid address");
+
+                    deposit_asset
+                })
+                .collect();
+            let segments: Vec<String> = segments.iter().map(|s| s.to_string()).collect();
+            let variables = Variables::new(
+                DayTime::new(Utc.ymd(2020, 11, 25).and_hms(hour, 0, 0), 0).expect("Valid offset"),
+                &segments,
+            );
+
+            matched_units_key(
+                "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C",
+                "legacy_250x250",
+                &query,
+                &deposit_assets,
+                &input(country),
+                &variables,
+            )
+        };
+    let key = |raw_query: &str, deposit_assets: &[&str], segments: &[&str], country: &str| {
+        key_at_hour(raw_query, deposit_assets, segments, country, 14)
+    };
+
+    let dai_lowercase = DAI.to_ascii_lowercase();
+    let expected = key("minScore=0.5", &[DAI, SAI], &["sports", "news"], "BG");
+    assert_eq!(
+        expected,
+        key(
+            "limit=5&min_score=0.5&gdpr_consent=CONSENT&skip=10&debug&unknown=1",
+            &[SAI, &dai_lowercase, DAI],
+            &["news", "sports"],
+            "BG"
+        ),
+        "The pagination, the unrelated parameters and the order of the values shouldn't change the key"
+    );
+    assert_ne!(
+        expected,
+        key("minScore=0.6", &[DAI, SAI], &["sports", "news"], "BG")
+    );
+    assert_ne!(
+        expected,
+        key("minScore=0.5", &[DAI], &["sports", "news"], "BG")
+    );
+    assert_ne!(
+        expected,
+        key("minScore=0.5", &[DAI, SAI], &["sports"], "BG")
+    );
+    assert_ne!(
+        expected,
+        key("minScore=0.5", &[DAI, SAI], &["sports", "news"], "US")
+    );
+    // the viewer's hour in their timezone, not the `?tz=` offset itself
+    assert_ne!(
+        expected,
+        key_at_hour("minScore=0.5", &[DAI, SAI], &["sports", "news"], "BG", 15)
+    );
+    assert_eq!(
+        expected,
+        key_at_hour(
+            "minScore=0.5&tz=-60",
+            &[DAI, SAI],
+            &["sports", "news"],
+            "BG",
+            14
+        )
+    );
+    assert_ne!(
+        expected,
+        key(
+            "minScore=0.5&noTargeting",
+            &[DAI, SAI],
+            &["sports", "news"],
+            "BG"
+        )
+    );
+}
+
+#[tokio::test]
+async fn units_carry_their_campaign_details() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+
+    // 2 Campaigns with the same AdUnits and prices, the one with the lower ChannelId is kept
+    let channels: Vec<Channel> = (1..=2_u8)
+        .map(|i| {
+            let mut channel = mock_channel(&rules);
+            channel.id = ChannelId::from([i; 32]);
+            // pinned for the `campaign` fixture
+            channel.creator = IDS["publisher"];
+            channel.deposit_asset = "0x6B175474E89094C44Da98b954EedeAC495271d0F".to_string();
+            channel.valid_until = Utc.ymd(2100, 1, 1).and_hms(0, 0, 0);
+            channel.spec.pricing_bounds = None;
+            let (mut leader, mut follower) = (
+                channel.spec.validators.leader().clone(),
+                channel.spec.validators.follower().clone(),
+            );
+            leader.url = "https://tom.adex.network".to_string();
+            follower.url = "https://jerry.adex.network".to_string();
+            channel.spec.validators = (leader, follower).into();
+
+            channel
+        })
+        .collect();
+    let expected_campaign = serde_json::json!({
+        "channelId": "0x0101010101010101010101010101010101010101010101010101010101010101",
+        "creator": "0xB7d3F81E857692d13e9D63b232A90F4A1793189E",
+        "depositAsset": "0x6B175474E89094C44Da98b954EedeAC495271d0F",
+        "pricingBounds": {
+            "min": "100000000000000",
+            "max": "1000000000000000",
+        },
+        // without `pricingBounds` in the spec, see `campaign_pricing_bounds_by_event_type`
+        "pricingBoundsByEvent": {
+            "IMPRESSION": {
+                "min": "100000000000000",
+                "max": "1000000000000000",
+            },
+        },
+        "validUntil": "2100-01-01T00:00:00Z",
+        "leaderUrl": "https://tom.adex.network",
+        "followerUrl": "https://jerry.adex.network",
+    });
+
+    let setup = Setup::new(mock_multiple_cache_campaigns(channels.clone())).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channels[0]))
+        .await;
+
+    let request = Request::get(format!(
+        "/units-for-slot/{}?depositAsset={}",
+        mock_slot.slot.ipfs, channels[0].deposit_asset
+    ))
+    .header(USER_AGENT, TEST_USER_AGENT)
+    .header(CLOUDFLARE_IPCOUNTY_HEADER.clone(), TEST_CLOUDFLARE_IPCOUNTY)
+    .body(Body::empty())
+    .unwrap();
+
+    let actual_response = setup.units_for_slot(request).await;
+
+    assert_eq!(http::StatusCode::OK, actual_response.status());
+
+    let actual: serde_json::Value =
+        serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+            .expect("Should deserialize");
+
+    let seconds_since_epoch =
+        serde_json::from_value(actual["targetingInputBase"]["global"]["secondsSinceEpoch"].clone())
+            .expect("Should have the timestamp");
+    // the units are deduplicated, so only the first Campaign is returned
+    let mut expected_response = get_expected_response(
+        vec![get_mock_campaign(channels[0].clone())],
+        seconds_since_epoch,
+    );
+    expected_response.accepted_referrers = mock_slot.accepted_referrers.clone();
+
+    let units = get_units_with_price(&channels[0])
+        .into_iter()
+        .map(|unit| MatchedUnit {
+            unit,
+            campaign: serde_json::from_value(expected_campaign.clone())
+                .expect("Should deserialize the fixture"),
+            also_available_in: vec![channels[1].id],
+            score: None,
+            last_refreshed: None,
+        })
+        .collect::<Vec<_>>();
+    let total_matched = units.len();
+
+    let expected = serde_json::to_value(&PagedResponse {
+        response: expected_response,
+        total_matched,
+        skip: 0,
+        limit: None,
+        day_time: DayTime::new(seconds_since_epoch, 0).expect("Valid offset"),
+        personalized: false,
+        strategy: Strategy::HighestPrice,
+        suspect_bot: false,
+        referrer_mismatch: false,
+        archived_units: None,
+        generation: None,
+        reason: None,
+        truncated: false,
+        units,
+    })
+    .expect("Should serialize");
+
+    pretty_assertions::assert_eq!(expected, actual);
+    for unit in actual["units"].as_array().expect("Should be an array") {
+        pretty_assertions::assert_eq!(expected_campaign, unit["campaign"]);
+    }
+}
+
+#[test]
+fn campaign_pricing_bounds_by_event_type() {
+    let with_spec = |spec: serde_json::Value| {
+        let mut channel = serde_json::to_value(mock_channel(&[])).expect("Should serialize");
+        let channel_spec = channel["spec"]
+            .as_object_mut()
+            .expect("The spec should be an object");
+        channel_spec.remove("pricingBounds");
+        for (key, value) in spec.as_object().expect("Should be an object") {
+            channel_spec.insert(key.clone(), value.clone());
+        }
+
+        let channel: Channel = serde_json::from_value(channel).expect("Should deserialize");
+        CampaignDetails::from(&channel)
+    };
+    let bounds = |min: u64, max: u64| PricingBounds {
+        min: min.into(),
+        max: max.into(),
+    };
+
+    // the IMPRESSION & CLICK bounds
+    let details = with_spec(serde_json::json!({
+        "minPerImpression": "1",
+        "maxPerImpression": "2",
+        "pricingBounds": {
+            "IMPRESSION": { "min": "100", "max": "200" },
+            "CLICK": { "min": "300", "max": "400" },
+        },
+    }));
+    assert_eq!(bounds(100, 200), details.pricing_bounds);
+    assert_eq!(
+        vec![
+            ("CLICK".to_string(), bounds(300, 400)),
+            ("IMPRESSION".to_string(), bounds(100, 200)),
+        ],
+        details
+            .pricing_bounds_by_event
+            .into_iter()
+            .collect::<Vec<_>>()
+    );
+
+    // only the CLICK bounds, the IMPRESSION ones fall back to the legacy fields
+    let details = with_spec(serde_json::json!({
+        "minPerImpression": "1",
+        "maxPerImpression": "2",
+        "pricingBounds": {
+            "CLICK": { "min": "300", "max": "400" },
+        },
+    }));
+    assert_eq!(bounds(1, 2), details.pricing_bounds);
+    assert_eq!(
+        Some(&bounds(300, 400)),
+        details.pricing_bounds_by_event.get("CLICK")
+    );
+
+    // the legacy fields only
+    let details = with_spec(serde_json::json!({
+        "minPerImpression": "1",
+        "maxPerImpression": "2",
+    }));
+    assert_eq!(bounds(1, 2), details.pricing_bounds);
+    assert_eq!(
+        vec![("IMPRESSION".to_string(), bounds(1, 2))],
+        details
+            .pricing_bounds_by_event
+            .into_iter()
+            .collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn same_unit_in_multiple_campaigns_is_deduplicated() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+
+    // 3 Campaigns with the same AdUnits at different prices
+    let channels: Vec<Channel> = (1..=3_u8)
+        .map(|i| {
+            let mut channel = mock_channel(&rules);
+            channel.id = ChannelId::from([i; 32]);
+            channel.spec.min_per_impression = (u64::from(i) * 100_000_000_000_000).into();
+
+            channel
+        })
+        .collect();
+    let units_per_channel = channels[0].spec.ad_units.len();
+    assert!(
+        units_per_channel > 1,
+        "We need at least 2 AdUnits for the limit"
+    );
+
+    let setup = Setup::new(mock_multiple_cache_campaigns(channels.clone())).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channels[0]))
+        .await;
+
+    let limit = units_per_channel - 1;
+    let request = Request::get(format!(
+        "/units-for-slot/{}?depositAsset={}&limit={}",
+        mock_slot.slot.ipfs, DUMMY_CHANNEL.deposit_asset, limit
+    ))
+    .header(USER_AGENT, TEST_USER_AGENT)
+    .header(CLOUDFLARE_IPCOUNTY_HEADER.clone(), TEST_CLOUDFLARE_IPCOUNTY)
+    .body(Body::empty())
+    .unwrap();
+
+    let actual_response = setup.units_for_slot(request).await;
+
+    assert_eq!(http::StatusCode::OK, actual_response.status());
+
+    let paged: PagedResponse =
+        serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+            .expect("Should deserialize");
+
+    // deduplicated before applying the limit
+    assert_eq!(units_per_channel, paged.total_matched);
+    assert_eq!(limit, paged.units.len());
+
+    let best_paying = &channels[2];
+    for unit in paged.units.iter() {
+        assert_eq!(best_paying.id, unit.campaign.channel_id);
+        assert_eq!(best_paying.spec.min_per_impression, unit.unit.price);
+        assert_eq!(vec![channels[1].id, channels[0].id], unit.also_available_in);
+    }
+
+    assert_eq!(1, paged.response.campaigns.len());
+    assert_eq!(best_paying.id, paged.response.campaigns[0].channel.id);
+}
+
+#[test]
+fn day_time_across_day_boundaries() {
+    // Sunday
+    let sunday_night = Utc.ymd(2020, 11, 29).and_hms(23, 30, 0);
+
+    assert_eq!(
+        Some(DayTime {
+            hour: 23,
+            day_of_week: 0,
+            timezone_offset: 0
+        }),
+        DayTime::new(sunday_night, 0)
+    );
+    // UTC+1 - it's already Monday
+    assert_eq!(
+        Some(DayTime {
+            hour: 0,
+            day_of_week: 1,
+            timezone_offset: 60
+        }),
+        DayTime::new(sunday_night, 60)
+    );
+
+    // Monday
+    let monday_morning = Utc.ymd(2020, 11, 30).and_hms(2, 0, 0);
+    // UTC-5 - it's still Sunday
+    assert_eq!(
+        Some(DayTime {
+            hour: 21,
+            day_of_week: 0,
+            timezone_offset: -300
+        }),
+        DayTime::new(monday_morning, -300)
+    );
+    // UTC-5:30
+    assert_eq!(
+        Some(DayTime {
+            hour: 20,
+            day_of_week: 0,
+            timezone_offset: -330
+        }),
+        DayTime::new(monday_morning, -330)
+    );
+    // Saturday, UTC+14 from Friday
+    assert_eq!(
+        Some(6),
+        DayTime::new(Utc.ymd(2020, 11, 27).and_hms(12, 0, 0), 14 * 60)
+            .map(|day_time| day_time.day_of_week)
+    );
+}
+
+#[test]
+fn parsing_the_timezone_offset() {
+    let timezone_offset = |query: &str| {
+        UnitsForSlotQuery::parse(query)
+            .map(|query| query.timezone_offset)
+            .map_err(|malformed| malformed.parameter)
+    };
+
+    assert_eq!(Ok(0), timezone_offset(""));
+    assert_eq!(Ok(0), timezone_offset("depositAsset=0x0"));
+    assert_eq!(Ok(120), timezone_offset("tz=120"));
+    assert_eq!(Ok(-300), timezone_offset("limit=1&tz=-300"));
+    assert_eq!(Ok(-720), timezone_offset("tz=-720"));
+    assert_eq!(Ok(840), timezone_offset("tz=840"));
+
+    for invalid in &[
+        "tz=",
+        "tz=UTC",
+        "tz=1.5",
+        "tz=841",
+        "tz=-721",
+        "tz=99999999999",
+    ] {
+        assert_eq!(Err("tz"), timezone_offset(invalid), "{}", invalid);
+    }
+}
+
+#[tokio::test]
+async fn day_time_uses_the_pinned_clock_and_the_timezone() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    // Sunday
+    let now = Utc.ymd(2020, 11, 29).and_hms(23, 30, 0);
+
+    let request = Request::get(format!(
+        "/units-for-slot/{}?depositAsset={}&tz=60",
+        mock_slot.slot.ipfs, channel.deposit_asset
+    ))
+    .body(Body::empty())
+    .unwrap();
+
+    let actual_response = setup.units_for_slot_at(request, now).await;
+
+    assert_eq!(http::StatusCode::OK, actual_response.status());
+
+    let paged: PagedResponse =
+        serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+            .expect("Should deserialize");
+
+    assert_eq!(
+        now,
+        paged
+            .response
+            .targeting_input_base
+            .global
+            .seconds_since_epoch
+    );
+    assert_eq!(
+        DayTime {
+            hour: 0,
+            day_of_week: 1,
+            timezone_offset: 60
+        },
+        paged.day_time
+    );
+
+    let request = Request::get(format!("/units-for-slot/{}?tz=UTC", mock_slot.slot.ipfs))
+        .body(Body::empty())
+        .unwrap();
+
+    let actual_response = setup.units_for_slot_at(request, now).await;
+
+    assert_eq!(http::StatusCode::BAD_REQUEST, actual_response.status());
+}
+
+#[tokio::test]
+async fn the_rules_are_evaluated_with_the_viewers_hour_and_day_of_week() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let daypart_rules = |rules: serde_json::Value| -> Vec<Rule> {
+        serde_json::from_value(rules).expect("Should deserialize the rules")
+    };
+
+    // only at night
+    let mut night = mock_channel(&daypart_rules(serde_json::json!([
+        { "onlyShowIf": { "lte": [{ "get": "hour" }, 5] } },
+    ])));
+    night.id = ChannelId::from([1; 32]);
+    // only in the business hours of the working days
+    let mut business_hours = mock_channel(&daypart_rules(serde_json::json!([
+        { "onlyShowIf": { "and": [
+            { "in": [[1, 2, 3, 4, 5], { "get": "dayOfWeek" }] },
+            { "gte": [{ "get": "hour" }, 9] },
+            { "lte": [{ "get": "hour" }, 17] },
+        ] } },
+    ])));
+    business_hours.id = ChannelId::from([2; 32]);
+
+    let setup = Setup::new(mock_multiple_cache_campaigns(vec![
+        night.clone(),
+        business_hours.clone(),
+    ]))
+    .await;
+    let mock_slot = get_supermarket_ad_slot(&[], &categories);
+    setup.mount_slot(&mock_slot, &market_ad_units(&night)).await;
+
+    // Sunday, 23:30 UTC
+    let now = Utc.ymd(2020, 11, 29).and_hms(23, 30, 0);
+    // (`?tz=`, the matched Campaign)
+    let cases = [
+        // Monday, 00:30
+        (60, Some(night.id)),
+        // Monday, 10:30
+        (660, Some(business_hours.id)),
+        // Sunday, 13:30
+        (-600, None),
+    ];
+    for (timezone_offset, expected) in cases.iter() {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?depositAsset={}&tz={}",
+            mock_slot.slot.ipfs, night.deposit_asset, timezone_offset
+        ))
+        .header(USER_AGENT, TEST_USER_AGENT)
+        .body(Body::empty())
+        .unwrap();
+
+        let actual_response = setup.units_for_slot_at(request, now).await;
+        assert_eq!(http::StatusCode::OK, actual_response.status());
+        let paged: PagedResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+                .expect("Should deserialize");
+
+        let matched = paged
+            .response
+            .campaigns
+            .iter()
+            .map(|campaign| campaign.channel.id)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            expected.iter().cloned().collect::<Vec<_>>(),
+            matched,
+            "tz={}",
+            timezone_offset
+        );
+    }
+}
+
+/// For each of the AdSlot categories (tags) it multiplies the `boost` by the given factor
+fn scoring_rules(tag_boosts: &[(&str, f64)]) -> Rules {
+    let rules = tag_boosts
+        .iter()
+        .map(|(tag, boost)| {
+            serde_json::json!({
+                "if": [
+                    { "intersects": [{ "get": "adSlot.categories" }, [tag]] },
+                    { "set": ["boost", { "mul": [{ "get": "boost" }, boost] }] }
+                ]
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::from_value(serde_json::json!(rules)).expect("Should deserialize the rules")
+}
+
+#[tokio::test]
+async fn targeting_scores_and_the_min_score() {
+    let logger = discard_logger();
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    // the AdSlot is required for the `adSlot.categories`
+    let input_base = get_expected_response(vec![], Utc::now()).targeting_input_base;
+
+    let tag_sets: Vec<(&[(&str, f64)], f64)> = vec![
+        // 1.0 * 2.0 * 1.5, `IAB1` is not an AdSlot category
+        (&[("IAB3", 2.0), ("IAB5", 1.5), ("IAB1", 3.0)], 3.0),
+        // no overlap with the AdSlot categories
+        (&[("IAB12", 4.0)], 1.0),
+        // weak overlap
+        (&[("IAB3", 0.5)], 0.5),
+        // 1.0 * 4.0 * 0.125
+        (&[("IAB13-7", 4.0), ("IAB5", 0.125)], 0.5),
+    ];
+
+    let channels: Vec<Channel> = tag_sets
+        .iter()
+        .enumerate()
+        .map(|(i, (tag_boosts, _))| {
+            let mut channel = mock_channel(&[]);
+            channel.id = ChannelId::from([i as u8 + 1; 32]);
+            channel.spec.targeting_rules = scoring_rules(tag_boosts);
+
+            channel
+        })
+        .collect();
+    let campaigns = mock_multiple_cache_campaigns(channels.clone())
+        .into_iter()
+        .map(|(_, campaign)| campaign)
+        .collect::<Vec<_>>();
+
+    let expected_score = |channel_id: ChannelId| {
+        let index = channels
+            .iter()
+            .position(|channel| channel.id == channel_id)
+            .expect("Should be one of the channels");
+
+        tag_sets[index].1
+    };
+
+    let scored = apply_targeting(
+        &DEVELOPMENT,
+        &logger,
+        campaigns.clone(),
+        input_base.clone(),
+        get_supermarket_ad_slot(&[], &categories),
+        0.0,
+        false,
+    )
+    .await;
+
+    assert_eq!(channels.len(), scored.len());
+    for targeted in scored.iter() {
+        let expected = expected_score(targeted.campaign.channel.id);
+
+        assert_eq!(
+            targeted.campaign.units_with_price.len(),
+            targeted.scores.len()
+        );
+        assert!(targeted.scores.iter().all(|score| *score == expected));
+    }
+
+    // a score of exactly the threshold is included
+    let above_threshold = apply_targeting(
+        &DEVELOPMENT,
+        &logger,
+        campaigns,
+        input_base,
+        get_supermarket_ad_slot(&[], &categories),
+        1.0,
+        false,
+    )
+    .await;
+
+    let mut channel_ids = above_threshold
+        .iter()
+        .map(|targeted| targeted.campaign.channel.id)
+        .collect::<Vec<_>>();
+    channel_ids.sort_by_key(|channel_id| channel_id.to_string());
+
+    assert_eq!(vec![channels[0].id, channels[1].id], channel_ids);
+}
+
+#[tokio::test]
+async fn debug_shows_the_score_of_each_unit() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    for (query, expected_score) in &[("debug=true", Some(1.0)), ("debug=false", None), ("", None)] {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?depositAsset={}&{}",
+            mock_slot.slot.ipfs, channel.deposit_asset, query
+        ))
+        .body(Body::empty())
+        .unwrap();
+
+        let actual_response = setup.units_for_slot(request).await;
+
+        assert_eq!(http::StatusCode::OK, actual_response.status());
+
+        let paged: PagedResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+                .expect("Should deserialize");
+
+        assert!(!paged.units.is_empty());
+        assert!(paged.units.iter().all(|unit| unit.score == *expected_score));
+        // the time of the last refresh is shown only with `?debug=true`
+        assert!(paged
+            .units
+            .iter()
+            .all(|unit| unit.last_refreshed.is_some() == expected_score.is_some()));
+    }
+
+    // the min score is higher than the default `boost`
+    let request = Request::get(format!(
+        "/units-for-slot/{}?depositAsset={}&minScore=1.5",
+        mock_slot.slot.ipfs, channel.deposit_asset
+    ))
+    .body(Body::empty())
+    .unwrap();
+
+    let actual_response = setup.units_for_slot(request).await;
+    let paged: PagedResponse =
+        serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+            .expect("Should deserialize");
+    assert_eq!(0, paged.total_matched);
+
+    for invalid in &["minScore=high", "minScore=NaN", "debug=yes"] {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?{}",
+            mock_slot.slot.ipfs, invalid
+        ))
+        .body(Body::empty())
+        .unwrap();
+
+        let actual_response = setup.units_for_slot(request).await;
+
+        assert_eq!(http::StatusCode::BAD_REQUEST, actual_response.status());
+    }
+}
+
+#[tokio::test]
+async fn the_response_version_is_selected_by_the_accept_header() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    let request = |accept: Option<&str>| {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?depositAsset={}&debug=true",
+            mock_slot.slot.ipfs, channel.deposit_asset
+        ));
+        let request = match accept {
+            Some(accept) => request.header(http::header::ACCEPT, accept),
+            None => request,
+        };
+
+        request.body(Body::empty()).unwrap()
+    };
+
+    for (accept, expected_version) in &[
+        (None, "1"),
+        (Some("application/json; version=1"), "1"),
+        (Some("application/json; version=2"), "2"),
+    ] {
+        let response = setup.units_for_slot(request(*accept)).await;
+
+        assert_eq!(http::StatusCode::OK, response.status());
+        assert_eq!(
+            *expected_version,
+            response.headers()[RESPONSE_VERSION_HEADER.clone()]
+        );
+    }
+
+    let response = setup
+        .units_for_slot(request(Some("application/json; version=3")))
+        .await;
+    assert_eq!(http::StatusCode::NOT_ACCEPTABLE, response.status());
+    let body = hyper::body::to_bytes(response).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("supported versions: 1, 2"));
+
+    // both versions are serialized from the same internal result
+    let response = setup.units_for_slot(request(None)).await;
+    let paged: PagedResponse =
+        serde_json::from_slice(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize");
+    let unit = paged.units[0].clone();
+
+    let v1: serde_json::Value = serde_json::from_str(
+        &ResponseVersion::V1
+            .to_json(clone_paged(&paged))
+            .expect("Should serialize"),
+    )
+    .expect("Should deserialize");
+    assert_eq!(paged.total_matched, v1["totalMatched"]);
+    assert_eq!(
+        serde_json::to_value(&unit.campaign).unwrap(),
+        v1["units"][0]["campaign"]
+    );
+    assert_eq!(unit.score, v1["units"][0]["score"].as_f64());
+    assert!(v1.get("page").is_none());
+
+    let v2: serde_json::Value = serde_json::from_str(
+        &ResponseVersion::V2
+            .to_json(paged)
+            .expect("Should serialize"),
+    )
+    .expect("Should deserialize");
+    assert_eq!(v1["totalMatched"], v2["page"]["totalMatched"]);
+    assert!(v2.get("totalMatched").is_none());
+    assert_eq!(v1["targetingInputBase"], v2["targetingInputBase"]);
+    assert_eq!(v1["dayTime"], v2["dayTime"]);
+    assert_eq!(
+        serde_json::to_value(&unit.campaign).unwrap(),
+        v2["campaigns"][0]["details"]
+    );
+    assert_eq!(
+        v1["campaigns"][0]["targetingRules"],
+        v2["campaigns"][0]["targetingRules"]
+    );
+    assert_eq!(
+        serde_json::json!(unit.campaign.channel_id),
+        v2["units"][0]["channelId"]
+    );
+    assert!(v2["units"][0].get("campaign").is_none());
+    assert_eq!(unit.score, v2["units"][0]["debug"]["score"].as_f64());
+    assert_eq!(
+        v1["units"][0]["lastRefreshed"],
+        v2["units"][0]["debug"]["lastRefreshed"]
+    );
+}
+
+/// [`PagedResponse`] is not `Clone`, so it goes through its JSON
+fn clone_paged(paged: &PagedResponse) -> PagedResponse {
+    serde_json::from_value(serde_json::to_value(paged).expect("Should serialize"))
+        .expect("Should deserialize")
+}
+
+#[tokio::test]
+async fn no_targeting_skips_the_campaign_rules() {
+    let logger = discard_logger();
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    // the AdSlot is required for the `adSlot.categories`
+    let input_base = get_expected_response(vec![], Utc::now()).targeting_input_base;
+
+    let non_matching_rules = get_mock_rules(&["IAB2", "IAB9-WS1", "IAB19"]);
+    let campaigns = mock_cache_campaign(mock_channel(&non_matching_rules), Status::Active)
+        .into_iter()
+        .map(|(_, campaign)| campaign)
+        .collect::<Vec<_>>();
+
+    let targeted = apply_targeting(
+        &DEVELOPMENT,
+        &logger,
+        campaigns.clone(),
+        input_base.clone(),
+        get_supermarket_ad_slot(&[], &categories),
+        0.0,
+        false,
+    )
+    .await;
+    assert!(targeted.is_empty());
+
+    let not_targeted = apply_targeting(
+        &DEVELOPMENT,
+        &logger,
+        campaigns,
+        input_base,
+        get_supermarket_ad_slot(&[], &categories),
+        0.0,
+        true,
+    )
+    .await;
+    assert_eq!(1, not_targeted.len());
+    assert!(not_targeted[0].campaign.targeting_rules.0.is_empty());
+}
+
+#[tokio::test]
+async fn too_complex_adslot_rules_show_no_units() {
+    let logger = discard_logger();
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let input_base = get_expected_response(vec![], Utc::now()).targeting_input_base;
+    let campaigns = mock_cache_campaign(mock_channel(&[]), Status::Active)
+        .into_iter()
+        .map(|(_, campaign)| campaign)
+        .collect::<Vec<_>>();
+
+    let mut boost = serde_json::json!({ "get": "boost" });
+    for _ in 0..crate::cache::validation::MAX_RULES_DEPTH {
+        boost = serde_json::json!({ "mul": [boost, 1.1] });
+    }
+    let deep: Vec<Rule> = serde_json::from_value(serde_json::json!([{ "set": ["boost", boost] }]))
+        .expect("Should deserialize the rules");
+    let wide = get_mock_rules(&["IAB3"; crate::cache::validation::MAX_RULES_NODES]);
+
+    let over_limits_before = ADSLOT_RULES_OVER_LIMITS.get();
+    for rules in [deep, wide].iter() {
+        let targeted = apply_targeting(
+            &DEVELOPMENT,
+            &logger,
+            campaigns.clone(),
+            input_base.clone(),
+            get_supermarket_ad_slot(rules, &categories),
+            0.0,
+            false,
+        )
+        .await;
+        assert!(targeted.is_empty());
+    }
+    assert!(ADSLOT_RULES_OVER_LIMITS.get() >= over_limits_before + 2);
+
+    // within the limits
+    let targeted = apply_targeting(
+        &DEVELOPMENT,
+        &logger,
+        campaigns,
+        input_base,
+        get_supermarket_ad_slot(&get_mock_rules(&categories), &categories),
+        0.0,
+        false,
+    )
+    .await;
+    assert_eq!(1, targeted.len());
+}
+
+#[tokio::test]
+async fn units_with_unreachable_media_are_demoted_or_excluded() {
+    let logger = discard_logger();
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let input_base = get_expected_response(vec![], Utc::now()).targeting_input_base;
+    let campaigns = mock_cache_campaign(mock_channel(&[]), Status::Active)
+        .into_iter()
+        .map(|(_, campaign)| campaign)
+        .collect::<Vec<_>>();
+
+    let targeted = apply_targeting(
+        &DEVELOPMENT,
+        &logger,
+        campaigns,
+        input_base,
+        get_supermarket_ad_slot(&[], &categories),
+        0.0,
+        false,
+    )
+    .await;
+    let matched_units = MatchedUnits::new(targeted);
+    let unit_ids = |matched_units: &MatchedUnits| {
+        matched_units
+            .page(Pagination::default(), false)
+            .1
+            .into_iter()
+            .map(|matched| matched.unit.unit.id)
+            .collect::<Vec<_>>()
+    };
+
+    let ranked = unit_ids(&matched_units);
+    assert!(ranked.len() > 1, "Should match multiple units");
+    let unreachable = ranked[0].clone();
+
+    let demoted = matched_units
+        .clone()
+        .with_unreachable_media(|unit| unit == unreachable, UnreachableMediaPolicy::Demote);
+    let mut expected = ranked[1..].to_vec();
+    expected.push(unreachable.clone());
+    assert_eq!(expected, unit_ids(&demoted));
+
+    let excluded = matched_units
+        .with_unreachable_media(|unit| unit == unreachable, UnreachableMediaPolicy::Exclude);
+    assert_eq!(ranked[1..].to_vec(), unit_ids(&excluded));
+    assert_eq!(ranked.len() - 1, excluded.total());
+}
+
+#[tokio::test]
+async fn stale_campaigns_are_not_served() {
+    let clock = MockClock::new();
+
+    let mut config = DEVELOPMENT.clone();
+    let max_staleness = Duration::from_secs(60);
+    config.max_campaign_staleness = Some(max_staleness);
+
+    let mock_client = MockClient::init(
+        vec![mock_cache_campaign(mock_channel(&[]), Status::Active)],
+        vec![],
+        None,
+    )
+    .await;
+    let cache = Cache::builder(mock_client)
+        .clock(Arc::new(clock.clone()))
+        .initialize()
+        .await;
+    let publisher_id = IDS["publisher"];
+
+    assert_eq!(
+        1,
+        get_campaigns(&cache, &config, &[], publisher_id, false)
+            .await
+            .len()
+    );
+
+    clock.advance(max_staleness + Duration::from_secs(1));
+    assert!(get_campaigns(&cache, &config, &[], publisher_id, false)
+        .await
+        .is_empty());
+    // without the option the Campaigns are served regardless
+    config.max_campaign_staleness = None;
+    assert_eq!(
+        1,
+        get_campaigns(&cache, &config, &[], publisher_id, false)
+            .await
+            .len()
+    );
+    config.max_campaign_staleness = Some(max_staleness);
+
+    // served again once refreshed
+    cache.fetch_new_campaigns().await;
+    assert_eq!(
+        1,
+        get_campaigns(&cache, &config, &[], publisher_id, false)
+            .await
+            .len()
+    );
+}
+
+#[tokio::test]
+async fn scheduled_campaigns_are_served_after_their_active_from() {
+    let clock = MockClock::new();
+
+    let mut channel = mock_channel(&[]);
+    channel.spec.active_from = Some(clock.now_utc() + chrono::Duration::minutes(1));
+    // even if the status is not updated yet
+    let mock_client = MockClient::init(
+        vec![mock_cache_campaign(channel, Status::Active)],
+        vec![],
+        None,
+    )
+    .await;
+    let cache = Cache::builder(mock_client)
+        .clock(Arc::new(clock.clone()))
+        .initialize()
+        .await;
+    let publisher_id = IDS["publisher"];
+
+    assert!(
+        get_campaigns(&cache, &DEVELOPMENT, &[], publisher_id, false)
+            .await
+            .is_empty()
+    );
+
+    clock.advance(Duration::from_secs(59));
+    assert!(
+        get_campaigns(&cache, &DEVELOPMENT, &[], publisher_id, false)
+            .await
+            .is_empty()
+    );
+
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(
+        1,
+        get_campaigns(&cache, &DEVELOPMENT, &[], publisher_id, false)
+            .await
+            .len()
+    );
+}
+
+fn targeted_to_json(targeted: &[TargetedCampaign]) -> Vec<serde_json::Value> {
+    targeted
+        .iter()
+        .map(|targeted| {
+            serde_json::json!({
+                "campaign": targeted.campaign,
+                "details": targeted.details,
+                "scores": targeted.scores,
+            })
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn memoized_targeting_agrees_with_the_fresh_evaluation() {
+    let logger = discard_logger();
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let ad_slot_response = get_supermarket_ad_slot(&[], &categories);
+    let mut input_base = get_expected_response(vec![], Utc::now()).targeting_input_base;
+    input_base.global.seconds_since_epoch =
+        memo::bucket_seconds(input_base.global.seconds_since_epoch);
+
+    let channels: Vec<Channel> = vec![
+        &[("IAB3", 2.0), ("IAB5", 1.5)][..],
+        &[("IAB12", 4.0)][..],
+        &[("IAB3", 0.5)][..],
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(i, tag_boosts)| {
+        let mut channel = mock_channel(&[]);
+        channel.id = ChannelId::from([i as u8 + 1; 32]);
+        channel.spec.targeting_rules = scoring_rules(tag_boosts);
+
+        channel
+    })
+    .collect();
+    let active = mock_multiple_cache_campaigns(channels);
+    let mut campaigns = active.values().cloned().collect::<Vec<_>>();
+
+    let mock_client = MockClient::init(vec![active], vec![], None).await;
+    let cache = Cache::initialize(mock_client).await;
+
+    let variables = Variables::new(
+        DayTime::new(input_base.global.seconds_since_epoch, 0).expect("Valid offset"),
+        &[],
+    );
+    let targeting = Targeting {
+        config: &DEVELOPMENT,
+        logger: &logger,
+        input_base: &input_base,
+        ad_slot_response: &ad_slot_response,
+        min_score: 0.75,
+        no_targeting: false,
+        slot_override: &SlotOverride::default(),
+        variables: &variables,
+        slot_rules_complexity: RulesComplexity::of(&ad_slot_response.slot.rules),
+    };
+    let fresh = |campaigns: Vec<Campaign>| {
+        apply_targeting(
+            &DEVELOPMENT,
+            &logger,
+            campaigns,
+            input_base.clone(),
+            ad_slot_response.clone(),
+            0.75,
+            false,
+        )
+    };
+
+    let expected = targeted_to_json(&fresh(campaigns.clone()).await);
+    assert_eq!(2, expected.len(), "The 0.5 score should be dropped");
+
+    let memoized =
+        apply_targeting_memoized(&cache, cache.generation(), &targeting, campaigns.clone()).await;
+    assert_eq!(expected, targeted_to_json(&memoized));
+    // the unmatched Campaign is memoized as well
+    assert_eq!(campaigns.len(), cache.targeting_memo.read().await.len());
+
+    let memoized_again =
+        apply_targeting_memoized(&cache, cache.generation(), &targeting, campaigns.clone()).await;
+    assert_eq!(expected, targeted_to_json(&memoized_again));
+
+    // the same Campaign with different rules still yields the memoized result,
+    // until the Active Campaigns change
+    for campaign in campaigns.iter_mut() {
+        campaign.channel.spec.targeting_rules = scoring_rules(&[("IAB3", 10.0)]);
+    }
+    let stale =
+        apply_targeting_memoized(&cache, cache.generation(), &targeting, campaigns.clone()).await;
+    assert_eq!(expected, targeted_to_json(&stale));
+
+    // discovering the same Campaigns again doesn't change them
+    let generation = cache.generation();
+    cache.fetch_new_campaigns().await;
+    assert_eq!(generation, cache.generation());
+    let stale =
+        apply_targeting_memoized(&cache, cache.generation(), &targeting, campaigns.clone()).await;
+    assert_eq!(expected, targeted_to_json(&stale));
+
+    let waiting = vec![(
+        campaigns[0].channel.id,
+        (Status::Waiting, campaigns[0].balances.clone()),
+    )]
+    .into_iter()
+    .collect();
+    cache
+        .apply_campaign_updates((waiting, Default::default()))
+        .await;
+    assert!(cache.generation() > generation);
+
+    let refreshed_expected = targeted_to_json(&fresh(campaigns.clone()).await);
+    assert_eq!(3, refreshed_expected.len());
+    let refreshed =
+        apply_targeting_memoized(&cache, cache.generation(), &targeting, campaigns).await;
+    assert_eq!(refreshed_expected, targeted_to_json(&refreshed));
+}
+
+/// Builds the same units-for-slot request for `GET` or, with a JSON `body`, for `POST`
+fn units_for_slot_request(
+    ad_slot_ipfs: &str,
+    query: &str,
+    body: Option<serde_json::Value>,
+) -> Request<Body> {
+    let uri = format!("/units-for-slot/{}?{}", ad_slot_ipfs, query);
+    let builder = match &body {
+        Some(_) => Request::post(uri).header(CONTENT_TYPE, "application/json"),
+        None => Request::get(uri),
+    };
+
+    builder
+        .header(USER_AGENT, TEST_USER_AGENT)
+        .header(CLOUDFLARE_IPCOUNTY_HEADER.clone(), TEST_CLOUDFLARE_IPCOUNTY)
+        .body(
+            body.map(|json| Body::from(json.to_string()))
+                .unwrap_or_else(Body::empty),
+        )
+        .unwrap()
+}
+
+#[tokio::test]
+async fn get_and_post_share_the_same_pipeline() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}&debug", channel.deposit_asset);
+
+    let call = |request: Request<Body>| {
+        let setup = &setup;
+
+        async move { setup.units_for_slot_at(request, now).await }
+    };
+    let paged = |response: Response<Body>| async move {
+        assert_eq!(http::StatusCode::OK, response.status());
+
+        serde_json::from_slice::<PagedResponse>(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize")
+    };
+
+    let get = paged(call(units_for_slot_request(&mock_slot.slot.ipfs, &query, None)).await).await;
+    let post = paged(
+        call(units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &query,
+            Some(serde_json::json!({})),
+        ))
+        .await,
+    )
+    .await;
+
+    assert!(!get.units.is_empty());
+    // an empty input doesn't override anything
+    assert_eq!(
+        serde_json::to_value(&get).expect("Should serialize"),
+        serde_json::to_value(&post).expect("Should serialize")
+    );
+
+    // the input overrides the headers and query values
+    let overridden = paged(
+        call(units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &query,
+            Some(serde_json::json!({
+                "country": "US",
+                "userAgentOs": "Android",
+                "acceptedAssets": ["0x0000000000000000000000000000000000000000"],
+                "segments": ["sports"],
+            })),
+        ))
+        .await,
+    )
+    .await;
+
+    let global = &overridden.response.targeting_input_base.global;
+    assert_eq!(Some("US".to_string()), global.country);
+    assert_eq!(Some("Android".to_string()), global.user_agent_os);
+    assert_eq!(
+        Some("Firefox".to_string()),
+        global.user_agent_browser_family
+    );
+    // no Campaign with the accepted asset
+    assert_eq!(0, overridden.total_matched);
+}
+
+#[tokio::test]
+async fn multiple_types_return_the_units_per_type() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let mut channel = mock_channel(&rules);
+    let mut leaderboard = channel.spec.ad_units[0].clone();
+    leaderboard.ipfs = "QmLeaderboardUnit".to_string();
+    leaderboard.ad_type = "legacy_728x90".to_string();
+    channel.spec.ad_units.push(leaderboard.clone());
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}", channel.deposit_asset);
+
+    let call = |request: Request<Body>| {
+        let setup = &setup;
+
+        async move {
+            let response = setup.units_for_slot_at(request, now).await;
+            assert_eq!(http::StatusCode::OK, response.status());
+
+            hyper::body::to_bytes(response).await.unwrap()
+        }
+    };
+    let unit_ids = |paged: &PagedResponse| {
+        paged
+            .units
+            .iter()
+            .map(|matched| matched.unit.unit.id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // a single type keeps the flat response
+    let one = serde_json::from_slice::<PagedResponse>(
+        &call(units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("{}&type=legacy_728x90", query),
+            None,
+        ))
+        .await,
+    )
+    .expect("Should deserialize");
+    assert_eq!(
+        "legacy_728x90",
+        one.response.targeting_input_base.global.ad_slot_type
+    );
+    assert_eq!(vec![leaderboard.ipfs.clone()], unit_ids(&one));
+
+    let two = serde_json::from_slice::<PerTypeResponse<PagedResponse>>(
+        &call(units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("{}&type=legacy_250x250&type=legacy_728x90", query),
+            None,
+        ))
+        .await,
+    )
+    .expect("Should deserialize");
+    assert_eq!(
+        vec!["legacy_250x250", "legacy_728x90"],
+        two.types.keys().map(String::as_str).collect::<Vec<_>>()
+    );
+    let slot_type = &two.types["legacy_250x250"];
+    assert!(!slot_type.units.is_empty());
+    assert!(!unit_ids(slot_type).contains(&leaderboard.ipfs));
+    assert_eq!(
+        "legacy_250x250",
+        slot_type.response.targeting_input_base.global.ad_slot_type
+    );
+    assert_eq!(
+        vec![leaderboard.ipfs.clone()],
+        unit_ids(&two.types["legacy_728x90"])
+    );
+
+    // the body types override the query and an unknown type has no units
+    let with_unknown = serde_json::from_slice::<PerTypeResponse<PagedResponse>>(
+        &call(units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("{}&type=legacy_300x250", query),
+            Some(serde_json::json!({
+                "types": ["legacy_728x90", "unknown_1x1"],
+            })),
+        ))
+        .await,
+    )
+    .expect("Should deserialize");
+    assert_eq!(
+        vec!["legacy_728x90", "unknown_1x1"],
+        with_unknown
+            .types
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![leaderboard.ipfs],
+        unit_ids(&with_unknown.types["legacy_728x90"])
+    );
+    let unknown = &with_unknown.types["unknown_1x1"];
+    assert_eq!(0, unknown.total_matched);
+    assert!(unknown.units.is_empty());
+}
+
+#[tokio::test]
+async fn the_selection_strategy_orders_the_units() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    // 3 Campaigns with different prices and AdUnits
+    let channels: Vec<Channel> = (1..=3_u8)
+        .map(|i| {
+            let mut channel = mock_channel(&rules);
+            channel.id = ChannelId::from([i; 32]);
+            channel.spec.min_per_impression = (u64::from(i) * 100_000_000_000_000).into();
+            for ad_unit in channel.spec.ad_units.iter_mut() {
+                ad_unit.ipfs = format!("{}{}", ad_unit.ipfs, i);
+            }
+
+            channel
+        })
+        .collect();
+
+    let mut setup = Setup::new(mock_multiple_cache_campaigns(channels.clone())).await;
+    setup.config.selection.default = Strategy::HighestPrice;
+    setup.config.selection.allowed = vec![Strategy::RoundRobin];
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let call = |query: String| {
+        let setup = &setup;
+        let request = units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("depositAsset={}&{}", channels[0].deposit_asset, query),
+            None,
+        );
+
+        async move { setup.units_for_slot_at(request, now).await }
+    };
+    let paged = |response: Response<Body>| async move {
+        assert_eq!(http::StatusCode::OK, response.status());
+
+        serde_json::from_slice::<PagedResponse>(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize")
+    };
+    let unit_ids = |paged: &PagedResponse| {
+        paged
+            .units
+            .iter()
+            .map(|matched| matched.unit.unit.id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // the default strategy
+    let highest_price = paged(call(String::new()).await).await;
+    assert_eq!(Strategy::HighestPrice, highest_price.strategy);
+    assert!(highest_price
+        .units
+        .windows(2)
+        .all(|pair| pair[0].unit.price >= pair[1].unit.price));
+    let ranked = unit_ids(&highest_price);
+    assert_eq!(
+        ranked,
+        unit_ids(&paged(call("strategy=highest_price".to_string()).await).await),
+        "The default strategy is always allowed"
+    );
+
+    // every request of the AdSlot starts with the next unit
+    for offset in 0..=ranked.len() {
+        let round_robin = paged(call("strategy=round_robin".to_string()).await).await;
+        assert_eq!(Strategy::RoundRobin, round_robin.strategy);
+
+        let mut expected = ranked.clone();
+        expected.rotate_left(offset % ranked.len());
+        assert_eq!(expected, unit_ids(&round_robin), "offset {}", offset);
+    }
+
+    // neither a strategy which isn't allowed nor an unknown one
+    for query in &["strategy=best_score", "strategy=random"] {
+        let refused = call(query.to_string()).await;
+        assert_eq!(http::StatusCode::BAD_REQUEST, refused.status(), "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn every_type_of_a_request_is_matched_from_the_same_generation() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let mut channel = mock_channel(&rules);
+    let mut leaderboard = channel.spec.ad_units[0].clone();
+    leaderboard.ipfs = "QmLeaderboardUnit".to_string();
+    leaderboard.ad_type = "legacy_728x90".to_string();
+    channel.spec.ad_units.push(leaderboard);
+
+    let active = mock_cache_campaign(channel.clone(), Status::Active);
+    let balances = active[&channel.id].balances.clone();
+
+    let setup = Setup::new(active).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!(
+        "depositAsset={}&debug=true&type=legacy_250x250&type=legacy_728x90",
+        channel.deposit_asset
+    );
+
+    // the Campaign is paused and resumed with every update, each of them is a new generation
+    let updates = {
+        let cache = setup.cache.clone();
+        async move {
+            for update in 0..20 {
+                let status = if update % 2 == 0 {
+                    Status::Waiting
+                } else {
+                    Status::Active
+                };
+                let updates = vec![(channel.id, (status, balances.clone()))]
+                    .into_iter()
+                    .collect();
+                cache
+                    .apply_campaign_updates((updates, Default::default()))
+                    .await;
+                tokio::time::delay_for(Duration::from_millis(1)).await;
+            }
+        }
+    };
+    let requests = async {
+        let mut generations = vec![];
+        for _ in 0..20 {
+            let response = setup
+                .units_for_slot_at(
+                    units_for_slot_request(&mock_slot.slot.ipfs, &query, None),
+                    now,
+                )
+                .await;
+            assert_eq!(http::StatusCode::OK, response.status());
+
+            let per_type = serde_json::from_slice::<PerTypeResponse<PagedResponse>>(
+                &hyper::body::to_bytes(response).await.unwrap(),
+            )
+            .expect("Should deserialize");
+            let types = per_type.types.values().collect::<Vec<_>>();
+            assert_eq!(2, types.len());
+
+            let generation = types[0].generation.expect("Shown with debug");
+            assert!(
+                types
+                    .iter()
+                    .all(|paged| paged.generation == Some(generation)),
+                "Every type should be matched from a single generation"
+            );
+            // the Campaign is either Active for all of the types or for none of them
+            assert!(
+                types.iter().all(|paged| paged.units.is_empty())
+                    || types.iter().all(|paged| !paged.units.is_empty()),
+                "The types were matched from different statuses of the Campaign"
+            );
+            generations.push(generation);
+        }
+
+        generations
+    };
+    let ((), generations) = futures::future::join(updates, requests).await;
+
+    assert!(
+        generations.windows(2).all(|pair| pair[0] <= pair[1]),
+        "The generations should never go back: {:?}",
+        generations
+    );
+}
+
+#[tokio::test]
+async fn archived_slots_are_gone_until_unarchived() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let clock = MockClock::new();
+    let mock_client = MockClient::init(
+        vec![mock_cache_campaign(channel.clone(), Status::Active)],
+        vec![],
+        None,
+    )
+    .await;
+    let mock_cache = Cache::builder(mock_client)
+        .clock(Arc::new(clock.clone()))
+        .initialize()
+        .await;
+
+    let mut archived_slot = get_supermarket_ad_slot(&rules, &categories);
+    archived_slot.slot.archived = true;
+    let mut unarchived_slot = archived_slot.clone();
+    unarchived_slot.slot.archived = false;
+
+    // each Market is expected to be called once
+    let mut archived = Setup::with_cache(mock_cache.clone()).await;
+    archived.config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+    archived.mount_expected_slot(&archived_slot, 1).await;
+    let mut unarchived = Setup::with_cache(mock_cache).await;
+    unarchived.config = archived.config.clone();
+    unarchived.mount_expected_slot(&unarchived_slot, 1).await;
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let request = || units_for_slot_request(&archived_slot.slot.ipfs, &query, None);
+
+    for _ in 0..2 {
+        let response = archived.units_for_slot_at(request(), now).await;
+        assert_eq!(http::StatusCode::GONE, response.status());
+    }
+
+    // the archived state is cached until the AdSlot expires
+    clock.advance(Duration::from_secs(30));
+    let response = unarchived.units_for_slot_at(request(), now).await;
+    assert_eq!(http::StatusCode::GONE, response.status());
+
+    clock.advance(Duration::from_secs(30));
+    let response = unarchived.units_for_slot_at(request(), now).await;
+    assert_eq!(http::StatusCode::OK, response.status());
+}
+
+#[tokio::test]
+async fn expired_slots_are_revalidated_with_the_market() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let clock = MockClock::new();
+    let mock_client = MockClient::init(
+        vec![mock_cache_campaign(channel.clone(), Status::Active)],
+        vec![],
+        None,
+    )
+    .await;
+    let mock_cache = Cache::builder(mock_client)
+        .clock(Arc::new(clock.clone()))
+        .initialize()
+        .await;
+
+    let mut setup = Setup::with_cache(mock_cache).await;
+    setup.config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    // the AdUnits are fetched only with the modified AdSlot
+    Mock::given(method("GET"))
+        .and(path("/market/units"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![])))
+        .expect(1)
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/market/slots/{}", mock_slot.slot.ipfs)))
+        .and(header("if-none-match", "\"v1\""))
+        .respond_with(ResponseTemplate::new(304))
+        .expect(2)
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/market/slots/{}", mock_slot.slot.ipfs)))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"v1\"")
+                .set_body_json(&mock_slot),
+        )
+        .expect(1)
+        .mount(&setup.server)
+        .await;
+
+    let not_modified = || {
+        SLOT_REVALIDATIONS
+            .with_label_values(&["not_modified"])
+            .get()
+    };
+    let not_modified_before = not_modified();
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    // fetched, cached, revalidated after it expires and again after the extended TTL
+    for advance in &[0, 30, 31, 61] {
+        clock.advance(Duration::from_secs(*advance));
+
+        let response = setup
+            .units_for_slot_at(
+                units_for_slot_request(&mock_slot.slot.ipfs, &query, None),
+                now,
+            )
+            .await;
+        assert_eq!(http::StatusCode::OK, response.status());
+
+        let paged = serde_json::from_slice::<PagedResponse>(
+            &hyper::body::to_bytes(response).await.unwrap(),
+        )
+        .expect("Should deserialize");
+        assert!(!paged.units.is_empty());
+    }
+    assert!(not_modified() >= not_modified_before + 2);
+}
+
+#[tokio::test]
+async fn only_the_existing_slots_are_counted_for_the_popularity() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    setup.config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+    setup.config.prewarm.top_slots = 10;
+    setup.config.prewarm.refresh_margin = Duration::from_secs(10);
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channel))
+        .await;
+
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    // the Market responds with `404` to the rest of the AdSlots
+    for ipfs in &[
+        mock_slot.slot.ipfs.as_str(),
+        "QmNotInTheMarket",
+        "QmRandomPath",
+    ] {
+        for _ in 0..2 {
+            setup
+                .units_for_slot(units_for_slot_request(ipfs, &query, None))
+                .await;
+        }
+    }
+
+    assert_eq!(
+        vec![mock_slot.slot.ipfs.clone()],
+        setup.cache.slot_popularity.top(10)
+    );
+    assert_eq!(
+        Some(2.0),
+        setup.cache.slot_popularity.requests(&mock_slot.slot.ipfs)
+    );
+}
+
+#[tokio::test]
+async fn proxied_slots_warm_the_units_for_slot() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    setup.config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    let ipfs = &mock_slot.slot.ipfs;
+    // only the proxied request fetches the AdSlot
+    setup.mount_expected_slot(&mock_slot, 1).await;
+    let proxy = Proxy::new(
+        (setup.server.uri() + "/market/")
+            .parse()
+            .expect("Wrong Market url"),
+        &DEVELOPMENT,
+        setup.logger.clone(),
+    );
+
+    let request = Request::get(format!("/slots/{}", ipfs))
+        .body(Body::empty())
+        .expect("Should build Request");
+    let upstream = crate::network::Upstream {
+        name: crate::network::DEFAULT_NETWORK.to_string(),
+        config: setup.config.clone(),
+        cache: setup.cache.clone(),
+        proxy: Some(proxy),
+        market: setup.market.clone(),
+        logger: setup.logger.clone(),
+    };
+    let proxied = crate::handle(request, crate::Listener::All, upstream)
+        .await
+        .expect("Should proxy the request");
+    assert_eq!(http::StatusCode::OK, proxied.status());
+
+    // passed through as it is
+    let proxied_slot =
+        serde_json::from_slice::<AdSlotResponse>(&hyper::body::to_bytes(proxied).await.unwrap())
+            .expect("Should deserialize");
+    assert_eq!(ipfs, &proxied_slot.slot.ipfs);
+
+    let cached = setup
+        .cache
+        .slots
+        .read()
+        .await
+        .latest(ipfs)
+        .expect("Should be cached");
+    assert!(cached.units.is_none());
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let response = setup
+        .units_for_slot_at(units_for_slot_request(ipfs, &query, None), now)
+        .await;
+    assert_eq!(http::StatusCode::OK, response.status());
+
+    let paged =
+        serde_json::from_slice::<PagedResponse>(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize");
+    assert!(!paged.units.is_empty());
+
+    // cached along with its AdUnits, but still from the time of the proxied request
+    let cached_with_units = setup
+        .cache
+        .slots
+        .read()
+        .await
+        .latest(ipfs)
+        .expect("Should be cached");
+    assert!(cached_with_units.units.is_some());
+    assert_eq!(cached.fetched_at, cached_with_units.fetched_at);
+}
+
+#[tokio::test]
+async fn archived_units_are_not_matched() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let mut channel = mock_channel(&rules);
+    let mut archived_unit = channel.spec.ad_units[0].clone();
+    archived_unit.ipfs = "QmArchivedUnit".to_string();
+    archived_unit.ad_type = "legacy_250x250".to_string();
+    archived_unit.archived = true;
+    channel.spec.ad_units.push(archived_unit.clone());
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup.mount_expected_slot(&mock_slot, 1).await;
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}&debug", channel.deposit_asset);
+    let response = setup
+        .units_for_slot_at(
+            units_for_slot_request(&mock_slot.slot.ipfs, &query, None),
+            now,
+        )
+        .await;
+    assert_eq!(http::StatusCode::OK, response.status());
+
+    let paged =
+        serde_json::from_slice::<PagedResponse>(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize");
+
+    assert!(!paged.units.is_empty());
+    assert!(paged
+        .units
+        .iter()
+        .all(|matched| matched.unit.unit.id != archived_unit.ipfs));
+    assert_eq!(Some(1), paged.archived_units);
+}
+
+#[tokio::test]
+async fn units_are_served_before_the_cache_is_initialized() {
+    use crate::cache::init::CollectedPage;
+    use tokio::sync::Semaphore;
+
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let fast_channel = mock_channel(&rules);
+    let mut slow_channel = mock_channel(&rules);
+    slow_channel.id = ChannelId::from([7; 32]);
+
+    let validator =
+        |url: &str| -> primitives::util::ApiUrl { url.parse().expect("Valid Validator URL") };
+    let (fast, slow) = (
+        validator("http://localhost:8005/"),
+        validator("http://localhost:8006/"),
+    );
+    let pages = vec![
+        CollectedPage::Campaigns(
+            fast.clone(),
+            mock_cache_campaign(fast_channel.clone(), Status::Active),
+        ),
+        CollectedPage::Done(fast.clone()),
+        CollectedPage::Campaigns(
+            slow.clone(),
+            mock_cache_campaign(slow_channel.clone(), Status::Active),
+        ),
+        CollectedPage::Done(slow.clone()),
+    ];
+    // only the pages of the fast Validator arrive
+    let released = Arc::new(Semaphore::new(2));
+    let mock_client = MockClient::init(vec![], vec![], None)
+        .await
+        .with_validators(vec![fast, slow].into_iter().collect())
+        .with_campaign_pages(pages, released.clone());
+
+    let mut config = DEVELOPMENT.clone();
+    config.initialization.ready_fraction = 0.5;
+    let cache = Cache::builder(mock_client).build();
+    assert!(!cache.is_ready(&config).await);
+
+    let initialization = tokio::spawn({
+        let cache = cache.clone();
+        async move {
+            cache
+                .fetch_initial_campaigns(futures::future::pending())
+                .await
+        }
+    });
+    let mut attempts = 0;
+    while !cache.is_ready(&config).await {
+        attempts += 1;
+        assert!(
+            attempts < 100,
+            "The Cache should be ready with half the Validators"
+        );
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    assert!(!cache.is_initialized().await);
+    assert!(
+        !cache
+            .is_ready(&{
+                let mut all_validators = config.clone();
+                all_validators.initialization.ready_fraction = 1.0;
+                all_validators
+            })
+            .await
+    );
+
+    let mut setup = Setup::with_cache(cache.clone()).await;
+    setup.config = config.clone();
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup.mount_expected_slot(&mock_slot, 1).await;
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}", fast_channel.deposit_asset);
+    let response = setup
+        .units_for_slot_at(
+            units_for_slot_request(&mock_slot.slot.ipfs, &query, None),
+            now,
+        )
+        .await;
+    assert_eq!(http::StatusCode::OK, response.status());
+
+    let paged =
+        serde_json::from_slice::<PagedResponse>(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize");
+    assert!(!paged.units.is_empty());
+    assert!(paged
+        .units
+        .iter()
+        .all(|matched| matched.campaign.channel_id == fast_channel.id));
+
+    released.add_permits(2);
+    assert!(initialization.await.expect("Should initialize"));
+    assert!(cache.is_initialized().await);
+    assert!(cache.is_ready(&config).await);
+    assert!(cache.active.read().await.contains_key(&slow_channel.id));
+}
+
+#[tokio::test]
+async fn empty_responses_have_a_reason_and_its_max_age() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    let mut empty = Setup::new(HashMap::new()).await;
+    empty.config.cache_control.max_age = Some(60);
+    empty.mount_expected_slot(&mock_slot, 1).await;
+    let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    setup.config.cache_control.max_age = Some(60);
+    setup.mount_expected_slot(&mock_slot, 2).await;
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let cases = [
+        (
+            &empty,
+            channel.deposit_asset.as_str(),
+            Some(EmptyReason::NoActiveCampaigns),
+            "max-age=5",
+        ),
+        (
+            &setup,
+            "0x000000000000000000000000000000000000000",
+            Some(EmptyReason::NoEligibleCampaigns),
+            "max-age=60",
+        ),
+        (&setup, channel.deposit_asset.as_str(), None, "max-age=60"),
+    ];
+    for (setup, deposit_asset, reason, cache_control) in cases.iter() {
+        let query = format!("depositAsset={}", deposit_asset);
+        let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+        let response = setup.units_for_slot_at(request, now).await;
+        assert_eq!(http::StatusCode::OK, response.status());
+        assert_eq!(
+            Some(*cache_control),
+            response
+                .headers()
+                .get(http::header::CACHE_CONTROL)
+                .and_then(|value| value.to_str().ok())
+        );
+
+        let json = serde_json::from_slice::<serde_json::Value>(
+            &hyper::body::to_bytes(response).await.unwrap(),
+        )
+        .expect("Should deserialize");
+        match reason {
+            Some(reason) => {
+                assert_eq!(serde_json::json!(reason.as_str()), json["reason"]);
+                assert_eq!(0, json["totalMatched"]);
+            }
+            None => assert!(json.get("reason").is_none()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn stale_cache_is_served_by_the_degradation_policy() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let fallback_unit = DUMMY_AD_UNITS[0].clone();
+    let mut mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    mock_slot.slot.fallback_unit = Some(fallback_unit.ipfs.clone());
+
+    let now = Utc.ymd(2020, 12, 1).and_hms(12, 0, 0);
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let max_staleness = Duration::from_secs(60);
+
+    // the policy, the `X-Degraded` header and whether the Campaign is served while the Cache is degraded
+    let cases = [
+        (DegradationPolicy::Strict, None, false),
+        (DegradationPolicy::ServeStale, Some("stale-cache"), true),
+        (
+            DegradationPolicy::FallbackOnly,
+            Some("fallback-only"),
+            false,
+        ),
+    ];
+    for (policy, degraded_header, serves_campaign) in cases.iter() {
+        let clock = MockClock::new();
+        let mock_client = MockClient::init(
+            vec![mock_cache_campaign(channel.clone(), Status::Active)],
+            vec![],
+            None,
+        )
+        .await;
+        let mock_cache = Cache::builder(mock_client)
+            .clock(Arc::new(clock.clone()))
+            .initialize()
+            .await;
+
+        let mut setup = Setup::with_cache(mock_cache).await;
+        setup.config.max_campaign_staleness = Some(max_staleness);
+        setup.config.degradation_policy = *policy;
+        setup.mount_expected_slot(&mock_slot, 2).await;
+        Mock::given(method("GET"))
+            .and(path(format!("/market/units/{}", fallback_unit.ipfs)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&AdUnitResponse {
+                unit: fallback_unit.clone(),
+            }))
+            .mount(&setup.server)
+            .await;
+
+        let call = || {
+            let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+            let setup = &setup;
+
+            async move {
+                let response = setup.units_for_slot_at(request, now).await;
+                assert_eq!(http::StatusCode::OK, response.status());
+
+                let degraded = response
+                    .headers()
+                    .get(DEGRADED_HEADER.clone())
+                    .map(|value| value.to_str().expect("Valid header").to_string());
+                let paged = serde_json::from_slice::<PagedResponse>(
+                    &hyper::body::to_bytes(response).await.unwrap(),
+                )
+                .expect("Should deserialize");
+
+                (degraded, paged)
+            }
+        };
+
+        // every policy serves the fresh Campaigns as usual
+        let (degraded, paged) = call().await;
+        assert_eq!(None, degraded, "{:?}", policy);
+        assert_eq!(1, paged.response.campaigns.len(), "{:?}", policy);
+        assert!(!setup.cache.is_degraded(&setup.config).await);
+
+        clock.advance(max_staleness + Duration::from_secs(1));
+        assert!(setup.cache.is_degraded(&setup.config).await);
+
+        let (degraded, paged) = call().await;
+        assert_eq!(
+            degraded_header.map(ToString::to_string),
+            degraded,
+            "{:?}",
+            policy
+        );
+        assert_eq!(
+            *serves_campaign,
+            !paged.response.campaigns.is_empty(),
+            "{:?}",
+            policy
+        );
+        assert_eq!(
+            Some(fallback_unit.ipfs.clone()),
+            paged.response.fallback_unit.map(|unit| unit.id),
+            "{:?}",
+            policy
+        );
+    }
+}
+
+#[tokio::test]
+async fn slot_fetch_timing_out_is_an_unavailable_market() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let fallback_unit = DUMMY_AD_UNITS[0].clone();
+    let mut mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    mock_slot.slot.fallback_unit = Some(fallback_unit.ipfs.clone());
+
+    let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    setup.config.timeouts.market_fetch_slot = Duration::from_millis(50);
+    setup
+        .mount_slow_slot(
+            &mock_slot,
+            &fallback_unit,
+            Duration::from_millis(500),
+            Duration::from_millis(0),
+        )
+        .await;
+
+    let timeouts = || {
+        MARKET_FETCH_TIMEOUTS
+            .with_label_values(&["fetch_slot"])
+            .get()
+    };
+    let timeouts_before = timeouts();
+
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+    let response = setup.units_for_slot(request).await;
+
+    assert_eq!(http::StatusCode::BAD_GATEWAY, response.status());
+    assert_eq!(
+        setup.config.market.retry_after.as_secs().to_string(),
+        response.headers()[http::header::RETRY_AFTER]
+    );
+    assert!(timeouts() > timeouts_before);
+}
+
+#[tokio::test]
+async fn the_truncated_units_of_the_slot_are_flagged() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    let mut unit = DUMMY_AD_UNITS[0].clone();
+    unit.ad_type = mock_slot.slot.ad_type.clone();
+
+    let response_json = |max_units_per_slot: Option<usize>| {
+        let (channel, mock_slot, unit) = (&channel, &mock_slot, &unit);
+
+        async move {
+            let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+            setup.config.limits.max_units_per_slot = max_units_per_slot;
+            // more AdUnits than the `max_units_per_slot`, regardless of the requested `limit`
+            setup
+                .mount_slot(mock_slot, &AdUnitsResponse(vec![unit.clone(); 3]))
+                .await;
+            let query = format!("depositAsset={}", channel.deposit_asset);
+
+            let response = setup
+                .units_for_slot(units_for_slot_request(&mock_slot.slot.ipfs, &query, None))
+                .await;
+            assert_eq!(http::StatusCode::OK, response.status());
+
+            serde_json::from_slice::<serde_json::Value>(
+                &hyper::body::to_bytes(response.into_body())
+                    .await
+                    .expect("Should read the body"),
+            )
+            .expect("Should deserialize")
+        }
+    };
+
+    assert_eq!(
+        serde_json::json!(true),
+        response_json(Some(2)).await["truncated"]
+    );
+    assert!(response_json(Some(3)).await.get("truncated").is_none());
+    assert!(response_json(None).await.get("truncated").is_none());
+}
+
+#[tokio::test]
+async fn slot_fetch_errors_distinguish_the_missing_slot_from_the_market_errors() {
+    let setup = Setup::new(HashMap::new()).await;
+
+    Mock::given(method("GET"))
+        .and(path("/market/slots/QmMissing"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/market/slots/QmMarketDown"))
+        .respond_with(ResponseTemplate::new(503).set_body_json(serde_json::json!({})))
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/market/slots/QmInvalid"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("{\"slot\":"))
+        .mount(&setup.server)
+        .await;
+
+    let cases = [
+        ("QmMissing", SlotFetchError::NotFound, "slot not found"),
+        (
+            "QmMarketDown",
+            SlotFetchError::MarketUnavailable,
+            "market unavailable",
+        ),
+        (
+            "QmInvalid",
+            SlotFetchError::InvalidResponse,
+            "invalid slot response",
+        ),
+    ];
+    for (ipfs, error, message) in cases.iter() {
+        let errors = || {
+            crate::metrics::SLOT_FETCH_ERRORS
+                .with_label_values(&[error.as_str()])
+                .get()
+        };
+        let errors_before = errors();
+
+        let request = units_for_slot_request(ipfs, "", None);
+        let response = setup.units_for_slot(request).await;
+
+        assert_eq!(error.status(), response.status(), "AdSlot: {}", ipfs);
+        assert_eq!(
+            *error == SlotFetchError::MarketUnavailable,
+            response.headers().contains_key(http::header::RETRY_AFTER),
+            "AdSlot: {}",
+            ipfs
+        );
+        assert!(errors() > errors_before, "AdSlot: {}", ipfs);
+
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("Should read the body");
+        assert_eq!(
+            serde_json::json!({ "error": message }),
+            serde_json::from_slice::<serde_json::Value>(&body).expect("Should be JSON")
+        );
+    }
+}
+
+#[tokio::test]
+async fn units_fetch_timing_out_serves_only_the_fallback_unit() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let fallback_unit = DUMMY_AD_UNITS[0].clone();
+    let mut mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    mock_slot.slot.fallback_unit = Some(fallback_unit.ipfs.clone());
+
+    let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    setup.config.timeouts.market_fetch_units = Duration::from_millis(50);
+    setup.config.prewarm.slot_cache_ttl = Duration::from_secs(60);
+    setup
+        .mount_slow_slot(
+            &mock_slot,
+            &fallback_unit,
+            Duration::from_millis(0),
+            Duration::from_millis(500),
+        )
+        .await;
+
+    let timeouts = || {
+        MARKET_FETCH_TIMEOUTS
+            .with_label_values(&["fetch_units"])
+            .get()
+    };
+    let timeouts_before = timeouts();
+
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+    let response = setup.units_for_slot(request).await;
+
+    assert_eq!(http::StatusCode::OK, response.status());
+    assert_eq!(
+        Some(UNITS_TIMEOUT_DEGRADED),
+        response
+            .headers()
+            .get(DEGRADED_HEADER.clone())
+            .map(|value| value.to_str().expect("Valid header"))
+    );
+    let paged =
+        serde_json::from_slice::<PagedResponse>(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize");
+
+    assert!(paged.response.campaigns.is_empty());
+    assert_eq!(0, paged.total_matched);
+    assert_eq!(
+        Some(fallback_unit.ipfs.clone()),
+        paged.response.fallback_unit.map(|unit| unit.id)
+    );
+    assert!(timeouts() > timeouts_before);
+    // counted for the owner of the AdSlot
+    let requests = setup
+        .cache
+        .publisher_stats
+        .read()
+        .await
+        .get(mock_slot.slot.owner, setup.cache.clock().now_utc());
+    assert_eq!(1, requests.total.requests);
+    assert_eq!(1, requests.total.fallbacks);
+    // the AdSlot without its AdUnits is not cached
+    assert!(matches!(
+        prewarm::cached_slot(&setup.cache, &setup.config, &mock_slot.slot.ipfs).await,
+        prewarm::SlotLookup::Missing
+    ));
+}
+
+#[tokio::test]
+async fn post_validates_the_body() {
+    let setup = Setup::new(HashMap::new()).await;
+    let ad_slot_ipfs = "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C";
+
+    let cases = vec![
+        (
+            Some("text/plain"),
+            "{}".to_string(),
+            http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            None,
+        ),
+        (
+            None,
+            "{}".to_string(),
+            http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            None,
+        ),
+        (
+            Some("application/json"),
+            "{\n  \"country\": 42\n}".to_string(),
+            http::StatusCode::BAD_REQUEST,
+            Some("line 2 column"),
+        ),
+        (
+            Some("application/json; charset=utf-8"),
+            "{\"country\": ".to_string(),
+            http::StatusCode::BAD_REQUEST,
+            Some("line 1 column"),
+        ),
+        // not an address
+        (
+            Some("application/json"),
+            "{\"acceptedAssets\": [\"DAI\"]}".to_string(),
+            http::StatusCode::BAD_REQUEST,
+            Some("line 1 column"),
+        ),
+        (
+            Some("application/json"),
+            format!("{{\"segments\": [\"{}\"]}}", "a".repeat(MAX_BODY_SIZE)),
+            http::StatusCode::PAYLOAD_TOO_LARGE,
+            None,
+        ),
+    ];
+
+    for (content_type, body, expected_status, expected_message) in cases {
+        let mut request = Request::post(format!("/units-for-slot/{}", ad_slot_ipfs));
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        let request = request.body(Body::from(body)).unwrap();
+
+        let actual_response = setup.units_for_slot(request).await;
+
+        assert_eq!(expected_status, actual_response.status());
+
+        if let Some(expected_message) = expected_message {
+            let body = hyper::body::to_bytes(actual_response).await.unwrap();
+            let message = String::from_utf8_lossy(&body);
+
+            assert!(message.contains(expected_message), "{}", message);
+        }
+    }
+}
+
+#[tokio::test]
+async fn personal_inputs_are_used_only_with_consent() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    // the Channel creator, who is not allowed to earn from its own Campaign
+    let body = serde_json::json!({ "publisherId": channel.creator, "segments": ["sports"] });
+    let tcf_v2 = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAAAAAAAA";
+
+    // (query, DNT header, personalized)
+    let cases = vec![
+        (format!("gdpr_consent={}", tcf_v2), None, true),
+        // consent missing
+        (String::new(), None, false),
+        // consent invalid
+        ("gdpr_consent=not-a-tcf-string!".to_string(), None, false),
+        // DNT takes precedence over the consent
+        (format!("gdpr_consent={}", tcf_v2), Some("1"), false),
+    ];
+
+    for (query, dnt, expected_personalized) in cases {
+        let mut request = units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("depositAsset={}&{}", channel.deposit_asset, query),
+            Some(body.clone()),
+        );
+        if let Some(dnt) = dnt {
+            request
+                .headers_mut()
+                .insert("DNT", http::HeaderValue::from_static(dnt));
+        }
+
+        let actual_response = setup.units_for_slot(request).await;
+
+        assert_eq!(http::StatusCode::OK, actual_response.status());
+
+        let paged: PagedResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+                .expect("Should deserialize");
+
+        assert_eq!(expected_personalized, paged.personalized, "{}", query);
+
+        let publisher_id = paged.response.targeting_input_base.global.publisher_id;
+        if expected_personalized {
+            // the personal publisher address is used, so the creator's Campaign is excluded
+            assert_eq!(channel.creator, publisher_id);
+            assert_eq!(0, paged.total_matched);
+        } else {
+            assert_eq!(mock_slot.slot.owner, publisher_id);
+            assert!(paged.total_matched > 0);
+        }
+    }
+}
+
+#[tokio::test]
+async fn the_rules_are_evaluated_with_the_viewers_segments() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules: Vec<Rule> = serde_json::from_value(serde_json::json!([
+        { "onlyShowIf": { "intersects": [{ "get": "segments" }, ["sports", "football"]] } },
+    ]))
+    .expect("Should deserialize the rules");
+    let channel = mock_channel(&rules);
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    let mock_slot = get_supermarket_ad_slot(&[], &categories);
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channel))
+        .await;
+
+    let tcf_v2 = "COvFyGBOvFyGBAbAAAENAPCAAOAAAAAAAAAAAAAAAAAA";
+    // (segments, consent, matched)
+    let cases = [
+        (vec!["news", "football"], Some(tcf_v2), true),
+        (vec!["news", "cooking"], Some(tcf_v2), false),
+        // the segments aren't used without the consent
+        (vec!["news", "football"], None, false),
+    ];
+    for (segments, consent, matched) in cases.iter() {
+        let query = match consent {
+            Some(consent) => format!(
+                "depositAsset={}&gdpr_consent={}",
+                channel.deposit_asset, consent
+            ),
+            None => format!("depositAsset={}", channel.deposit_asset),
+        };
+        let request = units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &query,
+            Some(serde_json::json!({ "segments": segments })),
+        );
+
+        let actual_response = setup.units_for_slot(request).await;
+        assert_eq!(http::StatusCode::OK, actual_response.status());
+        let paged: PagedResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(actual_response).await.unwrap())
+                .expect("Should deserialize");
+
+        assert_eq!(
+            *matched,
+            !paged.units.is_empty(),
+            "{:?}",
+            (segments, consent)
+        );
+    }
+}
+
+#[tokio::test]
+async fn suspected_bots_are_handled_by_the_policy() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+    let denied_ip = "66.249.64.10";
+    setup.config.bots.deny_list = CidrSet::new(vec!["66.249.64.0/19".parse().expect("Valid CIDR")]);
+
+    // (policy, User-Agent, client IP, expected status, expected `suspectBot`)
+    let cases = vec![
+        (BotPolicy::Serve, Some(googlebot), None, 200, false),
+        (BotPolicy::Flag, None, None, 200, false),
+        (BotPolicy::Flag, Some(googlebot), None, 200, true),
+        (BotPolicy::Flag, None, Some(denied_ip), 200, true),
+        (BotPolicy::Block, None, None, 200, false),
+        (BotPolicy::Block, Some(googlebot), None, 204, false),
+        (BotPolicy::Block, None, Some(denied_ip), 204, false),
+    ];
+
+    for (policy, user_agent, client_ip, expected_status, expected_suspect_bot) in cases {
+        setup.config.bots.policy = policy;
+
+        let mut request = units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("depositAsset={}", channel.deposit_asset),
+            None,
+        );
+        if let Some(user_agent) = user_agent {
+            request
+                .headers_mut()
+                .insert(USER_AGENT, http::HeaderValue::from_static(user_agent));
+        }
+        if let Some(client_ip) = client_ip {
+            request.headers_mut().insert(
+                "CF-Connecting-IP",
+                http::HeaderValue::from_static(client_ip),
+            );
+        }
+
+        let actual_response = setup.units_for_slot(request).await;
+
+        let case = format!("{:?} {:?} {:?}", policy, user_agent, client_ip);
+        assert_eq!(
+            expected_status,
+            actual_response.status().as_u16(),
+            "{}",
+            case
+        );
+
+        let body = hyper::body::to_bytes(actual_response).await.unwrap();
+        if expected_status == 204 {
+            assert!(body.is_empty(), "{}", case);
+            continue;
+        }
+
+        let json: serde_json::Value = serde_json::from_slice(&body).expect("Should deserialize");
+        // `suspectBot` is only present when set
+        assert_eq!(
+            expected_suspect_bot,
+            json.get("suspectBot").is_some(),
+            "{}",
+            case
+        );
+        if expected_suspect_bot {
+            assert_eq!(serde_json::Value::Bool(true), json["suspectBot"]);
+        }
+        // suspected bots are still served with the `flag` policy
+        assert!(
+            json["totalMatched"].as_u64().expect("Total matched") > 0,
+            "{}",
+            case
+        );
+    }
+}
+
+#[tokio::test]
+async fn mismatching_referrers_are_flagged_or_refused() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    // the website of the AdSlot is `https://adex.network`
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    // (strict, require, Referer, expected status, expected `referrerMismatch`)
+    let cases = vec![
+        (false, false, Some("https://www.adex.network/"), 200, false),
+        (false, false, Some("https://example.com/"), 200, true),
+        (false, false, None, 200, false),
+        (false, true, None, 200, true),
+        (
+            true,
+            false,
+            Some("https://blog.adex.network:8080/"),
+            200,
+            false,
+        ),
+        (true, false, Some("https://example.com/"), 204, false),
+        (true, false, None, 200, false),
+        (true, true, None, 204, false),
+    ];
+
+    for (strict, require, referer, expected_status, expected_mismatch) in cases {
+        setup.config.strict_referrer_check = strict;
+        setup.config.require_referrer = require;
+
+        let mut request = units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("depositAsset={}", channel.deposit_asset),
+            None,
+        );
+        if let Some(referer) = referer {
+            request.headers_mut().insert(
+                http::header::REFERER,
+                http::HeaderValue::from_static(referer),
+            );
+        }
+
+        let actual_response = setup.units_for_slot(request).await;
+
+        let case = format!("strict: {}, require: {}, {:?}", strict, require, referer);
+        assert_eq!(
+            expected_status,
+            actual_response.status().as_u16(),
+            "{}",
+            case
+        );
+
+        let body = hyper::body::to_bytes(actual_response).await.unwrap();
+        if expected_status == 204 {
+            assert!(body.is_empty(), "{}", case);
+            continue;
+        }
+
+        let json: serde_json::Value = serde_json::from_slice(&body).expect("Should deserialize");
+        // `referrerMismatch` is only present when set
+        assert_eq!(
+            expected_mismatch,
+            json.get("referrerMismatch").is_some(),
+            "{}",
+            case
+        );
+    }
+}
+
+#[tokio::test]
+async fn slow_requests_are_logged_with_the_slowest_phase() {
+    use crate::util::{test::MemoryDrain, REQUEST_ID_HEADER};
+    use std::time::Duration;
+
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    let fallback_unit = DUMMY_AD_UNITS[0].clone();
+
+    // (AdSlot delay, AdUnits delay, expected slowest phase)
+    let cases = vec![
+        (
+            Duration::from_millis(400),
+            Duration::from_millis(0),
+            Some("fetch_slot"),
+        ),
+        (
+            Duration::from_millis(0),
+            Duration::from_millis(400),
+            Some("fetch_units"),
+        ),
+        (Duration::from_millis(0), Duration::from_millis(0), None),
+    ];
+
+    for (slot_delay, units_delay, expected_phase) in cases {
+        let drain = MemoryDrain::default();
+        let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+        setup.logger = drain.logger();
+        setup.config.slow_request_threshold = Duration::from_millis(200);
+        setup
+            .mount_slow_slot(&mock_slot, &fallback_unit, slot_delay, units_delay)
+            .await;
+
+        let mut request = units_for_slot_request(
+            &mock_slot.slot.ipfs,
+            &format!("depositAsset={}", channel.deposit_asset),
+            None,
+        );
+        request.headers_mut().insert(
+            REQUEST_ID_HEADER,
+            http::HeaderValue::from_static("slow-request-id"),
+        );
+
+        let actual_response = setup.units_for_slot(request).await;
+        assert_eq!(http::StatusCode::OK, actual_response.status());
+
+        let slow_requests = drain
+            .records()
+            .into_iter()
+            .filter(|(message, _)| message == "Slow units-for-slot request")
+            .collect::<Vec<_>>();
+
+        match expected_phase {
+            Some(expected_phase) => {
+                assert_eq!(1, slow_requests.len(), "{}", expected_phase);
+                let key_values = &slow_requests[0].1;
+
+                assert_eq!("slow-request-id", key_values["request_id"]);
+                assert_eq!(expected_phase, key_values["slowest_phase"]);
+                for phase in &[
+                    "fetch_slot_ms",
+                    "fetch_units_ms",
+                    "targeting_ms",
+                    "serialization_ms",
+                ] {
+                    assert!(key_values.contains_key(*phase), "{}", phase);
+                }
+            }
+            None => assert!(slow_requests.is_empty()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn identical_concurrent_requests_are_coalesced() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    // the first fetch of the AdSlot fails
+    Mock::given(method("GET"))
+        .and(path(format!("/market/slots/{}", mock_slot.slot.ipfs)))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1_u64)
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/market/slots/{}", mock_slot.slot.ipfs)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&mock_slot))
+        .expect(1_u64)
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/market/units"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![])))
+        .expect(1_u64)
+        .mount(&setup.server)
+        .await;
+
+    let concurrent_requests = |count: usize| {
+        futures::future::join_all((0..count).map(|_| {
+            let request = Request::get(format!(
+                "/units-for-slot/{}?depositAsset={}",
+                mock_slot.slot.ipfs, channel.deposit_asset
+            ))
+            .header(USER_AGENT, TEST_USER_AGENT)
+            .body(Body::empty())
+            .unwrap();
+
+            get_units_for_slot_coalesced(
+                setup.logger.clone(),
+                setup.market.clone(),
+                setup.config.clone(),
+                setup.cache.clone(),
+                request,
+            )
+        }))
+    };
+
+    // the failure is shared only with the concurrent requests
+    let failed = concurrent_requests(10).await;
+    assert!(failed.iter().all(|response| {
+        response.as_ref().map(|response| response.status()).ok()
+            == Some(StatusCode::SERVICE_UNAVAILABLE)
+    }));
+
+    let responses = concurrent_requests(100).await;
+    let mut bodies = vec![];
+    for response in responses {
+        let response = response.expect("Should handle the request");
+        assert_eq!(StatusCode::OK, response.status());
+
+        bodies.push(hyper::body::to_bytes(response).await.unwrap());
+    }
+    assert!(bodies.iter().all(|body| body == &bodies[0]));
+    let paged: PagedResponse = serde_json::from_slice(&bodies[0]).expect("Should deserialize");
+    assert_eq!(1, paged.response.campaigns.len());
+}
+
+#[tokio::test]
+async fn requests_of_different_clients_are_coalesced() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    Mock::given(method("GET"))
+        .and(path(format!("/market/slots/{}", mock_slot.slot.ipfs)))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(&mock_slot)
+                .set_delay(Duration::from_millis(100)),
+        )
+        .expect(1_u64)
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/market/units"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![])))
+        .expect(1_u64)
+        .mount(&setup.server)
+        .await;
+
+    // the same country & browser, but a different IP
+    let responses = futures::future::join_all(["1.1.1.1", "2.2.2.2"].iter().map(|ip| {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?depositAsset={}",
+            mock_slot.slot.ipfs, channel.deposit_asset
+        ))
+        .header(USER_AGENT, TEST_USER_AGENT)
+        .header("cf-ipcountry", "BG")
+        .header("cf-connecting-ip", *ip)
+        .header("x-forwarded-for", *ip)
+        .body(Body::empty())
+        .unwrap();
+
+        get_units_for_slot_coalesced(
+            setup.logger.clone(),
+            setup.market.clone(),
+            setup.config.clone(),
+            setup.cache.clone(),
+            request,
+        )
+    }))
+    .await;
+
+    let mut bodies = vec![];
+    for response in responses {
+        let response = response.expect("Should handle the request");
+        assert_eq!(StatusCode::OK, response.status());
+
+        bodies.push(hyper::body::to_bytes(response).await.unwrap());
+    }
+    assert_eq!(bodies[0], bodies[1]);
+}
+
+#[tokio::test]
+async fn requests_with_a_non_deterministic_strategy_are_not_coalesced() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    // 3 Campaigns with different prices and AdUnits
+    let channels: Vec<Channel> = (1..=3_u8)
+        .map(|i| {
+            let mut channel = mock_channel(&rules);
+            channel.id = ChannelId::from([i; 32]);
+            channel.spec.min_per_impression = (u64::from(i) * 100_000_000_000_000).into();
+            for ad_unit in channel.spec.ad_units.iter_mut() {
+                ad_unit.ipfs = format!("{}{}", ad_unit.ipfs, i);
+            }
+
+            channel
+        })
+        .collect();
+
+    let mut setup = Setup::new(mock_multiple_cache_campaigns(channels.clone())).await;
+    setup.config.selection.default = Strategy::HighestPrice;
+    setup.config.selection.allowed = vec![Strategy::RoundRobin];
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup.mount_slot(&mock_slot, &AdUnitsResponse(vec![])).await;
+
+    let concurrent_requests = |query: &str| {
+        futures::future::join_all((0..3).map(|_| {
+            let request = Request::get(format!(
+                "/units-for-slot/{}?depositAsset={}&{}",
+                mock_slot.slot.ipfs, channels[0].deposit_asset, query
+            ))
+            .header(USER_AGENT, TEST_USER_AGENT)
+            .body(Body::empty())
+            .unwrap();
+
+            get_units_for_slot_coalesced(
+                setup.logger.clone(),
+                setup.market.clone(),
+                setup.config.clone(),
+                setup.cache.clone(),
+                request,
+            )
+        }))
+    };
+    let first_units = |responses: Vec<Result<Response<Body>, Error>>| async move {
+        let mut first_units = vec![];
+        for response in responses {
+            let response = response.expect("Should handle the request");
+            assert_eq!(StatusCode::OK, response.status());
+            let paged = serde_json::from_slice::<PagedResponse>(
+                &hyper::body::to_bytes(response).await.unwrap(),
+            )
+            .expect("Should deserialize");
+
+            first_units.push(paged.units[0].unit.unit.id.clone());
+        }
+        first_units.sort();
+
+        first_units
+    };
+
+    // the deterministic strategies share the response
+    let highest_price = first_units(concurrent_requests("strategy=highest_price").await).await;
+    assert!(highest_price.iter().all(|unit| unit == &highest_price[0]));
+
+    // every request of the AdSlot starts with the next unit, even the concurrent ones
+    let mut round_robin = first_units(concurrent_requests("strategy=round_robin").await).await;
+    round_robin.dedup();
+    assert_eq!(3, round_robin.len());
+}
+
+#[tokio::test]
+async fn every_coalesced_request_is_counted_in_the_serve_stats() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    // slow enough for all of the requests to be coalesced
+    Mock::given(method("GET"))
+        .and(path(format!("/market/slots/{}", mock_slot.slot.ipfs)))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(&mock_slot)
+                .set_delay(Duration::from_millis(100)),
+        )
+        .expect(1_u64)
+        .mount(&setup.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/market/units"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&AdUnitsResponse(vec![])))
+        .expect(1_u64)
+        .mount(&setup.server)
+        .await;
+
+    let responses = futures::future::join_all((0..5).map(|_| {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?depositAsset={}",
+            mock_slot.slot.ipfs, channel.deposit_asset
+        ))
+        .header(USER_AGENT, TEST_USER_AGENT)
+        .body(Body::empty())
+        .unwrap();
+
+        get_units_for_slot_coalesced(
+            setup.logger.clone(),
+            setup.market.clone(),
+            setup.config.clone(),
+            setup.cache.clone(),
+            request,
+        )
+    }))
+    .await;
+    for response in responses {
+        let response = response.expect("Should handle the request");
+        assert_eq!(StatusCode::OK, response.status());
+        // the Delivery is recorded, not passed on
+        assert!(response
+            .extensions()
+            .get::<serve_stats::Delivery>()
+            .is_none());
+    }
+
+    // the followers are counted as well, not only the request which did the work
+    let served = setup.cache.serve_counters.take();
+    assert_eq!(
+        vec![(channel.id, serve_stats::ServeCounts { served: 5, top: 5 })],
+        served
+    );
+    let requests = setup
+        .cache
+        .publisher_stats
+        .read()
+        .await
+        .get(mock_slot.slot.owner, setup.cache.clock().now_utc());
+    assert_eq!(5, requests.total.requests);
+    assert_eq!(5, requests.total.matched);
+}
+
+mod ad_slot_path {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parses_the_ad_slot_ipfs() {
+        let ipfs = "QmVwXu9oEgYSsL6G1WZtUQy6dEReqs3Nz9iaW4Cq5QLV8C";
+
+        assert_eq!(
+            AdSlotPath::Ipfs(ipfs),
+            AdSlotPath::parse(&format!("/units-for-slot/{}", ipfs))
+        );
+        assert_eq!(AdSlotPath::Missing, AdSlotPath::parse("/units-for-slot/"));
+        assert_eq!(AdSlotPath::Missing, AdSlotPath::parse("/slots/Qm"));
+
+        for invalid in &[
+            "/units-for-slot/units-for-slot/x",
+            "/units-for-slot/units-for-slot/",
+            "/units-for-slot/Qm/",
+            "/units-for-slot/Qm//",
+            "/units-for-slot/Qm/extra",
+            "/units-for-slot/Qm%2Fextra",
+            "/units-for-slot/..%2F..%2Fslots",
+        ] {
+            assert_eq!(
+                AdSlotPath::Invalid,
+                AdSlotPath::parse(invalid),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics_on_arbitrary_paths(path in "\\PC*") {
+            let _ = AdSlotPath::parse(&path);
+        }
+
+        #[test]
+        fn anything_after_the_ipfs_segment_is_invalid(
+            ipfs in "[a-zA-Z0-9]{1,64}",
+            rest in "(/|%2F|%2f|/units-for-slot/)[^?#]*",
+        ) {
+            let path = format!("{}{}{}", ROUTE_UNITS_FOR_SLOT, ipfs, rest);
+
+            prop_assert_eq!(AdSlotPath::Invalid, AdSlotPath::parse(&path));
+        }
+
+        #[test]
+        fn only_alphanumeric_segments_are_ipfs(path in "/units-for-slot/[a-zA-Z0-9/%.\\-]{0,64}") {
+            match AdSlotPath::parse(&path) {
+                AdSlotPath::Ipfs(ipfs) => {
+                    prop_assert!(!ipfs.is_empty());
+                    prop_assert!(ipfs.chars().all(|c| c.is_ascii_alphanumeric()));
+                    prop_assert_eq!(&path, &format!("{}{}", ROUTE_UNITS_FOR_SLOT, ipfs));
+                }
+                AdSlotPath::Missing => prop_assert_eq!(path.as_str(), ROUTE_UNITS_FOR_SLOT),
+                AdSlotPath::Invalid => {
+                    prop_assert!(path[ROUTE_UNITS_FOR_SLOT.len()..]
+                        .chars()
+                        .any(|c| !c.is_ascii_alphanumeric()));
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn ipfs_media_urls_are_rewritten_unless_raw_ipfs() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let mut channel = mock_channel(&rules);
+    channel.spec.ad_units[0].media_url = "ipfs://not a hash".to_string();
+    channel.spec.ad_units[1].media_url = "https://adex.network/banner.png".to_string();
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channel))
+        .await;
+
+    let media_urls = |query: &'static str| {
+        let request = Request::get(format!(
+            "/units-for-slot/{}?depositAsset={}{}",
+            mock_slot.slot.ipfs, channel.deposit_asset, query
+        ))
+        .header(USER_AGENT, TEST_USER_AGENT)
+        .body(Body::empty())
+        .unwrap();
+        let setup = &setup;
+
+        async move {
+            let response = setup.units_for_slot(request).await;
+            let paged: PagedResponse =
+                serde_json::from_slice(&hyper::body::to_bytes(response).await.unwrap())
+                    .expect("Should deserialize");
+
+            let mut media_urls = paged
+                .units
+                .into_iter()
+                .map(|matched| (matched.unit.unit.id, matched.unit.unit.media_url))
+                .collect::<Vec<_>>();
+            media_urls.sort();
+
+            media_urls
+        }
+    };
+
+    let raw = channel
+        .spec
+        .ad_units
+        .iter()
+        .map(|unit| (unit.ipfs.clone(), unit.media_url.clone()))
+        .collect::<HashMap<_, _>>();
+
+    let rewritten = media_urls("").await;
+    assert_eq!(raw.len(), rewritten.len());
+    for (unit_ipfs, media_url) in &rewritten {
+        let expected = ipfs::gateway_url(&DEVELOPMENT.ipfs_gateway, &raw[unit_ipfs])
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| raw[unit_ipfs].clone());
+        assert_eq!(&expected, media_url);
+    }
+    // the malformed & the `https://` media URLs are left as they are
+    assert!(rewritten.contains(&(
+        channel.spec.ad_units[0].ipfs.clone(),
+        "ipfs://not a hash".to_string()
+    )));
+    assert!(rewritten.contains(&(
+        channel.spec.ad_units[1].ipfs.clone(),
+        "https://adex.network/banner.png".to_string()
+    )));
+
+    let mut expected_raw = raw.into_iter().collect::<Vec<_>>();
+    expected_raw.sort();
+    assert_eq!(expected_raw, media_urls("&rawIpfs").await);
+}
+
+#[tokio::test]
+async fn the_sampled_requests_have_the_returned_units_and_no_publisher() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    setup
+        .mount_slot(&mock_slot, &market_ad_units(&channel))
+        .await;
+
+    let setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+    let query = format!("depositAsset={}", channel.deposit_asset);
+
+    for include_publisher in [false, true].iter() {
+        let (sampler, mut samples) = sampling::Sampler::channel(&crate::config::Sampling {
+            rate: 1.0,
+            include_publisher: *include_publisher,
+            seed: Some(42),
+            ..Default::default()
+        });
+        let mut request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+        request.extensions_mut().insert(sampler);
+
+        let response = setup.units_for_slot(request).await;
+        let response_json = serde_json::from_slice::<serde_json::Value>(
+            &hyper::body::to_bytes(response.into_body())
+                .await
+                .expect("Should read the body"),
+        )
+        .expect("Should deserialize");
+
+        let sample = samples.try_recv().expect("The request should be sampled");
+        assert_eq!(
+            format!("/units-for-slot/{}", mock_slot.slot.ipfs),
+            sample.path
+        );
+        assert_eq!(Some(query.clone()), sample.query);
+        assert!(!sample.units.is_empty());
+        assert_eq!(
+            response_json["units"]
+                .as_array()
+                .expect("Should have units")
+                .iter()
+                .map(|unit| unit["unit"]["ipfs"].clone())
+                .collect::<Vec<_>>(),
+            sample
+                .units
+                .iter()
+                .map(|ipfs| serde_json::json!(ipfs))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            *include_publisher,
+            sample.targeting_input.to_string().contains("publisherId")
+        );
+    }
+}
+
+/// The Cache with the Active Campaign of the `channel` and the `slot_override` of the `ad_slot`
+async fn cache_with_override(
+    channel: &Channel,
+    ad_slot: &AdSlotResponse,
+    slot_override: SlotOverride,
+) -> Cache<MockClient> {
+    let mock_client = MockClient::init(
+        vec![mock_cache_campaign(channel.clone(), Status::Active)],
+        vec![],
+        None,
+    )
+    .await;
+
+    Cache::builder(mock_client)
+        .slot_overrides(
+            vec![(ad_slot.slot.ipfs.clone(), slot_override)]
+                .into_iter()
+                .collect(),
+        )
+        .initialize()
+        .await
+}
+
+#[tokio::test]
+async fn blocked_slots_are_forbidden_without_fetching_them() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+
+    let setup = Setup::with_cache(
+        cache_with_override(
+            &channel,
+            &mock_slot,
+            SlotOverride {
+                blocked: true,
+                ..Default::default()
+            },
+        )
+        .await,
+    )
+    .await;
+    // the AdSlot is never fetched
+    setup.mount_expected_slot(&mock_slot, 0).await;
+
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+    let response = setup.units_for_slot(request).await;
+
+    assert_eq!(http::StatusCode::FORBIDDEN, response.status());
+    assert_eq!(
+        serde_json::json!({ "error": "slot blocked" }),
+        serde_json::from_slice::<serde_json::Value>(
+            &hyper::body::to_bytes(response).await.unwrap()
+        )
+        .expect("Should deserialize")
+    );
+}
+
+#[tokio::test]
+async fn forced_fallback_slots_serve_only_the_fallback_unit() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let fallback_unit = DUMMY_AD_UNITS[0].clone();
+    let mut mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    mock_slot.slot.fallback_unit = Some(fallback_unit.ipfs.clone());
+
+    let setup = Setup::with_cache(
+        cache_with_override(
+            &channel,
+            &mock_slot,
+            SlotOverride {
+                force_fallback: true,
+                ..Default::default()
+            },
+        )
+        .await,
+    )
+    .await;
+    setup
+        .mount_slow_slot(
+            &mock_slot,
+            &fallback_unit,
+            Duration::from_millis(0),
+            Duration::from_millis(0),
+        )
+        .await;
+
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+    let response = setup.units_for_slot(request).await;
+
+    assert_eq!(http::StatusCode::OK, response.status());
+    // it's not a degraded response
+    assert!(response.headers().get(DEGRADED_HEADER.clone()).is_none());
+    let paged =
+        serde_json::from_slice::<PagedResponse>(&hyper::body::to_bytes(response).await.unwrap())
+            .expect("Should deserialize");
+
+    assert!(paged.response.campaigns.is_empty());
+    assert_eq!(0, paged.total_matched);
+    assert_eq!(Some(EmptyReason::ForcedFallback), paged.reason);
+    assert_eq!(
+        Some(fallback_unit.ipfs.clone()),
+        paged.response.fallback_unit.map(|unit| unit.id)
+    );
+}
+
+#[tokio::test]
+async fn the_min_price_of_the_slot_override_tightens_the_global_one() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    // priced from 1 * 10^14 to 1 * 10^15
+    let channel = mock_channel(&rules);
+    let mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    let mut setup =
+        Setup::with_cache(cache_with_override(&channel, &mock_slot, SlotOverride::default()).await)
+            .await;
+    setup.mount_expected_slot(&mock_slot, 3).await;
+
+    let query = format!("depositAsset={}", channel.deposit_asset);
+    let cases = [
+        // another deposit asset
+        (
+            "0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359",
+            "2000000000000000",
+            None,
+        ),
+        // lower than the price of the units
+        (channel.deposit_asset.as_str(), "100000000000000", None),
+        // higher than the max price of the Campaign
+        (
+            channel.deposit_asset.as_str(),
+            "2000000000000000",
+            Some(EmptyReason::FilteredByPrice),
+        ),
+    ];
+    for (deposit_asset, min_price, reason) in cases.iter() {
+        let slot_override = SlotOverride {
+            min_price: vec![(
+                deposit_asset.to_string(),
+                BigNum::from_str(min_price).expect("Valid BigNum"),
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        setup.cache = cache_with_override(&channel, &mock_slot, slot_override).await;
+
+        let request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+        let response = setup.units_for_slot(request).await;
+        let paged = serde_json::from_slice::<PagedResponse>(
+            &hyper::body::to_bytes(response).await.unwrap(),
+        )
+        .expect("Should deserialize");
+
+        assert_eq!(*reason, paged.reason, "min price {}", min_price);
+        assert_eq!(reason.is_none(), !paged.units.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn an_aggressive_client_deadline_aborts_the_request_with_a_slow_market() {
+    let categories: [&str; 3] = ["IAB3", "IAB13-7", "IAB5"];
+    let rules = get_mock_rules(&categories);
+    let channel = mock_channel(&rules);
+
+    let fallback_unit = DUMMY_AD_UNITS[0].clone();
+    let mut mock_slot = get_supermarket_ad_slot(&rules, &categories);
+    mock_slot.slot.fallback_unit = Some(fallback_unit.ipfs.clone());
+
+    let slow = Duration::from_millis(400);
+    let no_delay = Duration::from_millis(0);
+    // the AdSlot delay, the AdUnits delay, the `X-Request-Timeout-Ms` and the expected status & phase
+    let cases = [
+        (slow, no_delay, Some("100"), 504, Some("fetch_slot")),
+        (no_delay, slow, Some("100"), 504, Some("fetch_units")),
+        // clamped to the max
+        (slow, no_delay, Some("60000"), 200, None),
+        // clamped to the min, which is still too short
+        (slow, no_delay, Some("1"), 504, Some("fetch_slot")),
+        // the usual timeouts apply without it
+        (slow, slow, None, 200, None),
+    ];
+    for (slot_delay, units_delay, client_timeout, status, phase) in cases.iter() {
+        let mut setup = Setup::new(mock_cache_campaign(channel.clone(), Status::Active)).await;
+        setup.config.timeouts.client_deadline.min = Duration::from_millis(50);
+        setup.config.timeouts.client_deadline.max = Duration::from_millis(1000);
+        setup
+            .mount_slow_slot(&mock_slot, &fallback_unit, *slot_delay, *units_delay)
+            .await;
+        let exceeded = || {
+            phase.map_or(0, |phase| {
+                DEADLINES_EXCEEDED.with_label_values(&[phase]).get()
+            })
+        };
+        let exceeded_before = exceeded();
+
+        let query = format!("depositAsset={}", channel.deposit_asset);
+        let mut request = units_for_slot_request(&mock_slot.slot.ipfs, &query, None);
+        if let Some(client_timeout) = client_timeout {
+            request.headers_mut().insert(
+                deadline::REQUEST_TIMEOUT_HEADER.clone(),
+                client_timeout.parse().expect("Valid header"),
+            );
+        }
+
+        let started = Instant::now();
+        let response = setup.units_for_slot(request).await;
+        let case = format!("{:?}", (slot_delay, units_delay, client_timeout));
+
+        assert_eq!(*status, response.status().as_u16(), "{}", case);
+        if phase.is_some() {
+            // aborted before the slow Market responded
+            assert!(started.elapsed() < *slow, "{}", case);
+            assert!(exceeded() > exceeded_before, "{}", case);
+            assert_eq!(
+                serde_json::json!({ "reason": "deadline_exceeded" }),
+                serde_json::from_slice::<serde_json::Value>(
+                    &hyper::body::to_bytes(response).await.unwrap()
+                )
+                .expect("Should deserialize"),
+                "{}",
+                case
+            );
+        }
+    }
+}
@@ -1,8 +1,380 @@
+use crate::metrics::CLOCK_STEPPED_BACK;
+use chrono::{DateTime, Utc};
+use slog::{warn, Logger};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// The source of the current time for the Cache, the Campaign statuses and the units-for-slot,
+/// so tests can control it with a [`MockClock`](test::MockClock).
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now_utc(&self) -> DateTime<Utc>;
+    /// Used for measuring durations, it follows the paused `tokio` time in tests
+    fn now_instant(&self) -> Instant;
+
+    /// The time since the `earlier` [`Clock::now_instant`], zero if it's later
+    fn elapsed_since(&self, earlier: Instant) -> Duration {
+        self.now_instant().saturating_duration_since(earlier)
+    }
+}
+
+/// The system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [`Clock`] whose wall-clock time never goes backwards, for the Campaign statuses.
+///
+/// When the system clock is stepped back (e.g. by NTP) the last time is kept until the clock catches up,
+/// i.e. the elapsed time is clamped to zero, which is logged (sampled) and counted in [`CLOCK_STEPPED_BACK`].
+/// Otherwise the Validators' Heartbeats which were recent at the last status check would be dated
+/// in the future (beyond the `clock_skew_tolerance`) and the Campaigns would become Unsound.
+#[derive(Debug)]
+pub struct NonDecreasingClock {
+    clock: Arc<dyn Clock>,
+    logger: Logger,
+    sampler: LogSampler,
+    last: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl NonDecreasingClock {
+    pub fn new(clock: Arc<dyn Clock>, logger: Logger) -> Self {
+        Self {
+            sampler: LogSampler::with_clock(LOG_SAMPLING_INTERVAL, clock.clone()),
+            clock,
+            logger,
+            last: Mutex::new(None),
+        }
+    }
+}
+
+impl Clock for NonDecreasingClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        let now = self.clock.now_utc();
+        let mut last = self.last.lock().expect("Should lock the last time");
+
+        match *last {
+            Some(last) if now < last => {
+                CLOCK_STEPPED_BACK.inc();
+                let message =
+                    "The system clock was stepped back, the elapsed time is clamped to zero";
+                if let Some(suppressed) = self.sampler.sample(message, &["stepped back"]) {
+                    let stepped_back = (last - now).to_std().unwrap_or_default();
+                    warn!(&self.logger, "{}", message; "stepped back" => ?stepped_back, "suppressed" => suppressed);
+                }
+
+                last
+            }
+            _ => {
+                *last = Some(now);
+
+                now
+            }
+        }
+    }
+
+    /// The monotonic time is not affected by the system clock
+    fn now_instant(&self) -> Instant {
+        self.clock.now_instant()
+    }
+}
+
+/// How often the identical error records on the hot paths are logged, see [`ERROR_SAMPLER`]
+pub const LOG_SAMPLING_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    /// Samples the error records which repeat on every request or Cache update while
+    /// the Market or the Validators are down, e.g. `Proxying request to market failed`
+    pub static ref ERROR_SAMPLER: LogSampler = LogSampler::new(LOG_SAMPLING_INTERVAL);
+}
+
+/// Rate-limits identical log records, i.e. with the same message and set of keys.
+///
+/// The first record is always logged, the identical ones after it at most once per `interval`,
+/// with the number of records suppressed since the last logged one:
+///
+/// ```ignore
+/// if let Some(suppressed) = ERROR_SAMPLER.sample("Request failed", &["error"]) {
+///     error!(&logger, "Request failed"; "error" => ?error, "suppressed" => suppressed);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LogSampler {
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    records: Arc<Mutex<HashMap<String, SampledRecord>>>,
+}
+
+#[derive(Debug)]
+struct SampledRecord {
+    logged_at: Instant,
+    suppressed: u64,
+}
+
+impl LogSampler {
+    pub fn new(interval: Duration) -> Self {
+        Self::with_clock(interval, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            interval,
+            clock,
+            records: Default::default(),
+        }
+    }
+
+    /// Returns the number of suppressed identical records if this one should be logged
+    /// or `None` if it should be suppressed.
+    pub fn sample(&self, message: &str, keys: &[&str]) -> Option<u64> {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        let record_key = std::iter::once(message)
+            .chain(keys)
+            .collect::<Vec<_>>()
+            .join("\0");
+
+        let now = self.clock.now_instant();
+        let mut records = self
+            .records
+            .lock()
+            .expect("Should lock the sampled records");
+        match records.get_mut(&record_key) {
+            Some(record) if now.saturating_duration_since(record.logged_at) < self.interval => {
+                record.suppressed += 1;
+
+                None
+            }
+            Some(record) => {
+                record.logged_at = now;
+
+                Some(std::mem::take(&mut record.suppressed))
+            }
+            None => {
+                records.insert(
+                    record_key,
+                    SampledRecord {
+                        logged_at: now,
+                        suppressed: 0,
+                    },
+                );
+
+                Some(0)
+            }
+        }
+    }
+}
+
+/// The most intervals a failing target is skipped for, see [`Backoff`]
+pub const MAX_BACKOFF_INTERVALS: u32 = 8;
+
+/// A circuit breaker for the periodic requests to the Market or the Validators (e.g. keeping the connections warm),
+/// while it's open the requests are skipped since they are pointless (and only add load) while the target is down.
+///
+/// After `n` consecutive failures the next `2^(n - 1)` intervals (at most [`MAX_BACKOFF_INTERVALS`]) are skipped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Backoff {
+    failures: u32,
+    skip: u32,
+}
+
+impl Backoff {
+    /// Whether this interval should be skipped
+    pub fn is_open(&mut self) -> bool {
+        if self.skip > 0 {
+            self.skip -= 1;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn record(&mut self, succeeded: bool) {
+        if succeeded {
+            *self = Self::default();
+        } else {
+            self.failures += 1;
+            self.skip = 2_u32
+                .saturating_pow(self.failures - 1)
+                .min(MAX_BACKOFF_INTERVALS);
+        }
+    }
+
+    /// The consecutive failures
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// A [`Backoff`] by time instead of intervals, shared by the periodic requests to the same target,
+/// e.g. the [`MarketApi::circuit_breaker`](crate::MarketApi::circuit_breaker) opened by a failure of either
+/// the pre-warming or the revalidation of the negative cached AdSlots.
+///
+/// After `n` consecutive failures it's open for `2^(n - 1)` times the `base` (at most [`MAX_BACKOFF_INTERVALS`] times).
+/// It's cheap to `clone()` it, the clones share the same state.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    base: Duration,
+    state: Arc<Mutex<BreakerState>>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(base: Duration) -> Self {
+        Self {
+            base,
+            state: Default::default(),
+        }
+    }
+
+    /// Whether the requests should be skipped at `now`
+    pub fn is_open(&self, now: Instant) -> bool {
+        self.state
+            .lock()
+            .expect("Should lock the circuit breaker")
+            .open_until
+            .map_or(false, |open_until| now < open_until)
+    }
+
+    pub fn record(&self, succeeded: bool, now: Instant) {
+        let mut state = self.state.lock().expect("Should lock the circuit breaker");
+        if succeeded {
+            *state = BreakerState::default();
+        } else {
+            state.failures += 1;
+            let intervals = 2_u32
+                .saturating_pow(state.failures - 1)
+                .min(MAX_BACKOFF_INTERVALS);
+            state.open_until = Some(now + self.base * intervals);
+        }
+    }
+
+    /// The consecutive failures
+    pub fn failures(&self) -> u32 {
+        self.state
+            .lock()
+            .expect("Should lock the circuit breaker")
+            .failures
+    }
+}
+
+/// Compares the two byte slices in a constant time for slices of the same length,
+/// used for comparing secrets like the admin token.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The header carrying the ID of the request, set by the client (or a proxy) or generated by [`new_request_id`]
+pub static REQUEST_ID_HEADER: &str = "x-request-id";
+
+lazy_static::lazy_static! {
+    /// Differentiates the generated request IDs between restarts
+    static ref REQUEST_ID_PREFIX: String = format!("{:x}", chrono::Utc::now().timestamp_millis());
+}
+
+static REQUEST_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a unique ID for a request without a [`REQUEST_ID_HEADER`], e.g. `176e1b4f5a0-2a`
+pub fn new_request_id() -> String {
+    format!(
+        "{}-{:x}",
+        *REQUEST_ID_PREFIX,
+        REQUEST_ID_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// The [`REQUEST_ID_HEADER`] of the request, or `-` if it's not set
+pub fn request_id(headers: &http::HeaderMap) -> &str {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .unwrap_or("-")
+}
+
 #[cfg(test)]
 pub mod test {
 
-    use slog::{o, Discard, Drain, Logger};
+    use super::Clock;
+    use chrono::{DateTime, Utc};
+    use slog::{o, Discard, Drain, Key, Logger, Never, OwnedKVList, Record, Serializer, KV};
     use slog_async::Async;
+    use std::{
+        collections::HashMap,
+        fmt,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::time::Instant;
+
+    /// A [`Clock`] which stands still until it's advanced, its clones share the same time
+    #[derive(Debug, Clone)]
+    pub struct MockClock {
+        now: Arc<Mutex<(DateTime<Utc>, Instant)>>,
+    }
+
+    impl MockClock {
+        /// Starts at the current system time
+        pub fn new() -> Self {
+            Self::starting_at(Utc::now())
+        }
+
+        pub fn starting_at(now_utc: DateTime<Utc>) -> Self {
+            Self {
+                now: Arc::new(Mutex::new((now_utc, Instant::now()))),
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            let mut now = self.now.lock().expect("Should lock the mock clock");
+            now.0 = now.0 + chrono::Duration::from_std(duration).expect("Should be in range");
+            now.1 += duration;
+        }
+
+        /// Steps only the wall-clock time back, like NTP correcting the system clock,
+        /// the monotonic time stands still
+        pub fn step_back(&self, duration: Duration) {
+            let mut now = self.now.lock().expect("Should lock the mock clock");
+            now.0 = now.0 - chrono::Duration::from_std(duration).expect("Should be in range");
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now_utc(&self) -> DateTime<Utc> {
+            self.now.lock().expect("Should lock the mock clock").0
+        }
+
+        fn now_instant(&self) -> Instant {
+            self.now.lock().expect("Should lock the mock clock").1
+        }
+    }
 
     pub fn logger() -> Logger {
         let decorator = slog_term::TermDecorator::new().build();
@@ -18,4 +390,176 @@ pub mod test {
 
         Logger::root(drain, o!())
     }
+
+    /// A logged message with its key-values
+    pub type LoggedRecord = (String, HashMap<String, String>);
+
+    /// Keeps all the logged records in memory, so tests can assert on them.
+    /// Unlike the [`logger()`] it logs synchronously.
+    #[derive(Debug, Clone, Default)]
+    pub struct MemoryDrain {
+        records: Arc<Mutex<Vec<LoggedRecord>>>,
+    }
+
+    impl MemoryDrain {
+        pub fn logger(&self) -> Logger {
+            Logger::root(self.clone(), o!())
+        }
+
+        pub fn records(&self) -> Vec<LoggedRecord> {
+            self.records.lock().expect("Should lock records").clone()
+        }
+    }
+
+    impl Drain for MemoryDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<(), Never> {
+            let mut key_values = KeyValues::default();
+            // serializing into a `HashMap` cannot fail
+            let _ = record.kv().serialize(record, &mut key_values);
+            let _ = values.serialize(record, &mut key_values);
+
+            self.records
+                .lock()
+                .expect("Should lock records")
+                .push((record.msg().to_string(), key_values.0));
+
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct KeyValues(HashMap<String, String>);
+
+    impl Serializer for KeyValues {
+        fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments<'_>) -> slog::Result {
+            self.0.insert(key.to_string(), val.to_string());
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn identical_error_records_are_sampled() {
+        use super::LogSampler;
+        use slog::error;
+
+        let clock = MockClock::new();
+        let sampler = LogSampler::with_clock(Duration::from_secs(10), Arc::new(clock.clone()));
+        let drain = MemoryDrain::default();
+        let logger = drain.logger();
+
+        let log_failure = |error: &str| {
+            if let Some(suppressed) = sampler.sample("Request failed", &["error"]) {
+                error!(&logger, "Request failed"; "error" => error, "suppressed" => suppressed);
+            }
+        };
+
+        // the first one is logged immediately
+        log_failure("connection refused");
+        assert_eq!(1, drain.records().len());
+        assert_eq!("0", drain.records()[0].1["suppressed"]);
+
+        for _ in 0..999 {
+            log_failure("connection refused");
+        }
+        clock.advance(Duration::from_secs(9));
+        log_failure("connection reset");
+        assert_eq!(1, drain.records().len());
+
+        // other records are sampled separately
+        if let Some(suppressed) = sampler.sample("Request failed", &["error", "url"]) {
+            error!(&logger, "Request failed"; "error" => "timeout", "url" => "/", "suppressed" => suppressed);
+        }
+        assert_eq!(2, drain.records().len());
+        // the order of the keys doesn't matter
+        assert_eq!(None, sampler.sample("Request failed", &["url", "error"]));
+
+        clock.advance(Duration::from_secs(1));
+        log_failure("connection refused");
+        let records = drain.records();
+        assert_eq!(3, records.len());
+        assert_eq!("Request failed", records[2].0);
+        assert_eq!("1000", records[2].1["suppressed"]);
+
+        // the count starts over after each logged record
+        log_failure("connection refused");
+        clock.advance(Duration::from_secs(10));
+        log_failure("connection refused");
+        let records = drain.records();
+        assert_eq!(4, records.len());
+        assert_eq!("1", records[3].1["suppressed"]);
+    }
+
+    #[test]
+    fn the_wall_clock_time_of_the_statuses_doesnt_go_backwards() {
+        use super::NonDecreasingClock;
+
+        let clock = MockClock::new();
+        let drain = MemoryDrain::default();
+        let status_clock = NonDecreasingClock::new(Arc::new(clock.clone()), drain.logger());
+
+        let before = status_clock.now_utc();
+        let instant = status_clock.now_instant();
+        assert_eq!(clock.now_utc(), before);
+
+        clock.step_back(Duration::from_secs(5));
+        assert_eq!(before, status_clock.now_utc());
+        assert_eq!(before, status_clock.now_utc());
+        assert_eq!(instant, status_clock.now_instant());
+        let records = drain.records();
+        assert_eq!(1, records.len(), "The warning should be sampled");
+        assert_eq!("5s", records[0].1["stepped back"]);
+
+        // until the clock catches up
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(before, status_clock.now_utc());
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now_utc(), status_clock.now_utc());
+        assert!(status_clock.now_utc() > before);
+        assert_eq!(instant + Duration::from_secs(6), status_clock.now_instant());
+    }
+
+    #[test]
+    fn the_backoff_is_capped() {
+        use super::{Backoff, MAX_BACKOFF_INTERVALS};
+
+        let mut backoff = Backoff::default();
+        for _ in 0..10 {
+            backoff.record(false);
+        }
+        assert_eq!(MAX_BACKOFF_INTERVALS, backoff.skip);
+
+        backoff.record(true);
+        assert!(!backoff.is_open());
+        assert_eq!(0, backoff.failures());
+    }
+
+    #[test]
+    fn the_circuit_breaker_is_shared_by_its_clones() {
+        use super::{CircuitBreaker, MAX_BACKOFF_INTERVALS};
+
+        let now = Instant::now();
+        let breaker = CircuitBreaker::new(Duration::from_secs(5));
+        let shared = breaker.clone();
+
+        shared.record(false, now);
+        assert!(breaker.is_open(now + Duration::from_secs(4)));
+        assert!(!breaker.is_open(now + Duration::from_secs(5)));
+
+        // 5s, 10s, 20s, 40s and then at most 40s
+        for _ in 0..10 {
+            breaker.record(false, now);
+        }
+        assert_eq!(11, shared.failures());
+        let max = Duration::from_secs(5) * MAX_BACKOFF_INTERVALS;
+        assert!(shared.is_open(now + max - Duration::from_secs(1)));
+        assert!(!shared.is_open(now + max));
+
+        shared.record(true, now);
+        assert!(!breaker.is_open(now));
+        assert_eq!(0, breaker.failures());
+    }
 }